@@ -222,6 +222,11 @@ enter_kernel:
   mov esp, edx
   sub esp, 0xc0000004
 
+  # pass along the BIOS keyboard flags, so holding a key can set boot options
+  xor eax, eax
+  mov al, [0x417]
+  mov [boot_flags], eax
+
   push offset initfs_start
   push 0x00000000
   
@@ -250,6 +255,9 @@ halt:
 # BootStruct for passing values to the kernel
 initfs_start: .long 0
 initfs_size: .long 0
+boot_flags: .long 0
+memory_map_address: .long memory_map_entries
+memory_map_count: .long 0
 
 filename_kernel: .ascii "KERNEL  BIN"
 filename_initfs: .ascii "INITFS  IMG"
@@ -3,9 +3,9 @@
 
 # Use BIOS interrupts to generate a map of all available memory that we can
 # pass to the kernel.
-# The memory map is stored as 0x1000:
-# We store the number of entries, followed by the first entry at 0x1004
-memory_map_length = 0x1000
+# Entries are stored starting at 0x1000, and their address and count are
+# recorded in the BootStruct
+memory_map_entries = 0x1000
 map_memory:
   push eax
   push ebx
@@ -15,32 +15,43 @@ map_memory:
   push esi
 
   # use int 0x15, eax=0xe820 to detect memory
-  mov di, 0x1004
+  mov di, memory_map_entries
   xor esi, esi
   xor ebx, ebx
-  mov edx, 0x534d4150
 map_memory_loop:
+   # some BIOSes clobber edx, so the signature is loaded for every call
+   mov edx, 0x534d4150
+   # BIOSes that only return 20 bytes leave the ACPI attributes untouched, so
+   # mark the entry as valid ahead of time
+   mov dword ptr [di + 20], 1
    mov eax, 0xe820
    mov ecx, 24
    int 0x15
    # if carry is set, map is completed
    jc map_memory_finished
-   # if ebx is zero, map is completed
-   cmp ebx, 0
-   je map_memory_finished
    # eax should now equal edx
    cmp eax, edx
    jne map_memory_finished
+   # skip empty entries, and ones ACPI says to ignore
+   mov ecx, [di + 8]
+   or ecx, [di + 12]
+   jz map_memory_next
+   test dword ptr [di + 20], 1
+   jz map_memory_next
 
-   # increment values, and loop
+   # keep the entry, and move on
    add di, 24
    inc esi
-   # arbitrarily cap at 170 entries, which would fill up to 0x2000
+map_memory_next:
+   # ebx is zero once the last entry has been returned
+   cmp ebx, 0
+   je map_memory_finished
+   # arbitrarily cap at 170 entries, which would fill up to 0x1ff0
    cmp esi, 170
    jb map_memory_loop
 
   map_memory_finished:
-    mov [memory_map_length], esi
+    mov [memory_map_count], esi
 
     pop esi
     pop edi
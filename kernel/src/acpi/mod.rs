@@ -0,0 +1,236 @@
+//! ACPI describes how to control the machine's power through tables left in
+//! memory by the firmware. The kernel only reads enough of them to turn the
+//! machine off and reset it: the RSDP points to the RSDT, which lists the
+//! FADT, which holds the power management ports and points to the DSDT, where
+//! the sleep type for soft-off is defined. The MADT, also listed in the RSDT,
+//! describes the APICs used in place of the 8259 PICs.
+//!
+//! Tables are found once at boot. Machines without ACPI, or with tables that
+//! cannot be read, can still be reset through the keyboard controller, but
+//! cannot be powered off.
+
+pub mod tables;
+
+#[cfg(not(test))]
+use spin::Mutex;
+#[cfg(not(test))]
+use tables::{Fadt, Madt, SleepType};
+
+/// What was learned from the tables at boot
+#[cfg(not(test))]
+#[derive(Copy, Clone)]
+struct PowerControl {
+  fadt: Fadt,
+  /// Missing if the DSDT could not be read, or does not define `_S5_`
+  soft_off: Option<SleepType>,
+}
+
+#[cfg(not(test))]
+static POWER: Mutex<Option<PowerControl>> = Mutex::new(None);
+
+#[cfg(not(test))]
+static MADT: Mutex<Option<Madt>> = Mutex::new(None);
+
+/// Tables are mapped one at a time into this kernel address range, which sits
+/// in the shared kernel page tables above the heap's limit
+#[cfg(not(test))]
+const FIRMWARE_WINDOW: usize = 0xfe000000;
+#[cfg(not(test))]
+const FIRMWARE_WINDOW_SIZE: usize = 0x100000;
+
+/// The first 4MiB of physical memory is always mapped here
+#[cfg(not(test))]
+const LOW_MEMORY_WINDOW: usize = 0xc0000000;
+
+/// Map a range of physical memory into the firmware window, replacing whatever
+/// was there before. The returned slice is only valid until the next call.
+#[cfg(not(test))]
+unsafe fn map_physical(address: u32, length: usize) -> Option<&'static [u8]> {
+  use crate::memory::address::VirtualAddress;
+  use crate::memory::physical::frame::Frame;
+  use crate::memory::virt::page_directory::{CurrentPageDirectory, PageDirectory, PermissionFlags};
+
+  let address = address as usize;
+  let offset = address & 0xfff;
+  let page_count = (offset + length + 0xfff) / 0x1000;
+  if length == 0 || page_count * 0x1000 > FIRMWARE_WINDOW_SIZE || address.checked_add(length).is_none() {
+    return None;
+  }
+  let directory = CurrentPageDirectory::get();
+  for page in 0..page_count {
+    let frame = Frame::new((address & 0xfffff000) + page * 0x1000);
+    directory.map(frame, VirtualAddress::new(FIRMWARE_WINDOW + page * 0x1000), PermissionFlags::empty());
+  }
+  Some(core::slice::from_raw_parts((FIRMWARE_WINDOW + offset) as *const u8, length))
+}
+
+/// Map a whole table, checking its signature and checksum
+#[cfg(not(test))]
+unsafe fn map_table(address: u32, signature: &[u8; 4]) -> Option<&'static [u8]> {
+  let header = tables::SdtHeader::parse(map_physical(address, tables::SDT_HEADER_SIZE)?)?;
+  let table = map_physical(address, header.length)?;
+  if tables::validate_table(table, signature) {
+    Some(table)
+  } else {
+    None
+  }
+}
+
+/// The RSDP is in the first KiB of the Extended BIOS Data Area, or in the BIOS
+/// ROM between 0xe0000 and 0xfffff
+#[cfg(not(test))]
+unsafe fn find_rsdt() -> Option<u32> {
+  let ebda_segment = *((LOW_MEMORY_WINDOW + 0x40e) as *const u16) as usize;
+  if ebda_segment != 0 {
+    let ebda = core::slice::from_raw_parts((LOW_MEMORY_WINDOW + (ebda_segment << 4)) as *const u8, 0x400);
+    if let Some(rsdt) = tables::find_rsdp(ebda) {
+      return Some(rsdt);
+    }
+  }
+  let rom = core::slice::from_raw_parts((LOW_MEMORY_WINDOW + 0xe0000) as *const u8, 0x20000);
+  tables::find_rsdp(rom)
+}
+
+/// Copy out the addresses of every table listed in the RSDT, since mapping
+/// each table replaces the RSDT
+#[cfg(not(test))]
+unsafe fn read_rsdt_entries() -> Option<alloc::vec::Vec<u32>> {
+  let rsdt_address = find_rsdt()?;
+  let rsdt = map_table(rsdt_address, tables::RSDT_SIGNATURE)?;
+  Some(tables::rsdt_entries(rsdt).collect())
+}
+
+#[cfg(not(test))]
+unsafe fn read_power_control(entries: &[u32]) -> Option<PowerControl> {
+  let fadt = entries.iter().find_map(|&entry| {
+    map_table(entry, tables::FADT_SIGNATURE).and_then(Fadt::parse)
+  })?;
+  let soft_off = map_table(fadt.dsdt, tables::DSDT_SIGNATURE)
+    .and_then(|dsdt| tables::find_s5_sleep_type(&dsdt[tables::SDT_HEADER_SIZE..]));
+  Some(PowerControl {
+    fadt,
+    soft_off,
+  })
+}
+
+/// Find the power management and interrupt controller tables. Must run after
+/// paging is enabled and the heap is ready.
+#[cfg(not(test))]
+pub fn init() {
+  let entries = match unsafe { read_rsdt_entries() } {
+    Some(entries) => entries,
+    None => {
+      crate::klog!("ACPI: no usable tables found");
+      return;
+    },
+  };
+  let madt = entries.iter().find_map(|&entry| unsafe {
+    map_table(entry, tables::MADT_SIGNATURE).and_then(Madt::parse)
+  });
+  *MADT.lock() = madt;
+
+  let control = unsafe { read_power_control(&entries) };
+  match control {
+    Some(control) => {
+      if control.soft_off.is_none() {
+        crate::klog!("ACPI: no soft-off state, power off is unavailable");
+      }
+      *POWER.lock() = Some(control);
+    },
+    None => crate::klog!("ACPI: no FADT, power off is unavailable"),
+  }
+}
+
+/// The interrupt controllers described by the firmware, if it lists any
+#[cfg(not(test))]
+pub fn get_madt() -> Option<Madt> {
+  MADT.lock().clone()
+}
+
+/// Make sure the firmware has handed power management to ACPI, so that writes
+/// to the PM1 control registers take effect
+#[cfg(not(test))]
+unsafe fn enable_acpi_mode(fadt: &Fadt) {
+  use crate::x86::io::Port;
+
+  let control = Port::new(fadt.pm1a_control);
+  if control.read_u16() & tables::PM1_SCI_ENABLE != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+    return;
+  }
+  Port::new(fadt.smi_command).write_u8(fadt.acpi_enable);
+  for _ in 0..1000000 {
+    if control.read_u16() & tables::PM1_SCI_ENABLE != 0 {
+      return;
+    }
+  }
+}
+
+/// Enter the S5 soft-off state. Only returns if the machine has no ACPI
+/// support, or ignored the request.
+#[cfg(not(test))]
+pub fn poweroff() {
+  use crate::x86::io::Port;
+
+  let control = match *POWER.lock() {
+    Some(control) => control,
+    None => return,
+  };
+  let soft_off = match control.soft_off {
+    Some(sleep_type) => sleep_type,
+    None => return,
+  };
+  unsafe {
+    llvm_asm!("cli" : : : : "volatile");
+    enable_acpi_mode(&control.fadt);
+    let pm1a = Port::new(control.fadt.pm1a_control);
+    let value = tables::sleep_control_value(soft_off.a, pm1a.read_u16());
+    pm1a.write_u16(value);
+    if control.fadt.pm1b_control != 0 {
+      let pm1b = Port::new(control.fadt.pm1b_control);
+      let value = tables::sleep_control_value(soft_off.b, pm1b.read_u16());
+      pm1b.write_u16(value);
+    }
+    llvm_asm!("sti" : : : : "volatile");
+  }
+}
+
+/// Reset the machine. The FADT's reset register is tried first, then the
+/// keyboard controller's reset line, and finally a triple fault, which every
+/// x86 machine answers with a reset.
+#[cfg(not(test))]
+pub fn reboot() -> ! {
+  use crate::x86::io::Port;
+
+  unsafe {
+    llvm_asm!("cli" : : : : "volatile");
+    let reset = POWER.try_lock().and_then(|power| power.and_then(|control| control.fadt.reset));
+    if let Some((port, value)) = reset {
+      Port::new(port).write_u8(value);
+    }
+
+    // Wait for the controller's input buffer to empty before sending the
+    // pulse-reset command
+    let status = Port::new(0x64);
+    for _ in 0..100000 {
+      if status.read_u8() & 2 == 0 {
+        break;
+      }
+    }
+    status.write_u8(0xfe);
+    for _ in 0..100000 {
+      llvm_asm!("pause" : : : : "volatile");
+    }
+
+    // With an empty IDT, the next interrupt cannot be delivered, and neither
+    // can the double fault that follows
+    let empty = crate::idt::IDTDescriptor {
+      size: 0,
+      offset: 0,
+    };
+    crate::idt::lidt(&empty);
+    llvm_asm!("int3" : : : : "volatile");
+  }
+  loop {
+    unsafe { llvm_asm!("hlt" : : : : "volatile") };
+  }
+}
@@ -0,0 +1,449 @@
+//! Parsing for the few ACPI tables the kernel reads. Everything here works on
+//! byte slices copied or mapped from firmware memory, so it can be checked
+//! without real tables.
+
+use alloc::vec::Vec;
+
+/// Every table starts with this header
+pub const SDT_HEADER_SIZE: usize = 36;
+/// Size of the ACPI 1.0 RSDP, which is all that is needed to find the RSDT
+pub const RSDP_SIZE: usize = 20;
+
+pub const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+pub const RSDT_SIGNATURE: &[u8; 4] = b"RSDT";
+pub const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+pub const DSDT_SIGNATURE: &[u8; 4] = b"DSDT";
+pub const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+/// Written to the SLP_EN bit of a PM1 control register to enter the sleep
+/// state whose type is in the SLP_TYP bits
+pub const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+/// Set in PM1 control once the firmware has handed power management to ACPI
+pub const PM1_SCI_ENABLE: u16 = 1;
+
+/// FADT flag showing that the reset register is implemented
+const FADT_RESET_REG_SUPPORTED: u32 = 1 << 10;
+/// Generic address space ID for the x86 I/O port space
+const ADDRESS_SPACE_SYSTEM_IO: u8 = 1;
+
+/// MADT flag showing that the machine also has a pair of 8259 PICs
+const MADT_PCAT_COMPAT: u32 = 1;
+/// Interrupt controller structures listed in the MADT
+const MADT_IO_APIC: u8 = 1;
+const MADT_SOURCE_OVERRIDE: u8 = 2;
+/// Polarity and trigger mode fields of an interrupt source override. A value
+/// of zero in either means the bus default, which for ISA is active high and
+/// edge triggered.
+const MPS_POLARITY_MASK: u16 = 3;
+const MPS_POLARITY_ACTIVE_LOW: u16 = 3;
+const MPS_TRIGGER_MASK: u16 = 3 << 2;
+const MPS_TRIGGER_LEVEL: u16 = 3 << 2;
+
+/// AML opcodes used by the `_S5_` package
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+
+/// ACPI structures are valid when all of their bytes sum to zero
+pub fn checksum_valid(bytes: &[u8]) -> bool {
+  bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+  let field = bytes.get(offset..(offset + 4))?;
+  Some(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+/// Scan memory for a valid RSDP, returning the RSDT address it holds. The RSDP
+/// is always aligned to 16 bytes.
+pub fn find_rsdp(area: &[u8]) -> Option<u32> {
+  let mut offset = 0;
+  while offset + RSDP_SIZE <= area.len() {
+    let candidate = &area[offset..(offset + RSDP_SIZE)];
+    if &candidate[0..8] == RSDP_SIGNATURE && checksum_valid(candidate) {
+      return read_u32(candidate, 16);
+    }
+    offset += 16;
+  }
+  None
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SdtHeader {
+  pub signature: [u8; 4],
+  /// Length of the whole table, including the header
+  pub length: usize,
+}
+
+impl SdtHeader {
+  pub fn parse(bytes: &[u8]) -> Option<SdtHeader> {
+    if bytes.len() < SDT_HEADER_SIZE {
+      return None;
+    }
+    let mut signature = [0; 4];
+    signature.copy_from_slice(&bytes[0..4]);
+    let length = read_u32(bytes, 4)? as usize;
+    if length < SDT_HEADER_SIZE {
+      return None;
+    }
+    Some(SdtHeader {
+      signature,
+      length,
+    })
+  }
+}
+
+/// Check that a complete table has the expected signature and checksum
+pub fn validate_table(table: &[u8], signature: &[u8; 4]) -> bool {
+  match SdtHeader::parse(table) {
+    Some(header) => {
+      &header.signature == signature
+        && header.length <= table.len()
+        && checksum_valid(&table[..header.length])
+    },
+    None => false,
+  }
+}
+
+/// Physical addresses of every table listed in the RSDT
+pub fn rsdt_entries<'a>(rsdt: &'a [u8]) -> impl Iterator<Item = u32> + 'a {
+  let length = SdtHeader::parse(rsdt).map_or(0, |header| header.length.min(rsdt.len()));
+  let entries = rsdt.get(SDT_HEADER_SIZE..length).unwrap_or(&[]);
+  entries.chunks_exact(4).map(|entry| {
+    u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]])
+  })
+}
+
+/// The fields of the FADT needed to change power state
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Fadt {
+  pub dsdt: u32,
+  /// Port that `acpi_enable` is written to if the firmware still owns power
+  /// management. Zero when the system is always in ACPI mode.
+  pub smi_command: u16,
+  pub acpi_enable: u8,
+  pub pm1a_control: u16,
+  /// Zero when the system only has one PM1 control register
+  pub pm1b_control: u16,
+  /// Port and value that reset the machine, if the FADT describes one
+  pub reset: Option<(u16, u8)>,
+}
+
+impl Fadt {
+  pub fn parse(table: &[u8]) -> Option<Fadt> {
+    let length = SdtHeader::parse(table)?.length.min(table.len());
+    let table = &table[..length];
+    let pm1a_control = read_u32(table, 64)? as u16;
+    if pm1a_control == 0 {
+      return None;
+    }
+    // The reset register was added in ACPI 2.0, so older tables end before it
+    let flags = read_u32(table, 112).unwrap_or(0);
+    let reset = if flags & FADT_RESET_REG_SUPPORTED != 0 && table.get(116) == Some(&ADDRESS_SPACE_SYSTEM_IO) {
+      match (read_u32(table, 120), table.get(128)) {
+        (Some(port), Some(value)) if port != 0 && port <= 0xffff => Some((port as u16, *value)),
+        _ => None,
+      }
+    } else {
+      None
+    };
+    Some(Fadt {
+      dsdt: read_u32(table, 40)?,
+      smi_command: read_u32(table, 48)? as u16,
+      acpi_enable: *table.get(52)?,
+      pm1a_control,
+      pm1b_control: read_u32(table, 68)? as u16,
+      reset,
+    })
+  }
+}
+
+/// An I/O APIC, which routes a range of global system interrupts
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct IoApicEntry {
+  pub id: u8,
+  /// Physical address of its registers
+  pub address: u32,
+  /// First global system interrupt it handles
+  pub gsi_base: u32,
+}
+
+/// How an ISA IRQ reaches the I/O APICs
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct IsaRoute {
+  pub gsi: u32,
+  pub active_low: bool,
+  pub level_triggered: bool,
+}
+
+/// The fields of the MADT needed to program the APICs
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Madt {
+  /// Physical address of every processor's local APIC registers
+  pub local_apic: u32,
+  /// Whether the machine also has 8259 PICs, which must be masked
+  pub has_pics: bool,
+  pub io_apics: Vec<IoApicEntry>,
+  /// ISA IRQs that are not wired to the global interrupt of the same number,
+  /// or not with the ISA defaults. The PIT is commonly moved to GSI 2.
+  overrides: Vec<(u8, IsaRoute)>,
+}
+
+impl Madt {
+  pub fn parse(table: &[u8]) -> Option<Madt> {
+    let length = SdtHeader::parse(table)?.length.min(table.len());
+    let table = &table[..length];
+    let mut madt = Madt {
+      local_apic: read_u32(table, 36)?,
+      has_pics: read_u32(table, 40)? & MADT_PCAT_COMPAT != 0,
+      io_apics: Vec::new(),
+      overrides: Vec::new(),
+    };
+    let mut offset = 44;
+    while offset + 2 <= table.len() {
+      let entry_type = table[offset];
+      let entry_length = table[offset + 1] as usize;
+      if entry_length < 2 {
+        break;
+      }
+      let entry = match table.get(offset..(offset + entry_length)) {
+        Some(entry) => entry,
+        None => break,
+      };
+      match entry_type {
+        MADT_IO_APIC if entry_length >= 12 => {
+          madt.io_apics.push(IoApicEntry {
+            id: entry[2],
+            address: read_u32(entry, 4)?,
+            gsi_base: read_u32(entry, 8)?,
+          });
+        },
+        // Only overrides on the ISA bus are defined
+        MADT_SOURCE_OVERRIDE if entry_length >= 10 && entry[2] == 0 => {
+          let flags = u16::from_le_bytes([entry[8], entry[9]]);
+          madt.overrides.push((entry[3], IsaRoute {
+            gsi: read_u32(entry, 4)?,
+            active_low: flags & MPS_POLARITY_MASK == MPS_POLARITY_ACTIVE_LOW,
+            level_triggered: flags & MPS_TRIGGER_MASK == MPS_TRIGGER_LEVEL,
+          }));
+        },
+        _ => (),
+      }
+      offset += entry_length;
+    }
+    Some(madt)
+  }
+
+  /// Find where an ISA IRQ is delivered, following any override
+  pub fn route_isa_irq(&self, irq: u8) -> IsaRoute {
+    let overridden = self.overrides.iter().find(|(source, _)| *source == irq);
+    match overridden {
+      Some((_, route)) => *route,
+      None => IsaRoute {
+        gsi: irq as u32,
+        active_low: false,
+        level_triggered: false,
+      },
+    }
+  }
+
+  /// The I/O APIC handling a global system interrupt, and the input pin it
+  /// arrives on. Each I/O APIC reports how many inputs it has, so the caller
+  /// checks the pin against that.
+  pub fn find_io_apic(&self, gsi: u32) -> Option<(IoApicEntry, u32)> {
+    self.io_apics.iter()
+      .filter(|apic| apic.gsi_base <= gsi)
+      .max_by_key(|apic| apic.gsi_base)
+      .map(|apic| (*apic, gsi - apic.gsi_base))
+  }
+}
+
+/// The SLP_TYP values for one sleep state, for the A and B control registers
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SleepType {
+  pub a: u8,
+  pub b: u8,
+}
+
+/// Value to write to a PM1 control register to enter a sleep state, keeping
+/// the register's other bits
+pub fn sleep_control_value(sleep_type: u8, current: u16) -> u16 {
+  let cleared = current & !(7 << PM1_SLEEP_TYPE_SHIFT);
+  cleared | (((sleep_type & 7) as u16) << PM1_SLEEP_TYPE_SHIFT) | PM1_SLEEP_ENABLE
+}
+
+/// Find the `_S5_` package in the DSDT's AML, which holds the sleep type for
+/// soft-off. Rather than run an AML interpreter, this looks for the name being
+/// defined and decodes the first two integers of the package that follows.
+pub fn find_s5_sleep_type(aml: &[u8]) -> Option<SleepType> {
+  let mut start = 0;
+  while let Some(found) = aml[start..].windows(4).position(|window| window == b"_S5_") {
+    let position = start + found;
+    start = position + 1;
+    // The name is defined as `Name(_S5_, Package() {...})`, optionally with
+    // a root prefix before the name
+    let defined = match position {
+      0 => false,
+      1 => aml[0] == AML_NAME_OP,
+      _ => aml[position - 1] == AML_NAME_OP || (aml[position - 1] == b'\\' && aml[position - 2] == AML_NAME_OP),
+    };
+    if !defined || aml.get(position + 4) != Some(&AML_PACKAGE_OP) {
+      continue;
+    }
+    let package = &aml[(position + 5)..];
+    // PkgLength encodes how many more bytes it uses in its top two bits
+    let length_bytes = 1 + (*package.first()? >> 6) as usize;
+    // Skip the element count too
+    let mut elements = package.get((length_bytes + 1)..)?;
+    let a = read_aml_integer(&mut elements)?;
+    let b = read_aml_integer(&mut elements)?;
+    return Some(SleepType { a, b });
+  }
+  None
+}
+
+fn read_aml_integer(aml: &mut &[u8]) -> Option<u8> {
+  let (value, used) = match *aml.first()? {
+    AML_BYTE_PREFIX => (*aml.get(1)?, 2),
+    AML_ZERO_OP => (0, 1),
+    AML_ONE_OP => (1, 1),
+    _ => return None,
+  };
+  *aml = &aml[used..];
+  Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fix_checksum(bytes: &mut [u8], offset: usize) {
+    bytes[offset] = 0;
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes[offset] = 0u8.wrapping_sub(sum);
+  }
+
+  fn table(signature: &[u8; 4], length: usize) -> Vec<u8> {
+    let mut table = alloc::vec![0; length];
+    table[0..4].copy_from_slice(signature);
+    table[4..8].copy_from_slice(&(length as u32).to_le_bytes());
+    table
+  }
+
+  #[test]
+  fn finds_rsdp() {
+    let mut area = alloc::vec![0u8; 64];
+    // A signature off the 16-byte boundary is ignored
+    area[4..12].copy_from_slice(RSDP_SIGNATURE);
+    area[32..40].copy_from_slice(RSDP_SIGNATURE);
+    area[48..52].copy_from_slice(&0x7fe1234u32.to_le_bytes());
+    assert_eq!(find_rsdp(&area), None);
+    fix_checksum(&mut area[32..52], 8);
+    assert_eq!(find_rsdp(&area), Some(0x7fe1234));
+  }
+
+  #[test]
+  fn lists_rsdt_entries() {
+    let mut rsdt = table(RSDT_SIGNATURE, SDT_HEADER_SIZE + 8);
+    rsdt[36..40].copy_from_slice(&0x1000u32.to_le_bytes());
+    rsdt[40..44].copy_from_slice(&0x2000u32.to_le_bytes());
+    fix_checksum(&mut rsdt, 9);
+    assert!(validate_table(&rsdt, RSDT_SIGNATURE));
+    assert!(!validate_table(&rsdt, FADT_SIGNATURE));
+    let entries: Vec<u32> = rsdt_entries(&rsdt).collect();
+    assert_eq!(entries, [0x1000, 0x2000]);
+    rsdt[20] ^= 1;
+    assert!(!validate_table(&rsdt, RSDT_SIGNATURE));
+  }
+
+  #[test]
+  fn parses_fadt() {
+    let mut fadt = table(FADT_SIGNATURE, 129);
+    fadt[40..44].copy_from_slice(&0x3000u32.to_le_bytes());
+    fadt[48..52].copy_from_slice(&0xb2u32.to_le_bytes());
+    fadt[52] = 0xf1;
+    fadt[64..68].copy_from_slice(&0x604u32.to_le_bytes());
+    assert_eq!(
+      Fadt::parse(&fadt),
+      Some(Fadt {
+        dsdt: 0x3000,
+        smi_command: 0xb2,
+        acpi_enable: 0xf1,
+        pm1a_control: 0x604,
+        pm1b_control: 0,
+        reset: None,
+      }),
+    );
+    fadt[112..116].copy_from_slice(&FADT_RESET_REG_SUPPORTED.to_le_bytes());
+    fadt[116] = ADDRESS_SPACE_SYSTEM_IO;
+    fadt[120..124].copy_from_slice(&0xcf9u32.to_le_bytes());
+    fadt[128] = 0x06;
+    assert_eq!(Fadt::parse(&fadt).unwrap().reset, Some((0xcf9, 0x06)));
+    // ACPI 1.0 tables stop before the reset register
+    let mut short = table(FADT_SIGNATURE, 116);
+    short[64..68].copy_from_slice(&0x604u32.to_le_bytes());
+    assert_eq!(Fadt::parse(&short).unwrap().reset, None);
+  }
+
+  #[test]
+  fn finds_s5_package() {
+    // Name(_S5_, Package(4) { 5, Zero, 0, 0 }) after unrelated AML that
+    // mentions the name without defining it
+    let aml = [
+      0x70, b'_', b'S', b'5', b'_', 0x00,
+      0x08, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0a, 0x05, 0x00, 0x0a, 0x00, 0x0a, 0x00,
+    ];
+    assert_eq!(find_s5_sleep_type(&aml), Some(SleepType { a: 5, b: 0 }));
+    let rooted = [0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x01, 0x0a, 0x07];
+    assert_eq!(find_s5_sleep_type(&rooted), Some(SleepType { a: 1, b: 7 }));
+    assert_eq!(find_s5_sleep_type(b"no sleep states"), None);
+  }
+
+  #[test]
+  fn builds_control_value() {
+    assert_eq!(sleep_control_value(5, PM1_SCI_ENABLE), 0x3401);
+    assert_eq!(sleep_control_value(0, 0x1c00), PM1_SLEEP_ENABLE);
+  }
+
+  #[test]
+  fn parses_madt() {
+    let mut madt = table(MADT_SIGNATURE, 44 + 8 + 12 + 10 + 10);
+    madt[36..40].copy_from_slice(&0xfee00000u32.to_le_bytes());
+    madt[40..44].copy_from_slice(&MADT_PCAT_COMPAT.to_le_bytes());
+    // A processor's local APIC, which is skipped
+    madt[44..46].copy_from_slice(&[0, 8]);
+    madt[52..56].copy_from_slice(&[MADT_IO_APIC, 12, 2, 0]);
+    madt[56..60].copy_from_slice(&0xfec00000u32.to_le_bytes());
+    // The PIT moved to GSI 2
+    madt[64..68].copy_from_slice(&[MADT_SOURCE_OVERRIDE, 10, 0, 0]);
+    madt[68..72].copy_from_slice(&2u32.to_le_bytes());
+    // The SCI, level triggered and active low
+    madt[74..78].copy_from_slice(&[MADT_SOURCE_OVERRIDE, 10, 0, 9]);
+    madt[78..82].copy_from_slice(&9u32.to_le_bytes());
+    madt[82..84].copy_from_slice(&0x000fu16.to_le_bytes());
+
+    let parsed = Madt::parse(&madt).unwrap();
+    assert_eq!(parsed.local_apic, 0xfee00000);
+    assert!(parsed.has_pics);
+    assert_eq!(parsed.io_apics, [IoApicEntry { id: 2, address: 0xfec00000, gsi_base: 0 }]);
+    assert_eq!(parsed.route_isa_irq(0), IsaRoute { gsi: 2, active_low: false, level_triggered: false });
+    assert_eq!(parsed.route_isa_irq(1), IsaRoute { gsi: 1, active_low: false, level_triggered: false });
+    assert_eq!(parsed.route_isa_irq(9), IsaRoute { gsi: 9, active_low: true, level_triggered: true });
+  }
+
+  #[test]
+  fn finds_io_apic_for_gsi() {
+    let mut madt = table(MADT_SIGNATURE, 44 + 24);
+    madt[44..48].copy_from_slice(&[MADT_IO_APIC, 12, 0, 0]);
+    madt[48..52].copy_from_slice(&0xfec00000u32.to_le_bytes());
+    madt[56..60].copy_from_slice(&[MADT_IO_APIC, 12, 1, 0]);
+    madt[60..64].copy_from_slice(&0xfec01000u32.to_le_bytes());
+    madt[64..68].copy_from_slice(&24u32.to_le_bytes());
+    let parsed = Madt::parse(&madt).unwrap();
+    assert_eq!(parsed.find_io_apic(4).map(|(apic, pin)| (apic.id, pin)), Some((0, 4)));
+    assert_eq!(parsed.find_io_apic(30).map(|(apic, pin)| (apic.id, pin)), Some((1, 6)));
+  }
+}
@@ -0,0 +1,247 @@
+//! Kernel assertions check conditions that should always hold, but are too
+//! expensive or too numerous to leave enabled everywhere. `kassert!` checks a
+//! condition at a single point, while `kinvariant!` documents a property of a
+//! data structure that must be true whenever it is observed.
+//!
+//! Checks are compiled in for debug builds, or when the `kassert` feature is
+//! enabled. In release builds without the feature, the condition is never
+//! evaluated and the optimizer removes the check entirely.
+//!
+//! Compiled-in checks can also be switched on or off at boot. Debug builds
+//! check by default; holding Shift while the bootloader runs enables checks in
+//! any build that contains them, and holding Ctrl as well lets the kernel keep
+//! running after a failure instead of panicking. Every failure is recorded, so
+//! problems found on real hardware can be inspected afterwards.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// Whether the assertion macros generate any code
+pub const COMPILED: bool = cfg!(any(debug_assertions, feature = "kassert"));
+
+/// Keyboard flags from the BIOS, passed through by the bootloader
+const BOOT_FLAG_SHIFT: usize = 0x03;
+const BOOT_FLAG_CTRL: usize = 0x04;
+
+static ENABLED: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+static CONTINUE_ON_FAILURE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CheckKind {
+  Assertion,
+  Invariant,
+}
+
+/// Location and text of a failed check
+#[derive(Copy, Clone, Debug)]
+pub struct Failure {
+  pub kind: CheckKind,
+  pub expression: &'static str,
+  pub file: &'static str,
+  pub line: u32,
+}
+
+/// Number of failures kept. Once full, the oldest are overwritten.
+pub const MAX_RECORDED_FAILURES: usize = 16;
+
+pub struct FailureLog {
+  recorded: [Option<Failure>; MAX_RECORDED_FAILURES],
+  /// Total number of failures since boot, including any that were overwritten
+  total: usize,
+}
+
+impl FailureLog {
+  pub const fn new() -> FailureLog {
+    FailureLog {
+      recorded: [None; MAX_RECORDED_FAILURES],
+      total: 0,
+    }
+  }
+
+  pub fn record(&mut self, failure: Failure) {
+    self.recorded[self.total % MAX_RECORDED_FAILURES] = Some(failure);
+    self.total += 1;
+  }
+
+  pub fn get_total(&self) -> usize {
+    self.total
+  }
+
+  /// Fetch a recorded failure, where 0 is the most recent
+  pub fn get_recent(&self, age: usize) -> Option<Failure> {
+    if age >= self.total || age >= MAX_RECORDED_FAILURES {
+      return None;
+    }
+    let index = (self.total - 1 - age) % MAX_RECORDED_FAILURES;
+    self.recorded[index]
+  }
+}
+
+static FAILURES: Mutex<FailureLog> = Mutex::new(FailureLog::new());
+
+pub fn is_enabled() -> bool {
+  ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+  ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn set_continue_on_failure(should_continue: bool) {
+  CONTINUE_ON_FAILURE.store(should_continue, Ordering::Relaxed);
+}
+
+/// Apply the keyboard state captured by the bootloader
+pub fn apply_boot_flags(flags: usize) {
+  if flags & BOOT_FLAG_SHIFT != 0 {
+    set_enabled(true);
+    set_continue_on_failure(flags & BOOT_FLAG_CTRL != 0);
+  }
+}
+
+pub fn get_failure_count() -> usize {
+  FAILURES.lock().get_total()
+}
+
+pub fn get_recent_failure(age: usize) -> Option<Failure> {
+  FAILURES.lock().get_recent(age)
+}
+
+/// Called by the assertion macros when a check fails. Records the failure,
+/// and panics unless the kernel was booted in continue mode.
+#[doc(hidden)]
+pub fn report_failure(kind: CheckKind, expression: &'static str, file: &'static str, line: u32, message: Option<fmt::Arguments>) {
+  let failure = Failure {
+    kind,
+    expression,
+    file,
+    line,
+  };
+  if let Some(mut log) = FAILURES.try_lock() {
+    log.record(failure);
+  }
+  let label = match kind {
+    CheckKind::Assertion => "Assertion",
+    CheckKind::Invariant => "Invariant",
+  };
+  if !CONTINUE_ON_FAILURE.load(Ordering::Relaxed) {
+    match message {
+      Some(args) => panic!("{} failed at {}:{}: {} ({})", label, file, line, expression, args),
+      None => panic!("{} failed at {}:{}: {}", label, file, line, expression),
+    }
+  }
+  print_failure(label, expression, file, line, message);
+}
+
+#[cfg(not(test))]
+fn print_failure(label: &str, expression: &str, file: &str, line: u32, message: Option<fmt::Arguments>) {
+  match message {
+    Some(args) => crate::kprintln!("{} failed at {}:{}: {} ({})", label, file, line, expression, args),
+    None => crate::kprintln!("{} failed at {}:{}: {}", label, file, line, expression),
+  }
+}
+
+#[cfg(test)]
+fn print_failure(_label: &str, _expression: &str, _file: &str, _line: u32, _message: Option<fmt::Arguments>) {
+}
+
+/// Check a condition at a single point in the kernel. An optional message,
+/// with format arguments, is reported alongside the failing expression.
+#[macro_export]
+macro_rules! kassert {
+  ($cond:expr $(,)?) => {
+    if $crate::assertions::COMPILED && $crate::assertions::is_enabled() && !($cond) {
+      $crate::assertions::report_failure(
+        $crate::assertions::CheckKind::Assertion,
+        stringify!($cond),
+        file!(),
+        line!(),
+        None,
+      );
+    }
+  };
+  ($cond:expr, $($arg:tt)+) => {
+    if $crate::assertions::COMPILED && $crate::assertions::is_enabled() && !($cond) {
+      $crate::assertions::report_failure(
+        $crate::assertions::CheckKind::Assertion,
+        stringify!($cond),
+        file!(),
+        line!(),
+        Some(format_args!($($arg)+)),
+      );
+    }
+  };
+}
+
+/// Check a property that a data structure must always satisfy
+#[macro_export]
+macro_rules! kinvariant {
+  ($cond:expr $(,)?) => {
+    if $crate::assertions::COMPILED && $crate::assertions::is_enabled() && !($cond) {
+      $crate::assertions::report_failure(
+        $crate::assertions::CheckKind::Invariant,
+        stringify!($cond),
+        file!(),
+        line!(),
+        None,
+      );
+    }
+  };
+  ($cond:expr, $($arg:tt)+) => {
+    if $crate::assertions::COMPILED && $crate::assertions::is_enabled() && !($cond) {
+      $crate::assertions::report_failure(
+        $crate::assertions::CheckKind::Invariant,
+        stringify!($cond),
+        file!(),
+        line!(),
+        Some(format_args!($($arg)+)),
+      );
+    }
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn failure_at(line: u32) -> Failure {
+    Failure {
+      kind: CheckKind::Assertion,
+      expression: "false",
+      file: "test.rs",
+      line,
+    }
+  }
+
+  #[test]
+  fn records_recent_failures() {
+    let mut log = FailureLog::new();
+    assert!(log.get_recent(0).is_none());
+    log.record(failure_at(1));
+    log.record(failure_at(2));
+    assert_eq!(log.get_total(), 2);
+    assert_eq!(log.get_recent(0).unwrap().line, 2);
+    assert_eq!(log.get_recent(1).unwrap().line, 1);
+    assert!(log.get_recent(2).is_none());
+  }
+
+  #[test]
+  fn overwrites_oldest() {
+    let mut log = FailureLog::new();
+    for line in 0..(MAX_RECORDED_FAILURES as u32 + 3) {
+      log.record(failure_at(line));
+    }
+    assert_eq!(log.get_total(), MAX_RECORDED_FAILURES + 3);
+    assert_eq!(log.get_recent(0).unwrap().line, MAX_RECORDED_FAILURES as u32 + 2);
+    assert_eq!(log.get_recent(MAX_RECORDED_FAILURES - 1).unwrap().line, 3);
+    assert!(log.get_recent(MAX_RECORDED_FAILURES).is_none());
+  }
+
+  #[test]
+  #[should_panic(expected = "Assertion failed")]
+  fn failing_assertion_panics() {
+    set_enabled(true);
+    kassert!(1 + 1 == 3, "math is broken");
+  }
+}
@@ -0,0 +1,277 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use syscall::flags::{AUDIO_FORMAT_S16, AUDIO_FORMAT_STEREO, AUDIO_FORMAT_U8, AUDIO_VOLUME_MAX};
+
+/// Every stream is converted to mono 16-bit samples at this rate before it is
+/// mixed, regardless of the format it was written in
+pub const OUTPUT_RATE: u32 = 22050;
+
+pub const MIN_STREAM_RATE: u32 = 4000;
+pub const MAX_STREAM_RATE: u32 = 48000;
+
+/// Each stream holds at most half a second of audio, at its own rate, so that
+/// a player cannot fill memory faster than the hardware drains it
+const MAX_QUEUED_SECONDS_DIVISOR: u32 = 2;
+
+/// Sample positions are tracked in 16.16 fixed point, so that streams at any
+/// rate step through their samples at the right pace for the output rate
+const FRACTION_BITS: u32 = 16;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct StreamFormat {
+  pub rate: u32,
+  /// One of the AUDIO_FORMAT_* sample types, optionally with
+  /// AUDIO_FORMAT_STEREO
+  pub flags: u32,
+}
+
+impl StreamFormat {
+  /// 8-bit unsigned mono at the output rate, the format a new stream starts in
+  pub const fn default() -> StreamFormat {
+    StreamFormat {
+      rate: OUTPUT_RATE,
+      flags: AUDIO_FORMAT_U8,
+    }
+  }
+
+  pub fn is_valid(&self) -> bool {
+    let sample_type = self.flags & !AUDIO_FORMAT_STEREO;
+    let known = sample_type == AUDIO_FORMAT_U8 || sample_type == AUDIO_FORMAT_S16;
+    known && self.rate >= MIN_STREAM_RATE && self.rate <= MAX_STREAM_RATE
+  }
+
+  fn bytes_per_sample(&self) -> usize {
+    if self.flags & AUDIO_FORMAT_S16 != 0 { 2 } else { 1 }
+  }
+
+  fn channels(&self) -> usize {
+    if self.flags & AUDIO_FORMAT_STEREO != 0 { 2 } else { 1 }
+  }
+
+  /// Size in bytes of one sample for every channel
+  pub fn frame_size(&self) -> usize {
+    self.bytes_per_sample() * self.channels()
+  }
+
+  /// Decode one frame as a mono 16-bit sample, averaging stereo channels
+  fn decode(&self, frame: &[u8]) -> i16 {
+    let channels = self.channels();
+    let mut total: i32 = 0;
+    for channel in 0..channels {
+      total += if self.flags & AUDIO_FORMAT_S16 != 0 {
+        let offset = channel * 2;
+        i16::from_le_bytes([frame[offset], frame[offset + 1]]) as i32
+      } else {
+        ((frame[channel] as i32) - 0x80) << 8
+      };
+    }
+    (total / channels as i32) as i16
+  }
+}
+
+/// PCM written through one handle, waiting to be mixed
+pub struct Stream {
+  format: StreamFormat,
+  volume: u32,
+  samples: VecDeque<i16>,
+  /// Position between the first two queued samples, as a fraction of a sample
+  position: u32,
+}
+
+impl Stream {
+  pub fn new() -> Stream {
+    Stream {
+      format: StreamFormat::default(),
+      volume: AUDIO_VOLUME_MAX,
+      samples: VecDeque::new(),
+      position: 0,
+    }
+  }
+
+  fn capacity(&self) -> usize {
+    (self.format.rate / MAX_QUEUED_SECONDS_DIVISOR) as usize
+  }
+
+  /// Decode as many whole frames as there is room for, returning how many
+  /// bytes were consumed
+  pub fn queue(&mut self, data: &[u8]) -> usize {
+    let frame_size = self.format.frame_size();
+    let room = self.capacity().saturating_sub(self.samples.len());
+    let frames = (data.len() / frame_size).min(room);
+    for frame in data.chunks_exact(frame_size).take(frames) {
+      self.samples.push_back(self.format.decode(frame));
+    }
+    frames * frame_size
+  }
+
+  /// Produce the next output sample, interpolating between queued samples to
+  /// convert from the stream's rate. Returns None once the stream runs dry.
+  fn next_sample(&mut self) -> Option<i32> {
+    let current = *self.samples.get(0)? as i32;
+    let next = self.samples.get(1).map(|s| *s as i32).unwrap_or(current);
+    let fraction = self.position as i32;
+    let sample = current + (((next - current) * fraction) >> FRACTION_BITS);
+    let step = (self.format.rate << FRACTION_BITS) / OUTPUT_RATE;
+    self.position += step;
+    while self.position >= (1 << FRACTION_BITS) && !self.samples.is_empty() {
+      self.samples.pop_front();
+      self.position -= 1 << FRACTION_BITS;
+    }
+    Some(sample * self.volume as i32 / AUDIO_VOLUME_MAX as i32)
+  }
+
+  pub fn get_format(&self) -> StreamFormat {
+    self.format
+  }
+
+  pub fn queued_samples(&self) -> usize {
+    self.samples.len()
+  }
+}
+
+/// Combines the streams of every open handle into a single output
+pub struct Mixer {
+  /// Streams identified by the handle they were opened with
+  streams: Vec<(u32, Stream)>,
+}
+
+impl Mixer {
+  pub const fn new() -> Mixer {
+    Mixer {
+      streams: Vec::new(),
+    }
+  }
+
+  pub fn add_stream(&mut self, id: u32) {
+    self.remove_stream(id);
+    self.streams.push((id, Stream::new()));
+  }
+
+  pub fn remove_stream(&mut self, id: u32) {
+    self.streams.retain(|(stream_id, _)| *stream_id != id);
+  }
+
+  pub fn get_stream(&mut self, id: u32) -> Option<&mut Stream> {
+    self.streams.iter_mut().find(|(stream_id, _)| *stream_id == id).map(|(_, stream)| stream)
+  }
+
+  /// Changing the format drops anything queued in the old one
+  pub fn set_format(&mut self, id: u32, format: StreamFormat) -> Result<(), ()> {
+    if !format.is_valid() {
+      return Err(());
+    }
+    let stream = self.get_stream(id).ok_or(())?;
+    stream.format = format;
+    stream.samples.clear();
+    stream.position = 0;
+    Ok(())
+  }
+
+  pub fn set_volume(&mut self, id: u32, volume: u32) -> Result<(), ()> {
+    let stream = self.get_stream(id).ok_or(())?;
+    stream.volume = volume.min(AUDIO_VOLUME_MAX);
+    Ok(())
+  }
+
+  pub fn get_volume(&self, id: u32) -> Option<u32> {
+    self.streams.iter().find(|(stream_id, _)| *stream_id == id).map(|(_, stream)| stream.volume)
+  }
+
+  /// Fill an output buffer with the sum of every stream, clipped to the range
+  /// of a 16-bit sample. Streams that run dry contribute silence.
+  pub fn mix(&mut self, output: &mut [i16]) {
+    for sample in output.iter_mut() {
+      let mut total: i32 = 0;
+      for (_, stream) in self.streams.iter_mut() {
+        total += stream.next_sample().unwrap_or(0);
+      }
+      *sample = total.max(i16::MIN as i32).min(i16::MAX as i32) as i16;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Mixer, StreamFormat, OUTPUT_RATE};
+  use syscall::flags::{AUDIO_FORMAT_S16, AUDIO_FORMAT_STEREO, AUDIO_FORMAT_U8};
+
+  fn s16(samples: &[i16]) -> alloc::vec::Vec<u8> {
+    samples.iter().flat_map(|s| s.to_le_bytes().to_vec()).collect()
+  }
+
+  #[test]
+  fn decodes_formats() {
+    let mut mixer = Mixer::new();
+    mixer.add_stream(1);
+    let stream = mixer.get_stream(1).unwrap();
+    assert_eq!(stream.queue(&[0x80, 0xff, 0x00]), 3);
+    let mut output = [0; 3];
+    mixer.mix(&mut output);
+    assert_eq!(output, [0, 0x7f00, -0x8000]);
+
+    mixer.set_format(1, StreamFormat { rate: OUTPUT_RATE, flags: AUDIO_FORMAT_S16 | AUDIO_FORMAT_STEREO }).unwrap();
+    let stream = mixer.get_stream(1).unwrap();
+    // A trailing partial frame is left for the next write
+    assert_eq!(stream.queue(&s16(&[1000, 3000, 7])[..5]), 4);
+    mixer.mix(&mut output[..1]);
+    assert_eq!(output[0], 2000);
+  }
+
+  #[test]
+  fn mixes_and_clips() {
+    let mut mixer = Mixer::new();
+    let format = StreamFormat { rate: OUTPUT_RATE, flags: AUDIO_FORMAT_S16 };
+    for id in 1..=2 {
+      mixer.add_stream(id);
+      mixer.set_format(id, format).unwrap();
+    }
+    mixer.get_stream(1).unwrap().queue(&s16(&[100, 30000, -30000]));
+    mixer.get_stream(2).unwrap().queue(&s16(&[50, 30000, -30000, 9]));
+    let mut output = [0; 5];
+    mixer.mix(&mut output);
+    assert_eq!(output, [150, 32767, -32768, 9, 0]);
+  }
+
+  #[test]
+  fn scales_volume() {
+    let mut mixer = Mixer::new();
+    mixer.add_stream(1);
+    mixer.set_format(1, StreamFormat { rate: OUTPUT_RATE, flags: AUDIO_FORMAT_S16 }).unwrap();
+    mixer.set_volume(1, 50).unwrap();
+    assert_eq!(mixer.get_volume(1), Some(50));
+    mixer.set_volume(1, 500).unwrap();
+    assert_eq!(mixer.get_volume(1), Some(100));
+    mixer.set_volume(1, 25).unwrap();
+    mixer.get_stream(1).unwrap().queue(&s16(&[4000]));
+    let mut output = [0; 1];
+    mixer.mix(&mut output);
+    assert_eq!(output, [1000]);
+  }
+
+  #[test]
+  fn resamples() {
+    let mut mixer = Mixer::new();
+    mixer.add_stream(1);
+    // Half the output rate: each input sample spans two output samples, and
+    // the second is halfway to the next input sample
+    mixer.set_format(1, StreamFormat { rate: OUTPUT_RATE / 2, flags: AUDIO_FORMAT_S16 }).unwrap();
+    mixer.get_stream(1).unwrap().queue(&s16(&[0, 1000, 2000]));
+    let mut output = [0; 6];
+    mixer.mix(&mut output);
+    assert_eq!(output, [0, 500, 1000, 1500, 2000, 2000]);
+  }
+
+  #[test]
+  fn limits_queue_and_formats() {
+    let mut mixer = Mixer::new();
+    mixer.add_stream(1);
+    assert!(mixer.set_format(1, StreamFormat { rate: 100, flags: AUDIO_FORMAT_U8 }).is_err());
+    assert!(mixer.set_format(1, StreamFormat { rate: 8000, flags: 0x40 }).is_err());
+    mixer.set_format(1, StreamFormat { rate: 8000, flags: AUDIO_FORMAT_U8 }).unwrap();
+    let data = [0x80; 5000];
+    let stream = mixer.get_stream(1).unwrap();
+    assert_eq!(stream.queue(&data), 4000);
+    assert_eq!(stream.queue(&data), 0);
+    assert_eq!(stream.queued_samples(), 4000);
+  }
+}
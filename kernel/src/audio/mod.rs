@@ -0,0 +1,19 @@
+//! Software mixing for DEV:\AUDIO. Every handle opened on the device gets its
+//! own stream, with its own sample format, rate, and volume, so that several
+//! programs can play at once. A sound card driver pulls the combined output
+//! from here whenever its hardware buffer needs refilling.
+
+use spin::Mutex;
+
+pub mod mixer;
+
+use mixer::Mixer;
+
+pub static MIXER: Mutex<Mixer> = Mutex::new(Mixer::new());
+
+/// Fill a hardware buffer with the next mono 16-bit samples of every stream
+/// mixed together, at mixer::OUTPUT_RATE. When nothing is playing, the buffer
+/// is filled with silence.
+pub fn fill_output(output: &mut [i16]) {
+  MIXER.lock().mix(output);
+}
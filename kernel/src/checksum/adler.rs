@@ -0,0 +1,67 @@
+//! Adler-32, the checksum used by zlib streams. It is weaker than a CRC, but
+//! needs no table and is quick to compute a byte at a time.
+
+const MODULUS: u32 = 65521;
+
+/// Number of bytes that can be summed before the totals must be reduced, so
+/// that they never overflow 32 bits
+const MAX_RUN: usize = 5552;
+
+pub fn adler32(data: &[u8]) -> u32 {
+  let mut adler = Adler32::new();
+  adler.update(data);
+  adler.finish()
+}
+
+/// An Adler-32 computed over data that arrives in pieces
+#[derive(Copy, Clone)]
+pub struct Adler32 {
+  a: u32,
+  b: u32,
+}
+
+impl Adler32 {
+  pub const fn new() -> Adler32 {
+    Adler32 {
+      a: 1,
+      b: 0,
+    }
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    for run in data.chunks(MAX_RUN) {
+      for byte in run {
+        self.a += *byte as u32;
+        self.b += self.a;
+      }
+      self.a %= MODULUS;
+      self.b %= MODULUS;
+    }
+  }
+
+  pub fn finish(&self) -> u32 {
+    (self.b << 16) | self.a
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{adler32, Adler32};
+
+  #[test]
+  fn matches_reference() {
+    assert_eq!(adler32(b""), 1);
+    assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+  }
+
+  #[test]
+  fn long_input_does_not_overflow() {
+    let data = [0xff; 20000];
+    let mut adler = Adler32::new();
+    for piece in data.chunks(7000) {
+      adler.update(piece);
+    }
+    assert_eq!(adler.finish(), adler32(&data));
+    assert_eq!(adler32(&data), 0x9f51d664);
+  }
+}
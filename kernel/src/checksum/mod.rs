@@ -0,0 +1,20 @@
+//! Checksums and digests shared by the rest of the kernel. Anything that needs
+//! to detect corrupted data, like file transfers over a serial line or images
+//! loaded from disk, should use these instead of carrying its own copy.
+//!
+//! Each algorithm has a function that checks a whole buffer at once, and a
+//! type that accepts the data in pieces for input that arrives gradually.
+
+pub mod adler;
+pub mod crc;
+pub mod sha1;
+
+pub use adler::adler32;
+pub use crc::{crc16, crc32};
+pub use sha1::sha1;
+
+/// Eight-bit sum of every byte, the simplest check, used by the original
+/// XMODEM protocol
+pub fn sum8(data: &[u8]) -> u8 {
+  data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
@@ -0,0 +1,147 @@
+//! SHA-1, for verifying that a file or image matches a known digest. SHA-1 is
+//! no longer safe against deliberate collisions, so it is only meant to catch
+//! corruption, not tampering.
+
+pub const DIGEST_SIZE: usize = 20;
+
+const BLOCK_SIZE: usize = 64;
+
+pub fn sha1(data: &[u8]) -> [u8; DIGEST_SIZE] {
+  let mut hash = Sha1::new();
+  hash.update(data);
+  hash.finish()
+}
+
+/// A SHA-1 digest computed over data that arrives in pieces
+#[derive(Copy, Clone)]
+pub struct Sha1 {
+  state: [u32; 5],
+  /// Input that does not yet fill a whole block
+  pending: [u8; BLOCK_SIZE],
+  pending_length: usize,
+  /// Total number of bytes passed to `update`
+  length: u64,
+}
+
+impl Sha1 {
+  pub const fn new() -> Sha1 {
+    Sha1 {
+      state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0],
+      pending: [0; BLOCK_SIZE],
+      pending_length: 0,
+      length: 0,
+    }
+  }
+
+  pub fn update(&mut self, mut data: &[u8]) {
+    self.length += data.len() as u64;
+    if self.pending_length > 0 {
+      let copy = (BLOCK_SIZE - self.pending_length).min(data.len());
+      self.pending[self.pending_length..(self.pending_length + copy)].copy_from_slice(&data[..copy]);
+      self.pending_length += copy;
+      data = &data[copy..];
+      if self.pending_length < BLOCK_SIZE {
+        return;
+      }
+      let block = self.pending;
+      self.process_block(&block);
+      self.pending_length = 0;
+    }
+    let mut blocks = data.chunks_exact(BLOCK_SIZE);
+    for block in &mut blocks {
+      self.process_block(block);
+    }
+    let rest = blocks.remainder();
+    self.pending[..rest.len()].copy_from_slice(rest);
+    self.pending_length = rest.len();
+  }
+
+  /// Pad the input and produce the digest. The padding is applied to a copy,
+  /// so more data can still be added afterwards.
+  pub fn finish(&self) -> [u8; DIGEST_SIZE] {
+    let mut last = *self;
+    let bit_length = self.length.wrapping_mul(8);
+    last.update(&[0x80]);
+    while last.pending_length != BLOCK_SIZE - 8 {
+      last.update(&[0]);
+    }
+    last.update(&bit_length.to_be_bytes());
+
+    let mut digest = [0; DIGEST_SIZE];
+    for (index, word) in last.state.iter().enumerate() {
+      digest[(index * 4)..(index * 4 + 4)].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+  }
+
+  fn process_block(&mut self, block: &[u8]) {
+    let mut schedule = [0u32; 80];
+    for (index, word) in block.chunks_exact(4).enumerate() {
+      schedule[index] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for index in 16..80 {
+      schedule[index] = (schedule[index - 3] ^ schedule[index - 8] ^ schedule[index - 14] ^ schedule[index - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = self.state;
+    for (index, word) in schedule.iter().enumerate() {
+      let (f, k) = match index {
+        0..=19 => ((b & c) | (!b & d), 0x5a827999),
+        20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+        40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+        _ => (b ^ c ^ d, 0xca62c1d6),
+      };
+      let temp = a.rotate_left(5)
+        .wrapping_add(f)
+        .wrapping_add(e)
+        .wrapping_add(k)
+        .wrapping_add(*word);
+      e = d;
+      d = c;
+      c = b.rotate_left(30);
+      b = a;
+      a = temp;
+    }
+
+    self.state[0] = self.state[0].wrapping_add(a);
+    self.state[1] = self.state[1].wrapping_add(b);
+    self.state[2] = self.state[2].wrapping_add(c);
+    self.state[3] = self.state[3].wrapping_add(d);
+    self.state[4] = self.state[4].wrapping_add(e);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{sha1, Sha1, DIGEST_SIZE};
+
+  fn hex(digest: [u8; DIGEST_SIZE]) -> alloc::string::String {
+    use core::fmt::Write;
+    let mut text = alloc::string::String::new();
+    for byte in digest.iter() {
+      write!(text, "{:02x}", byte).unwrap();
+    }
+    text
+  }
+
+  #[test]
+  fn matches_reference() {
+    assert_eq!(hex(sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(hex(sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    assert_eq!(
+      hex(sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+      "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+    );
+  }
+
+  #[test]
+  fn hashes_in_pieces() {
+    let data = [b'a'; 1000];
+    let mut hash = Sha1::new();
+    for piece in data.chunks(37) {
+      hash.update(piece);
+    }
+    assert_eq!(hex(hash.finish()), "291e9a6c66994949b57ba5e650361e98fc36b1ba");
+    assert_eq!(hash.finish(), sha1(&data));
+  }
+}
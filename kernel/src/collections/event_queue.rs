@@ -0,0 +1,87 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use crate::files::handle::LocalHandle;
+
+/// An event that can be queued for listeners and read back as bytes
+pub trait Event: Copy {
+  /// Size of the event when copied into a read buffer
+  const SIZE: usize;
+  /// Each listener keeps at most this many unread events. If a listener falls
+  /// behind, the oldest events are dropped.
+  const MAX_PENDING: usize;
+
+  fn write_bytes(&self, buffer: &mut [u8]);
+}
+
+/**
+ * Tracks every handle listening to a stream of events, like the devices that
+ * report drive changes or memory pressure. Each listener receives its own copy
+ * of every event published after it started listening, and reads them back as
+ * a series of fixed-size records.
+ * Space for each listener's events is reserved when it starts listening, so
+ * publishing never needs to allocate.
+ */
+pub struct EventQueue<T> {
+  listeners: BTreeMap<LocalHandle, VecDeque<T>>,
+}
+
+impl<T> EventQueue<T> {
+  pub const fn new() -> EventQueue<T> {
+    EventQueue {
+      listeners: BTreeMap::new(),
+    }
+  }
+}
+
+impl<T: Event> EventQueue<T> {
+  pub fn listen(&mut self, handle: LocalHandle) {
+    self.listeners.insert(handle, VecDeque::with_capacity(T::MAX_PENDING));
+  }
+
+  pub fn stop_listening(&mut self, handle: LocalHandle) {
+    self.listeners.remove(&handle);
+  }
+
+  /// Queue an event for every listener
+  pub fn publish(&mut self, event: T) {
+    for (_, pending) in self.listeners.iter_mut() {
+      Self::push(pending, event);
+    }
+  }
+
+  /// Queue an event for a single listener
+  pub fn publish_to(&mut self, handle: LocalHandle, event: T) {
+    if let Some(pending) = self.listeners.get_mut(&handle) {
+      Self::push(pending, event);
+    }
+  }
+
+  fn push(pending: &mut VecDeque<T>, event: T) {
+    if pending.len() >= T::MAX_PENDING {
+      pending.pop_front();
+    }
+    pending.push_back(event);
+  }
+
+  pub fn has_pending(&self, handle: LocalHandle) -> bool {
+    match self.listeners.get(&handle) {
+      Some(pending) => pending.len() > 0,
+      None => false,
+    }
+  }
+
+  /// Copy as many whole events as fit into the buffer, returning the number of
+  /// bytes written
+  pub fn read(&mut self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let pending = self.listeners.get_mut(&handle).ok_or(())?;
+    let mut written = 0;
+    while written + T::SIZE <= buffer.len() {
+      let event = match pending.pop_front() {
+        Some(event) => event,
+        None => break,
+      };
+      event.write_bytes(&mut buffer[written..(written + T::SIZE)]);
+      written += T::SIZE;
+    }
+    Ok(written)
+  }
+}
@@ -1,3 +1,7 @@
+pub mod event_queue;
 pub mod slotlist;
+pub mod timerwheel;
 
+pub use event_queue::{Event, EventQueue};
 pub use slotlist::SlotList;
+pub use timerwheel::{TimerId, TimerWheel};
@@ -57,6 +57,11 @@ impl<T: Sized> SlotList<T> {
     }
   }
 
+  /// Iterate over the items in every occupied slot
+  pub fn iter(&self) -> impl Iterator<Item = &T> {
+    self.slots.iter().filter_map(|slot| slot.as_ref())
+  }
+
   pub fn remove(&mut self, index: usize) -> Option<T> {
     let entry = self.slots.get_mut(index)?;
     let prev = entry.take();
@@ -116,4 +121,14 @@ mod tests {
     assert_eq!(list.insert(55), 1);
     assert_eq!(list.insert(66), 3);
   }
+
+  #[test]
+  fn iterating_items() {
+    let mut list: SlotList<u32> = SlotList::new();
+    list.insert(1);
+    list.insert(2);
+    list.insert(3);
+    list.remove(1);
+    assert_eq!(list.iter().copied().collect::<alloc::vec::Vec<u32>>(), [1, 3]);
+  }
 }
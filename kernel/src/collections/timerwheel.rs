@@ -0,0 +1,159 @@
+use alloc::vec::Vec;
+
+/// Number of buckets in the wheel. Timers further out than this many ticks
+/// share buckets with nearer ones, and are skipped until their turn comes.
+const WHEEL_SIZE: usize = 64;
+
+/// Identifies a scheduled timer, so that it can be cancelled
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TimerId(u32);
+
+struct Entry<T> {
+  id: TimerId,
+  expires: u64,
+  item: T,
+}
+
+/// TimerWheel holds items until a number of ticks have passed. Timers are
+/// hashed into a ring of buckets by the tick they expire on, so each tick only
+/// looks at one bucket rather than every pending timer. The wheel does not
+/// track time itself: its owner calls `advance` once per elapsed tick.
+/// The number of pending timers is bounded, so a runaway driver cannot fill
+/// the heap with work that will never run.
+pub struct TimerWheel<T> {
+  buckets: Vec<Vec<Entry<T>>>,
+  current_tick: u64,
+  next_id: u32,
+  pending: usize,
+  capacity: usize,
+}
+
+impl<T> TimerWheel<T> {
+  pub const fn new(capacity: usize) -> TimerWheel<T> {
+    TimerWheel {
+      buckets: Vec::new(),
+      current_tick: 0,
+      next_id: 1,
+      pending: 0,
+      capacity,
+    }
+  }
+
+  pub fn get_current_tick(&self) -> u64 {
+    self.current_tick
+  }
+
+  pub fn get_pending_count(&self) -> usize {
+    self.pending
+  }
+
+  /// Hold an item until `delay` ticks have passed. A delay of zero fires on
+  /// the next tick. If the wheel is full, the item is handed back.
+  pub fn schedule(&mut self, delay: u64, item: T) -> Result<TimerId, T> {
+    if self.pending >= self.capacity {
+      return Err(item);
+    }
+    if self.buckets.is_empty() {
+      self.buckets.resize_with(WHEEL_SIZE, Vec::new);
+    }
+    let id = TimerId(self.next_id);
+    self.next_id = self.next_id.wrapping_add(1).max(1);
+    let expires = self.current_tick + delay.max(1);
+    let bucket = (expires % WHEEL_SIZE as u64) as usize;
+    self.buckets[bucket].push(Entry { id, expires, item });
+    self.pending += 1;
+    Ok(id)
+  }
+
+  /// Remove a timer before it fires, returning its item. Returns None if the
+  /// timer has already fired or been cancelled.
+  pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+    for bucket in self.buckets.iter_mut() {
+      if let Some(index) = bucket.iter().position(|entry| entry.id == id) {
+        self.pending -= 1;
+        return Some(bucket.swap_remove(index).item);
+      }
+    }
+    None
+  }
+
+  /// Move forward by one tick, returning every item that expired on it in the
+  /// order they were scheduled
+  pub fn advance(&mut self) -> Vec<T> {
+    self.current_tick += 1;
+    let mut expired = Vec::new();
+    if self.buckets.is_empty() {
+      return expired;
+    }
+    let now = self.current_tick;
+    let bucket = &mut self.buckets[(now % WHEEL_SIZE as u64) as usize];
+    let mut index = 0;
+    while index < bucket.len() {
+      if bucket[index].expires <= now {
+        expired.push(bucket.remove(index).item);
+      } else {
+        index += 1;
+      }
+    }
+    self.pending -= expired.len();
+    expired
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::TimerWheel;
+
+  #[test]
+  fn fires_after_delay() {
+    let mut wheel = TimerWheel::new(8);
+    wheel.schedule(3, 'a').unwrap();
+    wheel.schedule(1, 'b').unwrap();
+    assert_eq!(wheel.advance(), ['b']);
+    assert!(wheel.advance().is_empty());
+    assert_eq!(wheel.advance(), ['a']);
+    assert_eq!(wheel.get_pending_count(), 0);
+  }
+
+  #[test]
+  fn zero_delay_fires_next_tick() {
+    let mut wheel = TimerWheel::new(8);
+    wheel.schedule(0, 1).unwrap();
+    wheel.schedule(0, 2).unwrap();
+    assert_eq!(wheel.advance(), [1, 2]);
+  }
+
+  #[test]
+  fn long_delays_wrap_around() {
+    let mut wheel = TimerWheel::new(8);
+    wheel.schedule(70, "late").unwrap();
+    wheel.schedule(6, "early").unwrap();
+    let mut fired = alloc::vec::Vec::new();
+    for _ in 0..70 {
+      for item in wheel.advance() {
+        fired.push((wheel.get_current_tick(), item));
+      }
+    }
+    assert_eq!(fired, [(6, "early"), (70, "late")]);
+  }
+
+  #[test]
+  fn cancelled_timers_do_not_fire() {
+    let mut wheel = TimerWheel::new(8);
+    let id = wheel.schedule(2, 5).unwrap();
+    assert_eq!(wheel.cancel(id), Some(5));
+    assert_eq!(wheel.cancel(id), None);
+    wheel.advance();
+    assert!(wheel.advance().is_empty());
+  }
+
+  #[test]
+  fn bounded_capacity() {
+    let mut wheel = TimerWheel::new(2);
+    assert!(wheel.schedule(1, 1).is_ok());
+    assert!(wheel.schedule(1, 2).is_ok());
+    assert_eq!(wheel.schedule(1, 3), Err(3));
+    wheel.advance();
+    assert!(wheel.schedule(1, 3).is_ok());
+  }
+}
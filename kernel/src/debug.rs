@@ -1,5 +1,36 @@
 use core::fmt::{self, Write};
-use crate::{devices, interrupts};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::{devices, interrupts, time};
+use crate::hardware::qemu;
+
+/// Whether klog entries are prefixed with the time since boot
+static LOG_TIMESTAMPS: AtomicBool = AtomicBool::new(cfg!(feature = "log_timestamps"));
+
+/// Whether kprint output is copied to the emulator's debug console
+static MIRROR_DEBUGCON: AtomicBool = AtomicBool::new(cfg!(feature = "debugcon"));
+
+/// Set at boot if the debug console responded
+static DEBUGCON_PRESENT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_log_timestamps(enabled: bool) {
+  LOG_TIMESTAMPS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn set_debugcon_mirror(enabled: bool) {
+  MIRROR_DEBUGCON.store(enabled, Ordering::Relaxed);
+}
+
+/// Check for the emulator's debug console. Output is only mirrored once the
+/// console has been found, so nothing is written to the port on real hardware.
+pub fn detect_debugcon() {
+  DEBUGCON_PRESENT.store(qemu::debugcon_present(), Ordering::Relaxed);
+}
+
+fn mirror_to_debugcon(args: fmt::Arguments) {
+  if MIRROR_DEBUGCON.load(Ordering::Relaxed) && DEBUGCON_PRESENT.load(Ordering::Relaxed) {
+    let _ = qemu::DebugConsole::new().write_fmt(args);
+  }
+}
 
 #[cfg(not(feature = "testing"))]
 pub fn _kprint(args: fmt::Arguments) {
@@ -8,6 +39,7 @@ pub fn _kprint(args: fmt::Arguments) {
   unsafe {
     devices::VGA_TEXT.write_fmt(args).unwrap();
   }
+  mirror_to_debugcon(args);
   if int_reenable {
     interrupts::sti();
   }
@@ -19,6 +51,7 @@ pub fn _kprint(args: fmt::Arguments) {
     let serial = devices::get_raw_serial();
     serial.write_fmt(args).unwrap();
   }
+  mirror_to_debugcon(args);
 }
 
 #[macro_export]
@@ -31,3 +64,23 @@ macro_rules! kprintln {
   () => ($crate::kprint!("\n"));
   ($($arg:tt)*) => ($crate::kprint!("{}\n", format_args!($($arg)*)));
 }
+
+/// Print a kernel log entry. When timestamps are enabled, the entry starts with
+/// the seconds since boot, to the microsecond, so that hardware events can be
+/// lined up with the boot time reported at startup and in PROC:\UPTIME.
+pub fn _klog(args: fmt::Arguments) {
+  if LOG_TIMESTAMPS.load(Ordering::Relaxed) {
+    let uptime = time::system::get_uptime().0;
+    let seconds = uptime / 10000000;
+    let micros = (uptime % 10000000) / 10;
+    _kprint(format_args!("[{:5}.{:06}] {}\n", seconds, micros, args));
+  } else {
+    _kprint(format_args!("{}\n", args));
+  }
+}
+
+/// Print a line to the kernel log, with a timestamp prefix if enabled
+#[macro_export]
+macro_rules! klog {
+  ($($arg:tt)*) => ($crate::debug::_klog(format_args!($($arg)*)));
+}
@@ -0,0 +1,54 @@
+//! Deterministic mode makes runs under an emulator reproducible, so automated
+//! regression tests can compare serial logs byte for byte.
+//!
+//! Normally the clock follows the RTC and the PIT, both of which depend on the
+//! host: the boot time differs between runs, and the number of ticks that
+//! elapse between two points in the kernel varies with host load. In
+//! deterministic mode the clock starts at a fixed time, and PIT interrupts
+//! only wake the CPU. Time instead advances by exactly one tick each time the
+//! idle loop completes a scheduling round, so sleeping processes wake at the
+//! same point in every run. Anything that needs a seed receives a fixed one.
+//!
+//! The mode is enabled by default when the `deterministic` feature is set, or
+//! at boot by holding Alt while the bootloader runs.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::time::timestamp::Timestamp;
+
+/// Keyboard flag from the BIOS, passed through by the bootloader
+const BOOT_FLAG_ALT: usize = 0x08;
+
+/// Wall-clock time at boot: midnight on 1 January 1980
+pub const FIXED_BOOT_TIME: Timestamp = Timestamp(0);
+
+/// Seed returned to every caller in place of sampled entropy
+pub const FIXED_SEED: u32 = 0x1980_0101;
+
+static ENABLED: AtomicBool = AtomicBool::new(cfg!(feature = "deterministic"));
+
+pub fn is_enabled() -> bool {
+  ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+  ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Apply the keyboard state captured by the bootloader
+pub fn apply_boot_flags(flags: usize) {
+  if flags & BOOT_FLAG_ALT != 0 {
+    set_enabled(true);
+  }
+}
+
+/// Produce a seed for a pseudo-random generator. Outside of deterministic mode
+/// it mixes the wall clock with the uptime, which differ on every boot.
+#[cfg(not(test))]
+pub fn get_seed() -> u32 {
+  if is_enabled() {
+    return FIXED_SEED;
+  }
+  let now = crate::time::system::get_system_time().0;
+  let uptime = crate::time::system::get_uptime().0;
+  (now ^ (now >> 32) ^ uptime.rotate_left(16)) as u32
+}
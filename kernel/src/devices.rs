@@ -1,8 +1,10 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
+use crate::disks;
 use crate::drivers::{self, com::serial::SerialPort};
-use crate::hardware::{dma, floppy, pic, pit, rtc};
+use crate::hardware::{ata, dma, floppy, pic, pit, ps2mouse, rtc};
 use crate::hardware::vga::text_mode;
+use crate::interrupts;
 use crate::memory::address::VirtualAddress;
 use crate::tty;
 use spin::{Mutex, RwLock};
@@ -16,33 +18,81 @@ pub static mut KEYBOARD: Option<Arc<Mutex<drivers::keyboard::Keyboard>>> = None;
 pub static COM1: SerialPort = SerialPort::new(0x3f8);
 static mut COM1_DIRECT: SerialPort = SerialPort::new(0x3f8);
 
+pub static MOUSE: ps2mouse::Ps2Mouse = ps2mouse::Ps2Mouse::new();
+
 pub static DMA: dma::DMA = dma::DMA::new();
 pub static FLOPPY: floppy::FloppyController = floppy::FloppyController::new();
+pub static ATA_PRIMARY: ata::AtaChannel = ata::AtaChannel::new(0x1f0, 0x3f6);
+pub static ATA_SECONDARY: ata::AtaChannel = ata::AtaChannel::new(0x170, 0x376);
+
+const RAMDISK_BLOCK_SIZE: usize = 512;
+const RAMDISK_BLOCKS: usize = 2880;
 
 pub static DEV: RwLock<drivers::DeviceDrivers> = RwLock::new(drivers::DeviceDrivers::new());
 
 pub unsafe fn init() {
-  PIC.init();
+  interrupts::controller::init();
   PIT.set_divider(11932); // approximately 100Hz
+  register_irq(0, interrupts::pic::pit);
 
   {
     let mut drivers = DEV.write();
     drivers.register_driver("ZERO", Arc::new(Box::new(drivers::zero::ZeroDevice::new())));
-    drivers.register_driver("NULL", Arc::new(Box::new(drivers::null::NullDevice::new())));
-    drivers.register_driver("COM1", Arc::new(Box::new(drivers::com::ComDevice::new(&COM1))));
+    let null = drivers.register_driver("NULL", Arc::new(Box::new(drivers::null::NullDevice::new())));
+    drivers.register_driver("FULL", Arc::new(Box::new(drivers::full::FullDevice::new())));
+    let com1_device = drivers::com::ComDevice::new(&COM1);
+    let com1 = drivers.register_driver("COM1", Arc::new(Box::new(drivers::line::LineDevice::new(Box::new(com1_device)))));
     
     let kbd = Arc::new(Mutex::new(drivers::keyboard::Keyboard::new()));
     let kbd_clone = Arc::clone(&kbd);
     KEYBOARD = Some(kbd);
     drivers.register_driver("KBD", Arc::new(Box::new(drivers::keyboard::KeyboardDevice::new(kbd_clone))));
 
-    drivers.register_driver("TTY0", Arc::new(Box::new(tty::device::TTYDevice::for_tty(0))));
+    let tty0 = drivers.register_driver("TTY0", Arc::new(Box::new(tty::device::TTYDevice::for_tty(0))));
     drivers.register_driver("TTY1", Arc::new(Box::new(tty::device::TTYDevice::for_tty(1))));
+    drivers.register_driver("SCREEN", Arc::new(Box::new(tty::screen::ScreenDevice::new())));
+
+    let fd0 = drivers.register_driver("FD0", Arc::new(Box::new(drivers::floppy::FloppyDevice::new(0))));
+    disks::blockio::register(fd0, Arc::new(drivers::floppy::FloppyDevice::new(0)));
+
+    // A scratch disk the size of a floppy, which can be snapshotted and
+    // rolled back between runs of destructive tests
+    let ramdisk = Arc::new(disks::snapshot::SnapshotDevice::new(Arc::new(
+      disks::ramdisk::RamDisk::new(RAMDISK_BLOCK_SIZE, RAMDISK_BLOCKS),
+    )));
+    let ram0 = drivers.register_driver("RAM0", Arc::new(Box::new(drivers::ramdisk::RamDiskDevice::new(Arc::clone(&ramdisk)))));
+    disks::blockio::register(ram0, ramdisk);
 
-    drivers.register_driver("FD0", Arc::new(Box::new(drivers::floppy::FloppyDevice::new(0))));
+    drivers.register_driver("AUDIO", Arc::new(Box::new(drivers::audio::AudioDevice::new())));
+
+    drivers.register_driver("MOUNTEV", Arc::new(Box::new(drivers::mountev::MountEventDevice::new())));
+    drivers.register_driver("MEMEV", Arc::new(Box::new(drivers::memev::MemoryEventDevice::new())));
+
+    // The names DOS reserves in every directory. There is no printer driver,
+    // so printing goes nowhere.
+    drivers.register_alias("NUL", null);
+    drivers.register_alias("CON", tty0);
+    drivers.register_alias("AUX", com1);
+    drivers.register_alias("PRN", null);
 
     COM1.init();
   }
+
+  register_irq(1, interrupts::pic::keyboard);
+  register_irq(4, interrupts::pic::com1);
+  register_irq(6, interrupts::pic::floppy);
+
+  if MOUSE.init() {
+    register_irq(12, interrupts::pic::mouse);
+  }
+}
+
+/// Attach a built-in driver's handler. The lines are all free at boot, so
+/// this cannot fail.
+fn register_irq(irq: u8, handler: interrupts::irq::IrqHandler) {
+  if interrupts::irq::register_handler(irq, handler).is_err() {
+    panic!("Could not register a handler for IRQ {}", irq);
+  }
 }
 
 pub fn get_device_number_by_name(filename: &[u8; 8]) -> Option<usize> {
@@ -50,6 +100,11 @@ pub fn get_device_number_by_name(filename: &[u8; 8]) -> Option<usize> {
   drivers.get_device_number_by_name(filename)
 }
 
+pub fn get_device_entry(index: usize) -> Option<(drivers::DeviceName, usize)> {
+  let drivers = DEV.read();
+  drivers.get_device_entry(index)
+}
+
 pub unsafe fn get_raw_serial() -> &'static mut SerialPort {
   &mut COM1_DIRECT
 }
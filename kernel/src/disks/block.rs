@@ -0,0 +1,137 @@
+//! Block devices are disks addressed in whole blocks, rather than through the
+//! byte stream a DeviceDriver exposes on DEV:. Filesystems read and write
+//! blocks through a device's request queue, which lets requests from several
+//! processes be merged into larger transfers before they reach the hardware.
+
+use alloc::vec;
+
+/// A disk that transfers data in fixed-size blocks. Buffers passed to the
+/// device are always a whole number of blocks long.
+pub trait BlockDevice {
+  /// Size of one block, in bytes
+  fn block_size(&self) -> usize;
+
+  /// Total number of blocks on the device
+  fn capacity(&self) -> usize;
+
+  /// Fill the buffer with consecutive blocks, starting at `lba`
+  fn read_blocks(&self, lba: usize, buffer: &mut [u8]) -> Result<(), ()>;
+
+  /// Write consecutive blocks from the buffer, starting at `lba`
+  fn write_blocks(&self, lba: usize, buffer: &[u8]) -> Result<(), ()>;
+
+  /// Whether a transfer of `count` blocks starting at `lba` stays on the
+  /// device
+  fn contains(&self, lba: usize, count: usize) -> bool {
+    lba.checked_add(count).map_or(false, |end| end <= self.capacity())
+  }
+}
+
+/// Read bytes from any offset on a block device, without the caller needing
+/// to know its block size. Whole blocks in the middle of the range are read
+/// straight into the buffer; only a partial block at either end is read into
+/// a separate buffer and copied. Returns the number of bytes read, which falls
+/// short when the range runs past the end of the device.
+pub fn read_bytes<D: BlockDevice + ?Sized>(device: &D, offset: usize, buffer: &mut [u8]) -> Result<usize, ()> {
+  let block_size = device.block_size();
+  let end = clip_range(device, offset, buffer.len());
+  let mut position = offset;
+  while position < end {
+    let done = position - offset;
+    let lba = position / block_size;
+    let local_offset = position % block_size;
+    if local_offset == 0 && end - position >= block_size {
+      let whole = (end - position) / block_size * block_size;
+      device.read_blocks(lba, &mut buffer[done..(done + whole)])?;
+      position += whole;
+    } else {
+      let chunk = (block_size - local_offset).min(end - position);
+      let mut block = vec![0; block_size];
+      device.read_blocks(lba, &mut block)?;
+      buffer[done..(done + chunk)].copy_from_slice(&block[local_offset..(local_offset + chunk)]);
+      position += chunk;
+    }
+  }
+  Ok(end.saturating_sub(offset))
+}
+
+/// Write bytes at any offset on a block device. A block that is only partly
+/// covered by the write is read first, so that the bytes around the write are
+/// kept. Returns the number of bytes written, which falls short when the range
+/// runs past the end of the device.
+pub fn write_bytes<D: BlockDevice + ?Sized>(device: &D, offset: usize, buffer: &[u8]) -> Result<usize, ()> {
+  let block_size = device.block_size();
+  let end = clip_range(device, offset, buffer.len());
+  let mut position = offset;
+  while position < end {
+    let done = position - offset;
+    let lba = position / block_size;
+    let local_offset = position % block_size;
+    if local_offset == 0 && end - position >= block_size {
+      let whole = (end - position) / block_size * block_size;
+      device.write_blocks(lba, &buffer[done..(done + whole)])?;
+      position += whole;
+    } else {
+      let chunk = (block_size - local_offset).min(end - position);
+      let mut block = vec![0; block_size];
+      device.read_blocks(lba, &mut block)?;
+      block[local_offset..(local_offset + chunk)].copy_from_slice(&buffer[done..(done + chunk)]);
+      device.write_blocks(lba, &block)?;
+      position += chunk;
+    }
+  }
+  Ok(end.saturating_sub(offset))
+}
+
+/// End of a byte range, limited to the size of the device
+fn clip_range<D: BlockDevice + ?Sized>(device: &D, offset: usize, length: usize) -> usize {
+  let device_size = device.capacity().saturating_mul(device.block_size());
+  offset.saturating_add(length).min(device_size)
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::{read_bytes, write_bytes, BlockDevice};
+  use super::super::ramdisk::RamDisk;
+
+  fn filled_disk() -> RamDisk {
+    let disk = RamDisk::new(16, 4);
+    let contents: Vec<u8> = (0..64).collect();
+    disk.write_blocks(0, &contents).unwrap();
+    disk
+  }
+
+  #[test]
+  fn reads_unaligned_ranges() {
+    let disk = filled_disk();
+    let mut buffer = [0; 40];
+    assert_eq!(read_bytes(&disk, 5, &mut buffer), Ok(40));
+    let expected: Vec<u8> = (5..45).collect();
+    assert_eq!(&buffer[..], &expected[..]);
+    let mut small = [0; 3];
+    assert_eq!(read_bytes(&disk, 17, &mut small), Ok(3));
+    assert_eq!(small, [17, 18, 19]);
+  }
+
+  #[test]
+  fn writes_keep_surrounding_bytes() {
+    let disk = filled_disk();
+    assert_eq!(write_bytes(&disk, 14, &[0xff; 20]), Ok(20));
+    let mut contents = [0; 64];
+    disk.read_blocks(0, &mut contents).unwrap();
+    assert_eq!(contents[13], 13);
+    assert!(contents[14..34].iter().all(|byte| *byte == 0xff));
+    assert_eq!(contents[34], 34);
+  }
+
+  #[test]
+  fn stops_at_end_of_device() {
+    let disk = filled_disk();
+    let mut buffer = [0; 8];
+    assert_eq!(read_bytes(&disk, 60, &mut buffer), Ok(4));
+    assert_eq!(&buffer[..4], &[60, 61, 62, 63]);
+    assert_eq!(read_bytes(&disk, 64, &mut buffer), Ok(0));
+    assert_eq!(write_bytes(&disk, 62, &[1; 8]), Ok(2));
+  }
+}
@@ -0,0 +1,213 @@
+//! Block devices registered with the kernel, and the BLOCKIO thread that
+//! services their request queues. A process reading or writing blocks adds a
+//! request to the device's queue and blocks until BLOCKIO has carried it out,
+//! so requests from every process pass through one place where they can be
+//! merged and ordered.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::process::{self, id::ProcessID};
+use crate::task::WaitQueue;
+use spin::{Mutex, RwLock};
+use syscall::result::SystemError;
+use super::block::BlockDevice;
+use super::queue::{Completion, RequestId, RequestQueue};
+
+pub type BlockDeviceType = dyn BlockDevice + Send + Sync;
+
+pub struct BlockQueue {
+  device: Arc<BlockDeviceType>,
+  requests: Mutex<RequestQueue>,
+  /// Finished requests, waiting to be collected by whoever submitted them
+  completed: Mutex<Vec<Completion>>,
+  /// The process that submitted each request it is waiting on. If that
+  /// process is gone by the time the request finishes, nobody will collect
+  /// the completion, so it is dropped instead.
+  requesters: Mutex<BTreeMap<RequestId, ProcessID>>,
+  /// Processes waiting for their requests to finish
+  done: WaitQueue,
+}
+
+impl BlockQueue {
+  fn new(device: Arc<BlockDeviceType>) -> BlockQueue {
+    BlockQueue {
+      device,
+      requests: Mutex::new(RequestQueue::new()),
+      completed: Mutex::new(Vec::new()),
+      requesters: Mutex::new(BTreeMap::new()),
+      done: WaitQueue::new(),
+    }
+  }
+
+  pub fn block_size(&self) -> usize {
+    self.device.block_size()
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.device.capacity()
+  }
+
+  /// Number of whole blocks in a buffer. Only whole blocks can be
+  /// transferred, so any other length is rejected.
+  fn count_blocks(&self, length: usize) -> Result<usize, SystemError> {
+    let block_size = self.block_size();
+    if length % block_size != 0 {
+      return Err(SystemError::InvalidArgument);
+    }
+    Ok(length / block_size)
+  }
+
+  /// Fill the buffer with blocks starting at `lba`, waiting for the request
+  /// to be serviced
+  pub fn read(&self, lba: usize, buffer: &mut [u8]) -> Result<(), SystemError> {
+    let count = self.count_blocks(buffer.len())?;
+    let id = self.requests.lock().submit_read(lba, count);
+    PENDING.fetch_add(1, Ordering::SeqCst);
+    let data = self.wait_for(id).map_err(|_| SystemError::IOError)?;
+    buffer[..data.len()].copy_from_slice(&data);
+    Ok(())
+  }
+
+  /// Write blocks starting at `lba`, waiting until they reach the device
+  pub fn write(&self, lba: usize, buffer: &[u8]) -> Result<(), SystemError> {
+    let count = self.count_blocks(buffer.len())?;
+    let id = self.requests.lock().submit_write(lba, count, buffer.to_vec());
+    PENDING.fetch_add(1, Ordering::SeqCst);
+    self.wait_for(id).map(|_| ()).map_err(|_| SystemError::IOError)
+  }
+
+  fn wait_for(&self, id: RequestId) -> Result<Vec<u8>, ()> {
+    if process::current_process().is_none() {
+      // Nothing can block before the scheduler is running, so the queue is
+      // serviced directly
+      self.service();
+      return self.take_completion(id).ok_or(())?;
+    }
+    self.requesters.lock().insert(id, process::get_current_pid());
+    IO_QUEUE.notify_one();
+    loop {
+      if let Some(result) = self.take_completion(id) {
+        return result;
+      }
+      // The condition runs with interrupts off, so it must not spin on a
+      // lock that BLOCKIO could be holding. If the lock is busy, it reports
+      // ready and the completion is checked again with interrupts on.
+      self.done.wait_until(|| {
+        self.completed.try_lock().map_or(true, |completed| completed.iter().any(|c| c.id == id))
+      });
+    }
+  }
+
+  fn take_completion(&self, id: RequestId) -> Option<Result<Vec<u8>, ()>> {
+    let mut completed = self.completed.lock();
+    let index = completed.iter().position(|c| c.id == id)?;
+    self.requesters.lock().remove(&id);
+    Some(completed.swap_remove(index).result)
+  }
+
+  /// Drop the completions of requests whose process exited or was killed
+  /// while waiting for them
+  fn drop_orphaned_completions(&self) {
+    let mut completed = self.completed.lock();
+    let mut requesters = self.requesters.lock();
+    let orphaned: Vec<RequestId> = {
+      let processes = process::all_processes();
+      requesters.iter()
+        .filter(|(_, pid)| match processes.get_process(**pid) {
+          Some(process) => process.is_terminated(),
+          None => true,
+        })
+        .map(|(id, _)| *id)
+        .collect()
+    };
+    for id in orphaned {
+      if let Some(index) = completed.iter().position(|c| c.id == id) {
+        completed.swap_remove(index);
+        requesters.remove(&id);
+      }
+    }
+  }
+
+  /// Carry out every queued request
+  fn service(&self) {
+    loop {
+      let batch = match self.requests.lock().take_batch() {
+        Some(batch) => batch,
+        None => return,
+      };
+      let completions = batch.dispatch(&*self.device);
+      PENDING.fetch_sub(completions.len(), Ordering::SeqCst);
+      self.completed.lock().extend(completions);
+      self.drop_orphaned_completions();
+      self.done.notify_all();
+    }
+  }
+}
+
+/// A queue can stand in for its device, so that the byte-range helpers in the
+/// block module work on queued access too
+impl BlockDevice for BlockQueue {
+  fn block_size(&self) -> usize {
+    self.device.block_size()
+  }
+
+  fn capacity(&self) -> usize {
+    self.device.capacity()
+  }
+
+  fn read_blocks(&self, lba: usize, buffer: &mut [u8]) -> Result<(), ()> {
+    self.read(lba, buffer).map_err(|_| ())
+  }
+
+  fn write_blocks(&self, lba: usize, buffer: &[u8]) -> Result<(), ()> {
+    self.write(lba, buffer).map_err(|_| ())
+  }
+}
+
+/// Block devices, keyed by their device number on DEV:
+static BLOCK_DEVICES: RwLock<Vec<(usize, Arc<BlockQueue>)>> = RwLock::new(Vec::new());
+
+/// BLOCKIO sleeps here while every queue is empty
+static IO_QUEUE: WaitQueue = WaitQueue::new();
+/// Requests submitted to any queue and not yet carried out. BLOCKIO checks
+/// this with interrupts off, so it is kept outside of any lock.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Make a device available for block access. `device_number` is the number of
+/// the same disk on DEV:, which filesystems already use to identify it.
+pub fn register(device_number: usize, device: Arc<BlockDeviceType>) {
+  let queue = Arc::new(BlockQueue::new(device));
+  let mut devices = BLOCK_DEVICES.write();
+  devices.retain(|(number, _)| *number != device_number);
+  devices.push((device_number, queue));
+}
+
+pub fn get_queue(device_number: usize) -> Option<Arc<BlockQueue>> {
+  BLOCK_DEVICES.read().iter()
+    .find(|(number, _)| *number == device_number)
+    .map(|(_, queue)| Arc::clone(queue))
+}
+
+fn any_requests() -> bool {
+  PENDING.load(Ordering::SeqCst) > 0
+}
+
+/// Start the thread that services block requests
+pub fn start() {
+  process::spawn_kthread(run_block_io, "BLOCKIO").expect("Failed to start block I/O thread");
+}
+
+#[inline(never)]
+extern "C" fn run_block_io() {
+  loop {
+    IO_QUEUE.wait_until(any_requests);
+    let queues: Vec<Arc<BlockQueue>> = BLOCK_DEVICES.read().iter()
+      .map(|(_, queue)| Arc::clone(queue))
+      .collect();
+    for queue in queues {
+      queue.service();
+    }
+  }
+}
@@ -0,0 +1,156 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use crate::drivers::{ata::{atapi::AtapiDevice, AtaDevice}, floppy};
+use super::blockio;
+use crate::hardware::ata::{AtaChannel, AtaError, DrivePosition};
+use crate::filesystems::{self, MountSource};
+use crate::filesystems::options::MountOptions;
+use crate::devices;
+use crate::messages;
+use crate::process;
+use crate::workers;
+use syscall::messages::{FLOPPY_INIT_FAILED, FLOPPY_READY, MEDIA_CHANGED, NO_READABLE_DISC, REMOUNT_FAILED};
+
+/// Queue up initialization for each known drive on the disk worker pool. Each
+/// drive is mounted by its own job, so a slow or missing device does not hold
+/// up the others.
+pub fn init() {
+  workers::submit_fn(workers::DISK_POOL, mount_floppy).expect("Failed to queue floppy init");
+  workers::submit_fn(workers::DISK_POOL, probe_hard_disks).expect("Failed to queue ATA probe");
+}
+
+fn mount_floppy() {
+  let floppy = &devices::FLOPPY;
+  match floppy.init() {
+    Ok(_) => messages::console(FLOPPY_READY, &[]),
+    Err(e) => messages::console(FLOPPY_INIT_FAILED, &[&format_args!("{:?}", e)]),
+  }
+
+  floppy::init_dma();
+
+  let fat_fs = filesystems::fat12::create_fs("FD0").unwrap();
+  // Floppies can be removed at any time, so writes are not held in memory
+  let mut options = MountOptions::new();
+  options.sync = true;
+  if filesystems::VFS.mount("A", fat_fs, options, MountSource::new("FAT", Some("FD0"))).is_err() {
+    panic!("Failed to register A:");
+  }
+
+  process::send_signal(process::id::ProcessID::new(1), syscall::signals::CONTINUE);
+}
+
+/// How often the floppy drive's disk change line is checked
+const MEDIA_POLL_INTERVAL_MS: usize = 500;
+
+/// Begin watching removable drives for media changes. The check runs on the
+/// disk worker pool, since acknowledging a change waits on the controller, and
+/// schedules the next check once it is done.
+pub fn start_media_watch() {
+  let _ = workers::schedule_delayed_work(|| {
+    let _ = workers::submit_fn(workers::DISK_POOL, || {
+      check_removable_media();
+      start_media_watch();
+    });
+  }, MEDIA_POLL_INTERVAL_MS);
+}
+
+/// When the floppy disk is removed, drive A: is marked as changed so that
+/// nothing is read from or written to the wrong disk. Once a disk is inserted
+/// again, the drive is remounted; handles opened on the old disk remain stale.
+fn check_removable_media() {
+  let floppy = &devices::FLOPPY;
+  if !floppy.is_ready() || !floppy.disk_changed() {
+    return;
+  }
+  let index = match filesystems::get_fs_number("A") {
+    Some(index) => index,
+    None => return,
+  };
+  let already_changed = match filesystems::get_fs(index) {
+    Some(fs) => fs.check_media().is_err(),
+    None => return,
+  };
+  if !already_changed {
+    messages::console(MEDIA_CHANGED, &[&"A:"]);
+    filesystems::VFS.media_changed(index);
+  }
+  match floppy.acknowledge_disk_change() {
+    Ok(true) => {
+      if filesystems::VFS.remount_drive(index).is_err() {
+        messages::console(REMOUNT_FAILED, &[&"A:"]);
+      }
+    },
+    // The drive is empty, or the controller did not respond; the drive
+    // stays unusable until the next check
+    _ => (),
+  }
+}
+
+/// Hard disks are named by their position on the ATA bus, so a disk keeps the
+/// same name even if another one is missing
+static ATA_DRIVES: [(&str, &AtaChannel, DrivePosition); 4] = [
+  ("HDA", &devices::ATA_PRIMARY, DrivePosition::Master),
+  ("HDB", &devices::ATA_PRIMARY, DrivePosition::Slave),
+  ("HDC", &devices::ATA_SECONDARY, DrivePosition::Master),
+  ("HDD", &devices::ATA_SECONDARY, DrivePosition::Slave),
+];
+
+/// Identify each drive on the two ATA channels, and register a block device
+/// for every disk that responds. Hard disks are not mounted automatically; a
+/// filesystem can be created on one with `fat12::create_fs("HDA")`.
+/// CD-ROM drives are registered as CD0, CD1, and so on, and the first one is
+/// mounted as D: if it holds a readable disc.
+fn probe_hard_disks() {
+  let mut cd_count = 0;
+  for (name, channel, position) in ATA_DRIVES.iter() {
+    let identity = match channel.identify(*position) {
+      Ok(identity) => identity,
+      Err(AtaError::PacketDevice) => {
+        register_cd_drive(*channel, *position, cd_count);
+        cd_count += 1;
+        continue;
+      },
+      Err(_) => continue,
+    };
+    let model = core::str::from_utf8(&identity.model).unwrap_or("").trim_end();
+    let geometry = identity.geometry;
+    crate::tty::console_write(format_args!(
+      "{}: {} ({} sectors, CHS {}/{}/{})\n",
+      name,
+      model,
+      identity.sector_count,
+      geometry.get_cylinders(),
+      geometry.get_heads(),
+      geometry.get_sectors_per_track(),
+    ));
+    let device = AtaDevice::new(*channel, *position, identity.sector_count, geometry);
+    let number = devices::DEV.write().register_driver(name, Arc::new(Box::new(device)));
+    let block_device = AtaDevice::new(*channel, *position, identity.sector_count, geometry);
+    blockio::register(number, Arc::new(block_device));
+  }
+}
+
+/// At most four drives can be attached to the two channels
+const CD_NAMES: [&str; 4] = ["CD0", "CD1", "CD2", "CD3"];
+
+fn register_cd_drive(channel: &'static AtaChannel, position: DrivePosition, index: usize) {
+  let model = match channel.identify_packet(position) {
+    Ok(model) => model,
+    Err(_) => return,
+  };
+  let name = CD_NAMES[index];
+  let model_str = core::str::from_utf8(&model).unwrap_or("").trim_end();
+  crate::tty::console_write(format_args!("{}: {}\n", name, model_str));
+  let device = AtapiDevice::new(channel, position);
+  devices::DEV.write().register_driver(name, Arc::new(Box::new(device)));
+
+  if index != 0 {
+    return;
+  }
+  match filesystems::iso9660::create_fs(name) {
+    Ok(iso_fs) => {
+      let _ = filesystems::VFS.mount("D", iso_fs, MountOptions::read_only(), MountSource::new("ISO9660", Some(name)));
+    },
+    Err(_) => messages::console(NO_READABLE_DISC, &[&name]),
+  }
+}
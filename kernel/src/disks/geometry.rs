@@ -0,0 +1,247 @@
+//! Older software addresses disks by cylinder, head, and sector (CHS) rather
+//! than by a linear block address (LBA). The BIOS, the INT 13h services used
+//! by DOS programs, and the partition table all speak CHS, so block devices
+//! report a geometry that can be used to translate between the two.
+//!
+//! Drives do not always report a geometry that agrees with their capacity.
+//! Hard disks often report a default geometry that only covers the first 8GB,
+//! or one with more heads than the BIOS can express. When that happens, a
+//! geometry is derived from the sector count instead, using the same
+//! translation a BIOS would.
+
+/// Largest number of cylinders addressable through INT 13h
+pub const MAX_BIOS_CYLINDERS: usize = 1024;
+/// Largest number of heads addressable through INT 13h
+pub const MAX_BIOS_HEADS: usize = 255;
+/// Sectors are numbered from 1, and only six bits are available
+pub const MAX_SECTORS_PER_TRACK: usize = 63;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GeometryError {
+  /// The block address is beyond the end of the disk
+  OutOfRange,
+  /// The head or sector does not exist in this geometry
+  InvalidAddress,
+}
+
+/// A cylinder, head, and sector address. Sectors are numbered from 1.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Chs {
+  pub cylinder: usize,
+  pub head: usize,
+  pub sector: usize,
+}
+
+impl Chs {
+  pub const fn new(cylinder: usize, head: usize, sector: usize) -> Chs {
+    Chs {
+      cylinder,
+      head,
+      sector,
+    }
+  }
+
+  /// Pack the address into the CX and DH registers, the way INT 13h expects.
+  /// The top two bits of the cylinder are stored in the top of CL.
+  pub fn to_int13_registers(&self) -> Result<(u16, u8), GeometryError> {
+    if self.cylinder >= MAX_BIOS_CYLINDERS || self.head > MAX_BIOS_HEADS || self.sector > MAX_SECTORS_PER_TRACK {
+      return Err(GeometryError::OutOfRange);
+    }
+    let cl = (self.sector as u16) | (((self.cylinder >> 8) as u16 & 3) << 6);
+    let ch = self.cylinder as u16 & 0xff;
+    Ok(((ch << 8) | cl, self.head as u8))
+  }
+
+  pub fn from_int13_registers(cx: u16, dh: u8) -> Chs {
+    let cylinder = ((cx >> 8) as usize) | (((cx as usize) & 0xc0) << 2);
+    Chs {
+      cylinder,
+      head: dh as usize,
+      sector: (cx & 0x3f) as usize,
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Geometry {
+  cylinders: usize,
+  heads: usize,
+  sectors_per_track: usize,
+}
+
+/// Layouts of the standard PC floppy formats, identified by sector count
+const FLOPPY_FORMATS: [Geometry; 5] = [
+  Geometry::new(40, 2, 9),
+  Geometry::new(80, 2, 9),
+  Geometry::new(80, 2, 15),
+  Geometry::new(80, 2, 18),
+  Geometry::new(80, 2, 36),
+];
+
+pub const FLOPPY_1440K: Geometry = Geometry::new(80, 2, 18);
+
+impl Geometry {
+  pub const fn new(cylinders: usize, heads: usize, sectors_per_track: usize) -> Geometry {
+    Geometry {
+      cylinders,
+      heads,
+      sectors_per_track,
+    }
+  }
+
+  /// Choose a geometry for a disk of the given size. Floppy sizes map to their
+  /// standard layouts. Anything else uses the BIOS "LBA assist" translation:
+  /// 63 sectors per track, and the smallest number of heads that fits the disk
+  /// in 1024 cylinders.
+  pub fn for_sector_count(total_sectors: usize) -> Geometry {
+    for format in FLOPPY_FORMATS.iter() {
+      if format.get_total_sectors() == total_sectors {
+        return *format;
+      }
+    }
+    let sectors_per_track = MAX_SECTORS_PER_TRACK;
+    let mut heads = 16;
+    while heads < MAX_BIOS_HEADS && total_sectors > MAX_BIOS_CYLINDERS * heads * sectors_per_track {
+      heads = (heads * 2).min(MAX_BIOS_HEADS);
+    }
+    let cylinders = (total_sectors / (heads * sectors_per_track)).max(1).min(MAX_BIOS_CYLINDERS);
+    Geometry::new(cylinders, heads, sectors_per_track)
+  }
+
+  /// Check a geometry reported by a drive against its actual capacity. If the
+  /// reported values cannot be expressed to the BIOS, or describe a disk
+  /// larger than the real one, or leave more than a cylinder unaddressed on a
+  /// disk small enough to be fully covered, a translated geometry is used.
+  pub fn reconcile(reported: Geometry, total_sectors: usize) -> Geometry {
+    if !reported.is_bios_compatible() {
+      return Geometry::for_sector_count(total_sectors);
+    }
+    let covered = reported.get_total_sectors();
+    if covered > total_sectors {
+      return Geometry::for_sector_count(total_sectors);
+    }
+    let cylinder_size = reported.heads * reported.sectors_per_track;
+    let fully_addressable = total_sectors <= MAX_BIOS_CYLINDERS * MAX_BIOS_HEADS * MAX_SECTORS_PER_TRACK;
+    if fully_addressable && total_sectors - covered >= cylinder_size {
+      let translated = Geometry::for_sector_count(total_sectors);
+      if translated.get_total_sectors() > covered {
+        return translated;
+      }
+    }
+    reported
+  }
+
+  pub fn get_cylinders(&self) -> usize {
+    self.cylinders
+  }
+
+  pub fn get_heads(&self) -> usize {
+    self.heads
+  }
+
+  pub fn get_sectors_per_track(&self) -> usize {
+    self.sectors_per_track
+  }
+
+  /// Number of sectors addressable with this geometry, which may be fewer than
+  /// the disk actually holds
+  pub fn get_total_sectors(&self) -> usize {
+    self.cylinders * self.heads * self.sectors_per_track
+  }
+
+  pub fn is_bios_compatible(&self) -> bool {
+    self.cylinders > 0 && self.cylinders <= MAX_BIOS_CYLINDERS
+      && self.heads > 0 && self.heads <= MAX_BIOS_HEADS
+      && self.sectors_per_track > 0 && self.sectors_per_track <= MAX_SECTORS_PER_TRACK
+  }
+
+  pub fn lba_to_chs(&self, lba: usize) -> Result<Chs, GeometryError> {
+    if lba >= self.get_total_sectors() {
+      return Err(GeometryError::OutOfRange);
+    }
+    let cylinder_size = self.heads * self.sectors_per_track;
+    let cylinder = lba / cylinder_size;
+    let head = (lba % cylinder_size) / self.sectors_per_track;
+    let sector = (lba % self.sectors_per_track) + 1;
+    Ok(Chs::new(cylinder, head, sector))
+  }
+
+  pub fn chs_to_lba(&self, chs: Chs) -> Result<usize, GeometryError> {
+    if chs.sector == 0 || chs.sector > self.sectors_per_track || chs.head >= self.heads {
+      return Err(GeometryError::InvalidAddress);
+    }
+    if chs.cylinder >= self.cylinders {
+      return Err(GeometryError::OutOfRange);
+    }
+    Ok((chs.cylinder * self.heads + chs.head) * self.sectors_per_track + chs.sector - 1)
+  }
+
+  /// Pack the geometry into a single number, for reporting through an ioctl.
+  /// Cylinders occupy the top 16 bits, followed by heads and sectors.
+  pub fn to_u32(&self) -> u32 {
+    ((self.cylinders as u32 & 0xffff) << 16) | ((self.heads as u32 & 0xff) << 8) | (self.sectors_per_track as u32 & 0xff)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn floppy_translation() {
+    let geometry = FLOPPY_1440K;
+    assert_eq!(geometry.lba_to_chs(0), Ok(Chs::new(0, 0, 1)));
+    assert_eq!(geometry.lba_to_chs(17), Ok(Chs::new(0, 0, 18)));
+    assert_eq!(geometry.lba_to_chs(18), Ok(Chs::new(0, 1, 1)));
+    assert_eq!(geometry.lba_to_chs(36), Ok(Chs::new(1, 0, 1)));
+    assert_eq!(geometry.lba_to_chs(2880), Err(GeometryError::OutOfRange));
+    for lba in 0..2880 {
+      let chs = geometry.lba_to_chs(lba).unwrap();
+      assert_eq!(geometry.chs_to_lba(chs), Ok(lba));
+    }
+    assert_eq!(geometry.chs_to_lba(Chs::new(0, 0, 0)), Err(GeometryError::InvalidAddress));
+    assert_eq!(geometry.chs_to_lba(Chs::new(0, 2, 1)), Err(GeometryError::InvalidAddress));
+  }
+
+  #[test]
+  fn geometry_from_size() {
+    assert_eq!(Geometry::for_sector_count(2880), FLOPPY_1440K);
+    assert_eq!(Geometry::for_sector_count(720), Geometry::new(40, 2, 9));
+    // 20MB disk fits in 16 heads
+    assert_eq!(Geometry::for_sector_count(40960), Geometry::new(40, 16, 63));
+    // 2GB disk needs more heads to stay under 1024 cylinders
+    let large = Geometry::for_sector_count(4194304);
+    assert_eq!(large.get_heads(), 128);
+    assert!(large.get_cylinders() <= MAX_BIOS_CYLINDERS);
+    // Disks beyond 8GB are capped
+    let huge = Geometry::for_sector_count(64 * 1024 * 1024);
+    assert_eq!(huge, Geometry::new(1024, 255, 63));
+  }
+
+  #[test]
+  fn inconsistent_geometry() {
+    // A sensible reported geometry is kept
+    let reported = Geometry::new(1015, 16, 63);
+    assert_eq!(Geometry::reconcile(reported, 1015 * 16 * 63), reported);
+    // Too many sectors per track for the BIOS
+    assert_eq!(Geometry::reconcile(Geometry::new(100, 16, 255), 40960), Geometry::new(40, 16, 63));
+    // Claims to be larger than the disk
+    assert_eq!(Geometry::reconcile(Geometry::new(1000, 16, 63), 40960), Geometry::new(40, 16, 63));
+    // The default 16383/16/63 geometry of a large disk can't be used by the
+    // BIOS, and is translated
+    assert_eq!(Geometry::reconcile(Geometry::new(16383, 16, 63), 4194304).get_heads(), 128);
+    // Only covers a fraction of a disk that could be fully addressed
+    assert_eq!(Geometry::reconcile(Geometry::new(20, 16, 63), 40960), Geometry::new(40, 16, 63));
+  }
+
+  #[test]
+  fn int13_registers() {
+    let chs = Chs::new(0x3ff, 15, 63);
+    let (cx, dh) = chs.to_int13_registers().unwrap();
+    assert_eq!(cx, 0xffff);
+    assert_eq!(dh, 15);
+    assert_eq!(Chs::from_int13_registers(cx, dh), chs);
+    assert_eq!(Chs::from_int13_registers(0x0102, 1), Chs::new(1, 1, 2));
+    assert_eq!(Chs::new(1024, 0, 1).to_int13_registers(), Err(GeometryError::OutOfRange));
+  }
+}
@@ -0,0 +1,13 @@
+pub mod block;
+pub mod geometry;
+pub mod queue;
+pub mod ramdisk;
+pub mod snapshot;
+
+#[cfg(not(test))]
+pub mod blockio;
+#[cfg(not(test))]
+pub mod drives;
+
+#[cfg(not(test))]
+pub use drives::{init, start_media_watch};
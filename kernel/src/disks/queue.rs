@@ -0,0 +1,315 @@
+//! Requests waiting for a block device. Processes add requests as they need
+//! blocks, and the device's I/O thread takes them off in batches. Requests of
+//! the same kind that cover neighboring blocks are merged into one batch, so
+//! that the device sees a single large transfer instead of many small ones.
+//!
+//! Requests are otherwise handled in the order they arrive. A request is only
+//! merged ahead of earlier ones if it does not touch any of the same blocks,
+//! so a read always sees the data written by any write queued before it.
+
+use alloc::vec::Vec;
+use super::block::BlockDevice;
+
+/// Largest number of blocks merged into a single transfer
+pub const MAX_MERGED_BLOCKS: usize = 64;
+
+pub type RequestId = u32;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RequestKind {
+  Read,
+  Write,
+}
+
+struct Request {
+  id: RequestId,
+  kind: RequestKind,
+  lba: usize,
+  count: usize,
+  /// Contents of the blocks to write. Empty for reads.
+  data: Vec<u8>,
+}
+
+impl Request {
+  fn end(&self) -> usize {
+    self.lba + self.count
+  }
+
+  fn overlaps(&self, other: &Request) -> bool {
+    self.lba < other.end() && other.lba < self.end()
+  }
+}
+
+/// The outcome of a request. Reads are given the contents of their blocks,
+/// and writes an empty Vec.
+pub struct Completion {
+  pub id: RequestId,
+  pub result: Result<Vec<u8>, ()>,
+}
+
+/// Requests for one contiguous run of blocks, transferred together
+pub struct Batch {
+  kind: RequestKind,
+  lba: usize,
+  count: usize,
+  /// Merged requests, sorted by block address
+  parts: Vec<Request>,
+}
+
+impl Batch {
+  pub fn get_kind(&self) -> RequestKind {
+    self.kind
+  }
+
+  pub fn get_lba(&self) -> usize {
+    self.lba
+  }
+
+  pub fn get_count(&self) -> usize {
+    self.count
+  }
+
+  /// Run the batch as a single transfer, and split the result between the
+  /// requests it was made from
+  pub fn dispatch(self, device: &dyn BlockDevice) -> Vec<Completion> {
+    let block_size = device.block_size();
+    let in_range = device.contains(self.lba, self.count);
+    match self.kind {
+      RequestKind::Read => {
+        let mut data = Vec::with_capacity(self.count * block_size);
+        data.resize(self.count * block_size, 0);
+        let result = if in_range {
+          device.read_blocks(self.lba, &mut data)
+        } else {
+          Err(())
+        };
+        let lba = self.lba;
+        self.parts.into_iter().map(|part| {
+          let start = (part.lba - lba) * block_size;
+          let end = start + part.count * block_size;
+          Completion {
+            id: part.id,
+            result: result.map(|_| data[start..end].to_vec()),
+          }
+        }).collect()
+      },
+      RequestKind::Write => {
+        let mut data = Vec::with_capacity(self.count * block_size);
+        for part in self.parts.iter() {
+          data.extend_from_slice(&part.data);
+        }
+        let result = if in_range && data.len() == self.count * block_size {
+          device.write_blocks(self.lba, &data)
+        } else {
+          Err(())
+        };
+        self.parts.into_iter().map(|part| Completion {
+          id: part.id,
+          result: result.map(|_| Vec::new()),
+        }).collect()
+      },
+    }
+  }
+}
+
+pub struct RequestQueue {
+  pending: Vec<Request>,
+  next_id: RequestId,
+}
+
+impl RequestQueue {
+  pub const fn new() -> RequestQueue {
+    RequestQueue {
+      pending: Vec::new(),
+      next_id: 1,
+    }
+  }
+
+  fn push(&mut self, kind: RequestKind, lba: usize, count: usize, data: Vec<u8>) -> RequestId {
+    let id = self.next_id;
+    self.next_id = self.next_id.wrapping_add(1).max(1);
+    self.pending.push(Request {
+      id,
+      kind,
+      lba,
+      count,
+      data,
+    });
+    id
+  }
+
+  /// Queue a read of `count` blocks
+  pub fn submit_read(&mut self, lba: usize, count: usize) -> RequestId {
+    self.push(RequestKind::Read, lba, count, Vec::new())
+  }
+
+  /// Queue a write of `count` blocks. The data must be exactly that many
+  /// blocks long, or the request fails when it is dispatched.
+  pub fn submit_write(&mut self, lba: usize, count: usize, data: Vec<u8>) -> RequestId {
+    self.push(RequestKind::Write, lba, count, data)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.pending.is_empty()
+  }
+
+  pub fn len(&self) -> usize {
+    self.pending.len()
+  }
+
+  /// Remove the oldest request, along with every other request that can be
+  /// merged with it
+  pub fn take_batch(&mut self) -> Option<Batch> {
+    if self.pending.is_empty() {
+      return None;
+    }
+    let first = self.pending.remove(0);
+    let kind = first.kind;
+    let mut start = first.lba;
+    let mut end = first.end();
+    let mut parts = alloc::vec![first];
+    // Merging one request can make another one adjacent, so keep scanning
+    // until nothing more joins the batch
+    loop {
+      let mut merged = false;
+      let mut index = 0;
+      while index < self.pending.len() {
+        let candidate = &self.pending[index];
+        let adjacent = candidate.end() == start || candidate.lba == end;
+        let fits = end - start + candidate.count <= MAX_MERGED_BLOCKS;
+        let reorders = self.pending[..index].iter().any(|earlier| earlier.overlaps(candidate));
+        if candidate.kind == kind && adjacent && fits && !reorders {
+          let request = self.pending.remove(index);
+          start = start.min(request.lba);
+          end = end.max(request.end());
+          parts.push(request);
+          merged = true;
+        } else {
+          index += 1;
+        }
+      }
+      if !merged {
+        break;
+      }
+    }
+    parts.sort_by_key(|part| part.lba);
+    Some(Batch {
+      kind,
+      lba: start,
+      count: end - start,
+      parts,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use spin::RwLock;
+  use super::{BlockDevice, RequestKind, RequestQueue, MAX_MERGED_BLOCKS};
+
+  struct MemoryDevice {
+    blocks: RwLock<Vec<u8>>,
+    transfers: RwLock<usize>,
+  }
+
+  impl MemoryDevice {
+    fn new(count: usize) -> MemoryDevice {
+      let blocks = (0..count * 4).map(|i| (i / 4) as u8).collect();
+      MemoryDevice {
+        blocks: RwLock::new(blocks),
+        transfers: RwLock::new(0),
+      }
+    }
+  }
+
+  impl BlockDevice for MemoryDevice {
+    fn block_size(&self) -> usize {
+      4
+    }
+
+    fn capacity(&self) -> usize {
+      self.blocks.read().len() / 4
+    }
+
+    fn read_blocks(&self, lba: usize, buffer: &mut [u8]) -> Result<(), ()> {
+      *self.transfers.write() += 1;
+      buffer.copy_from_slice(&self.blocks.read()[lba * 4..lba * 4 + buffer.len()]);
+      Ok(())
+    }
+
+    fn write_blocks(&self, lba: usize, buffer: &[u8]) -> Result<(), ()> {
+      *self.transfers.write() += 1;
+      self.blocks.write()[lba * 4..lba * 4 + buffer.len()].copy_from_slice(buffer);
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn merges_adjacent_reads() {
+    let device = MemoryDevice::new(16);
+    let mut queue = RequestQueue::new();
+    let a = queue.submit_read(4, 2);
+    let b = queue.submit_read(2, 2);
+    let c = queue.submit_read(6, 1);
+    let d = queue.submit_read(10, 1);
+    let batch = queue.take_batch().unwrap();
+    assert_eq!((batch.get_kind(), batch.get_lba(), batch.get_count()), (RequestKind::Read, 2, 5));
+    let completions = batch.dispatch(&device);
+    assert_eq!(*device.transfers.read(), 1);
+    let find = |id| completions.iter().find(|c| c.id == id).unwrap().result.clone().unwrap();
+    assert_eq!(find(a), [4, 4, 4, 4, 5, 5, 5, 5]);
+    assert_eq!(find(b), [2, 2, 2, 2, 3, 3, 3, 3]);
+    assert_eq!(find(c), [6, 6, 6, 6]);
+    assert!(completions.iter().all(|c| c.id != d));
+    assert_eq!(queue.len(), 1);
+  }
+
+  #[test]
+  fn merges_writes_in_block_order() {
+    let device = MemoryDevice::new(8);
+    let mut queue = RequestQueue::new();
+    queue.submit_write(3, 1, alloc::vec![9; 4]);
+    queue.submit_write(2, 1, alloc::vec![8; 4]);
+    queue.submit_read(2, 2);
+    let batch = queue.take_batch().unwrap();
+    assert_eq!((batch.get_lba(), batch.get_count()), (2, 2));
+    assert!(batch.dispatch(&device).iter().all(|c| c.result.is_ok()));
+    let read = queue.take_batch().unwrap().dispatch(&device);
+    assert_eq!(read[0].result.clone().unwrap(), [8, 8, 8, 8, 9, 9, 9, 9]);
+  }
+
+  #[test]
+  fn keeps_overlapping_requests_in_order() {
+    let mut queue = RequestQueue::new();
+    queue.submit_read(0, 1);
+    queue.submit_write(1, 1, alloc::vec![0; 4]);
+    // Adjacent to the first read, but must not jump ahead of the write
+    queue.submit_read(1, 1);
+    let batch = queue.take_batch().unwrap();
+    assert_eq!(batch.get_count(), 1);
+    assert_eq!(queue.take_batch().unwrap().get_kind(), RequestKind::Write);
+    assert_eq!(queue.take_batch().unwrap().get_kind(), RequestKind::Read);
+    assert!(queue.take_batch().is_none());
+  }
+
+  #[test]
+  fn limits_batch_size() {
+    let mut queue = RequestQueue::new();
+    queue.submit_read(0, MAX_MERGED_BLOCKS);
+    queue.submit_read(MAX_MERGED_BLOCKS, 1);
+    assert_eq!(queue.take_batch().unwrap().get_count(), MAX_MERGED_BLOCKS);
+    assert_eq!(queue.take_batch().unwrap().get_lba(), MAX_MERGED_BLOCKS);
+  }
+
+  #[test]
+  fn fails_requests_past_the_end() {
+    let device = MemoryDevice::new(4);
+    let mut queue = RequestQueue::new();
+    queue.submit_read(3, 2);
+    queue.submit_write(0, 2, alloc::vec![0; 4]);
+    assert!(queue.take_batch().unwrap().dispatch(&device)[0].result.is_err());
+    assert!(queue.take_batch().unwrap().dispatch(&device)[0].result.is_err());
+    assert_eq!(*device.transfers.read(), 0);
+  }
+}
@@ -0,0 +1,68 @@
+//! A disk held entirely in kernel memory. Blocks are only allocated once they
+//! are written, so a large disk that is mostly empty costs very little.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use spin::RwLock;
+use super::block::BlockDevice;
+
+pub struct RamDisk {
+  block_size: usize,
+  capacity: usize,
+  /// Blocks that have been written. Any other block reads as zeroes.
+  blocks: RwLock<BTreeMap<usize, Box<[u8]>>>,
+}
+
+impl RamDisk {
+  pub fn new(block_size: usize, capacity: usize) -> RamDisk {
+    RamDisk {
+      block_size,
+      capacity,
+      blocks: RwLock::new(BTreeMap::new()),
+    }
+  }
+
+  /// Number of blocks that currently take up memory
+  pub fn get_allocated_blocks(&self) -> usize {
+    self.blocks.read().len()
+  }
+}
+
+impl BlockDevice for RamDisk {
+  fn block_size(&self) -> usize {
+    self.block_size
+  }
+
+  fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  fn read_blocks(&self, lba: usize, buffer: &mut [u8]) -> Result<(), ()> {
+    let count = buffer.len() / self.block_size;
+    if !self.contains(lba, count) {
+      return Err(());
+    }
+    let blocks = self.blocks.read();
+    for (index, chunk) in buffer.chunks_exact_mut(self.block_size).enumerate() {
+      match blocks.get(&(lba + index)) {
+        Some(block) => chunk.copy_from_slice(block),
+        None => chunk.iter_mut().for_each(|byte| *byte = 0),
+      }
+    }
+    Ok(())
+  }
+
+  fn write_blocks(&self, lba: usize, buffer: &[u8]) -> Result<(), ()> {
+    let count = buffer.len() / self.block_size;
+    if !self.contains(lba, count) {
+      return Err(());
+    }
+    let mut blocks = self.blocks.write();
+    for (index, chunk) in buffer.chunks_exact(self.block_size).enumerate() {
+      let block = blocks.entry(lba + index).or_insert_with(|| vec![0; chunk.len()].into_boxed_slice());
+      block.copy_from_slice(chunk);
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,196 @@
+//! Copy-on-write snapshots of a block device. While a snapshot is active, the
+//! underlying disk is frozen: writes land in an in-memory delta instead, and
+//! reads see the delta layered over the frozen image. The delta can then be
+//! discarded, returning the disk to exactly the state it was frozen in, or
+//! committed, writing every change through to the disk.
+//!
+//! This makes it cheap to run destructive filesystem operations over and over
+//! against the same starting image. A filesystem mounted on the device keeps
+//! its own caches, so it should be remounted after a snapshot is discarded.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::RwLock;
+use super::block::BlockDevice;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SnapshotError {
+  /// A snapshot was requested while one is already active
+  AlreadyActive,
+  /// There is no snapshot to discard or commit
+  NotActive,
+  /// The underlying device refused a write during a commit. Blocks that were
+  /// not written are still held in the snapshot.
+  CommitFailed,
+}
+
+/// Blocks written since the snapshot was created, keyed by block address
+type Delta = BTreeMap<usize, Box<[u8]>>;
+
+pub struct SnapshotDevice {
+  base: Arc<dyn BlockDevice + Send + Sync>,
+  /// None when no snapshot is active, and writes go straight to the base
+  delta: RwLock<Option<Delta>>,
+}
+
+impl SnapshotDevice {
+  pub fn new(base: Arc<dyn BlockDevice + Send + Sync>) -> SnapshotDevice {
+    SnapshotDevice {
+      base,
+      delta: RwLock::new(None),
+    }
+  }
+
+  pub fn is_active(&self) -> bool {
+    self.delta.read().is_some()
+  }
+
+  /// Number of blocks written since the snapshot was created
+  pub fn get_changed_blocks(&self) -> Option<usize> {
+    self.delta.read().as_ref().map(|delta| delta.len())
+  }
+
+  /// Freeze the device in its current state
+  pub fn create(&self) -> Result<(), SnapshotError> {
+    let mut delta = self.delta.write();
+    if delta.is_some() {
+      return Err(SnapshotError::AlreadyActive);
+    }
+    *delta = Some(BTreeMap::new());
+    Ok(())
+  }
+
+  /// Drop every write made since the snapshot was created
+  pub fn discard(&self) -> Result<(), SnapshotError> {
+    self.delta.write().take().map(|_| ()).ok_or(SnapshotError::NotActive)
+  }
+
+  /// Write the changed blocks to the device and end the snapshot. Runs of
+  /// consecutive blocks are written together.
+  pub fn commit(&self) -> Result<(), SnapshotError> {
+    let mut lock = self.delta.write();
+    let delta = lock.as_mut().ok_or(SnapshotError::NotActive)?;
+    while let Some(&start) = delta.keys().next() {
+      let mut run = Vec::new();
+      let mut end = start;
+      while let Some(block) = delta.get(&end) {
+        run.extend_from_slice(block);
+        end += 1;
+      }
+      self.base.write_blocks(start, &run).map_err(|_| SnapshotError::CommitFailed)?;
+      for lba in start..end {
+        delta.remove(&lba);
+      }
+    }
+    *lock = None;
+    Ok(())
+  }
+}
+
+impl BlockDevice for SnapshotDevice {
+  fn block_size(&self) -> usize {
+    self.base.block_size()
+  }
+
+  fn capacity(&self) -> usize {
+    self.base.capacity()
+  }
+
+  fn read_blocks(&self, lba: usize, buffer: &mut [u8]) -> Result<(), ()> {
+    let block_size = self.block_size();
+    let count = buffer.len() / block_size;
+    let lock = self.delta.read();
+    self.base.read_blocks(lba, buffer)?;
+    if let Some(delta) = lock.as_ref() {
+      for (block_lba, block) in delta.range(lba..(lba + count)) {
+        let offset = (block_lba - lba) * block_size;
+        buffer[offset..(offset + block_size)].copy_from_slice(block);
+      }
+    }
+    Ok(())
+  }
+
+  fn write_blocks(&self, lba: usize, buffer: &[u8]) -> Result<(), ()> {
+    let block_size = self.block_size();
+    let mut lock = self.delta.write();
+    let delta = match lock.as_mut() {
+      Some(delta) => delta,
+      None => return self.base.write_blocks(lba, buffer),
+    };
+    if !self.contains(lba, buffer.len() / block_size) {
+      return Err(());
+    }
+    for (index, chunk) in buffer.chunks_exact(block_size).enumerate() {
+      delta.insert(lba + index, Box::from(chunk));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::sync::Arc;
+  use super::super::block::BlockDevice;
+  use super::super::ramdisk::RamDisk;
+  use super::{SnapshotDevice, SnapshotError};
+
+  fn read(device: &dyn BlockDevice, lba: usize, count: usize) -> alloc::vec::Vec<u8> {
+    let mut buffer = alloc::vec![0; count * 4];
+    device.read_blocks(lba, &mut buffer).unwrap();
+    buffer
+  }
+
+  fn setup() -> (Arc<RamDisk>, SnapshotDevice) {
+    let base = Arc::new(RamDisk::new(4, 8));
+    base.write_blocks(0, &[1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3]).unwrap();
+    let snapshot = SnapshotDevice::new(base.clone());
+    (base, snapshot)
+  }
+
+  #[test]
+  fn writes_through_without_snapshot() {
+    let (base, snapshot) = setup();
+    snapshot.write_blocks(1, &[9; 4]).unwrap();
+    assert_eq!(read(&*base, 1, 1), [9; 4]);
+    assert_eq!(snapshot.get_changed_blocks(), None);
+    assert_eq!(snapshot.discard(), Err(SnapshotError::NotActive));
+  }
+
+  #[test]
+  fn discards_changes() {
+    let (base, snapshot) = setup();
+    snapshot.create().unwrap();
+    assert_eq!(snapshot.create(), Err(SnapshotError::AlreadyActive));
+    snapshot.write_blocks(1, &[7, 7, 7, 7, 8, 8, 8, 8]).unwrap();
+    assert_eq!(read(&snapshot, 0, 3), [1, 1, 1, 1, 7, 7, 7, 7, 8, 8, 8, 8]);
+    assert_eq!(read(&*base, 1, 2), [2, 2, 2, 2, 3, 3, 3, 3]);
+    assert_eq!(snapshot.get_changed_blocks(), Some(2));
+    snapshot.discard().unwrap();
+    assert_eq!(read(&snapshot, 0, 3), [1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3]);
+    assert!(!snapshot.is_active());
+  }
+
+  #[test]
+  fn commits_changes() {
+    let (base, snapshot) = setup();
+    snapshot.create().unwrap();
+    snapshot.write_blocks(0, &[5; 4]).unwrap();
+    snapshot.write_blocks(2, &[6; 8]).unwrap();
+    snapshot.write_blocks(7, &[4; 4]).unwrap();
+    assert_eq!(base.get_allocated_blocks(), 3);
+    snapshot.commit().unwrap();
+    assert_eq!(read(&*base, 0, 4), [5, 5, 5, 5, 2, 2, 2, 2, 6, 6, 6, 6, 6, 6, 6, 6]);
+    assert_eq!(read(&*base, 7, 1), [4; 4]);
+    assert_eq!(snapshot.commit(), Err(SnapshotError::NotActive));
+  }
+
+  #[test]
+  fn rejects_writes_past_the_end() {
+    let (_, snapshot) = setup();
+    snapshot.create().unwrap();
+    assert!(snapshot.write_blocks(7, &[0; 8]).is_err());
+    assert_eq!(snapshot.get_changed_blocks(), Some(0));
+  }
+}
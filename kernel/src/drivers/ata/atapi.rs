@@ -0,0 +1,90 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use crate::files::cursor::SeekMethod;
+use crate::files::handle::LocalHandle;
+use crate::hardware::ata::{AtaChannel, DrivePosition, ATAPI_SECTOR_SIZE};
+use spin::RwLock;
+use super::super::driver::DeviceDriver;
+use syscall::files::OpenFlags;
+
+/// Device driver for an ATAPI CD-ROM drive. The disc is exposed as a
+/// read-only byte stream. Its size is queried each time the device is opened,
+/// since the disc may have been changed.
+pub struct AtapiDevice {
+  channel: &'static AtaChannel,
+  position: DrivePosition,
+  open_files: RwLock<BTreeMap<LocalHandle, OpenFile>>,
+}
+
+impl AtapiDevice {
+  pub fn new(channel: &'static AtaChannel, position: DrivePosition) -> AtapiDevice {
+    AtapiDevice {
+      channel,
+      position,
+      open_files: RwLock::new(BTreeMap::new()),
+    }
+  }
+}
+
+impl DeviceDriver for AtapiDevice {
+  fn open(&self, handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
+    // An empty drive has no capacity, so every read will fail until a disc is
+    // inserted and the device is reopened
+    let sector_count = self.channel.read_capacity(self.position).unwrap_or(0);
+    let open_file = OpenFile {
+      cursor: 0,
+      byte_size: sector_count * ATAPI_SECTOR_SIZE,
+    };
+    self.open_files.write().insert(handle, open_file);
+    Ok(())
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    self.open_files.write().remove(&handle);
+    Ok(())
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let (cursor, byte_size) = match self.open_files.read().get(&handle) {
+      Some(open_file) => Ok((open_file.cursor, open_file.byte_size)),
+      None => Err(()),
+    }?;
+    let end = (cursor + buffer.len()).min(byte_size);
+    if cursor >= end {
+      return Ok(0);
+    }
+    let length = end - cursor;
+    let first_sector = cursor / ATAPI_SECTOR_SIZE;
+    let last_sector = (end + ATAPI_SECTOR_SIZE - 1) / ATAPI_SECTOR_SIZE;
+    let mut sectors = Vec::with_capacity((last_sector - first_sector) * ATAPI_SECTOR_SIZE);
+    sectors.resize((last_sector - first_sector) * ATAPI_SECTOR_SIZE, 0);
+    self.channel.read_packet_sectors(self.position, first_sector, &mut sectors).map_err(|_| ())?;
+
+    let local_offset = cursor - first_sector * ATAPI_SECTOR_SIZE;
+    buffer[..length].copy_from_slice(&sectors[local_offset..(local_offset + length)]);
+    match self.open_files.write().get_mut(&handle) {
+      Some(open_file) => {
+        open_file.cursor += length;
+        Ok(length)
+      },
+      None => Err(()),
+    }
+  }
+
+  fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
+    match self.open_files.write().get_mut(&handle) {
+      Some(open_file) => {
+        let new_cursor = offset.from_current_position(open_file.cursor);
+        open_file.cursor = new_cursor;
+        Ok(new_cursor)
+      },
+      None => Err(())
+    }
+  }
+}
+
+/// Stores metadata associated with a currently open file handle
+struct OpenFile {
+  pub cursor: usize,
+  pub byte_size: usize,
+}
@@ -0,0 +1,121 @@
+use alloc::collections::BTreeMap;
+use crate::disks::block::{self, BlockDevice};
+use crate::disks::geometry::Geometry;
+use crate::files::cursor::SeekMethod;
+use crate::files::handle::LocalHandle;
+use crate::hardware::ata::{AtaChannel, DrivePosition, SECTOR_SIZE};
+use spin::RwLock;
+use super::driver::DeviceDriver;
+use syscall::files::OpenFlags;
+
+pub mod atapi;
+
+/// Device driver for an ATA hard disk. Like the floppy driver, it exposes the
+/// whole disk as a byte stream, so that a filesystem can be layered on top of
+/// it. Reads and writes that do not line up with sector boundaries go through
+/// the block layer, which expands them to cover whole sectors.
+pub struct AtaDevice {
+  channel: &'static AtaChannel,
+  position: DrivePosition,
+  sector_count: usize,
+  geometry: Geometry,
+  open_files: RwLock<BTreeMap<LocalHandle, OpenFile>>,
+}
+
+impl AtaDevice {
+  pub fn new(channel: &'static AtaChannel, position: DrivePosition, sector_count: usize, geometry: Geometry) -> AtaDevice {
+    AtaDevice {
+      channel,
+      position,
+      sector_count,
+      geometry,
+      open_files: RwLock::new(BTreeMap::new()),
+    }
+  }
+
+  pub fn get_byte_size(&self) -> usize {
+    self.sector_count * SECTOR_SIZE
+  }
+
+  fn get_cursor(&self, handle: LocalHandle) -> Result<usize, ()> {
+    match self.open_files.read().get(&handle) {
+      Some(open_file) => Ok(open_file.cursor),
+      None => Err(()),
+    }
+  }
+
+  fn advance_cursor(&self, handle: LocalHandle, length: usize) -> Result<usize, ()> {
+    match self.open_files.write().get_mut(&handle) {
+      Some(open_file) => {
+        open_file.cursor += length;
+        Ok(length)
+      },
+      None => Err(()),
+    }
+  }
+}
+
+impl DeviceDriver for AtaDevice {
+  fn open(&self, handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
+    let open_file = OpenFile {
+      cursor: 0,
+    };
+    self.open_files.write().insert(handle, open_file);
+    Ok(())
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    self.open_files.write().remove(&handle);
+    Ok(())
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let cursor = self.get_cursor(handle)?;
+    let length = block::read_bytes(self, cursor, buffer)?;
+    self.advance_cursor(handle, length)
+  }
+
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
+    let cursor = self.get_cursor(handle)?;
+    let length = block::write_bytes(self, cursor, buffer)?;
+    self.advance_cursor(handle, length)
+  }
+
+  fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
+    match self.open_files.write().get_mut(&handle) {
+      Some(open_file) => {
+        let new_cursor = offset.from_current_position(open_file.cursor);
+        open_file.cursor = new_cursor;
+        Ok(new_cursor)
+      },
+      None => Err(())
+    }
+  }
+
+  fn get_geometry(&self) -> Option<Geometry> {
+    Some(self.geometry)
+  }
+}
+
+impl BlockDevice for AtaDevice {
+  fn block_size(&self) -> usize {
+    SECTOR_SIZE
+  }
+
+  fn capacity(&self) -> usize {
+    self.sector_count
+  }
+
+  fn read_blocks(&self, lba: usize, buffer: &mut [u8]) -> Result<(), ()> {
+    self.channel.read_sectors(self.position, lba, buffer).map_err(|_| ())
+  }
+
+  fn write_blocks(&self, lba: usize, buffer: &[u8]) -> Result<(), ()> {
+    self.channel.write_sectors(self.position, lba, buffer).map_err(|_| ())
+  }
+}
+
+/// Stores metadata associated with a currently open file handle
+struct OpenFile {
+  pub cursor: usize,
+}
@@ -0,0 +1,62 @@
+use crate::audio::{MIXER, mixer::StreamFormat};
+use crate::files::handle::{Handle, LocalHandle};
+use super::driver::DeviceDriver;
+use syscall::files::OpenFlags;
+use syscall::flags::{
+  AUDIO_GET_QUEUED, AUDIO_GET_RATE, AUDIO_GET_VOLUME, AUDIO_SET_FORMAT,
+  AUDIO_SET_RATE, AUDIO_SET_VOLUME,
+};
+
+/// DEV:\AUDIO, where programs write PCM samples to be played. Each handle is a
+/// separate stream in the kernel mixer, so the device can be shared. Writes
+/// accept as much as fits in the stream's queue, and return how many bytes
+/// were taken; the rest should be written again once some has played.
+pub struct AudioDevice {
+
+}
+
+impl AudioDevice {
+  pub const fn new() -> AudioDevice {
+    AudioDevice {
+
+    }
+  }
+}
+
+impl DeviceDriver for AudioDevice {
+  fn open(&self, handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
+    MIXER.lock().add_stream(handle.as_u32());
+    Ok(())
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    MIXER.lock().remove_stream(handle.as_u32());
+    Ok(())
+  }
+
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
+    let mut mixer = MIXER.lock();
+    let stream = mixer.get_stream(handle.as_u32()).ok_or(())?;
+    Ok(stream.queue(buffer))
+  }
+
+  fn ioctl(&self, handle: LocalHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    let id = handle.as_u32();
+    let mut mixer = MIXER.lock();
+    match command {
+      AUDIO_SET_FORMAT => {
+        let rate = mixer.get_stream(id).ok_or(())?.get_format().rate;
+        mixer.set_format(id, StreamFormat { rate, flags: arg }).map(|_| 0)
+      },
+      AUDIO_SET_RATE => {
+        let flags = mixer.get_stream(id).ok_or(())?.get_format().flags;
+        mixer.set_format(id, StreamFormat { rate: arg, flags }).map(|_| 0)
+      },
+      AUDIO_GET_RATE => Ok(mixer.get_stream(id).ok_or(())?.get_format().rate),
+      AUDIO_SET_VOLUME => mixer.set_volume(id, arg).map(|_| 0),
+      AUDIO_GET_VOLUME => mixer.get_volume(id).ok_or(()),
+      AUDIO_GET_QUEUED => Ok(mixer.get_stream(id).ok_or(())?.queued_samples() as u32),
+      _ => Err(()),
+    }
+  }
+}
@@ -1,17 +1,21 @@
 use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use crate::files::handle::LocalHandle;
 use crate::process::id::ProcessID;
-use super::driver::{DeviceDriver};
+use super::driver::{DeviceDriver, OpenPolicy};
 use super::queue::ReadQueue;
 use spin::Mutex;
 
 pub mod serial;
 
 use serial::SerialPort;
+use syscall::files::OpenFlags;
 
 pub struct ComDevice {
   serial: &'static SerialPort,
   queue: Mutex<VecDeque<ProcessID>>,
+  /// Handles opened with OPEN_NONBLOCK, which never wait for data
+  nonblocking: Mutex<Vec<LocalHandle>>,
 }
 
 impl ComDevice {
@@ -19,20 +23,37 @@ impl ComDevice {
     ComDevice {
       serial,
       queue: Mutex::new(VecDeque::with_capacity(2)),
+      nonblocking: Mutex::new(Vec::new()),
     }
   }
 }
 
 impl DeviceDriver for ComDevice {
-  fn open(&self, _handle: LocalHandle) -> Result<(), ()> {
+  fn open(&self, handle: LocalHandle, flags: OpenFlags) -> Result<(), ()> {
+    if flags.is_nonblocking() {
+      self.nonblocking.lock().push(handle);
+    }
     Ok(())
   }
 
-  fn close(&self, _handle: LocalHandle) -> Result<(), ()> {
+  /// Two programs reading the same port would each receive part of the input
+  fn get_open_policy(&self) -> OpenPolicy {
+    OpenPolicy::Exclusive
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    self.nonblocking.lock().retain(|h| *h != handle);
     Ok(())
   }
 
-  fn read(&self, _handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    if self.nonblocking.lock().contains(&handle) {
+      // Don't jump ahead of any blocked readers still waiting their turn
+      if self.get_queue_length() > 0 {
+        return Ok(0);
+      }
+      return Ok(self.read_available_data(buffer));
+    }
     let bytes_read = self.blocking_read(buffer);
 
     Ok(bytes_read)
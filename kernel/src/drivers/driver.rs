@@ -1,12 +1,35 @@
+use crate::disks::geometry::Geometry;
 use crate::files::{cursor::SeekMethod, handle::LocalHandle};
+use syscall::files::OpenFlags;
+
+/// Whether a device can be opened by more than one handle at a time
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OpenPolicy {
+  /// Every handle shares the device, like the console TTYs
+  Shared,
+  /// The device keeps state for a single user, like a serial port, and
+  /// refuses to open again until its handle is closed
+  Exclusive,
+}
 
 pub trait DeviceDriver {
-  fn open(&self, handle: LocalHandle) -> Result<(), ()> {
+  /// Prepare a newly opened handle. The DEV: drive already refuses reads and
+  /// writes the flags do not allow, but drivers may also honor flags like
+  /// OPEN_NONBLOCK.
+  fn open(&self, _handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
     Err(())
   }
 
+  /// How the DEV: drive treats a second open of the device. Any device can
+  /// still be opened exclusively with OPEN_EXCLUSIVE.
+  fn get_open_policy(&self) -> OpenPolicy {
+    OpenPolicy::Shared
+  }
+
+  /// Release any state the driver keeps for a handle. Drivers that track
+  /// nothing per handle have nothing to do.
   fn close(&self, _handle: LocalHandle) -> Result<(), ()> {
-    Err(())
+    Ok(())
   }
 
   fn read(&self, _handle: LocalHandle, _buffer: &mut [u8]) -> Result<usize, ()> {
@@ -20,4 +43,17 @@ pub trait DeviceDriver {
   fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
     Err(())
   }
-}
\ No newline at end of file
+
+  /// Device-specific commands, for anything not covered by reading and
+  /// writing. Commands the DEV filesystem does not handle itself are passed
+  /// through here.
+  fn ioctl(&self, _handle: LocalHandle, _command: u32, _arg: u32) -> Result<u32, ()> {
+    Err(())
+  }
+
+  /// Block devices report the geometry used to translate CHS addresses, for
+  /// software that still relies on them
+  fn get_geometry(&self) -> Option<Geometry> {
+    None
+  }
+}
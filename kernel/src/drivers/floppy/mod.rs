@@ -1,15 +1,20 @@
 use alloc::collections::BTreeMap;
 use crate::devices;
+use crate::disks::block::{self, BlockDevice};
 use crate::files::cursor::SeekMethod;
 use crate::files::handle::LocalHandle;
+use crate::hardware::dma::{Transfer, TransferDirection};
 use crate::memory::address::{PhysicalAddress, VirtualAddress};
+use crate::memory::dma;
 use crate::process;
 use spin::RwLock;
 use super::driver::DeviceDriver;
 
 pub mod sector;
 
-use sector::{Sector, SectorRange};
+use crate::disks::geometry::Geometry;
+use sector::{Sector, SectorRange, FLOPPY_GEOMETRY, SECTOR_SIZE};
+use syscall::files::OpenFlags;
 
 /// Device driver for interacting with data on a floppy disk. It exposes the
 /// floppy disk as a byte stream, and can be used by a filesystem implementation
@@ -30,10 +35,27 @@ impl FloppyDevice {
       open_files: RwLock::new(BTreeMap::new()),
     }
   }
+
+  fn get_cursor(&self, handle: LocalHandle) -> Result<usize, ()> {
+    match self.open_files.read().get(&handle) {
+      Some(open_file) => Ok(open_file.cursor),
+      None => Err(()),
+    }
+  }
+
+  fn advance_cursor(&self, handle: LocalHandle, length: usize) -> Result<usize, ()> {
+    match self.open_files.write().get_mut(&handle) {
+      Some(open_file) => {
+        open_file.cursor += length;
+        Ok(length)
+      },
+      None => Err(()),
+    }
+  }
 }
 
 impl DeviceDriver for FloppyDevice {
-  fn open(&self, handle: LocalHandle) -> Result<(), ()> {
+  fn open(&self, handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
     let open_file = OpenFile {
       cursor: 0,
     };
@@ -47,34 +69,17 @@ impl DeviceDriver for FloppyDevice {
   }
 
   fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
-    let cursor = match self.open_files.read().get(&handle) {
-      Some(open_file) => Ok(open_file.cursor),
-      None => Err(())
-    }?;
-
-    let length = buffer.len();
-    let sectors = SectorRange::for_byte_range(cursor, length);
-
-    let dma_src = load_sectors_to_cache(&sectors, 0x56)?;
-    let local_offset = sectors.get_local_offset(cursor);
-    let dma_src_ptr = (dma_src.as_usize() + local_offset) as *const u8;
-    for i in 0..length {
-      unsafe {
-        buffer[i] = *dma_src_ptr.offset(i as isize);
-      }
-    }
-
-    match self.open_files.write().get_mut(&handle) {
-      Some(open_file) => {
-        open_file.cursor += length;
-        Ok(length)
-      },
-      None => Err(()),
-    }
+    let cursor = self.get_cursor(handle)?;
+    let length = block::read_bytes(self, cursor, buffer)?;
+    self.advance_cursor(handle, length)
   }
 
-  fn write(&self, _handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
-    Ok(0)
+  /// The controller can only write whole sectors, so the block layer reads
+  /// back any sector the write only partly covers before writing it
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
+    let cursor = self.get_cursor(handle)?;
+    let length = block::write_bytes(self, cursor, buffer)?;
+    self.advance_cursor(handle, length)
   }
 
   fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
@@ -87,6 +92,51 @@ impl DeviceDriver for FloppyDevice {
       None => Err(())
     }
   }
+
+  fn get_geometry(&self) -> Option<Geometry> {
+    Some(FLOPPY_GEOMETRY)
+  }
+}
+
+/// Filesystems read the disk a run of sectors at a time. Each run is split
+/// into transfers that fit the DMA buffer and stay on one track.
+impl BlockDevice for FloppyDevice {
+  fn block_size(&self) -> usize {
+    SECTOR_SIZE
+  }
+
+  fn capacity(&self) -> usize {
+    FLOPPY_GEOMETRY.get_total_sectors()
+  }
+
+  fn read_blocks(&self, lba: usize, buffer: &mut [u8]) -> Result<(), ()> {
+    let mut offset = 0;
+    while offset < buffer.len() {
+      let sectors = SectorRange::for_byte_range(lba * SECTOR_SIZE + offset, buffer.len() - offset)
+        .limit(DMA_SIZE / SECTOR_SIZE);
+      let length = sectors.byte_length();
+      let dma_src = load_sectors_to_cache(&sectors)?;
+      let source = unsafe { core::slice::from_raw_parts(dma_src.as_usize() as *const u8, length) };
+      buffer[offset..(offset + length)].copy_from_slice(source);
+      offset += length;
+    }
+    Ok(())
+  }
+
+  fn write_blocks(&self, lba: usize, buffer: &[u8]) -> Result<(), ()> {
+    let mut offset = 0;
+    while offset < buffer.len() {
+      let sectors = SectorRange::for_byte_range(lba * SECTOR_SIZE + offset, buffer.len() - offset)
+        .limit(DMA_SIZE / SECTOR_SIZE);
+      let length = sectors.byte_length();
+      let dma_dest = get_dma_addresses().1;
+      let dest = unsafe { core::slice::from_raw_parts_mut(dma_dest.as_usize() as *mut u8, length) };
+      dest.copy_from_slice(&buffer[offset..(offset + length)]);
+      store_sectors_from_cache(&sectors)?;
+      offset += length;
+    }
+    Ok(())
+  }
 }
 
 /// Stores metadata associated with a currently open file handle
@@ -98,8 +148,12 @@ static DMA_ADDR: RwLock<Option<(PhysicalAddress, VirtualAddress)>> = RwLock::new
 
 const DMA_SIZE: usize = 4096;
 
+/// The floppy controller is always on channel 2
+const DMA_CHANNEL: u8 = 2;
+
 pub fn init_dma() {
-  let address_pair = process::current_process().unwrap().kernel_mmap_dma(DMA_SIZE);
+  let buffer = dma::alloc_dma_buffer(DMA_SIZE).expect("No low memory left for the floppy DMA buffer");
+  let address_pair = (buffer.get_physical_address(), buffer.get_virtual_address());
   let mut dma_addr = DMA_ADDR.write();
   *dma_addr = Some(address_pair);
   crate::tty::console_write(format_args!("Floppy DMA at {:?}/{:?}\n", address_pair.0, address_pair.1));
@@ -117,15 +171,27 @@ pub fn get_dma_addresses() -> (PhysicalAddress, VirtualAddress) {
   }
 }
 
-pub fn load_sectors_to_cache(sectors: &SectorRange, dma_mode: u8) -> Result<VirtualAddress, ()> {
+pub fn load_sectors_to_cache(sectors: &SectorRange) -> Result<VirtualAddress, ()> {
   let (dma_phys, dma_virt) = get_dma_addresses();
   {
-    let channel = devices::DMA.get_channel(2);
-    channel.set_address(dma_phys);
-    channel.set_count(sectors.byte_length() - 1);
-    channel.set_mode(dma_mode);
+    let channel = devices::DMA.get_channel(DMA_CHANNEL);
+    let transfer = Transfer::single(TransferDirection::ToMemory).auto_init();
+    channel.program(dma_phys, sectors.byte_length(), transfer).map_err(|_| ())?;
   }
-  let (c, h, s) = sectors.get_first_sector().to_chs();
+  let (c, h, s) = sectors.get_first_sector().to_chs()?;
   devices::FLOPPY.read(c, h, s).map_err(|_| ())?;
   Ok(dma_virt)
 }
+
+/// Write the contents of the DMA buffer to the sectors in the range
+pub fn store_sectors_from_cache(sectors: &SectorRange) -> Result<(), ()> {
+  let (dma_phys, _) = get_dma_addresses();
+  {
+    let channel = devices::DMA.get_channel(DMA_CHANNEL);
+    let transfer = Transfer::single(TransferDirection::FromMemory);
+    channel.program(dma_phys, sectors.byte_length(), transfer).map_err(|_| ())?;
+  }
+  let (c, h, s) = sectors.get_first_sector().to_chs()?;
+  devices::FLOPPY.write(c, h, s).map_err(|_| ())?;
+  Ok(())
+}
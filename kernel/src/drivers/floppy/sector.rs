@@ -1,16 +1,18 @@
+use crate::disks::geometry::{Geometry, FLOPPY_1440K};
+
 /// Reference to a sector, in LBA format
 #[derive(Copy, Clone)]
 pub struct Sector(usize);
 
-const SECTORS_PER_TRACK: usize = 18;
-const SECTOR_SIZE: usize = 512;
+/// The controller is currently always configured for 1.44M disks
+pub const FLOPPY_GEOMETRY: Geometry = FLOPPY_1440K;
+pub const SECTOR_SIZE: usize = 512;
 
 impl Sector {
-  pub fn to_chs(&self) -> (usize, usize, usize) {
-    let c = self.0 / (2 * SECTORS_PER_TRACK);
-    let h = (self.0 % (2 * SECTORS_PER_TRACK)) / SECTORS_PER_TRACK;
-    let s = (self.0 % (2 * SECTORS_PER_TRACK)) % SECTORS_PER_TRACK + 1;
-    (c, h, s)
+  /// Sectors beyond the end of the disk have no CHS address
+  pub fn to_chs(&self) -> Result<(usize, usize, usize), ()> {
+    let chs = FLOPPY_GEOMETRY.lba_to_chs(self.0).map_err(|_| ())?;
+    Ok((chs.cylinder, chs.head, chs.sector))
   }
 }
 
@@ -33,6 +35,18 @@ impl SectorRange {
     }
   }
 
+  /// Shorten the range so that the controller can transfer it in one
+  /// command: at most `max_count` sectors, ending no later than the track
+  /// that the first sector is on
+  pub fn limit(&self, max_count: usize) -> SectorRange {
+    let per_track = FLOPPY_GEOMETRY.get_sectors_per_track();
+    let left_in_track = per_track - (self.first.0 % per_track);
+    SectorRange {
+      first: self.first,
+      count: self.count.min(max_count).min(left_in_track),
+    }
+  }
+
   pub fn byte_length(&self) -> usize {
     self.count * SECTOR_SIZE
   }
@@ -0,0 +1,42 @@
+use crate::files::handle::LocalHandle;
+use super::driver::{DeviceDriver};
+use syscall::files::OpenFlags;
+
+/// Reads like ZERO, but every write fails as if the disk were full, for
+/// testing how programs handle a failed write
+pub struct FullDevice {
+
+}
+
+impl FullDevice {
+  pub const fn new() -> FullDevice {
+    FullDevice {
+
+    }
+  }
+}
+
+impl DeviceDriver for FullDevice {
+  fn open(&self, _handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
+    Ok(())
+  }
+
+  fn close(&self, _handle: LocalHandle) -> Result<(), ()> {
+    Ok(())
+  }
+
+  fn read(&self, _handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    for byte in buffer.iter_mut() {
+      *byte = 0;
+    }
+    Ok(buffer.len())
+  }
+
+  fn write(&self, _handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
+    if buffer.is_empty() {
+      Ok(0)
+    } else {
+      Err(())
+    }
+  }
+}
@@ -14,6 +14,8 @@ pub enum KeyCode {
   Control = 0x12,
   Menu = 0x13,
   Alt = 0x14,
+  NumLock = 0x15,
+  ScrollLock = 0x16,
 
   Escape = 0x1b,
 
@@ -110,13 +112,17 @@ pub const SCANCODES_TO_KEYCODES: [KeyCode; 60] = [
 ];
 
 pub fn get_keycode(scan_code: u8) -> KeyCode {
-  if scan_code < 60 {
-    SCANCODES_TO_KEYCODES[scan_code as usize]
-  } else {
-    KeyCode::None
+  match scan_code {
+    0..=59 => SCANCODES_TO_KEYCODES[scan_code as usize],
+    0x45 => KeyCode::NumLock,
+    0x46 => KeyCode::ScrollLock,
+    _ => KeyCode::None,
   }
 }
 
+/// Scan codes past the end of SCANCODES_TO_KEYCODES that map to a key
+const LOCK_SCANCODES: [u8; 2] = [0x45, 0x46];
+
 pub fn get_extended_keycode(scan_code: u8) -> KeyCode {
   match scan_code {
     0x1c => KeyCode::Enter,
@@ -127,3 +133,19 @@ pub fn get_extended_keycode(scan_code: u8) -> KeyCode {
     _ => KeyCode::None,
   }
 }
+
+/// Scan codes, following an 0xe0 prefix, that map to a key
+const EXTENDED_SCANCODES: [u8; 5] = [0x1c, 0x48, 0x4b, 0x4d, 0x50];
+
+/// Find the key with a numeric code, as long as some scan code can produce it
+pub fn keycode_from_u8(value: u8) -> Option<KeyCode> {
+  if value == KeyCode::None as u8 {
+    return None;
+  }
+  let locks = LOCK_SCANCODES.iter().map(|scan_code| get_keycode(*scan_code));
+  let extended = EXTENDED_SCANCODES.iter().map(|scan_code| get_extended_keycode(*scan_code));
+  SCANCODES_TO_KEYCODES.iter().copied()
+    .chain(locks)
+    .chain(extended)
+    .find(|code| *code as u8 == value)
+}
@@ -1,18 +1,113 @@
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::devices;
 use crate::files::handle::LocalHandle;
+use crate::process;
 use crate::tty;
+use crate::workers;
 use crate::x86::io::Port;
 use spin::Mutex;
 use super::driver::DeviceDriver;
 
 pub mod codes;
 pub mod readers;
+pub mod sticky;
 
 use codes::KeyCode;
+use syscall::files::OpenFlags;
+use syscall::flags::{
+  KBD_GET_KEYMAP, KBD_GET_REPEAT_DELAY, KBD_GET_REPEAT_RATE, KBD_GET_STICKY,
+  KBD_RESET_KEYMAP, KBD_SET_KEYMAP, KBD_SET_REPEAT_DELAY, KBD_SET_REPEAT_RATE, KBD_SET_STICKY,
+};
+use syscall::input::{KeyEvent, KEY_PRESS, KEY_RELEASE, KEY_REPEAT};
+
+/// Sent by the controller to acknowledge a command, or to ask for a resend
+const RESPONSE_ACK: u8 = 0xfa;
+const RESPONSE_RESEND: u8 = 0xfe;
+
+const COMMAND_SET_LEDS: u8 = 0xed;
+const COMMAND_SET_TYPEMATIC: u8 = 0xf3;
+
+/// How long to wait before trying again to update the LEDs, if the keyboard
+/// is busy
+const LED_RETRY_MS: usize = 10;
+
+/// Keymap entries are indexed by scan code, with the top bit set for codes
+/// that follow an 0xe0 prefix
+const KEYMAP_SIZE: usize = 0x100;
+const KEYMAP_EXTENDED: usize = 0x80;
+
+/// Repeat rates supported by the controller, in tenths of a character per
+/// second, indexed by the low five bits of the typematic byte
+const REPEAT_RATES: [u32; 32] = [
+  300, 267, 240, 218, 200, 185, 171, 160,
+  150, 133, 120, 109, 100, 92, 86, 80,
+  75, 67, 60, 55, 50, 46, 43, 40,
+  37, 33, 30, 27, 25, 23, 21, 20,
+];
+
+/// How keys repeat while held down
+#[derive(Copy, Clone)]
+pub struct Typematic {
+  /// Index into REPEAT_RATES
+  rate: u8,
+  /// Delay before repeating, in units of 250ms minus one
+  delay: u8,
+}
+
+impl Typematic {
+  /// The rate and delay a keyboard uses after it is reset
+  pub const fn default() -> Typematic {
+    Typematic {
+      rate: 0x0b,
+      delay: 1,
+    }
+  }
+
+  pub fn get_rate(&self) -> u32 {
+    REPEAT_RATES[self.rate as usize] / 10
+  }
+
+  /// Pick the supported rate closest to a number of characters per second
+  pub fn set_rate(&mut self, per_second: u32) {
+    let target = per_second.saturating_mul(10);
+    let mut best = 0;
+    for (index, rate) in REPEAT_RATES.iter().enumerate() {
+      let distance = if *rate > target { rate - target } else { target - rate };
+      let best_rate = REPEAT_RATES[best];
+      let best_distance = if best_rate > target { best_rate - target } else { target - best_rate };
+      if distance < best_distance {
+        best = index;
+      }
+    }
+    self.rate = best as u8;
+  }
+
+  pub fn get_delay(&self) -> u32 {
+    (self.delay as u32 + 1) * 250
+  }
+
+  pub fn set_delay(&mut self, ms: u32) {
+    let steps = (ms + 125) / 250;
+    self.delay = (steps.max(1).min(4) - 1) as u8;
+  }
+
+  pub fn to_byte(&self) -> u8 {
+    (self.delay << 5) | self.rate
+  }
+}
 
 pub struct Keyboard {
   receiving_extended_code: bool,
   data: Port,
+  status: Port,
+  typematic: Typematic,
+  /// Bitmap of the key codes currently held down, so that repeats sent by the
+  /// keyboard can be told apart from new presses
+  held_keys: u128,
+  /// Keys that have been remapped from their default scan code
+  keymap: [Option<KeyCode>; KEYMAP_SIZE],
+  sticky: sticky::StickyKeys,
 
   open_readers: Mutex<readers::OpenReaders>,
 }
@@ -28,21 +123,43 @@ impl Keyboard {
     Keyboard {
       receiving_extended_code: false,
       data: Port::new(0x60),
+      status: Port::new(0x64),
+      typematic: Typematic::default(),
+      held_keys: 0,
+      keymap: [None; KEYMAP_SIZE],
+      sticky: sticky::StickyKeys::new(),
       open_readers: Mutex::new(readers::OpenReaders::new()),
     }
   }
 
-  pub fn handle_data(&mut self, data: u8) {
-    match self.generate_action_from_scan_code(data) {
-      Some(action) => {
-        self.process_action(action);
-        tty::get_router().write().send_key_action(action);
-      },
-      None => (),
+  /// Handle a scan code, along with the time it was received in milliseconds
+  /// since boot
+  pub fn handle_data(&mut self, data: u8, time: u32) {
+    let action = match self.generate_action_from_scan_code(data) {
+      Some(action) => action,
+      None => return,
+    };
+    let mut actions = Vec::with_capacity(4);
+    self.sticky.process(action, &mut actions);
+    self.dispatch(actions, time);
+  }
+
+  /// Deliver actions to open readers and to the TTYs
+  fn dispatch(&mut self, actions: Vec<KeyAction>, time: u32) {
+    for action in actions {
+      self.process_action(action, time);
+      let job_signal = tty::get_router().write().send_key_action(action);
+      if let Some((group, sig)) = job_signal {
+        process::send_signal_to_group(group, sig);
+      }
     }
   }
 
   pub fn generate_action_from_scan_code(&mut self, scan_code: u8) -> Option<KeyAction> {
+    if scan_code == RESPONSE_ACK || scan_code == RESPONSE_RESEND {
+      // Replies to commands are not keys
+      return None;
+    }
     if scan_code == 0xe0 {
       self.receiving_extended_code = true;
       return None;
@@ -50,11 +167,12 @@ impl Keyboard {
     let scan_code_key = scan_code & 0x7f;
     let pressed = scan_code & 0x80 == 0;
 
-    let key_code = if self.receiving_extended_code {
-      codes::get_extended_keycode(scan_code_key)
+    let (key_code, keymap_index) = if self.receiving_extended_code {
+      (codes::get_extended_keycode(scan_code_key), scan_code_key as usize | KEYMAP_EXTENDED)
     } else {
-      codes::get_keycode(scan_code_key)
+      (codes::get_keycode(scan_code_key), scan_code_key as usize)
     };
+    let key_code = self.keymap[keymap_index].unwrap_or(key_code);
 
     self.receiving_extended_code = false;
 
@@ -68,23 +186,127 @@ impl Keyboard {
     }
   }
 
-  pub fn process_action(&mut self, action: KeyAction) {
+  pub fn process_action(&mut self, action: KeyAction, time: u32) {
+    let event = match action {
+      KeyAction::Press(code) => {
+        let bit = 1u128 << (code as u8 & 0x7f);
+        let repeat = self.held_keys & bit != 0;
+        self.held_keys |= bit;
+        KeyEvent {
+          action: if repeat { KEY_REPEAT } else { KEY_PRESS },
+          key_code: code as u8,
+          time,
+        }
+      },
+      KeyAction::Release(code) => {
+        self.held_keys &= !(1u128 << (code as u8 & 0x7f));
+        KeyEvent {
+          action: KEY_RELEASE,
+          key_code: code as u8,
+          time,
+        }
+      },
+    };
+    let bytes = event.to_bytes();
     let mut open_readers = self.open_readers.lock();
     for (_, codes) in open_readers.get_map().iter_mut() {
-      match action {
-        KeyAction::Press(code) => {
-          codes.push(1);
-          codes.push(code as u8);
-        },
-        KeyAction::Release(code) => {
-          codes.push(2);
-          codes.push(code as u8);
+      codes.extend_from_slice(&bytes);
+    }
+  }
+
+  /// Look up the key produced by a scan code, after any remapping
+  pub fn get_mapping(&self, index: usize) -> Option<KeyCode> {
+    if index >= KEYMAP_SIZE {
+      return None;
+    }
+    match self.keymap[index] {
+      Some(code) => Some(code),
+      None => {
+        let code = if index & KEYMAP_EXTENDED != 0 {
+          codes::get_extended_keycode((index & 0x7f) as u8)
+        } else {
+          codes::get_keycode(index as u8)
+        };
+        match code {
+          KeyCode::None => None,
+          _ => Some(code),
         }
-      }
+      },
+    }
+  }
+
+  /// Make a scan code produce a different key, or restore its default if
+  /// `code` is None
+  pub fn set_mapping(&mut self, index: usize, code: Option<KeyCode>) -> Result<(), ()> {
+    if index >= KEYMAP_SIZE {
+      return Err(());
+    }
+    self.keymap[index] = code;
+    Ok(())
+  }
+
+  pub fn reset_mappings(&mut self) {
+    self.keymap = [None; KEYMAP_SIZE];
+  }
+
+  pub fn is_sticky(&self) -> bool {
+    self.sticky.is_enabled()
+  }
+
+  pub fn set_sticky(&mut self, enabled: bool) {
+    let mut actions = Vec::new();
+    self.sticky.set_enabled(enabled, &mut actions);
+    let time = crate::time::system::get_uptime().in_ms() as u32;
+    self.dispatch(actions, time);
+  }
+
+  pub fn get_typematic(&self) -> Typematic {
+    self.typematic
+  }
+
+  /// Send new repeat settings to the keyboard
+  pub fn set_typematic(&mut self, typematic: Typematic) {
+    self.typematic = typematic;
+    // The controller acknowledges each byte; those replies arrive through the
+    // interrupt handler and are discarded there
+    self.send_byte(COMMAND_SET_TYPEMATIC);
+    self.send_byte(typematic.to_byte());
+  }
+
+  /// Light the LEDs, with bits in the order Scroll, Num, Caps
+  pub fn set_leds(&mut self, leds: u8) {
+    self.send_byte(COMMAND_SET_LEDS);
+    self.send_byte(leds & 7);
+  }
+
+  fn send_byte(&self, value: u8) {
+    unsafe {
+      // Wait for the controller's input buffer to be empty
+      while self.status.read_u8() & 2 != 0 {}
+      self.data.write_u8(value);
     }
   }
 }
 
+/// Update the keyboard LEDs. Key presses are routed to the TTYs while the
+/// keyboard is locked, so the LEDs are set later from the timer process.
+pub fn update_leds(leds: u8) {
+  let _ = workers::schedule_delayed_work(move || apply_leds(leds), 0);
+}
+
+fn apply_leds(leds: u8) {
+  let keyboard = match unsafe { &devices::KEYBOARD } {
+    Some(keyboard) => keyboard,
+    None => return,
+  };
+  match keyboard.try_lock() {
+    Some(mut keyboard) => keyboard.set_leds(leds),
+    None => {
+      let _ = workers::schedule_delayed_work(move || apply_leds(leds), LED_RETRY_MS);
+    },
+  }
+}
+
 pub struct KeyboardDevice {
   keyboard: Arc<Mutex<Keyboard>>,
 }
@@ -98,7 +320,7 @@ impl KeyboardDevice {
 }
 
 impl DeviceDriver for KeyboardDevice {
-  fn open(&self, handle: LocalHandle) -> Result<(), ()> {
+  fn open(&self, handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
     let keyboard = self.keyboard.lock();
     let mut open_readers = keyboard.open_readers.lock();
     open_readers.open(handle);
@@ -122,4 +344,47 @@ impl DeviceDriver for KeyboardDevice {
   fn write(&self, _handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
     Ok(0)
   }
+
+  fn ioctl(&self, _handle: LocalHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    let mut keyboard = self.keyboard.lock();
+    let mut typematic = keyboard.get_typematic();
+    match command {
+      KBD_GET_REPEAT_RATE => Ok(typematic.get_rate()),
+      KBD_SET_REPEAT_RATE => {
+        typematic.set_rate(arg);
+        keyboard.set_typematic(typematic);
+        Ok(typematic.get_rate())
+      },
+      KBD_GET_REPEAT_DELAY => Ok(typematic.get_delay()),
+      KBD_SET_REPEAT_DELAY => {
+        typematic.set_delay(arg);
+        keyboard.set_typematic(typematic);
+        Ok(typematic.get_delay())
+      },
+      KBD_GET_KEYMAP => {
+        let code = keyboard.get_mapping(arg as usize);
+        Ok(code.map_or(0, |code| code as u32))
+      },
+      KBD_SET_KEYMAP => {
+        let index = (arg & 0xff) as usize;
+        let value = ((arg >> 8) & 0xff) as u8;
+        let code = if value == 0 {
+          None
+        } else {
+          Some(codes::keycode_from_u8(value).ok_or(())?)
+        };
+        keyboard.set_mapping(index, code).map(|_| 0)
+      },
+      KBD_RESET_KEYMAP => {
+        keyboard.reset_mappings();
+        Ok(0)
+      },
+      KBD_GET_STICKY => Ok(keyboard.is_sticky() as u32),
+      KBD_SET_STICKY => {
+        keyboard.set_sticky(arg != 0);
+        Ok(0)
+      },
+      _ => Err(()),
+    }
+  }
 }
@@ -1,5 +1,6 @@
 use alloc::{collections::BTreeMap, vec::Vec};
 use crate::files::handle::LocalHandle;
+use syscall::input::KEY_EVENT_SIZE;
 
 pub struct OpenReaders {
   map: BTreeMap<LocalHandle, Vec<u8>>,
@@ -19,15 +20,13 @@ impl OpenReaders {
   pub fn read(&mut self, handle: LocalHandle, buffer: &mut [u8]) -> usize {
     match self.map.get_mut(&handle) {
       Some(entry) => {
+        // Only whole events are returned, oldest first
         let mut read_len = entry.len();
         if buffer.len() < read_len {
-          read_len = buffer.len();
+          read_len = buffer.len() - (buffer.len() % KEY_EVENT_SIZE);
         }
-        for i in 0..read_len {
-          buffer[i] = match entry.pop() {
-            Some(code) => code,
-            None => 0,
-          };
+        for (dest, code) in buffer.iter_mut().zip(entry.drain(..read_len)) {
+          *dest = code;
         }
         read_len
       },
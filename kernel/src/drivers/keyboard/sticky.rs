@@ -0,0 +1,109 @@
+use alloc::vec::Vec;
+use super::KeyAction;
+use super::codes::KeyCode;
+
+/// Modifiers that can be latched
+const MODIFIERS: [KeyCode; 3] = [KeyCode::Shift, KeyCode::Control, KeyCode::Alt];
+
+fn modifier_bit(code: KeyCode) -> u8 {
+  for (index, modifier) in MODIFIERS.iter().enumerate() {
+    if *modifier as u8 == code as u8 {
+      return 1 << index;
+    }
+  }
+  0
+}
+
+/**
+ * Sticky keys let modifiers be typed one at a time instead of held together.
+ * Tapping a modifier on its own latches it: its release is held back until the
+ * next ordinary key has been pressed and released. Tapping a latched modifier
+ * again releases it without typing anything.
+ */
+pub struct StickyKeys {
+  enabled: bool,
+  /// Modifiers whose release is being held back
+  latched: u8,
+  /// Modifiers that are physically down
+  held: u8,
+  /// Modifiers that were combined with another key while held, and so should
+  /// be released normally
+  chorded: u8,
+  /// Latched modifiers that were pressed again, and will be released when the
+  /// key comes back up
+  unlatching: u8,
+}
+
+impl StickyKeys {
+  pub const fn new() -> StickyKeys {
+    StickyKeys {
+      enabled: false,
+      latched: 0,
+      held: 0,
+      chorded: 0,
+      unlatching: 0,
+    }
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.enabled
+  }
+
+  /// Turning sticky keys off releases any modifiers that are still latched
+  pub fn set_enabled(&mut self, enabled: bool, output: &mut Vec<KeyAction>) {
+    self.enabled = enabled;
+    if !enabled {
+      self.release_latched(output);
+      self.chorded = 0;
+      self.unlatching = 0;
+    }
+  }
+
+  /// Translate a physical key action into the actions seen by the rest of
+  /// the system
+  pub fn process(&mut self, action: KeyAction, output: &mut Vec<KeyAction>) {
+    if !self.enabled {
+      output.push(action);
+      return;
+    }
+    match action {
+      KeyAction::Press(code) => {
+        let bit = modifier_bit(code);
+        if bit == 0 {
+          self.chorded |= self.held;
+        } else if self.latched & bit != 0 {
+          self.latched &= !bit;
+          self.unlatching |= bit;
+        }
+        self.held |= bit;
+        output.push(action);
+      },
+      KeyAction::Release(code) => {
+        let bit = modifier_bit(code);
+        if bit == 0 {
+          output.push(action);
+          self.release_latched(output);
+          return;
+        }
+        self.held &= !bit;
+        let tapped = (self.chorded | self.unlatching) & bit == 0;
+        self.chorded &= !bit;
+        self.unlatching &= !bit;
+        if tapped {
+          self.latched |= bit;
+        } else {
+          output.push(action);
+        }
+      },
+    }
+  }
+
+  fn release_latched(&mut self, output: &mut Vec<KeyAction>) {
+    for modifier in MODIFIERS.iter() {
+      if self.latched & modifier_bit(*modifier) != 0 {
+        output.push(KeyAction::Release(*modifier));
+      }
+    }
+    self.latched = 0;
+  }
+}
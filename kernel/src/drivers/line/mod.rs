@@ -0,0 +1,134 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use crate::files::{cursor::SeekMethod, handle::LocalHandle};
+use crate::line_editor::{LineEditor, WordList};
+use crate::syscalls::user::Caller;
+use spin::Mutex;
+use super::DriverType;
+use syscall::files::OpenFlags;
+use syscall::flags::{TCGETS, TCSETS, TTY_CANONICAL, TTY_ECHO, TTY_SET_COMPLETIONS};
+use super::driver::{DeviceDriver, OpenPolicy};
+
+/// Wraps a character device attached to a terminal, like a serial port, and
+/// gives it the same canonical mode as the console TTYs. Until TCSETS turns
+/// on TTY_CANONICAL, reads and writes pass straight through. In canonical
+/// mode, reads collect input from the device and echo edits back to it, and
+/// only return once a whole line has been typed.
+pub struct LineDevice {
+  inner: Box<DriverType>,
+  state: Mutex<LineState>,
+}
+
+struct LineState {
+  canonical: bool,
+  echo: bool,
+  editor: LineEditor,
+  completions: WordList,
+}
+
+impl LineDevice {
+  pub fn new(inner: Box<DriverType>) -> LineDevice {
+    LineDevice {
+      inner,
+      state: Mutex::new(LineState {
+        canonical: false,
+        echo: true,
+        editor: LineEditor::new(),
+        completions: WordList::new(),
+      }),
+    }
+  }
+}
+
+/// Read the list of words for TTY_SET_COMPLETIONS, from the StringPtr the
+/// ioctl argument points to
+pub fn read_completions(arg: u32) -> Result<WordList, ()> {
+  let text = Caller::new(true).read_text(arg).map_err(|_| ())?;
+  Ok(WordList::from_text(&text))
+}
+
+impl DeviceDriver for LineDevice {
+  fn open(&self, handle: LocalHandle, flags: OpenFlags) -> Result<(), ()> {
+    self.inner.open(handle, flags)
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    self.inner.close(handle)
+  }
+
+  fn get_open_policy(&self) -> OpenPolicy {
+    self.inner.get_open_policy()
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let mut raw: [u8; 16] = [0; 16];
+    loop {
+      {
+        let mut state = self.state.lock();
+        if !state.canonical {
+          break;
+        }
+        if state.editor.has_line() {
+          return Ok(state.editor.read(buffer));
+        }
+      }
+      // The device may block, so the state is not held while it is read
+      let count = self.inner.read(handle, &mut raw)?;
+      if count == 0 {
+        // A non-blocking handle with nothing waiting
+        return Ok(0);
+      }
+      let mut echo = Vec::new();
+      {
+        let mut state = self.state.lock();
+        let LineState { editor, completions, .. } = &mut *state;
+        for byte in raw[..count].iter() {
+          editor.input(*byte, &mut echo, &*completions);
+        }
+        if !state.echo {
+          echo.clear();
+        }
+      }
+      if !echo.is_empty() {
+        self.inner.write(handle, &echo)?;
+      }
+    }
+    self.inner.read(handle, buffer)
+  }
+
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
+    self.inner.write(handle, buffer)
+  }
+
+  fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
+    self.inner.seek(handle, offset)
+  }
+
+  fn ioctl(&self, handle: LocalHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    match command {
+      TCGETS => {
+        let state = self.state.lock();
+        let mut flags = 0;
+        if state.echo {
+          flags |= TTY_ECHO;
+        }
+        if state.canonical {
+          flags |= TTY_CANONICAL;
+        }
+        Ok(flags)
+      },
+      TCSETS => {
+        let mut state = self.state.lock();
+        state.echo = arg & TTY_ECHO != 0;
+        state.canonical = arg & TTY_CANONICAL != 0;
+        Ok(0)
+      },
+      TTY_SET_COMPLETIONS => {
+        let completions = read_completions(arg)?;
+        self.state.lock().completions = completions;
+        Ok(0)
+      },
+      _ => self.inner.ioctl(handle, command, arg),
+    }
+  }
+}
@@ -0,0 +1,43 @@
+use crate::files::handle::LocalHandle;
+use crate::memory::pressure::{self, MEMORY_EVENTS};
+use super::driver::DeviceDriver;
+use syscall::files::OpenFlags;
+
+/// DEV:\MEMEV reports changes in memory pressure. The first read after opening
+/// returns the current level, and later reads return zero or more whole event
+/// records for each change since; a program that wants to wait for changes
+/// should poll it.
+pub struct MemoryEventDevice {
+
+}
+
+impl MemoryEventDevice {
+  pub const fn new() -> MemoryEventDevice {
+    MemoryEventDevice {
+
+    }
+  }
+}
+
+impl DeviceDriver for MemoryEventDevice {
+  fn open(&self, handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
+    MEMORY_EVENTS.lock().listen(handle, pressure::get_current_event());
+    Ok(())
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    MEMORY_EVENTS.lock().stop_listening(handle);
+    Ok(())
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let mut events = MEMORY_EVENTS.lock();
+    // A change made while the queue was busy has not been published yet
+    events.catch_up(pressure::get_current_event());
+    events.read(handle, buffer)
+  }
+
+  fn write(&self, _handle: LocalHandle, _buffer: &[u8]) -> Result<usize, ()> {
+    Err(())
+  }
+}
@@ -2,13 +2,20 @@ use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
+pub mod ata;
+pub mod audio;
 pub mod blocking;
 pub mod com;
 pub mod driver;
 pub mod floppy;
+pub mod full;
 pub mod keyboard;
+pub mod line;
+pub mod memev;
+pub mod mountev;
 pub mod null;
 pub mod queue;
+pub mod ramdisk;
 pub mod zero;
 
 pub type DeviceName = [u8; 8];
@@ -51,22 +58,51 @@ impl DeviceDrivers {
     self.get_device(number)
   }
 
+  /// Fetch the name and number of the nth registered device, in the order
+  /// they were registered
+  pub fn get_device_entry(&self, index: usize) -> Option<(DeviceName, usize)> {
+    self.device_names.get(index).map(|entry| (entry.0, entry.1))
+  }
+
   pub fn register_driver(&mut self, name: &str, driver: Arc<Box<DriverType>>) -> usize {
-    let mut name_array: [u8; 8] = [0x20; 8];
-    if name.len() > 8 {
+    let name_array = match device_name(name) {
+      Some(name) => name,
       // Too long
-      return 0;
-    }
-    let name_bytes = name.as_bytes();
-    let mut index = 0;
-    while index < 8 && index < name_bytes.len() {
-      name_array[index] = name_bytes[index];
-      index += 1;
-    }
+      None => return 0,
+    };
 
     self.drivers.push(driver);
     let index = self.drivers.len();
     self.device_names.push(DeviceNumberByName(name_array, index));
     index
   }
-}
\ No newline at end of file
+
+  /// Give an already registered device another name, which opens the same
+  /// device
+  pub fn register_alias(&mut self, name: &str, driver_number: usize) -> bool {
+    let name_array = match device_name(name) {
+      Some(name) => name,
+      None => return false,
+    };
+    if self.get_device(driver_number).is_none() || self.get_device_number_by_name(&name_array).is_some() {
+      return false;
+    }
+    self.device_names.push(DeviceNumberByName(name_array, driver_number));
+    true
+  }
+}
+
+/// Device names are stored padded with spaces to eight bytes
+fn device_name(name: &str) -> Option<DeviceName> {
+  let mut name_array: [u8; 8] = [0x20; 8];
+  if name.len() > 8 {
+    return None;
+  }
+  let name_bytes = name.as_bytes();
+  let mut index = 0;
+  while index < 8 && index < name_bytes.len() {
+    name_array[index] = name_bytes[index];
+    index += 1;
+  }
+  Some(name_array)
+}
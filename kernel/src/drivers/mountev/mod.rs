@@ -0,0 +1,39 @@
+use crate::files::handle::LocalHandle;
+use crate::filesystems::events::MOUNT_EVENTS;
+use super::driver::DeviceDriver;
+use syscall::files::OpenFlags;
+
+/// DEV:\MOUNTEV reports drives being mounted, unmounted, or having their media
+/// swapped. Each read returns zero or more whole event records; a program that
+/// wants to wait for changes should poll it.
+pub struct MountEventDevice {
+
+}
+
+impl MountEventDevice {
+  pub const fn new() -> MountEventDevice {
+    MountEventDevice {
+
+    }
+  }
+}
+
+impl DeviceDriver for MountEventDevice {
+  fn open(&self, handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
+    MOUNT_EVENTS.lock().listen(handle);
+    Ok(())
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    MOUNT_EVENTS.lock().stop_listening(handle);
+    Ok(())
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    MOUNT_EVENTS.lock().read(handle, buffer)
+  }
+
+  fn write(&self, _handle: LocalHandle, _buffer: &[u8]) -> Result<usize, ()> {
+    Err(())
+  }
+}
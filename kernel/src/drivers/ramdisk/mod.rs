@@ -0,0 +1,106 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use crate::disks::block::{self, BlockDevice};
+use crate::disks::snapshot::SnapshotDevice;
+use crate::files::cursor::SeekMethod;
+use crate::files::handle::LocalHandle;
+use spin::RwLock;
+use super::driver::DeviceDriver;
+use syscall::files::OpenFlags;
+use syscall::flags::{
+  DISK_SNAPSHOT_COMMIT, DISK_SNAPSHOT_CREATE, DISK_SNAPSHOT_DISCARD,
+  DISK_SNAPSHOT_GET_CHANGED,
+};
+
+/// Byte-stream access to a memory-backed disk, like DEV:\RAM0. The disk sits
+/// behind a snapshot layer, which is controlled with the DISK_SNAPSHOT_*
+/// ioctls. Like the hardware disk drivers, reads and writes that do not line
+/// up with block boundaries go through the block layer, which expands them to
+/// cover whole blocks.
+pub struct RamDiskDevice {
+  disk: Arc<SnapshotDevice>,
+  open_files: RwLock<BTreeMap<LocalHandle, OpenFile>>,
+}
+
+impl RamDiskDevice {
+  pub fn new(disk: Arc<SnapshotDevice>) -> RamDiskDevice {
+    RamDiskDevice {
+      disk,
+      open_files: RwLock::new(BTreeMap::new()),
+    }
+  }
+
+  pub fn get_byte_size(&self) -> usize {
+    self.disk.capacity() * self.disk.block_size()
+  }
+
+  fn get_cursor(&self, handle: LocalHandle) -> Result<usize, ()> {
+    match self.open_files.read().get(&handle) {
+      Some(open_file) => Ok(open_file.cursor),
+      None => Err(()),
+    }
+  }
+
+  fn advance_cursor(&self, handle: LocalHandle, length: usize) -> Result<usize, ()> {
+    match self.open_files.write().get_mut(&handle) {
+      Some(open_file) => {
+        open_file.cursor += length;
+        Ok(length)
+      },
+      None => Err(()),
+    }
+  }
+}
+
+impl DeviceDriver for RamDiskDevice {
+  fn open(&self, handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
+    let open_file = OpenFile {
+      cursor: 0,
+    };
+    self.open_files.write().insert(handle, open_file);
+    Ok(())
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    self.open_files.write().remove(&handle);
+    Ok(())
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let cursor = self.get_cursor(handle)?;
+    let length = block::read_bytes(&*self.disk, cursor, buffer)?;
+    self.advance_cursor(handle, length)
+  }
+
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
+    let cursor = self.get_cursor(handle)?;
+    let length = block::write_bytes(&*self.disk, cursor, buffer)?;
+    self.advance_cursor(handle, length)
+  }
+
+  fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
+    match self.open_files.write().get_mut(&handle) {
+      Some(open_file) => {
+        let new_cursor = offset.from_current_position(open_file.cursor);
+        open_file.cursor = new_cursor;
+        Ok(new_cursor)
+      },
+      None => Err(())
+    }
+  }
+
+  fn ioctl(&self, _handle: LocalHandle, command: u32, _arg: u32) -> Result<u32, ()> {
+    match command {
+      DISK_SNAPSHOT_CREATE => self.disk.create().map(|_| 0).map_err(|_| ()),
+      DISK_SNAPSHOT_DISCARD => self.disk.discard().map(|_| 0).map_err(|_| ()),
+      DISK_SNAPSHOT_COMMIT => self.disk.commit().map(|_| 0).map_err(|_| ()),
+      DISK_SNAPSHOT_GET_CHANGED => self.disk.get_changed_blocks().map(|count| count as u32).ok_or(()),
+      _ => Err(()),
+    }
+  }
+}
+
+/// Stores metadata associated with a currently open file handle
+struct OpenFile {
+  pub cursor: usize,
+}
@@ -1,5 +1,6 @@
 use crate::files::handle::LocalHandle;
 use super::driver::{DeviceDriver};
+use syscall::files::OpenFlags;
 
 pub struct ZeroDevice {
 
@@ -14,7 +15,7 @@ impl ZeroDevice {
 }
 
 impl DeviceDriver for ZeroDevice {
-  fn open(&self, _handle: LocalHandle) -> Result<(), ()> {
+  fn open(&self, _handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
     Ok(())
   }
 
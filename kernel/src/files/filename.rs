@@ -1,3 +1,6 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
 /**
  * Split a path into its drive and local path components
  */
@@ -21,3 +24,146 @@ pub fn string_to_drive_and_path(raw: &str) -> (&str, &str) {
     },
   }
 }
+
+/**
+ * Combine a path with a working directory, producing an absolute path that
+ * always names a drive. `.` and `..` components are resolved, and `..` never
+ * climbs above the root of a drive. A relative path on a drive other than the
+ * working directory's is resolved from the root of that drive.
+ */
+pub fn resolve_path(cwd: &str, raw: &str) -> String {
+  let (cwd_drive, cwd_path) = string_to_drive_and_path(cwd);
+  let (drive, path) = string_to_drive_and_path(raw);
+  let (drive, base) = if drive.len() == 0 {
+    (cwd_drive, cwd_path)
+  } else if drive.eq_ignore_ascii_case(cwd_drive) {
+    (drive, cwd_path)
+  } else {
+    (drive, "")
+  };
+  let base = if path.starts_with('\\') {
+    ""
+  } else {
+    base
+  };
+
+  let mut components: Vec<&str> = Vec::new();
+  for part in base.split('\\').chain(path.split('\\')) {
+    match part {
+      "" | "." => (),
+      ".." => {
+        components.pop();
+      },
+      _ => components.push(part),
+    }
+  }
+
+  let mut resolved = String::from(drive);
+  resolved.push(':');
+  if components.is_empty() {
+    resolved.push('\\');
+  }
+  for component in components {
+    resolved.push('\\');
+    resolved.push_str(component);
+  }
+  resolved
+}
+
+/// Names DOS reserves in every directory, for its standard devices
+const DOS_DEVICE_NAMES: [&str; 4] = ["AUX", "CON", "NUL", "PRN"];
+
+/// DOS treats a reserved device name anywhere in the filesystem as the device
+/// itself, with or without an extension, and also as a drive name like
+/// `NUL:`. If an absolute path names one of these devices, return the path of
+/// that device on the DEV: drive.
+pub fn dos_device_path(path: &str) -> Option<String> {
+  let (drive, local) = string_to_drive_and_path(path);
+  if drive.eq_ignore_ascii_case("DEV") {
+    return None;
+  }
+  let last = local.rsplit('\\').next().unwrap_or("");
+  let name = if last.is_empty() {
+    drive
+  } else {
+    last.split('.').next().unwrap_or("")
+  };
+  let device = DOS_DEVICE_NAMES.iter().find(|device| device.eq_ignore_ascii_case(name))?;
+  let mut device_path = String::from("DEV:\\");
+  device_path.push_str(device);
+  Some(device_path)
+}
+
+/// Suffix given to the file that holds new contents while they are written
+pub const TEMPORARY_SUFFIX: &str = ".$$$";
+
+/// Path of the scratch file used when atomically replacing `path`. It is kept
+/// in the same directory, since only a move within a directory can be made
+/// atomic.
+pub fn temporary_path(path: &str) -> String {
+  let mut temp = String::from(path.trim_end_matches('\\'));
+  temp.push_str(TEMPORARY_SUFFIX);
+  temp
+}
+
+/// Name of the program in a command line: the file name of the executable,
+/// without its drive or directories
+pub fn program_name(command_line: &str) -> &str {
+  let path = command_line.split(' ').next().unwrap_or("");
+  match path.rfind(|ch| ch == '\\' || ch == ':') {
+    Some(index) => &path[(index + 1)..],
+    None => path,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resolves_relative_paths() {
+    assert_eq!(resolve_path("A:\\", "FILE.TXT"), "A:\\FILE.TXT");
+    assert_eq!(resolve_path("A:\\DOCS", "NOTES\\.\\A.TXT"), "A:\\DOCS\\NOTES\\A.TXT");
+    assert_eq!(resolve_path("A:\\DOCS\\OLD", "..\\NEW"), "A:\\DOCS\\NEW");
+    assert_eq!(resolve_path("A:\\DOCS", "\\ROOT.TXT"), "A:\\ROOT.TXT");
+    assert_eq!(resolve_path("A:\\DOCS", ""), "A:\\DOCS");
+  }
+
+  #[test]
+  fn stays_within_drive_root() {
+    assert_eq!(resolve_path("A:\\", "..\\..\\X"), "A:\\X");
+    assert_eq!(resolve_path("A:\\DOCS", ".."), "A:\\");
+  }
+
+  #[test]
+  fn resolves_other_drives() {
+    assert_eq!(resolve_path("A:\\DOCS", "DEV:\\TTY0"), "DEV:\\TTY0");
+    assert_eq!(resolve_path("A:\\DOCS", "INIT:TEST.TXT"), "INIT:\\TEST.TXT");
+    assert_eq!(resolve_path("A:\\DOCS", "a:SUB"), "a:\\DOCS\\SUB");
+  }
+
+  #[test]
+  fn dos_device_names() {
+    assert_eq!(dos_device_path("A:\\NUL").as_deref(), Some("DEV:\\NUL"));
+    assert_eq!(dos_device_path("A:\\DOCS\\nul.txt").as_deref(), Some("DEV:\\NUL"));
+    assert_eq!(dos_device_path("INIT:\\CON").as_deref(), Some("DEV:\\CON"));
+    assert_eq!(dos_device_path("PRN:\\").as_deref(), Some("DEV:\\PRN"));
+    assert_eq!(dos_device_path("A:\\NULL"), None);
+    assert_eq!(dos_device_path("A:\\AUX\\FILE.TXT"), None);
+    assert_eq!(dos_device_path("DEV:\\NUL"), None);
+  }
+
+  #[test]
+  fn temporary_paths() {
+    assert_eq!(temporary_path("\\REGISTRY.DAT"), "\\REGISTRY.DAT.$$$");
+    assert_eq!(temporary_path("\\HISTORY"), "\\HISTORY.$$$");
+  }
+
+  #[test]
+  fn program_names() {
+    assert_eq!(program_name("A:\\BIN\\EDIT.ELF NOTES.TXT"), "EDIT.ELF");
+    assert_eq!(program_name("INIT:SHELL.BIN"), "SHELL.BIN");
+    assert_eq!(program_name("KERNEL"), "KERNEL");
+    assert_eq!(program_name(""), "");
+  }
+}
@@ -1,6 +1,8 @@
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::cmp;
 use core::sync::atomic::{AtomicU32, Ordering};
+use syscall::files::OpenFlags;
 
 pub trait Handle {
   fn new(handle: u32) -> Self;
@@ -94,12 +96,21 @@ impl Eq for DriveHandlePair {}
 
 const MAX_OPEN_FILES: usize = 4096;
 
+#[derive(Clone, Debug)]
+struct OpenHandle {
+  pair: DriveHandlePair,
+  flags: OpenFlags,
+  /// Full path the handle was opened by, if it was opened by name
+  path: Option<Arc<str>>,
+}
+
 /**
  * Map a process's file handles to the filesystem and fs-specific handle behind
- * each one.
+ * each one, along with the flags the handle was opened with.
  */
+#[derive(Clone)]
 pub struct FileHandleMap {
-  map: Vec<Option<DriveHandlePair>>,
+  map: Vec<Option<OpenHandle>>,
 }
 
 impl FileHandleMap {
@@ -109,35 +120,47 @@ impl FileHandleMap {
     }
   }
 
-  pub fn open_handle(&mut self, drive: usize, local: LocalHandle) -> Option<FileHandle> {
+  pub fn open_handle(&mut self, drive: usize, local: LocalHandle, flags: OpenFlags) -> Option<FileHandle> {
     let handle = self.get_next_available_handle()?;
-    self.set_handle_directly(handle, drive, local);
+    self.set_handle_directly(handle, drive, local, flags);
     Some(handle)
   }
 
-  pub fn set_handle_directly(&mut self, handle: FileHandle, drive: usize, local: LocalHandle) -> Option<DriveHandlePair> {
+  pub fn set_handle_directly(&mut self, handle: FileHandle, drive: usize, local: LocalHandle, flags: OpenFlags) -> Option<DriveHandlePair> {
     let pair = DriveHandlePair(drive, local);
     while self.map.len() <= handle.as_usize() {
       self.map.push(None);
     }
-    let prev = self.map[handle.as_usize()];
-    self.map[handle.as_usize()] = Some(pair);
-    prev
+    let prev = self.map[handle.as_usize()].take();
+    self.map[handle.as_usize()] = Some(OpenHandle { pair, flags, path: None });
+    prev.map(|open| open.pair)
   }
 
   pub fn close_handle(&mut self, handle: FileHandle) -> Option<DriveHandlePair> {
     let entry = self.map.get_mut(handle.as_usize());
     match entry {
       Some(e) => {
-        let prev = *e;
-        *e = None;
-        return prev;
+        return e.take().map(|open| open.pair);
       },
       None => (),
     }
     None
   }
 
+  /// Remember the path an open handle was opened by
+  pub fn set_path(&mut self, handle: FileHandle, path: Arc<str>) {
+    if let Some(Some(open)) = self.map.get_mut(handle.as_usize()) {
+      open.path = Some(path);
+    }
+  }
+
+  pub fn get_path(&self, handle: FileHandle) -> Option<Arc<str>> {
+    match self.map.get(handle.as_usize()) {
+      Some(Some(open)) => open.path.clone(),
+      _ => None,
+    }
+  }
+
   pub fn get_next_available_handle(&mut self) -> Option<FileHandle> {
     for (index, item) in self.map.iter().enumerate() {
       match item {
@@ -158,7 +181,7 @@ impl FileHandleMap {
 
     for item in self.map.iter() {
       match item {
-        Some(pair) => if pair == &seek {
+        Some(open) => if open.pair == seek {
           return true;
         },
         None => (),
@@ -167,10 +190,34 @@ impl FileHandleMap {
     false
   }
 
+  pub fn references_drive(&self, drive: usize) -> bool {
+    self.map.iter().any(|item| match item {
+      Some(open) => open.pair.0 == drive,
+      None => false,
+    })
+  }
+
+  /// Number of handles open on a drive
+  pub fn count_drive_handles(&self, drive: usize) -> usize {
+    self.map.iter().filter(|item| match item {
+      Some(open) => open.pair.0 == drive,
+      None => false,
+    }).count()
+  }
+
   pub fn get_drive_and_handle(&self, handle: FileHandle) -> Option<DriveHandlePair> {
     let index = handle.as_usize();
     match self.map.get(index) {
-      Some(pair) => *pair,
+      Some(entry) => entry.as_ref().map(|open| open.pair),
+      None => None,
+    }
+  }
+
+  /// Fetch the flags a handle was opened with
+  pub fn get_flags(&self, handle: FileHandle) -> Option<OpenFlags> {
+    let index = handle.as_usize();
+    match self.map.get(index) {
+      Some(entry) => entry.as_ref().map(|open| open.flags),
       None => None,
     }
   }
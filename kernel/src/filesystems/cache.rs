@@ -0,0 +1,375 @@
+//! Sector cache sitting between filesystems and the disk drivers beneath them.
+//! Walking a FAT directory or following a cluster chain touches the same few
+//! sectors over and over, and on a floppy each of those reads costs a seek.
+//! Blocks are kept in memory and evicted in least-recently-used order.
+//!
+//! Reads that miss the cache also fetch a few of the following blocks, since
+//! files and directories are usually read sequentially. Writes are held in the
+//! cache and written back when the block is evicted or the cache is flushed,
+//! unless the cache is in write-through mode.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Number of blocks held by a cache unless otherwise configured
+pub const DEFAULT_CAPACITY: usize = 64;
+/// Number of extra blocks read after a miss
+pub const DEFAULT_READ_AHEAD: usize = 3;
+
+/// The device underneath a cache. Blocks are addressed by the drive they
+/// belong to and their logical block address.
+pub trait BlockStore {
+  /// Fill the buffer with consecutive blocks, starting at `lba`. The buffer
+  /// length is always a multiple of the block size.
+  fn read_blocks(&self, drive: usize, lba: usize, buffer: &mut [u8]) -> Result<(), ()>;
+  fn write_block(&self, drive: usize, lba: usize, buffer: &[u8]) -> Result<(), ()>;
+  /// Total number of blocks on a drive, so read-ahead never goes past the end
+  fn block_count(&self, drive: usize) -> usize;
+}
+
+struct CachedBlock {
+  drive: usize,
+  lba: usize,
+  data: Box<[u8]>,
+  dirty: bool,
+  last_used: u32,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+  pub hits: usize,
+  pub misses: usize,
+  pub write_backs: usize,
+}
+
+pub struct BlockCache {
+  block_size: usize,
+  capacity: usize,
+  read_ahead: usize,
+  write_through: bool,
+  blocks: Vec<CachedBlock>,
+  clock: u32,
+  stats: CacheStats,
+}
+
+impl BlockCache {
+  pub const fn new(block_size: usize, capacity: usize) -> BlockCache {
+    BlockCache {
+      block_size,
+      capacity,
+      read_ahead: DEFAULT_READ_AHEAD,
+      write_through: false,
+      blocks: Vec::new(),
+      clock: 0,
+      stats: CacheStats {
+        hits: 0,
+        misses: 0,
+        write_backs: 0,
+      },
+    }
+  }
+
+  pub fn get_block_size(&self) -> usize {
+    self.block_size
+  }
+
+  /// Change the block size. Any cached blocks are discarded, so the cache must
+  /// be flushed first if it contains changes.
+  pub fn set_block_size(&mut self, block_size: usize) {
+    self.block_size = block_size;
+    self.blocks.clear();
+  }
+
+  pub fn set_read_ahead(&mut self, count: usize) {
+    self.read_ahead = count;
+  }
+
+  /// In write-through mode, every write goes straight to the device as well
+  /// as updating the cache. This is used for removable media.
+  pub fn set_write_through(&mut self, write_through: bool) {
+    self.write_through = write_through;
+  }
+
+  pub fn get_stats(&self) -> CacheStats {
+    self.stats
+  }
+
+  pub fn dirty_count(&self) -> usize {
+    self.blocks.iter().filter(|b| b.dirty).count()
+  }
+
+  fn tick(&mut self) -> u32 {
+    self.clock = self.clock.wrapping_add(1);
+    self.clock
+  }
+
+  fn find(&self, drive: usize, lba: usize) -> Option<usize> {
+    self.blocks.iter().position(|b| b.drive == drive && b.lba == lba)
+  }
+
+  /// Make room for one more block, writing back the evicted block if it has
+  /// unsaved changes
+  fn evict_one(&mut self, store: &dyn BlockStore) -> Result<(), ()> {
+    if self.blocks.len() < self.capacity {
+      return Ok(());
+    }
+    let now = self.clock;
+    let mut oldest = 0;
+    for (index, block) in self.blocks.iter().enumerate() {
+      if now.wrapping_sub(block.last_used) > now.wrapping_sub(self.blocks[oldest].last_used) {
+        oldest = index;
+      }
+    }
+    if self.blocks[oldest].dirty {
+      let block = &self.blocks[oldest];
+      store.write_block(block.drive, block.lba, &block.data)?;
+      self.stats.write_backs += 1;
+    }
+    self.blocks.swap_remove(oldest);
+    Ok(())
+  }
+
+  fn insert(&mut self, store: &dyn BlockStore, drive: usize, lba: usize, data: &[u8], dirty: bool) -> Result<usize, ()> {
+    if self.capacity == 0 {
+      return Err(());
+    }
+    self.evict_one(store)?;
+    let last_used = self.tick();
+    self.blocks.push(CachedBlock {
+      drive,
+      lba,
+      data: Box::from(data),
+      dirty,
+      last_used,
+    });
+    Ok(self.blocks.len() - 1)
+  }
+
+  /// Read a single block into the buffer, which must be no larger than one
+  /// block
+  pub fn read(&mut self, store: &dyn BlockStore, drive: usize, lba: usize, buffer: &mut [u8]) -> Result<(), ()> {
+    let length = buffer.len().min(self.block_size);
+    if let Some(index) = self.find(drive, lba) {
+      self.stats.hits += 1;
+      let now = self.tick();
+      let block = &mut self.blocks[index];
+      block.last_used = now;
+      buffer[..length].copy_from_slice(&block.data[..length]);
+      return Ok(());
+    }
+    self.stats.misses += 1;
+
+    // Extend the read past the requested block, stopping at the end of the
+    // drive or at a block that is already cached, since the cached copy may
+    // be newer than what is on disk
+    let last = store.block_count(drive);
+    let max_blocks = (self.read_ahead + 1).min(self.capacity.max(1));
+    let mut count = 1;
+    while count < max_blocks && lba + count < last && self.find(drive, lba + count).is_none() {
+      count += 1;
+    }
+    let mut fetched = Vec::with_capacity(count * self.block_size);
+    fetched.resize(count * self.block_size, 0);
+    let fetched_ok = store.read_blocks(drive, lba, fetched.as_mut_slice()).is_ok();
+    if !fetched_ok {
+      if count == 1 {
+        return Err(());
+      }
+      // Fall back to reading just the requested block, in case the error was
+      // caused by one of the extra blocks
+      count = 1;
+      fetched.truncate(self.block_size);
+      store.read_blocks(drive, lba, fetched.as_mut_slice())?;
+    }
+
+    buffer[..length].copy_from_slice(&fetched[..length]);
+    // Insert the read-ahead blocks first, so that the requested block is the
+    // most recently used
+    for offset in (0..count).rev() {
+      let start = offset * self.block_size;
+      let data = &fetched[start..(start + self.block_size)];
+      self.insert(store, drive, lba + offset, data, false)?;
+    }
+    Ok(())
+  }
+
+  /// Write a single block. The data is held in the cache until it is flushed
+  /// or evicted, unless the cache is in write-through mode.
+  pub fn write(&mut self, store: &dyn BlockStore, drive: usize, lba: usize, data: &[u8]) -> Result<(), ()> {
+    if data.len() != self.block_size {
+      // Partial blocks bypass the cache entirely
+      self.invalidate(drive, lba);
+      return store.write_block(drive, lba, data);
+    }
+    if self.write_through {
+      store.write_block(drive, lba, data)?;
+    }
+    let dirty = !self.write_through;
+    let now = self.tick();
+    match self.find(drive, lba) {
+      Some(index) => {
+        let block = &mut self.blocks[index];
+        block.data.copy_from_slice(data);
+        block.dirty = block.dirty || dirty;
+        block.last_used = now;
+      },
+      None => {
+        self.insert(store, drive, lba, data, dirty)?;
+      },
+    }
+    Ok(())
+  }
+
+  /// Write every modified block on a drive back to the device. Blocks are
+  /// written in order of address to reduce seeking.
+  pub fn flush(&mut self, store: &dyn BlockStore, drive: usize) -> Result<(), ()> {
+    let mut dirty: Vec<usize> = self.blocks.iter()
+      .enumerate()
+      .filter(|(_, b)| b.dirty && b.drive == drive)
+      .map(|(index, _)| index)
+      .collect();
+    dirty.sort_by_key(|index| self.blocks[*index].lba);
+    for index in dirty {
+      let block = &mut self.blocks[index];
+      store.write_block(block.drive, block.lba, &block.data)?;
+      block.dirty = false;
+      self.stats.write_backs += 1;
+    }
+    Ok(())
+  }
+
+  /// Drop a cached block without writing it back
+  pub fn invalidate(&mut self, drive: usize, lba: usize) {
+    self.blocks.retain(|b| !(b.drive == drive && b.lba == lba));
+  }
+
+  /// Drop every cached block for a drive without writing anything back, used
+  /// when the media has changed
+  pub fn invalidate_drive(&mut self, drive: usize) {
+    self.blocks.retain(|b| b.drive != drive);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use core::cell::RefCell;
+  use super::{BlockCache, BlockStore};
+
+  const BLOCK: usize = 4;
+
+  struct MemoryStore {
+    data: RefCell<Vec<u8>>,
+    reads: RefCell<Vec<(usize, usize)>>,
+    writes: RefCell<Vec<usize>>,
+  }
+
+  impl MemoryStore {
+    fn new(blocks: usize) -> MemoryStore {
+      let mut data = Vec::new();
+      for i in 0..(blocks * BLOCK) {
+        data.push((i / BLOCK) as u8);
+      }
+      MemoryStore {
+        data: RefCell::new(data),
+        reads: RefCell::new(Vec::new()),
+        writes: RefCell::new(Vec::new()),
+      }
+    }
+  }
+
+  impl BlockStore for MemoryStore {
+    fn read_blocks(&self, _drive: usize, lba: usize, buffer: &mut [u8]) -> Result<(), ()> {
+      self.reads.borrow_mut().push((lba, buffer.len() / BLOCK));
+      let start = lba * BLOCK;
+      buffer.copy_from_slice(&self.data.borrow()[start..(start + buffer.len())]);
+      Ok(())
+    }
+
+    fn write_block(&self, _drive: usize, lba: usize, buffer: &[u8]) -> Result<(), ()> {
+      self.writes.borrow_mut().push(lba);
+      let start = lba * BLOCK;
+      self.data.borrow_mut()[start..(start + buffer.len())].copy_from_slice(buffer);
+      Ok(())
+    }
+
+    fn block_count(&self, _drive: usize) -> usize {
+      self.data.borrow().len() / BLOCK
+    }
+  }
+
+  #[test]
+  fn read_ahead() {
+    let store = MemoryStore::new(16);
+    let mut cache = BlockCache::new(BLOCK, 8);
+    let mut buffer = [0; BLOCK];
+    cache.read(&store, 0, 2, &mut buffer).unwrap();
+    assert_eq!(buffer, [2; BLOCK]);
+    cache.read(&store, 0, 3, &mut buffer).unwrap();
+    assert_eq!(buffer, [3; BLOCK]);
+    cache.read(&store, 0, 5, &mut buffer).unwrap();
+    assert_eq!(*store.reads.borrow(), [(2, 4)]);
+    assert_eq!(cache.get_stats().hits, 2);
+    // Read-ahead stops at the end of the drive
+    cache.read(&store, 0, 14, &mut buffer).unwrap();
+    assert_eq!(store.reads.borrow()[1], (14, 2));
+  }
+
+  #[test]
+  fn delayed_write_back() {
+    let store = MemoryStore::new(16);
+    let mut cache = BlockCache::new(BLOCK, 8);
+    cache.write(&store, 0, 7, &[9; BLOCK]).unwrap();
+    cache.write(&store, 0, 1, &[8; BLOCK]).unwrap();
+    assert_eq!(store.writes.borrow().len(), 0);
+    let mut buffer = [0; BLOCK];
+    cache.read(&store, 0, 7, &mut buffer).unwrap();
+    assert_eq!(buffer, [9; BLOCK]);
+    assert_eq!(cache.dirty_count(), 2);
+    cache.flush(&store, 0).unwrap();
+    assert_eq!(*store.writes.borrow(), [1, 7]);
+    assert_eq!(cache.dirty_count(), 0);
+    assert_eq!(store.data.borrow()[7 * BLOCK], 9);
+  }
+
+  #[test]
+  fn write_through() {
+    let store = MemoryStore::new(16);
+    let mut cache = BlockCache::new(BLOCK, 8);
+    cache.set_write_through(true);
+    cache.write(&store, 0, 3, &[5; BLOCK]).unwrap();
+    assert_eq!(*store.writes.borrow(), [3]);
+    assert_eq!(cache.dirty_count(), 0);
+  }
+
+  #[test]
+  fn eviction_writes_back_dirty_blocks() {
+    let store = MemoryStore::new(16);
+    let mut cache = BlockCache::new(BLOCK, 2);
+    cache.set_read_ahead(0);
+    cache.write(&store, 0, 0, &[7; BLOCK]).unwrap();
+    let mut buffer = [0; BLOCK];
+    cache.read(&store, 0, 1, &mut buffer).unwrap();
+    cache.read(&store, 0, 2, &mut buffer).unwrap();
+    assert_eq!(*store.writes.borrow(), [0]);
+    assert_eq!(store.data.borrow()[0], 7);
+    // The most recently used block survives
+    cache.read(&store, 0, 2, &mut buffer).unwrap();
+    assert_eq!(store.reads.borrow().len(), 2);
+  }
+
+  #[test]
+  fn drives_are_separate() {
+    let store = MemoryStore::new(16);
+    let mut cache = BlockCache::new(BLOCK, 8);
+    cache.write(&store, 1, 4, &[1; BLOCK]).unwrap();
+    cache.write(&store, 2, 4, &[2; BLOCK]).unwrap();
+    let mut buffer = [0; BLOCK];
+    cache.read(&store, 1, 4, &mut buffer).unwrap();
+    assert_eq!(buffer, [1; BLOCK]);
+    cache.invalidate_drive(1);
+    assert_eq!(cache.dirty_count(), 1);
+    cache.flush(&store, 1).unwrap();
+    assert_eq!(store.writes.borrow().len(), 0);
+  }
+}
@@ -0,0 +1,138 @@
+use core::fmt;
+use syscall::result::SystemError;
+
+/// What a filesystem is able to do, reported by the driver when it is mounted.
+/// The VFS checks these before handing an operation to the driver, so that
+/// every filesystem rejects an unsupported operation with the same error.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Capabilities {
+  /// Files can be created, written, and removed
+  pub writable: bool,
+  /// Open files can be repositioned with seek
+  pub seekable: bool,
+  /// The filesystem has directories that can be opened and listed
+  pub directories: bool,
+  /// Files record when they were last modified
+  pub timestamps: bool,
+  /// Files can carry named extended attributes
+  pub xattrs: bool,
+  /// Names that differ only in case refer to different files
+  pub case_sensitive: bool,
+}
+
+impl Capabilities {
+  /// A filesystem that can only open and read files
+  pub const fn none() -> Capabilities {
+    Capabilities {
+      writable: false,
+      seekable: false,
+      directories: false,
+      timestamps: false,
+      xattrs: false,
+      case_sensitive: false,
+    }
+  }
+
+  pub fn require_write(&self) -> Result<(), SystemError> {
+    if self.writable {
+      Ok(())
+    } else {
+      Err(SystemError::ReadOnlyFileSystem)
+    }
+  }
+
+  pub fn require_seek(&self) -> Result<(), SystemError> {
+    if self.seekable {
+      Ok(())
+    } else {
+      Err(SystemError::InvalidSeek)
+    }
+  }
+
+  pub fn require_directories(&self) -> Result<(), SystemError> {
+    if self.directories {
+      Ok(())
+    } else {
+      Err(SystemError::NotDirectory)
+    }
+  }
+
+  pub fn require_timestamps(&self) -> Result<(), SystemError> {
+    if self.timestamps {
+      Ok(())
+    } else {
+      Err(SystemError::UnsupportedCommand)
+    }
+  }
+
+  pub fn require_xattrs(&self) -> Result<(), SystemError> {
+    if self.xattrs {
+      Ok(())
+    } else {
+      Err(SystemError::UnsupportedCommand)
+    }
+  }
+}
+
+impl Default for Capabilities {
+  fn default() -> Capabilities {
+    Capabilities::none()
+  }
+}
+
+/// Lists the supported capabilities as comma-separated flags, as they appear
+/// in PROC:\MOUNTS. A filesystem with none of them is shown as `-`.
+impl fmt::Display for Capabilities {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let flags = [
+      (self.writable, "write"),
+      (self.seekable, "seek"),
+      (self.directories, "dir"),
+      (self.timestamps, "time"),
+      (self.xattrs, "xattr"),
+      (self.case_sensitive, "case"),
+    ];
+    let mut first = true;
+    for (_, name) in flags.iter().filter(|(supported, _)| *supported) {
+      if !first {
+        f.write_str(",")?;
+      }
+      f.write_str(name)?;
+      first = false;
+    }
+    if first {
+      f.write_str("-")?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::format;
+  use super::Capabilities;
+
+  #[test]
+  fn lists_flags() {
+    assert_eq!(format!("{}", Capabilities::none()), "-");
+    let caps = Capabilities {
+      writable: true,
+      seekable: true,
+      directories: true,
+      xattrs: true,
+      ..Capabilities::none()
+    };
+    assert_eq!(format!("{}", caps), "write,seek,dir,xattr");
+  }
+
+  #[test]
+  fn reports_missing_capabilities() {
+    let caps = Capabilities {
+      seekable: true,
+      ..Capabilities::none()
+    };
+    assert!(caps.require_seek().is_ok());
+    assert!(caps.require_write().is_err());
+    assert!(caps.require_directories().is_err());
+  }
+}
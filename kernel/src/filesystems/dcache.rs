@@ -0,0 +1,162 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Default number of paths kept in the cache
+pub const DEFAULT_CAPACITY: usize = 64;
+
+struct CacheEntry {
+  drive: usize,
+  path: Box<str>,
+  /// Filesystem-specific identifier for the directory entry, as returned by
+  /// FileSystem::lookup
+  entry: usize,
+  last_used: u32,
+}
+
+/**
+ * The directory cache remembers which directory entry a path resolved to, so
+ * that repeated opens of the same file can skip walking directories on disk.
+ * Paths are normalized before being stored, since DOS names are not case
+ * sensitive.
+ * The cache has a fixed capacity; when it is full, the least recently used
+ * entry is replaced.
+ */
+pub struct DirectoryCache {
+  entries: Vec<CacheEntry>,
+  capacity: usize,
+  clock: u32,
+}
+
+/// Upper-case a path and remove leading separators, so that equivalent paths
+/// share a cache entry
+pub fn normalize_path(path: &str) -> String {
+  let trimmed = path.trim_start_matches('\\');
+  let mut normalized = String::with_capacity(trimmed.len());
+  for ch in trimmed.chars() {
+    normalized.push(ch.to_ascii_uppercase());
+  }
+  normalized
+}
+
+impl DirectoryCache {
+  pub const fn new(capacity: usize) -> DirectoryCache {
+    DirectoryCache {
+      entries: Vec::new(),
+      capacity,
+      clock: 0,
+    }
+  }
+
+  fn tick(&mut self) -> u32 {
+    self.clock = self.clock.wrapping_add(1);
+    self.clock
+  }
+
+  fn find_index(&self, drive: usize, path: &str) -> Option<usize> {
+    self.entries.iter().position(|e| e.drive == drive && e.path.as_ref() == path)
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  /// Look up a previously resolved path
+  pub fn get(&mut self, drive: usize, path: &str) -> Option<usize> {
+    let normalized = normalize_path(path);
+    let index = self.find_index(drive, &normalized)?;
+    let now = self.tick();
+    let entry = &mut self.entries[index];
+    entry.last_used = now;
+    Some(entry.entry)
+  }
+
+  /// Store the result of resolving a path, evicting the least recently used
+  /// entry if the cache is full
+  pub fn insert(&mut self, drive: usize, path: &str, entry: usize) {
+    if self.capacity == 0 {
+      return;
+    }
+    let normalized = normalize_path(path);
+    let now = self.tick();
+    if let Some(index) = self.find_index(drive, &normalized) {
+      let existing = &mut self.entries[index];
+      existing.entry = entry;
+      existing.last_used = now;
+      return;
+    }
+    let new_entry = CacheEntry {
+      drive,
+      path: normalized.into_boxed_str(),
+      entry,
+      last_used: now,
+    };
+    if self.entries.len() < self.capacity {
+      self.entries.push(new_entry);
+      return;
+    }
+    let mut oldest = 0;
+    for (index, e) in self.entries.iter().enumerate() {
+      if now.wrapping_sub(e.last_used) > now.wrapping_sub(self.entries[oldest].last_used) {
+        oldest = index;
+      }
+    }
+    self.entries[oldest] = new_entry;
+  }
+
+  /// Forget a single path, used when the file it points to is created,
+  /// deleted, or renamed
+  pub fn invalidate(&mut self, drive: usize, path: &str) {
+    let normalized = normalize_path(path);
+    self.entries.retain(|e| !(e.drive == drive && e.path.as_ref() == normalized.as_str()));
+  }
+
+  /// Forget every path on a drive
+  pub fn invalidate_drive(&mut self, drive: usize) {
+    self.entries.retain(|e| e.drive != drive);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{DirectoryCache, normalize_path};
+
+  #[test]
+  fn normalizing() {
+    assert_eq!(normalize_path("\\config.sys"), "CONFIG.SYS");
+    assert_eq!(normalize_path("DIR\\File.Txt"), "DIR\\FILE.TXT");
+  }
+
+  #[test]
+  fn lookup_and_invalidate() {
+    let mut cache = DirectoryCache::new(4);
+    cache.insert(1, "\\COMMAND.COM", 0x130);
+    assert_eq!(cache.get(1, "\\command.com"), Some(0x130));
+    assert_eq!(cache.get(2, "\\command.com"), None);
+    cache.invalidate(1, "COMMAND.COM");
+    assert_eq!(cache.get(1, "\\command.com"), None);
+  }
+
+  #[test]
+  fn evicts_least_recently_used() {
+    let mut cache = DirectoryCache::new(2);
+    cache.insert(0, "A", 1);
+    cache.insert(0, "B", 2);
+    assert_eq!(cache.get(0, "A"), Some(1));
+    cache.insert(0, "C", 3);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get(0, "B"), None);
+    assert_eq!(cache.get(0, "A"), Some(1));
+    assert_eq!(cache.get(0, "C"), Some(3));
+  }
+
+  #[test]
+  fn invalidate_whole_drive() {
+    let mut cache = DirectoryCache::new(4);
+    cache.insert(0, "A", 1);
+    cache.insert(1, "B", 2);
+    cache.invalidate_drive(0);
+    assert_eq!(cache.get(0, "A"), None);
+    assert_eq!(cache.get(1, "B"), Some(2));
+  }
+}
@@ -2,36 +2,82 @@ use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::any::Any;
+use crate::collections::SlotList;
 use crate::devices;
-use crate::files::{handle::{Handle, HandleAllocator, LocalHandle}, cursor::SeekMethod};
+use crate::drivers::driver::OpenPolicy;
+use crate::files::{handle::{Handle, LocalHandle}, cursor::SeekMethod};
 use spin::RwLock;
+use super::capabilities::Capabilities;
 use super::filesystem::FileSystem;
-use syscall::files::DirEntryInfo;
+use syscall::files::{DirEntryInfo, DirEntryType, OpenFlags};
+use syscall::result::SystemError;
+
+/// What a handle on the DEV: drive refers to
+#[derive(Copy, Clone)]
+enum DevHandle {
+  /// An open device, identified by its device number, and the access allowed
+  /// through the handle
+  Device(usize, OpenFlags),
+  /// The root directory, which lists every device
+  Directory,
+}
 
 pub struct DevFileSystem {
-  handle_allocator: HandleAllocator<LocalHandle>,
-  handle_to_device: RwLock<Vec<Option<usize>>>,
+  /// Handles index directly into this list, so closed slots are reused by the
+  /// next open
+  open_handles: RwLock<SlotList<DevHandle>>,
 }
 
 impl DevFileSystem {
   pub const fn new() -> DevFileSystem {
     DevFileSystem {
-      handle_allocator: HandleAllocator::<LocalHandle>::new(),
-      handle_to_device: RwLock::new(Vec::new()),
+      open_handles: RwLock::new(SlotList::new()),
     }
   }
 
+  fn get_handle(&self, handle: LocalHandle) -> Option<DevHandle> {
+    self.open_handles.read().get(handle.as_usize()).copied()
+  }
+
   pub fn get_device_for_handle(&self, handle: LocalHandle) -> Option<usize> {
-    let handle_to_device = self.handle_to_device.read();
-    match handle_to_device.get(handle.as_u32() as usize) {
-      Some(option) => *option,
-      None => None,
+    match self.get_handle(handle) {
+      Some(DevHandle::Device(number, _)) => Some(number),
+      _ => None,
     }
   }
-}
 
-impl FileSystem for DevFileSystem {
-  fn open(&self, path: &str) -> Result<LocalHandle, ()> {
+  /// Find the device behind a handle, as long as the handle was opened with
+  /// the access needed for an operation
+  fn get_device_with_access(&self, handle: LocalHandle, write: bool) -> Option<usize> {
+    match self.get_handle(handle) {
+      Some(DevHandle::Device(number, flags)) => {
+        let allowed = if write { flags.can_write() } else { flags.can_read() };
+        if allowed {
+          Some(number)
+        } else {
+          None
+        }
+      },
+      _ => None,
+    }
+  }
+
+  /// Exclusive devices, and devices held through an OPEN_EXCLUSIVE handle,
+  /// cannot be opened again until every existing handle has been closed
+  fn is_device_busy(handles: &SlotList<DevHandle>, number: usize, flags: OpenFlags) -> bool {
+    let exclusive = flags.is_exclusive() || match devices::get_driver_for_device(number) {
+      Some(driver) => driver.get_open_policy() == OpenPolicy::Exclusive,
+      None => false,
+    };
+    handles.iter().any(|open| match open {
+      DevHandle::Device(open_number, open_flags) => {
+        *open_number == number && (exclusive || open_flags.is_exclusive())
+      },
+      DevHandle::Directory => false,
+    })
+  }
+
+  fn find_device(path: &str) -> Option<usize> {
     let local_path = if path.starts_with('\\') {
       &path[1..]
     } else {
@@ -50,26 +96,61 @@ impl FileSystem for DevFileSystem {
     }
   
     // needs to account for directories
-    match devices::get_device_number_by_name(&name) {
-      Some(number) => {
-        let handle = self.handle_allocator.get_next();
-        {
-          let driver = devices::get_driver_for_device(number).ok_or(())?;
-          driver.open(handle)?;
-        }
-        let mut handle_to_device = self.handle_to_device.write();
-        while handle_to_device.len() < handle.as_u32() as usize {
-          handle_to_device.push(None);
-        }
-        handle_to_device.push(Some(number));
-        Ok(handle)
-      },
+    devices::get_device_number_by_name(&name)
+  }
+
+  /// Allocate a handle for a device, and let the driver set up any state it
+  /// keeps for the handle. Duplicates of an existing handle belong to the
+  /// same owner, so they skip the check for a device that is already open.
+  fn open_device(&self, number: usize, flags: OpenFlags, duplicate: bool) -> Result<LocalHandle, ()> {
+    let driver = devices::get_driver_for_device(number).ok_or(())?;
+    let index = {
+      let mut handles = self.open_handles.write();
+      if !duplicate && Self::is_device_busy(&handles, number, flags) {
+        return Err(());
+      }
+      handles.insert(DevHandle::Device(number, flags))
+    };
+    let handle = LocalHandle::new(index as u32);
+    if driver.open(handle, flags).is_err() {
+      self.open_handles.write().remove(index);
+      return Err(());
+    }
+    Ok(handle)
+  }
+}
+
+impl FileSystem for DevFileSystem {
+  /// Seeks are passed to the device, which may still refuse them
+  fn get_capabilities(&self) -> Capabilities {
+    Capabilities {
+      writable: true,
+      seekable: true,
+      directories: true,
+      ..Capabilities::none()
+    }
+  }
+
+  fn open(&self, path: &str, flags: OpenFlags) -> Result<LocalHandle, ()> {
+    match Self::find_device(path) {
+      Some(number) => self.open_device(number, flags, false),
       None => Err(()),
     }
   }
 
+  fn check_open(&self, path: &str, flags: OpenFlags) -> Result<(), SystemError> {
+    let number = match Self::find_device(path) {
+      Some(number) => number,
+      None => return Ok(()),
+    };
+    if Self::is_device_busy(&self.open_handles.read(), number, flags) {
+      return Err(SystemError::Busy);
+    }
+    Ok(())
+  }
+
   fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
-    match self.get_device_for_handle(handle) {
+    match self.get_device_with_access(handle, false) {
       Some(number) => {
         let driver = devices::get_driver_for_device(number).ok_or(())?;
         match driver.read(handle, buffer) {
@@ -81,43 +162,52 @@ impl FileSystem for DevFileSystem {
     }
   }
 
-  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
-    match self.get_device_for_handle(handle) {
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, SystemError> {
+    match self.get_device_with_access(handle, true) {
       Some(number) => {
-        let driver = devices::get_driver_for_device(number).ok_or(())?;
+        let driver = devices::get_driver_for_device(number).ok_or(SystemError::NoSuchEntity)?;
         match driver.write(handle, buffer) {
           Ok(len) => Ok(len),
-          Err(_) => Err(())
+          Err(_) => Err(SystemError::IOError)
         }
       },
-      None => Err(())
+      None => Err(SystemError::BadFileDescriptor)
     }
   }
 
   fn close(&self, handle: LocalHandle) -> Result<(), ()> {
-
-    Err(())
+    let closed = self.open_handles.write().remove(handle.as_usize()).ok_or(())?;
+    match closed {
+      DevHandle::Device(number, _) => {
+        let driver = devices::get_driver_for_device(number).ok_or(())?;
+        driver.close(handle)
+      },
+      DevHandle::Directory => Ok(()),
+    }
   }
 
   fn dup(&self, handle: LocalHandle) -> Result<LocalHandle, ()> {
-    let device = self.get_device_for_handle(handle);
-    match device {
-      Some(number) => {
-        let new_handle = self.handle_allocator.get_next();
-        let mut handle_to_device = self.handle_to_device.write();
-        handle_to_device.push(Some(number));
-        Ok(new_handle)
-      },
-      None => Err(()),
+    match self.get_handle(handle) {
+      Some(DevHandle::Device(number, flags)) => self.open_device(number, flags, true),
+      _ => Err(()),
     }
   }
 
-  fn ioctl(&self, handle: LocalHandle, command: u32, _arg: u32) -> Result<u32, ()> {
+  fn ioctl(&self, handle: LocalHandle, command: u32, arg: u32) -> Result<u32, ()> {
     match command {
       0 => { // Identify device number
         self.get_device_for_handle(handle).map(|d| d as u32).ok_or(())
       },
-      _ => Err(())
+      1 => { // Report CHS geometry of a block device
+        let number = self.get_device_for_handle(handle).ok_or(())?;
+        let driver = devices::get_driver_for_device(number).ok_or(())?;
+        driver.get_geometry().map(|g| g.to_u32()).ok_or(())
+      },
+      _ => {
+        let number = self.get_device_for_handle(handle).ok_or(())?;
+        let driver = devices::get_driver_for_device(number).ok_or(())?;
+        driver.ioctl(handle, command, arg)
+      },
     }
   }
 
@@ -135,10 +225,35 @@ impl FileSystem for DevFileSystem {
   }
 
   fn open_dir(&self, path: &str) -> Result<LocalHandle, ()> {
-    Err(())
+    // Devices all live in the root, there are no subdirectories
+    if path.chars().any(|c| c != '\\') {
+      return Err(());
+    }
+    let index = self.open_handles.write().insert(DevHandle::Directory);
+    Ok(LocalHandle::new(index as u32))
   }
 
   fn read_dir(&self, handle: LocalHandle, index: usize, info: &mut DirEntryInfo) -> Result<(), ()> {
-    Err(())
+    match self.get_handle(handle) {
+      Some(DevHandle::Directory) => (),
+      _ => return Err(()),
+    }
+    *info = DirEntryInfo::empty();
+    let (name, number) = match devices::get_device_entry(index) {
+      Some(entry) => entry,
+      None => return Ok(()),
+    };
+    info.file_name = name;
+    let geometry = devices::get_driver_for_device(number).and_then(|driver| driver.get_geometry());
+    match geometry {
+      Some(geometry) => {
+        info.entry_type = DirEntryType::BlockDevice;
+        info.byte_size = geometry.get_total_sectors() * 512;
+      },
+      None => {
+        info.entry_type = DirEntryType::CharacterDevice;
+      },
+    }
+    Ok(())
   }
 }
\ No newline at end of file
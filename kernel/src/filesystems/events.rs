@@ -0,0 +1,84 @@
+use crate::collections::{Event, EventQueue};
+use spin::Mutex;
+use syscall::files::{MountEvent, MountEventKind, MOUNT_EVENT_SIZE};
+
+/// Each listener keeps at most this many unread events. If a listener falls
+/// behind, the oldest events are dropped.
+pub const MAX_PENDING_EVENTS: usize = 32;
+
+impl Event for MountEvent {
+  const SIZE: usize = MOUNT_EVENT_SIZE;
+  const MAX_PENDING: usize = MAX_PENDING_EVENTS;
+
+  fn write_bytes(&self, buffer: &mut [u8]) {
+    buffer.copy_from_slice(&self.to_bytes());
+  }
+}
+
+/// Every handle listening for drive changes
+pub type MountEventQueue = EventQueue<MountEvent>;
+
+pub static MOUNT_EVENTS: Mutex<MountEventQueue> = Mutex::new(MountEventQueue::new());
+
+pub fn drive_mounted(name: &str) {
+  MOUNT_EVENTS.lock().publish(MountEvent::new(MountEventKind::Mounted, name));
+}
+
+pub fn drive_unmounted(name: &str) {
+  MOUNT_EVENTS.lock().publish(MountEvent::new(MountEventKind::Unmounted, name));
+}
+
+pub fn media_changed(name: &str) {
+  MOUNT_EVENTS.lock().publish(MountEvent::new(MountEventKind::MediaChanged, name));
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::files::handle::{Handle, LocalHandle};
+  use syscall::files::{MountEvent, MountEventKind, MOUNT_EVENT_SIZE};
+  use super::{MountEventQueue, MAX_PENDING_EVENTS};
+
+  #[test]
+  fn listeners_receive_events() {
+    let mut queue = MountEventQueue::new();
+    let first = LocalHandle::new(1);
+    let second = LocalHandle::new(2);
+    queue.listen(first);
+    queue.publish(MountEvent::new(MountEventKind::Mounted, "A"));
+    queue.listen(second);
+    queue.publish(MountEvent::new(MountEventKind::MediaChanged, "A"));
+
+    let mut buffer = [0; MOUNT_EVENT_SIZE * 4];
+    assert_eq!(queue.read(first, &mut buffer), Ok(MOUNT_EVENT_SIZE * 2));
+    assert_eq!(MountEvent::from_bytes(&buffer[0..]).unwrap().kind, MountEventKind::Mounted);
+    assert_eq!(&MountEvent::from_bytes(&buffer[0..]).unwrap().drive_name, b"A       ");
+    assert_eq!(MountEvent::from_bytes(&buffer[MOUNT_EVENT_SIZE..]).unwrap().kind, MountEventKind::MediaChanged);
+    assert_eq!(queue.read(second, &mut buffer), Ok(MOUNT_EVENT_SIZE));
+    assert!(!queue.has_pending(first));
+  }
+
+  #[test]
+  fn reads_whole_events_only() {
+    let mut queue = MountEventQueue::new();
+    let handle = LocalHandle::new(1);
+    queue.listen(handle);
+    queue.publish(MountEvent::new(MountEventKind::Unmounted, "B"));
+    let mut small = [0; MOUNT_EVENT_SIZE - 1];
+    assert_eq!(queue.read(handle, &mut small), Ok(0));
+    assert!(queue.has_pending(handle));
+  }
+
+  #[test]
+  fn drops_oldest_when_full() {
+    let mut queue = MountEventQueue::new();
+    let handle = LocalHandle::new(1);
+    queue.listen(handle);
+    queue.publish(MountEvent::new(MountEventKind::Mounted, "OLD"));
+    for _ in 0..MAX_PENDING_EVENTS {
+      queue.publish(MountEvent::new(MountEventKind::Mounted, "NEW"));
+    }
+    let mut buffer = [0; MOUNT_EVENT_SIZE];
+    queue.read(handle, &mut buffer).unwrap();
+    assert_eq!(&MountEvent::from_bytes(&buffer).unwrap().drive_name, b"NEW     ");
+  }
+}
@@ -14,6 +14,12 @@ impl Directory {
       clusters: ClusterChain::empty(),
     }
   }
+
+  /// The cluster a ".." entry uses to refer to this directory. The root
+  /// directory has no clusters, and is referred to as cluster zero.
+  pub fn get_first_cluster(&self) -> Cluster {
+    self.clusters.clusters.first().copied().unwrap_or(Cluster::new(0))
+  }
 }
 
 /// On-disk representation of a file or subdirectory
@@ -48,7 +54,29 @@ pub struct DirectoryEntry {
   byte_size: u32,
 }
 
+/// First byte of a file name, marking an entry that was deleted and can be
+/// reused
+pub const DELETED_ENTRY: u8 = 0xe5;
+
 impl DirectoryEntry {
+  pub fn new(file_name: [u8; 8], ext: [u8; 3], attributes: u8) -> DirectoryEntry {
+    DirectoryEntry {
+      file_name,
+      ext,
+      attributes,
+      nonstandard_attributes: 0,
+      fine_create_time: 0,
+      creation_time: FileTime::new(0),
+      creation_date: FileDate::new(0),
+      access_date: FileDate::new(0),
+      extended_attributes: 0,
+      last_modify_time: FileTime::new(0),
+      last_modify_date: FileDate::new(0),
+      first_file_cluster: 0,
+      byte_size: 0,
+    }
+  }
+
   pub fn at_address(addr: VirtualAddress) -> &'static mut DirectoryEntry {
     let ptr = addr.as_usize() as *mut DirectoryEntry;
     unsafe {
@@ -82,6 +110,60 @@ impl DirectoryEntry {
     self.file_name[0] == 0
   }
 
+  /// A free entry is either past the end of the directory, or was deleted
+  pub fn is_free(&self) -> bool {
+    self.file_name[0] == 0 || self.file_name[0] == DELETED_ENTRY
+  }
+
+  pub fn set_name(&mut self, file_name: [u8; 8], ext: [u8; 3]) {
+    self.file_name = file_name;
+    self.ext = ext;
+  }
+
+  pub fn mark_deleted(&mut self) {
+    self.file_name[0] = DELETED_ENTRY;
+  }
+
+  /// Handle identifying this entry's records in the extended attribute
+  /// sidecar, or zero if it has none
+  pub fn get_ea_handle(&self) -> u16 {
+    self.extended_attributes
+  }
+
+  pub fn set_ea_handle(&mut self, handle: u16) {
+    self.extended_attributes = handle;
+  }
+
+  pub fn set_first_cluster(&mut self, cluster: Cluster) {
+    self.first_file_cluster = cluster.as_usize() as u16;
+  }
+
+  pub fn set_byte_size(&mut self, size: usize) {
+    self.byte_size = size as u32;
+  }
+
+  pub fn set_creation_time(&mut self, date: FileDate, time: FileTime) {
+    self.creation_date = date;
+    self.creation_time = time;
+    self.fine_create_time = 0;
+  }
+
+  pub fn set_modify_time(&mut self, date: FileDate, time: FileTime) {
+    self.last_modify_date = date;
+    self.last_modify_time = time;
+  }
+
+  pub fn set_access_date(&mut self, date: FileDate) {
+    self.access_date = date;
+  }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    let len = core::mem::size_of::<DirectoryEntry>();
+    unsafe {
+      core::slice::from_raw_parts(self as *const DirectoryEntry as *const u8, len)
+    }
+  }
+
   pub fn copy_name(&self, buffer: &mut [u8; 8]) {
     for i in 0..8 {
       buffer[i] = self.file_name[i];
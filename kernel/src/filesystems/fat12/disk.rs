@@ -26,6 +26,7 @@ impl SectorRange {
   }
 }
 
+#[derive(Copy, Clone)]
 pub struct DiskConfig {
   bytes_per_sector: usize,
   sectors_per_cluster: usize,
@@ -59,6 +60,10 @@ impl DiskConfig {
     self.total_sectors = bpb.total_sectors as usize;
   }
 
+  pub fn get_total_sectors(&self) -> usize {
+    self.total_sectors
+  }
+
   pub fn get_sectors_per_cluster(&self) -> usize {
     self.sectors_per_cluster
   }
@@ -101,11 +106,24 @@ impl DiskConfig {
   }
 
   pub fn get_data_sectors(&self) -> SectorRange {
-    let first_sector = self.get_root_directory_sectors().get_first_sector();
-    let count = self.total_sectors - first_sector;
+    let root_sectors = self.get_root_directory_sectors();
+    let first_sector = root_sectors.get_first_sector() + root_sectors.get_sector_count();
+    let count = self.total_sectors.saturating_sub(first_sector);
     SectorRange::new(first_sector, count)
   }
 
+  /// Number of entries in the FAT that refer to real clusters on disk, plus
+  /// the two reserved entries at the start of the table
+  pub fn get_cluster_count(&self) -> usize {
+    self.get_data_sectors().get_sector_count() / self.sectors_per_cluster + 2
+  }
+
+  /// Maximum number of entries in the root directory. Unlike subdirectories,
+  /// the root directory cannot grow beyond this.
+  pub fn get_root_directory_entries(&self) -> usize {
+    self.root_directory_entries
+  }
+
   pub fn get_directory_index_location(&self, index: usize) -> (usize, usize) {
     let entries_per_sector = self.bytes_per_sector / DIRECTORY_ENTRY_SIZE;
     let absolute_sector = index / entries_per_sector;
@@ -0,0 +1,229 @@
+//! FAT has no place to store extended attributes, so they are kept in a hidden
+//! sidecar file in the root directory, following the approach OS/2 took. Each
+//! directory entry with attributes stores a 16-bit handle in the otherwise
+//! unused extended attribute field, and the sidecar maps those handles to
+//! lists of name / value pairs.
+//!
+//! The sidecar begins with a four-byte signature, followed by a series of
+//! records:
+//!   u16 handle, u16 attribute count
+//!   for each attribute: u8 name length, name, u16 value length, value
+//! All integers are little-endian.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use super::errors::FatError;
+
+pub const SIDECAR_NAME: [u8; 8] = *b"EA DATA ";
+pub const SIDECAR_EXT: [u8; 3] = *b" SF";
+/// Sidecar files are marked hidden and system
+pub const SIDECAR_ATTRIBUTES: u8 = 0x06;
+
+const SIGNATURE: [u8; 4] = *b"EA01";
+
+pub const MAX_NAME_LENGTH: usize = 0xff;
+pub const MAX_VALUE_LENGTH: usize = 0xffff;
+
+struct Attribute {
+  name: Box<[u8]>,
+  value: Box<[u8]>,
+}
+
+struct Record {
+  handle: u16,
+  attributes: Vec<Attribute>,
+}
+
+pub struct ExtendedAttributeStore {
+  records: Vec<Record>,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, FatError> {
+  if offset + 2 > bytes.len() {
+    return Err(FatError::CorruptExtendedAttributes);
+  }
+  Ok(bytes[offset] as u16 | ((bytes[offset + 1] as u16) << 8))
+}
+
+fn read_slice(bytes: &[u8], offset: usize, length: usize) -> Result<&[u8], FatError> {
+  if offset + length > bytes.len() {
+    return Err(FatError::CorruptExtendedAttributes);
+  }
+  Ok(&bytes[offset..(offset + length)])
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+  bytes.push(value as u8);
+  bytes.push((value >> 8) as u8);
+}
+
+/// Attribute names are compared without regard to case
+fn names_match(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| x.eq_ignore_ascii_case(y))
+}
+
+impl ExtendedAttributeStore {
+  pub fn new() -> ExtendedAttributeStore {
+    ExtendedAttributeStore {
+      records: Vec::new(),
+    }
+  }
+
+  /// Parse the contents of a sidecar file. An empty file is treated as an
+  /// empty store.
+  pub fn parse(bytes: &[u8]) -> Result<ExtendedAttributeStore, FatError> {
+    let mut store = ExtendedAttributeStore::new();
+    if bytes.len() == 0 {
+      return Ok(store);
+    }
+    if read_slice(bytes, 0, 4)? != SIGNATURE {
+      return Err(FatError::CorruptExtendedAttributes);
+    }
+    let mut offset = 4;
+    while offset < bytes.len() {
+      let handle = read_u16(bytes, offset)?;
+      let count = read_u16(bytes, offset + 2)? as usize;
+      offset += 4;
+      let mut attributes = Vec::with_capacity(count);
+      for _ in 0..count {
+        let name_length = read_slice(bytes, offset, 1)?[0] as usize;
+        let name = read_slice(bytes, offset + 1, name_length)?;
+        offset += 1 + name_length;
+        let value_length = read_u16(bytes, offset)? as usize;
+        let value = read_slice(bytes, offset + 2, value_length)?;
+        offset += 2 + value_length;
+        attributes.push(Attribute {
+          name: Box::from(name),
+          value: Box::from(value),
+        });
+      }
+      store.records.push(Record { handle, attributes });
+    }
+    Ok(store)
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&SIGNATURE);
+    for record in self.records.iter() {
+      push_u16(&mut bytes, record.handle);
+      push_u16(&mut bytes, record.attributes.len() as u16);
+      for attr in record.attributes.iter() {
+        bytes.push(attr.name.len() as u8);
+        bytes.extend_from_slice(&attr.name);
+        push_u16(&mut bytes, attr.value.len() as u16);
+        bytes.extend_from_slice(&attr.value);
+      }
+    }
+    bytes
+  }
+
+  /// Find a handle that is not used by any record. Handle zero is reserved to
+  /// mean that a file has no attributes.
+  pub fn allocate_handle(&self) -> Result<u16, FatError> {
+    let mut candidate: u16 = 1;
+    loop {
+      if !self.records.iter().any(|r| r.handle == candidate) {
+        return Ok(candidate);
+      }
+      candidate = candidate.checked_add(1).ok_or(FatError::NoSpace)?;
+    }
+  }
+
+  pub fn get(&self, handle: u16, name: &[u8]) -> Option<&[u8]> {
+    let record = self.records.iter().find(|r| r.handle == handle)?;
+    let attr = record.attributes.iter().find(|a| names_match(&a.name, name))?;
+    Some(&attr.value)
+  }
+
+  /// Set an attribute on a handle, replacing any previous value. An empty
+  /// value removes the attribute.
+  pub fn set(&mut self, handle: u16, name: &[u8], value: &[u8]) -> Result<(), FatError> {
+    if name.len() == 0 || name.len() > MAX_NAME_LENGTH || value.len() > MAX_VALUE_LENGTH {
+      return Err(FatError::InvalidAttribute);
+    }
+    if value.len() == 0 {
+      self.remove(handle, name);
+      return Ok(());
+    }
+    let index = match self.records.iter().position(|r| r.handle == handle) {
+      Some(index) => index,
+      None => {
+        self.records.push(Record { handle, attributes: Vec::new() });
+        self.records.len() - 1
+      },
+    };
+    let record = &mut self.records[index];
+    match record.attributes.iter_mut().find(|a| names_match(&a.name, name)) {
+      Some(attr) => attr.value = Box::from(value),
+      None => record.attributes.push(Attribute {
+        name: Box::from(name),
+        value: Box::from(value),
+      }),
+    }
+    Ok(())
+  }
+
+  pub fn remove(&mut self, handle: u16, name: &[u8]) {
+    if let Some(record) = self.records.iter_mut().find(|r| r.handle == handle) {
+      record.attributes.retain(|a| !names_match(&a.name, name));
+    }
+    self.records.retain(|r| r.attributes.len() > 0);
+  }
+
+  /// Drop every attribute associated with a handle, used when the file is
+  /// deleted
+  pub fn remove_all(&mut self, handle: u16) {
+    self.records.retain(|r| r.handle != handle);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{ExtendedAttributeStore, FatError};
+
+  #[test]
+  fn set_and_get() {
+    let mut store = ExtendedAttributeStore::new();
+    store.set(1, b"TYPE", b"TEXT").unwrap();
+    store.set(1, b"OWNER", b"ROOT").unwrap();
+    store.set(2, b"TYPE", b"EXE").unwrap();
+    assert_eq!(store.get(1, b"type"), Some(&b"TEXT"[..]));
+    assert_eq!(store.get(2, b"TYPE"), Some(&b"EXE"[..]));
+    assert_eq!(store.get(2, b"OWNER"), None);
+    store.set(1, b"TYPE", b"DATA").unwrap();
+    assert_eq!(store.get(1, b"TYPE"), Some(&b"DATA"[..]));
+    store.set(1, b"TYPE", b"").unwrap();
+    assert_eq!(store.get(1, b"TYPE"), None);
+  }
+
+  #[test]
+  fn round_trip() {
+    let mut store = ExtendedAttributeStore::new();
+    store.set(3, b"A", b"12345").unwrap();
+    store.set(7, b"LONGER NAME", b"x").unwrap();
+    let bytes = store.to_bytes();
+    let parsed = ExtendedAttributeStore::parse(&bytes).unwrap();
+    assert_eq!(parsed.get(3, b"A"), Some(&b"12345"[..]));
+    assert_eq!(parsed.get(7, b"LONGER NAME"), Some(&b"x"[..]));
+    assert_eq!(parsed.to_bytes(), bytes);
+  }
+
+  #[test]
+  fn rejects_corrupt_data() {
+    assert!(ExtendedAttributeStore::parse(&[]).is_ok());
+    assert_eq!(ExtendedAttributeStore::parse(b"NOPE").err(), Some(FatError::CorruptExtendedAttributes));
+    assert_eq!(ExtendedAttributeStore::parse(b"EA01\x01\x00\x01\x00\x05AB").err(), Some(FatError::CorruptExtendedAttributes));
+  }
+
+  #[test]
+  fn handles() {
+    let mut store = ExtendedAttributeStore::new();
+    assert_eq!(store.allocate_handle(), Ok(1));
+    store.set(1, b"A", b"B").unwrap();
+    assert_eq!(store.allocate_handle(), Ok(2));
+    store.remove_all(1);
+    assert_eq!(store.get(1, b"A"), None);
+    assert_eq!(store.allocate_handle(), Ok(1));
+  }
+}
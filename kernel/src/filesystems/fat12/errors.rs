@@ -1,4 +1,33 @@
+use syscall::result::SystemError;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum FatError {
   /// The disk does not contain the specified fat table
   InvalidFatTable,
-}
\ No newline at end of file
+  /// There are no free clusters left in the FAT
+  NoSpace,
+  /// The root directory has a fixed number of entries, and all are in use
+  DirectoryFull,
+  /// Reading from or writing to the underlying device failed
+  IOError,
+  /// No directory entry matched the requested name
+  NotFound,
+  /// The extended attribute sidecar file could not be parsed
+  CorruptExtendedAttributes,
+  /// An extended attribute name or value was empty or too long
+  InvalidAttribute,
+  /// The disk was swapped, and the filesystem has not been remounted
+  MediaChanged,
+}
+
+impl FatError {
+  pub fn to_system_error(&self) -> SystemError {
+    match self {
+      FatError::NoSpace | FatError::DirectoryFull => SystemError::NoSpace,
+      FatError::NotFound => SystemError::NoSuchEntity,
+      FatError::InvalidAttribute => SystemError::InvalidArgument,
+      FatError::MediaChanged => SystemError::MediaChanged,
+      _ => SystemError::IOError,
+    }
+  }
+}
@@ -75,10 +75,11 @@ impl Iterator for ChainSectorIterator {
     let cluster_count = self.clusters.len();
     if cluster_count == 0 {
       // No clusters means we're iterating over the root directory
-      if self.sector_index > self.root_dir_sectors.get_sector_count() {
+      if self.sector_index >= self.root_dir_sectors.get_sector_count() {
         return None;
       }
       let sector = self.root_dir_sectors.get_first_sector() + self.sector_index;
+      self.sector_index += 1;
       return Some(sector);
     }
 
@@ -125,6 +126,17 @@ impl FatEntry {
     }
   }
 
+  pub fn to_value(&self) -> u16 {
+    match self {
+      FatEntry::NextCluster(cluster) => cluster.as_usize() as u16,
+      FatEntry::EndOfChain => 0xfff,
+      FatEntry::Free => 0,
+      FatEntry::BadSector => 0xff7,
+      FatEntry::Reserved => 0xff6,
+      FatEntry::TemporaryAllocation => 1,
+    }
+  }
+
   pub fn has_next(&self) -> bool {
     match self {
       FatEntry::NextCluster(_) => true,
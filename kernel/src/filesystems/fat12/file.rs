@@ -1,8 +1,23 @@
+use crate::time::date::{Date, Time};
+
 #[derive(Copy, Clone)]
 #[repr(transparent)]
 pub struct FileTime(u16);
 
 impl FileTime {
+  pub fn new(raw: u16) -> FileTime {
+    FileTime(raw)
+  }
+
+  /// FAT times only have a resolution of two seconds
+  pub fn from_time(time: &Time) -> FileTime {
+    FileTime(
+      ((time.hours as u16) << 11) |
+      ((time.minutes as u16 & 0x3f) << 5) |
+      ((time.seconds as u16 >> 1) & 0x1f)
+    )
+  }
+
   pub fn get_hours(&self) -> u16 {
     self.0 >> 11
   }
@@ -16,11 +31,31 @@ impl FileTime {
   }
 }
 
+/// Years are stored in seven bits, counted from 1980
+pub const FAT_MAX_YEAR: u8 = 127;
+
 #[derive(Copy, Clone)]
 #[repr(transparent)]
 pub struct FileDate(u16);
 
 impl FileDate {
+  pub fn new(raw: u16) -> FileDate {
+    FileDate(raw)
+  }
+
+  /// FAT dates end in 2107. Later dates are stored as the last day it can
+  /// represent, rather than wrapping around to 1980.
+  pub fn from_date(date: &Date) -> FileDate {
+    if date.year > FAT_MAX_YEAR {
+      return FileDate::from_date(&Date { day: 31, month: 12, year: FAT_MAX_YEAR });
+    }
+    FileDate(
+      ((date.year as u16 & 0x7f) << 9) |
+      ((date.month as u16 & 0xf) << 5) |
+      (date.day as u16 & 0x1f)
+    )
+  }
+
   pub fn get_year(&self) -> usize {
     ((self.0 >> 9) & 0x7f) as usize + 1980
   }
@@ -118,7 +153,26 @@ pub fn file_name_components_from_string(s: &str) -> ([u8; 8], [u8; 3]) {
 
 #[cfg(test)]
 mod tests {
-  use super::file_name_components_from_string;
+  use crate::time::date::{Date, Time};
+  use super::{FileDate, FileTime, file_name_components_from_string};
+
+  #[test]
+  fn date_time_conversion() {
+    let date = FileDate::from_date(&Date { day: 14, month: 7, year: 41 });
+    assert_eq!(date.get_year(), 2021);
+    assert_eq!(date.get_month(), 7);
+    assert_eq!(date.get_day(), 14);
+
+    let time = FileTime::from_time(&Time { hours: 23, minutes: 5, seconds: 37 });
+    assert_eq!(time.get_hours(), 23);
+    assert_eq!(time.get_minutes(), 5);
+    assert_eq!(time.get_seconds(), 36);
+
+    let date = FileDate::from_date(&Date { day: 1, month: 3, year: 130 });
+    assert_eq!(date.get_year(), 2107);
+    assert_eq!(date.get_month(), 12);
+    assert_eq!(date.get_day(), 31);
+  }
 
   #[test]
   fn file_name_from_string() {
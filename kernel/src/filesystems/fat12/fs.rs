@@ -1,33 +1,153 @@
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use crate::devices;
+use crate::disks::block;
+use crate::disks::blockio;
 use crate::drivers::driver::DeviceDriver;
 use crate::files::cursor::SeekMethod;
 use crate::files::handle::{Handle, HandleAllocator, LocalHandle};
 use crate::memory::address::VirtualAddress;
+use crate::task::SleepMutex;
+use crate::time::timestamp::Timestamp;
 use spin::RwLock;
-use super::directory::{Directory, DirectoryEntry, DirectoryEntryIterator};
+use super::directory::{Directory, DirectoryEntry, DELETED_ENTRY};
 use super::disk::{BiosParamBlock, DiskConfig, DIRECTORY_ENTRY_SIZE};
+use super::ea::{self, ExtendedAttributeStore};
+use super::errors::FatError;
 use super::fat::{Cluster, ClusterChain, FatEntry, FatSection, FatValueResult};
-use super::file::{FileType, file_name_components_from_string};
-use super::super::filesystem::FileSystem;
-use syscall::files::{DirEntryInfo, DirEntryType};
+use super::file::{FileDate, FileTime, FileType, file_name_components_from_string};
+use super::ids::FileIdTable;
+use super::lfn::{self, LongNameCollector};
+use super::table::{FatTable, FIRST_DATA_CLUSTER};
+use super::super::cache::{self, BlockCache, BlockStore};
+use super::super::capabilities::Capabilities;
+use super::super::filesystem::{FileSystem, FileSystemKind};
+use super::super::options::MountOptions;
+use crate::syscalls::user::Caller;
+use syscall::files::{ClusterMap, ClusterMove, DirEntryInfo, DirEntryType, FileStatus, OpenFlags};
+use syscall::flags::{FAT_GET_CLUSTERS, FAT_IS_CLUSTER_FREE, FAT_MOVE_CLUSTER, FAT_UPDATE_ENTRY};
+use syscall::result::SystemError;
+
+/// Largest run of bytes a file holds in memory before writing it out
+const WRITE_BUFFER_SIZE: usize = 4096;
+
+/// Bytes written to a file that have not reached the disk yet. They form a
+/// single run starting at `offset`, and the clusters to hold them are only
+/// allocated when the run is flushed, so that a file written a byte at a time
+/// grows by whole batches of clusters.
+struct PendingWrite {
+  /// Handle the run was written through. Only it can extend the run; any
+  /// other access to the file flushes it first.
+  owner: LocalHandle,
+  offset: usize,
+  data: Vec<u8>,
+  /// Size of the file on disk when the run started. Anything between this and
+  /// `offset` was skipped over by a seek, and is filled with zeroes.
+  disk_size: usize,
+}
+
+impl PendingWrite {
+  fn end(&self) -> usize {
+    self.offset + self.data.len()
+  }
+}
 
 struct OpenFile {
   pub cursor: usize,
   pub file_type: FileType,
   pub clusters: ClusterChain,
+  /// Sector and index of the directory entry describing this file. The root
+  /// directory has no entry of its own.
+  pub entry_location: Option<(usize, usize)>,
+  pub byte_size: usize,
+  /// Set when the file contents or size have changed, and the directory entry
+  /// needs to be rewritten
+  pub modified: bool,
+  /// Set when the file has been read, so that its access date can be updated
+  pub accessed: bool,
+  /// Set when the disk was swapped while the file was open. The cluster chain
+  /// belongs to the old disk, so every further access fails.
+  pub stale: bool,
+  /// Access allowed through this handle
+  pub flags: OpenFlags,
+}
+
+impl OpenFile {
+  pub fn get_first_cluster(&self) -> Option<Cluster> {
+    self.clusters.clusters.first().copied()
+  }
+}
+
+/// A directory entry located by walking a directory
+struct FoundEntry {
+  entry: DirectoryEntry,
+  location: (usize, usize),
+  /// Locations of the long file name entries stored before the 8.3 entry
+  long_name_slots: Vec<(usize, usize)>,
+}
+
+/// The final component of a path, kept both as written and as a translated 8.3
+/// name
+struct SearchName<'a> {
+  full_name: &'a str,
+  name: [u8; 8],
+  ext: [u8; 3],
+  /// Set when the component does not fit in an 8.3 name, in which case it can
+  /// only match a long file name
+  is_long: bool,
+}
+
+impl<'a> SearchName<'a> {
+  fn matches(&self, entry: &DirectoryEntry, long_name: Option<&str>) -> bool {
+    if let Some(long) = long_name {
+      if long.eq_ignore_ascii_case(self.full_name) {
+        return true;
+      }
+    }
+    !self.is_long && entry.name_matches_search(&self.name, &self.ext)
+  }
 }
 
 pub struct Fat12FileSystem {
   handle_allocator: HandleAllocator<LocalHandle>,
   open_files: RwLock<BTreeMap<LocalHandle, OpenFile>>,
+  /// Written data waiting to be flushed, keyed by the location of each file's
+  /// directory entry, so that every handle to a file sees the same run. The
+  /// owning handle's cursor and byte size already include it.
+  pending_writes: RwLock<BTreeMap<(usize, usize), PendingWrite>>,
 
   drive_number: usize,
   drive_access_handle: LocalHandle,
 
-  config: DiskConfig,
-  io_buffer: RwLock<Vec<u8>>,
+  config: RwLock<DiskConfig>,
+  io_buffer: SleepMutex<Vec<u8>>,
+
+  options: RwLock<MountOptions>,
+
+  /// Full copy of the FAT, loaded the first time the table needs modification
+  fat_table: SleepMutex<Option<FatTable>>,
+
+  /// Contents of the extended attribute sidecar, loaded on first use
+  xattrs: RwLock<Option<ExtendedAttributeStore>>,
+
+  /// Stable identifiers reported by stat, which follow files through renames
+  file_ids: RwLock<FileIdTable>,
+
+  /// Recently used sectors, so that directory walks and FAT lookups do not
+  /// go to the disk every time. This and the buffers above stay locked while
+  /// the disk is read or written, so they use sleeping locks.
+  cache: SleepMutex<BlockCache>,
+
+  /// Set when the device reports that its media was swapped. No sectors are
+  /// read or written until the filesystem is remounted.
+  needs_remount: RwLock<bool>,
+}
+
+/// Every subdirectory starts with "." and ".." entries, which do not count
+/// towards its contents
+fn is_dot_entry(entry: &DirectoryEntry) -> bool {
+  let name = entry.get_name();
+  name == b".       " || name == b"..      "
 }
 
 impl Fat12FileSystem {
@@ -39,72 +159,102 @@ impl Fat12FileSystem {
     Fat12FileSystem {
       handle_allocator: HandleAllocator::new(),
       open_files: RwLock::new(BTreeMap::new()),
+      pending_writes: RwLock::new(BTreeMap::new()),
 
       drive_number,
       drive_access_handle,
 
-      config: DiskConfig::empty(),
-      io_buffer: RwLock::new(io_buffer),
+      config: RwLock::new(DiskConfig::empty()),
+      io_buffer: SleepMutex::new(io_buffer),
+
+      options: RwLock::new(MountOptions::new()),
+
+      fat_table: SleepMutex::new(None),
+
+      xattrs: RwLock::new(None),
+
+      file_ids: RwLock::new(FileIdTable::new()),
+
+      cache: SleepMutex::new(BlockCache::new(512, cache::DEFAULT_CAPACITY)),
+
+      needs_remount: RwLock::new(false),
     }
   }
 
   pub fn init(&mut self) -> Result<(), ()> {
     let driver = devices::get_driver_for_device(self.drive_number).ok_or(())?;
-    driver.open(self.drive_access_handle)?;
+    driver.open(self.drive_access_handle, OpenFlags::read_write())?;
+    self.read_boot_sector()
+  }
+
+  /// Load the disk layout from the BIOS Parameter Block in the boot sector
+  fn read_boot_sector(&self) -> Result<(), ()> {
+    let driver = devices::get_driver_for_device(self.drive_number).ok_or(())?;
     driver.seek(self.drive_access_handle, SeekMethod::Absolute(0x0b))?;
     let mut bpb = BiosParamBlock::empty();
     driver.read(self.drive_access_handle, bpb.as_buffer())?;
-    self.config.from_bpb(&bpb);
+    let mut config = self.config.write();
+    config.from_bpb(&bpb);
+    self.cache.lock().set_block_size(config.get_bytes_per_sector());
     Ok(())
   }
 
-  fn get_io_buffer_address(&self) -> VirtualAddress {
-    VirtualAddress::new(self.io_buffer.read().as_ptr() as usize)
+  /// The disk layout is read from the boot sector, and can change when the
+  /// filesystem is remounted on new media
+  fn get_config(&self) -> DiskConfig {
+    *self.config.read()
+  }
+
+  pub fn get_options(&self) -> MountOptions {
+    *self.options.read()
   }
 
   fn get_fat_sector_for_cluster(&self, cluster: Cluster) -> usize {
-    let clusters_per_sector = self.config.get_bytes_per_sector() * 2 / 3 + 1;
+    let clusters_per_sector = self.get_config().get_bytes_per_sector() * 2 / 3 + 1;
     cluster.as_usize() / clusters_per_sector
   }
 
-  fn load_sector_of_fat_table(&self, table: usize, sector: usize) -> Result<(), ()> {
-    if sector >= self.config.get_sectors_per_fat() {
+  fn load_sector_of_fat_table(&self, table: usize, sector: usize, buffer: &mut [u8]) -> Result<(), ()> {
+    if sector >= self.get_config().get_sectors_per_fat() {
       return Err(())
     }
 
-    let fat_sectors = self.config.get_fat_sectors(table).map_err(|_| ())?;
+    let fat_sectors = self.get_config().get_fat_sectors(table).map_err(|_| ())?;
     let sector_index = fat_sectors.get_first_sector() + sector;
-    let position = self.config.get_bytes_per_sector() * sector_index;
-
-    let driver = devices::get_driver_for_device(self.drive_number).ok_or(())?;
-    driver.seek(self.drive_access_handle, SeekMethod::Absolute(position))?;
-    {
-      let mut buffer = self.io_buffer.write();
-      driver.read(self.drive_access_handle, buffer.as_mut_slice())?;
-    }
-    Ok(())
+    self.read_sector(sector_index, buffer).map_err(|_| ())
   }
 
   pub fn get_cluster_chain(&self, first_cluster: Cluster) -> Result<ClusterChain, ()> {
+    if first_cluster.as_usize() < 2 {
+      // Empty files have no clusters allocated
+      return Ok(ClusterChain::empty());
+    }
+    if let Some(table) = self.fat_table.lock().as_ref() {
+      // Once the table has been loaded for modification, it is authoritative
+      return Ok(ClusterChain::from_vec(table.get_chain(first_cluster)));
+    }
     let mut clusters = Vec::with_capacity(1);
     let mut next = FatEntry::NextCluster(first_cluster);
     let mut current_fat_sector = 0xffff;
     let mut fat_sector_byte_offset = 0;
     let mut first_cluster_in_fat_sector = Cluster::new(0);
 
-    let clusters_per_sector = self.config.get_bytes_per_sector() * 2 / 3 + 1;
+    let clusters_per_sector = self.get_config().get_bytes_per_sector() * 2 / 3 + 1;
 
+    // The buffer holds the current FAT sector between iterations, so it stays
+    // locked for the whole walk
+    let mut buffer = self.io_buffer.lock();
     while let FatEntry::NextCluster(c) = next {
       clusters.push(c);
 
       let sector = self.get_fat_sector_for_cluster(c);
       if sector != current_fat_sector {
-        self.load_sector_of_fat_table(0, sector);
+        self.load_sector_of_fat_table(0, sector, buffer.as_mut_slice());
 
         first_cluster_in_fat_sector = Cluster::new(clusters_per_sector * sector);
         
         if sector > 0 {
-          let prev_trailing_bytes = sector * self.config.get_bytes_per_sector() % 3;
+          let prev_trailing_bytes = sector * self.get_config().get_bytes_per_sector() % 3;
           fat_sector_byte_offset = 3 - prev_trailing_bytes;
         } else {
           fat_sector_byte_offset = 0;
@@ -113,32 +263,29 @@ impl Fat12FileSystem {
         current_fat_sector = sector;
       }
       
-      let value = {
-        let mut buffer = self.io_buffer.write();
-        FatSection::at_slice(buffer.as_mut_slice(), fat_sector_byte_offset, first_cluster_in_fat_sector)
-          .get_value(c)
-      };
+      let value = FatSection::at_slice(buffer.as_mut_slice(), fat_sector_byte_offset, first_cluster_in_fat_sector)
+        .get_value(c);
       match value {
         FatValueResult::Partial4(part) => {
-          self.load_sector_of_fat_table(0, sector + 1);
+          self.load_sector_of_fat_table(0, sector + 1, buffer.as_mut_slice());
           current_fat_sector += 1;
           first_cluster_in_fat_sector = Cluster::new(
             first_cluster_in_fat_sector.as_usize() + clusters_per_sector
           );
           fat_sector_byte_offset = 1;
 
-          let high = self.io_buffer.read()[0] as u16;
+          let high = buffer[0] as u16;
           next = FatEntry::from_value((part as u16) | (high << 4));
         },
         FatValueResult::Partial8(part) => {
-          self.load_sector_of_fat_table(0, sector + 1);
+          self.load_sector_of_fat_table(0, sector + 1, buffer.as_mut_slice());
           current_fat_sector += 1;
           first_cluster_in_fat_sector = Cluster::new(
             first_cluster_in_fat_sector.as_usize() + clusters_per_sector
           );
           fat_sector_byte_offset = 2;
 
-          let high = (self.io_buffer.read()[0] & 0x0f) as u16;
+          let high = (buffer[0] & 0x0f) as u16;
           next = FatEntry::from_value((part as u16) | (high << 8));
         },
         FatValueResult::Success(entry) => {
@@ -151,85 +298,1144 @@ impl Fat12FileSystem {
     Ok(ClusterChain::from_vec(clusters))
   }
 
-  pub fn find_entry_in_directory(&self, name: &[u8; 8], ext: &[u8; 3], search_dir: Directory) -> Result<DirectoryEntry, ()> {
-    let driver = devices::get_driver_for_device(self.drive_number).ok_or(())?;
-    for sector in search_dir.clusters.sector_iter(&self.config) {
-      let bytes_per_sector = self.config.get_bytes_per_sector();
-      let position = sector * bytes_per_sector;
-      driver.seek(self.drive_access_handle, SeekMethod::Absolute(position))?;
-      {
-        let mut buffer = self.io_buffer.write();
-        driver.read(self.drive_access_handle, buffer.as_mut_slice())?;
-      }
-
-      let entries_per_sector = bytes_per_sector / DIRECTORY_ENTRY_SIZE;
-      let buffer_addr = self.get_io_buffer_address();
-      let mut entry_count = 0;
-      for entry in DirectoryEntryIterator::new(buffer_addr, entries_per_sector) {
-        entry_count += 1;
-        if entry.name_matches_search(&name, &ext) {
-          return Ok(*entry);
+  fn ensure_current_media(&self) -> Result<(), FatError> {
+    if *self.needs_remount.read() {
+      Err(FatError::MediaChanged)
+    } else {
+      Ok(())
+    }
+  }
+
+  fn read_sector(&self, sector: usize, buffer: &mut [u8]) -> Result<(), FatError> {
+    self.ensure_current_media()?;
+    self.cache.lock()
+      .read(self, self.drive_number, sector, buffer)
+      .map_err(|_| FatError::IOError)
+  }
+
+  fn write_sector(&self, sector: usize, buffer: &[u8]) -> Result<(), FatError> {
+    self.ensure_current_media()?;
+    self.cache.lock()
+      .write(self, self.drive_number, sector, buffer)
+      .map_err(|_| FatError::IOError)
+  }
+
+  /// Write any sectors held in the cache back to the disk
+  fn flush_cache(&self) -> Result<(), FatError> {
+    self.ensure_current_media()?;
+    self.cache.lock()
+      .flush(self, self.drive_number)
+      .map_err(|_| FatError::IOError)
+  }
+
+  /// Read the entire first FAT into memory, if it has not been loaded yet
+  fn ensure_fat_table_loaded(&self) -> Result<(), FatError> {
+    if self.fat_table.lock().is_some() {
+      return Ok(());
+    }
+    let bytes_per_sector = self.get_config().get_bytes_per_sector();
+    let fat_sectors = self.get_config().get_fat_sectors(0)?;
+    let mut bytes = Vec::with_capacity(fat_sectors.get_sector_count() * bytes_per_sector);
+    for _ in 0..(fat_sectors.get_sector_count() * bytes_per_sector) {
+      bytes.push(0);
+    }
+    for i in 0..fat_sectors.get_sector_count() {
+      let start = i * bytes_per_sector;
+      self.read_sector(fat_sectors.get_first_sector() + i, &mut bytes[start..(start + bytes_per_sector)])?;
+    }
+    let table = FatTable::new(bytes, bytes_per_sector, self.get_config().get_cluster_count());
+    *self.fat_table.lock() = Some(table);
+    Ok(())
+  }
+
+  /// Write every modified FAT sector to each copy of the table on disk
+  fn flush_fat_table(&self, table: &mut FatTable) -> Result<(), FatError> {
+    let mut copy = 0;
+    while let Ok(fat_sectors) = self.get_config().get_fat_sectors(copy) {
+      for sector in table.get_dirty_sectors() {
+        self.write_sector(fat_sectors.get_first_sector() + sector, table.get_sector(sector))?;
+      }
+      copy += 1;
+    }
+    table.clear_dirty();
+    Ok(())
+  }
+
+  /// Allocate a chain of clusters, optionally appended to an existing chain,
+  /// and persist the updated table. If the table cannot be written, the
+  /// allocation is undone so that the in-memory copy never diverges from what
+  /// the caller believes happened.
+  pub fn allocate_clusters(&self, count: usize, append_to: Option<Cluster>) -> Result<Vec<Cluster>, FatError> {
+    self.ensure_fat_table_loaded()?;
+    let mut table_lock = self.fat_table.lock();
+    let table = table_lock.as_mut().ok_or(FatError::InvalidFatTable)?;
+    let allocated = table.allocate(count, append_to)?;
+    if let Err(e) = self.flush_fat_table(table) {
+      table.rollback(&allocated, append_to);
+      return Err(e);
+    }
+    Ok(allocated)
+  }
+
+  /// Allocate clusters like `allocate_clusters`, but in a single contiguous
+  /// run when one is available
+  pub fn allocate_contiguous_clusters(&self, count: usize, append_to: Option<Cluster>) -> Result<Vec<Cluster>, FatError> {
+    self.ensure_fat_table_loaded()?;
+    let mut table_lock = self.fat_table.lock();
+    let table = table_lock.as_mut().ok_or(FatError::InvalidFatTable)?;
+    let allocated = table.allocate_contiguous(count, append_to)?;
+    if let Err(e) = self.flush_fat_table(table) {
+      table.rollback(&allocated, append_to);
+      return Err(e);
+    }
+    Ok(allocated)
+  }
+
+  /// Return clusters from a failed operation to the free pool
+  pub fn release_clusters(&self, allocated: &[Cluster], append_to: Option<Cluster>) -> Result<(), FatError> {
+    let mut table_lock = self.fat_table.lock();
+    let table = table_lock.as_mut().ok_or(FatError::InvalidFatTable)?;
+    table.rollback(allocated, append_to);
+    self.flush_fat_table(table)
+  }
+
+  /// Fill every sector of a cluster with zeroes, so that a new directory
+  /// cluster contains no stale entries
+  fn zero_cluster(&self, cluster: Cluster) -> Result<(), FatError> {
+    let chain = ClusterChain::from_vec(alloc::vec![cluster]);
+    let mut buffer = self.io_buffer.lock();
+    for byte in buffer.iter_mut() {
+      *byte = 0;
+    }
+    for sector in chain.sector_iter(&self.get_config()) {
+      self.write_sector(sector, buffer.as_slice())?;
+    }
+    Ok(())
+  }
+
+  /// Locate a run of consecutive unused entries in a directory, returning the
+  /// sector and index of each entry in the run. Runs longer than one entry are
+  /// needed to store long file names.
+  /// The root directory has a fixed size, so if it is full a DirectoryFull
+  /// error is returned. Subdirectories are extended with new clusters; if none
+  /// can be allocated a NoSpace error is returned.
+  fn find_free_directory_run(&self, dir: &Directory, count: usize) -> Result<Vec<(usize, usize)>, FatError> {
+    let bytes_per_sector = self.get_config().get_bytes_per_sector();
+    let entries_per_sector = bytes_per_sector / DIRECTORY_ENTRY_SIZE;
+    let is_root = dir.clusters.clusters.len() == 0;
+    let mut run: Vec<(usize, usize)> = Vec::with_capacity(count);
+    for sector in dir.clusters.sector_iter(&self.get_config()) {
+      // The entries are read straight out of the buffer, so it stays locked
+      // until the whole sector has been scanned
+      let mut buffer = self.io_buffer.lock();
+      self.read_sector(sector, buffer.as_mut_slice())?;
+      for index in 0..entries_per_sector {
+        let entry = DirectoryEntry::at_address(
+          VirtualAddress::new(buffer.as_ptr() as usize + index * DIRECTORY_ENTRY_SIZE)
+        );
+        if entry.is_free() {
+          run.push((sector, index));
+          if run.len() == count {
+            return Ok(run);
+          }
+        } else {
+          run.clear();
         }
       }
-      if entry_count < entries_per_sector {
-        return Err(());
+    }
+    if is_root {
+      return Err(FatError::DirectoryFull);
+    }
+
+    // Free entries at the end of the directory continue into the new clusters
+    let entries_per_cluster = entries_per_sector * self.get_config().get_sectors_per_cluster();
+    let needed = count - run.len();
+    let cluster_count = (needed + entries_per_cluster - 1) / entries_per_cluster;
+    let tail = dir.clusters.clusters.last().copied();
+    let added = self.allocate_clusters(cluster_count, tail)?;
+    for cluster in added.iter() {
+      if let Err(e) = self.zero_cluster(*cluster) {
+        self.release_clusters(&added, tail)?;
+        return Err(e);
       }
     }
-    Err(())
+    let new_chain = ClusterChain::from_vec(added);
+    for sector in new_chain.sector_iter(&self.get_config()) {
+      for index in 0..entries_per_sector {
+        if run.len() == count {
+          return Ok(run);
+        }
+        run.push((sector, index));
+      }
+    }
+    Ok(run)
   }
-}
 
-impl FileSystem for Fat12FileSystem {
-  fn open(&self, path: &str) -> Result<LocalHandle, ()> {
-    
-    // First, find the file
-    // Iterate through each directory name in the path, until coming to the
-    // parent directory
-    let mut parts = path.split("\\");
-    let mut search_dir = Directory::empty();
-
-    let mut part = parts.next().ok_or(())?;
+  /// Write a new entry into a directory. The entry is written with a single
+  /// sector write, so a failure never leaves a partial entry on disk.
+  pub fn add_directory_entry(&self, dir: &Directory, entry: &DirectoryEntry) -> Result<(usize, usize), FatError> {
+    let mut raw = [0; DIRECTORY_ENTRY_SIZE];
+    raw.copy_from_slice(entry.as_bytes());
+    self.add_directory_entries(dir, &[raw])
+  }
+
+  /// Write a series of consecutive entries into a directory, such as a long
+  /// file name followed by its 8.3 entry. The location of the last entry is
+  /// returned.
+  fn add_directory_entries(&self, dir: &Directory, entries: &[lfn::RawEntry]) -> Result<(usize, usize), FatError> {
+    let slots = self.find_free_directory_run(dir, entries.len())?;
+    let mut buffer = self.io_buffer.lock();
+    let mut current_sector = None;
+    for (slot, raw) in slots.iter().zip(entries.iter()) {
+      let (sector, index) = *slot;
+      if current_sector != Some(sector) {
+        if let Some(previous) = current_sector {
+          self.write_sector(previous, buffer.as_slice())?;
+        }
+        self.read_sector(sector, buffer.as_mut_slice())?;
+        current_sector = Some(sector);
+      }
+      let offset = index * DIRECTORY_ENTRY_SIZE;
+      buffer[offset..(offset + DIRECTORY_ENTRY_SIZE)].copy_from_slice(raw);
+    }
+    if let Some(sector) = current_sector {
+      self.write_sector(sector, buffer.as_slice())?;
+    }
+    slots.last().copied().ok_or(FatError::IOError)
+  }
+
+  pub fn find_entry_in_directory(&self, name: &[u8; 8], ext: &[u8; 3], search_dir: Directory) -> Result<DirectoryEntry, ()> {
+    self.find_entry_matching(&search_dir, |entry| entry.name_matches_search(name, ext))
+      .map(|(entry, _, _)| entry)
+      .map_err(|_| ())
+  }
+
+  /**
+   * Visit every entry in a directory in order, along with its long file name
+   * if one is stored before it. Deleted entries and the long name entries
+   * themselves are skipped. The walk stops when the visitor returns true, and
+   * the entry it stopped on is returned.
+   * The visitor is called while the IO buffer is locked, so it must not
+   * perform any disk access.
+   */
+  fn walk_directory<F: FnMut(&DirectoryEntry, Option<&str>) -> bool>(&self, dir: &Directory, mut visitor: F) -> Result<Option<FoundEntry>, FatError> {
+    let bytes_per_sector = self.get_config().get_bytes_per_sector();
+    let entries_per_sector = bytes_per_sector / DIRECTORY_ENTRY_SIZE;
+    let mut collector = LongNameCollector::new();
+    let mut long_name_slots: Vec<(usize, usize)> = Vec::new();
+    for sector in dir.clusters.sector_iter(&self.get_config()) {
+      let mut buffer = self.io_buffer.lock();
+      self.read_sector(sector, buffer.as_mut_slice())?;
+      for index in 0..entries_per_sector {
+        let offset = index * DIRECTORY_ENTRY_SIZE;
+        let raw = &buffer[offset..(offset + DIRECTORY_ENTRY_SIZE)];
+        if raw[0] == 0 {
+          // End of the directory
+          return Ok(None);
+        }
+        if raw[0] == DELETED_ENTRY {
+          collector.reset();
+          long_name_slots.clear();
+          continue;
+        }
+        if lfn::is_long_name_entry(raw) {
+          collector.push(raw);
+          long_name_slots.push((sector, index));
+          continue;
+        }
+        let entry = *DirectoryEntry::at_address(VirtualAddress::new(buffer.as_ptr() as usize + offset));
+        let mut short_name = [0; 11];
+        entry.get_full_name(&mut short_name);
+        let long_name = collector.finish(&short_name);
+        let slots = if long_name.is_some() {
+          core::mem::replace(&mut long_name_slots, Vec::new())
+        } else {
+          long_name_slots.clear();
+          Vec::new()
+        };
+        if visitor(&entry, long_name.as_deref()) {
+          return Ok(Some(FoundEntry {
+            entry,
+            location: (sector, index),
+            long_name_slots: slots,
+          }));
+        }
+      }
+    }
+    Ok(None)
+  }
+
+  /// Walk a directory until an entry satisfies the predicate, returning a
+  /// copy of the entry as well as the sector and index where it is stored
+  fn find_entry_matching<F: Fn(&DirectoryEntry) -> bool>(&self, search_dir: &Directory, predicate: F) -> Result<(DirectoryEntry, usize, usize), FatError> {
+    let found = self.walk_directory(search_dir, |entry, _| predicate(entry))?;
+    found
+      .map(|f| (f.entry, f.location.0, f.location.1))
+      .ok_or(FatError::NotFound)
+  }
+
+  /// Find the entry for a path component, matching either its long name or
+  /// its 8.3 name
+  fn find_named_entry(&self, search_dir: &Directory, search: &SearchName) -> Result<FoundEntry, FatError> {
+    let found = self.walk_directory(search_dir, |entry, long_name| search.matches(entry, long_name))?;
+    found.ok_or(FatError::NotFound)
+  }
+
+  fn make_search_name<'a>(&self, part: &'a str) -> SearchName<'a> {
+    let (mut name, mut ext) = file_name_components_from_string(part);
+    let codepage = self.get_options().codepage;
+    codepage.translate_name(&mut name);
+    codepage.translate_name(&mut ext);
+    SearchName {
+      full_name: part,
+      name,
+      ext,
+      is_long: lfn::needs_long_name(part),
+    }
+  }
+
+  /// Find a subdirectory of `parent` and load its cluster chain. A ".." entry
+  /// pointing at cluster zero leads back to the root directory.
+  fn enter_directory(&self, parent: &Directory, search: &SearchName) -> Result<Directory, FatError> {
+    let found = self.find_named_entry(parent, search)?;
+    if !found.entry.get_file_type().is_directory() {
+      return Err(FatError::NotFound);
+    }
+    let clusters = self.get_cluster_chain(found.entry.get_first_cluster()).map_err(|_| FatError::IOError)?;
+    Ok(Directory {
+      clusters,
+    })
+  }
+
+  /// Split a path into the directory to search and the name of the final
+  /// component, walking each directory along the way
+  fn resolve_path<'a>(&self, path: &'a str) -> Result<(Directory, SearchName<'a>), FatError> {
+    let mut parts = path.split("\\").filter(|part| part.len() > 0).peekable();
+    let mut dir = Directory::empty();
     loop {
-      // find the directory with the matching name, and fetch its cluster chain
+      let part = parts.next().ok_or(FatError::NotFound)?;
+      let search = self.make_search_name(part);
+      if parts.peek().is_none() {
+        return Ok((dir, search));
+      }
+      dir = self.enter_directory(&dir, &search)?;
+    }
+  }
 
-      // to be implemented...
+  /// Location of the ".." entry of a subdirectory, which is always the second
+  /// entry of its first cluster
+  fn dot_dot_location(&self, cluster: Cluster) -> Result<(usize, usize), FatError> {
+    let chain = ClusterChain::from_vec(alloc::vec![cluster]);
+    let sector = chain.sector_iter(&self.get_config()).next().ok_or(FatError::IOError)?;
+    Ok((sector, 1))
+  }
 
-      let next = parts.next();
-      match next {
-        Some(p) => part = p,
-        None => break,
+  /// Check whether a directory is `ancestor` or sits somewhere below it, by
+  /// following ".." entries up to the root
+  fn is_within_directory(&self, dir: &Directory, ancestor: Cluster) -> Result<bool, FatError> {
+    let mut current = dir.get_first_cluster();
+    // A corrupt disk could link directories in a loop
+    for _ in 0..self.get_config().get_cluster_count() {
+      if current == ancestor {
+        return Ok(true);
       }
+      if current.as_usize() < FIRST_DATA_CLUSTER {
+        return Ok(false);
+      }
+      current = self.read_entry(self.dot_dot_location(current)?)?.get_first_cluster();
     }
+    Ok(false)
+  }
 
-    // With the parent directory located, iterate through all directory entries
-    // to find a file with a matching name
-    let (name, ext) = file_name_components_from_string(part);
+  /// Pick an 8.3 alias for a long name that does not collide with any other
+  /// entry in the directory
+  fn generate_short_alias(&self, dir: &Directory, long_name: &str) -> Result<([u8; 8], [u8; 3]), FatError> {
+    let (mut basis, mut ext) = lfn::short_name_basis(long_name);
+    let codepage = self.get_options().codepage;
+    codepage.translate_name(&mut basis);
+    codepage.translate_name(&mut ext);
+    let mut existing: Vec<[u8; 11]> = Vec::new();
+    self.walk_directory(dir, |entry, _| {
+      let mut short_name = [0; 11];
+      entry.get_full_name(&mut short_name);
+      existing.push(short_name);
+      false
+    })?;
+    for number in 1..1000000 {
+      let name = lfn::apply_numeric_tail(&basis, number);
+      let mut short_name = [0; 11];
+      short_name[..8].copy_from_slice(&name);
+      short_name[8..].copy_from_slice(&ext);
+      if !existing.contains(&short_name) {
+        return Ok((name, ext));
+      }
+    }
+    Err(FatError::DirectoryFull)
+  }
 
-    let entry = self.find_entry_in_directory(&name, &ext, search_dir)?;
+  /// Add an entry for the final component of a path, along with long name
+  /// entries if the name does not fit in 8.3. Returns the new entry and the
+  /// location of its 8.3 entry.
+  fn add_named_entry(&self, dir: &Directory, search: &SearchName, attributes: u8, first_cluster: Option<Cluster>) -> Result<(DirectoryEntry, (usize, usize)), SystemError> {
+    let (name, ext) = if search.is_long {
+      self.generate_short_alias(dir, search.full_name).map_err(|e| e.to_system_error())?
+    } else {
+      (search.name, search.ext)
+    };
+    let mut entry = DirectoryEntry::new(name, ext, attributes);
+    if let Some(cluster) = first_cluster {
+      entry.set_first_cluster(cluster);
+    }
+    let (date, time) = Self::get_current_file_date_time();
+    entry.set_creation_time(date, time);
+    entry.set_modify_time(date, time);
+    entry.set_access_date(date);
+    let mut raw_entries = if search.is_long {
+      let mut short_name = [0; 11];
+      entry.get_full_name(&mut short_name);
+      lfn::create_entries(search.full_name, &short_name).map_err(|_| SystemError::InvalidArgument)?
+    } else {
+      Vec::new()
+    };
+    let mut raw = [0; DIRECTORY_ENTRY_SIZE];
+    raw.copy_from_slice(entry.as_bytes());
+    raw_entries.push(raw);
+    let location = self.add_directory_entries(dir, &raw_entries).map_err(|e| e.to_system_error())?;
+    Ok((entry, location))
+  }
+
+  /// Fill a new directory cluster with empty entries, except for the "." and
+  /// ".." entries that every subdirectory begins with
+  fn write_dot_entries(&self, cluster: Cluster, parent: Cluster) -> Result<(), FatError> {
+    self.zero_cluster(cluster)?;
+    let (date, time) = Self::get_current_file_date_time();
+    let mut dot = DirectoryEntry::new(*b".       ", *b"   ", 0x10);
+    dot.set_first_cluster(cluster);
+    let mut dot_dot = DirectoryEntry::new(*b"..      ", *b"   ", 0x10);
+    dot_dot.set_first_cluster(parent);
+    for entry in [&mut dot, &mut dot_dot].iter_mut() {
+      entry.set_creation_time(date, time);
+      entry.set_modify_time(date, time);
+      entry.set_access_date(date);
+    }
+    let chain = ClusterChain::from_vec(alloc::vec![cluster]);
+    let sector = chain.sector_iter(&self.get_config()).next().ok_or(FatError::IOError)?;
+    let mut buffer = self.io_buffer.lock();
+    self.read_sector(sector, buffer.as_mut_slice())?;
+    buffer[..DIRECTORY_ENTRY_SIZE].copy_from_slice(dot.as_bytes());
+    buffer[DIRECTORY_ENTRY_SIZE..(DIRECTORY_ENTRY_SIZE * 2)].copy_from_slice(dot_dot.as_bytes());
+    self.write_sector(sector, buffer.as_slice())
+  }
+
+  /// Remove a file or directory entry, along with its long name, its
+  /// clusters, and any extended attributes
+  fn remove_entry(&self, found: &FoundEntry) -> Result<(), SystemError> {
+    let entry = found.entry;
+    // Open handles keep the entry's location and clusters, and would go on
+    // using them after they were freed and handed to another file
+    let in_use = self.open_files.read().values()
+      .any(|file| file.entry_location == Some(found.location));
+    if in_use {
+      return Err(SystemError::Busy);
+    }
+    // Remove the directory entry first. If the FAT update fails afterwards,
+    // the worst case is some lost clusters rather than a dangling entry that
+    // points at free space. Long name entries are removed before the 8.3
+    // entry, since an orphaned long name is ignored.
+    for slot in found.long_name_slots.iter() {
+      self.update_entry(*slot, |entry| entry.mark_deleted())
+        .map_err(|e| e.to_system_error())?;
+    }
+    self.update_entry(found.location, |entry| entry.mark_deleted())
+      .map_err(|e| e.to_system_error())?;
+    self.file_ids.write().vacate(self.location_to_entry_id(found.location));
+    // Nothing is left to flush buffered writes into, and they must not land
+    // in whatever file takes the slot next
+    self.pending_writes.write().remove(&found.location);
     let first_cluster = entry.get_first_cluster();
-    let cluster_chain = self.get_cluster_chain(first_cluster)?;
+    if first_cluster.as_usize() >= 2 {
+      self.ensure_fat_table_loaded().map_err(|e| e.to_system_error())?;
+      let mut table_lock = self.fat_table.lock();
+      let table = table_lock.as_mut().ok_or(SystemError::IOError)?;
+      table.free_chain(first_cluster);
+      self.flush_fat_table(table).map_err(|e| e.to_system_error())?;
+    }
+    let ea_handle = entry.get_ea_handle();
+    if ea_handle != 0 {
+      self.modify_xattrs(|store| {
+        store.remove_all(ea_handle);
+        Ok(())
+      })?;
+    }
+    Ok(())
+  }
+
+  fn get_cluster_size(&self) -> usize {
+    let config = self.get_config();
+    config.get_bytes_per_sector() * config.get_sectors_per_cluster()
+  }
+
+  /// Determine which disk sector contains a byte offset within a file
+  fn get_sector_for_offset(&self, clusters: &ClusterChain, offset: usize) -> Option<usize> {
+    let cluster_size = self.get_cluster_size();
+    let cluster = clusters.clusters.get(offset / cluster_size)?;
+    let config = self.get_config();
+    let sector_in_cluster = (offset % cluster_size) / config.get_bytes_per_sector();
+    let data_start = config.get_data_sectors().get_first_sector();
+    Some(data_start + (cluster.as_usize() - 2) * config.get_sectors_per_cluster() + sector_in_cluster)
+  }
+
+  /// Write bytes to a range of a file's clusters. If `source` is None, the
+  /// range is filled with zeroes. Sectors that are only partially covered are
+  /// read first so that surrounding data is preserved.
+  fn write_range(&self, clusters: &ClusterChain, offset: usize, length: usize, source: Option<&[u8]>) -> Result<(), FatError> {
+    let bytes_per_sector = self.get_config().get_bytes_per_sector();
+    let mut written = 0;
+    while written < length {
+      let position = offset + written;
+      let sector = self.get_sector_for_offset(clusters, position).ok_or(FatError::IOError)?;
+      let local_offset = position % bytes_per_sector;
+      let chunk = (bytes_per_sector - local_offset).min(length - written);
+      let mut buffer = self.io_buffer.lock();
+      if chunk < bytes_per_sector {
+        self.read_sector(sector, buffer.as_mut_slice())?;
+      }
+      let dest = &mut buffer[local_offset..(local_offset + chunk)];
+      match source {
+        Some(src) => dest.copy_from_slice(&src[written..(written + chunk)]),
+        None => {
+          for byte in dest.iter_mut() {
+            *byte = 0;
+          }
+        },
+      }
+      self.write_sector(sector, buffer.as_slice())?;
+      written += chunk;
+    }
+    Ok(())
+  }
+
+  /// Make sure a file has enough clusters to hold `length` bytes, allocating
+  /// and linking new ones as necessary. Returns the clusters that were added,
+  /// so that they can be released if a later step fails.
+  fn ensure_capacity(&self, file_clusters: &ClusterChain, length: usize) -> Result<(ClusterChain, Vec<Cluster>), FatError> {
+    let cluster_size = self.get_cluster_size();
+    let needed = (length + cluster_size - 1) / cluster_size;
+    let current = file_clusters.clusters.len();
+    if needed <= current {
+      return Ok((ClusterChain::from_vec(file_clusters.clusters.to_vec()), Vec::new()));
+    }
+    let tail = file_clusters.clusters.last().copied();
+    let added = self.allocate_clusters(needed - current, tail)?;
+    let mut extended = file_clusters.clusters.to_vec();
+    extended.extend_from_slice(&added);
+    Ok((ClusterChain::from_vec(extended), added))
+  }
+
+  /// Shorten a cluster chain so that only the first `keep` clusters remain
+  fn shrink_chain(&self, file_clusters: &ClusterChain, keep: usize) -> Result<ClusterChain, FatError> {
+    if keep >= file_clusters.clusters.len() {
+      return Ok(ClusterChain::from_vec(file_clusters.clusters.to_vec()));
+    }
+    self.ensure_fat_table_loaded()?;
+    let mut table_lock = self.fat_table.lock();
+    let table = table_lock.as_mut().ok_or(FatError::InvalidFatTable)?;
+    table.free_chain(file_clusters.clusters[keep]);
+    if keep > 0 {
+      table.set(file_clusters.clusters[keep - 1], FatEntry::EndOfChain);
+    }
+    self.flush_fat_table(table)?;
+    Ok(ClusterChain::from_vec(file_clusters.clusters[0..keep].to_vec()))
+  }
+
+  /// FAT entries have no time zone, so like DOS they are stamped in local time
+  fn get_current_file_date_time() -> (FileDate, FileTime) {
+    let now = crate::time::system::get_local_time();
+    (FileDate::from_date(&now.date), FileTime::from_time(&now.time))
+  }
+
+  /// Apply a modification to the directory entry stored at a location on disk
+  fn update_entry<F: FnOnce(&mut DirectoryEntry)>(&self, location: (usize, usize), f: F) -> Result<(), FatError> {
+    let (sector, index) = location;
+    let mut buffer = self.io_buffer.lock();
+    self.read_sector(sector, buffer.as_mut_slice())?;
+    let entry_addr = VirtualAddress::new(buffer.as_ptr() as usize + index * DIRECTORY_ENTRY_SIZE);
+    f(DirectoryEntry::at_address(entry_addr));
+    self.write_sector(sector, buffer.as_slice())
+  }
+
+  /// Write the size, first cluster, and timestamps of an open file back to its
+  /// directory entry
+  fn flush_entry(&self, handle: LocalHandle) -> Result<(), FatError> {
+    self.flush_pending(handle)?;
+    let (location, first_cluster, byte_size, modified, accessed) = {
+      let files = self.open_files.read();
+      let file = files.get(&handle).ok_or(FatError::NotFound)?;
+      (file.entry_location, file.get_first_cluster(), file.byte_size, file.modified, file.accessed)
+    };
+    let location = match location {
+      Some(location) => location,
+      None => return Ok(()),
+    };
+    let update_access = accessed && !self.get_options().no_atime;
+    if !modified && !update_access {
+      return Ok(());
+    }
+    let (date, time) = Self::get_current_file_date_time();
+    self.update_entry(location, |entry| {
+      if modified {
+        entry.set_first_cluster(first_cluster.unwrap_or(Cluster::new(0)));
+        entry.set_byte_size(byte_size);
+        entry.set_modify_time(date, time);
+      }
+      if update_access || modified {
+        entry.set_access_date(date);
+      }
+    })?;
+    if let Some(file) = self.open_files.write().get_mut(&handle) {
+      file.modified = false;
+      file.accessed = false;
+    }
+    Ok(())
+  }
+
+  /// Try to hold a write in the file's pending run instead of writing it to
+  /// disk. Returns false if the write has to go through to the disk, because
+  /// the drive or handle is synchronous, or the write does not continue a run
+  /// started through the same handle.
+  fn try_buffer_write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<bool, SystemError> {
+    let sync = self.get_options().sync;
+    let mut files = self.open_files.write();
+    let file = files.get_mut(&handle).ok_or(SystemError::BadFileDescriptor)?;
+    if file.stale {
+      return Err(SystemError::MediaChanged);
+    }
+    if file.file_type.is_directory() || !file.flags.can_write() {
+      return Err(SystemError::BadFileDescriptor);
+    }
+    if buffer.len() == 0 {
+      return Ok(true);
+    }
+    if sync || file.flags.is_sync() {
+      return Ok(false);
+    }
+    let location = match file.entry_location {
+      Some(location) => location,
+      None => return Ok(false),
+    };
+    let cursor = file.cursor;
+    let mut pending_writes = self.pending_writes.write();
+    match pending_writes.get_mut(&location) {
+      Some(pending) => {
+        if pending.owner != handle || pending.end() != cursor || pending.data.len() + buffer.len() > WRITE_BUFFER_SIZE {
+          return Ok(false);
+        }
+        pending.data.extend_from_slice(buffer);
+      },
+      None => {
+        if buffer.len() >= WRITE_BUFFER_SIZE {
+          return Ok(false);
+        }
+        pending_writes.insert(location, PendingWrite {
+          owner: handle,
+          offset: cursor,
+          data: buffer.to_vec(),
+          disk_size: file.byte_size,
+        });
+      },
+    }
+    file.cursor += buffer.len();
+    file.byte_size = file.byte_size.max(file.cursor);
+    file.modified = true;
+    Ok(true)
+  }
+
+  /// Write out the pending run of the file a handle refers to, whichever
+  /// handle it was written through. Called before every access that depends
+  /// on the file's contents or size.
+  fn flush_pending(&self, handle: LocalHandle) -> Result<(), FatError> {
+    let location = self.open_files.read().get(&handle).ok_or(FatError::NotFound)?.entry_location;
+    match location {
+      Some(location) => self.flush_file(location),
+      None => Ok(()),
+    }
+  }
+
+  /// Write out the pending run of a file, if it has one. Clusters for the
+  /// whole run are allocated in a single batch, so a file built up from many
+  /// small writes still ends up with as few fragments as possible.
+  fn flush_file(&self, location: (usize, usize)) -> Result<(), FatError> {
+    let (pending, clusters, cursor) = {
+      let files = self.open_files.read();
+      let pending = match self.pending_writes.write().remove(&location) {
+        Some(pending) => pending,
+        None => return Ok(()),
+      };
+      // Closing a handle flushes its run first, so the owner is still open
+      let file = files.get(&pending.owner).ok_or(FatError::NotFound)?;
+      (pending, ClusterChain::from_vec(file.clusters.clusters.to_vec()), file.cursor)
+    };
+    let handle = pending.owner;
+    let disk_size = pending.disk_size;
+    let end = pending.end();
+    let tail = clusters.clusters.last().copied();
+    let result = self.ensure_capacity(&clusters, end).and_then(|(extended, added)| {
+      let mut result = Ok(());
+      if pending.offset > disk_size {
+        // Seeking past the end of a file leaves a gap that must read as zeroes
+        result = self.write_range(&extended, disk_size, pending.offset - disk_size, None);
+      }
+      if result.is_ok() {
+        result = self.write_range(&extended, pending.offset, pending.data.len(), Some(pending.data.as_slice()));
+      }
+      if result.is_err() && added.len() > 0 {
+        let _ = self.release_clusters(&added, tail);
+      }
+      result.map(|_| extended)
+    });
+    match result {
+      Ok(extended) => self.file_changed(handle, extended, disk_size.max(end), cursor),
+      Err(e) => {
+        // The buffered bytes are lost, so the file goes back to its size on disk
+        if let Some(file) = self.open_files.write().get_mut(&handle) {
+          file.byte_size = disk_size;
+        }
+        Err(e)
+      },
+    }
+  }
+
+  /// Record the new state of a file after its contents changed, and flush the
+  /// directory entry immediately if the drive was mounted for sync writes.
+  /// Every other handle to the same file sees the new clusters and size.
+  fn file_changed(&self, handle: LocalHandle, clusters: ClusterChain, byte_size: usize, cursor: usize) -> Result<(), FatError> {
+    {
+      let mut files = self.open_files.write();
+      let file = files.get_mut(&handle).ok_or(FatError::NotFound)?;
+      file.cursor = cursor;
+      file.modified = true;
+      let location = file.entry_location;
+      for (other_handle, file) in files.iter_mut() {
+        if *other_handle == handle || (location.is_some() && file.entry_location == location) {
+          file.clusters = ClusterChain::from_vec(clusters.clusters.to_vec());
+          file.byte_size = byte_size;
+        }
+      }
+    }
+    if self.get_options().sync {
+      self.flush_entry(handle)?;
+    }
+    Ok(())
+  }
+
+  /// Read a copy of the directory entry stored at a location on disk
+  fn read_entry(&self, location: (usize, usize)) -> Result<DirectoryEntry, FatError> {
+    let (sector, index) = location;
+    let mut buffer = self.io_buffer.lock();
+    self.read_sector(sector, buffer.as_mut_slice())?;
+    let entry_addr = VirtualAddress::new(buffer.as_ptr() as usize + index * DIRECTORY_ENTRY_SIZE);
+    Ok(*DirectoryEntry::at_address(entry_addr))
+  }
+
+  fn find_xattr_sidecar(&self) -> Result<(DirectoryEntry, usize, usize), FatError> {
+    self.find_entry_matching(&Directory::empty(), |entry| {
+      entry.name_matches_search(&ea::SIDECAR_NAME, &ea::SIDECAR_EXT)
+    })
+  }
+
+  /// Read the extended attribute sidecar into memory, if it has not been
+  /// loaded yet. A disk without a sidecar has no attributes.
+  fn ensure_xattrs_loaded(&self) -> Result<(), SystemError> {
+    if self.xattrs.read().is_some() {
+      return Ok(());
+    }
+    let store = match self.find_xattr_sidecar() {
+      Ok((entry, sector, index)) => {
+        let handle = self.open_directory_entry(&entry, (sector, index), OpenFlags::read_write()).map_err(|e| e.to_system_error())?;
+        let mut bytes = Vec::with_capacity(entry.get_byte_size());
+        bytes.resize(entry.get_byte_size(), 0);
+        let read = self.read(handle, bytes.as_mut_slice());
+        let _ = self.close(handle);
+        let length = read.map_err(|_| SystemError::IOError)?;
+        ExtendedAttributeStore::parse(&bytes[..length]).map_err(|e| e.to_system_error())?
+      },
+      Err(FatError::NotFound) => ExtendedAttributeStore::new(),
+      Err(e) => return Err(e.to_system_error()),
+    };
+    *self.xattrs.write() = Some(store);
+    Ok(())
+  }
+
+  /// Rewrite the sidecar file with the current attribute store, creating it
+  /// if necessary
+  fn save_xattrs(&self, bytes: &[u8]) -> Result<(), SystemError> {
+    let (entry, location) = match self.find_xattr_sidecar() {
+      Ok((entry, sector, index)) => (entry, (sector, index)),
+      Err(FatError::NotFound) => {
+        let entry = DirectoryEntry::new(ea::SIDECAR_NAME, ea::SIDECAR_EXT, ea::SIDECAR_ATTRIBUTES);
+        let location = self.add_directory_entry(&Directory::empty(), &entry).map_err(|e| e.to_system_error())?;
+        (entry, location)
+      },
+      Err(e) => return Err(e.to_system_error()),
+    };
+    let handle = self.open_directory_entry(&entry, location, OpenFlags::read_write()).map_err(|e| e.to_system_error())?;
+    let mut result = self.truncate(handle, 0);
+    if result.is_ok() {
+      result = self.write(handle, bytes).map(|_| ());
+    }
+    let closed = self.close(handle).map_err(|_| SystemError::IOError);
+    result.and(closed)
+  }
+
+  /// Apply a change to the attribute store and persist it. If the sidecar
+  /// cannot be written, the in-memory copy is discarded so that it is reloaded
+  /// from disk on next use.
+  fn modify_xattrs<F: FnOnce(&mut ExtendedAttributeStore) -> Result<(), FatError>>(&self, f: F) -> Result<(), SystemError> {
+    self.ensure_xattrs_loaded()?;
+    let bytes = {
+      let mut lock = self.xattrs.write();
+      let store = lock.as_mut().ok_or(SystemError::IOError)?;
+      f(store).map_err(|e| e.to_system_error())?;
+      store.to_bytes()
+    };
+    let result = self.save_xattrs(&bytes);
+    if result.is_err() {
+      *self.xattrs.write() = None;
+    }
+    result
+  }
+
+  fn get_entry_location(&self, handle: LocalHandle) -> Result<(usize, usize), SystemError> {
+    let files = self.open_files.read();
+    let file = files.get(&handle).ok_or(SystemError::BadFileDescriptor)?;
+    if file.stale {
+      return Err(SystemError::MediaChanged);
+    }
+    // The root directory has no entry to attach attributes to
+    file.entry_location.ok_or(SystemError::UnsupportedCommand)
+  }
+
+  /// Pack the location of a directory entry into a single number, which the
+  /// VFS can cache in place of the path
+  fn location_to_entry_id(&self, location: (usize, usize)) -> usize {
+    let entries_per_sector = self.get_config().get_bytes_per_sector() / DIRECTORY_ENTRY_SIZE;
+    location.0 * entries_per_sector + location.1
+  }
+
+  fn entry_id_to_location(&self, id: usize) -> (usize, usize) {
+    let entries_per_sector = self.get_config().get_bytes_per_sector() / DIRECTORY_ENTRY_SIZE;
+    (id / entries_per_sector, id % entries_per_sector)
+  }
+
+  /// Point open handles at the new location of a directory entry that was
+  /// moved by a rename, so that their size and timestamps are written back to
+  /// the right place
+  fn relocate_open_entries(&self, from: (usize, usize), to: (usize, usize)) {
+    for file in self.open_files.write().values_mut() {
+      if file.entry_location == Some(from) {
+        file.entry_location = Some(to);
+      }
+    }
+    let mut pending_writes = self.pending_writes.write();
+    if let Some(pending) = pending_writes.remove(&from) {
+      pending_writes.insert(to, pending);
+    }
+  }
+
+  /// Open a handle to a directory entry. If the file is already open, its
+  /// directory entry may not have been written back yet, so the new handle
+  /// starts from the state the other handles share instead.
+  fn open_directory_entry(&self, entry: &DirectoryEntry, location: (usize, usize), flags: OpenFlags) -> Result<LocalHandle, FatError> {
+    self.flush_file(location)?;
+    let shared = self.open_files.read().values()
+      .find(|file| file.entry_location == Some(location) && !file.stale)
+      .map(|file| (ClusterChain::from_vec(file.clusters.clusters.to_vec()), file.byte_size));
+    let (cluster_chain, byte_size) = match shared {
+      Some(shared) => shared,
+      None => {
+        let chain = self.get_cluster_chain(entry.get_first_cluster()).map_err(|_| FatError::IOError)?;
+        (chain, entry.get_byte_size())
+      },
+    };
     let open_file = OpenFile {
       cursor: 0,
-      file_type: FileType::File,
+      file_type: entry.get_file_type(),
       clusters: cluster_chain,
+      entry_location: Some(location),
+      byte_size,
+      modified: false,
+      accessed: false,
+      stale: false,
+      flags,
     };
     let handle = self.handle_allocator.get_next();
     self.open_files.write().insert(handle, open_file);
-
-    crate::kprintln!("FOUND FILE {:?}.{:?}", name, ext);
     Ok(handle)
   }
 
+  /// Apply a change to the in-memory FAT and write the modified sectors out
+  fn modify_fat_table<F: FnOnce(&mut FatTable) -> Result<(), FatError>>(&self, f: F) -> Result<(), FatError> {
+    self.ensure_fat_table_loaded()?;
+    let mut table_lock = self.fat_table.lock();
+    let table = table_lock.as_mut().ok_or(FatError::InvalidFatTable)?;
+    f(table)?;
+    self.flush_fat_table(table)
+  }
+
+  /// Copy the contents of one data cluster to another, a sector at a time
+  fn copy_cluster(&self, from: Cluster, to: Cluster) -> Result<(), FatError> {
+    let config = self.get_config();
+    let source = ClusterChain::from_vec(alloc::vec![from]);
+    let dest = ClusterChain::from_vec(alloc::vec![to]);
+    let mut buffer = self.io_buffer.lock();
+    for (read_from, write_to) in source.sector_iter(&config).zip(dest.sector_iter(&config)) {
+      self.read_sector(read_from, buffer.as_mut_slice())?;
+      self.write_sector(write_to, buffer.as_slice())?;
+    }
+    Ok(())
+  }
+
+  /// Defragmenting moves a file's clusters out from under every handle that
+  /// has it open, so it is only allowed through a handle open for writing,
+  /// while no other handle has the same file open or can write to the drive
+  fn check_exclusive_handle(&self, handle: LocalHandle) -> Result<(), SystemError> {
+    let files = self.open_files.read();
+    let file = files.get(&handle).ok_or(SystemError::BadFileDescriptor)?;
+    if file.stale {
+      return Err(SystemError::MediaChanged);
+    }
+    if file.file_type.is_directory() || !file.flags.can_write() {
+      return Err(SystemError::BadFileDescriptor);
+    }
+    let busy = files.iter().any(|(other_handle, other)| {
+      *other_handle != handle && (other.flags.can_write() || other.entry_location == file.entry_location)
+    });
+    if busy {
+      return Err(SystemError::Busy);
+    }
+    Ok(())
+  }
+
+  /// Copy the clusters of an open file into the buffer a ClusterMap points to
+  fn get_cluster_map(&self, handle: LocalHandle, arg: u32) -> Result<u32, SystemError> {
+    let map = Caller::new(true).read_value::<ClusterMap>(arg)?;
+    let buffer = unsafe { Caller::new(true).slice_mut::<u32>(map.addr as u32, map.count)? };
+    self.flush_pending(handle).map_err(|e| e.to_system_error())?;
+    let files = self.open_files.read();
+    let file = files.get(&handle).ok_or(SystemError::BadFileDescriptor)?;
+    if file.stale {
+      return Err(SystemError::MediaChanged);
+    }
+    let clusters = &file.clusters.clusters;
+    for (dest, cluster) in buffer.iter_mut().zip(clusters.iter()) {
+      *dest = cluster.as_usize() as u32;
+    }
+    Ok(clusters.len() as u32)
+  }
+
+  fn is_cluster_free(&self, cluster: Cluster) -> Result<bool, FatError> {
+    self.ensure_fat_table_loaded()?;
+    let table_lock = self.fat_table.lock();
+    let table = table_lock.as_ref().ok_or(FatError::InvalidFatTable)?;
+    Ok(table.is_free(cluster))
+  }
+
+  /// Move one cluster of an open file to a free cluster elsewhere on the disk.
+  /// FAT has no journal, so the steps are ordered such that an interruption at
+  /// any point leaves the file intact, at worst alongside a lost cluster that
+  /// a disk check can reclaim: the new cluster is claimed and filled first,
+  /// then the file is pointed at it, and only then is the old one freed.
+  fn move_cluster(&self, handle: LocalHandle, index: usize, target: Cluster) -> Result<(), SystemError> {
+    self.check_exclusive_handle(handle)?;
+    self.flush_pending(handle).map_err(|e| e.to_system_error())?;
+    let (clusters, location) = {
+      let files = self.open_files.read();
+      let file = files.get(&handle).ok_or(SystemError::BadFileDescriptor)?;
+      (file.clusters.clusters.to_vec(), file.entry_location)
+    };
+    let source = *clusters.get(index).ok_or(SystemError::InvalidArgument)?;
+    if source == target {
+      return Ok(());
+    }
+    let mut claimed = false;
+    self.modify_fat_table(|table| {
+      if !table.is_free(target) {
+        return Ok(());
+      }
+      let next = table.get(source);
+      table.set(target, next);
+      claimed = true;
+      Ok(())
+    }).map_err(|e| e.to_system_error())?;
+    if !claimed {
+      return Err(SystemError::InvalidArgument);
+    }
+    let relinked = self.copy_cluster(source, target).and_then(|_| {
+      match (index, location) {
+        (0, Some(location)) => self.update_entry(location, |entry| entry.set_first_cluster(target)),
+        (0, None) => Err(FatError::NotFound),
+        _ => self.modify_fat_table(|table| {
+          table.set(clusters[index - 1], FatEntry::NextCluster(target));
+          Ok(())
+        }),
+      }
+    });
+    if let Err(e) = relinked {
+      let _ = self.modify_fat_table(|table| {
+        table.set(target, FatEntry::Free);
+        Ok(())
+      });
+      return Err(e.to_system_error());
+    }
+    if let Some(file) = self.open_files.write().get_mut(&handle) {
+      let mut moved = clusters;
+      moved[index] = target;
+      file.clusters = ClusterChain::from_vec(moved);
+    }
+    self.modify_fat_table(|table| {
+      table.set(source, FatEntry::Free);
+      Ok(())
+    }).map_err(|e| e.to_system_error())
+  }
+
+  /// Write a buffer directly to the disk, allocating any clusters it needs
+  fn write_through(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, SystemError> {
+    let (cursor, byte_size, clusters) = {
+      let files = self.open_files.read();
+      let file = files.get(&handle).ok_or(SystemError::BadFileDescriptor)?;
+      if file.stale {
+        return Err(SystemError::MediaChanged);
+      }
+      if file.file_type.is_directory() || !file.flags.can_write() {
+        return Err(SystemError::BadFileDescriptor);
+      }
+      (file.cursor, file.byte_size, ClusterChain::from_vec(file.clusters.clusters.to_vec()))
+    };
+    if buffer.len() == 0 {
+      return Ok(0);
+    }
+    let end = cursor + buffer.len();
+    let tail = clusters.clusters.last().copied();
+    let (extended, added) = self.ensure_capacity(&clusters, end).map_err(|e| e.to_system_error())?;
+
+    let mut result = Ok(());
+    if cursor > byte_size {
+      // Seeking past the end of a file leaves a gap that must read as zeroes
+      result = self.write_range(&extended, byte_size, cursor - byte_size, None);
+    }
+    if result.is_ok() {
+      result = self.write_range(&extended, cursor, buffer.len(), Some(buffer));
+    }
+    if let Err(e) = result {
+      if added.len() > 0 {
+        let _ = self.release_clusters(&added, tail);
+      }
+      return Err(e.to_system_error());
+    }
+
+    self.file_changed(handle, extended, byte_size.max(end), end).map_err(|e| e.to_system_error())?;
+    Ok(buffer.len())
+  }
+}
+
+impl FileSystem for Fat12FileSystem {
+  fn get_kind(&self) -> FileSystemKind {
+    FileSystemKind::KernelAsync
+  }
+
+  /// Extended attributes are kept in a hidden sidecar file, and names are
+  /// matched without regard to case
+  fn get_capabilities(&self) -> Capabilities {
+    Capabilities {
+      writable: true,
+      seekable: true,
+      directories: true,
+      timestamps: true,
+      xattrs: true,
+      case_sensitive: false,
+    }
+  }
+
+  fn open(&self, path: &str, flags: OpenFlags) -> Result<LocalHandle, ()> {
+    let (search_dir, search) = self.resolve_path(path).map_err(|_| ())?;
+    let found = self.find_named_entry(&search_dir, &search).map_err(|_| ())?;
+    self.open_directory_entry(&found.entry, found.location, flags).map_err(|_| ())
+  }
+
+  /// Lookups are cached by path, but a file with a long name can also be
+  /// reached, and removed, through its short alias. The cache holds the
+  /// file's identifier rather than its slot, so that once the file is gone a
+  /// new file in the same slot is not opened in its place.
+  fn lookup(&self, path: &str) -> Result<usize, ()> {
+    let (search_dir, search) = self.resolve_path(path).map_err(|_| ())?;
+    let found = self.find_named_entry(&search_dir, &search).map_err(|_| ())?;
+    let id = self.file_ids.read().get_id(self.location_to_entry_id(found.location)).ok_or(())?;
+    Ok(id as usize)
+  }
+
+  fn open_entry(&self, id: usize, flags: OpenFlags) -> Result<LocalHandle, ()> {
+    self.open_by_id(id as u32, flags)
+  }
+
   fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
-    Err(())
+    self.flush_pending(handle).map_err(|_| ())?;
+    let (cursor, byte_size, clusters) = {
+      let files = self.open_files.read();
+      let file = files.get(&handle).ok_or(())?;
+      if file.stale || !file.flags.can_read() {
+        return Err(());
+      }
+      (file.cursor, file.byte_size, ClusterChain::from_vec(file.clusters.clusters.to_vec()))
+    };
+    if cursor >= byte_size {
+      return Ok(0);
+    }
+    let to_read = buffer.len().min(byte_size - cursor);
+    let bytes_per_sector = self.get_config().get_bytes_per_sector();
+    let mut read = 0;
+    while read < to_read {
+      let position = cursor + read;
+      let sector = self.get_sector_for_offset(&clusters, position).ok_or(())?;
+      let local_offset = position % bytes_per_sector;
+      let chunk = (bytes_per_sector - local_offset).min(to_read - read);
+      let mut io_buffer = self.io_buffer.lock();
+      self.read_sector(sector, io_buffer.as_mut_slice()).map_err(|_| ())?;
+      buffer[read..(read + chunk)].copy_from_slice(&io_buffer[local_offset..(local_offset + chunk)]);
+      read += chunk;
+    }
+    if let Some(file) = self.open_files.write().get_mut(&handle) {
+      file.cursor += read;
+      file.accessed = true;
+    }
+    Ok(read)
   }
 
-  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
-    Err(())
+  /// Small sequential writes are collected in memory, and only reach the disk
+  /// when the run grows too large, the handle moves elsewhere, or the file is
+  /// read, flushed, closed, or used through any other handle. Handles opened
+  /// with OPEN_SYNC, and drives mounted for sync writes, always write straight
+  /// through.
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, SystemError> {
+    if self.try_buffer_write(handle, buffer)? {
+      return Ok(buffer.len());
+    }
+    self.flush_pending(handle).map_err(|e| e.to_system_error())?;
+    if self.try_buffer_write(handle, buffer)? {
+      return Ok(buffer.len());
+    }
+    self.write_through(handle, buffer)
   }
 
   fn close(&self, handle: LocalHandle) -> Result<(), ()> {
-    Err(())
+    let stale = self.open_files.read().get(&handle).ok_or(())?.stale;
+    if stale {
+      // Nothing can be written back to a disk that has been removed
+      self.open_files.write().remove(&handle);
+      return Ok(());
+    }
+    let flushed = self.flush_entry(handle).and_then(|_| self.flush_cache());
+    self.open_files.write().remove(&handle).ok_or(())?;
+    flushed.map_err(|_| ())
   }
 
   fn dup(&self, handle: LocalHandle) -> Result<LocalHandle, ()> {
@@ -237,63 +1443,598 @@ impl FileSystem for Fat12FileSystem {
   }
 
   fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
-    Err(())
+    let mut files = self.open_files.write();
+    let file = files.get_mut(&handle).ok_or(())?;
+    file.cursor = offset.from_current_position(file.cursor);
+    Ok(file.cursor)
+  }
+
+  /// The FAT_* ioctls give a DEFRAG program the primitives it needs to
+  /// rearrange a drive without writing to the disk directly
+  fn ioctl(&self, handle: LocalHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    match command {
+      FAT_GET_CLUSTERS => self.get_cluster_map(handle, arg).map_err(|_| ()),
+      FAT_IS_CLUSTER_FREE => {
+        let free = self.is_cluster_free(Cluster::new(arg as usize)).map_err(|_| ())?;
+        Ok(if free { 1 } else { 0 })
+      },
+      FAT_MOVE_CLUSTER => {
+        let request = Caller::new(true).read_value::<ClusterMove>(arg).map_err(|_| ())?;
+        self.move_cluster(handle, request.index as usize, Cluster::new(request.target as usize))
+          .map(|_| 0)
+          .map_err(|_| ())
+      },
+      FAT_UPDATE_ENTRY => {
+        self.check_exclusive_handle(handle).map_err(|_| ())?;
+        self.flush_entry(handle).map(|_| 0).map_err(|_| ())
+      },
+      _ => Err(()),
+    }
+  }
+
+  fn create(&self, path: &str) -> Result<LocalHandle, SystemError> {
+    let (search_dir, search) = self.resolve_path(path).map_err(|_| SystemError::NoSuchEntity)?;
+    if search.name[0] == 0x20 {
+      return Err(SystemError::NoSuchEntity);
+    }
+    if let Ok(found) = self.find_named_entry(&search_dir, &search) {
+      if !found.entry.get_file_type().is_file() {
+        return Err(SystemError::NotDirectory);
+      }
+      let handle = self.open_directory_entry(&found.entry, found.location, OpenFlags::read_write()).map_err(|e| e.to_system_error())?;
+      self.truncate(handle, 0)?;
+      return Ok(handle);
+    }
+
+    // Archive bit is set on all newly created files
+    let (entry, location) = self.add_named_entry(&search_dir, &search, 0x20, None)?;
+    self.open_directory_entry(&entry, location, OpenFlags::read_write()).map_err(|e| e.to_system_error())
+  }
+
+  fn truncate(&self, handle: LocalHandle, length: usize) -> Result<(), SystemError> {
+    self.flush_pending(handle).map_err(|e| e.to_system_error())?;
+    let (cursor, byte_size, clusters) = {
+      let files = self.open_files.read();
+      let file = files.get(&handle).ok_or(SystemError::BadFileDescriptor)?;
+      if file.stale {
+        return Err(SystemError::MediaChanged);
+      }
+      (file.cursor, file.byte_size, ClusterChain::from_vec(file.clusters.clusters.to_vec()))
+    };
+    let new_chain = if length < byte_size {
+      let cluster_size = self.get_cluster_size();
+      let keep = (length + cluster_size - 1) / cluster_size;
+      self.shrink_chain(&clusters, keep).map_err(|e| e.to_system_error())?
+    } else if length > byte_size {
+      let tail = clusters.clusters.last().copied();
+      let (extended, added) = self.ensure_capacity(&clusters, length).map_err(|e| e.to_system_error())?;
+      if let Err(e) = self.write_range(&extended, byte_size, length - byte_size, None) {
+        if added.len() > 0 {
+          let _ = self.release_clusters(&added, tail);
+        }
+        return Err(e.to_system_error());
+      }
+      extended
+    } else {
+      return Ok(());
+    };
+    self.file_changed(handle, new_chain, length, cursor).map_err(|e| e.to_system_error())
+  }
+
+  fn preallocate(&self, handle: LocalHandle, length: usize) -> Result<(), SystemError> {
+    self.flush_pending(handle).map_err(|e| e.to_system_error())?;
+    let (cursor, byte_size, clusters) = {
+      let files = self.open_files.read();
+      let file = files.get(&handle).ok_or(SystemError::BadFileDescriptor)?;
+      if file.stale {
+        return Err(SystemError::MediaChanged);
+      }
+      if file.file_type.is_directory() || !file.flags.can_write() {
+        return Err(SystemError::BadFileDescriptor);
+      }
+      (file.cursor, file.byte_size, ClusterChain::from_vec(file.clusters.clusters.to_vec()))
+    };
+    if length <= byte_size {
+      return Ok(());
+    }
+    let cluster_size = self.get_cluster_size();
+    let needed = (length + cluster_size - 1) / cluster_size;
+    let current = clusters.clusters.len();
+    let tail = clusters.clusters.last().copied();
+    let added = if needed > current {
+      self.allocate_contiguous_clusters(needed - current, tail).map_err(|e| e.to_system_error())?
+    } else {
+      Vec::new()
+    };
+    let mut extended = clusters.clusters.to_vec();
+    extended.extend_from_slice(&added);
+    let extended = ClusterChain::from_vec(extended);
+    // FAT cannot mark clusters as allocated but unwritten, so the new space is
+    // zeroed like any other extension
+    if let Err(e) = self.write_range(&extended, byte_size, length - byte_size, None) {
+      if added.len() > 0 {
+        let _ = self.release_clusters(&added, tail);
+      }
+      return Err(e.to_system_error());
+    }
+    self.file_changed(handle, extended, length, cursor).map_err(|e| e.to_system_error())
+  }
+
+  /// Directory entries store local time, so the timestamp is converted
+  /// using the current time zone
+  fn set_modified_time(&self, path: &str, time: Timestamp) -> Result<(), SystemError> {
+    let (search_dir, search) = self.resolve_path(path).map_err(|_| SystemError::NoSuchEntity)?;
+    let found = self.find_named_entry(&search_dir, &search)
+      .map_err(|_| SystemError::NoSuchEntity)?;
+    // Handles with unwritten changes would stamp the current time over the
+    // new one when they are flushed, so their changes go to disk first
+    let open_handles: Vec<LocalHandle> = self.open_files.read().iter()
+      .filter(|(_, file)| file.entry_location == Some(found.location))
+      .map(|(handle, _)| *handle)
+      .collect();
+    for handle in open_handles {
+      self.flush_entry(handle).map_err(|e| e.to_system_error())?;
+    }
+    let local = crate::time::system::to_local_datetime(time);
+    let (date, time) = (FileDate::from_date(&local.date), FileTime::from_time(&local.time));
+    self.update_entry(found.location, |entry| entry.set_modify_time(date, time))
+      .map_err(|e| e.to_system_error())
+  }
+
+  fn delete(&self, path: &str) -> Result<(), SystemError> {
+    let (search_dir, search) = self.resolve_path(path).map_err(|_| SystemError::NoSuchEntity)?;
+    let found = self.find_named_entry(&search_dir, &search)
+      .map_err(|_| SystemError::NoSuchEntity)?;
+    if !found.entry.get_file_type().is_file() {
+      return Err(SystemError::UnsupportedCommand);
+    }
+    self.remove_entry(&found)
+  }
+
+  fn mkdir(&self, path: &str) -> Result<(), SystemError> {
+    let (search_dir, search) = self.resolve_path(path).map_err(|_| SystemError::NoSuchEntity)?;
+    if search.name[0] == 0x20 {
+      return Err(SystemError::InvalidArgument);
+    }
+    if self.find_named_entry(&search_dir, &search).is_ok() {
+      return Err(SystemError::AlreadyExists);
+    }
+    let allocated = self.allocate_clusters(1, None).map_err(|e| e.to_system_error())?;
+    let cluster = allocated[0];
+    let parent = search_dir.get_first_cluster();
+    if let Err(e) = self.write_dot_entries(cluster, parent) {
+      let _ = self.release_clusters(&allocated, None);
+      return Err(e.to_system_error());
+    }
+    if let Err(e) = self.add_named_entry(&search_dir, &search, 0x10, Some(cluster)) {
+      let _ = self.release_clusters(&allocated, None);
+      return Err(e);
+    }
+    Ok(())
+  }
+
+  fn rmdir(&self, path: &str) -> Result<(), SystemError> {
+    let (search_dir, search) = self.resolve_path(path).map_err(|_| SystemError::NoSuchEntity)?;
+    let found = self.find_named_entry(&search_dir, &search)
+      .map_err(|_| SystemError::NoSuchEntity)?;
+    if !found.entry.get_file_type().is_directory() {
+      return Err(SystemError::NotDirectory);
+    }
+    let first_cluster = found.entry.get_first_cluster();
+    if first_cluster.as_usize() >= 2 {
+      let dir = Directory {
+        clusters: self.get_cluster_chain(first_cluster).map_err(|_| SystemError::IOError)?,
+      };
+      let occupied = self.walk_directory(&dir, |entry, _| !is_dot_entry(entry))
+        .map_err(|e| e.to_system_error())?;
+      if occupied.is_some() {
+        return Err(SystemError::NotEmpty);
+      }
+    }
+    self.remove_entry(&found)
+  }
+
+  fn rename(&self, old_path: &str, new_path: &str) -> Result<(), SystemError> {
+    let (old_dir, old_search) = self.resolve_path(old_path).map_err(|_| SystemError::NoSuchEntity)?;
+    let (new_dir, new_search) = self.resolve_path(new_path).map_err(|_| SystemError::NoSuchEntity)?;
+    if new_search.name[0] == 0x20 {
+      return Err(SystemError::InvalidArgument);
+    }
+    let found = self.find_named_entry(&old_dir, &old_search).map_err(|e| e.to_system_error())?;
+    if let FileType::VolumeLabel = found.entry.get_file_type() {
+      return Err(SystemError::NoSuchEntity);
+    }
+    match self.find_named_entry(&new_dir, &new_search) {
+      // Renaming an entry onto itself only changes its capitalization
+      Ok(existing) => if existing.location != found.location {
+        return Err(SystemError::AlreadyExists);
+      },
+      Err(FatError::NotFound) => (),
+      Err(e) => return Err(e.to_system_error()),
+    }
+
+    let same_directory = old_dir.get_first_cluster() == new_dir.get_first_cluster();
+    let moved_directory = found.entry.get_file_type().is_directory() && !same_directory;
+    if moved_directory {
+      // A directory cannot be moved inside itself
+      let moved_cluster = found.entry.get_first_cluster();
+      if self.is_within_directory(&new_dir, moved_cluster).map_err(|e| e.to_system_error())? {
+        return Err(SystemError::InvalidArgument);
+      }
+    }
+
+    if !new_search.is_long && same_directory {
+      // The new name fits in the 8.3 entry, which is rewritten in place with a
+      // single sector write. Any old long name is removed first; if that is
+      // interrupted, the file is still found by its old short name.
+      for slot in found.long_name_slots.iter() {
+        self.update_entry(*slot, |entry| entry.mark_deleted())
+          .map_err(|e| e.to_system_error())?;
+      }
+      return self.update_entry(found.location, |entry| entry.set_name(new_search.name, new_search.ext))
+        .map_err(|e| e.to_system_error());
+    }
+
+    // A long name, or a move to another directory, needs new entries, which
+    // are written before the old ones are removed. An interruption leaves the
+    // file reachable under both names, rather than under neither.
+    let (name, ext) = if new_search.is_long {
+      self.generate_short_alias(&new_dir, new_search.full_name)
+        .map_err(|e| e.to_system_error())?
+    } else {
+      (new_search.name, new_search.ext)
+    };
+    let mut entry = found.entry;
+    entry.set_name(name, ext);
+    let mut short_name = [0; 11];
+    entry.get_full_name(&mut short_name);
+    let mut raw_entries = if new_search.is_long {
+      lfn::create_entries(new_search.full_name, &short_name)
+        .map_err(|_| SystemError::InvalidArgument)?
+    } else {
+      Vec::new()
+    };
+    let mut raw = [0; DIRECTORY_ENTRY_SIZE];
+    raw.copy_from_slice(entry.as_bytes());
+    raw_entries.push(raw);
+    let location = self.add_directory_entries(&new_dir, &raw_entries).map_err(|e| e.to_system_error())?;
+    for slot in found.long_name_slots.iter() {
+      self.update_entry(*slot, |entry| entry.mark_deleted())
+        .map_err(|e| e.to_system_error())?;
+    }
+    self.update_entry(found.location, |entry| entry.mark_deleted())
+      .map_err(|e| e.to_system_error())?;
+    self.relocate_open_entries(found.location, location);
+    self.file_ids.write().relocate(self.location_to_entry_id(found.location), self.location_to_entry_id(location));
+    if moved_directory {
+      let dot_dot = self.dot_dot_location(found.entry.get_first_cluster()).map_err(|e| e.to_system_error())?;
+      let parent = new_dir.get_first_cluster();
+      self.update_entry(dot_dot, |entry| entry.set_first_cluster(parent))
+        .map_err(|e| e.to_system_error())?;
+    }
+    Ok(())
+  }
+
+  fn replace(&self, source_path: &str, target_path: &str) -> Result<(), SystemError> {
+    let (source_dir, source_search) = self.resolve_path(source_path).map_err(|_| SystemError::NoSuchEntity)?;
+    let (target_dir, target_search) = self.resolve_path(target_path).map_err(|_| SystemError::NoSuchEntity)?;
+    let source = self.find_named_entry(&source_dir, &source_search).map_err(|e| e.to_system_error())?;
+    let target = match self.find_named_entry(&target_dir, &target_search) {
+      Ok(target) => target,
+      Err(FatError::NotFound) => return self.rename(source_path, target_path),
+      Err(e) => return Err(e.to_system_error()),
+    };
+    if !source.entry.get_file_type().is_file() || !target.entry.get_file_type().is_file() {
+      return Err(SystemError::UnsupportedCommand);
+    }
+    if source.location == target.location {
+      return Err(SystemError::InvalidArgument);
+    }
+    let in_use = self.open_files.read().values().any(|file| {
+      file.entry_location == Some(source.location) || file.entry_location == Some(target.location)
+    });
+    if in_use {
+      return Err(SystemError::Busy);
+    }
+
+    // The source entry is removed first, leaving its clusters unreferenced.
+    // The target entry is then pointed at those clusters with a single sector
+    // write. An interruption at any point leaves the target with either its
+    // old or its new contents, and at worst some lost clusters. The target
+    // keeps its name, attributes, and extended attributes. The sector cache
+    // holds writes back, so it is flushed after each step to keep them in
+    // order on the disk.
+    for slot in source.long_name_slots.iter() {
+      self.update_entry(*slot, |entry| entry.mark_deleted())
+        .map_err(|e| e.to_system_error())?;
+    }
+    self.update_entry(source.location, |entry| entry.mark_deleted())
+      .map_err(|e| e.to_system_error())?;
+    self.file_ids.write().vacate(self.location_to_entry_id(source.location));
+    self.flush_cache().map_err(|e| e.to_system_error())?;
+    let new_cluster = source.entry.get_first_cluster();
+    let new_size = source.entry.get_byte_size();
+    let (date, time) = Self::get_current_file_date_time();
+    self.update_entry(target.location, |entry| {
+      entry.set_first_cluster(new_cluster);
+      entry.set_byte_size(new_size);
+      entry.set_modify_time(date, time);
+      entry.set_access_date(date);
+    }).map_err(|e| e.to_system_error())?;
+    self.flush_cache().map_err(|e| e.to_system_error())?;
+
+    let old_cluster = target.entry.get_first_cluster();
+    if old_cluster.as_usize() >= 2 {
+      self.ensure_fat_table_loaded().map_err(|e| e.to_system_error())?;
+      let mut table_lock = self.fat_table.lock();
+      let table = table_lock.as_mut().ok_or(SystemError::IOError)?;
+      table.free_chain(old_cluster);
+      self.flush_fat_table(table).map_err(|e| e.to_system_error())?;
+    }
+    let ea_handle = source.entry.get_ea_handle();
+    if ea_handle != 0 {
+      self.modify_xattrs(|store| {
+        store.remove_all(ea_handle);
+        Ok(())
+      })?;
+    }
+    self.flush_cache().map_err(|e| e.to_system_error())
+  }
+
+  fn get_xattr(&self, handle: LocalHandle, name: &str, buffer: &mut [u8]) -> Result<usize, SystemError> {
+    let location = self.get_entry_location(handle)?;
+    let entry = self.read_entry(location).map_err(|e| e.to_system_error())?;
+    let ea_handle = entry.get_ea_handle();
+    if ea_handle == 0 {
+      return Err(SystemError::NoSuchEntity);
+    }
+    self.ensure_xattrs_loaded()?;
+    let lock = self.xattrs.read();
+    let store = lock.as_ref().ok_or(SystemError::IOError)?;
+    let value = store.get(ea_handle, name.as_bytes()).ok_or(SystemError::NoSuchEntity)?;
+    let to_copy = value.len().min(buffer.len());
+    buffer[..to_copy].copy_from_slice(&value[..to_copy]);
+    Ok(value.len())
+  }
+
+  fn set_xattr(&self, handle: LocalHandle, name: &str, value: &[u8]) -> Result<(), SystemError> {
+    let location = self.get_entry_location(handle)?;
+    let entry = self.read_entry(location).map_err(|e| e.to_system_error())?;
+    let existing_handle = entry.get_ea_handle();
+    if existing_handle == 0 && value.len() == 0 {
+      // Removing an attribute from a file that has none
+      return Ok(());
+    }
+    let mut ea_handle = existing_handle;
+    self.modify_xattrs(|store| {
+      if ea_handle == 0 {
+        ea_handle = store.allocate_handle()?;
+      }
+      store.set(ea_handle, name.as_bytes(), value)
+    })?;
+    if existing_handle != ea_handle {
+      self.update_entry(location, |entry| entry.set_ea_handle(ea_handle))
+        .map_err(|e| e.to_system_error())?;
+    }
+    Ok(())
+  }
+
+  /// Files are identified by the slot of their directory entry. The root
+  /// directory has no entry, and so no identifier.
+  fn stat(&self, handle: LocalHandle, status: &mut FileStatus) -> Result<(), ()> {
+    self.flush_pending(handle).map_err(|_| ())?;
+    let files = self.open_files.read();
+    let file = files.get(&handle).ok_or(())?;
+    if file.stale {
+      return Err(());
+    }
+    status.file_id = match file.entry_location {
+      Some(location) => self.file_ids.read().get_id(self.location_to_entry_id(location)).unwrap_or(0),
+      None => 0,
+    };
+    status.entry_type = if file.file_type.is_directory() {
+      DirEntryType::Directory
+    } else {
+      DirEntryType::File
+    };
+    status.byte_size = file.byte_size;
+    Ok(())
+  }
+
+  /// The identifier leads straight to the file's directory entry, wherever it
+  /// is on the disk, without walking any directories
+  fn open_by_id(&self, id: u32, flags: OpenFlags) -> Result<LocalHandle, ()> {
+    let entry_id = self.file_ids.read().resolve(id).ok_or(())?;
+    let location = self.entry_id_to_location(entry_id);
+    let entry = self.read_entry(location).map_err(|_| ())?;
+    if entry.is_free() || lfn::is_long_name_entry(entry.as_bytes()) {
+      return Err(());
+    }
+    if let FileType::VolumeLabel = entry.get_file_type() {
+      return Err(());
+    }
+    self.open_directory_entry(&entry, location, flags).map_err(|_| ())
   }
 
   fn open_dir(&self, path: &str) -> Result<LocalHandle, ()> {
-    let handle = self.handle_allocator.get_next();
+    self.ensure_current_media().map_err(|_| ())?;
+    if path.split("\\").any(|part| part.len() > 0) {
+      let (search_dir, search) = self.resolve_path(path).map_err(|_| ())?;
+      let found = self.find_named_entry(&search_dir, &search).map_err(|_| ())?;
+      if !found.entry.get_file_type().is_directory() {
+        return Err(());
+      }
+      return self.open_directory_entry(&found.entry, found.location, OpenFlags::read_only()).map_err(|_| ());
+    }
 
+    let handle = self.handle_allocator.get_next();
     let dir = Directory::empty(); // Root directory
     let open_file = OpenFile {
       cursor: 0,
       file_type: FileType::Directory,
       clusters: dir.clusters,
+      entry_location: None,
+      byte_size: 0,
+      modified: false,
+      accessed: false,
+      stale: false,
+      flags: OpenFlags::read_only(),
     };
     self.open_files.write().insert(handle, open_file);
     Ok(handle)
   }
 
+  fn apply_mount_options(&self, options: &MountOptions) -> Result<(), ()> {
+    *self.options.write() = *options;
+    self.cache.lock().set_write_through(options.sync);
+    Ok(())
+  }
+
+  fn check_handle(&self, handle: LocalHandle) -> Result<(), SystemError> {
+    let files = self.open_files.read();
+    let file = files.get(&handle).ok_or(SystemError::BadFileDescriptor)?;
+    if file.stale {
+      Err(SystemError::MediaChanged)
+    } else {
+      Ok(())
+    }
+  }
+
+  fn check_media(&self) -> Result<(), SystemError> {
+    self.ensure_current_media().map_err(|e| e.to_system_error())
+  }
+
+  /// Everything held in memory describes the old disk. Pending writes are
+  /// discarded rather than flushed, since they would corrupt the new disk.
+  fn media_changed(&self) {
+    *self.needs_remount.write() = true;
+    self.cache.lock().invalidate_drive(self.drive_number);
+    *self.fat_table.lock() = None;
+    *self.xattrs.write() = None;
+    *self.file_ids.write() = FileIdTable::new();
+    for (_, file) in self.open_files.write().iter_mut() {
+      file.stale = true;
+    }
+    self.pending_writes.write().clear();
+  }
+
+  fn remount(&self) -> Result<(), ()> {
+    self.read_boot_sector()?;
+    *self.needs_remount.write() = false;
+    Ok(())
+  }
+
+  /// Write back cached sectors and release the device. Pending writes for a
+  /// disk that was already swapped out are dropped, as in media_changed.
+  fn unmount(&self) -> Result<(), SystemError> {
+    if !self.open_files.read().is_empty() {
+      return Err(SystemError::Busy);
+    }
+    if !*self.needs_remount.read() {
+      self.flush_cache().map_err(|e| e.to_system_error())?;
+    }
+    super::super::release_device(self.drive_access_handle);
+    Ok(())
+  }
+
+  /// A disk that was swapped out has nothing worth writing back. Otherwise,
+  /// every open file's pending writes and directory entry go out first.
+  fn sync(&self) -> Result<(), SystemError> {
+    if *self.needs_remount.read() {
+      return Ok(());
+    }
+    let handles: Vec<LocalHandle> = self.open_files.read().keys().copied().collect();
+    for handle in handles {
+      self.flush_entry(handle).map_err(|e| e.to_system_error())?;
+    }
+    self.flush_cache().map_err(|e| e.to_system_error())
+  }
+
   fn read_dir(&self, handle: LocalHandle, index: usize, info: &mut DirEntryInfo) -> Result<(), ()> {
-    let (sector, local_index) = {
+    let filled = self.read_dir_entries(handle, index, core::slice::from_mut(info))?;
+    if filled == 0 {
+      *info = DirEntryInfo::empty();
+    }
+    Ok(())
+  }
+
+  /// Fill every requested entry in a single walk of the directory, rather
+  /// than walking it again for each index
+  fn read_dir_entries(&self, handle: LocalHandle, index: usize, entries: &mut [DirEntryInfo]) -> Result<usize, ()> {
+    let dir = {
       let files = self.open_files.read();
       let file = files.get(&handle).ok_or(())?;
-      let (dir_sector, local_index) = self.config.get_directory_index_location(index);
-      let mut iter = file.clusters.sector_iter(&self.config);
-      for _ in 0..dir_sector {
-        iter.next();
+      if file.stale {
+        return Err(());
+      }
+      Directory {
+        clusters: ClusterChain::from_vec(file.clusters.clusters.to_vec()),
       }
-      let sector = iter.next().ok_or(())?;
-
-      (sector, local_index)
     };
 
-    let position = sector * self.config.get_bytes_per_sector() + local_index * DIRECTORY_ENTRY_SIZE;
-
-    let driver = devices::get_driver_for_device(self.drive_number).ok_or(())?;
-    driver.seek(self.drive_access_handle, SeekMethod::Absolute(position))?;
-
-    {
-      let mut buffer = self.io_buffer.write();
-      let total_slice = buffer.as_mut_slice();
-      let subset = &mut total_slice[0..DIRECTORY_ENTRY_SIZE];
-      driver.read(self.drive_access_handle, subset)?;
+    if entries.is_empty() {
+      return Ok(0);
     }
 
-    let buffer_addr = self.get_io_buffer_address();
-    let entry = DirectoryEntry::at_address(buffer_addr);
+    // The index counts visible entries, so long name entries and the volume
+    // label do not take up a slot
+    let mut visible = 0;
+    let mut filled = 0;
+    self.walk_directory(&dir, |entry, long| {
+      if let FileType::VolumeLabel = entry.get_file_type() {
+        return false;
+      }
+      if visible >= index {
+        let info = &mut entries[filled];
+        entry.copy_name(&mut info.file_name);
+        entry.copy_ext(&mut info.file_ext);
+        info.entry_type = if entry.get_file_type().is_directory() {
+          DirEntryType::Directory
+        } else {
+          DirEntryType::File
+        };
+        info.byte_size = entry.get_byte_size();
+        info.set_long_name(long.unwrap_or(""));
+        filled += 1;
+      }
+      visible += 1;
+      filled == entries.len()
+    }).map_err(|_| ())?;
 
-    if entry.is_empty() {
-      info.file_name = [0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20];
-      info.file_ext = [0x20, 0x20, 0x20];
-      info.entry_type = DirEntryType::Empty;
-      info.byte_size = 0;
-    } else {
-      entry.copy_name(&mut info.file_name);
-      entry.copy_ext(&mut info.file_ext);
-      info.entry_type = DirEntryType::File;
-      info.byte_size = entry.get_byte_size();
+    Ok(filled)
+  }
+}
+
+/// Disks registered as block devices are read through their request queue.
+/// Other devices are read as a byte stream through their driver. Sectors are
+/// addressed by byte offset either way, so the device's blocks do not need to
+/// be the same size as the filesystem's sectors.
+impl BlockStore for Fat12FileSystem {
+  fn read_blocks(&self, drive: usize, lba: usize, buffer: &mut [u8]) -> Result<(), ()> {
+    let position = lba * self.get_config().get_bytes_per_sector();
+    if let Some(queue) = blockio::get_queue(drive) {
+      let read = block::read_bytes(&*queue, position, buffer)?;
+      return if read == buffer.len() { Ok(()) } else { Err(()) };
     }
+    let driver = devices::get_driver_for_device(drive).ok_or(())?;
+    driver.seek(self.drive_access_handle, SeekMethod::Absolute(position))?;
+    driver.read(self.drive_access_handle, buffer)?;
+    Ok(())
+  }
 
+  fn write_block(&self, drive: usize, lba: usize, buffer: &[u8]) -> Result<(), ()> {
+    let position = lba * self.get_config().get_bytes_per_sector();
+    if let Some(queue) = blockio::get_queue(drive) {
+      let written = block::write_bytes(&*queue, position, buffer)?;
+      return if written == buffer.len() { Ok(()) } else { Err(()) };
+    }
+    let driver = devices::get_driver_for_device(drive).ok_or(())?;
+    driver.seek(self.drive_access_handle, SeekMethod::Absolute(position))?;
+    driver.write(self.drive_access_handle, buffer)?;
     Ok(())
   }
-}
\ No newline at end of file
+
+  fn block_count(&self, _drive: usize) -> usize {
+    self.get_config().get_total_sectors()
+  }
+}
@@ -0,0 +1,136 @@
+//! FAT has no inode numbers, so a file is best identified by the slot its
+//! directory entry occupies. Slots are not permanent, though: a deleted file's
+//! slot can be reused by a new file, and a rename may move a file's entry to a
+//! different slot or directory.
+//! Identifiers pack the slot number together with a generation count for that
+//! slot, which goes up every time a file leaves it. An identifier from a slot
+//! that has since been vacated no longer matches, and files that moved after
+//! their identifier was handed out are remembered, so the identifier follows
+//! them. The table lives only as long as the mount.
+
+use alloc::collections::BTreeMap;
+
+const SLOT_BITS: u32 = 20;
+const SLOT_MASK: u32 = (1 << SLOT_BITS) - 1;
+const GENERATION_MASK: u32 = (1 << (32 - SLOT_BITS)) - 1;
+
+pub struct FileIdTable {
+  /// Number of times a file has left each slot. Slots that have never been
+  /// vacated are not stored.
+  generations: BTreeMap<usize, u32>,
+  /// Current slot of every file that moved, by identifier
+  moved: BTreeMap<u32, usize>,
+  /// Identifier of the file in each slot that a moved file now occupies
+  moved_into: BTreeMap<usize, u32>,
+}
+
+impl FileIdTable {
+  pub fn new() -> FileIdTable {
+    FileIdTable {
+      generations: BTreeMap::new(),
+      moved: BTreeMap::new(),
+      moved_into: BTreeMap::new(),
+    }
+  }
+
+  fn get_generation(&self, slot: usize) -> u32 {
+    self.generations.get(&slot).copied().unwrap_or(0) & GENERATION_MASK
+  }
+
+  /// Identifier of the file whose entry is in a slot. Slot zero is never a
+  /// directory entry, which keeps zero free to mean "no identifier". Returns
+  /// None if the slot number is too large to pack.
+  pub fn get_id(&self, slot: usize) -> Option<u32> {
+    if let Some(id) = self.moved_into.get(&slot) {
+      return Some(*id);
+    }
+    if slot == 0 || slot > SLOT_MASK as usize {
+      return None;
+    }
+    Some((self.get_generation(slot) << SLOT_BITS) | slot as u32)
+  }
+
+  /// Find the slot currently holding the file with an identifier, if it
+  /// still exists
+  pub fn resolve(&self, id: u32) -> Option<usize> {
+    if let Some(slot) = self.moved.get(&id) {
+      return Some(*slot);
+    }
+    let slot = (id & SLOT_MASK) as usize;
+    if slot == 0 || self.moved_into.contains_key(&slot) {
+      return None;
+    }
+    if self.get_generation(slot) != id >> SLOT_BITS {
+      return None;
+    }
+    Some(slot)
+  }
+
+  /// Record that the file in a slot was removed
+  pub fn vacate(&mut self, slot: usize) {
+    if let Some(id) = self.moved_into.remove(&slot) {
+      self.moved.remove(&id);
+    }
+    let generation = self.generations.entry(slot).or_insert(0);
+    *generation = generation.wrapping_add(1);
+  }
+
+  /// Record that the file in one slot now has its entry in another
+  pub fn relocate(&mut self, from: usize, to: usize) {
+    let id = self.get_id(from);
+    self.vacate(from);
+    if let Some(id) = id {
+      self.moved.insert(id, to);
+      self.moved_into.insert(to, id);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ids_resolve_to_their_slot() {
+    let table = FileIdTable::new();
+    let id = table.get_id(37).unwrap();
+    assert_ne!(id, 0);
+    assert_eq!(table.resolve(id), Some(37));
+    assert_eq!(table.get_id(0), None);
+    assert_eq!(table.resolve(0), None);
+  }
+
+  #[test]
+  fn reused_slots_get_new_ids() {
+    let mut table = FileIdTable::new();
+    let old = table.get_id(37).unwrap();
+    table.vacate(37);
+    assert_eq!(table.resolve(old), None);
+    let new = table.get_id(37).unwrap();
+    assert_ne!(old, new);
+    assert_eq!(table.resolve(new), Some(37));
+  }
+
+  #[test]
+  fn ids_follow_renames() {
+    let mut table = FileIdTable::new();
+    let id = table.get_id(37).unwrap();
+    table.relocate(37, 90);
+    assert_eq!(table.get_id(90), Some(id));
+    assert_eq!(table.resolve(id), Some(90));
+    // A new file in the old slot does not take over the identifier
+    let other = table.get_id(37).unwrap();
+    assert_ne!(other, id);
+    assert_eq!(table.resolve(other), Some(37));
+    // Moving back to the original slot, once it is free, keeps the identifier
+    table.vacate(37);
+    assert_eq!(table.resolve(other), None);
+    table.relocate(90, 37);
+    assert_eq!(table.get_id(37), Some(id));
+    assert_eq!(table.resolve(id), Some(37));
+    // Once the file is deleted, its identifier is gone for good
+    table.vacate(37);
+    assert_eq!(table.resolve(id), None);
+    assert_ne!(table.get_id(37), Some(id));
+  }
+}
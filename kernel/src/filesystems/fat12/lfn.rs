@@ -0,0 +1,328 @@
+//! VFAT long file names are stored in a series of extra directory entries
+//! placed immediately before the 8.3 entry they belong to. Each one holds 13
+//! UCS-2 characters, and is marked with an attribute combination (read-only,
+//! hidden, system, volume label) that older versions of DOS ignore.
+//! The entries are stored in reverse order: the first one on disk holds the
+//! end of the name, and has bit 6 set in its sequence number.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::disk::DIRECTORY_ENTRY_SIZE;
+
+pub const LONG_NAME_ATTRIBUTES: u8 = 0x0f;
+pub const CHARS_PER_ENTRY: usize = 13;
+/// Longest name that can be stored, in UCS-2 characters
+pub const MAX_LONG_NAME_LENGTH: usize = 255;
+/// Most entries a single long name can occupy
+const MAX_ENTRIES: usize = (MAX_LONG_NAME_LENGTH + CHARS_PER_ENTRY - 1) / CHARS_PER_ENTRY;
+
+const LAST_ENTRY_FLAG: u8 = 0x40;
+const SEQUENCE_MASK: u8 = 0x1f;
+/// Byte offsets of the 13 characters within a long name entry
+const CHAR_OFFSETS: [usize; CHARS_PER_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Characters that are allowed in long names, but not in 8.3 names
+const INVALID_SHORT_CHARS: &[u8] = b" +,;=[]";
+
+pub type RawEntry = [u8; DIRECTORY_ENTRY_SIZE];
+
+pub fn is_long_name_entry(raw: &[u8]) -> bool {
+  raw[11] == LONG_NAME_ATTRIBUTES
+}
+
+/// Checksum of the 11-byte short name, stored in each long name entry so that
+/// orphaned entries can be detected
+pub fn checksum(short_name: &[u8; 11]) -> u8 {
+  let mut sum: u8 = 0;
+  for byte in short_name.iter() {
+    sum = ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*byte);
+  }
+  sum
+}
+
+/// Collects long name entries while a directory is being walked, and produces
+/// the full name once the matching 8.3 entry is reached
+pub struct LongNameCollector {
+  chars: Vec<u16>,
+  expected: u8,
+  checksum: u8,
+  valid: bool,
+}
+
+impl LongNameCollector {
+  pub fn new() -> LongNameCollector {
+    LongNameCollector {
+      chars: Vec::new(),
+      expected: 0,
+      checksum: 0,
+      valid: false,
+    }
+  }
+
+  pub fn reset(&mut self) {
+    self.chars.clear();
+    self.expected = 0;
+    self.valid = false;
+  }
+
+  /// Add the next long name entry. An entry that is out of sequence, or whose
+  /// sequence number could not belong to a valid name, discards whatever has
+  /// been collected so far.
+  pub fn push(&mut self, raw: &[u8]) {
+    let order = raw[0];
+    let sequence = order & SEQUENCE_MASK;
+    if sequence == 0 || sequence as usize > MAX_ENTRIES {
+      self.reset();
+      return;
+    }
+    if order & LAST_ENTRY_FLAG != 0 {
+      self.chars.clear();
+      self.chars.resize(sequence as usize * CHARS_PER_ENTRY, 0xffff);
+      self.expected = sequence;
+      self.checksum = raw[13];
+      self.valid = true;
+    } else if !self.valid || sequence + 1 != self.expected || raw[13] != self.checksum {
+      self.reset();
+      return;
+    } else {
+      self.expected = sequence;
+    }
+    let start = (sequence as usize - 1) * CHARS_PER_ENTRY;
+    for (i, offset) in CHAR_OFFSETS.iter().enumerate() {
+      self.chars[start + i] = raw[*offset] as u16 | ((raw[*offset + 1] as u16) << 8);
+    }
+  }
+
+  /// Called when an 8.3 entry is reached. If the collected entries form a
+  /// complete name belonging to that entry, the name is returned.
+  pub fn finish(&mut self, short_name: &[u8; 11]) -> Option<String> {
+    let complete = self.valid && self.expected == 1 && checksum(short_name) == self.checksum;
+    let result = if complete {
+      let length = self.chars.iter().position(|c| *c == 0 || *c == 0xffff).unwrap_or(self.chars.len());
+      let decoded = core::char::decode_utf16(self.chars[..length].iter().copied())
+        .map(|r| r.unwrap_or('?'))
+        .collect::<String>();
+      Some(decoded)
+    } else {
+      None
+    };
+    self.reset();
+    result
+  }
+}
+
+/// Determine whether a name can be stored as a plain 8.3 entry. Case is not
+/// significant, since names are upper-cased before being stored.
+pub fn needs_long_name(name: &str) -> bool {
+  let bytes = name.as_bytes();
+  let (base, ext) = match name.rfind('.') {
+    Some(dot) => (&bytes[..dot], &bytes[(dot + 1)..]),
+    None => (bytes, &bytes[0..0]),
+  };
+  if base.len() == 0 || base.len() > 8 || ext.len() > 3 {
+    return true;
+  }
+  base.iter().chain(ext.iter()).any(|ch| {
+    *ch == b'.' || *ch >= 0x80 || INVALID_SHORT_CHARS.contains(ch)
+  })
+}
+
+fn to_short_char(ch: u8) -> Option<u8> {
+  if ch == b' ' || ch == b'.' {
+    None
+  } else if ch >= 0x80 || INVALID_SHORT_CHARS.contains(&ch) {
+    Some(b'_')
+  } else {
+    Some(ch.to_ascii_uppercase())
+  }
+}
+
+/// Build the basis for a short alias of a long name: spaces and periods are
+/// dropped, invalid characters become underscores, and the base and extension
+/// are truncated to fit.
+pub fn short_name_basis(name: &str) -> ([u8; 8], [u8; 3]) {
+  let mut short_name = [0x20; 8];
+  let mut short_ext = [0x20; 3];
+  let trimmed = name.trim_start_matches('.');
+  let (base, ext) = match trimmed.rfind('.') {
+    Some(dot) => (&trimmed[..dot], &trimmed[(dot + 1)..]),
+    None => (trimmed, ""),
+  };
+  // Multi-byte characters are each reduced to a single underscore
+  let base_chars = base.chars().map(|c| if c.is_ascii() { c as u8 } else { 0x80 });
+  for (slot, ch) in short_name.iter_mut().zip(base_chars.filter_map(to_short_char)) {
+    *slot = ch;
+  }
+  let ext_chars = ext.chars().map(|c| if c.is_ascii() { c as u8 } else { 0x80 });
+  for (slot, ch) in short_ext.iter_mut().zip(ext_chars.filter_map(to_short_char)) {
+    *slot = ch;
+  }
+  if short_name[0] == 0x20 {
+    short_name[0] = b'_';
+  }
+  (short_name, short_ext)
+}
+
+/// Add a "~N" numeric tail to a short name basis, truncating it as needed
+pub fn apply_numeric_tail(basis: &[u8; 8], number: usize) -> [u8; 8] {
+  let mut digits = [0u8; 7];
+  let mut digit_count = 0;
+  let mut remaining = number;
+  loop {
+    digits[digit_count] = b'0' + (remaining % 10) as u8;
+    digit_count += 1;
+    remaining /= 10;
+    if remaining == 0 || digit_count == digits.len() {
+      break;
+    }
+  }
+  let basis_length = basis.iter().position(|c| *c == 0x20).unwrap_or(8);
+  let keep = basis_length.min(8 - 1 - digit_count);
+  let mut name = [0x20; 8];
+  name[..keep].copy_from_slice(&basis[..keep]);
+  name[keep] = b'~';
+  for i in 0..digit_count {
+    name[keep + 1 + i] = digits[digit_count - 1 - i];
+  }
+  name
+}
+
+/// Build the long name entries for a name, in the order they are written to
+/// disk. The 8.3 entry must be written directly after them.
+pub fn create_entries(name: &str, short_name: &[u8; 11]) -> Result<Vec<RawEntry>, ()> {
+  let chars: Vec<u16> = name.encode_utf16().collect();
+  if chars.len() == 0 || chars.len() > MAX_LONG_NAME_LENGTH {
+    return Err(());
+  }
+  let count = (chars.len() + CHARS_PER_ENTRY - 1) / CHARS_PER_ENTRY;
+  let sum = checksum(short_name);
+  let mut entries = Vec::with_capacity(count);
+  for sequence in (1..=count).rev() {
+    let mut raw = [0; DIRECTORY_ENTRY_SIZE];
+    raw[0] = sequence as u8;
+    if sequence == count {
+      raw[0] |= LAST_ENTRY_FLAG;
+    }
+    raw[11] = LONG_NAME_ATTRIBUTES;
+    raw[13] = sum;
+    let start = (sequence - 1) * CHARS_PER_ENTRY;
+    for (i, offset) in CHAR_OFFSETS.iter().enumerate() {
+      // The name is terminated with a null, and any remaining space is
+      // padded with 0xffff
+      let ch = match chars.get(start + i) {
+        Some(ch) => *ch,
+        None if start + i == chars.len() => 0,
+        None => 0xffff,
+      };
+      raw[*offset] = ch as u8;
+      raw[*offset + 1] = (ch >> 8) as u8;
+    }
+    entries.push(raw);
+  }
+  Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn full_short_name(name: &[u8; 8], ext: &[u8; 3]) -> [u8; 11] {
+    let mut full = [0; 11];
+    full[..8].copy_from_slice(name);
+    full[8..].copy_from_slice(ext);
+    full
+  }
+
+  #[test]
+  fn short_name_rules() {
+    assert!(!needs_long_name("COMMAND.COM"));
+    assert!(!needs_long_name("readme.txt"));
+    assert!(!needs_long_name("NOEXT"));
+    assert!(needs_long_name("Long File Name.txt"));
+    assert!(needs_long_name("archive.tar.gz"));
+    assert!(needs_long_name("TOOLONGNAME.TXT"));
+    assert!(needs_long_name("FILE.TEXT"));
+  }
+
+  #[test]
+  fn aliases() {
+    let (name, ext) = short_name_basis("Long File Name.text");
+    assert_eq!(&name, b"LONGFILE");
+    assert_eq!(&ext, b"TEX");
+    assert_eq!(&apply_numeric_tail(&name, 1), b"LONGFI~1");
+    assert_eq!(&apply_numeric_tail(&name, 12), b"LONGF~12");
+    let (name, ext) = short_name_basis(".profile");
+    assert_eq!(&name, b"PROFILE ");
+    assert_eq!(&ext, b"   ");
+    let (name, _) = short_name_basis("a+b");
+    assert_eq!(&apply_numeric_tail(&name, 3), b"A_B~3   ");
+  }
+
+  #[test]
+  fn round_trip() {
+    let short = full_short_name(b"LONGFI~1", b"TXT");
+    let entries = create_entries("Long File Name Example.txt", &short).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0][0], 0x42);
+    assert_eq!(entries[1][0], 0x01);
+    let mut collector = LongNameCollector::new();
+    for entry in entries.iter() {
+      assert!(is_long_name_entry(entry));
+      collector.push(entry);
+    }
+    assert_eq!(collector.finish(&short).as_deref(), Some("Long File Name Example.txt"));
+  }
+
+  #[test]
+  fn exact_multiple_of_entry_length() {
+    let short = full_short_name(b"ABCDEF~1", b"   ");
+    let entries = create_entries("ABCDEFGHIJKLM", &short).unwrap();
+    assert_eq!(entries.len(), 1);
+    let mut collector = LongNameCollector::new();
+    collector.push(&entries[0]);
+    assert_eq!(collector.finish(&short).as_deref(), Some("ABCDEFGHIJKLM"));
+  }
+
+  #[test]
+  fn rejects_orphans() {
+    let short = full_short_name(b"LONGFI~1", b"TXT");
+    let other = full_short_name(b"OTHER   ", b"TXT");
+    let entries = create_entries("Long File Name Example.txt", &short).unwrap();
+    let mut collector = LongNameCollector::new();
+    for entry in entries.iter() {
+      collector.push(entry);
+    }
+    assert_eq!(collector.finish(&other), None);
+    // Missing the first entry on disk
+    collector.push(&entries[1]);
+    assert_eq!(collector.finish(&short), None);
+  }
+
+  #[test]
+  fn rejects_bad_sequence_numbers() {
+    let short = full_short_name(b"LONGFI~1", b"TXT");
+    let entries = create_entries("Long File Name Example.txt", &short).unwrap();
+    let mut collector = LongNameCollector::new();
+    // A zero sequence number after a valid start discards the partial name
+    collector.push(&entries[0]);
+    let mut zero = entries[1];
+    zero[0] = 0;
+    collector.push(&zero);
+    collector.push(&entries[1]);
+    assert_eq!(collector.finish(&short), None);
+    // So does a zero sequence number marked as the last entry
+    zero[0] = 0x40;
+    collector.push(&zero);
+    assert_eq!(collector.finish(&short), None);
+    // Sequence numbers beyond the longest possible name are not trusted
+    let mut huge = entries[0];
+    huge[0] = 0x40 | 0x1f;
+    collector.push(&huge);
+    assert_eq!(collector.finish(&short), None);
+    // A valid name still works afterwards
+    for entry in entries.iter() {
+      collector.push(entry);
+    }
+    assert_eq!(collector.finish(&short).as_deref(), Some("Long File Name Example.txt"));
+  }
+}
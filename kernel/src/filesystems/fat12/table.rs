@@ -0,0 +1,357 @@
+use alloc::vec::Vec;
+use super::errors::FatError;
+use super::fat::{Cluster, FatEntry};
+
+/// The first two entries of the FAT are reserved, and do not refer to data
+pub const FIRST_DATA_CLUSTER: usize = 2;
+
+/// In-memory copy of an entire FAT12 table. Reading individual sectors is
+/// enough to follow a cluster chain, but modifying the table requires
+/// searching for free clusters and updating entries that may straddle sector
+/// boundaries, which is far simpler with the whole table in memory. Sectors
+/// that have been modified are tracked so that only they need to be flushed
+/// back to disk.
+pub struct FatTable {
+  bytes: Vec<u8>,
+  bytes_per_sector: usize,
+  dirty: Vec<bool>,
+  /// Total number of entries in the table, including the two reserved ones
+  cluster_count: usize,
+  /// Where to start looking for the next free cluster
+  next_free_hint: usize,
+}
+
+impl FatTable {
+  pub fn new(bytes: Vec<u8>, bytes_per_sector: usize, cluster_count: usize) -> FatTable {
+    let sector_count = (bytes.len() + bytes_per_sector - 1) / bytes_per_sector;
+    let max_clusters = bytes.len() * 2 / 3;
+    let mut dirty = Vec::with_capacity(sector_count);
+    for _ in 0..sector_count {
+      dirty.push(false);
+    }
+    FatTable {
+      bytes,
+      bytes_per_sector,
+      dirty,
+      cluster_count: cluster_count.min(max_clusters),
+      next_free_hint: FIRST_DATA_CLUSTER,
+    }
+  }
+
+  pub fn get_cluster_count(&self) -> usize {
+    self.cluster_count
+  }
+
+  fn is_valid(&self, cluster: Cluster) -> bool {
+    let index = cluster.as_usize();
+    index >= FIRST_DATA_CLUSTER && index < self.cluster_count
+  }
+
+  pub fn get(&self, cluster: Cluster) -> FatEntry {
+    let index = cluster.as_usize();
+    let offset = index + (index >> 1);
+    let low = self.bytes[offset] as u16;
+    let high = self.bytes[offset + 1] as u16;
+    let pair = low | (high << 8);
+    let value = if index & 1 == 0 {
+      pair & 0xfff
+    } else {
+      pair >> 4
+    };
+    FatEntry::from_value(value)
+  }
+
+  pub fn set(&mut self, cluster: Cluster, entry: FatEntry) {
+    let index = cluster.as_usize();
+    let offset = index + (index >> 1);
+    let value = entry.to_value() & 0xfff;
+    if index & 1 == 0 {
+      self.bytes[offset] = (value & 0xff) as u8;
+      self.bytes[offset + 1] = (self.bytes[offset + 1] & 0xf0) | ((value >> 8) as u8);
+    } else {
+      self.bytes[offset] = (self.bytes[offset] & 0x0f) | (((value & 0x0f) as u8) << 4);
+      self.bytes[offset + 1] = (value >> 4) as u8;
+    }
+    self.mark_dirty(offset);
+    self.mark_dirty(offset + 1);
+  }
+
+  fn mark_dirty(&mut self, byte_offset: usize) {
+    let sector = byte_offset / self.bytes_per_sector;
+    if let Some(flag) = self.dirty.get_mut(sector) {
+      *flag = true;
+    }
+  }
+
+  /// Whether a cluster refers to data and is not part of any chain
+  pub fn is_free(&self, cluster: Cluster) -> bool {
+    self.is_valid(cluster) && self.get(cluster) == FatEntry::Free
+  }
+
+  pub fn count_free(&self) -> usize {
+    let mut count = 0;
+    for index in FIRST_DATA_CLUSTER..self.cluster_count {
+      if self.get(Cluster::new(index)) == FatEntry::Free {
+        count += 1;
+      }
+    }
+    count
+  }
+
+  fn find_free(&self) -> Option<Cluster> {
+    let range = self.cluster_count - FIRST_DATA_CLUSTER;
+    for step in 0..range {
+      let index = FIRST_DATA_CLUSTER + (self.next_free_hint - FIRST_DATA_CLUSTER + step) % range;
+      let cluster = Cluster::new(index);
+      if self.get(cluster) == FatEntry::Free {
+        return Some(cluster);
+      }
+    }
+    None
+  }
+
+  /// Follow a chain from its first cluster, returning every cluster in order
+  pub fn get_chain(&self, first: Cluster) -> Vec<Cluster> {
+    let mut chain = Vec::new();
+    let mut current = first;
+    while self.is_valid(current) && chain.len() < self.cluster_count {
+      chain.push(current);
+      match self.get(current) {
+        FatEntry::NextCluster(next) => current = next,
+        _ => break,
+      }
+    }
+    chain
+  }
+
+  /// Allocate `count` clusters as a single chain. If `append_to` is provided,
+  /// the new chain is linked to the end of that cluster.
+  /// Allocation is all-or-nothing: if there are not enough free clusters, the
+  /// table is left untouched and a NoSpace error is returned.
+  pub fn allocate(&mut self, count: usize, append_to: Option<Cluster>) -> Result<Vec<Cluster>, FatError> {
+    if count == 0 {
+      return Ok(Vec::new());
+    }
+    if self.count_free() < count {
+      return Err(FatError::NoSpace);
+    }
+    let mut allocated: Vec<Cluster> = Vec::with_capacity(count);
+    for _ in 0..count {
+      let cluster = self.find_free().ok_or(FatError::NoSpace)?;
+      self.set(cluster, FatEntry::EndOfChain);
+      if let Some(prev) = allocated.last() {
+        self.set(*prev, FatEntry::NextCluster(cluster));
+      }
+      self.next_free_hint = cluster.as_usize();
+      allocated.push(cluster);
+    }
+    if let Some(tail) = append_to {
+      self.set(tail, FatEntry::NextCluster(allocated[0]));
+    }
+    Ok(allocated)
+  }
+
+  fn is_free_run(&self, start: usize, count: usize) -> bool {
+    start >= FIRST_DATA_CLUSTER
+      && start + count <= self.cluster_count
+      && (start..(start + count)).all(|index| self.get(Cluster::new(index)) == FatEntry::Free)
+  }
+
+  /// Find the first cluster of `count` consecutive free clusters. The clusters
+  /// immediately following `after` are preferred, so that a file being
+  /// extended stays in one piece.
+  fn find_free_run(&self, count: usize, after: Option<Cluster>) -> Option<Cluster> {
+    if let Some(tail) = after {
+      if self.is_free_run(tail.as_usize() + 1, count) {
+        return Some(Cluster::new(tail.as_usize() + 1));
+      }
+    }
+    let mut run_start = FIRST_DATA_CLUSTER;
+    let mut run_length = 0;
+    for index in FIRST_DATA_CLUSTER..self.cluster_count {
+      if self.get(Cluster::new(index)) != FatEntry::Free {
+        run_length = 0;
+        continue;
+      }
+      if run_length == 0 {
+        run_start = index;
+      }
+      run_length += 1;
+      if run_length == count {
+        return Some(Cluster::new(run_start));
+      }
+    }
+    None
+  }
+
+  /// Allocate `count` clusters as a single chain, like `allocate`, but place
+  /// them in one contiguous run when the free space allows it. If no run is
+  /// long enough, this falls back to an ordinary allocation.
+  pub fn allocate_contiguous(&mut self, count: usize, append_to: Option<Cluster>) -> Result<Vec<Cluster>, FatError> {
+    if count == 0 {
+      return Ok(Vec::new());
+    }
+    let start = match self.find_free_run(count, append_to) {
+      Some(cluster) => cluster.as_usize(),
+      None => return self.allocate(count, append_to),
+    };
+    let mut allocated: Vec<Cluster> = Vec::with_capacity(count);
+    for index in start..(start + count) {
+      let cluster = Cluster::new(index);
+      self.set(cluster, FatEntry::EndOfChain);
+      if let Some(prev) = allocated.last() {
+        self.set(*prev, FatEntry::NextCluster(cluster));
+      }
+      allocated.push(cluster);
+    }
+    self.next_free_hint = start + count - 1;
+    if let Some(tail) = append_to {
+      self.set(tail, FatEntry::NextCluster(allocated[0]));
+    }
+    Ok(allocated)
+  }
+
+  /// Undo an allocation that could not be completed. Every cluster in the list
+  /// is marked free again, and if the allocation had been appended to an
+  /// existing chain, that chain is terminated where it was before.
+  pub fn rollback(&mut self, allocated: &[Cluster], append_to: Option<Cluster>) {
+    for cluster in allocated.iter() {
+      self.set(*cluster, FatEntry::Free);
+    }
+    if let Some(tail) = append_to {
+      self.set(tail, FatEntry::EndOfChain);
+    }
+  }
+
+  /// Release every cluster in a chain, returning the number freed
+  pub fn free_chain(&mut self, first: Cluster) -> usize {
+    let chain = self.get_chain(first);
+    for cluster in chain.iter() {
+      self.set(*cluster, FatEntry::Free);
+    }
+    chain.len()
+  }
+
+  /// Return the indexes of all sectors modified since the last flush
+  pub fn get_dirty_sectors(&self) -> Vec<usize> {
+    let mut sectors = Vec::new();
+    for (index, dirty) in self.dirty.iter().enumerate() {
+      if *dirty {
+        sectors.push(index);
+      }
+    }
+    sectors
+  }
+
+  pub fn clear_dirty(&mut self) {
+    for flag in self.dirty.iter_mut() {
+      *flag = false;
+    }
+  }
+
+  pub fn get_sector(&self, sector: usize) -> &[u8] {
+    let start = sector * self.bytes_per_sector;
+    let end = (start + self.bytes_per_sector).min(self.bytes.len());
+    &self.bytes[start..end]
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::{Cluster, FatEntry, FatError, FatTable};
+
+  fn empty_table() -> FatTable {
+    let mut bytes = Vec::with_capacity(24);
+    for _ in 0..24 {
+      bytes.push(0);
+    }
+    bytes[0] = 0xf0;
+    bytes[1] = 0xff;
+    bytes[2] = 0xff;
+    FatTable::new(bytes, 12, 16)
+  }
+
+  #[test]
+  fn get_and_set() {
+    let mut table = empty_table();
+    table.set(Cluster::new(2), FatEntry::NextCluster(Cluster::new(3)));
+    table.set(Cluster::new(3), FatEntry::NextCluster(Cluster::new(0x10a)));
+    table.set(Cluster::new(4), FatEntry::EndOfChain);
+    assert_eq!(table.get(Cluster::new(2)), FatEntry::NextCluster(Cluster::new(3)));
+    assert_eq!(table.get(Cluster::new(3)), FatEntry::NextCluster(Cluster::new(0x10a)));
+    assert_eq!(table.get(Cluster::new(4)), FatEntry::EndOfChain);
+    assert_eq!(table.get(Cluster::new(5)), FatEntry::Free);
+    assert_eq!(table.get_dirty_sectors(), [0]);
+  }
+
+  #[test]
+  fn allocate_chain() {
+    let mut table = empty_table();
+    let clusters = table.allocate(3, None).unwrap();
+    assert_eq!(clusters, [Cluster::new(2), Cluster::new(3), Cluster::new(4)]);
+    assert_eq!(table.get_chain(Cluster::new(2)), clusters);
+    let more = table.allocate(2, Some(Cluster::new(4))).unwrap();
+    assert_eq!(more, [Cluster::new(5), Cluster::new(6)]);
+    assert_eq!(table.get_chain(Cluster::new(2)).len(), 5);
+    assert_eq!(table.count_free(), 9);
+  }
+
+  #[test]
+  fn allocation_is_all_or_nothing() {
+    let mut table = empty_table();
+    table.allocate(10, None).unwrap();
+    assert_eq!(table.count_free(), 4);
+    table.clear_dirty();
+    assert_eq!(table.allocate(5, None), Err(FatError::NoSpace));
+    assert_eq!(table.count_free(), 4);
+    assert_eq!(table.get_dirty_sectors().len(), 0);
+  }
+
+  #[test]
+  fn rollback_allocation() {
+    let mut table = empty_table();
+    table.allocate(2, None).unwrap();
+    let added = table.allocate(3, Some(Cluster::new(3))).unwrap();
+    table.rollback(&added, Some(Cluster::new(3)));
+    assert_eq!(table.get_chain(Cluster::new(2)), [Cluster::new(2), Cluster::new(3)]);
+    assert_eq!(table.count_free(), 12);
+  }
+
+  #[test]
+  fn free_chain() {
+    let mut table = empty_table();
+    table.allocate(4, None).unwrap();
+    assert_eq!(table.free_chain(Cluster::new(2)), 4);
+    assert_eq!(table.count_free(), 14);
+  }
+
+  #[test]
+  fn free_clusters() {
+    let mut table = empty_table();
+    table.allocate(1, None).unwrap();
+    assert!(!table.is_free(Cluster::new(2)));
+    assert!(table.is_free(Cluster::new(3)));
+    // Reserved and out-of-range entries never count as free
+    assert!(!table.is_free(Cluster::new(1)));
+    assert!(!table.is_free(Cluster::new(16)));
+  }
+
+  #[test]
+  fn allocate_contiguous_run() {
+    let mut table = empty_table();
+    table.allocate(3, None).unwrap();
+    table.allocate(1, None).unwrap();
+    table.free_chain(Cluster::new(2));
+    // Clusters 2-4 are free, but too short for the run
+    let run = table.allocate_contiguous(4, None).unwrap();
+    assert_eq!(run, [Cluster::new(6), Cluster::new(7), Cluster::new(8), Cluster::new(9)]);
+    let more = table.allocate_contiguous(2, Some(Cluster::new(9))).unwrap();
+    assert_eq!(more, [Cluster::new(10), Cluster::new(11)]);
+    assert_eq!(table.get_chain(Cluster::new(6)).len(), 6);
+    // With no run long enough, the clusters are scattered
+    let scattered = table.allocate_contiguous(6, None).unwrap();
+    assert_eq!(scattered.len(), 6);
+    assert_eq!(table.count_free(), 1);
+  }
+}
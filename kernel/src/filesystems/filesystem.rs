@@ -1,10 +1,42 @@
 use crate::files::{cursor::SeekMethod, handle::LocalHandle};
-use syscall::files::DirEntryInfo;
+use crate::time::timestamp::Timestamp;
+use super::capabilities::Capabilities;
+use super::options::MountOptions;
+use syscall::files::{DirEntryInfo, FileStatus, OpenFlags};
+use syscall::result::SystemError;
+
+/// How the VFS runs reads and writes on a filesystem
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FileSystemKind {
+  /// Operations finish without waiting on hardware, so they run directly on
+  /// the calling process
+  KernelSync,
+  /// Operations wait on a slow device. The VFS hands them to a disk worker,
+  /// and the caller blocks on a promise until the worker is done.
+  KernelAsync,
+}
 
 pub trait FileSystem {
-  fn open(&self, path: &str) -> Result<LocalHandle, ()>;
+  fn get_kind(&self) -> FileSystemKind {
+    FileSystemKind::KernelSync
+  }
+
+  /// Describe what the filesystem supports. This is read once at mount time,
+  /// and the VFS refuses any operation the filesystem did not claim before it
+  /// reaches the driver.
+  fn get_capabilities(&self) -> Capabilities {
+    Capabilities::none()
+  }
+
+  /// Open a file. The flags describe how the handle will be used, and reads or
+  /// writes through a handle opened without that access should be rejected.
+  /// Filesystems that cannot be modified may ignore them, since they already
+  /// refuse every write.
+  fn open(&self, path: &str, flags: OpenFlags) -> Result<LocalHandle, ()>;
   fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()>;
-  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()>;
+  /// Writes return a SystemError, so that conditions like a full disk can be
+  /// reported to the caller
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, SystemError>;
   fn close(&self, handle: LocalHandle) -> Result<(), ()>;
   fn dup(&self, handle: LocalHandle) -> Result<LocalHandle, ()>;
   fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()>;
@@ -12,7 +44,175 @@ pub trait FileSystem {
   fn open_dir(&self, path: &str) -> Result<LocalHandle, ()>;
   fn read_dir(&self, handle: LocalHandle, index: usize, info: &mut DirEntryInfo) -> Result<(), ()>;
 
+  /// Fill a run of entries, starting at `index`, and return how many were
+  /// written. Fewer than requested means the end of the directory was reached.
+  /// The default reads one index at a time; filesystems that have to walk the
+  /// directory to find an index should override it to make a single pass.
+  fn read_dir_entries(&self, handle: LocalHandle, index: usize, entries: &mut [DirEntryInfo]) -> Result<usize, ()> {
+    for (offset, info) in entries.iter_mut().enumerate() {
+      self.read_dir(handle, index + offset, info)?;
+      if info.is_empty() {
+        return Ok(offset);
+      }
+    }
+    Ok(entries.len())
+  }
+
+  /// Resolve a path to a filesystem-specific number identifying its directory
+  /// entry. The VFS caches these, so that repeated opens of the same path can
+  /// skip the directory walk by calling `open_entry` instead.
+  /// Filesystems that cannot reopen files this way leave the default, and are
+  /// always opened by path.
+  fn lookup(&self, _path: &str) -> Result<usize, ()> {
+    Err(())
+  }
+
+  /// Open a file from an identifier previously returned by `lookup`
+  fn open_entry(&self, _entry: usize, _flags: OpenFlags) -> Result<LocalHandle, ()> {
+    Err(())
+  }
+
+  /// Describe an open file. The drive field is filled in by the VFS.
+  fn stat(&self, _handle: LocalHandle, _status: &mut FileStatus) -> Result<(), ()> {
+    Err(())
+  }
+
+  /// Reopen a file from the stable identifier reported by `stat`
+  fn open_by_id(&self, _id: u32, _flags: OpenFlags) -> Result<LocalHandle, ()> {
+    Err(())
+  }
+
+  /// Copy the value of a named extended attribute into the buffer, returning
+  /// the full length of the value. If the buffer is too small, the value is
+  /// truncated.
+  fn get_xattr(&self, _handle: LocalHandle, _name: &str, _buffer: &mut [u8]) -> Result<usize, SystemError> {
+    Err(SystemError::UnsupportedCommand)
+  }
+
+  /// Set a named extended attribute. An empty value removes the attribute.
+  fn set_xattr(&self, _handle: LocalHandle, _name: &str, _value: &[u8]) -> Result<(), SystemError> {
+    Err(SystemError::UnsupportedCommand)
+  }
+
   fn ioctl(&self, handle: LocalHandle, command: u32, arg: u32) -> Result<u32, ()> {
     Err(())
   }
+
+  /// Create a new, empty file at the path and open it. If the file already
+  /// exists, it is truncated to zero length.
+  fn create(&self, _path: &str) -> Result<LocalHandle, SystemError> {
+    Err(SystemError::UnsupportedCommand)
+  }
+
+  /// Shrink or extend an open file to exactly `length` bytes
+  fn truncate(&self, _handle: LocalHandle, _length: usize) -> Result<(), SystemError> {
+    Err(SystemError::UnsupportedCommand)
+  }
+
+  /// Reserve space so that an open file can hold at least `length` bytes
+  /// without any further allocation. A shorter file is extended to `length`,
+  /// and the new bytes read as zeroes. Existing contents are never changed,
+  /// and a file that is already long enough is left alone.
+  fn preallocate(&self, _handle: LocalHandle, _length: usize) -> Result<(), SystemError> {
+    Err(SystemError::UnsupportedCommand)
+  }
+
+  /// Change when the file or directory at the path was last modified. Open
+  /// handles that go on to modify the file will update the time again.
+  fn set_modified_time(&self, _path: &str, _time: Timestamp) -> Result<(), SystemError> {
+    Err(SystemError::UnsupportedCommand)
+  }
+
+  /// Remove a file from the filesystem
+  fn delete(&self, _path: &str) -> Result<(), SystemError> {
+    Err(SystemError::UnsupportedCommand)
+  }
+
+  /// Give an existing file an additional name on the same drive. The file is
+  /// only removed once every one of its names has been deleted.
+  fn link(&self, _existing_path: &str, _new_path: &str) -> Result<(), SystemError> {
+    Err(SystemError::UnsupportedCommand)
+  }
+
+  /// Move a file or directory to a new path on the same drive. The change
+  /// must be atomic: if it fails, the entry is still found at its old path.
+  /// Open handles to the entry remain valid. Fails with AlreadyExists if
+  /// something else is found at the new path.
+  fn rename(&self, _old_path: &str, _new_path: &str) -> Result<(), SystemError> {
+    Err(SystemError::UnsupportedCommand)
+  }
+
+  /// Move a file over an existing file on the same drive, for atomic updates.
+  /// If the operation is interrupted, the target must be left with either its
+  /// old or its new contents. If nothing exists at the target path, this is a
+  /// plain rename. The default deletes the target before renaming, which is
+  /// only safe on filesystems that do not outlive a crash.
+  fn replace(&self, source_path: &str, target_path: &str) -> Result<(), SystemError> {
+    match self.rename(source_path, target_path) {
+      Err(SystemError::AlreadyExists) => {
+        self.delete(target_path)?;
+        self.rename(source_path, target_path)
+      },
+      result => result,
+    }
+  }
+
+  /// Create a new, empty directory. The parent directory must already exist.
+  fn mkdir(&self, _path: &str) -> Result<(), SystemError> {
+    Err(SystemError::UnsupportedCommand)
+  }
+
+  /// Remove a directory, which must be empty
+  fn rmdir(&self, _path: &str) -> Result<(), SystemError> {
+    Err(SystemError::UnsupportedCommand)
+  }
+
+  /// Report whether an open handle can still be used. Handles on removable
+  /// media become stale when the disk is swapped, and fail until closed.
+  fn check_handle(&self, _handle: LocalHandle) -> Result<(), SystemError> {
+    Ok(())
+  }
+
+  /// Report why an open would be refused before attempting it, for reasons
+  /// more specific than the file not existing, such as a device that is
+  /// already in use. Open itself must still enforce the same rules.
+  fn check_open(&self, _path: &str, _flags: OpenFlags) -> Result<(), SystemError> {
+    Ok(())
+  }
+
+  /// Report whether the filesystem can accept new work, or is waiting to be
+  /// remounted after a media change
+  fn check_media(&self) -> Result<(), SystemError> {
+    Ok(())
+  }
+
+  /// Called when the underlying device reports that its media was swapped.
+  /// Cached data must be discarded, and open handles marked stale.
+  fn media_changed(&self) {
+  }
+
+  /// Read the filesystem again from the current media, after a media change
+  fn remount(&self) -> Result<(), ()> {
+    Ok(())
+  }
+
+  /// Called before the drive is removed from the VFS. Anything held in memory
+  /// should be written out, and the backing device released. Filesystems that
+  /// still have open files should refuse with Busy.
+  fn unmount(&self) -> Result<(), SystemError> {
+    Ok(())
+  }
+
+  /// Write anything held in memory back to the device, without unmounting.
+  /// Called some time after a burst of writes has finished.
+  fn sync(&self) -> Result<(), SystemError> {
+    Ok(())
+  }
+
+  /// Called once when the filesystem is mounted as a drive. Filesystems that
+  /// care about access times, name translation, or write caching should store
+  /// the relevant options here.
+  fn apply_mount_options(&self, _options: &MountOptions) -> Result<(), ()> {
+    Ok(())
+  }
 }
\ No newline at end of file
@@ -2,8 +2,10 @@ use alloc::collections::BTreeMap;
 use crate::files::{cursor::SeekMethod, handle::{HandleAllocator, LocalHandle}};
 use crate::memory::address::VirtualAddress;
 use spin::RwLock;
+use super::capabilities::Capabilities;
 use super::filesystem::FileSystem;
-use syscall::files::DirEntryInfo;
+use syscall::files::{DirEntryInfo, OpenFlags};
+use syscall::result::SystemError;
 
 struct OpenFile {
   pub cursor: usize,
@@ -28,7 +30,15 @@ impl InitFileSystem {
 }
 
 impl FileSystem for InitFileSystem {
-  fn open(&self, path: &str) -> Result<LocalHandle, ()> {
+  /// The boot files are a flat, read-only list
+  fn get_capabilities(&self) -> Capabilities {
+    Capabilities {
+      seekable: true,
+      ..Capabilities::none()
+    }
+  }
+
+  fn open(&self, path: &str, _flags: OpenFlags) -> Result<LocalHandle, ()> {
     let local_path = if path.starts_with('\\') {
       &path[1..]
     } else {
@@ -80,8 +90,8 @@ impl FileSystem for InitFileSystem {
     }
   }
 
-  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
-    Ok(0)
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, SystemError> {
+    Err(SystemError::ReadOnlyFileSystem)
   }
 
   fn close(&self, handle: LocalHandle) -> Result<(), ()> {
@@ -0,0 +1,191 @@
+//! Reads and writes through the VFS. Filesystems that report themselves as
+//! KernelAsync wait on slow devices, so their operations are handed to the
+//! disk worker pool and the caller receives a promise. Waiting on the promise
+//! blocks the calling process, letting everything else run until the worker
+//! is done.
+//!
+//! Workers run in their own address space, so they never touch the caller's
+//! memory. Data is read into, or written from, a kernel buffer owned by the
+//! request, and copied to or from the caller once it has resumed.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::files::handle::LocalHandle;
+use crate::process;
+use crate::promise::{Promise, PromiseValue};
+use crate::workers;
+use spin::Mutex;
+use syscall::result::SystemError;
+use super::{FileSystemMap, FileSystemType};
+use super::filesystem::FileSystemKind;
+
+/// How long a drive must go without writes before its cache is synced
+const SYNC_DELAY_MS: usize = 2000;
+
+/// Drives with a sync already scheduled
+static PENDING_SYNCS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// Number of bytes read or written
+pub type IoResult = Result<usize, SystemError>;
+
+/// A read or write that may still be running on a disk worker
+pub struct IoRequest {
+  result: PromiseValue<IoResult>,
+  buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl IoRequest {
+  pub fn is_complete(&self) -> bool {
+    self.result.is_resolved()
+  }
+
+  /// Block until the operation is done, and return its result
+  pub fn wait(&self) -> IoResult {
+    self.result.wait()
+  }
+
+  /// Block until a read is done, and copy the data it read into `dest`
+  pub fn wait_into(&self, dest: &mut [u8]) -> IoResult {
+    let length = self.wait()?;
+    let buffer = self.buffer.lock();
+    let length = length.min(dest.len()).min(buffer.len());
+    dest[..length].copy_from_slice(&buffer[..length]);
+    Ok(length)
+  }
+}
+
+/// An operation and everything it needs, ready to run on whichever process
+/// picks it up
+struct PendingIo<F> {
+  fs: Arc<Box<FileSystemType>>,
+  buffer: Arc<Mutex<Vec<u8>>>,
+  op: F,
+  promise: Promise<IoResult>,
+}
+
+impl<F: FnOnce(&FileSystemType, &mut [u8]) -> IoResult> PendingIo<F> {
+  fn run(self) {
+    let result = {
+      let mut buffer = self.buffer.lock();
+      (self.op)(&**self.fs, &mut buffer[..])
+    };
+    self.promise.resolve(result);
+  }
+}
+
+/// A disk worker that submitted work to its own pool and then waited on it
+/// could deadlock the pool, so workers always run operations themselves
+fn runs_on_worker(fs: &FileSystemType) -> bool {
+  fs.get_kind() == FileSystemKind::KernelAsync
+    && !workers::is_member(workers::DISK_POOL, process::get_current_pid())
+}
+
+/// Start an operation, on the disk worker pool if the filesystem needs it. If
+/// the pool does not exist yet, or its queue is full, the operation runs on
+/// the calling process instead and the request is already complete.
+fn dispatch<F>(fs: Arc<Box<FileSystemType>>, buffer: Vec<u8>, op: F) -> IoRequest
+  where F: FnOnce(&FileSystemType, &mut [u8]) -> IoResult + Send + 'static {
+  let promise = Promise::new();
+  let buffer = Arc::new(Mutex::new(buffer));
+  let request = IoRequest {
+    result: promise.get_value(),
+    buffer: Arc::clone(&buffer),
+  };
+  let use_worker = runs_on_worker(&**fs);
+  let pending = PendingIo { fs, buffer, op, promise };
+  if !use_worker {
+    pending.run();
+    return request;
+  }
+
+  // The job is dropped if submission fails, so the operation is shared and
+  // taken by whoever runs it
+  let shared = Arc::new(Mutex::new(Some(pending)));
+  let for_worker = Arc::clone(&shared);
+  let submitted = workers::submit_fn(workers::DISK_POOL, move || {
+    let pending = for_worker.lock().take();
+    if let Some(pending) = pending {
+      pending.run();
+    }
+  });
+  if submitted.is_err() {
+    let pending = shared.lock().take();
+    if let Some(pending) = pending {
+      pending.run();
+    }
+  }
+  request
+}
+
+/// Write a drive's cached data back once its writes have settled. The sync
+/// runs on the disk worker pool, since it may wait on the device; if either
+/// the timer or the pool is full, the data is written when the cache next
+/// evicts it or the drive is unmounted.
+fn schedule_sync(index: usize) {
+  {
+    let mut pending = PENDING_SYNCS.lock();
+    if pending.contains(&index) {
+      return;
+    }
+    pending.push(index);
+  }
+  let scheduled = workers::schedule_delayed_work(move || {
+    let submitted = workers::submit_fn(workers::DISK_POOL, move || {
+      PENDING_SYNCS.lock().retain(|pending| *pending != index);
+      if let Some(fs) = super::get_fs(index) {
+        let _ = fs.sync();
+      }
+    });
+    if submitted.is_err() {
+      PENDING_SYNCS.lock().retain(|pending| *pending != index);
+    }
+  }, SYNC_DELAY_MS);
+  if scheduled.is_err() {
+    PENDING_SYNCS.lock().retain(|pending| *pending != index);
+  }
+}
+
+impl FileSystemMap {
+  /// Start reading up to `length` bytes from an open file
+  pub fn submit_read(&self, index: usize, handle: LocalHandle, length: usize) -> Result<IoRequest, SystemError> {
+    let fs = self.get_fs(index).ok_or(SystemError::NoSuchFileSystem)?;
+    let mut buffer = Vec::with_capacity(length);
+    buffer.resize(length, 0);
+    Ok(dispatch(fs, buffer, move |fs, buffer| {
+      fs.read(handle, buffer).map_err(|_| SystemError::IOError)
+    }))
+  }
+
+  /// Start writing a copy of `data` to an open file
+  pub fn submit_write(&self, index: usize, handle: LocalHandle, data: Vec<u8>) -> Result<IoRequest, SystemError> {
+    let fs = self.get_fs(index).ok_or(SystemError::NoSuchFileSystem)?;
+    Ok(dispatch(fs, data, move |fs, buffer| fs.write(handle, buffer)))
+  }
+
+  /// Read from an open file, blocking the current process until the data is
+  /// available. Filesystems that do not wait on hardware are read in place.
+  pub fn read(&self, index: usize, handle: LocalHandle, dest: &mut [u8]) -> IoResult {
+    let fs = self.get_fs(index).ok_or(SystemError::NoSuchFileSystem)?;
+    if !runs_on_worker(&**fs) {
+      return fs.read(handle, dest).map_err(|_| SystemError::IOError);
+    }
+    self.submit_read(index, handle, dest.len())?.wait_into(dest)
+  }
+
+  /// Write to an open file, blocking the current process until the write is
+  /// done
+  pub fn write(&self, index: usize, handle: LocalHandle, src: &[u8]) -> IoResult {
+    let fs = self.get_fs(index).ok_or(SystemError::NoSuchFileSystem)?;
+    let result = if !runs_on_worker(&**fs) {
+      fs.write(handle, src)
+    } else {
+      let mut data = Vec::with_capacity(src.len());
+      data.extend_from_slice(src);
+      self.submit_write(index, handle, data)?.wait()
+    };
+    if result.is_ok() {
+      schedule_sync(index);
+    }
+    result
+  }
+}
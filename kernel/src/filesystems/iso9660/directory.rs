@@ -0,0 +1,308 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::errors::IsoError;
+
+pub const FLAG_HIDDEN: u8 = 0x01;
+pub const FLAG_DIRECTORY: u8 = 0x02;
+
+/// Fixed-size portion of a directory record, before the file identifier
+const RECORD_HEADER_SIZE: usize = 33;
+
+/// Rock Ridge NM flags marking the entry as "." or ".."
+const NM_CURRENT: u8 = 0x02;
+const NM_PARENT: u8 = 0x04;
+
+/// Numeric fields are stored in both byte orders; only the little-endian
+/// copy is used
+pub fn read_u16_le(raw: &[u8], offset: usize) -> u16 {
+  raw[offset] as u16 | ((raw[offset + 1] as u16) << 8)
+}
+
+pub fn read_u32_le(raw: &[u8], offset: usize) -> u32 {
+  raw[offset] as u32
+    | ((raw[offset + 1] as u32) << 8)
+    | ((raw[offset + 2] as u32) << 16)
+    | ((raw[offset + 3] as u32) << 24)
+}
+
+/// A single entry in a directory, describing the location and size of a file
+/// or subdirectory
+#[derive(Clone, Debug)]
+pub struct DirectoryRecord {
+  /// First logical block of the file's contents
+  pub extent: usize,
+  pub data_length: usize,
+  pub flags: u8,
+  identifier: Vec<u8>,
+  /// Alternate name from a Rock Ridge NM entry, if the disc has one
+  rock_ridge_name: Option<String>,
+}
+
+impl DirectoryRecord {
+  /// Parse a record from a slice exactly as long as the record's length byte
+  pub fn parse(raw: &[u8]) -> Result<DirectoryRecord, IsoError> {
+    if raw.len() < RECORD_HEADER_SIZE + 1 || raw[0] as usize != raw.len() {
+      return Err(IsoError::InvalidRecord);
+    }
+    let identifier_length = raw[32] as usize;
+    let identifier_end = RECORD_HEADER_SIZE + identifier_length;
+    if identifier_end > raw.len() {
+      return Err(IsoError::InvalidRecord);
+    }
+    // The system use area starts on an even offset
+    let system_use_start = identifier_end + (identifier_end & 1);
+    let rock_ridge_name = if system_use_start < raw.len() {
+      parse_rock_ridge_name(&raw[system_use_start..])
+    } else {
+      None
+    };
+    Ok(DirectoryRecord {
+      extent: read_u32_le(raw, 2) as usize,
+      data_length: read_u32_le(raw, 10) as usize,
+      flags: raw[25],
+      identifier: Vec::from(&raw[RECORD_HEADER_SIZE..identifier_end]),
+      rock_ridge_name,
+    })
+  }
+
+  pub fn is_directory(&self) -> bool {
+    self.flags & FLAG_DIRECTORY != 0
+  }
+
+  pub fn is_hidden(&self) -> bool {
+    self.flags & FLAG_HIDDEN != 0
+  }
+
+  /// The first two records of every directory point to the directory itself
+  /// and its parent, using the single-byte identifiers 0 and 1
+  pub fn is_self(&self) -> bool {
+    self.identifier.as_slice() == [0]
+  }
+
+  pub fn is_parent(&self) -> bool {
+    self.identifier.as_slice() == [1]
+  }
+
+  /// The ISO9660 name, without the ";1" version suffix or the trailing period
+  /// added to names that have no extension
+  pub fn get_iso_name(&self) -> String {
+    let mut end = self.identifier.iter().position(|c| *c == b';').unwrap_or(self.identifier.len());
+    if end > 0 && self.identifier[end - 1] == b'.' {
+      end -= 1;
+    }
+    String::from_utf8_lossy(&self.identifier[..end]).into_owned()
+  }
+
+  /// The name shown to users: the Rock Ridge name when present, otherwise the
+  /// ISO9660 name
+  pub fn get_name(&self) -> String {
+    match &self.rock_ridge_name {
+      Some(name) => name.clone(),
+      None => self.get_iso_name(),
+    }
+  }
+
+  /// Compare a path component with this record, ignoring case. Either the
+  /// Rock Ridge name or the ISO9660 name may be used.
+  pub fn name_matches(&self, name: &str) -> bool {
+    if let Some(rr) = &self.rock_ridge_name {
+      if rr.eq_ignore_ascii_case(name) {
+        return true;
+      }
+    }
+    self.get_iso_name().eq_ignore_ascii_case(name)
+  }
+
+  /// Build an 8.3 name for DOS programs, truncating longer names
+  pub fn get_short_name(&self) -> ([u8; 8], [u8; 3]) {
+    let iso_name = self.get_iso_name();
+    let (base, ext) = match iso_name.rfind('.') {
+      Some(dot) => (&iso_name[..dot], &iso_name[(dot + 1)..]),
+      None => (iso_name.as_str(), ""),
+    };
+    let mut short_name = [0x20; 8];
+    let mut short_ext = [0x20; 3];
+    for (slot, ch) in short_name.iter_mut().zip(base.bytes()) {
+      *slot = ch.to_ascii_uppercase();
+    }
+    for (slot, ch) in short_ext.iter_mut().zip(ext.bytes()) {
+      *slot = ch.to_ascii_uppercase();
+    }
+    (short_name, short_ext)
+  }
+
+  /// Determine whether the displayed name is fully represented by the 8.3
+  /// name, or needs to be reported as a long name
+  pub fn fits_short_name(&self) -> bool {
+    let name = self.get_name();
+    let (base, ext) = match name.rfind('.') {
+      Some(dot) => (&name[..dot], &name[(dot + 1)..]),
+      None => (name.as_str(), ""),
+    };
+    base.len() > 0 && base.len() <= 8 && ext.len() <= 3
+      && !base.contains('.')
+      && !name.bytes().any(|c| c.is_ascii_lowercase() || c >= 0x80)
+  }
+}
+
+/// Collect the name stored in Rock Ridge NM entries. A name may be split
+/// across several NM entries, each flagged to continue into the next.
+fn parse_rock_ridge_name(system_use: &[u8]) -> Option<String> {
+  let mut name: Vec<u8> = Vec::new();
+  let mut found = false;
+  let mut offset = 0;
+  while offset + 4 <= system_use.len() {
+    let length = system_use[offset + 2] as usize;
+    if length < 4 || offset + length > system_use.len() {
+      break;
+    }
+    let entry = &system_use[offset..(offset + length)];
+    if &entry[0..2] == b"NM" && length >= 5 {
+      let flags = entry[4];
+      if flags & (NM_CURRENT | NM_PARENT) == 0 {
+        name.extend_from_slice(&entry[5..]);
+        found = true;
+      }
+    }
+    offset += length;
+  }
+  if found && name.len() > 0 {
+    Some(String::from_utf8_lossy(&name).into_owned())
+  } else {
+    None
+  }
+}
+
+/// Iterates over the records stored in the contents of a directory. Records
+/// never cross a sector boundary; the unused space at the end of a sector is
+/// filled with zeroes.
+pub struct RecordIterator<'a> {
+  data: &'a [u8],
+  offset: usize,
+  sector_size: usize,
+}
+
+impl<'a> RecordIterator<'a> {
+  pub fn new(data: &'a [u8], sector_size: usize) -> RecordIterator<'a> {
+    RecordIterator {
+      data,
+      offset: 0,
+      sector_size,
+    }
+  }
+}
+
+impl<'a> Iterator for RecordIterator<'a> {
+  type Item = Result<DirectoryRecord, IsoError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      if self.offset >= self.data.len() {
+        return None;
+      }
+      let length = self.data[self.offset] as usize;
+      if length == 0 {
+        // Skip the padding to the start of the next sector
+        self.offset = (self.offset / self.sector_size + 1) * self.sector_size;
+        continue;
+      }
+      let start = self.offset;
+      if start + length > self.data.len() {
+        self.offset = self.data.len();
+        return Some(Err(IsoError::InvalidRecord));
+      }
+      self.offset += length;
+      return Some(DirectoryRecord::parse(&self.data[start..(start + length)]));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::*;
+
+  /// Build a raw directory record, optionally with a Rock Ridge name
+  fn make_record(identifier: &[u8], extent: u32, length: u32, flags: u8, rock_ridge: Option<&[u8]>) -> Vec<u8> {
+    let mut raw = Vec::new();
+    raw.resize(RECORD_HEADER_SIZE, 0);
+    raw[2..6].copy_from_slice(&extent.to_le_bytes());
+    raw[6..10].copy_from_slice(&extent.to_be_bytes());
+    raw[10..14].copy_from_slice(&length.to_le_bytes());
+    raw[14..18].copy_from_slice(&length.to_be_bytes());
+    raw[25] = flags;
+    raw[32] = identifier.len() as u8;
+    raw.extend_from_slice(identifier);
+    if raw.len() & 1 != 0 {
+      raw.push(0);
+    }
+    if let Some(name) = rock_ridge {
+      raw.extend_from_slice(b"NM");
+      raw.push(5 + name.len() as u8);
+      raw.push(1);
+      raw.push(0);
+      raw.extend_from_slice(name);
+    }
+    if raw.len() & 1 != 0 {
+      raw.push(0);
+    }
+    raw[0] = raw.len() as u8;
+    raw
+  }
+
+  #[test]
+  fn iso_names() {
+    let record = DirectoryRecord::parse(&make_record(b"README.TXT;1", 30, 100, 0, None)).unwrap();
+    assert_eq!(record.extent, 30);
+    assert_eq!(record.data_length, 100);
+    assert!(!record.is_directory());
+    assert_eq!(record.get_name(), "README.TXT");
+    assert!(record.name_matches("readme.txt"));
+    assert!(record.fits_short_name());
+    let record = DirectoryRecord::parse(&make_record(b"NOEXT.;1", 31, 0, 0, None)).unwrap();
+    assert_eq!(record.get_name(), "NOEXT");
+    assert_eq!(record.get_short_name(), (*b"NOEXT   ", *b"   "));
+    let record = DirectoryRecord::parse(&make_record(&[0], 20, 2048, FLAG_DIRECTORY, None)).unwrap();
+    assert!(record.is_self());
+    assert!(record.is_directory());
+  }
+
+  #[test]
+  fn rock_ridge_names() {
+    let raw = make_record(b"LONGFILE.TXT;1", 40, 10, 0, Some(b"Long File Name.txt"));
+    let record = DirectoryRecord::parse(&raw).unwrap();
+    assert_eq!(record.get_name(), "Long File Name.txt");
+    assert!(record.name_matches("long file name.TXT"));
+    assert!(record.name_matches("LONGFILE.TXT"));
+    assert!(!record.fits_short_name());
+    assert_eq!(record.get_short_name(), (*b"LONGFILE", *b"TXT"));
+  }
+
+  #[test]
+  fn iterate_records() {
+    let sector_size = 128;
+    let mut data = Vec::new();
+    data.extend_from_slice(&make_record(&[0], 20, 128, FLAG_DIRECTORY, None));
+    data.extend_from_slice(&make_record(b"A.TXT;1", 21, 5, 0, None));
+    // The next record does not fit, so the rest of the sector is padding
+    data.resize(sector_size, 0);
+    data.extend_from_slice(&make_record(b"B.TXT;1", 22, 6, 0, None));
+    data.resize(sector_size * 2, 0);
+
+    let names: Vec<String> = RecordIterator::new(&data, sector_size)
+      .map(|r| r.unwrap())
+      .filter(|r| !r.is_self())
+      .map(|r| r.get_name())
+      .collect();
+    assert_eq!(names, ["A.TXT", "B.TXT"]);
+  }
+
+  #[test]
+  fn truncated_record() {
+    let mut data = make_record(b"A.TXT;1", 21, 5, 0, None);
+    data[0] = 60;
+    let mut iter = RecordIterator::new(&data, 2048);
+    assert!(matches!(iter.next(), Some(Err(IsoError::InvalidRecord))));
+    assert!(iter.next().is_none());
+  }
+}
@@ -0,0 +1,25 @@
+use syscall::result::SystemError;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IsoError {
+  /// The disc does not contain a primary volume descriptor
+  NotIso,
+  /// A directory record or volume descriptor was malformed
+  InvalidRecord,
+  /// Reading from the underlying device failed
+  IOError,
+  /// No directory entry matched the requested name
+  NotFound,
+  /// A path component that should be a directory names a file
+  NotDirectory,
+}
+
+impl IsoError {
+  pub fn to_system_error(&self) -> SystemError {
+    match self {
+      IsoError::NotFound => SystemError::NoSuchEntity,
+      IsoError::NotDirectory => SystemError::NotDirectory,
+      _ => SystemError::IOError,
+    }
+  }
+}
@@ -0,0 +1,312 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use crate::devices;
+use crate::files::cursor::SeekMethod;
+use crate::files::handle::{Handle, HandleAllocator, LocalHandle};
+use crate::task::SleepMutex;
+use spin::RwLock;
+use super::directory::{DirectoryRecord, RecordIterator};
+use super::errors::IsoError;
+use super::volume::{VolumeDescriptor, DESCRIPTOR_SIZE, SYSTEM_AREA_SECTORS};
+use super::super::cache::{self, BlockCache, BlockStore};
+use super::super::capabilities::Capabilities;
+use super::super::filesystem::{FileSystem, FileSystemKind};
+use syscall::files::{DirEntryInfo, DirEntryType, FileStatus, OpenFlags};
+use syscall::result::SystemError;
+
+/// Stop looking for the primary volume descriptor after this many sectors
+const MAX_DESCRIPTORS: usize = 32;
+
+/// Largest directory that will be loaded into memory. Real directories are far
+/// smaller; anything bigger is a corrupt or malicious record.
+const MAX_DIRECTORY_SIZE: usize = 1024 * 1024;
+
+struct OpenFile {
+  pub cursor: usize,
+  pub record: DirectoryRecord,
+}
+
+/// Read-only filesystem for CD-ROM media. Files are stored contiguously, so
+/// reading only requires the extent and length from the directory record.
+/// Multi-extent and interleaved files are not supported.
+pub struct Iso9660FileSystem {
+  handle_allocator: HandleAllocator<LocalHandle>,
+  open_files: RwLock<BTreeMap<LocalHandle, OpenFile>>,
+
+  drive_number: usize,
+  drive_access_handle: LocalHandle,
+
+  block_size: usize,
+  volume_blocks: usize,
+  root: Option<DirectoryRecord>,
+
+  cache: SleepMutex<BlockCache>,
+}
+
+impl Iso9660FileSystem {
+  pub fn new(drive_number: usize, drive_access_handle: LocalHandle) -> Iso9660FileSystem {
+    Iso9660FileSystem {
+      handle_allocator: HandleAllocator::new(),
+      open_files: RwLock::new(BTreeMap::new()),
+
+      drive_number,
+      drive_access_handle,
+
+      block_size: DESCRIPTOR_SIZE,
+      volume_blocks: 0,
+      root: None,
+
+      cache: SleepMutex::new(BlockCache::new(DESCRIPTOR_SIZE, cache::DEFAULT_CAPACITY)),
+    }
+  }
+
+  /// Scan the volume descriptors for the primary descriptor, which locates the
+  /// root directory
+  pub fn init(&mut self) -> Result<(), IsoError> {
+    let driver = devices::get_driver_for_device(self.drive_number).ok_or(IsoError::IOError)?;
+    driver.open(self.drive_access_handle, OpenFlags::read_only()).map_err(|_| IsoError::IOError)?;
+    let mut raw = Vec::with_capacity(DESCRIPTOR_SIZE);
+    raw.resize(DESCRIPTOR_SIZE, 0);
+    for index in 0..MAX_DESCRIPTORS {
+      let position = (SYSTEM_AREA_SECTORS + index) * DESCRIPTOR_SIZE;
+      driver.seek(self.drive_access_handle, SeekMethod::Absolute(position)).map_err(|_| IsoError::IOError)?;
+      let read = driver.read(self.drive_access_handle, &mut raw).map_err(|_| IsoError::IOError)?;
+      if read < DESCRIPTOR_SIZE {
+        return Err(IsoError::NotIso);
+      }
+      match VolumeDescriptor::parse(&raw)? {
+        VolumeDescriptor::Primary(volume) => {
+          self.block_size = volume.block_size;
+          self.volume_blocks = volume.volume_blocks;
+          self.root = Some(volume.root);
+          self.cache.lock().set_block_size(volume.block_size);
+          return Ok(());
+        },
+        VolumeDescriptor::Terminator => return Err(IsoError::NotIso),
+        VolumeDescriptor::Other => (),
+      }
+    }
+    Err(IsoError::NotIso)
+  }
+
+  fn get_root(&self) -> Result<DirectoryRecord, IsoError> {
+    self.root.clone().ok_or(IsoError::NotIso)
+  }
+
+  fn read_block(&self, lba: usize, buffer: &mut [u8]) -> Result<(), IsoError> {
+    self.cache.lock()
+      .read(self, self.drive_number, lba, buffer)
+      .map_err(|_| IsoError::IOError)
+  }
+
+  /// Load the entire contents of a directory. Directories on CDs are rarely
+  /// more than a few sectors long. The length comes straight from the disc, so
+  /// a directory that is implausibly large or runs off the end of the volume
+  /// is rejected before anything is allocated.
+  fn read_directory(&self, dir: &DirectoryRecord) -> Result<Vec<DirectoryRecord>, IsoError> {
+    if !dir.is_directory() {
+      return Err(IsoError::NotDirectory);
+    }
+    if dir.data_length > MAX_DIRECTORY_SIZE {
+      return Err(IsoError::InvalidRecord);
+    }
+    let block_count = dir.data_length
+      .checked_add(self.block_size - 1)
+      .map(|length| length / self.block_size)
+      .ok_or(IsoError::InvalidRecord)?;
+    match dir.extent.checked_add(block_count) {
+      Some(end) if end <= self.volume_blocks => (),
+      _ => return Err(IsoError::InvalidRecord),
+    }
+    let mut data = Vec::with_capacity(block_count * self.block_size);
+    data.resize(block_count * self.block_size, 0);
+    for i in 0..block_count {
+      let start = i * self.block_size;
+      self.read_block(dir.extent + i, &mut data[start..(start + self.block_size)])?;
+    }
+    data.truncate(dir.data_length);
+    RecordIterator::new(&data, self.block_size)
+      .filter(|record| match record {
+        Ok(r) => !r.is_self() && !r.is_parent(),
+        Err(_) => true,
+      })
+      .collect()
+  }
+
+  /// Walk each component of a path, starting at the root directory
+  fn resolve_path(&self, path: &str) -> Result<DirectoryRecord, IsoError> {
+    let mut current = self.get_root()?;
+    for part in path.split("\\").filter(|part| part.len() > 0) {
+      let entries = self.read_directory(&current)?;
+      current = entries.into_iter()
+        .find(|entry| entry.name_matches(part))
+        .ok_or(IsoError::NotFound)?;
+    }
+    Ok(current)
+  }
+
+  fn open_record(&self, record: DirectoryRecord) -> LocalHandle {
+    let handle = self.handle_allocator.get_next();
+    let open_file = OpenFile {
+      cursor: 0,
+      record,
+    };
+    self.open_files.write().insert(handle, open_file);
+    handle
+  }
+}
+
+impl FileSystem for Iso9660FileSystem {
+  fn get_kind(&self) -> FileSystemKind {
+    FileSystemKind::KernelAsync
+  }
+
+  fn get_capabilities(&self) -> Capabilities {
+    Capabilities {
+      seekable: true,
+      directories: true,
+      ..Capabilities::none()
+    }
+  }
+
+  fn open(&self, path: &str, _flags: OpenFlags) -> Result<LocalHandle, ()> {
+    let record = self.resolve_path(path).map_err(|_| ())?;
+    if record.is_directory() {
+      return Err(());
+    }
+    Ok(self.open_record(record))
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let (cursor, extent, byte_size) = {
+      let files = self.open_files.read();
+      let file = files.get(&handle).ok_or(())?;
+      (file.cursor, file.record.extent, file.record.data_length)
+    };
+    if cursor >= byte_size {
+      return Ok(0);
+    }
+    let to_read = buffer.len().min(byte_size - cursor);
+    let mut block = Vec::with_capacity(self.block_size);
+    block.resize(self.block_size, 0);
+    let mut read = 0;
+    while read < to_read {
+      let position = cursor + read;
+      let local_offset = position % self.block_size;
+      let chunk = (self.block_size - local_offset).min(to_read - read);
+      self.read_block(extent + position / self.block_size, &mut block).map_err(|_| ())?;
+      buffer[read..(read + chunk)].copy_from_slice(&block[local_offset..(local_offset + chunk)]);
+      read += chunk;
+    }
+    if let Some(file) = self.open_files.write().get_mut(&handle) {
+      file.cursor += read;
+    }
+    Ok(read)
+  }
+
+  fn write(&self, _handle: LocalHandle, _buffer: &[u8]) -> Result<usize, SystemError> {
+    Err(SystemError::ReadOnlyFileSystem)
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    self.open_files.write().remove(&handle).ok_or(())?;
+    Ok(())
+  }
+
+  fn dup(&self, _handle: LocalHandle) -> Result<LocalHandle, ()> {
+    Err(())
+  }
+
+  fn unmount(&self) -> Result<(), SystemError> {
+    if !self.open_files.read().is_empty() {
+      return Err(SystemError::Busy);
+    }
+    super::super::release_device(self.drive_access_handle);
+    Ok(())
+  }
+
+  fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
+    let mut files = self.open_files.write();
+    let file = files.get_mut(&handle).ok_or(())?;
+    file.cursor = offset.from_current_position(file.cursor);
+    Ok(file.cursor)
+  }
+
+  fn open_dir(&self, path: &str) -> Result<LocalHandle, ()> {
+    let record = self.resolve_path(path).map_err(|_| ())?;
+    if !record.is_directory() {
+      return Err(());
+    }
+    Ok(self.open_record(record))
+  }
+
+  fn read_dir(&self, handle: LocalHandle, index: usize, info: &mut DirEntryInfo) -> Result<(), ()> {
+    let dir = {
+      let files = self.open_files.read();
+      let file = files.get(&handle).ok_or(())?;
+      file.record.clone()
+    };
+    let entries = self.read_directory(&dir).map_err(|_| ())?;
+    match entries.iter().filter(|entry| !entry.is_hidden()).nth(index) {
+      Some(entry) => {
+        let (name, ext) = entry.get_short_name();
+        info.file_name = name;
+        info.file_ext = ext;
+        info.entry_type = if entry.is_directory() {
+          DirEntryType::Directory
+        } else {
+          DirEntryType::File
+        };
+        info.byte_size = entry.data_length;
+        if entry.fits_short_name() {
+          info.set_long_name("");
+        } else {
+          info.set_long_name(&entry.get_name());
+        }
+      },
+      None => {
+        *info = DirEntryInfo::empty();
+      },
+    }
+    Ok(())
+  }
+
+  /// Nothing on the disc can move, so the extent identifies a file
+  fn stat(&self, handle: LocalHandle, status: &mut FileStatus) -> Result<(), ()> {
+    let files = self.open_files.read();
+    let file = files.get(&handle).ok_or(())?;
+    status.file_id = file.record.extent as u32;
+    status.entry_type = if file.record.is_directory() {
+      DirEntryType::Directory
+    } else {
+      DirEntryType::File
+    };
+    status.byte_size = file.record.data_length;
+    Ok(())
+  }
+
+  fn create(&self, _path: &str) -> Result<LocalHandle, SystemError> {
+    Err(SystemError::ReadOnlyFileSystem)
+  }
+
+  fn delete(&self, _path: &str) -> Result<(), SystemError> {
+    Err(SystemError::ReadOnlyFileSystem)
+  }
+}
+
+impl BlockStore for Iso9660FileSystem {
+  fn read_blocks(&self, drive: usize, lba: usize, buffer: &mut [u8]) -> Result<(), ()> {
+    let driver = devices::get_driver_for_device(drive).ok_or(())?;
+    driver.seek(self.drive_access_handle, SeekMethod::Absolute(lba * self.block_size))?;
+    driver.read(self.drive_access_handle, buffer)?;
+    Ok(())
+  }
+
+  fn write_block(&self, _drive: usize, _lba: usize, _buffer: &[u8]) -> Result<(), ()> {
+    Err(())
+  }
+
+  fn block_count(&self, _drive: usize) -> usize {
+    self.volume_blocks
+  }
+}
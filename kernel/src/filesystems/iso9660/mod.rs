@@ -0,0 +1,33 @@
+//! ISO9660 is the read-only filesystem used on CD-ROMs. The disc begins with
+//! 16 reserved sectors, followed by a series of volume descriptors. The
+//! primary volume descriptor contains the directory record for the root
+//! directory, and every other file and directory is reached from there.
+//! Rock Ridge extensions, when present, provide names that are not limited to
+//! the restricted ISO9660 character set.
+
+pub mod directory;
+pub mod errors;
+#[cfg(not(test))]
+pub mod fs;
+pub mod volume;
+
+#[cfg(not(test))]
+use alloc::boxed::Box;
+#[cfg(not(test))]
+use super::FileSystemType;
+#[cfg(not(test))]
+use syscall::files::OpenFlags;
+
+#[cfg(not(test))]
+pub fn create_fs(device: &str) -> Result<Box<FileSystemType>, ()> {
+  let dev_fs = unsafe {
+    super::get_fs(super::DEV_FS).unwrap()
+  };
+  let access_handle = dev_fs.open(device, OpenFlags::read_only())?;
+  let device_no = dev_fs.ioctl(access_handle, 0, 0)? as usize;
+
+  let mut iso_fs = fs::Iso9660FileSystem::new(device_no, access_handle);
+  iso_fs.init().map_err(|_| ())?;
+
+  Ok(Box::new(iso_fs))
+}
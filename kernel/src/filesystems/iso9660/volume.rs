@@ -0,0 +1,105 @@
+use super::directory::{read_u16_le, read_u32_le, DirectoryRecord};
+use super::errors::IsoError;
+
+/// Sectors reserved at the start of the disc, before the volume descriptors
+pub const SYSTEM_AREA_SECTORS: usize = 16;
+/// Volume descriptors always occupy a 2048-byte sector
+pub const DESCRIPTOR_SIZE: usize = 2048;
+
+const STANDARD_IDENTIFIER: &[u8; 5] = b"CD001";
+const TYPE_PRIMARY: u8 = 1;
+const TYPE_TERMINATOR: u8 = 255;
+
+/// Offset of the root directory record within the primary volume descriptor
+const ROOT_RECORD_OFFSET: usize = 156;
+const ROOT_RECORD_SIZE: usize = 34;
+
+pub struct PrimaryVolume {
+  pub volume_id: [u8; 32],
+  /// Number of logical blocks on the volume
+  pub volume_blocks: usize,
+  pub block_size: usize,
+  pub root: DirectoryRecord,
+}
+
+pub enum VolumeDescriptor {
+  Primary(PrimaryVolume),
+  /// Marks the end of the descriptor set
+  Terminator,
+  /// Boot records and supplementary descriptors are not used
+  Other,
+}
+
+impl VolumeDescriptor {
+  pub fn parse(raw: &[u8]) -> Result<VolumeDescriptor, IsoError> {
+    if raw.len() < DESCRIPTOR_SIZE || &raw[1..6] != STANDARD_IDENTIFIER {
+      return Err(IsoError::NotIso);
+    }
+    match raw[0] {
+      TYPE_PRIMARY => {
+        let mut volume_id = [0; 32];
+        volume_id.copy_from_slice(&raw[40..72]);
+        let block_size = read_u16_le(raw, 128) as usize;
+        if block_size == 0 {
+          return Err(IsoError::InvalidRecord);
+        }
+        let root = DirectoryRecord::parse(&raw[ROOT_RECORD_OFFSET..(ROOT_RECORD_OFFSET + ROOT_RECORD_SIZE)])?;
+        Ok(VolumeDescriptor::Primary(PrimaryVolume {
+          volume_id,
+          volume_blocks: read_u32_le(raw, 80) as usize,
+          block_size,
+          root,
+        }))
+      },
+      TYPE_TERMINATOR => Ok(VolumeDescriptor::Terminator),
+      _ => Ok(VolumeDescriptor::Other),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn make_descriptor(descriptor_type: u8) -> [u8; DESCRIPTOR_SIZE] {
+    let mut raw = [0; DESCRIPTOR_SIZE];
+    raw[0] = descriptor_type;
+    raw[1..6].copy_from_slice(STANDARD_IDENTIFIER);
+    raw[6] = 1;
+    raw
+  }
+
+  #[test]
+  fn primary_descriptor() {
+    let mut raw = make_descriptor(TYPE_PRIMARY);
+    raw[40..46].copy_from_slice(b"TESTCD");
+    raw[80..84].copy_from_slice(&300u32.to_le_bytes());
+    raw[128..130].copy_from_slice(&2048u16.to_le_bytes());
+    let root = &mut raw[ROOT_RECORD_OFFSET..(ROOT_RECORD_OFFSET + ROOT_RECORD_SIZE)];
+    root[0] = ROOT_RECORD_SIZE as u8;
+    root[2..6].copy_from_slice(&20u32.to_le_bytes());
+    root[10..14].copy_from_slice(&2048u32.to_le_bytes());
+    root[25] = 2;
+    root[32] = 1;
+    match VolumeDescriptor::parse(&raw).unwrap() {
+      VolumeDescriptor::Primary(volume) => {
+        assert_eq!(&volume.volume_id[..6], b"TESTCD");
+        assert_eq!(volume.volume_blocks, 300);
+        assert_eq!(volume.block_size, 2048);
+        assert_eq!(volume.root.extent, 20);
+        assert!(volume.root.is_directory());
+        assert!(volume.root.is_self());
+      },
+      _ => panic!("Expected primary volume descriptor"),
+    }
+  }
+
+  #[test]
+  fn other_descriptors() {
+    assert!(matches!(VolumeDescriptor::parse(&make_descriptor(TYPE_TERMINATOR)), Ok(VolumeDescriptor::Terminator)));
+    assert!(matches!(VolumeDescriptor::parse(&make_descriptor(0)), Ok(VolumeDescriptor::Other)));
+    let mut raw = make_descriptor(TYPE_PRIMARY);
+    raw[1] = b'X';
+    assert!(matches!(VolumeDescriptor::parse(&raw), Err(IsoError::NotIso)));
+  }
+}
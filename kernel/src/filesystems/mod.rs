@@ -1,15 +1,33 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use crate::files::handle::LocalHandle;
+use crate::task::SleepMutex;
 use spin::RwLock;
+use syscall::files::OpenFlags;
+use syscall::result::SystemError;
 
 #[cfg(not(test))]
 pub mod dev;
 #[cfg(not(test))]
 pub mod init;
+#[cfg(not(test))]
+pub mod io;
 
+pub mod cache;
+pub mod capabilities;
+pub mod dcache;
+pub mod events;
 pub mod fat12;
 pub mod filesystem;
+pub mod iso9660;
+pub mod options;
+pub mod proc;
+pub mod ramfs;
+
+use capabilities::Capabilities;
+use dcache::DirectoryCache;
+use options::MountOptions;
 
 pub type FileSystemType = dyn filesystem::FileSystem + Send + Sync;
 
@@ -25,7 +43,24 @@ impl FileSystemNumber {
   }
 }
 
-pub struct NamedFileSystem(pub Box<str>, pub Arc<Box<FileSystemType>>);
+/// Where a mounted drive came from: the filesystem driver that reads it, and
+/// the device holding its data. Drives that live in memory have no device.
+#[derive(Clone)]
+pub struct MountSource {
+  pub driver: Box<str>,
+  pub device: Option<Box<str>>,
+}
+
+impl MountSource {
+  pub fn new(driver: &str, device: Option<&str>) -> MountSource {
+    MountSource {
+      driver: Box::from(driver),
+      device: device.map(Box::from),
+    }
+  }
+}
+
+pub struct NamedFileSystem(pub Box<str>, pub Arc<Box<FileSystemType>>, pub MountOptions, pub MountSource, pub Capabilities);
 
 impl NamedFileSystem {
   pub fn matches_name(&self, name: &str) -> bool {
@@ -35,41 +70,265 @@ impl NamedFileSystem {
   pub fn get_fs(&self) -> Arc<Box<FileSystemType>> {
     self.1.clone()
   }
+
+  pub fn get_options(&self) -> MountOptions {
+    self.2
+  }
+
+  pub fn get_source(&self) -> MountSource {
+    self.3.clone()
+  }
+
+  pub fn get_capabilities(&self) -> Capabilities {
+    self.4
+  }
 }
 
+/// Table of mounted drives. A drive's number is its index in the table, and is
+/// stored alongside every open handle, so unmounting a drive only empties its
+/// slot. Empty slots are reused by later mounts.
 pub struct FileSystemMap {
-  map: RwLock<Vec<NamedFileSystem>>,
+  /// Every file operation looks up its drive here, so a process that finds
+  /// the table locked sleeps instead of spinning away its time slice
+  map: SleepMutex<Vec<Option<NamedFileSystem>>>,
+  dcache: RwLock<DirectoryCache>,
 }
 
 impl FileSystemMap {
   pub const fn new() -> FileSystemMap {
     FileSystemMap {
-      map: RwLock::new(Vec::new()),
+      map: SleepMutex::new(Vec::new()),
+      dcache: RwLock::new(DirectoryCache::new(dcache::DEFAULT_CAPACITY)),
     }
   }
 
+  /// Register a filesystem with the default mount options
   pub fn register_fs(&self, name: &str, fs: Box<FileSystemType>) -> Result<usize, ()> {
-    let mut map = self.map.write();
-    map.push(NamedFileSystem(Box::from(name), Arc::new(fs)));
-    Ok(map.len() - 1)
+    self.mount_drive(name, fs, MountOptions::default())
+  }
+
+  /// Register a filesystem as a named drive. The options are handed to the
+  /// filesystem before it becomes visible, so that it can reject any it does
+  /// not support.
+  pub fn mount_drive(&self, name: &str, fs: Box<FileSystemType>, options: MountOptions) -> Result<usize, ()> {
+    self.mount(name, fs, options, MountSource::new(name, None)).map_err(|_| ())
+  }
+
+  /// Register a filesystem read from a device, recording where it came from
+  /// in the mount table. Fails if the name is already in use, or if the
+  /// device already backs another drive, unless both are read-only. Two
+  /// drivers writing to the same disk would each overwrite the other's
+  /// changes. A filesystem that is turned away releases its device.
+  pub fn mount(&self, name: &str, fs: Box<FileSystemType>, options: MountOptions, source: MountSource) -> Result<usize, SystemError> {
+    fs.apply_mount_options(&options).map_err(|_| SystemError::InvalidArgument)?;
+    let capabilities = fs.get_capabilities();
+    let index = {
+      let mut map = self.map.lock();
+      let conflict = if map.iter().flatten().any(|entry| entry.matches_name(name)) {
+        Some(SystemError::AlreadyExists)
+      } else if !Self::is_device_available(&map, source.device.as_deref(), options.read_only) {
+        Some(SystemError::Busy)
+      } else {
+        None
+      };
+      if let Some(error) = conflict {
+        drop(map);
+        let _ = fs.unmount();
+        return Err(error);
+      }
+      let entry = NamedFileSystem(Box::from(name), Arc::new(fs), options, source, capabilities);
+      match map.iter().position(|slot| slot.is_none()) {
+        Some(free) => {
+          map[free] = Some(entry);
+          free
+        },
+        None => {
+          map.push(Some(entry));
+          map.len() - 1
+        },
+      }
+    };
+    self.invalidate_drive(index);
+    events::drive_mounted(name);
+    Ok(index)
+  }
+
+  /// Remove a drive from the table. The caller is responsible for making sure
+  /// no process still holds a handle on the drive; the filesystem itself
+  /// refuses if it has files open, and flushes anything held in memory.
+  pub fn unmount_drive(&self, index: usize) -> Result<(), SystemError> {
+    let (name, fs) = {
+      let map = self.map.lock();
+      let entry = map.get(index).and_then(|slot| slot.as_ref()).ok_or(SystemError::NoSuchDrive)?;
+      if entry.get_options().permanent {
+        return Err(SystemError::PermissionDenied);
+      }
+      (entry.0.clone(), entry.get_fs())
+    };
+    fs.unmount()?;
+    self.map.lock()[index] = None;
+    self.invalidate_drive(index);
+    events::drive_unmounted(&name);
+    Ok(())
+  }
+
+  /// Number of slots in the table, including empty ones left by unmounting
+  pub fn get_slot_count(&self) -> usize {
+    self.map.lock().len()
   }
 
   pub fn get_fs_number(&self, name: &str) -> Option<usize> {
-    let map = self.map.read();
-    let mut index = 0;
-    for entry in map.iter() {
-      if entry.matches_name(name) {
-        return Some(index);
+    let map = self.map.lock();
+    map.iter().position(|slot| match slot {
+      Some(entry) => entry.matches_name(name),
+      None => false,
+    })
+  }
+
+  /// Find the drive backed by a device, if any
+  pub fn get_fs_number_for_device(&self, device: &str) -> Option<usize> {
+    let map = self.map.lock();
+    map.iter().position(|slot| match slot {
+      Some(entry) => entry.3.device.as_deref() == Some(device),
+      None => false,
+    })
+  }
+
+  /// Whether a device could be mounted as another drive. Drives in memory
+  /// have no device and never conflict.
+  pub fn can_mount_device(&self, device: &str, read_only: bool) -> bool {
+    let map = self.map.lock();
+    Self::is_device_available(&map, Some(device), read_only)
+  }
+
+  fn is_device_available(map: &Vec<Option<NamedFileSystem>>, device: Option<&str>, read_only: bool) -> bool {
+    let device = match device {
+      Some(device) => device,
+      None => return true,
+    };
+    map.iter().flatten().all(|entry| {
+      entry.3.device.as_deref() != Some(device) || (read_only && entry.get_options().read_only)
+    })
+  }
+
+  fn with_entry<R, F: FnOnce(&NamedFileSystem) -> R>(&self, index: usize, f: F) -> Option<R> {
+    let map = self.map.lock();
+    let entry = map.get(index)?.as_ref()?;
+    Some(f(entry))
+  }
+
+  pub fn get_fs(&self, index: usize) -> Option<Arc<Box<FileSystemType>>> {
+    self.with_entry(index, |entry| entry.get_fs())
+  }
+
+  pub fn get_mount_options(&self, index: usize) -> Option<MountOptions> {
+    self.with_entry(index, |entry| entry.get_options())
+  }
+
+  pub fn get_mount_source(&self, index: usize) -> Option<MountSource> {
+    self.with_entry(index, |entry| entry.get_source())
+  }
+
+  pub fn get_capabilities(&self, index: usize) -> Option<Capabilities> {
+    self.with_entry(index, |entry| entry.get_capabilities())
+  }
+
+  /// Confirm that a drive can be modified: it must not be mounted read-only,
+  /// and its filesystem must support writing at all
+  pub fn check_writable(&self, index: usize) -> Result<(), SystemError> {
+    let (options, capabilities) = self
+      .with_entry(index, |entry| (entry.get_options(), entry.get_capabilities()))
+      .ok_or(SystemError::NoSuchFileSystem)?;
+    if options.read_only {
+      return Err(SystemError::ReadOnlyFileSystem);
+    }
+    capabilities.require_write()
+  }
+
+  /**
+   * Open a path on a drive, using the directory cache to skip the lookup when
+   * the path has been resolved before. If the cached entry can no longer be
+   * opened, it is discarded and the path is resolved again.
+   */
+  pub fn open_path(&self, index: usize, path: &str, flags: OpenFlags) -> Result<LocalHandle, ()> {
+    let fs = self.get_fs(index).ok_or(())?;
+    let cached = self.dcache.write().get(index, path);
+    if let Some(entry) = cached {
+      match fs.open_entry(entry, flags) {
+        Ok(handle) => return Ok(handle),
+        Err(_) => self.dcache.write().invalidate(index, path),
       }
-      index += 1;
     }
-    None
+    match fs.lookup(path) {
+      Ok(entry) => {
+        let handle = fs.open_entry(entry, flags)?;
+        self.dcache.write().insert(index, path, entry);
+        Ok(handle)
+      },
+      // Filesystems without lookup support are always opened by path
+      Err(_) => fs.open(path, flags),
+    }
   }
 
-  pub fn get_fs(&self, index: usize) -> Option<Arc<Box<FileSystemType>>> {
-    let map = self.map.read();
-    let entry = map.get(index)?;
-    Some(entry.get_fs())
+  /// Drop any cached lookup of a path, called whenever a file is created or
+  /// removed
+  pub fn invalidate_path(&self, index: usize, path: &str) {
+    self.dcache.write().invalidate(index, path);
+  }
+
+  pub fn invalidate_drive(&self, index: usize) {
+    self.dcache.write().invalidate_drive(index);
+  }
+
+  pub fn get_drive_name(&self, index: usize) -> Option<Box<str>> {
+    self.with_entry(index, |entry| entry.0.clone())
+  }
+
+  /// Called when a removable drive reports new media. Open handles on the
+  /// drive become stale, and it refuses new work until remounted.
+  pub fn media_changed(&self, index: usize) {
+    let fs = match self.get_fs(index) {
+      Some(fs) => fs,
+      None => return,
+    };
+    self.invalidate_drive(index);
+    fs.media_changed();
+    if let Some(name) = self.get_drive_name(index) {
+      events::media_changed(&name);
+    }
+  }
+
+  /// Make a drive usable again after a media change, by having its filesystem
+  /// read the new disk
+  pub fn remount_drive(&self, index: usize) -> Result<(), ()> {
+    let fs = self.get_fs(index).ok_or(())?;
+    fs.remount()?;
+    self.invalidate_drive(index);
+    if let Some(name) = self.get_drive_name(index) {
+      events::drive_mounted(&name);
+    }
+    Ok(())
+  }
+
+  /// Remount every drive, discarding all cached lookups. Drives that fail to
+  /// remount, like an empty floppy drive, are left as they are.
+  pub fn remount_all(&self) {
+    let count = self.map.lock().len();
+    for index in 0..count {
+      self.invalidate_drive(index);
+      let _ = self.remount_drive(index);
+    }
+  }
+
+  /// Write every drive's cached data back to its device, ignoring drives that
+  /// fail, so that as much as possible is saved
+  pub fn sync_all(&self) {
+    let count = self.map.lock().len();
+    for index in 0..count {
+      if let Some(fs) = self.get_fs(index) {
+        let _ = fs.sync();
+      }
+    }
   }
 }
 
@@ -77,6 +336,7 @@ pub static VFS: FileSystemMap = FileSystemMap::new();
 
 pub static mut DEV_FS: usize = 0;
 pub static mut PIPE_FS: usize = 0;
+pub static mut ANON_FS: usize = 0;
 
 pub fn get_fs_number(name: &str) -> Option<usize> {
   VFS.get_fs_number(name)
@@ -86,14 +346,60 @@ pub fn get_fs(index: usize) -> Option<Arc<Box<FileSystemType>>> {
   VFS.get_fs(index)
 }
 
+pub fn get_mount_options(index: usize) -> Option<MountOptions> {
+  VFS.get_mount_options(index)
+}
+
+pub fn get_capabilities(index: usize) -> Option<Capabilities> {
+  VFS.get_capabilities(index)
+}
+
+pub fn check_writable(index: usize) -> Result<(), SystemError> {
+  VFS.check_writable(index)
+}
+
+pub fn open_path(index: usize, path: &str, flags: OpenFlags) -> Result<LocalHandle, ()> {
+  VFS.open_path(index, path, flags)
+}
+
+pub fn invalidate_path(index: usize, path: &str) {
+  VFS.invalidate_path(index, path)
+}
+
 #[cfg(not(test))]
 pub fn init_fs() {
   let dev_fs = dev::DevFileSystem::new();
-  let dev_number = VFS.register_fs("DEV", Box::new(dev_fs)).expect("Failed to register DEV FS");
+  let dev_number = VFS.mount_drive("DEV", Box::new(dev_fs), MountOptions::permanent(false)).expect("Failed to register DEV FS");
   let pipe_fs = crate::pipes::create_fs();
-  let pipe_number = VFS.register_fs("PIPE", pipe_fs).expect("Failed to register PIPE FS");
+  let pipe_number = VFS.mount_drive("PIPE", pipe_fs, MountOptions::permanent(false)).expect("Failed to register PIPE FS");
+  VFS.register_fs("TMP", ramfs::create_fs()).expect("Failed to register TMP FS");
+  let anon_number = VFS.mount_drive("ANON", ramfs::create_anonymous_fs(), MountOptions::permanent(false)).expect("Failed to register ANON FS");
+  VFS.mount_drive("PROC", proc::create_fs(), MountOptions::permanent(true)).expect("Failed to register PROC FS");
   unsafe {
     PIPE_FS = pipe_number;
+    ANON_FS = anon_number;
     DEV_FS = dev_number;
   }
 }
+
+/// Close the handle that a filesystem opened on its backing device through
+/// the DEV: drive
+#[cfg(not(test))]
+pub fn release_device(handle: LocalHandle) {
+  let dev_fs = unsafe { get_fs(DEV_FS) };
+  if let Some(dev_fs) = dev_fs {
+    let _ = dev_fs.close(handle);
+  }
+}
+
+/// Create a filesystem from the data on a device, using the driver with the
+/// given name. The device is named as it appears on the DEV: drive.
+#[cfg(not(test))]
+pub fn create_for_device(driver: &str, device: &str) -> Result<Box<FileSystemType>, SystemError> {
+  let created = match driver {
+    "FAT" | "FAT12" => fat12::create_fs(device),
+    "ISO9660" | "CDFS" => iso9660::create_fs(device),
+    _ => return Err(SystemError::NoSuchFileSystem),
+  };
+  created.map_err(|_| SystemError::IOError)
+}
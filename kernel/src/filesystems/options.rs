@@ -0,0 +1,184 @@
+use syscall::files::{MOUNT_FLAG_NO_ATIME, MOUNT_FLAG_PERMANENT, MOUNT_FLAG_READ_ONLY, MOUNT_FLAG_SYNC};
+
+/// Code pages determine how extended (non-ASCII) characters in short file names
+/// are interpreted. DOS stores 8.3 names in upper case, so the main job of the
+/// code page is to know which lower case characters map to which upper case
+/// ones when translating a user-provided name.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CodePage {
+  /// Original IBM PC character set
+  CP437,
+  /// Multilingual Latin 1
+  CP850,
+}
+
+impl CodePage {
+  /// Convert a single character to its upper case form in this code page
+  pub fn to_upper(&self, ch: u8) -> u8 {
+    if ch >= b'a' && ch <= b'z' {
+      return ch - 32;
+    }
+    let shared = match ch {
+      0x81 => 0x9a, // u umlaut
+      0x82 => 0x90, // e acute
+      0x84 => 0x8e, // a umlaut
+      0x86 => 0x8f, // a ring
+      0x87 => 0x80, // c cedilla
+      0x91 => 0x92, // ae
+      0x94 => 0x99, // o umlaut
+      0xa4 => 0xa5, // n tilde
+      _ => ch,
+    };
+    if shared != ch {
+      return shared;
+    }
+    match self {
+      CodePage::CP437 => ch,
+      CodePage::CP850 => match ch {
+        0x83 => 0xb6, // a circumflex
+        0x85 => 0xb7, // a grave
+        0x88 => 0xd2, // e circumflex
+        0x89 => 0xd3, // e umlaut
+        0x8a => 0xd4, // e grave
+        0x8b => 0xd8, // i umlaut
+        0x8c => 0xd7, // i circumflex
+        0x8d => 0xde, // i grave
+        0x93 => 0xe2, // o circumflex
+        0x95 => 0xe3, // o grave
+        0x96 => 0xea, // u circumflex
+        0x97 => 0xeb, // u grave
+        0x9b => 0x9d, // o slash
+        0xa0 => 0xb5, // a acute
+        0xa1 => 0xd6, // i acute
+        0xa2 => 0xe0, // o acute
+        0xa3 => 0xe9, // u acute
+        0xc6 => 0xc7, // a tilde
+        0xe4 => 0xe5, // o tilde
+        0xec => 0xed, // y acute
+        _ => ch,
+      },
+    }
+  }
+
+  /// Upper-case every character of a name in place
+  pub fn translate_name(&self, name: &mut [u8]) {
+    for ch in name.iter_mut() {
+      *ch = self.to_upper(*ch);
+    }
+  }
+}
+
+/// Options provided when a drive is mounted. The VFS enforces the options it
+/// can (like read-only), and passes the full set to the filesystem so that
+/// drivers can honor the rest.
+#[derive(Copy, Clone, Debug)]
+pub struct MountOptions {
+  /// Reject all write operations on the drive
+  pub read_only: bool,
+  /// Do not update access dates when files are read
+  pub no_atime: bool,
+  /// Code page used to translate file names
+  pub codepage: CodePage,
+  /// Flush each write to the underlying device before returning, rather than
+  /// holding dirty data in memory. Useful for removable media like floppies.
+  pub sync: bool,
+  /// Refuse to unmount the drive. Set for drives the kernel itself relies on.
+  pub permanent: bool,
+}
+
+impl MountOptions {
+  pub const fn new() -> MountOptions {
+    MountOptions {
+      read_only: false,
+      no_atime: false,
+      codepage: CodePage::CP437,
+      sync: false,
+      permanent: false,
+    }
+  }
+
+  pub const fn read_only() -> MountOptions {
+    MountOptions {
+      read_only: true,
+      no_atime: true,
+      codepage: CodePage::CP437,
+      sync: false,
+      permanent: false,
+    }
+  }
+
+  /// Options for drives created by the kernel at boot, which must stay mounted
+  pub fn permanent(read_only: bool) -> MountOptions {
+    let mut options = if read_only {
+      MountOptions::read_only()
+    } else {
+      MountOptions::new()
+    };
+    options.permanent = true;
+    options
+  }
+
+  /// The options as reported in the mount table, using the MOUNT_FLAG values
+  pub fn get_flags(&self) -> u32 {
+    let mut flags = 0;
+    if self.read_only {
+      flags |= MOUNT_FLAG_READ_ONLY;
+    }
+    if self.permanent {
+      flags |= MOUNT_FLAG_PERMANENT;
+    }
+    if self.sync {
+      flags |= MOUNT_FLAG_SYNC;
+    }
+    if self.no_atime {
+      flags |= MOUNT_FLAG_NO_ATIME;
+    }
+    flags
+  }
+}
+
+impl Default for MountOptions {
+  fn default() -> MountOptions {
+    MountOptions::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{CodePage, MountOptions};
+  use syscall::files::{MOUNT_FLAG_NO_ATIME, MOUNT_FLAG_PERMANENT, MOUNT_FLAG_READ_ONLY};
+
+  #[test]
+  fn ascii_upper_case() {
+    assert_eq!(CodePage::CP437.to_upper(b'a'), b'A');
+    assert_eq!(CodePage::CP850.to_upper(b'z'), b'Z');
+    assert_eq!(CodePage::CP437.to_upper(b'Q'), b'Q');
+    assert_eq!(CodePage::CP437.to_upper(b'~'), b'~');
+  }
+
+  #[test]
+  fn extended_upper_case() {
+    assert_eq!(CodePage::CP437.to_upper(0x81), 0x9a);
+    assert_eq!(CodePage::CP850.to_upper(0x81), 0x9a);
+    // a acute has no upper case form in 437
+    assert_eq!(CodePage::CP437.to_upper(0xa0), 0xa0);
+    assert_eq!(CodePage::CP850.to_upper(0xa0), 0xb5);
+  }
+
+  #[test]
+  fn translate_whole_name() {
+    let mut name = [b'r', b'e', b'a', b'd', b'm', b'e', b' ', b' '];
+    CodePage::CP437.translate_name(&mut name);
+    assert_eq!(name, [b'R', b'E', b'A', b'D', b'M', b'E', b' ', b' ']);
+  }
+
+  #[test]
+  fn flags_for_mount_table() {
+    assert_eq!(MountOptions::new().get_flags(), 0);
+    assert_eq!(MountOptions::read_only().get_flags(), MOUNT_FLAG_READ_ONLY | MOUNT_FLAG_NO_ATIME);
+    assert_eq!(
+      MountOptions::permanent(true).get_flags(),
+      MOUNT_FLAG_READ_ONLY | MOUNT_FLAG_NO_ATIME | MOUNT_FLAG_PERMANENT,
+    );
+  }
+}
@@ -0,0 +1,190 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use crate::files::cursor::SeekMethod;
+use crate::files::handle::{HandleAllocator, LocalHandle};
+use crate::process::{self, id::ProcessID};
+use spin::RwLock;
+use super::generate::generate;
+use super::path::{pid_to_name, ProcPath, PROCESS_FILES, SYSTEM_FILES};
+use super::super::capabilities::Capabilities;
+use super::super::filesystem::FileSystem;
+use syscall::files::{DirEntryInfo, DirEntryType, FileStatus, OpenFlags};
+use syscall::result::SystemError;
+
+enum OpenEntry {
+  /// Contents are captured when the file is opened
+  File { contents: Vec<u8>, cursor: usize },
+  Directory(ProcPath),
+}
+
+pub struct ProcFileSystem {
+  handle_allocator: HandleAllocator<LocalHandle>,
+  open_entries: RwLock<BTreeMap<LocalHandle, OpenEntry>>,
+}
+
+impl ProcFileSystem {
+  pub fn new() -> ProcFileSystem {
+    ProcFileSystem {
+      handle_allocator: HandleAllocator::new(),
+      open_entries: RwLock::new(BTreeMap::new()),
+    }
+  }
+
+  fn insert_entry(&self, entry: OpenEntry) -> LocalHandle {
+    let handle = self.handle_allocator.get_next();
+    self.open_entries.write().insert(handle, entry);
+    handle
+  }
+}
+
+fn process_exists(pid: u32) -> bool {
+  process::all_processes().get_process(ProcessID::new(pid)).is_some()
+}
+
+/// Names longer than the 8.3 field are truncated there, and reported in full
+/// as the long name
+fn set_name(info: &mut DirEntryInfo, name: &[u8]) {
+  info.file_name = [0x20; 8];
+  info.file_ext = [0x20; 3];
+  for (slot, ch) in info.file_name.iter_mut().zip(name.iter()) {
+    *slot = *ch;
+  }
+  let long_name = if name.len() > 8 {
+    core::str::from_utf8(name).unwrap_or("")
+  } else {
+    ""
+  };
+  info.set_long_name(long_name);
+}
+
+impl FileSystem for ProcFileSystem {
+  fn get_capabilities(&self) -> Capabilities {
+    Capabilities {
+      seekable: true,
+      directories: true,
+      ..Capabilities::none()
+    }
+  }
+
+  fn open(&self, path: &str, _flags: OpenFlags) -> Result<LocalHandle, ()> {
+    let proc_path = ProcPath::parse(path).ok_or(())?;
+    let contents = generate(proc_path).ok_or(())?.into_bytes();
+    Ok(self.insert_entry(OpenEntry::File { contents, cursor: 0 }))
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let mut entries = self.open_entries.write();
+    match entries.get_mut(&handle) {
+      Some(OpenEntry::File { contents, cursor }) => {
+        let start = (*cursor).min(contents.len());
+        let length = buffer.len().min(contents.len() - start);
+        buffer[..length].copy_from_slice(&contents[start..(start + length)]);
+        *cursor = start + length;
+        Ok(length)
+      },
+      _ => Err(()),
+    }
+  }
+
+  fn write(&self, _handle: LocalHandle, _buffer: &[u8]) -> Result<usize, SystemError> {
+    Err(SystemError::ReadOnlyFileSystem)
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    self.open_entries.write().remove(&handle).map(|_| ()).ok_or(())
+  }
+
+  fn dup(&self, handle: LocalHandle) -> Result<LocalHandle, ()> {
+    let copy = match self.open_entries.read().get(&handle).ok_or(())? {
+      OpenEntry::File { contents, cursor } => OpenEntry::File {
+        contents: contents.clone(),
+        cursor: *cursor,
+      },
+      OpenEntry::Directory(path) => OpenEntry::Directory(*path),
+    };
+    Ok(self.insert_entry(copy))
+  }
+
+  fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
+    let mut entries = self.open_entries.write();
+    match entries.get_mut(&handle) {
+      Some(OpenEntry::File { cursor, .. }) => {
+        *cursor = offset.from_current_position(*cursor);
+        Ok(*cursor)
+      },
+      _ => Err(()),
+    }
+  }
+
+  fn open_dir(&self, path: &str) -> Result<LocalHandle, ()> {
+    let proc_path = ProcPath::parse(path).ok_or(())?;
+    match proc_path {
+      ProcPath::Root => (),
+      ProcPath::ProcessDir(pid) if process_exists(pid) => (),
+      _ => return Err(()),
+    }
+    Ok(self.insert_entry(OpenEntry::Directory(proc_path)))
+  }
+
+  fn read_dir(&self, handle: LocalHandle, index: usize, info: &mut DirEntryInfo) -> Result<(), ()> {
+    let dir = match self.open_entries.read().get(&handle) {
+      Some(OpenEntry::Directory(path)) => *path,
+      _ => return Err(()),
+    };
+    *info = DirEntryInfo::empty();
+    match dir {
+      ProcPath::Root => {
+        if let Some((name, _)) = SYSTEM_FILES.get(index) {
+          set_name(info, name.as_bytes());
+          info.entry_type = DirEntryType::File;
+          return Ok(());
+        }
+        let pid = process::all_processes().iter()
+          .map(|(id, _)| id.as_u32())
+          .nth(index - SYSTEM_FILES.len());
+        if let Some(pid) = pid {
+          set_name(info, &pid_to_name(pid));
+          info.entry_type = DirEntryType::Directory;
+        }
+      },
+      ProcPath::ProcessDir(_) => {
+        if let Some((name, _)) = PROCESS_FILES.get(index) {
+          set_name(info, name.as_bytes());
+          info.entry_type = DirEntryType::File;
+        }
+      },
+      _ => return Err(()),
+    }
+    Ok(())
+  }
+
+  fn stat(&self, handle: LocalHandle, status: &mut FileStatus) -> Result<(), ()> {
+    match self.open_entries.read().get(&handle).ok_or(())? {
+      OpenEntry::File { contents, .. } => {
+        status.entry_type = DirEntryType::File;
+        status.byte_size = contents.len();
+      },
+      OpenEntry::Directory(_) => {
+        status.entry_type = DirEntryType::Directory;
+        status.byte_size = 0;
+      },
+    }
+    Ok(())
+  }
+
+  fn create(&self, _path: &str) -> Result<LocalHandle, SystemError> {
+    Err(SystemError::ReadOnlyFileSystem)
+  }
+
+  fn truncate(&self, _handle: LocalHandle, _length: usize) -> Result<(), SystemError> {
+    Err(SystemError::ReadOnlyFileSystem)
+  }
+
+  fn delete(&self, _path: &str) -> Result<(), SystemError> {
+    Err(SystemError::ReadOnlyFileSystem)
+  }
+
+  fn mkdir(&self, _path: &str) -> Result<(), SystemError> {
+    Err(SystemError::ReadOnlyFileSystem)
+  }
+}
@@ -0,0 +1,316 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use crate::files::filename;
+use crate::hardware::cpu;
+use crate::files::handle::Handle;
+use crate::interrupts::{controller, latency};
+use crate::memory::address::VirtualAddress;
+use crate::memory::{physical, pressure};
+use crate::memory::virt::page_directory;
+use crate::memory::virt::page_entry::{self, PageTableEntry};
+use crate::memory::virt::region::{Permissions, VirtualMemoryRegion};
+use crate::process::{self, id::ProcessID, process_state::{ProcessState, RunState}, subsystem::Subsystem};
+use crate::time;
+use super::path::{ProcPath, ProcessFile};
+use super::super::VFS;
+
+/// Produce the current contents of a file. Returns None if the path is a
+/// directory, or refers to a process that no longer exists.
+pub fn generate(path: ProcPath) -> Option<String> {
+  let mut out = String::new();
+  let result = match path {
+    ProcPath::MemInfo => write_meminfo(&mut out),
+    ProcPath::Uptime => write_uptime(&mut out),
+    ProcPath::Mounts => write_mounts(&mut out),
+    ProcPath::CpuInfo => write_cpuinfo(&mut out),
+    ProcPath::IrqLatency => write_irqlat(&mut out),
+    ProcPath::Process(pid, file) => {
+      let process = process::all_processes().get_process(ProcessID::new(pid))?.clone();
+      match file {
+        ProcessFile::Status => write_status(&mut out, &process),
+        ProcessFile::Handles => write_handles(&mut out, &process),
+        ProcessFile::Maps => write_maps(&mut out, &process),
+        ProcessFile::CmdLine => writeln!(out, "{}", process.get_command_line()),
+        ProcessFile::PageTables => write_pagetables(&mut out, &process),
+      }
+    },
+    ProcPath::Root | ProcPath::ProcessDir(_) => return None,
+  };
+  result.ok()?;
+  Some(out)
+}
+
+fn write_meminfo(out: &mut String) -> fmt::Result {
+  writeln!(out, "Total: {} KiB", physical::get_usable_frame_count() * 4)?;
+  writeln!(out, "Free: {} KiB", physical::get_free_frame_count() * 4)?;
+  writeln!(out, "Pressure: {:?}", pressure::get_level())?;
+  writeln!(out, "KernelHeap: {} KiB", process::memory::get_kernel_heap_size() / 1024)
+}
+
+fn write_cpuinfo(out: &mut String) -> fmt::Result {
+  let info = cpu::get_info();
+  writeln!(out, "Vendor: {}", info.get_vendor())?;
+  writeln!(out, "Family: {}", info.get_family())?;
+  writeln!(out, "Model: {}", info.get_model())?;
+  writeln!(out, "Stepping: {}", info.get_stepping())?;
+  write!(out, "Features:")?;
+  for feature in cpu::ALL_FEATURES.iter() {
+    if info.has_feature(*feature) {
+      write!(out, " {}", feature.get_name())?;
+    }
+  }
+  writeln!(out)?;
+  match cpu::get_tsc_khz() {
+    Some(khz) => writeln!(out, "TSC: {} kHz", khz),
+    None => Ok(()),
+  }
+}
+
+/// One line per IRQ that has fired: its number and vector, how many times it
+/// was measured, the average and worst times in nanoseconds, and the
+/// histogram buckets in order
+fn write_irqlat(out: &mut String) -> fmt::Result {
+  if cpu::get_tsc_khz().is_none() {
+    return writeln!(out, "No TSC, latency is not measured");
+  }
+  for (irq, line) in latency::get_stats().iter().enumerate() {
+    if line.count == 0 {
+      continue;
+    }
+    write!(
+      out,
+      "IRQ{} {:#04x}: count {} avg {}ns worst {}ns histogram",
+      irq,
+      controller::IRQ_BASE_VECTOR as usize + irq,
+      line.count,
+      line.get_average_ns(),
+      line.worst_ns,
+    )?;
+    for bucket in line.histogram.iter() {
+      write!(out, " {}", bucket)?;
+    }
+    writeln!(out)?;
+  }
+  Ok(())
+}
+
+/// One line per drive: its name, driver, device, whether it was mounted
+/// read-only, the number of handles open on it, and the capabilities its
+/// filesystem reported
+fn write_mounts(out: &mut String) -> fmt::Result {
+  for index in 0..VFS.get_slot_count() {
+    let name = match VFS.get_drive_name(index) {
+      Some(name) => name,
+      None => continue,
+    };
+    let read_only = VFS.get_mount_options(index).map(|o| o.read_only).unwrap_or(false);
+    let source = VFS.get_mount_source(index);
+    let driver = source.as_ref().map(|s| s.driver.as_ref()).unwrap_or("");
+    let device = source.as_ref().and_then(|s| s.device.as_deref()).unwrap_or("-");
+    let capabilities = VFS.get_capabilities(index).unwrap_or_default();
+    let open_handles = process::all_processes().count_drive_handles(index);
+    writeln!(out, "{}: {} {} {} {} {}", name, driver, device, if read_only { "ro" } else { "rw" }, open_handles, capabilities)?;
+  }
+  Ok(())
+}
+
+fn write_status(out: &mut String, process: &ProcessState) -> fmt::Result {
+  let state = match process.get_run_state() {
+    RunState::Running | RunState::Resumed(_) => "Running",
+    RunState::Sleeping(_) => "Sleeping",
+    RunState::Paused => "Paused",
+    RunState::Blocked(_) => "Blocked",
+    RunState::Terminated => "Terminated",
+  };
+  let subsystem = match *process.get_subsystem().read() {
+    Subsystem::Native => "Native",
+    Subsystem::DOS(_) => "DOS",
+  };
+  let command_line = process.get_command_line();
+  writeln!(out, "Name: {}", filename::program_name(&command_line))?;
+  writeln!(out, "Pid: {}", process.get_id().as_u32())?;
+  writeln!(out, "Parent: {}", process.get_parent().as_u32())?;
+  writeln!(out, "State: {}", state)?;
+  writeln!(out, "Subsystem: {}", subsystem)?;
+  let cpu = process.get_cpu_usage().get_permille();
+  writeln!(out, "Cpu: {}.{}%", cpu / 10, cpu % 10)?;
+  writeln!(out, "ExitCode: {}", process.get_exit_code())
+}
+
+/// Seconds since boot, with hundredths, followed by the boot time on the wall
+/// clock so that logs from different boots can be lined up
+fn write_uptime(out: &mut String) -> fmt::Result {
+  let uptime = time::system::get_uptime();
+  let hundredths = (uptime.in_ms() / 10) % 100;
+  writeln!(out, "{}.{:02}", uptime.in_seconds(), hundredths)?;
+  let boot = time::system::to_local_datetime(time::system::get_boot_time().to_timestamp());
+  writeln!(out, "Booted: {} {}", boot.date, boot.time)
+}
+
+/// One line per handle: the process's handle number, whether it is a file or
+/// directory, the drive and filesystem handle it refers to, and the path it
+/// was opened by when one is known
+fn write_handles(out: &mut String, process: &ProcessState) -> fmt::Result {
+  let maps = [
+    ("file", process.get_open_files()),
+    ("dir", process.get_open_directories()),
+  ];
+  for (kind, map) in maps.iter() {
+    let map = map.read();
+    for (handle, pair) in map.iter() {
+      let drive = VFS.get_drive_name(pair.0);
+      let drive_name = drive.as_ref().map(|name| name.as_ref()).unwrap_or("?");
+      write!(out, "{} {} {}:{}", handle.as_u32(), kind, drive_name, pair.1.as_u32())?;
+      match map.get_path(handle) {
+        Some(path) => writeln!(out, " {}", path)?,
+        None => writeln!(out)?,
+      }
+    }
+  }
+  Ok(())
+}
+
+fn write_region(out: &mut String, name: &str, region: &VirtualMemoryRegion) -> fmt::Result {
+  if region.get_size() == 0 {
+    return Ok(());
+  }
+  let start = region.get_starting_address_as_usize();
+  let end = start + region.get_size() - 1;
+  let permissions = match region.get_permissions() {
+    Permissions::ReadOnly => "r-",
+    Permissions::ReadWrite => "rw",
+    Permissions::CopyOnWrite => "rc",
+  };
+  writeln!(out, "{:08x}-{:08x} {} {}", start, end, permissions, name)
+}
+
+fn write_maps(out: &mut String, process: &ProcessState) -> fmt::Result {
+  let regions = process.get_memory_regions().read();
+  for region in regions.execution_regions.iter() {
+    write_region(out, "exec", region)?;
+  }
+  write_region(out, "heap", &regions.heap_region)?;
+  write_region(out, "stack", &regions.stack_region)?;
+  write_region(out, "kernel-exec", &regions.kernel_exec_region)?;
+  write_region(out, "kernel-stack", &regions.kernel_stack_region)
+}
+
+/// A page of a process's address space, as the hardware sees it, next to the
+/// region the kernel believes it belongs to
+#[derive(Copy, Clone, Eq, PartialEq)]
+struct PageLabel {
+  user: bool,
+  write: bool,
+  accessed: bool,
+  dirty: bool,
+  region: &'static str,
+  problem: Option<&'static str>,
+}
+
+fn label_page(regions: &[(&'static str, VirtualMemoryRegion)], vaddr: VirtualAddress, dir_entry: PageTableEntry, entry: PageTableEntry) -> PageLabel {
+  // The hardware requires both levels to grant an access
+  let user = dir_entry.is_user_access_granted() && entry.is_user_access_granted();
+  let write = dir_entry.is_write_access_granted() && entry.is_write_access_granted();
+  let kernel_half = vaddr.as_usize() >= 0xc0000000;
+  let owner = regions.iter().find(|(_, region)| region.contains_address(vaddr));
+  let problem = match owner {
+    None if !kernel_half => Some("no region"),
+    Some((_, region)) if region.get_permissions() == Permissions::ReadOnly && write => Some("writable"),
+    _ if kernel_half && user => Some("user access to kernel"),
+    Some(_) if !kernel_half && !user => Some("no user access"),
+    _ => None,
+  };
+  PageLabel {
+    user,
+    write,
+    accessed: entry.has_been_accessed(),
+    dirty: entry.get_flags() & page_entry::ENTRY_DIRTY != 0,
+    region: owner.map(|(name, _)| *name).unwrap_or("-"),
+    problem,
+  }
+}
+
+/// Consecutive pages backed by consecutive frames, with the same label
+struct PageRun {
+  start: usize,
+  frame: usize,
+  pages: usize,
+  label: PageLabel,
+}
+
+fn write_page_run(out: &mut String, run: &PageRun) -> fmt::Result {
+  let label = &run.label;
+  let end = run.start + run.pages * 0x1000 - 1;
+  write!(
+    out,
+    "{:08x}-{:08x} {:08x} {}{}{}{} {}",
+    run.start,
+    end,
+    run.frame,
+    if label.user { 'u' } else { 'k' },
+    if label.write { 'w' } else { '-' },
+    if label.accessed { 'a' } else { '-' },
+    if label.dirty { 'd' } else { '-' },
+    label.region,
+  )?;
+  match label.problem {
+    Some(problem) => writeln!(out, " !{}", problem),
+    None => writeln!(out),
+  }
+}
+
+/// Every page mapped by the process's page directory, as runs of virtual
+/// addresses, the first physical frame, the effective flags, and the region
+/// that owns them. Mappings that disagree with the process's regions are
+/// marked with a '!' and counted at the end.
+fn write_pagetables(out: &mut String, process: &ProcessState) -> fmt::Result {
+  // Kernel threads run in the kernel process's directory, so its regions are
+  // the ones to check against, along with the thread's own stack
+  let mut regions = Vec::new();
+  let owner = if process.is_kernel_thread() {
+    process::all_processes().get_process(ProcessID::new(0)).cloned()
+  } else {
+    None
+  };
+  if owner.is_some() {
+    regions.push(("thread-stack", process.get_memory_regions().read().kernel_stack_region));
+  }
+  {
+    let memory = owner.as_deref().unwrap_or(process).get_memory_regions().read();
+    for region in memory.execution_regions.iter() {
+      regions.push(("exec", *region));
+    }
+    regions.push(("heap", memory.heap_region));
+    regions.push(("stack", memory.stack_region));
+    regions.push(("kernel-exec", memory.kernel_exec_region));
+    regions.push(("kernel-stack", memory.kernel_stack_region));
+    for slot in memory.thread_stacks.keys() {
+      regions.push(("thread-stack", process::thread::stack_region(*slot)));
+    }
+  }
+  let directory = process.get_page_directory().get_address();
+
+  let mut runs: Vec<PageRun> = Vec::new();
+  page_directory::for_each_mapping(directory, |vaddr, dir_entry, entry| {
+    let label = label_page(&regions, vaddr, dir_entry, entry);
+    let frame = entry.get_address().as_usize();
+    if let Some(last) = runs.last_mut() {
+      let offset = last.pages * 0x1000;
+      if last.label == label && last.start + offset == vaddr.as_usize() && last.frame + offset == frame {
+        last.pages += 1;
+        return;
+      }
+    }
+    runs.push(PageRun { start: vaddr.as_usize(), frame, pages: 1, label });
+  });
+
+  let mut problems = 0;
+  for run in runs.iter() {
+    write_page_run(out, run)?;
+    if run.label.problem.is_some() {
+      problems += run.pages;
+    }
+  }
+  writeln!(out, "Discrepancies: {}", problems)
+}
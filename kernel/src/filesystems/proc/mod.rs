@@ -0,0 +1,18 @@
+//! PROC: is a synthetic drive that exposes kernel state as text files. The
+//! root contains system-wide files (MEMINFO, UPTIME, MOUNTS, CPUINFO, IRQLAT)
+//! and a directory for each process, named by its PID, containing STATUS,
+//! HANDLES, MAPS, CMDLINE, and PAGETABLES. File contents are generated when a
+//! file is opened, so each handle reads a consistent snapshot no matter how
+//! long it stays open.
+
+pub mod path;
+
+#[cfg(not(test))]
+pub mod fs;
+#[cfg(not(test))]
+pub mod generate;
+
+#[cfg(not(test))]
+pub fn create_fs() -> alloc::boxed::Box<super::FileSystemType> {
+  alloc::boxed::Box::new(fs::ProcFileSystem::new())
+}
@@ -0,0 +1,142 @@
+/// Files found in the directory of each process
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProcessFile {
+  /// Identity and run state of the process
+  Status,
+  /// Open file and directory handles
+  Handles,
+  /// Virtual memory regions
+  Maps,
+  /// Path of the executable, followed by its arguments
+  CmdLine,
+  /// Pages mapped by the page directory, checked against the regions
+  PageTables,
+}
+
+pub const PROCESS_FILES: [(&str, ProcessFile); 5] = [
+  ("STATUS", ProcessFile::Status),
+  ("HANDLES", ProcessFile::Handles),
+  ("MAPS", ProcessFile::Maps),
+  ("CMDLINE", ProcessFile::CmdLine),
+  ("PAGETABLES", ProcessFile::PageTables),
+];
+
+/// Every location on the PROC drive. Process directories are named by their
+/// decimal PID.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProcPath {
+  Root,
+  MemInfo,
+  Uptime,
+  Mounts,
+  CpuInfo,
+  IrqLatency,
+  ProcessDir(u32),
+  Process(u32, ProcessFile),
+}
+
+pub const SYSTEM_FILES: [(&str, ProcPath); 5] = [
+  ("MEMINFO", ProcPath::MemInfo),
+  ("UPTIME", ProcPath::Uptime),
+  ("MOUNTS", ProcPath::Mounts),
+  ("CPUINFO", ProcPath::CpuInfo),
+  ("IRQLAT", ProcPath::IrqLatency),
+];
+
+impl ProcPath {
+  pub fn parse(path: &str) -> Option<ProcPath> {
+    let mut parts = path.split("\\").filter(|part| part.len() > 0);
+    let first = match parts.next() {
+      Some(part) => part,
+      None => return Some(ProcPath::Root),
+    };
+    for (name, entry) in SYSTEM_FILES.iter() {
+      if name.eq_ignore_ascii_case(first) {
+        return match parts.next() {
+          Some(_) => None,
+          None => Some(*entry),
+        };
+      }
+    }
+    let pid = parse_pid(first)?;
+    let file = match parts.next() {
+      Some(part) => part,
+      None => return Some(ProcPath::ProcessDir(pid)),
+    };
+    if parts.next().is_some() {
+      return None;
+    }
+    PROCESS_FILES.iter()
+      .find(|(name, _)| name.eq_ignore_ascii_case(file))
+      .map(|(_, kind)| ProcPath::Process(pid, *kind))
+  }
+
+  pub fn is_directory(&self) -> bool {
+    match self {
+      ProcPath::Root | ProcPath::ProcessDir(_) => true,
+      _ => false,
+    }
+  }
+}
+
+fn parse_pid(name: &str) -> Option<u32> {
+  if name.len() == 0 || name.len() > 8 {
+    return None;
+  }
+  let mut pid: u32 = 0;
+  for ch in name.bytes() {
+    if !ch.is_ascii_digit() {
+      return None;
+    }
+    pid = pid * 10 + (ch - b'0') as u32;
+  }
+  Some(pid)
+}
+
+/// Write a PID as a space-padded directory name
+pub fn pid_to_name(pid: u32) -> [u8; 8] {
+  let mut digits = [0u8; 10];
+  let mut length = 0;
+  let mut remaining = pid;
+  loop {
+    digits[length] = b'0' + (remaining % 10) as u8;
+    length += 1;
+    remaining /= 10;
+    if remaining == 0 {
+      break;
+    }
+  }
+  let mut name = [0x20; 8];
+  for i in 0..length.min(8) {
+    name[i] = digits[length - 1 - i];
+  }
+  name
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_paths() {
+    assert_eq!(ProcPath::parse("\\"), Some(ProcPath::Root));
+    assert_eq!(ProcPath::parse("\\meminfo"), Some(ProcPath::MemInfo));
+    assert_eq!(ProcPath::parse("CPUINFO"), Some(ProcPath::CpuInfo));
+    assert_eq!(ProcPath::parse("\\irqlat"), Some(ProcPath::IrqLatency));
+    assert_eq!(ProcPath::parse("\\MOUNTS\\X"), None);
+    assert_eq!(ProcPath::parse("\\12"), Some(ProcPath::ProcessDir(12)));
+    assert_eq!(ProcPath::parse("\\12\\"), Some(ProcPath::ProcessDir(12)));
+    assert_eq!(ProcPath::parse("\\3\\Status"), Some(ProcPath::Process(3, ProcessFile::Status)));
+    assert_eq!(ProcPath::parse("\\3\\MAPS"), Some(ProcPath::Process(3, ProcessFile::Maps)));
+    assert_eq!(ProcPath::parse("\\3\\cmdline"), Some(ProcPath::Process(3, ProcessFile::CmdLine)));
+    assert_eq!(ProcPath::parse("\\3\\PageTables"), Some(ProcPath::Process(3, ProcessFile::PageTables)));
+    assert_eq!(ProcPath::parse("\\3\\OTHER"), None);
+    assert_eq!(ProcPath::parse("\\3x"), None);
+  }
+
+  #[test]
+  fn pid_names() {
+    assert_eq!(&pid_to_name(0), b"0       ");
+    assert_eq!(&pid_to_name(305), b"305     ");
+  }
+}
@@ -0,0 +1,33 @@
+use syscall::result::SystemError;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RamFsError {
+  /// No entry exists at the requested path
+  NotFound,
+  /// A path component that should be a directory names a file
+  NotDirectory,
+  /// A file operation was attempted on a directory
+  IsDirectory,
+  /// An entry with the same name already exists
+  AlreadyExists,
+  /// Directories can only be removed once they are empty
+  NotEmpty,
+  /// Storing the data would exceed the capacity of the drive
+  NoSpace,
+  /// The path was empty, or named the root directory
+  InvalidPath,
+}
+
+impl RamFsError {
+  pub fn to_system_error(&self) -> SystemError {
+    match self {
+      RamFsError::NotFound => SystemError::NoSuchEntity,
+      RamFsError::NotDirectory => SystemError::NotDirectory,
+      RamFsError::NotEmpty => SystemError::NotEmpty,
+      RamFsError::NoSpace => SystemError::NoSpace,
+      RamFsError::AlreadyExists => SystemError::AlreadyExists,
+      RamFsError::IsDirectory
+        | RamFsError::InvalidPath => SystemError::InvalidArgument,
+    }
+  }
+}
@@ -0,0 +1,316 @@
+use alloc::collections::BTreeMap;
+use crate::files::cursor::SeekMethod;
+use crate::files::handle::{Handle, HandleAllocator, LocalHandle};
+use spin::RwLock;
+use super::errors::RamFsError;
+use super::tree::NodeTree;
+use super::super::capabilities::Capabilities;
+use super::super::filesystem::FileSystem;
+use syscall::files::{DirEntryInfo, DirEntryType, FileStatus, OpenFlags};
+use syscall::result::SystemError;
+
+struct OpenFile {
+  pub node: usize,
+  pub cursor: usize,
+  pub flags: OpenFlags,
+}
+
+pub struct RamFileSystem {
+  handle_allocator: HandleAllocator<LocalHandle>,
+  open_files: RwLock<BTreeMap<LocalHandle, OpenFile>>,
+  tree: RwLock<NodeTree>,
+}
+
+impl RamFileSystem {
+  pub fn new(capacity: usize) -> RamFileSystem {
+    RamFileSystem {
+      handle_allocator: HandleAllocator::new(),
+      open_files: RwLock::new(BTreeMap::new()),
+      tree: RwLock::new(NodeTree::new(capacity)),
+    }
+  }
+
+  /// Open a handle to a node, which must be a directory if `directory` is set
+  /// and a file otherwise
+  fn open_node(&self, node: usize, directory: bool, flags: OpenFlags) -> Result<LocalHandle, RamFsError> {
+    {
+      let mut tree = self.tree.write();
+      match (tree.is_directory(node)?, directory) {
+        (true, false) => return Err(RamFsError::IsDirectory),
+        (false, true) => return Err(RamFsError::NotDirectory),
+        _ => (),
+      }
+      tree.open_node(node)?;
+    }
+    let handle = self.handle_allocator.get_next();
+    self.open_files.write().insert(handle, OpenFile {
+      node,
+      cursor: 0,
+      flags,
+    });
+    Ok(handle)
+  }
+
+  fn get_node(&self, handle: LocalHandle) -> Result<usize, ()> {
+    let files = self.open_files.read();
+    files.get(&handle).map(|file| file.node).ok_or(())
+  }
+}
+
+/// Build the space-padded 8.3 form of a name, for programs that only read the
+/// short name of a directory entry
+fn short_name(name: &str) -> ([u8; 8], [u8; 3]) {
+  let (base, ext) = match name.rfind('.') {
+    Some(dot) => (&name[..dot], &name[(dot + 1)..]),
+    None => (name, ""),
+  };
+  let mut short_name = [0x20; 8];
+  let mut short_ext = [0x20; 3];
+  for (slot, ch) in short_name.iter_mut().zip(base.bytes()) {
+    *slot = ch.to_ascii_uppercase();
+  }
+  for (slot, ch) in short_ext.iter_mut().zip(ext.bytes()) {
+    *slot = ch.to_ascii_uppercase();
+  }
+  (short_name, short_ext)
+}
+
+fn fits_short_name(name: &str) -> bool {
+  let (base, ext) = match name.rfind('.') {
+    Some(dot) => (&name[..dot], &name[(dot + 1)..]),
+    None => (name, ""),
+  };
+  base.len() > 0 && base.len() <= 8 && ext.len() <= 3
+    && !base.contains('.')
+    && !name.bytes().any(|c| c.is_ascii_lowercase() || c >= 0x80)
+}
+
+impl FileSystem for RamFileSystem {
+  fn get_capabilities(&self) -> Capabilities {
+    Capabilities {
+      writable: true,
+      seekable: true,
+      directories: true,
+      ..Capabilities::none()
+    }
+  }
+
+  fn open(&self, path: &str, flags: OpenFlags) -> Result<LocalHandle, ()> {
+    let node = self.tree.read().lookup(path).map_err(|_| ())?;
+    self.open_node(node, false, flags).map_err(|_| ())
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let mut files = self.open_files.write();
+    let file = files.get_mut(&handle).ok_or(())?;
+    if !file.flags.can_read() {
+      return Err(());
+    }
+    let read = self.tree.read().read(file.node, file.cursor, buffer).map_err(|_| ())?;
+    file.cursor += read;
+    Ok(read)
+  }
+
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, SystemError> {
+    let mut files = self.open_files.write();
+    let file = files.get_mut(&handle).ok_or(SystemError::BadFileDescriptor)?;
+    if !file.flags.can_write() {
+      return Err(SystemError::BadFileDescriptor);
+    }
+    let written = self.tree.write()
+      .write(file.node, file.cursor, buffer)
+      .map_err(|e| e.to_system_error())?;
+    file.cursor += written;
+    Ok(written)
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    let file = self.open_files.write().remove(&handle).ok_or(())?;
+    self.tree.write().close_node(file.node);
+    Ok(())
+  }
+
+  fn dup(&self, handle: LocalHandle) -> Result<LocalHandle, ()> {
+    let (node, cursor, flags) = {
+      let files = self.open_files.read();
+      let file = files.get(&handle).ok_or(())?;
+      (file.node, file.cursor, file.flags)
+    };
+    self.tree.write().reopen_node(node).map_err(|_| ())?;
+    let new_handle = self.handle_allocator.get_next();
+    self.open_files.write().insert(new_handle, OpenFile {
+      node,
+      cursor,
+      flags,
+    });
+    Ok(new_handle)
+  }
+
+  fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
+    let mut files = self.open_files.write();
+    let file = files.get_mut(&handle).ok_or(())?;
+    file.cursor = offset.from_current_position(file.cursor);
+    Ok(file.cursor)
+  }
+
+  fn open_dir(&self, path: &str) -> Result<LocalHandle, ()> {
+    let node = self.tree.read().lookup(path).map_err(|_| ())?;
+    self.open_node(node, true, OpenFlags::read_only()).map_err(|_| ())
+  }
+
+  fn read_dir(&self, handle: LocalHandle, index: usize, info: &mut DirEntryInfo) -> Result<(), ()> {
+    let dir = self.get_node(handle)?;
+    let tree = self.tree.read();
+    match tree.get_entry(dir, index).map_err(|_| ())? {
+      Some((name, node)) => {
+        let (file_name, file_ext) = short_name(name);
+        info.file_name = file_name;
+        info.file_ext = file_ext;
+        info.entry_type = if tree.is_directory(node).map_err(|_| ())? {
+          DirEntryType::Directory
+        } else {
+          DirEntryType::File
+        };
+        info.byte_size = tree.get_size(node).map_err(|_| ())?;
+        if fits_short_name(name) {
+          info.set_long_name("");
+        } else {
+          info.set_long_name(name);
+        }
+      },
+      None => {
+        *info = DirEntryInfo::empty();
+      },
+    }
+    Ok(())
+  }
+
+  /// Node numbers are never reused, so they are safe to cache
+  fn lookup(&self, path: &str) -> Result<usize, ()> {
+    self.tree.read().lookup(path).map_err(|_| ())
+  }
+
+  fn open_entry(&self, entry: usize, flags: OpenFlags) -> Result<LocalHandle, ()> {
+    self.open_node(entry, false, flags).map_err(|_| ())
+  }
+
+  fn stat(&self, handle: LocalHandle, status: &mut FileStatus) -> Result<(), ()> {
+    let node = self.get_node(handle)?;
+    let tree = self.tree.read();
+    status.file_id = node as u32;
+    status.entry_type = if tree.is_directory(node).map_err(|_| ())? {
+      DirEntryType::Directory
+    } else {
+      DirEntryType::File
+    };
+    status.byte_size = tree.get_size(node).map_err(|_| ())?;
+    status.link_count = tree.get_link_count(node).map_err(|_| ())? as u32;
+    Ok(())
+  }
+
+  fn open_by_id(&self, id: u32, flags: OpenFlags) -> Result<LocalHandle, ()> {
+    self.open_node(id as usize, false, flags).map_err(|_| ())
+  }
+
+  fn create(&self, path: &str) -> Result<LocalHandle, SystemError> {
+    let node = self.tree.write().create_file(path).map_err(|e| e.to_system_error())?;
+    self.open_node(node, false, OpenFlags::read_write()).map_err(|e| e.to_system_error())
+  }
+
+  fn truncate(&self, handle: LocalHandle, length: usize) -> Result<(), SystemError> {
+    let node = self.get_node(handle).map_err(|_| SystemError::BadFileDescriptor)?;
+    self.tree.write().set_length(node, length).map_err(|e| e.to_system_error())
+  }
+
+  fn preallocate(&self, handle: LocalHandle, length: usize) -> Result<(), SystemError> {
+    let node = self.get_node(handle).map_err(|_| SystemError::BadFileDescriptor)?;
+    self.tree.write().preallocate(node, length).map_err(|e| e.to_system_error())
+  }
+
+  fn delete(&self, path: &str) -> Result<(), SystemError> {
+    self.tree.write().remove_file(path).map_err(|e| e.to_system_error())
+  }
+
+  fn link(&self, existing_path: &str, new_path: &str) -> Result<(), SystemError> {
+    self.tree.write().link(existing_path, new_path).map_err(|e| e.to_system_error())
+  }
+
+  fn rename(&self, old_path: &str, new_path: &str) -> Result<(), SystemError> {
+    self.tree.write().rename(old_path, new_path).map_err(|e| e.to_system_error())
+  }
+
+  fn mkdir(&self, path: &str) -> Result<(), SystemError> {
+    self.tree.write().make_directory(path).map_err(|e| e.to_system_error())?;
+    Ok(())
+  }
+
+  fn rmdir(&self, path: &str) -> Result<(), SystemError> {
+    self.tree.write().remove_directory(path).map_err(|e| e.to_system_error())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn handles_share_data() {
+    let fs = RamFileSystem::new(1024);
+    assert!(fs.mkdir("\\TEMP").is_ok());
+    let writer = fs.create("\\TEMP\\notes.txt").ok().unwrap();
+    assert_eq!(fs.write(writer, b"abcdef").ok(), Some(6));
+    let reader = fs.open("\\temp\\NOTES.TXT", OpenFlags::read_only()).unwrap();
+    let mut buffer = [0; 4];
+    assert_eq!(fs.read(reader, &mut buffer), Ok(4));
+    assert_eq!(&buffer, b"abcd");
+    let copy = fs.dup(reader).unwrap();
+    assert_eq!(fs.read(copy, &mut buffer), Ok(2));
+    assert_eq!(&buffer[..2], b"ef");
+    assert!(fs.open("\\TEMP", OpenFlags::read_write()).is_err());
+    // Read-only handles, and their duplicates, cannot write
+    assert!(fs.write(reader, b"x").is_err());
+    assert!(fs.write(copy, b"x").is_err());
+
+    let dir = fs.open_dir("\\TEMP").unwrap();
+    let mut info = DirEntryInfo::empty();
+    fs.read_dir(dir, 0, &mut info).unwrap();
+    assert_eq!(&info.file_name, b"NOTES   ");
+    assert_eq!(&info.file_ext, b"TXT");
+    assert_eq!(info.byte_size, 6);
+    assert_eq!(info.get_long_name(), Some("notes.txt"));
+    fs.read_dir(dir, 1, &mut info).unwrap();
+    assert!(info.is_empty());
+  }
+
+  #[test]
+  fn directories() {
+    let fs = RamFileSystem::new(1024);
+    assert!(fs.mkdir("\\DOCS").is_ok());
+    assert!(fs.mkdir("\\DOCS").is_err());
+    let file = fs.create("\\DOCS\\A.TXT").ok().unwrap();
+    assert!(fs.close(file).is_ok());
+    assert!(matches!(fs.rmdir("\\DOCS"), Err(SystemError::NotEmpty)));
+    assert!(matches!(fs.rmdir("\\DOCS\\A.TXT"), Err(SystemError::NotDirectory)));
+    assert!(fs.delete("\\DOCS\\A.TXT").is_ok());
+    assert!(fs.rmdir("\\DOCS").is_ok());
+    assert!(fs.open_dir("\\DOCS").is_err());
+  }
+
+  #[test]
+  fn replace_existing_file() {
+    let fs = RamFileSystem::new(1024);
+    let old = fs.create("\\CONFIG.DAT").ok().unwrap();
+    assert_eq!(fs.write(old, b"old").ok(), Some(3));
+    assert!(fs.close(old).is_ok());
+    let new = fs.create("\\CONFIG.DAT.$$$").ok().unwrap();
+    assert_eq!(fs.write(new, b"new!").ok(), Some(4));
+    assert!(fs.close(new).is_ok());
+
+    assert!(fs.replace("\\CONFIG.DAT.$$$", "\\CONFIG.DAT").is_ok());
+    assert!(fs.open("\\CONFIG.DAT.$$$", OpenFlags::read_only()).is_err());
+    let reader = fs.open("\\CONFIG.DAT", OpenFlags::read_only()).unwrap();
+    let mut buffer = [0; 8];
+    assert_eq!(fs.read(reader, &mut buffer), Ok(4));
+    assert_eq!(&buffer[..4], b"new!");
+  }
+}
@@ -0,0 +1,31 @@
+//! RAMFS keeps files and directories in kernel heap allocations. Nothing is
+//! written to a device, so its contents are lost on reboot, but it provides a
+//! writable scratch drive that does not depend on any disk driver. It is
+//! mounted as TMP: at boot.
+//! A second instance, ANON:, backs anonymous memory files. Each one is unlinked
+//! as soon as it is created, so it has no name and lives only as long as the
+//! handles referencing it.
+//! The kernel heap is small, so each instance is limited to a fixed number of
+//! bytes of file data.
+
+pub mod errors;
+pub mod fs;
+pub mod tree;
+
+use alloc::boxed::Box;
+use super::FileSystemType;
+
+/// Bytes of file data the TMP: drive can hold
+pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+/// Bytes of file data the ANON: drive can hold, enough for a couple of
+/// full-screen buffers
+pub const ANONYMOUS_CAPACITY: usize = 256 * 1024;
+
+pub fn create_fs() -> Box<FileSystemType> {
+  Box::new(fs::RamFileSystem::new(DEFAULT_CAPACITY))
+}
+
+pub fn create_anonymous_fs() -> Box<FileSystemType> {
+  Box::new(fs::RamFileSystem::new(ANONYMOUS_CAPACITY))
+}
@@ -0,0 +1,522 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use super::errors::RamFsError;
+
+/// Node number of the root directory, which always exists
+pub const ROOT_NODE: usize = 1;
+
+pub struct DirEntry {
+  pub name: Box<str>,
+  pub node: usize,
+}
+
+pub enum NodeContents {
+  File(Vec<u8>),
+  /// Entries are kept in the order they were created
+  Directory(Vec<DirEntry>),
+}
+
+pub struct Node {
+  pub contents: NodeContents,
+  /// Number of open handles referencing this node
+  open_count: usize,
+  /// Number of directory entries naming this node. Files can have several
+  /// names through hard links; once the last is removed, the file stays
+  /// readable through existing handles until the last one is closed.
+  link_count: usize,
+}
+
+impl Node {
+  fn new(contents: NodeContents) -> Node {
+    Node {
+      contents,
+      open_count: 0,
+      link_count: 1,
+    }
+  }
+
+  pub fn is_directory(&self) -> bool {
+    match self.contents {
+      NodeContents::Directory(_) => true,
+      NodeContents::File(_) => false,
+    }
+  }
+}
+
+/// Every file and directory on a RAM drive, indexed by node number. Node
+/// numbers are never reused, so they can safely be cached or handed out as
+/// stable file identifiers.
+pub struct NodeTree {
+  nodes: BTreeMap<usize, Node>,
+  next_node: usize,
+  /// Bytes of file data currently stored
+  used_bytes: usize,
+  capacity: usize,
+}
+
+impl NodeTree {
+  pub fn new(capacity: usize) -> NodeTree {
+    let mut nodes = BTreeMap::new();
+    nodes.insert(ROOT_NODE, Node::new(NodeContents::Directory(Vec::new())));
+    NodeTree {
+      nodes,
+      next_node: ROOT_NODE + 1,
+      used_bytes: 0,
+      capacity,
+    }
+  }
+
+  pub fn get_used_bytes(&self) -> usize {
+    self.used_bytes
+  }
+
+  pub fn get_capacity(&self) -> usize {
+    self.capacity
+  }
+
+  fn get_node(&self, node: usize) -> Result<&Node, RamFsError> {
+    self.nodes.get(&node).ok_or(RamFsError::NotFound)
+  }
+
+  fn get_node_mut(&mut self, node: usize) -> Result<&mut Node, RamFsError> {
+    self.nodes.get_mut(&node).ok_or(RamFsError::NotFound)
+  }
+
+  fn get_entries(&self, dir: usize) -> Result<&Vec<DirEntry>, RamFsError> {
+    match self.get_node(dir)?.contents {
+      NodeContents::Directory(ref entries) => Ok(entries),
+      NodeContents::File(_) => Err(RamFsError::NotDirectory),
+    }
+  }
+
+  fn find_child(&self, dir: usize, name: &str) -> Result<usize, RamFsError> {
+    self.get_entries(dir)?
+      .iter()
+      .find(|entry| entry.name.eq_ignore_ascii_case(name))
+      .map(|entry| entry.node)
+      .ok_or(RamFsError::NotFound)
+  }
+
+  /// Walk each component of a path, starting at the root directory
+  pub fn lookup(&self, path: &str) -> Result<usize, RamFsError> {
+    let mut current = ROOT_NODE;
+    for part in path.split("\\").filter(|part| part.len() > 0) {
+      current = self.find_child(current, part)?;
+    }
+    Ok(current)
+  }
+
+  /// Split a path into the directory that contains it, and its final name
+  fn lookup_parent<'a>(&self, path: &'a str) -> Result<(usize, &'a str), RamFsError> {
+    let trimmed = path.trim_end_matches('\\');
+    let (parent_path, name) = match trimmed.rfind('\\') {
+      Some(split) => (&trimmed[..split], &trimmed[(split + 1)..]),
+      None => ("", trimmed),
+    };
+    if name.len() == 0 {
+      return Err(RamFsError::InvalidPath);
+    }
+    let parent = self.lookup(parent_path)?;
+    self.get_entries(parent)?;
+    Ok((parent, name))
+  }
+
+  fn insert_node(&mut self, path: &str, contents: NodeContents) -> Result<usize, RamFsError> {
+    let (parent, name) = self.lookup_parent(path)?;
+    if self.find_child(parent, name).is_ok() {
+      return Err(RamFsError::AlreadyExists);
+    }
+    let node = self.next_node;
+    self.next_node += 1;
+    self.nodes.insert(node, Node::new(contents));
+    self.add_entry(parent, name, node)?;
+    Ok(node)
+  }
+
+  /// Create an empty file, or truncate the file if it already exists
+  pub fn create_file(&mut self, path: &str) -> Result<usize, RamFsError> {
+    match self.lookup(path) {
+      Ok(node) => {
+        self.set_length(node, 0)?;
+        Ok(node)
+      },
+      Err(RamFsError::NotFound) => self.insert_node(path, NodeContents::File(Vec::new())),
+      Err(e) => Err(e),
+    }
+  }
+
+  pub fn make_directory(&mut self, path: &str) -> Result<usize, RamFsError> {
+    self.insert_node(path, NodeContents::Directory(Vec::new()))
+  }
+
+  /// Remove an entry from its directory. The node itself is only discarded
+  /// once nothing has it open.
+  fn unlink(&mut self, path: &str, directory: bool) -> Result<(), RamFsError> {
+    let (parent, name) = self.lookup_parent(path)?;
+    let node = self.find_child(parent, name)?;
+    match self.get_node(node)?.contents {
+      NodeContents::Directory(ref entries) => {
+        if !directory {
+          return Err(RamFsError::IsDirectory);
+        }
+        if entries.len() > 0 {
+          return Err(RamFsError::NotEmpty);
+        }
+      },
+      NodeContents::File(_) => {
+        if directory {
+          return Err(RamFsError::NotDirectory);
+        }
+      },
+    }
+    self.remove_entry(parent, name)?;
+    let entry = self.get_node_mut(node)?;
+    entry.link_count -= 1;
+    if entry.link_count == 0 && entry.open_count == 0 {
+      self.discard(node);
+    }
+    Ok(())
+  }
+
+  /// Remove a single name from a directory. Other names for the same node,
+  /// even in the same directory, are left in place.
+  fn remove_entry(&mut self, dir: usize, name: &str) -> Result<DirEntry, RamFsError> {
+    match self.get_node_mut(dir)?.contents {
+      NodeContents::Directory(ref mut entries) => {
+        let index = entries.iter()
+          .position(|entry| entry.name.eq_ignore_ascii_case(name))
+          .ok_or(RamFsError::NotFound)?;
+        Ok(entries.remove(index))
+      },
+      NodeContents::File(_) => Err(RamFsError::NotDirectory),
+    }
+  }
+
+  fn add_entry(&mut self, dir: usize, name: &str, node: usize) -> Result<(), RamFsError> {
+    match self.get_node_mut(dir)?.contents {
+      NodeContents::Directory(ref mut entries) => {
+        entries.push(DirEntry {
+          name: Box::from(name),
+          node,
+        });
+        Ok(())
+      },
+      NodeContents::File(_) => Err(RamFsError::NotDirectory),
+    }
+  }
+
+  /// Give an existing file an additional name. Both names refer to the same
+  /// data, and the file is only removed once every name has been unlinked.
+  /// Directories cannot be linked, since that could create a cycle.
+  pub fn link(&mut self, existing_path: &str, new_path: &str) -> Result<(), RamFsError> {
+    let node = self.lookup(existing_path)?;
+    if self.is_directory(node)? {
+      return Err(RamFsError::IsDirectory);
+    }
+    let (parent, name) = self.lookup_parent(new_path)?;
+    if self.find_child(parent, name).is_ok() {
+      return Err(RamFsError::AlreadyExists);
+    }
+    self.add_entry(parent, name, node)?;
+    self.get_node_mut(node)?.link_count += 1;
+    Ok(())
+  }
+
+  /// Move an entry to a new name, possibly in another directory. The node is
+  /// unchanged, so open handles and cached node numbers remain valid. The
+  /// destination must not exist, unless it is the same entry with different
+  /// capitalization.
+  pub fn rename(&mut self, old_path: &str, new_path: &str) -> Result<(), RamFsError> {
+    let (old_parent, old_name) = self.lookup_parent(old_path)?;
+    let node = self.find_child(old_parent, old_name)?;
+    let (new_parent, new_name) = self.lookup_parent(new_path)?;
+    let same_entry = old_parent == new_parent && old_name.eq_ignore_ascii_case(new_name);
+    match self.find_child(new_parent, new_name) {
+      Ok(_) if same_entry => (),
+      Ok(_) => return Err(RamFsError::AlreadyExists),
+      Err(RamFsError::NotFound) => (),
+      Err(e) => return Err(e),
+    }
+    if self.is_directory(node)? && self.subtree_contains(node, new_parent) {
+      // A directory cannot be moved inside itself
+      return Err(RamFsError::InvalidPath);
+    }
+    let mut entry = self.remove_entry(old_parent, old_name)?;
+    entry.name = Box::from(new_name);
+    match self.get_node_mut(new_parent)?.contents {
+      NodeContents::Directory(ref mut entries) => entries.push(entry),
+      NodeContents::File(_) => return Err(RamFsError::NotDirectory),
+    }
+    Ok(())
+  }
+
+  /// Check whether a node is a directory, or is found anywhere beneath it
+  fn subtree_contains(&self, dir: usize, target: usize) -> bool {
+    if dir == target {
+      return true;
+    }
+    match self.get_entries(dir) {
+      Ok(entries) => entries.iter().any(|entry| self.subtree_contains(entry.node, target)),
+      Err(_) => false,
+    }
+  }
+
+  pub fn remove_file(&mut self, path: &str) -> Result<(), RamFsError> {
+    self.unlink(path, false)
+  }
+
+  pub fn remove_directory(&mut self, path: &str) -> Result<(), RamFsError> {
+    self.unlink(path, true)
+  }
+
+  fn discard(&mut self, node: usize) {
+    if let Some(removed) = self.nodes.remove(&node) {
+      if let NodeContents::File(data) = removed.contents {
+        self.used_bytes -= data.len();
+      }
+    }
+  }
+
+  /// Record a new handle to a node. Only nodes that are still linked into the
+  /// tree can be opened.
+  pub fn open_node(&mut self, node: usize) -> Result<(), RamFsError> {
+    let entry = self.get_node_mut(node)?;
+    if entry.link_count == 0 {
+      return Err(RamFsError::NotFound);
+    }
+    entry.open_count += 1;
+    Ok(())
+  }
+
+  /// Count another handle to a node that is already open, even if it has since
+  /// been unlinked
+  pub fn reopen_node(&mut self, node: usize) -> Result<(), RamFsError> {
+    self.get_node_mut(node)?.open_count += 1;
+    Ok(())
+  }
+
+  pub fn close_node(&mut self, node: usize) {
+    let should_discard = match self.nodes.get_mut(&node) {
+      Some(entry) => {
+        entry.open_count = entry.open_count.saturating_sub(1);
+        entry.open_count == 0 && entry.link_count == 0
+      },
+      None => false,
+    };
+    if should_discard {
+      self.discard(node);
+    }
+  }
+
+  pub fn is_directory(&self, node: usize) -> Result<bool, RamFsError> {
+    Ok(self.get_node(node)?.is_directory())
+  }
+
+  pub fn get_link_count(&self, node: usize) -> Result<usize, RamFsError> {
+    Ok(self.get_node(node)?.link_count)
+  }
+
+  /// Size of a file in bytes. Directories report a size of zero.
+  pub fn get_size(&self, node: usize) -> Result<usize, RamFsError> {
+    match self.get_node(node)?.contents {
+      NodeContents::File(ref data) => Ok(data.len()),
+      NodeContents::Directory(_) => Ok(0),
+    }
+  }
+
+  /// Fetch the name and node number of the nth entry in a directory
+  pub fn get_entry(&self, dir: usize, index: usize) -> Result<Option<(&str, usize)>, RamFsError> {
+    let entries = self.get_entries(dir)?;
+    Ok(entries.get(index).map(|entry| (entry.name.as_ref(), entry.node)))
+  }
+
+  pub fn read(&self, node: usize, offset: usize, buffer: &mut [u8]) -> Result<usize, RamFsError> {
+    let data = match self.get_node(node)?.contents {
+      NodeContents::File(ref data) => data,
+      NodeContents::Directory(_) => return Err(RamFsError::IsDirectory),
+    };
+    if offset >= data.len() {
+      return Ok(0);
+    }
+    let to_read = buffer.len().min(data.len() - offset);
+    buffer[..to_read].copy_from_slice(&data[offset..(offset + to_read)]);
+    Ok(to_read)
+  }
+
+  /// Write data at an offset, extending the file as needed. Writing past the
+  /// end fills the gap with zeroes. If the drive is nearly full, only the part
+  /// that fits is written.
+  pub fn write(&mut self, node: usize, offset: usize, buffer: &[u8]) -> Result<usize, RamFsError> {
+    let available = self.capacity - self.used_bytes;
+    let data = match self.get_node_mut(node)?.contents {
+      NodeContents::File(ref mut data) => data,
+      NodeContents::Directory(_) => return Err(RamFsError::IsDirectory),
+    };
+    let current_length = data.len();
+    let max_length = current_length + available;
+    if buffer.len() == 0 {
+      return Ok(0);
+    }
+    if offset >= max_length {
+      return Err(RamFsError::NoSpace);
+    }
+    let to_write = buffer.len().min(max_length - offset);
+    let end = offset + to_write;
+    if end > current_length {
+      data.resize(end, 0);
+    }
+    data[offset..end].copy_from_slice(&buffer[..to_write]);
+    let new_length = data.len();
+    self.used_bytes += new_length - current_length;
+    Ok(to_write)
+  }
+
+  pub fn set_length(&mut self, node: usize, length: usize) -> Result<(), RamFsError> {
+    let available = self.capacity - self.used_bytes;
+    let data = match self.get_node_mut(node)?.contents {
+      NodeContents::File(ref mut data) => data,
+      NodeContents::Directory(_) => return Err(RamFsError::IsDirectory),
+    };
+    let current_length = data.len();
+    if length > current_length + available {
+      return Err(RamFsError::NoSpace);
+    }
+    data.resize(length, 0);
+    data.shrink_to_fit();
+    self.used_bytes = self.used_bytes + length - current_length;
+    Ok(())
+  }
+
+  /// Extend a file to at least `length` bytes. Files that are already long
+  /// enough are unchanged.
+  pub fn preallocate(&mut self, node: usize, length: usize) -> Result<(), RamFsError> {
+    if self.get_size(node)? >= length {
+      return Ok(());
+    }
+    self.set_length(node, length)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn create_and_lookup() {
+    let mut tree = NodeTree::new(1024);
+    let dir = tree.make_directory("\\DOCS").unwrap();
+    let file = tree.create_file("\\DOCS\\readme.txt").unwrap();
+    assert_eq!(tree.lookup("\\"), Ok(ROOT_NODE));
+    assert_eq!(tree.lookup("\\docs"), Ok(dir));
+    assert_eq!(tree.lookup("\\DOCS\\README.TXT"), Ok(file));
+    assert_eq!(tree.lookup("\\DOCS\\OTHER"), Err(RamFsError::NotFound));
+    assert_eq!(tree.lookup("\\DOCS\\README.TXT\\X"), Err(RamFsError::NotDirectory));
+    assert_eq!(tree.make_directory("\\docs"), Err(RamFsError::AlreadyExists));
+    assert_eq!(tree.create_file("\\MISSING\\A"), Err(RamFsError::NotFound));
+    assert_eq!(tree.get_entry(dir, 0), Ok(Some(("readme.txt", file))));
+    assert_eq!(tree.get_entry(dir, 1), Ok(None));
+  }
+
+  #[test]
+  fn read_and_write() {
+    let mut tree = NodeTree::new(1024);
+    let file = tree.create_file("A.TXT").unwrap();
+    assert_eq!(tree.write(file, 0, b"hello"), Ok(5));
+    assert_eq!(tree.write(file, 8, b"!"), Ok(1));
+    assert_eq!(tree.get_size(file), Ok(9));
+    let mut buffer = [0xff; 16];
+    assert_eq!(tree.read(file, 0, &mut buffer), Ok(9));
+    assert_eq!(&buffer[..9], b"hello\0\0\0!");
+    assert_eq!(tree.read(file, 9, &mut buffer), Ok(0));
+    assert_eq!(tree.get_used_bytes(), 9);
+    // Recreating the file truncates it
+    assert_eq!(tree.create_file("a.txt"), Ok(file));
+    assert_eq!(tree.get_size(file), Ok(0));
+    assert_eq!(tree.get_used_bytes(), 0);
+  }
+
+  #[test]
+  fn capacity_limit() {
+    let mut tree = NodeTree::new(8);
+    let file = tree.create_file("A").unwrap();
+    assert_eq!(tree.write(file, 0, b"0123456789"), Ok(8));
+    assert_eq!(tree.write(file, 8, b"x"), Err(RamFsError::NoSpace));
+    // Overwriting existing bytes needs no new space
+    assert_eq!(tree.write(file, 0, b"ab"), Ok(2));
+    assert_eq!(tree.set_length(file, 4), Ok(()));
+    assert_eq!(tree.get_used_bytes(), 4);
+    assert_eq!(tree.set_length(file, 9), Err(RamFsError::NoSpace));
+  }
+
+  #[test]
+  fn unlink_open_file() {
+    let mut tree = NodeTree::new(1024);
+    let file = tree.create_file("A").unwrap();
+    tree.write(file, 0, b"data").unwrap();
+    tree.open_node(file).unwrap();
+    tree.remove_file("A").unwrap();
+    assert_eq!(tree.lookup("A"), Err(RamFsError::NotFound));
+    // The open handle can still read the data
+    let mut buffer = [0; 4];
+    assert_eq!(tree.read(file, 0, &mut buffer), Ok(4));
+    assert_eq!(tree.open_node(file), Err(RamFsError::NotFound));
+    tree.close_node(file);
+    assert_eq!(tree.read(file, 0, &mut buffer), Err(RamFsError::NotFound));
+    assert_eq!(tree.get_used_bytes(), 0);
+  }
+
+  #[test]
+  fn remove_directories() {
+    let mut tree = NodeTree::new(1024);
+    tree.make_directory("D").unwrap();
+    tree.create_file("D\\F").unwrap();
+    assert_eq!(tree.remove_file("D"), Err(RamFsError::IsDirectory));
+    assert_eq!(tree.remove_directory("D"), Err(RamFsError::NotEmpty));
+    assert_eq!(tree.remove_directory("D\\F"), Err(RamFsError::NotDirectory));
+    tree.remove_file("D\\F").unwrap();
+    tree.remove_directory("D").unwrap();
+    assert_eq!(tree.lookup("D"), Err(RamFsError::NotFound));
+    assert_eq!(tree.remove_directory("\\"), Err(RamFsError::InvalidPath));
+  }
+
+  #[test]
+  fn hard_links() {
+    let mut tree = NodeTree::new(1024);
+    let file = tree.create_file("A").unwrap();
+    tree.write(file, 0, b"data").unwrap();
+    tree.make_directory("D").unwrap();
+    tree.link("A", "D\\B").unwrap();
+    tree.link("A", "C").unwrap();
+    assert_eq!(tree.lookup("D\\B"), Ok(file));
+    assert_eq!(tree.get_link_count(file), Ok(3));
+    assert_eq!(tree.link("A", "C"), Err(RamFsError::AlreadyExists));
+    assert_eq!(tree.link("D", "E"), Err(RamFsError::IsDirectory));
+    // Removing one name leaves the others, even in the same directory
+    tree.remove_file("A").unwrap();
+    assert_eq!(tree.lookup("C"), Ok(file));
+    tree.remove_file("C").unwrap();
+    assert_eq!(tree.get_used_bytes(), 4);
+    tree.remove_file("D\\B").unwrap();
+    assert_eq!(tree.get_used_bytes(), 0);
+  }
+
+  #[test]
+  fn rename_entries() {
+    let mut tree = NodeTree::new(1024);
+    let dir = tree.make_directory("D").unwrap();
+    let file = tree.create_file("A").unwrap();
+    tree.create_file("B").unwrap();
+    assert_eq!(tree.rename("A", "B"), Err(RamFsError::AlreadyExists));
+    tree.rename("A", "D\\moved").unwrap();
+    assert_eq!(tree.lookup("A"), Err(RamFsError::NotFound));
+    assert_eq!(tree.lookup("D\\MOVED"), Ok(file));
+    // Changing only the capitalization is allowed
+    tree.rename("D\\MOVED", "D\\Moved").unwrap();
+    assert_eq!(tree.get_entry(dir, 0), Ok(Some(("Moved", file))));
+    tree.make_directory("D\\SUB").unwrap();
+    assert_eq!(tree.rename("D", "D\\SUB\\D"), Err(RamFsError::InvalidPath));
+    tree.rename("D", "E").unwrap();
+    assert_eq!(tree.lookup("E\\MOVED"), Ok(file));
+  }
+}
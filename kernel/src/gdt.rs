@@ -1,5 +1,7 @@
 use core::mem;
 
+use crate::interrupts;
+
 pub const GDT_ACCESS_PRESENT: u8 = 1 << 7;
 pub const GDT_ACCESS_RING_0: u8 = 0;
 pub const GDT_ACCESS_RING_1: u8 = 1 << 5;
@@ -74,7 +76,7 @@ static mut GDTR: GDTDescriptor = GDTDescriptor {
   offset: 0,
 };
 
-static mut GDT: [GDTEntry; 6] = [
+static mut GDT: [GDTEntry; 7] = [
   // Null entry - 0x00
   GDTEntry::new(0, 0, 0, 0),
 
@@ -117,8 +119,19 @@ static mut GDT: [GDTEntry; 6] = [
     GDT_ACCESS_PRESENT | GDT_ACCESS_RING_3 | GDT_ACCESS_SYSTEM_DESCRIPTOR | GDT_ACCESS_EXECUTABLE | GDT_ACCESS_ACCESSED,
     0
   ),
+
+  // Double fault TSS - 0x30
+  GDTEntry::new(
+    0,
+    0xffffffff,
+    GDT_ACCESS_PRESENT | GDT_ACCESS_RING_0 | GDT_ACCESS_SYSTEM_DESCRIPTOR | GDT_ACCESS_EXECUTABLE | GDT_ACCESS_ACCESSED,
+    0
+  ),
 ];
 
+/// Selector of the task that handles double faults
+pub const DOUBLE_FAULT_TSS_SELECTOR: u16 = 0x30;
+
 #[repr(C, packed)]
 pub struct TaskStateSegment {
   prev_tss: u32,
@@ -151,6 +164,38 @@ pub struct TaskStateSegment {
 }
 
 impl TaskStateSegment {
+  pub const fn empty() -> TaskStateSegment {
+    TaskStateSegment {
+      prev_tss: 0,
+      esp0: 0,
+      ss0: 0,
+      esp1: 0,
+      ss1: 0,
+      esp2: 0,
+      ss2: 0,
+      cr3: 0,
+      eip: 0,
+      eflags: 0,
+      eax: 0,
+      ecx: 0,
+      edx: 0,
+      ebx: 0,
+      esp: 0,
+      ebp: 0,
+      esi: 0,
+      edi: 0,
+      es: 0,
+      cs: 0,
+      ss: 0,
+      ds: 0,
+      fs: 0,
+      gs: 0,
+      ldt: 0,
+      trap: 0,
+      iomap_base: 0,
+    }
+  }
+
   pub fn zero(&mut self) {
     self.prev_tss = 0;
     self.esp0 = 0;
@@ -188,51 +233,96 @@ impl TaskStateSegment {
   pub fn set_stack_pointer(&mut self, pointer: u32) {
     self.esp0 = pointer;
   }
+
+  /// Prepare a task that starts running kernel code at `entry`, on the stack
+  /// ending at `stack_top`
+  pub fn set_kernel_task(&mut self, entry: u32, stack_top: u32) {
+    self.eip = entry;
+    self.esp = stack_top;
+    self.eflags = 2;
+    self.cs = 0x08;
+    self.ds = 0x10;
+    self.es = 0x10;
+    self.fs = 0x10;
+    self.gs = 0x10;
+    self.ss = 0x10;
+  }
 }
 
-static mut TSS: TaskStateSegment = TaskStateSegment {
-  prev_tss: 0,
-  esp0: 0,
-  ss0: 0,
-  esp1: 0,
-  ss1: 0,
-  esp2: 0,
-  ss2: 0,
-  cr3: 0,
-  eip: 0,
-  eflags: 0,
-  eax: 0,
-  ecx: 0,
-  edx: 0,
-  ebx: 0,
-  esp: 0,
-  ebp: 0,
-  esi: 0,
-  edi: 0,
-  es: 0,
-  cs: 0,
-  ss: 0,
-  ds: 0,
-  fs: 0,
-  gs: 0,
-  ldt: 0,
-  trap: 0,
-  iomap_base: 0,
+/// One bit for each of the 65536 ports
+pub const IO_BITMAP_SIZE: usize = 0x2000;
+
+/// The I/O permission bitmap sits right after the TSS, which points to it. A
+/// clear bit lets ring 3 code, and VM86 code, use the port directly; anything
+/// else traps. The CPU may read one byte past the end, which must be all ones.
+#[repr(C, packed)]
+struct TaskStateWithIoMap {
+  tss: TaskStateSegment,
+  io_bitmap: [u8; IO_BITMAP_SIZE + 1],
+}
+
+static mut TSS: TaskStateWithIoMap = TaskStateWithIoMap {
+  tss: TaskStateSegment::empty(),
+  io_bitmap: [0xff; IO_BITMAP_SIZE + 1],
 };
 
+/// Overflowing a kernel stack faults again as soon as the CPU tries to push
+/// the page fault onto it, which becomes a double fault. That fault is handled
+/// by a separate task, so the CPU switches to a stack that is known to be good
+/// before pushing anything.
+static mut DOUBLE_FAULT_TSS: TaskStateSegment = TaskStateSegment::empty();
+
+static mut DOUBLE_FAULT_STACK: [u8; 0x2000] = [0; 0x2000];
+
 pub unsafe fn init() {
   GDTR.size = (GDT.len() * mem::size_of::<GDTEntry>() - 1) as u16;
   GDTR.offset = GDT.as_ptr() as *const GDTEntry as u32;
 
-  TSS.zero();
-  TSS.set_stack_segment(0x10);
-  GDT[5].set_limit(mem::size_of::<TaskStateSegment>() as u32);
-  GDT[5].set_base(&TSS as *const TaskStateSegment as u32);
+  TSS.tss.zero();
+  TSS.tss.set_stack_segment(0x10);
+  TSS.tss.iomap_base = mem::size_of::<TaskStateSegment>() as u16;
+  GDT[5].set_limit(mem::size_of::<TaskStateWithIoMap>() as u32 - 1);
+  GDT[5].set_base(&TSS as *const TaskStateWithIoMap as u32);
+
+  let double_fault_stack_top = DOUBLE_FAULT_STACK.as_ptr() as u32 + DOUBLE_FAULT_STACK.len() as u32;
+  DOUBLE_FAULT_TSS.set_kernel_task(
+    interrupts::exceptions::double_fault_task as usize as u32,
+    double_fault_stack_top,
+  );
+  GDT[6].set_limit(mem::size_of::<TaskStateSegment>() as u32);
+  GDT[6].set_base(&DOUBLE_FAULT_TSS as *const TaskStateSegment as u32);
 
   lgdt(&GDTR);
   ltr(0x28);
 }
 
 pub unsafe fn set_tss_stack_pointer(sp: u32) {
-  TSS.set_stack_pointer(sp);
+  TSS.tss.set_stack_pointer(sp);
+}
+
+/// Copy a process's permissions into the start of the I/O bitmap. Ports past
+/// the end of `bitmap` are left as they are.
+pub unsafe fn set_io_bitmap(bitmap: &[u8]) {
+  let length = bitmap.len().min(IO_BITMAP_SIZE);
+  TSS.io_bitmap[..length].copy_from_slice(&bitmap[..length]);
+}
+
+/// Deny every port in the first `length` bytes of the I/O bitmap
+pub unsafe fn deny_io_ports(length: usize) {
+  let length = length.min(IO_BITMAP_SIZE);
+  for byte in TSS.io_bitmap[..length].iter_mut() {
+    *byte = 0xff;
+  }
+}
+
+/// The double fault task loads its page directory from its TSS, so it has to
+/// follow the directory of whichever process is running
+pub unsafe fn set_double_fault_page_directory(cr3: u32) {
+  DOUBLE_FAULT_TSS.cr3 = cr3;
+}
+
+/// Where the task that double faulted was running, as saved by the CPU when it
+/// switched to the double fault task: (eip, esp)
+pub unsafe fn get_interrupted_task_registers() -> (u32, u32) {
+  (TSS.tss.eip, TSS.tss.esp)
 }
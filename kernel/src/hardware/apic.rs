@@ -0,0 +1,156 @@
+//! The APIC replaces the pair of 8259 PICs on machines from the Pentium on.
+//! Each processor has a local APIC, which delivers interrupts to it and must
+//! be told when one has been handled. Devices are wired to one or more I/O
+//! APICs, which route each input pin to a vector on a chosen local APIC.
+//!
+//! Both are programmed through memory-mapped registers. The local APIC's
+//! registers sit 16 bytes apart, while an I/O APIC exposes only an index and a
+//! data register, through which its internal registers are selected.
+
+use core::ptr::{read_volatile, write_volatile};
+use crate::memory::address::VirtualAddress;
+use crate::memory::physical::frame::Frame;
+use crate::memory::virt::page_directory::{CurrentPageDirectory, PageDirectory, PermissionFlags};
+use crate::x86::registers::{read_msr, write_msr};
+
+/// MSR holding the local APIC's physical address and global enable bit
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+// I/O APIC registers
+const IOAPIC_SELECT: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+// Fields of a redirection table entry
+const REDIRECT_MASKED: u32 = 1 << 16;
+const REDIRECT_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECT_ACTIVE_LOW: u32 = 1 << 13;
+
+/// Map a page of device registers into kernel memory, returning the virtual
+/// address of the first register
+pub unsafe fn map_registers(physical: u32, window: usize) -> usize {
+  let physical = physical as usize;
+  CurrentPageDirectory::get().map(
+    Frame::new(physical & 0xfffff000),
+    VirtualAddress::new(window),
+    PermissionFlags::new(PermissionFlags::WRITE_ACCESS | PermissionFlags::CACHE_DISABLE),
+  );
+  window + (physical & 0xfff)
+}
+
+/// Turn the local APIC on in the base MSR. Firmware sometimes leaves it off
+/// when it hands the machine over in PIC mode.
+pub unsafe fn enable_local_apic_msr() {
+  let base = read_msr(IA32_APIC_BASE);
+  if base & APIC_BASE_ENABLE == 0 {
+    write_msr(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+  }
+}
+
+#[derive(Copy, Clone)]
+pub struct LocalApic {
+  /// Virtual address of the mapped registers
+  base: usize,
+}
+
+impl LocalApic {
+  pub const fn new(base: usize) -> LocalApic {
+    LocalApic {
+      base,
+    }
+  }
+
+  unsafe fn read(&self, register: usize) -> u32 {
+    read_volatile((self.base + register) as *const u32)
+  }
+
+  unsafe fn write(&self, register: usize, value: u32) {
+    write_volatile((self.base + register) as *mut u32, value);
+  }
+
+  pub unsafe fn get_id(&self) -> u8 {
+    (self.read(LAPIC_ID) >> 24) as u8
+  }
+
+  /// Start accepting interrupts. The spurious vector is raised when an
+  /// interrupt disappears before it can be delivered, and needs no EOI.
+  pub unsafe fn enable(&self, spurious_vector: u8) {
+    self.write(LAPIC_TASK_PRIORITY, 0);
+    let spurious = self.read(LAPIC_SPURIOUS) & !0xff;
+    self.write(LAPIC_SPURIOUS, spurious | SPURIOUS_APIC_ENABLE | spurious_vector as u32);
+  }
+
+  pub unsafe fn end_of_interrupt(&self) {
+    self.write(LAPIC_EOI, 0);
+  }
+}
+
+#[derive(Copy, Clone)]
+pub struct IoApic {
+  /// Virtual address of the mapped registers
+  base: usize,
+}
+
+impl IoApic {
+  pub const fn new(base: usize) -> IoApic {
+    IoApic {
+      base,
+    }
+  }
+
+  unsafe fn read(&self, register: u32) -> u32 {
+    write_volatile((self.base + IOAPIC_SELECT) as *mut u32, register);
+    read_volatile((self.base + IOAPIC_WINDOW) as *const u32)
+  }
+
+  unsafe fn write(&self, register: u32, value: u32) {
+    write_volatile((self.base + IOAPIC_SELECT) as *mut u32, register);
+    write_volatile((self.base + IOAPIC_WINDOW) as *mut u32, value);
+  }
+
+  /// Number of input pins, each with its own redirection entry
+  pub unsafe fn get_pin_count(&self) -> u32 {
+    ((self.read(IOAPIC_VERSION) >> 16) & 0xff) + 1
+  }
+
+  /// Send a pin to a vector on one local APIC. The pin starts out masked.
+  pub unsafe fn route(&self, pin: u32, vector: u8, destination: u8, active_low: bool, level_triggered: bool) {
+    let mut low = REDIRECT_MASKED | vector as u32;
+    if active_low {
+      low |= REDIRECT_ACTIVE_LOW;
+    }
+    if level_triggered {
+      low |= REDIRECT_LEVEL_TRIGGERED;
+    }
+    let register = IOAPIC_REDIRECTION + pin * 2;
+    self.write(register, REDIRECT_MASKED);
+    self.write(register + 1, (destination as u32) << 24);
+    self.write(register, low);
+  }
+
+  pub unsafe fn set_masked(&self, pin: u32, masked: bool) {
+    let register = IOAPIC_REDIRECTION + pin * 2;
+    let low = self.read(register);
+    if masked {
+      self.write(register, low | REDIRECT_MASKED);
+    } else {
+      self.write(register, low & !REDIRECT_MASKED);
+    }
+  }
+
+  /// Mask every pin, leaving nothing routed
+  pub unsafe fn mask_all(&self) {
+    for pin in 0..self.get_pin_count() {
+      self.write(IOAPIC_REDIRECTION + pin * 2, REDIRECT_MASKED);
+    }
+  }
+}
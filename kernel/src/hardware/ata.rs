@@ -0,0 +1,436 @@
+//! Low-level access to ATA (IDE) hard disks using programmed IO.
+//!
+//! A PC has up to two ATA channels, each with a master and a slave drive. Every
+//! channel exposes a block of command registers and a separate control
+//! register. Commands are issued by selecting a drive, writing the sector
+//! address and count, and then writing the command byte. Data is transferred
+//! 16 bits at a time through the data register whenever the drive raises DRQ.
+//!
+//! Interrupts are disabled on each channel, and the driver polls the status
+//! register instead. Only 28-bit LBA addressing is supported, which limits
+//! drives to 128GiB.
+//!
+//! CD-ROM drives use ATAPI, which wraps SCSI commands in a 12-byte packet sent
+//! through the data register. They identify themselves with a different
+//! signature, and are read in 2048-byte sectors.
+
+use crate::disks::geometry::Geometry;
+use crate::x86::io::Port;
+use spin::Mutex;
+
+pub const SECTOR_SIZE: usize = 512;
+pub const ATAPI_SECTOR_SIZE: usize = 2048;
+/// Largest number of sectors that can be transferred by a single command
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+/// Number of status polls before a drive is considered unresponsive
+const POLL_LIMIT: usize = 100000;
+
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_PACKET: u8 = 0xa0;
+const COMMAND_IDENTIFY_PACKET: u8 = 0xa1;
+const COMMAND_CACHE_FLUSH: u8 = 0xe7;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+
+/// LBA mid and high registers hold this signature after an ATAPI device
+/// rejects the IDENTIFY command
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xeb);
+
+/// Setting nIEN in the control register prevents the drive from raising IRQs
+const CONTROL_NO_INTERRUPTS: u8 = 0x02;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AtaError {
+  /// Nothing is attached at the requested position, or it is not an ATA disk
+  NoDevice,
+  /// The position holds an ATAPI device, which must be identified with
+  /// `identify_packet` instead
+  PacketDevice,
+  /// The drive did not become ready in time
+  Timeout,
+  /// The drive reported an error while processing a command
+  DeviceError(u8),
+  /// The drive reported a fault
+  DeviceFault,
+  /// The sector range falls outside the disk, or beyond 28-bit addressing
+  OutOfRange,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DrivePosition {
+  Master,
+  Slave,
+}
+
+impl DrivePosition {
+  fn select_bits(&self) -> u8 {
+    match self {
+      DrivePosition::Master => 0xe0,
+      DrivePosition::Slave => 0xf0,
+    }
+  }
+}
+
+/// Words 27 through 46 of the IDENTIFY response hold the model name. Strings
+/// are stored with the bytes of each word swapped.
+fn model_from_words(words: &[u16; 256]) -> [u8; 40] {
+  let mut model = [0x20; 40];
+  for i in 0..20 {
+    let word = words[27 + i];
+    model[i * 2] = (word >> 8) as u8;
+    model[i * 2 + 1] = word as u8;
+  }
+  model
+}
+
+/// Information returned by the IDENTIFY command
+#[derive(Copy, Clone)]
+pub struct DriveIdentity {
+  /// Number of sectors addressable with 28-bit LBA
+  pub sector_count: usize,
+  /// Model string, padded with spaces
+  pub model: [u8; 40],
+  /// CHS geometry, checked against the sector count
+  pub geometry: Geometry,
+}
+
+impl DriveIdentity {
+  /// Extract the relevant fields from the 256 words returned by IDENTIFY
+  pub fn from_words(words: &[u16; 256]) -> Result<DriveIdentity, AtaError> {
+    let supports_lba = words[49] & (1 << 9) != 0;
+    if !supports_lba {
+      return Err(AtaError::NoDevice);
+    }
+    let sector_count = words[60] as usize | ((words[61] as usize) << 16);
+    // Words 1, 3, and 6 hold the default cylinders, heads, and sectors
+    let reported = Geometry::new(words[1] as usize, words[3] as usize, words[6] as usize);
+    let geometry = Geometry::reconcile(reported, sector_count);
+    let model = model_from_words(words);
+    Ok(DriveIdentity {
+      sector_count,
+      model,
+      geometry,
+    })
+  }
+}
+
+pub struct AtaChannel {
+  io_base: u16,
+  control: Port,
+  /// Only one command can be in progress on a channel at a time, even if the
+  /// two drives are used by different processes
+  lock: Mutex<()>,
+}
+
+impl AtaChannel {
+  pub const fn new(io_base: u16, control_base: u16) -> AtaChannel {
+    AtaChannel {
+      io_base,
+      control: Port::new(control_base),
+      lock: Mutex::new(()),
+    }
+  }
+
+  fn register(&self, offset: u16) -> Port {
+    Port::new(self.io_base + offset)
+  }
+
+  fn data(&self) -> Port { self.register(0) }
+  fn error(&self) -> Port { self.register(1) }
+  fn sector_count(&self) -> Port { self.register(2) }
+  fn lba_low(&self) -> Port { self.register(3) }
+  fn lba_mid(&self) -> Port { self.register(4) }
+  fn lba_high(&self) -> Port { self.register(5) }
+  fn drive_select(&self) -> Port { self.register(6) }
+  fn command(&self) -> Port { self.register(7) }
+
+  fn get_status(&self) -> u8 {
+    unsafe {
+      self.register(7).read_u8()
+    }
+  }
+
+  /// Reading the alternate status register has no side effects, and is used
+  /// to give the drive the 400ns it needs after a drive select
+  fn delay_400ns(&self) {
+    for _ in 0..4 {
+      unsafe {
+        self.control.read_u8();
+      }
+    }
+  }
+
+  fn wait_not_busy(&self) -> Result<u8, AtaError> {
+    for _ in 0..POLL_LIMIT {
+      let status = self.get_status();
+      if status & STATUS_BSY == 0 {
+        return Ok(status);
+      }
+    }
+    Err(AtaError::Timeout)
+  }
+
+  /// Wait until the drive is ready to transfer a sector of data
+  fn wait_for_data(&self) -> Result<(), AtaError> {
+    for _ in 0..POLL_LIMIT {
+      let status = self.get_status();
+      if status & STATUS_BSY != 0 {
+        continue;
+      }
+      if status & STATUS_ERR != 0 {
+        let error = unsafe { self.error().read_u8() };
+        return Err(AtaError::DeviceError(error));
+      }
+      if status & STATUS_DF != 0 {
+        return Err(AtaError::DeviceFault);
+      }
+      if status & STATUS_DRQ != 0 {
+        return Ok(());
+      }
+    }
+    Err(AtaError::Timeout)
+  }
+
+  fn select(&self, position: DrivePosition, lba_top: u8) {
+    unsafe {
+      self.drive_select().write_u8(position.select_bits() | (lba_top & 0x0f));
+    }
+    self.delay_400ns();
+  }
+
+  fn setup_transfer(&self, position: DrivePosition, lba: usize, count: usize) -> Result<(), AtaError> {
+    if lba + count > 0x10000000 {
+      return Err(AtaError::OutOfRange);
+    }
+    self.select(position, (lba >> 24) as u8);
+    self.wait_not_busy()?;
+    unsafe {
+      // A count of zero means 256 sectors
+      self.sector_count().write_u8(count as u8);
+      self.lba_low().write_u8(lba as u8);
+      self.lba_mid().write_u8((lba >> 8) as u8);
+      self.lba_high().write_u8((lba >> 16) as u8);
+    }
+    Ok(())
+  }
+
+  /// Ask a drive to describe itself. Returns NoDevice if the position is
+  /// empty, or is occupied by something other than an ATA disk.
+  pub fn identify(&self, position: DrivePosition) -> Result<DriveIdentity, AtaError> {
+    let _guard = self.lock.lock();
+    unsafe {
+      self.control.write_u8(CONTROL_NO_INTERRUPTS);
+    }
+    self.select(position, 0);
+    unsafe {
+      self.sector_count().write_u8(0);
+      self.lba_low().write_u8(0);
+      self.lba_mid().write_u8(0);
+      self.lba_high().write_u8(0);
+      self.command().write_u8(COMMAND_IDENTIFY);
+    }
+    let status = self.get_status();
+    if status == 0 || status == 0xff {
+      // No drive, or a floating bus with no controller
+      return Err(AtaError::NoDevice);
+    }
+    self.wait_not_busy()?;
+    let signature = unsafe { (self.lba_mid().read_u8(), self.lba_high().read_u8()) };
+    if signature == ATAPI_SIGNATURE {
+      return Err(AtaError::PacketDevice);
+    }
+    if signature != (0, 0) {
+      // SATA devices identify themselves with a signature here
+      return Err(AtaError::NoDevice);
+    }
+    self.wait_for_data()?;
+    let mut words = [0u16; 256];
+    for word in words.iter_mut() {
+      *word = unsafe { self.data().read_u16() };
+    }
+    DriveIdentity::from_words(&words)
+  }
+
+  /// Read consecutive sectors into the buffer, whose length must be a multiple
+  /// of the sector size
+  pub fn read_sectors(&self, position: DrivePosition, lba: usize, buffer: &mut [u8]) -> Result<(), AtaError> {
+    let _guard = self.lock.lock();
+    let total = buffer.len() / SECTOR_SIZE;
+    let mut done = 0;
+    while done < total {
+      let count = (total - done).min(MAX_SECTORS_PER_COMMAND);
+      self.setup_transfer(position, lba + done, count)?;
+      unsafe {
+        self.command().write_u8(COMMAND_READ_SECTORS);
+      }
+      for sector in 0..count {
+        self.wait_for_data()?;
+        let start = (done + sector) * SECTOR_SIZE;
+        for i in 0..(SECTOR_SIZE / 2) {
+          let word = unsafe { self.data().read_u16() };
+          buffer[start + i * 2] = word as u8;
+          buffer[start + i * 2 + 1] = (word >> 8) as u8;
+        }
+      }
+      done += count;
+    }
+    Ok(())
+  }
+
+  /// Write consecutive sectors from the buffer, whose length must be a
+  /// multiple of the sector size. The drive's write cache is flushed before
+  /// returning.
+  pub fn write_sectors(&self, position: DrivePosition, lba: usize, buffer: &[u8]) -> Result<(), AtaError> {
+    let _guard = self.lock.lock();
+    let total = buffer.len() / SECTOR_SIZE;
+    let mut done = 0;
+    while done < total {
+      let count = (total - done).min(MAX_SECTORS_PER_COMMAND);
+      self.setup_transfer(position, lba + done, count)?;
+      unsafe {
+        self.command().write_u8(COMMAND_WRITE_SECTORS);
+      }
+      for sector in 0..count {
+        self.wait_for_data()?;
+        let start = (done + sector) * SECTOR_SIZE;
+        for i in 0..(SECTOR_SIZE / 2) {
+          let word = buffer[start + i * 2] as u16 | ((buffer[start + i * 2 + 1] as u16) << 8);
+          unsafe {
+            self.data().write_u16(word);
+          }
+        }
+      }
+      done += count;
+    }
+    unsafe {
+      self.command().write_u8(COMMAND_CACHE_FLUSH);
+    }
+    let status = self.wait_not_busy()?;
+    if status & STATUS_ERR != 0 {
+      let error = unsafe { self.error().read_u8() };
+      return Err(AtaError::DeviceError(error));
+    }
+    Ok(())
+  }
+
+  /// Identify an ATAPI device, returning its model string
+  pub fn identify_packet(&self, position: DrivePosition) -> Result<[u8; 40], AtaError> {
+    let _guard = self.lock.lock();
+    self.select(position, 0);
+    unsafe {
+      self.command().write_u8(COMMAND_IDENTIFY_PACKET);
+    }
+    self.wait_for_data()?;
+    let mut words = [0u16; 256];
+    for word in words.iter_mut() {
+      *word = unsafe { self.data().read_u16() };
+    }
+    Ok(model_from_words(&words))
+  }
+
+  /// Issue a PACKET command, and send the SCSI command packet once the device
+  /// is ready for it. `byte_limit` is the largest amount of data the device
+  /// may return for each DRQ block.
+  fn send_packet(&self, position: DrivePosition, packet: &[u8; 12], byte_limit: u16) -> Result<(), AtaError> {
+    self.select(position, 0);
+    self.wait_not_busy()?;
+    unsafe {
+      // PIO transfer, no DMA or overlap
+      self.error().write_u8(0);
+      self.lba_mid().write_u8(byte_limit as u8);
+      self.lba_high().write_u8((byte_limit >> 8) as u8);
+      self.command().write_u8(COMMAND_PACKET);
+    }
+    self.wait_for_data()?;
+    for i in 0..6 {
+      let word = packet[i * 2] as u16 | ((packet[i * 2 + 1] as u16) << 8);
+      unsafe {
+        self.data().write_u16(word);
+      }
+    }
+    Ok(())
+  }
+
+  /// Read a single DRQ block from the device. The device reports how many
+  /// bytes it is sending in the LBA mid and high registers.
+  fn receive_block(&self, buffer: &mut [u8]) -> Result<usize, AtaError> {
+    self.wait_for_data()?;
+    let length = unsafe {
+      self.lba_mid().read_u8() as usize | ((self.lba_high().read_u8() as usize) << 8)
+    };
+    for i in 0..(length / 2) {
+      let word = unsafe { self.data().read_u16() };
+      if i * 2 + 1 < buffer.len() {
+        buffer[i * 2] = word as u8;
+        buffer[i * 2 + 1] = (word >> 8) as u8;
+      }
+    }
+    Ok(length.min(buffer.len()))
+  }
+
+  fn finish_packet(&self) -> Result<(), AtaError> {
+    let status = self.wait_not_busy()?;
+    if status & STATUS_ERR != 0 {
+      let error = unsafe { self.error().read_u8() };
+      return Err(AtaError::DeviceError(error));
+    }
+    Ok(())
+  }
+
+  /// Ask an ATAPI device for the number of sectors on the inserted media. Fails
+  /// if the drive is empty.
+  pub fn read_capacity(&self, position: DrivePosition) -> Result<usize, AtaError> {
+    let _guard = self.lock.lock();
+    let mut packet = [0; 12];
+    packet[0] = SCSI_READ_CAPACITY;
+    self.send_packet(position, &packet, 8)?;
+    let mut response = [0; 8];
+    self.receive_block(&mut response)?;
+    self.finish_packet()?;
+    // The response holds the last LBA, followed by the block size, both
+    // big-endian
+    let last_lba = u32::from_be_bytes([response[0], response[1], response[2], response[3]]);
+    Ok(last_lba as usize + 1)
+  }
+
+  /// Read consecutive 2048-byte sectors from an ATAPI device
+  pub fn read_packet_sectors(&self, position: DrivePosition, lba: usize, buffer: &mut [u8]) -> Result<(), AtaError> {
+    let _guard = self.lock.lock();
+    let total = buffer.len() / ATAPI_SECTOR_SIZE;
+    let mut done = 0;
+    while done < total {
+      let count = (total - done).min(0xffff);
+      let start = lba + done;
+      let packet = [
+        SCSI_READ_10,
+        0,
+        (start >> 24) as u8,
+        (start >> 16) as u8,
+        (start >> 8) as u8,
+        start as u8,
+        0,
+        (count >> 8) as u8,
+        count as u8,
+        0,
+        0,
+        0,
+      ];
+      self.send_packet(position, &packet, ATAPI_SECTOR_SIZE as u16)?;
+      for sector in 0..count {
+        let offset = (done + sector) * ATAPI_SECTOR_SIZE;
+        self.receive_block(&mut buffer[offset..(offset + ATAPI_SECTOR_SIZE)])?;
+      }
+      self.finish_packet()?;
+      done += count;
+    }
+    Ok(())
+  }
+}
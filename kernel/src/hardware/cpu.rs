@@ -0,0 +1,266 @@
+//! Identifies the processor with CPUID once at boot, so that other parts of
+//! the kernel can check for a feature before relying on it. The 386 and early
+//! 486 processors have no CPUID instruction at all; they are reported as an
+//! unknown vendor with no optional features.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::RwLock;
+use super::pit::{self, PIT};
+
+/// Optional features the kernel cares about, from CPUID leaf 1
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Feature {
+  /// On-chip floating point unit
+  Fpu,
+  /// Time stamp counter, read with RDTSC
+  Tsc,
+  /// Physical address extension
+  Pae,
+  /// On-chip local APIC
+  Apic,
+  Mmx,
+  /// FXSAVE and FXRSTOR, needed to save SSE state
+  Fxsr,
+  Sse,
+  Sse2,
+}
+
+impl Feature {
+  /// Bit in EDX from leaf 1
+  fn get_bit(&self) -> u32 {
+    match self {
+      Feature::Fpu => 1 << 0,
+      Feature::Tsc => 1 << 4,
+      Feature::Pae => 1 << 6,
+      Feature::Apic => 1 << 9,
+      Feature::Mmx => 1 << 23,
+      Feature::Fxsr => 1 << 24,
+      Feature::Sse => 1 << 25,
+      Feature::Sse2 => 1 << 26,
+    }
+  }
+
+  pub fn get_name(&self) -> &'static str {
+    match self {
+      Feature::Fpu => "FPU",
+      Feature::Tsc => "TSC",
+      Feature::Pae => "PAE",
+      Feature::Apic => "APIC",
+      Feature::Mmx => "MMX",
+      Feature::Fxsr => "FXSR",
+      Feature::Sse => "SSE",
+      Feature::Sse2 => "SSE2",
+    }
+  }
+}
+
+pub const ALL_FEATURES: [Feature; 8] = [
+  Feature::Fpu, Feature::Tsc, Feature::Pae, Feature::Apic, Feature::Mmx,
+  Feature::Fxsr, Feature::Sse, Feature::Sse2,
+];
+
+#[derive(Copy, Clone)]
+pub struct CpuInfo {
+  /// Twelve-character vendor string, like "GenuineIntel". All zeroes when the
+  /// processor has no CPUID.
+  vendor: [u8; 12],
+  family: u32,
+  model: u32,
+  stepping: u32,
+  /// EDX from leaf 1, holding the feature flags
+  features: u32,
+}
+
+impl CpuInfo {
+  pub const fn unknown() -> CpuInfo {
+    CpuInfo {
+      vendor: [0; 12],
+      family: 0,
+      model: 0,
+      stepping: 0,
+      features: 0,
+    }
+  }
+
+  pub fn get_vendor(&self) -> &str {
+    match core::str::from_utf8(&self.vendor) {
+      Ok(vendor) if self.vendor[0] != 0 => vendor,
+      _ => "Unknown",
+    }
+  }
+
+  pub fn get_family(&self) -> u32 {
+    self.family
+  }
+
+  pub fn get_model(&self) -> u32 {
+    self.model
+  }
+
+  pub fn get_stepping(&self) -> u32 {
+    self.stepping
+  }
+
+  pub fn has_feature(&self, feature: Feature) -> bool {
+    self.features & feature.get_bit() != 0
+  }
+
+  /// Decode the registers returned by leaves 0 and 1
+  fn from_leaves(leaf_zero: [u32; 4], leaf_one: Option<[u32; 4]>) -> CpuInfo {
+    let mut info = CpuInfo::unknown();
+    // The vendor string is spread across EBX, EDX, and ECX, in that order
+    info.vendor[0..4].copy_from_slice(&leaf_zero[1].to_le_bytes());
+    info.vendor[4..8].copy_from_slice(&leaf_zero[3].to_le_bytes());
+    info.vendor[8..12].copy_from_slice(&leaf_zero[2].to_le_bytes());
+    if let Some([signature, _, _, edx]) = leaf_one {
+      let base_family = (signature >> 8) & 0xf;
+      let base_model = (signature >> 4) & 0xf;
+      // Extended fields only apply to later families
+      info.family = if base_family == 0xf {
+        base_family + ((signature >> 20) & 0xff)
+      } else {
+        base_family
+      };
+      info.model = if base_family == 0x6 || base_family == 0xf {
+        base_model | (((signature >> 16) & 0xf) << 4)
+      } else {
+        base_model
+      };
+      info.stepping = signature & 0xf;
+      info.features = edx;
+    }
+    info
+  }
+}
+
+impl fmt::Display for CpuInfo {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.get_vendor())?;
+    if self.family != 0 {
+      write!(f, " family {} model {} stepping {}", self.family, self.model, self.stepping)?;
+    }
+    write!(f, ", features:")?;
+    let mut any = false;
+    for feature in ALL_FEATURES.iter() {
+      if self.has_feature(*feature) {
+        write!(f, " {}", feature.get_name())?;
+        any = true;
+      }
+    }
+    if !any {
+      write!(f, " none")?;
+    }
+    Ok(())
+  }
+}
+
+static CPU_INFO: RwLock<CpuInfo> = RwLock::new(CpuInfo::unknown());
+
+/// CPUID is available if the ID bit in EFLAGS can be changed
+fn cpuid_supported() -> bool {
+  let changed: u32;
+  unsafe {
+    llvm_asm!("pushfd
+          pop eax
+          mov ecx, eax
+          xor eax, 0x200000
+          push eax
+          popfd
+          pushfd
+          pop eax
+          push ecx
+          popfd
+          xor eax, ecx" : "={eax}"(changed) : :
+          "ecx" :
+          "intel", "volatile"
+    );
+  }
+  changed & 0x200000 != 0
+}
+
+/// Run CPUID for a leaf, returning EAX, EBX, ECX, and EDX. EBX holds the PLT
+/// address, so its result is passed out through ESI instead.
+fn cpuid(leaf: u32) -> [u32; 4] {
+  let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+  unsafe {
+    llvm_asm!("mov esi, ebx
+          cpuid
+          xchg esi, ebx" : "={eax}"(eax), "={esi}"(ebx), "={ecx}"(ecx), "={edx}"(edx) :
+          "{eax}"(leaf), "{ecx}"(0) : :
+          "intel", "volatile"
+    );
+  }
+  [eax, ebx, ecx, edx]
+}
+
+/// Identify the processor. Must run once at boot, before anything checks for
+/// a feature.
+pub fn init() {
+  if !cpuid_supported() {
+    return;
+  }
+  let leaf_zero = cpuid(0);
+  let leaf_one = if leaf_zero[0] >= 1 {
+    Some(cpuid(1))
+  } else {
+    None
+  };
+  *CPU_INFO.write() = CpuInfo::from_leaves(leaf_zero, leaf_one);
+  calibrate_tsc();
+}
+
+pub fn get_info() -> CpuInfo {
+  *CPU_INFO.read()
+}
+
+pub fn has_feature(feature: Feature) -> bool {
+  CPU_INFO.read().has_feature(feature)
+}
+
+/// Read the time stamp counter, if the processor has one. It counts cycles,
+/// so it needs to be calibrated against a known timer before it can be
+/// turned into a duration.
+pub fn read_tsc() -> Option<u64> {
+  if !has_feature(Feature::Tsc) {
+    return None;
+  }
+  let (low, high): (u32, u32);
+  unsafe {
+    llvm_asm!("rdtsc" : "={eax}"(low), "={edx}"(high) : : : "intel", "volatile");
+  }
+  Some(((high as u64) << 32) | low as u64)
+}
+
+/// TSC ticks per millisecond, or zero if it has not been measured
+static TSC_KHZ: AtomicU32 = AtomicU32::new(0);
+
+/// How many calibration intervals fit in a second
+const CALIBRATION_DIVISOR: u32 = 100;
+
+/// Count TSC ticks across 10ms of PIT channel 2. Runs at boot, before the
+/// speaker or anything else uses the channel.
+fn calibrate_tsc() {
+  if !has_feature(Feature::Tsc) {
+    return;
+  }
+  let mut timer = PIT::new();
+  let (start, end) = unsafe {
+    timer.start_one_shot((pit::BASE_FREQUENCY / CALIBRATION_DIVISOR) as u16);
+    let start = read_tsc().unwrap_or(0);
+    while !timer.one_shot_done() {}
+    let end = read_tsc().unwrap_or(start);
+    timer.stop_tone();
+    (start, end)
+  };
+  let per_second = (end - start) * CALIBRATION_DIVISOR as u64;
+  TSC_KHZ.store((per_second / 1000) as u32, Ordering::Relaxed);
+}
+
+/// Rate of the time stamp counter, if the processor has one
+pub fn get_tsc_khz() -> Option<u32> {
+  match TSC_KHZ.load(Ordering::Relaxed) {
+    0 => None,
+    khz => Some(khz),
+  }
+}
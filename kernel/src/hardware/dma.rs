@@ -1,150 +1,297 @@
+//! Interface with old-school ISA DMA, provided by a pair of 8237 controllers.
+//! The first handles the 8-bit channels 0-3, and the second handles the 16-bit
+//! channels 4-7. Channel 4 cascades the first controller into the second, so
+//! it is never available to drivers.
+//!
+//! Programming a channel takes several writes to registers shared by the whole
+//! controller, so each controller is locked while a channel is being set up.
+//! The channel is masked for the duration, so that the device cannot start a
+//! transfer with a half-written address or count.
+
 use crate::memory::address::PhysicalAddress;
 use crate::x86::io::Port;
 use spin::{Mutex, MutexGuard};
 
-/**
- * Interface with old-school ISA DMA. There are only two chips present in the
- * system, and each enforces locks to ensure there are no conflicts between
- * drivers when setting up addresses, configuration, etc.
- */
+/// Which way data moves during a transfer, from the point of view of memory
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TransferDirection {
+  /// Run the transfer without moving any data
+  Verify,
+  /// The device writes into memory, as when reading from a disk
+  ToMemory,
+  /// The device reads out of memory, as when writing to a disk
+  FromMemory,
+}
+
+/// How the controller paces a transfer
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TransferMode {
+  /// Transfer for as long as the device requests it
+  Demand,
+  /// Transfer one unit per request, the mode used by the floppy and most
+  /// sound cards
+  Single,
+  /// Transfer the whole block once the device makes its first request
+  Block,
+}
+
+/// Everything written to a channel's mode register
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Transfer {
+  pub direction: TransferDirection,
+  pub mode: TransferMode,
+  /// Reload the address and count once the transfer completes, so that the
+  /// device can loop over the same buffer
+  pub auto_init: bool,
+}
+
+impl Transfer {
+  pub const fn single(direction: TransferDirection) -> Transfer {
+    Transfer {
+      direction,
+      mode: TransferMode::Single,
+      auto_init: false,
+    }
+  }
+
+  pub const fn auto_init(self) -> Transfer {
+    Transfer {
+      auto_init: true,
+      ..self
+    }
+  }
+
+  fn mode_byte(&self, channel: u8) -> u8 {
+    let direction = match self.direction {
+      TransferDirection::Verify => 0x00,
+      TransferDirection::ToMemory => 0x04,
+      TransferDirection::FromMemory => 0x08,
+    };
+    let mode = match self.mode {
+      TransferMode::Demand => 0x00,
+      TransferMode::Single => 0x40,
+      TransferMode::Block => 0x80,
+    };
+    let auto_init = if self.auto_init { 0x10 } else { 0 };
+    mode | auto_init | direction | (channel & 3)
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DMAError {
+  /// The channel does not exist, or is the cascade channel
+  InvalidChannel,
+  /// The buffer is above the 16MiB the controller can address
+  AddressTooHigh,
+  /// The buffer is empty, larger than a single transfer, or misaligned for a
+  /// 16-bit channel
+  InvalidLength,
+  /// The buffer crosses a 64KiB boundary on an 8-bit channel, or a 128KiB
+  /// boundary on a 16-bit channel
+  CrossesBoundary,
+}
+
+/// Highest physical address a transfer can reach
+const ADDRESS_LIMIT: usize = 0x1000000;
+
+/// Check that a buffer can be transferred in one go on a channel
+pub fn validate_transfer(channel: u8, address: PhysicalAddress, length: usize) -> Result<(), DMAError> {
+  if channel > 7 || channel == 4 {
+    return Err(DMAError::InvalidChannel);
+  }
+  let start = address.as_usize();
+  let word_sized = channel > 4;
+  let page_size = if word_sized { 0x20000 } else { 0x10000 };
+  if length == 0 || length > page_size {
+    return Err(DMAError::InvalidLength);
+  }
+  if word_sized && (start & 1 != 0 || length & 1 != 0) {
+    return Err(DMAError::InvalidLength);
+  }
+  if start + length > ADDRESS_LIMIT {
+    return Err(DMAError::AddressTooHigh);
+  }
+  if start / page_size != (start + length - 1) / page_size {
+    return Err(DMAError::CrossesBoundary);
+  }
+  Ok(())
+}
+
 pub struct DMAController {
-  registers: DMARegisters,
-  // The mask_base serves as the starting point for masking, and the lock around
-  // it is used to ensure only one thread can edit registers at a time
-  mask_base: Mutex<u8>,
+  registers: ControllerRegisters,
+  /// Channel numbers on this controller start here
+  first_channel: u8,
+  /// The second controller counts addresses and lengths in 16-bit words
+  word_sized: bool,
+  lock: Mutex<()>,
 }
 
 impl DMAController {
-  pub fn get_channel(&self, channel: u8) -> DMAChannel {
-    let lock = self.mask_base.lock();
-    unsafe {
-      self.registers.flip_flop_reset.write_u8(0xff);
+  pub const fn low_channels() -> DMAController {
+    DMAController {
+      registers: ControllerRegisters::low_channels(),
+      first_channel: 0,
+      word_sized: false,
+      lock: Mutex::new(()),
     }
-    let channel_mask = *lock | (1 << channel as usize);
-    DMAChannel::new(lock, self.registers.get_channel_registers(channel))
   }
 
-  pub const fn low_channels() -> DMAController {
+  pub const fn high_channels() -> DMAController {
     DMAController {
-      registers: DMARegisters::low_channels(),
-      mask_base: Mutex::new(1),
+      registers: ControllerRegisters::high_channels(),
+      first_channel: 4,
+      word_sized: true,
+      lock: Mutex::new(()),
+    }
+  }
+
+  /// Lock the controller and mask one of its channels, so that it can be
+  /// reprogrammed. The channel is unmasked when the returned value is dropped.
+  pub fn get_channel(&self, channel: u8) -> DMAChannel {
+    let lock = self.lock.lock();
+    let local = channel - self.first_channel;
+    let registers = ChannelRegisters {
+      address: self.registers.address[local as usize],
+      count: self.registers.count[local as usize],
+      page: Port::new(PAGE_PORTS[channel as usize]),
+      controller: &self.registers,
+    };
+    unsafe {
+      self.registers.single_mask.write_u8(0x04 | local);
+    }
+    DMAChannel {
+      _lock: lock,
+      local,
+      word_sized: self.word_sized,
+      registers,
     }
   }
 }
 
-struct DMARegisters {
-  start_address_1: Port,
-  count_register_1: Port,
-  page_1: Port,
-  start_address_2: Port,
-  count_register_2: Port,
-  page_2: Port,
-  start_address_3: Port,
-  count_register_3: Port,
-  page_3: Port,
+/// Page registers, which hold bits 16-23 of each channel's address. Channel 4
+/// has no page register, since it only cascades the first controller.
+const PAGE_PORTS: [u16; 8] = [0x87, 0x83, 0x81, 0x82, 0x8f, 0x8b, 0x89, 0x8a];
+
+struct ControllerRegisters {
+  address: [Port; 4],
+  count: [Port; 4],
+  single_mask: Port,
   mode: Port,
   flip_flop_reset: Port,
-  pub multi_channel_mask: Port,
-}
-
-impl DMARegisters {
-  pub const fn low_channels() -> DMARegisters {
-    DMARegisters {
-      start_address_1: Port::new(0x02),
-      count_register_1: Port::new(0x03),
-      page_1: Port::new(0x83),
-      start_address_2: Port::new(0x04),
-      count_register_2: Port::new(0x05),
-      page_2: Port::new(0x81),
-      start_address_3: Port::new(0x06),
-      count_register_3: Port::new(0x07),
-      page_3: Port::new(0x82),
+}
+
+impl ControllerRegisters {
+  pub const fn low_channels() -> ControllerRegisters {
+    ControllerRegisters {
+      address: [Port::new(0x00), Port::new(0x02), Port::new(0x04), Port::new(0x06)],
+      count: [Port::new(0x01), Port::new(0x03), Port::new(0x05), Port::new(0x07)],
+      single_mask: Port::new(0x0a),
       mode: Port::new(0x0b),
       flip_flop_reset: Port::new(0x0c),
-      multi_channel_mask: Port::new(0x0f)
     }
   }
 
-  pub fn get_channel_registers(&self, channel: u8) -> ChannelRegisters {
-    let (start_address, count_register, page) = match channel {
-      1 => (self.start_address_1, self.count_register_1, self.page_1),
-      2 => (self.start_address_2, self.count_register_2, self.page_2),
-      3 => (self.start_address_3, self.count_register_3, self.page_3),
-      _ => panic!("invalid channel"),
-    };
-    ChannelRegisters {
-      start_address,
-      count_register,
-      page,
-      mode: self.mode,
-      flip_flop_reset: self.flip_flop_reset,
-      multi_channel_mask: self.multi_channel_mask,
+  pub const fn high_channels() -> ControllerRegisters {
+    ControllerRegisters {
+      address: [Port::new(0xc0), Port::new(0xc4), Port::new(0xc8), Port::new(0xcc)],
+      count: [Port::new(0xc2), Port::new(0xc6), Port::new(0xca), Port::new(0xce)],
+      single_mask: Port::new(0xd4),
+      mode: Port::new(0xd6),
+      flip_flop_reset: Port::new(0xd8),
     }
   }
 }
 
-pub struct ChannelRegisters {
-  pub start_address: Port,
-  pub count_register: Port,
-  pub page: Port,
-  pub mode: Port,
-  pub flip_flop_reset: Port,
-  pub multi_channel_mask: Port,
+struct ChannelRegisters<'a> {
+  address: Port,
+  count: Port,
+  page: Port,
+  controller: &'a ControllerRegisters,
 }
 
-impl ChannelRegisters {
-  pub fn set_address(&self, addr: PhysicalAddress) {
-    let addr_32 = addr.as_u32();
-    let addr_low = (addr_32 & 0xff) as u8;
-    let addr_mid = ((addr_32 >> 8) & 0xff) as u8;
-    let addr_high = ((addr_32 >> 16) & 0xff) as u8;
-
-    unsafe {
-      self.flip_flop_reset.write_u8(0xff);
-      self.start_address.write_u8(addr_low);
-      self.start_address.write_u8(addr_mid);
-      self.page.write_u8(addr_high);
-    }
+impl<'a> ChannelRegisters<'a> {
+  /// The address and count registers take two bytes in a row, low byte first.
+  /// Resetting the flip-flop makes sure the next access is the low byte.
+  unsafe fn write_pair(&self, port: Port, value: u16) {
+    self.controller.flip_flop_reset.write_u8(0xff);
+    port.write_u8((value & 0xff) as u8);
+    port.write_u8((value >> 8) as u8);
   }
 
-  pub fn set_count(&self, count: usize) {
-    let count_low = (count & 0xff) as u8;
-    let count_high = ((count >> 8) & 0xff) as u8;
-
-    unsafe {
-      self.flip_flop_reset.write_u8(0xff);
-      self.count_register.write_u8(count_low);
-      self.count_register.write_u8(count_high);
-    }
+  unsafe fn read_pair(&self, port: Port) -> u16 {
+    self.controller.flip_flop_reset.write_u8(0xff);
+    let low = port.read_u8() as u16;
+    let high = port.read_u8() as u16;
+    (high << 8) | low
   }
 }
 
+/// A channel that has been masked and locked for programming
 pub struct DMAChannel<'a> {
-  mask_base: MutexGuard<'a, u8>,
-  registers: ChannelRegisters,
+  _lock: MutexGuard<'a, ()>,
+  /// Channel number within its controller
+  local: u8,
+  word_sized: bool,
+  registers: ChannelRegisters<'a>,
 }
 
 impl<'a> DMAChannel<'a> {
-  pub fn new(mask_base: MutexGuard<'a, u8>, registers: ChannelRegisters) -> DMAChannel {
-    DMAChannel {
-      mask_base,
-      registers,
+  /// Set the physical address of the buffer. On a 16-bit channel the address
+  /// must be even.
+  pub fn set_address(&self, addr: PhysicalAddress) {
+    let addr_32 = addr.as_u32();
+    let offset = if self.word_sized {
+      // The second controller counts words, and shifts the address left by
+      // one when it puts it on the bus. Bit 16 comes from the offset, so the
+      // page register's lowest bit is ignored.
+      (addr_32 >> 1) & 0xffff
+    } else {
+      addr_32 & 0xffff
+    };
+    unsafe {
+      self.registers.write_pair(self.registers.address, offset as u16);
+      self.registers.page.write_u8(((addr_32 >> 16) & 0xff) as u8);
     }
   }
 
-  pub fn set_address(&self, addr: PhysicalAddress) {
-    self.registers.set_address(addr);
+  /// Set the raw count register, which holds one less than the number of
+  /// units to transfer. Units are bytes on channels 0-3, and words on 4-7.
+  pub fn set_count(&self, count: usize) {
+    unsafe {
+      self.registers.write_pair(self.registers.count, count as u16);
+    }
   }
 
-  pub fn set_count(&self, count: usize) {
-    self.registers.set_count(count);
+  /// Set the number of bytes to transfer
+  pub fn set_length(&self, length: usize) {
+    let units = if self.word_sized { length >> 1 } else { length };
+    self.set_count(units - 1);
   }
 
-  pub fn set_mode(&self, mode: u8) {
+  pub fn set_mode(&self, transfer: Transfer) {
     unsafe {
-      self.registers.mode.write_u8(mode);
+      self.registers.controller.mode.write_u8(transfer.mode_byte(self.local));
     }
   }
+
+  /// Program a whole transfer at once, after checking that the controller
+  /// can actually perform it
+  pub fn program(&self, addr: PhysicalAddress, length: usize, transfer: Transfer) -> Result<(), DMAError> {
+    let channel = if self.word_sized { self.local + 4 } else { self.local };
+    validate_transfer(channel, addr, length)?;
+    self.set_mode(transfer);
+    self.set_address(addr);
+    self.set_length(length);
+    Ok(())
+  }
+
+  /// Number of bytes left in the current transfer. The count register
+  /// underflows to 0xffff once a transfer completes, which reads as zero.
+  pub fn get_remaining(&self) -> usize {
+    let count = unsafe { self.registers.read_pair(self.registers.count) };
+    let units = (count as usize + 1) & 0xffff;
+    if self.word_sized { units << 1 } else { units }
+  }
 }
 
 /**
@@ -153,25 +300,31 @@ impl<'a> DMAChannel<'a> {
 impl<'a> Drop for DMAChannel<'a> {
   fn drop(&mut self) {
     unsafe {
-      self.registers.multi_channel_mask.write_u8(*self.mask_base);
+      self.registers.controller.single_mask.write_u8(self.local);
     }
   }
 }
 
 pub struct DMA {
   low: DMAController,
-  //high: DMAController,
+  high: DMAController,
 }
 
 impl DMA {
   pub const fn new() -> DMA {
     DMA {
       low: DMAController::low_channels(),
+      high: DMAController::high_channels(),
     }
   }
 
+  /// Lock and mask a channel for programming. Panics on channel 4, which
+  /// links the two controllers, or on a channel that does not exist.
   pub fn get_channel(&self, channel: u8) -> DMAChannel {
-    let source = &self.low;
-    source.get_channel(channel % 4)
+    match channel {
+      0..=3 => self.low.get_channel(channel),
+      5..=7 => self.high.get_channel(channel),
+      _ => panic!("invalid DMA channel {}", channel),
+    }
   }
 }
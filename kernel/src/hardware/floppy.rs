@@ -7,18 +7,47 @@
 //! for an IRQ6 interrupt if the command returns a response. Sending commands
 //! involves looping and waiting for some result, and is frequently problematic.
 //! Drivers accessing the floppy controller should be aware of this.
+//!
+//! While waiting for IRQ6, the calling process is blocked on a WaitQueue, so
+//! a slow seek or a missing disk does not keep it spinning on the CPU. Every
+//! wait is bounded by a timeout, after which the command is treated as failed.
 
-use crate::drivers::blocking::WakeReference;
-use crate::process::{get_current_pid, send_signal, sleep, yield_coop};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::collections::TimerId;
+use crate::process::{sleep, yield_coop};
+use crate::task::WaitQueue;
+use crate::workers::{cancel_delayed_work, schedule_delayed_work};
 use crate::x86::io::Port;
 use spin::RwLock;
 
+/// How long the motor keeps spinning after the last operation, so that a run
+/// of reads does not wait for it to spin up each time
+const MOTOR_OFF_DELAY_MS: usize = 2000;
+/// How long the motor needs to reach a stable speed before reading
+const MOTOR_SPIN_UP_MS: usize = 300;
+/// Longest wait for IRQ6. A transfer with no disk in the drive never
+/// completes, so this is what eventually reports the failure.
+const INTERRUPT_TIMEOUT_MS: usize = 3000;
+/// Reads and writes that fail are retried this many times, recalibrating
+/// the drive between attempts
+const TRANSFER_ATTEMPTS: usize = 3;
+
+/// End of track, the last sector number on each side of a 1.44M disk
+const SECTORS_PER_TRACK: u8 = 18;
+
 #[derive(Copy, Clone, Debug)]
 pub enum ControllerError {
   InvalidResponse,
   NotReadyForParam,
   ReadyTimeout,
-  UnsupportedController
+  UnsupportedController,
+  /// IRQ6 did not arrive in time
+  InterruptTimeout,
+  /// The command ended abnormally. Contains the ST0, ST1, and ST2 status
+  /// bytes returned by the controller.
+  TransferFailed(u8, u8, u8),
+  /// The disk's write-protect tab is set
+  WriteProtected,
 }
 
 pub struct FloppyController {
@@ -26,10 +55,20 @@ pub struct FloppyController {
   /// Reset before each interrupt-blocked request, set to true each time an INT6
   /// is fired. This helps cover cases where the hardware finishes work before
   /// the driver code starts looking for an interrupt.
-  interrupt_received: RwLock<bool>,
-  wake_on_int: WakeReference,
+  interrupt_received: AtomicBool,
+  /// Set by the timeout job if IRQ6 has not arrived in time
+  interrupt_timed_out: AtomicBool,
+  /// Processes waiting for IRQ6
+  interrupt_queue: WaitQueue,
 
   motor_on: RwLock<bool>,
+  /// Operations currently relying on the motor
+  motor_users: RwLock<usize>,
+  /// Pending job that turns the motor off once it has been idle
+  motor_timer: RwLock<Option<TimerId>>,
+  /// The disk change line can only be read while the motor is on, so its
+  /// state is captured before the motor is turned off
+  change_latched: RwLock<bool>,
 
   dor_port: Port,
   msr_port: Port,
@@ -41,9 +80,13 @@ impl FloppyController {
   pub const fn new() -> FloppyController {
     FloppyController {
       initialized: RwLock::new(false),
-      interrupt_received: RwLock::new(false),
-      wake_on_int: WakeReference::new(),
+      interrupt_received: AtomicBool::new(false),
+      interrupt_timed_out: AtomicBool::new(false),
+      interrupt_queue: WaitQueue::new(),
       motor_on: RwLock::new(false),
+      motor_users: RwLock::new(0),
+      motor_timer: RwLock::new(None),
+      change_latched: RwLock::new(false),
       dor_port: Port::new(0x3f2),
       msr_port: Port::new(0x3f4),
       fifo_port: Port::new(0x3f5),
@@ -67,26 +110,37 @@ impl FloppyController {
   /// When IRQ6 is triggered, this method should be called to alert any blocked
   /// process that work has completed.
   pub fn handle_int6(&self) {
-    match self.interrupt_received.try_write() {
-      Some(mut lock) => *lock = true,
-      // if it's already being written, ignore the interrupt
-      None => (),
-    }
-    self.wake_on_int.wake();
+    self.interrupt_received.store(true, Ordering::SeqCst);
+    self.interrupt_queue.notify_all();
   }
 
-  ///
-  pub fn wait_for_interrupt(&self) {
-    if let Some(val) = self.interrupt_received.try_read() {
-      if *val {
-        return;
-      }
+  /// Forget any earlier interrupt. Called before each action that will raise
+  /// IRQ6, so that the wait that follows cannot see a stale one.
+  fn expect_interrupt(&self) {
+    self.interrupt_received.store(false, Ordering::SeqCst);
+  }
+
+  /// Block until IRQ6 arrives, or until INTERRUPT_TIMEOUT_MS passes
+  pub fn wait_for_interrupt(&'static self) -> Result<(), ControllerError> {
+    if self.interrupt_received.load(Ordering::SeqCst) {
+      return Ok(());
+    }
+    self.interrupt_timed_out.store(false, Ordering::SeqCst);
+    let timer = schedule_delayed_work(move || {
+      self.interrupt_timed_out.store(true, Ordering::SeqCst);
+      self.interrupt_queue.notify_all();
+    }, INTERRUPT_TIMEOUT_MS).ok();
+    self.interrupt_queue.wait_until(|| {
+      self.interrupt_received.load(Ordering::SeqCst) || self.interrupt_timed_out.load(Ordering::SeqCst)
+    });
+    if let Some(timer) = timer {
+      cancel_delayed_work(timer);
+    }
+    if self.interrupt_received.load(Ordering::SeqCst) {
+      Ok(())
+    } else {
+      Err(ControllerError::InterruptTimeout)
     }
-    let pid = get_current_pid();
-    self.wake_on_int.set_process(pid);
-    send_signal(pid, syscall::signals::STOP);
-    yield_coop();
-    self.wake_on_int.clear_process();
   }
 
   /// The RQM bit indicates that a driver can now read or write data at the FIFO
@@ -114,12 +168,12 @@ impl FloppyController {
   /// Issue a command to the floppy controller. If it succeeds, it will return
   /// an Ok Result. Because not all commands have a response phase, handling
   /// the response from a command is done in a different method.
-  pub fn send_command(&self, command: Command, params: &[u8]) -> Result<(), ControllerError> {
+  pub fn send_command(&'static self, command: Command, params: &[u8]) -> Result<(), ControllerError> {
     if self.get_status() & 0xc0 != 0x80 {
       self.reset()?;
     }
 
-    *self.interrupt_received.write() = false;
+    self.expect_interrupt();
     unsafe {
       self.fifo_port.write_u8(command as u8);
     }
@@ -176,15 +230,61 @@ impl FloppyController {
       self.dor_port.write_u8(0x10 | dor);
     }
     *motor = true;
-    sleep(300);
+    sleep(MOTOR_SPIN_UP_MS);
+  }
+
+  /// Keep the motor spinning while `f` runs. Once no operation needs it, the
+  /// motor is turned off after a short delay.
+  pub fn with_motor<R, F: FnOnce() -> R>(&'static self, f: F) -> R {
+    *self.motor_users.write() += 1;
+    if let Some(timer) = self.motor_timer.write().take() {
+      cancel_delayed_work(timer);
+    }
+    self.ensure_motor_on();
+    let result = f();
+
+    let idle = {
+      let mut users = self.motor_users.write();
+      *users -= 1;
+      *users == 0
+    };
+    if idle {
+      if let Ok(timer) = schedule_delayed_work(move || self.turn_off_idle_motor(), MOTOR_OFF_DELAY_MS) {
+        *self.motor_timer.write() = Some(timer);
+      }
+    }
+    result
   }
 
-  pub fn reset(&self) -> Result<(), ControllerError> {
+  fn turn_off_idle_motor(&self) {
+    // An operation may have started after this job was already running
+    if *self.motor_users.read() > 0 {
+      return;
+    }
+    *self.motor_timer.write() = None;
+    let mut motor = self.motor_on.write();
+    if !*motor {
+      return;
+    }
+    if self.read_change_line() {
+      *self.change_latched.write() = true;
+    }
+    unsafe {
+      let dor = self.dor_port.read_u8();
+      self.dor_port.write_u8(dor & !0x10);
+    }
+    *motor = false;
+  }
+
+  pub fn reset(&'static self) -> Result<(), ControllerError> {
+    self.expect_interrupt();
     unsafe {
+      // Resetting clears the motor bit, so keep it on if it was running
+      let motor = if *self.motor_on.read() { 0x10 } else { 0 };
       self.dor_port.write_u8(0);
-      self.dor_port.write_u8(0x0c);
+      self.dor_port.write_u8(0x0c | motor);
     }
-    self.wait_for_interrupt();
+    self.wait_for_interrupt()?;
 
     let mut sense = [0, 0];
     for _ in 0..4 {
@@ -202,7 +302,23 @@ impl FloppyController {
     Ok(())
   }
 
-  pub fn init(&self) -> Result<(), ControllerError> {
+  /// Move the head back to track 0. A single recalibrate steps at most 79
+  /// tracks, so it is tried twice before giving up.
+  fn recalibrate(&'static self) -> Result<(), ControllerError> {
+    let mut st0 = [0, 0];
+    for _ in 0..2 {
+      self.send_command(Command::Recalibrate, &[0])?;
+      self.wait_for_interrupt()?;
+      self.send_command(Command::SenseInterrupt, &[])?;
+      self.get_response(&mut st0)?;
+      if st0[0] & 0x20 == 0x20 {
+        return Ok(());
+      }
+    }
+    Err(ControllerError::TransferFailed(st0[0], 0, 0))
+  }
+
+  pub fn init(&'static self) -> Result<(), ControllerError> {
     self.send_command(Command::Version, &[])?;
     let mut version_response = [0];
     self.get_response(&mut version_response)?;
@@ -215,34 +331,85 @@ impl FloppyController {
     self.get_response(&mut lock_response)?;
     // Check if lock bit is set?
     self.reset()?;
-    self.ensure_motor_on();
-    let mut st0 = [0, 0];
-    self.send_command(Command::Recalibrate, &[0])?;
-    self.wait_for_interrupt();
-    self.send_command(Command::SenseInterrupt, &[])?;
-    self.get_response(&mut st0)?;
-    if st0[0] & 0x20 != 0x20 {
-      // Retry command
-      self.send_command(Command::Recalibrate, &[0])?;
-      self.wait_for_interrupt();
-      self.send_command(Command::SenseInterrupt, &[])?;
-      self.get_response(&mut st0)?;
-    }
+    self.with_motor(|| self.recalibrate())?;
 
     *self.initialized.write() = true;
 
     Ok(())
   }
 
-  pub fn read(&self, cylinder: usize, head: usize, sector: usize) -> Result<(), ControllerError> {
-    self.dma(Command::ReadData, cylinder, head, sector)
+  /// Bit 7 of the digital input register is the disk change line. It is set
+  /// when the disk is removed, and stays set until a seek is performed with a
+  /// disk in the drive. The line is only valid while the motor is on; while
+  /// it is off, the state captured when the motor stopped is reported.
+  pub fn disk_changed(&self) -> bool {
+    if *self.change_latched.read() {
+      return true;
+    }
+    if !*self.motor_on.read() {
+      return false;
+    }
+    self.read_change_line()
   }
 
-  pub fn write(&self, cylinder: usize, head: usize, sector: usize) -> Result<(), ControllerError> {
-    self.dma(Command::WriteData, cylinder, head, sector)
+  fn read_change_line(&self) -> bool {
+    unsafe {
+      self.ccr_dir_port.read_u8() & 0x80 != 0
+    }
   }
 
-  pub fn dma(&self, command: Command, cylinder: usize, head: usize, sector: usize) -> Result<(), ControllerError> {
+  /// Attempt to clear the disk change line by seeking away from track 0 and
+  /// back. Returns true if a disk is present, in which case the line is now
+  /// clear.
+  pub fn acknowledge_disk_change(&'static self) -> Result<bool, ControllerError> {
+    self.with_motor(|| {
+      let mut st0 = [0, 0];
+      self.send_command(Command::Seek, &[0, 1])?;
+      self.wait_for_interrupt()?;
+      self.send_command(Command::SenseInterrupt, &[])?;
+      self.get_response(&mut st0)?;
+      self.send_command(Command::Recalibrate, &[0])?;
+      self.wait_for_interrupt()?;
+      self.send_command(Command::SenseInterrupt, &[])?;
+      self.get_response(&mut st0)?;
+      let changed = self.read_change_line();
+      *self.change_latched.write() = changed;
+      Ok(!changed)
+    })
+  }
+
+  /// Read into the DMA buffer, starting at the given sector. The controller
+  /// keeps reading sectors until the DMA channel reaches the end of its
+  /// count, so a single call can read the rest of a track.
+  pub fn read(&'static self, cylinder: usize, head: usize, sector: usize) -> Result<(), ControllerError> {
+    self.with_motor(|| self.transfer(Command::ReadData, cylinder, head, sector))
+  }
+
+  pub fn write(&'static self, cylinder: usize, head: usize, sector: usize) -> Result<(), ControllerError> {
+    self.with_motor(|| self.transfer(Command::WriteData, cylinder, head, sector))
+  }
+
+  /// Run a read or write command, recalibrating and retrying if it fails. A
+  /// write-protected disk will never succeed, so it is not retried.
+  fn transfer(&'static self, command: Command, cylinder: usize, head: usize, sector: usize) -> Result<(), ControllerError> {
+    let mut result = Ok(());
+    for attempt in 0..TRANSFER_ATTEMPTS {
+      if attempt > 0 {
+        // The head may have lost track of its position; if even that fails,
+        // the controller is reset on the next command
+        let _ = self.recalibrate();
+      }
+      result = self.dma(command, cylinder, head, sector);
+      match result {
+        Ok(_) | Err(ControllerError::WriteProtected) => return result,
+        Err(_) => (),
+      }
+    }
+    result
+  }
+
+  /// Run a read or write command once. The motor must already be on.
+  pub fn dma(&'static self, command: Command, cylinder: usize, head: usize, sector: usize) -> Result<(), ControllerError> {
     self.send_command(
       command,
       &[
@@ -251,19 +418,27 @@ impl FloppyController {
         head as u8,
         sector as u8,
         2,
-        18,
+        SECTORS_PER_TRACK,
         0x1b,
         0xff,
       ],
     )?;
-    self.wait_for_interrupt();
+    self.wait_for_interrupt()?;
     let mut response = [0, 0, 0, 0, 0, 0, 0];
     self.get_response(&mut response)?;
-    // Process response
-    Ok(())
+    let (st0, st1, st2) = (response[0], response[1], response[2]);
+    // The top two bits of ST0 are zero when the command ended normally
+    if st0 & 0xc0 == 0 {
+      Ok(())
+    } else if st1 & 0x02 != 0 {
+      Err(ControllerError::WriteProtected)
+    } else {
+      Err(ControllerError::TransferFailed(st0, st1, st2))
+    }
   }
 }
 
+#[derive(Copy, Clone)]
 #[repr(u8)]
 pub enum Command {
   ReadTrack = 0x02,
@@ -1,7 +1,11 @@
+pub mod apic;
+pub mod ata;
+pub mod cpu;
 pub mod dma;
 pub mod floppy;
 pub mod pic;
 pub mod pit;
+pub mod ps2mouse;
 pub mod qemu;
 pub mod rtc;
 pub mod vga;
\ No newline at end of file
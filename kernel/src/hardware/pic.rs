@@ -28,6 +28,36 @@ impl PIC {
     self.secondary_data.write_u8(0x01);
   }
 
+  /// Block every IRQ, on both chips
+  pub unsafe fn mask_all(&mut self) {
+    self.primary_data.write_u8(0xff);
+    self.secondary_data.write_u8(0xff);
+  }
+
+  pub unsafe fn mask_irq(&mut self, irq: u8) {
+    if irq >= 8 {
+      let mask = self.secondary_data.read_u8();
+      self.secondary_data.write_u8(mask | (1 << (irq - 8)));
+    } else {
+      let mask = self.primary_data.read_u8();
+      self.primary_data.write_u8(mask | (1 << irq));
+    }
+  }
+
+  /// Allow an IRQ through. IRQs on the secondary chip also need the cascade
+  /// line on the primary unmasked.
+  pub unsafe fn unmask_irq(&mut self, irq: u8) {
+    if irq >= 8 {
+      let mask = self.secondary_data.read_u8();
+      self.secondary_data.write_u8(mask & !(1 << (irq - 8)));
+      let mask = self.primary_data.read_u8();
+      self.primary_data.write_u8(mask & !(1 << 2));
+    } else {
+      let mask = self.primary_data.read_u8();
+      self.primary_data.write_u8(mask & !(1 << irq));
+    }
+  }
+
   pub unsafe fn acknowledge_interrupt(&mut self, irq: u8) {
     if irq >= 8 {
       // send command to second chip too
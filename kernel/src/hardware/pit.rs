@@ -1,9 +1,15 @@
 use crate::x86::io::Port;
 
+/// Input clock of the PIT, in Hz
+pub const BASE_FREQUENCY: u32 = 1193182;
+
 pub struct PIT {
   channel_0_data: Port,
   channel_2_data: Port,
   command: Port,
+  /// Keyboard controller port B. The lowest two bits gate channel 2 and
+  /// connect its output to the PC speaker.
+  speaker_control: Port,
 }
 
 impl PIT {
@@ -12,6 +18,7 @@ impl PIT {
       channel_0_data: Port::new(0x40),
       channel_2_data: Port::new(0x42),
       command: Port::new(0x43),
+      speaker_control: Port::new(0x61),
     }
   }
 
@@ -20,4 +27,34 @@ impl PIT {
     self.channel_0_data.write_u8((div & 0xff) as u8); // LSB
     self.channel_0_data.write_u8((div >> 8) as u8); // MSB
   }
-}
\ No newline at end of file
+
+  /// Play a square wave through the PC speaker until stop_tone is called
+  pub unsafe fn start_tone(&mut self, frequency: u32) {
+    let div = BASE_FREQUENCY / frequency.max(19);
+    self.command.write_u8(0xb6); // Channel 2 + Mode 3 (Square Wave) + LSB/MSB IO
+    self.channel_2_data.write_u8((div & 0xff) as u8);
+    self.channel_2_data.write_u8((div >> 8) as u8);
+    let gate = self.speaker_control.read_u8();
+    self.speaker_control.write_u8(gate | 3);
+  }
+
+  pub unsafe fn stop_tone(&mut self) {
+    let gate = self.speaker_control.read_u8();
+    self.speaker_control.write_u8(gate & !3);
+  }
+
+  /// Start channel 2 counting down from `count` once, with the speaker
+  /// disconnected. Used to time short intervals before interrupts are running.
+  pub unsafe fn start_one_shot(&mut self, count: u16) {
+    let gate = self.speaker_control.read_u8();
+    self.speaker_control.write_u8((gate & !2) | 1);
+    self.command.write_u8(0xb0); // Channel 2 + Mode 0 (Terminal Count) + LSB/MSB IO
+    self.channel_2_data.write_u8((count & 0xff) as u8);
+    self.channel_2_data.write_u8((count >> 8) as u8);
+  }
+
+  /// Whether channel 2 has counted down to zero since `start_one_shot`
+  pub unsafe fn one_shot_done(&self) -> bool {
+    self.speaker_control.read_u8() & 0x20 != 0
+  }
+}
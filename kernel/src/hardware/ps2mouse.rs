@@ -0,0 +1,163 @@
+//! The PS/2 mouse is attached to the auxiliary port of the 8042 keyboard
+//! controller, and shares its data port with the keyboard. Once streaming is
+//! enabled, it sends a three-byte packet on IRQ12 each time it moves or a
+//! button changes: a flags byte holding the buttons, sign bits, and overflow
+//! bits, followed by the X and Y movement.
+
+use crate::x86::io::Port;
+use spin::Mutex;
+
+/// Sent by the mouse to acknowledge each command byte
+const ACK: u8 = 0xfa;
+
+const FLAG_ALWAYS_SET: u8 = 0x08;
+const FLAG_X_SIGN: u8 = 0x10;
+const FLAG_Y_SIGN: u8 = 0x20;
+const FLAG_X_OVERFLOW: u8 = 0x40;
+const FLAG_Y_OVERFLOW: u8 = 0x80;
+
+/// Movement and button state reported by one packet
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MousePacket {
+  /// Movement since the previous packet, in mickeys. Positive Y points up.
+  pub dx: i16,
+  pub dy: i16,
+  /// Bit 0 is the left button, bit 1 the right, and bit 2 the middle
+  pub buttons: u8,
+}
+
+struct PacketState {
+  bytes: [u8; 3],
+  length: usize,
+}
+
+pub struct Ps2Mouse {
+  data_port: Port,
+  /// Reads return the controller status, writes send controller commands
+  command_port: Port,
+  packet: Mutex<PacketState>,
+}
+
+impl Ps2Mouse {
+  pub const fn new() -> Ps2Mouse {
+    Ps2Mouse {
+      data_port: Port::new(0x60),
+      command_port: Port::new(0x64),
+      packet: Mutex::new(PacketState {
+        bytes: [0; 3],
+        length: 0,
+      }),
+    }
+  }
+
+  unsafe fn wait_to_write(&self) -> bool {
+    for _ in 0..100000 {
+      if self.command_port.read_u8() & 0x02 == 0 {
+        return true;
+      }
+    }
+    false
+  }
+
+  unsafe fn wait_to_read(&self) -> bool {
+    for _ in 0..100000 {
+      if self.command_port.read_u8() & 0x01 != 0 {
+        return true;
+      }
+    }
+    false
+  }
+
+  unsafe fn write_controller(&self, command: u8) -> Option<()> {
+    if !self.wait_to_write() {
+      return None;
+    }
+    self.command_port.write_u8(command);
+    Some(())
+  }
+
+  unsafe fn write_data(&self, value: u8) -> Option<()> {
+    if !self.wait_to_write() {
+      return None;
+    }
+    self.data_port.write_u8(value);
+    Some(())
+  }
+
+  unsafe fn read_data(&self) -> Option<u8> {
+    if !self.wait_to_read() {
+      return None;
+    }
+    Some(self.data_port.read_u8())
+  }
+
+  /// Send a byte to the mouse itself, rather than the controller, and return
+  /// whether it was acknowledged
+  unsafe fn write_mouse(&self, value: u8) -> bool {
+    if self.write_controller(0xd4).is_none() || self.write_data(value).is_none() {
+      return false;
+    }
+    self.read_data() == Some(ACK)
+  }
+
+  /// Enable the auxiliary port and its interrupt, and start the mouse
+  /// streaming packets. Must run before interrupts are enabled, since the
+  /// keyboard interrupt would otherwise consume the replies. Returns false if
+  /// no mouse answered.
+  pub unsafe fn init(&self) -> bool {
+    if self.write_controller(0xa8).is_none() {
+      return false;
+    }
+    // Set the IRQ12 enable bit in the configuration byte, and clear the bit
+    // that disables the auxiliary clock
+    if self.write_controller(0x20).is_none() {
+      return false;
+    }
+    let config = match self.read_data() {
+      Some(config) => config,
+      None => return false,
+    };
+    if self.write_controller(0x60).is_none() || self.write_data((config | 0x02) & !0x20).is_none() {
+      return false;
+    }
+    // Restore default rate and resolution, then enable streaming
+    self.write_mouse(0xf6) && self.write_mouse(0xf4)
+  }
+
+  /// Collect a byte from IRQ12, returning a packet once all three bytes have
+  /// arrived. A first byte without its always-set bit means the stream is out
+  /// of step, so it is dropped until a plausible first byte comes along.
+  pub fn handle_byte(&self, byte: u8) -> Option<MousePacket> {
+    let mut packet = self.packet.lock();
+    if packet.length == 0 && byte & FLAG_ALWAYS_SET == 0 {
+      return None;
+    }
+    let index = packet.length;
+    packet.bytes[index] = byte;
+    packet.length += 1;
+    if packet.length < 3 {
+      return None;
+    }
+    packet.length = 0;
+    let flags = packet.bytes[0];
+    let mut dx = packet.bytes[1] as i16;
+    if flags & FLAG_X_SIGN != 0 {
+      dx -= 0x100;
+    }
+    let mut dy = packet.bytes[2] as i16;
+    if flags & FLAG_Y_SIGN != 0 {
+      dy -= 0x100;
+    }
+    // Overflowed movement is meaningless, and is dropped rather than
+    // throwing the cursor across the screen
+    if flags & (FLAG_X_OVERFLOW | FLAG_Y_OVERFLOW) != 0 {
+      dx = 0;
+      dy = 0;
+    }
+    Some(MousePacket {
+      dx,
+      dy,
+      buttons: flags & 0x07,
+    })
+  }
+}
@@ -1,8 +1,55 @@
+//! Devices that QEMU and Bochs provide for talking to the host. Neither exists
+//! on real hardware, where reads from their ports return 0xff and writes are
+//! ignored.
+//!
+//! The debug console at port 0xe9 prints every byte written to it on the
+//! host, without needing a serial port to be configured. Reading the port
+//! returns 0xe9 when the console is attached.
+//!
+//! The isa-debug-exit device stops the emulator as soon as a value is written
+//! to it. QEMU exits with the status `(value << 1) | 1`, so a harness can tell
+//! a deliberate exit from a crash. It must be added on the command line with
+//! `-device isa-debug-exit,iobase=0xf4,iosize=0x04`.
+
+use core::fmt;
 use crate::x86::io::Port;
 
+const DEBUGCON_PORT: u16 = 0xe9;
 const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
 
 pub fn debug_exit(code: u32) {
   let port = Port::new(ISA_DEBUG_EXIT_PORT);
   unsafe { port.write_u32(code) };
-}
\ No newline at end of file
+}
+
+/// Detect whether the emulator has attached a debug console
+pub fn debugcon_present() -> bool {
+  let port = Port::new(DEBUGCON_PORT);
+  unsafe { port.read_u8() == DEBUGCON_PORT as u8 }
+}
+
+/// Writes text straight to the debug console
+pub struct DebugConsole {
+  port: Port,
+}
+
+impl DebugConsole {
+  pub const fn new() -> DebugConsole {
+    DebugConsole {
+      port: Port::new(DEBUGCON_PORT),
+    }
+  }
+
+  pub fn write_bytes(&self, bytes: &[u8]) {
+    for byte in bytes {
+      unsafe { self.port.write_u8(*byte) };
+    }
+  }
+}
+
+impl fmt::Write for DebugConsole {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    self.write_bytes(s.as_bytes());
+    Ok(())
+  }
+}
@@ -1,4 +1,4 @@
-use crate::time::date::{Date, DateTime, Time};
+use crate::time::date::{BASE_YEAR, Date, DateTime, Time};
 use crate::x86::io::Port;
 
 pub struct RTC {
@@ -15,15 +15,31 @@ pub struct RTCTime {
   day: u8,
   month: u8,
   year: u8,
+  /// Zero if the CMOS has no valid century register
+  century: u8,
 }
 
+/// Most chipsets keep the century here, though ACPI allows it to move
+const CENTURY_REGISTER: u8 = 0x32;
+
 impl RTCTime {
+  pub fn get_full_year(&self) -> u32 {
+    let century = match self.century {
+      19..=21 => self.century as u32,
+      // Without a century, two-digit years are assumed to fall in the range
+      // DOS can represent
+      _ => if self.year >= 80 { 19 } else { 20 },
+    };
+    century * 100 + self.year as u32
+  }
+
   pub fn to_datetime(&self) -> DateTime {
+    let year = self.get_full_year().max(BASE_YEAR) - BASE_YEAR;
     DateTime {
       date: Date {
         day: self.day,
         month: self.month,
-        year: self.year + 20,
+        year: year.min(u8::MAX as u32) as u8,
       },
 
       time: Time {
@@ -69,6 +85,7 @@ impl RTC {
       day: 0,
       month: 0,
       year: 0,
+      century: 0,
     };
 
     time.seconds = self.read_register(nmi | 0);
@@ -77,6 +94,7 @@ impl RTC {
     time.day = self.read_register(nmi | 0x07);
     time.month = self.read_register(nmi | 0x08);
     time.year = self.read_register(nmi | 0x09);
+    time.century = self.read_register(nmi | CENTURY_REGISTER);
 
     if use_bcd {
       // Convert all bcd times to binary
@@ -85,8 +103,11 @@ impl RTC {
       time.day = convert_bcd(time.day);
       time.month = convert_bcd(time.month);
       time.year = convert_bcd(time.year);
+      time.century = convert_bcd(time.century);
 
-      if !use_24_hour {
+      if use_24_hour {
+        time.hours = convert_bcd(time.hours);
+      } else {
         let pm = time.hours & 0x80 != 0;
         time.hours = convert_bcd(time.hours & 0x7f);
         time.hours %= 12;
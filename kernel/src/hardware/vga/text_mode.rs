@@ -193,6 +193,21 @@ impl TextMode {
     }
   }
 
+  /// Swap the foreground and background colors of every cell. Calling it a
+  /// second time restores the original colors.
+  pub fn invert_screen(&self) {
+    let mut offset = 1;
+    while offset < 2 * 80 * 25 {
+      unsafe {
+        let color_ptr = self.base_pointer.offset(offset);
+        let current_color = read_volatile(color_ptr);
+        let inverted_color = ((current_color & 0xf) << 4) | ((current_color & 0xf0) >> 4);
+        write_volatile(color_ptr, inverted_color);
+      }
+      offset += 2;
+    }
+  }
+
   pub fn disable_cursor(&self) {
     let offset = (self.cursor_row as isize) * 160 + (self.cursor_col as isize) * 2;
     unsafe {
@@ -223,6 +238,26 @@ impl TextMode {
     }
   }
 
+  /// Replace the whole screen with a grid of 80x25 character and color cells
+  pub fn write_cells(&mut self, cells: &[u16]) {
+    let dest = self.base_pointer as *mut u16;
+    for (offset, cell) in cells.iter().take(80 * 25).enumerate() {
+      unsafe {
+        write_volatile(dest.add(offset), *cell);
+      }
+    }
+  }
+
+  /// Copy the character and color bytes of the whole screen, in the same
+  /// layout as video memory
+  pub fn read_cells(&self, dest: &mut [u8]) {
+    for (offset, byte) in dest.iter_mut().take(2 * 80 * 25).enumerate() {
+      unsafe {
+        *byte = read_volatile(self.base_pointer.add(offset));
+      }
+    }
+  }
+
   pub fn set_buffer_pointer(&mut self, ptr: usize) -> usize {
     let current_ptr = self.base_pointer as usize;
     self.base_pointer = ptr as *mut u8;
@@ -63,6 +63,15 @@ impl IDTEntry {
     self.set_handler_at_offset(offset);
   }
 
+  /// Handle the interrupt by switching to the task in a TSS, rather than by
+  /// calling a function on the current stack
+  pub fn set_task_gate(&mut self, tss: SegmentSelector) {
+    self.offset_low = 0;
+    self.offset_high = 0;
+    self.selector = tss;
+    self.type_and_attributes = IDT_PRESENT | IDT_DESCRIPTOR_RING_0 | IDT_GATE_TYPE_TASK_32;
+  }
+
   fn set_handler_at_offset(&mut self, offset: usize) {
     self.offset_low = offset as u16;
     self.offset_high = (offset >> 16) as u16;
@@ -100,8 +109,9 @@ pub unsafe fn init() {
 
   // Set exception handlers
   IDT[0].set_handler(interrupts::exceptions::divide_by_zero);
+  IDT[7].set_handler(interrupts::exceptions::device_not_available);
 
-  IDT[8].set_handler(interrupts::exceptions::double_fault);
+  IDT[8].set_task_gate(SegmentSelector::new(crate::gdt::DOUBLE_FAULT_TSS_SELECTOR >> 3, 0));
 
   IDT[0xd].set_handler_with_error(interrupts::exceptions::gpf);
   IDT[0xe].set_handler_with_error(interrupts::exceptions::page_fault);
@@ -110,11 +120,12 @@ pub unsafe fn init() {
   
   IDT[0x2b].set_usermode_handler(syscall_handler);
 
-  IDT[0x30].set_handler(interrupts::pic::pit);
-  IDT[0x31].set_handler(interrupts::pic::keyboard);
-  IDT[0x34].set_handler(interrupts::pic::com1);
+  let irq_base = interrupts::controller::IRQ_BASE_VECTOR as usize;
+  for (irq, stub) in interrupts::irq::STUBS.iter().enumerate() {
+    IDT[irq_base + irq].set_handler(*stub);
+  }
 
-  IDT[0x36].set_handler(interrupts::pic::floppy);
+  IDT[interrupts::controller::SPURIOUS_VECTOR as usize].set_handler(interrupts::controller::spurious);
 
   lidt(&IDTR);
 }
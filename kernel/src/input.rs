@@ -1,6 +1,7 @@
 use crate::buffers::RingBuffer;
 use crate::devices;
 use crate::process::{self, id::ProcessID};
+use crate::time;
 
 /**
  * The Input thread runs with kernel-level permissions, and sleeps until an
@@ -11,28 +12,45 @@ use crate::process::{self, id::ProcessID};
 
 pub static mut INPUT_THREAD_ID: ProcessID = ProcessID::new(0);
 
-static mut INPUT_EVENTS_DATA: [u8; 32] = [0; 32];
+/// Each queued event is a scan code followed by the uptime in milliseconds
+/// when it arrived, so that events are timed by the interrupt rather than by
+/// whenever the input thread gets to run
+const INPUT_EVENT_SIZE: usize = 5;
+const INPUT_EVENTS_CAPACITY: usize = INPUT_EVENT_SIZE * 16;
+
+static mut INPUT_EVENTS_DATA: [u8; INPUT_EVENTS_CAPACITY] = [0; INPUT_EVENTS_CAPACITY];
 pub static INPUT_EVENTS: RingBuffer = RingBuffer::new(unsafe { &INPUT_EVENTS_DATA });
 
+/// Queue a scan code from the keyboard interrupt. If the queue is full the
+/// code is dropped whole, rather than leaving a partial event behind.
+pub fn push_scan_code(scan_code: u8) {
+  if INPUT_EVENTS.available_bytes() + INPUT_EVENT_SIZE > INPUT_EVENTS_CAPACITY {
+    return;
+  }
+  let time = (time::system::get_uptime().in_ms() as u32).to_le_bytes();
+  INPUT_EVENTS.write(&[scan_code, time[0], time[1], time[2], time[3]]);
+}
+
 #[inline(never)]
 pub extern "C" fn run_input() {
   unsafe {
     INPUT_THREAD_ID = process::get_current_pid();
   }
-  crate::tty::console_write(format_args!("Keyboard Ready"));
-  let mut read_buffer: [u8; 1] = [0; 1];
+  crate::messages::console(syscall::messages::KEYBOARD_READY, &[]);
+  let mut read_buffer: [u8; INPUT_EVENT_SIZE] = [0; INPUT_EVENT_SIZE];
   loop {
     process::send_signal(unsafe { INPUT_THREAD_ID }, syscall::signals::STOP);
     process::yield_coop();
-    let to_read = INPUT_EVENTS.available_bytes();
+    let to_read = INPUT_EVENTS.available_bytes() / INPUT_EVENT_SIZE;
     for _ in 0..to_read {
       let read_len = INPUT_EVENTS.read(&mut read_buffer);
-      if read_len < 1 {
+      if read_len < INPUT_EVENT_SIZE {
         break;
       }
+      let time = u32::from_le_bytes([read_buffer[1], read_buffer[2], read_buffer[3], read_buffer[4]]);
       unsafe {
         if let Some(kbd) = &devices::KEYBOARD {
-          kbd.lock().handle_data(read_buffer[0]);
+          kbd.lock().handle_data(read_buffer[0], time);
         }
       }
     }
@@ -41,4 +59,4 @@ pub extern "C" fn run_input() {
 
 pub fn wake_thread() {
   process::send_signal(unsafe { INPUT_THREAD_ID }, syscall::signals::CONTINUE);
-}
\ No newline at end of file
+}
@@ -0,0 +1,143 @@
+//! Hardware interrupts reach the CPU through one of two kinds of controller.
+//! The pair of 8259 PICs exists on every PC, and is always set up first. When
+//! the processor has a local APIC and the ACPI tables describe an I/O APIC,
+//! the PICs are masked and ISA IRQs are routed through the I/O APIC instead.
+//!
+//! Either way, IRQ n arrives at vector 0x30 + n, so handlers do not need to
+//! know which controller delivered it. The stubs in `irq` acknowledge each
+//! interrupt through here once its handlers have run.
+//! Every IRQ starts out masked, and is unmasked once its driver is ready.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::acpi::{self, tables::Madt};
+use crate::devices;
+use crate::hardware::apic::{self, IoApic, LocalApic};
+use crate::hardware::cpu::{self, Feature};
+use spin::RwLock;
+use super::stack;
+
+/// Vector of IRQ 0. The CPU reserves everything below 0x20 for exceptions.
+pub const IRQ_BASE_VECTOR: u8 = 0x30;
+/// Raised by the local APIC when an interrupt is withdrawn before delivery
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+pub const ISA_IRQ_COUNT: usize = 16;
+/// The secondary PIC is chained through IRQ 2, which never fires on its own
+const CASCADE_IRQ: u8 = 2;
+
+/// Device registers are mapped into the shared kernel page tables, above the
+/// shared data page. The local APIC's registers always start on a page.
+const LOCAL_APIC_WINDOW: usize = 0xfe200000;
+const IO_APIC_WINDOW: usize = 0xfe201000;
+/// PCs have one I/O APIC, rarely two. Any past this many are ignored.
+const MAX_IO_APICS: usize = 8;
+
+static USING_APIC: AtomicBool = AtomicBool::new(false);
+static LOCAL_APIC: LocalApic = LocalApic::new(LOCAL_APIC_WINDOW);
+/// The I/O APIC and pin each ISA IRQ is routed through, in APIC mode
+static ISA_PINS: RwLock<[Option<(IoApic, u32)>; ISA_IRQ_COUNT]> = RwLock::new([None; ISA_IRQ_COUNT]);
+
+pub fn is_using_apic() -> bool {
+  USING_APIC.load(Ordering::SeqCst)
+}
+
+/// The MADT, if the machine has everything needed to use the APIC
+fn find_madt() -> Option<Madt> {
+  if !cpu::has_feature(Feature::Apic) {
+    return None;
+  }
+  let madt = acpi::get_madt()?;
+  if madt.io_apics.is_empty() {
+    return None;
+  }
+  Some(madt)
+}
+
+unsafe fn init_apic(madt: &Madt) {
+  apic::enable_local_apic_msr();
+  apic::map_registers(madt.local_apic, LOCAL_APIC_WINDOW);
+  LOCAL_APIC.enable(SPURIOUS_VECTOR);
+  let destination = LOCAL_APIC.get_id();
+
+  let io_apics: Vec<IoApic> = madt.io_apics.iter().take(MAX_IO_APICS).enumerate().map(|(index, entry)| {
+    let io_apic = IoApic::new(apic::map_registers(entry.address, IO_APIC_WINDOW + index * 0x1000));
+    io_apic.mask_all();
+    io_apic
+  }).collect();
+
+  let mut pins = ISA_PINS.write();
+  for irq in 0..(ISA_IRQ_COUNT as u8) {
+    if irq == CASCADE_IRQ {
+      continue;
+    }
+    let route = madt.route_isa_irq(irq);
+    let (entry, pin) = match madt.find_io_apic(route.gsi) {
+      Some(found) => found,
+      None => continue,
+    };
+    let io_apic = match madt.io_apics.iter().position(|apic| *apic == entry).and_then(|index| io_apics.get(index)) {
+      Some(io_apic) => *io_apic,
+      None => continue,
+    };
+    if pin >= io_apic.get_pin_count() {
+      continue;
+    }
+    io_apic.route(pin, IRQ_BASE_VECTOR + irq, destination, route.active_low, route.level_triggered);
+    pins[irq as usize] = Some((io_apic, pin));
+  }
+  USING_APIC.store(true, Ordering::SeqCst);
+}
+
+/// Set up the interrupt controllers, with every IRQ masked. Must run after
+/// ACPI tables have been read, and before any driver unmasks its IRQ.
+pub unsafe fn init() {
+  devices::PIC.init();
+  devices::PIC.mask_all();
+  match find_madt() {
+    Some(madt) => {
+      init_apic(&madt);
+      crate::klog!("Interrupts: routed through the I/O APIC");
+    },
+    None => crate::klog!("Interrupts: using the 8259 PICs"),
+  }
+}
+
+/// Tell the controller an IRQ has been handled, so it can deliver the next
+pub fn acknowledge(irq: u8) {
+  unsafe {
+    if is_using_apic() {
+      LOCAL_APIC.end_of_interrupt();
+    } else {
+      devices::PIC.acknowledge_interrupt(irq);
+    }
+  }
+}
+
+fn set_masked(irq: u8, masked: bool) {
+  if irq as usize >= ISA_IRQ_COUNT {
+    return;
+  }
+  unsafe {
+    if is_using_apic() {
+      if let Some((io_apic, pin)) = ISA_PINS.read()[irq as usize] {
+        io_apic.set_masked(pin, masked);
+      }
+    } else if masked {
+      devices::PIC.mask_irq(irq);
+    } else {
+      devices::PIC.unmask_irq(irq);
+    }
+  }
+}
+
+pub fn mask(irq: u8) {
+  set_masked(irq, true);
+}
+
+pub fn unmask(irq: u8) {
+  set_masked(irq, false);
+}
+
+/// A spurious interrupt was never really raised, so it is not acknowledged
+pub extern "x86-interrupt" fn spurious(_frame: &stack::StackFrame) {
+}
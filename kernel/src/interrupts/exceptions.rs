@@ -1,3 +1,4 @@
+use crate::files::cursor::SeekMethod;
 use crate::filesystems;
 use crate::kprintln;
 use crate::memory::{
@@ -5,13 +6,14 @@ use crate::memory::{
   address::{VirtualAddress},
   physical,
   virt::{
-    page_directory::{CurrentPageDirectory, PageDirectory, PermissionFlags},
-    region::{MemoryRegionType, Permissions},
+    page_directory::{self, CurrentPageDirectory, PageDirectory, PermissionFlags},
+    region::{MemoryRegionType, Permissions, VirtualMemoryRegion},
   },
 };
-use crate::process;
+use crate::process::{self, vm86::TrapResult};
 use super::stack::StackFrame;
-use super::syscall_legacy::{DosApiRegisters, dos_api, VM8086Frame};
+use super::syscall_legacy::{DosApiRegisters, VM8086Frame};
+use syscall::result::SystemError;
 
 #[no_mangle]
 pub extern "x86-interrupt" fn divide_by_zero(stack_frame: &StackFrame) {
@@ -19,10 +21,36 @@ pub extern "x86-interrupt" fn divide_by_zero(stack_frame: &StackFrame) {
   loop {}
 }
 
+/// Raised by the first floating point instruction a process runs after a
+/// switch, while another process owns the FPU registers
 #[no_mangle]
-pub extern "x86-interrupt" fn double_fault(stack_frame: &StackFrame) {
-  //kprintln!("\nERR: Double Fault\n{:?}", stack_frame);
-  loop {}
+pub extern "x86-interrupt" fn device_not_available(_stack_frame: &StackFrame) {
+  process::fpu::handle_unavailable();
+}
+
+/// ID of the process that was running when a fatal fault happened, or -1 if
+/// it cannot be determined safely
+fn faulting_process_id() -> i64 {
+  match process::try_current_process_id() {
+    Some(id) => id.as_u32() as i64,
+    None => -1,
+  }
+}
+
+/// Entry point of the double fault task. It runs on a stack of its own, and
+/// never returns to the task that faulted.
+#[no_mangle]
+pub extern "C" fn double_fault_task() -> ! {
+  let address: usize;
+  unsafe {
+    llvm_asm!("mov $0, cr2" : "=r"(address) : : : "intel", "volatile");
+  }
+  let (eip, esp) = unsafe { crate::gdt::get_interrupted_task_registers() };
+  let pid = faulting_process_id();
+  if process::memory::is_stack_guard(esp as usize) || process::memory::is_stack_guard(address) {
+    panic!("Kernel stack overflow in process {}: ESP {:#010x}, IP {:#010x}", pid, esp, eip);
+  }
+  panic!("Double fault in process {}: ESP {:#010x}, IP {:#010x}, CR2 {:#010x}", pid, esp, eip, address);
 }
 
 #[no_mangle]
@@ -35,24 +63,47 @@ pub extern "x86-interrupt" fn gpf(stack_frame: &StackFrame, error: u32) {
     // VM 8086
     let stack_frame_ptr = stack_frame as *const StackFrame as usize;
     let vm_frame_ptr = (stack_frame_ptr + 12) as *mut VM8086Frame;
+    let current_proc = process::current_process().expect("VM86 fault outside a process");
     unsafe {
       let regs = &mut *((reg_ptr - 6 * 4) as *mut DosApiRegisters);
       let vm_frame = &mut *vm_frame_ptr;
       let mut_stack_frame = &mut *(stack_frame_ptr as *mut StackFrame);
-      let op_ptr = ((stack_frame.cs << 4) + stack_frame.eip) as *const u8;
-      if *op_ptr == 0xcd {
-        // INT
-        match *op_ptr.offset(1) {
-          0x21 => {
-            // DOS API
-            dos_api(regs, vm_frame);
-          },
-          _ => (),
-        }
-        mut_stack_frame.eip += 2;
-        return;
+      match process::vm86::handle_gpf(&current_proc, mut_stack_frame, regs, vm_frame) {
+        TrapResult::Handled => return,
+        TrapResult::Unhandled(op) => {
+          // Only the DOS program is at fault, so only it has to end
+          kprintln!(
+            "\nProcess {} killed: unsupported VM86 instruction {:#04x}",
+            current_proc.get_id().as_u32(),
+            op,
+          );
+          current_proc.terminate(syscall::signals::ILL, 0);
+        },
       }
     }
+    // A terminated process never comes back from the switch, so its reference
+    // would be leaked if it were still held
+    drop(current_proc);
+    process::yield_coop();
+    return;
+  }
+
+  if stack_frame.is_from_usermode() {
+    let eip = stack_frame.eip;
+    match process::current_process() {
+      Some(current) => {
+        kprintln!(
+          "\nProcess {} killed: General Protection Fault, code {} at IP {:#010x}",
+          current.get_id().as_u32(),
+          error,
+          eip,
+        );
+        current.terminate(syscall::signals::SEGFAULT, 0);
+      },
+      None => panic!("User GPF outside a process"),
+    }
+    process::yield_coop();
+    return;
   }
 
   kprintln!("\nERR: General Protection Fault, code {}", error);
@@ -60,150 +111,194 @@ pub extern "x86-interrupt" fn gpf(stack_frame: &StackFrame, error: u32) {
   loop {}
 }
 
+/// Bits of the error code pushed with a page fault
+const FAULT_PRESENT: u32 = 1;
+const FAULT_WRITE: u32 = 2;
+const FAULT_USER: u32 = 4;
+
 #[no_mangle]
 pub extern "x86-interrupt" fn page_fault(stack_frame: &StackFrame, error: u32) {
   let address: usize;
   unsafe {
     llvm_asm!("mov $0, cr2" : "=r"(address) : : : "intel", "volatile");
   }
-  kprintln!("\nPage Fault at {:#010x} ({:x})", address, error);
-  let current_proc = process::current_process().expect("Page fault outside a process");
-  if address >= 0xc0000000 {
-    // Kernel region
-    if error & 4 == 4 {
-      kprintln!("IP: {:#010x}", stack_frame.eip);
-      // At ring 3
-      kprintln!("Attempt to access kernel memory from userspace: {:#010x}", address);
-      loop {}
-    } else {
-      if error & 1 == 0 {
-        // Page was not present
-        // If it is in the heap or stack regions, map a new physical frame and
-        // extend the region
-
-        let vaddr = VirtualAddress::new(address);
-        let current_pagedir = CurrentPageDirectory::get();
-        match current_proc.get_range_containing_address(vaddr) {
-          Some(range) => {
-            match range.backing_type() {
-              MemoryRegionType::Anonymous(_) => {
-                let kernel_frame = match memory::physical::allocate_frame() {
-                  Ok(frame) => frame,
-                  Err(_) => {
-                    // Out of memory
-                    // At some point we need to implement disk paging
-                    panic!("Unable to allocate kernel memory");
-                  },
-                };
-                current_pagedir.map(kernel_frame, VirtualAddress::new(address & 0xfffff000), PermissionFlags::empty());
-                return;
-              },
-              MemoryRegionType::DMA(frame_range) => {
-                let offset = (address & 0xfffff000) - range.get_starting_address_as_usize();
-                let paddr = frame_range.get_starting_address().as_usize();
-                let frame = physical::frame::Frame::new(paddr + offset);
-
-                let page_start = VirtualAddress::new(address & 0xfffff000);
-                let flags = PermissionFlags::empty();
-                current_pagedir.map(frame, page_start, flags);
-                return;
-              },
-              _ => (),
-            }
-          },
-          None => (),
-        }
-        return;
+  let reason = match resolve_page_fault(address, error) {
+    Ok(()) => return,
+    Err(reason) => reason,
+  };
+  if error & FAULT_USER == 0 && process::memory::is_stack_guard(address) {
+    // Reached only if the handler itself could still push onto the stack
+    panic!("Kernel stack overflow in process {} at {:#010x}\n{:?}", faulting_process_id(), address, stack_frame);
+  }
+  if address >= 0xc0000000 && error & FAULT_USER == 0 {
+    // The kernel touched its own memory in a way no region allows
+    panic!("Page fault at {:#010x} ({:x}): {}\n{:?}", address, error, reason, stack_frame);
+  }
+  // The process made an invalid access, either directly or by passing a bad
+  // pointer to the kernel. It is the only thing that has to end.
+  let access = if error & FAULT_WRITE != 0 { "write" } else { "read" };
+  let eip = stack_frame.eip;
+  match process::current_process() {
+    Some(current) => {
+      kprintln!(
+        "\nProcess {} killed: {} of {:#010x} at IP {:#010x}, {}",
+        current.get_id().as_u32(),
+        access,
+        address,
+        eip,
+        reason,
+      );
+      current.terminate(syscall::signals::SEGFAULT, 0);
+    },
+    None => panic!("Page fault outside a process"),
+  }
+  process::yield_coop();
+}
+
+/// Try to make a faulting address accessible, according to the region of the
+/// current process that contains it. On failure, returns a description of
+/// why the access was invalid.
+fn resolve_page_fault(address: usize, error: u32) -> Result<(), &'static str> {
+  let current_proc = process::current_process().ok_or("no current process")?;
+  let vaddr = VirtualAddress::new(address);
+  let is_user_address = address < 0xc0000000;
+  if !is_user_address && error & FAULT_USER != 0 {
+    return Err("kernel memory is not accessible from userspace");
+  }
+  let region = match current_proc.get_range_containing_address(vaddr) {
+    Some(region) => region,
+    None => {
+      // Touching memory just below the stack grows it
+      if !is_user_address || error & FAULT_PRESENT != 0 || !current_proc.grow_stack(vaddr) {
+        return Err("address is not mapped");
       }
-    }
+      current_proc.get_range_containing_address(vaddr).ok_or("address is not mapped")?
+    },
+  };
+  let page_start = VirtualAddress::new(address & 0xfffff000);
+  if error & FAULT_PRESENT == 0 {
+    map_missing_page(region, page_start, is_user_address)
+  } else if error & FAULT_WRITE != 0 {
+    copy_page_on_write(region, page_start, is_user_address)
   } else {
-    // User region
-    //kprintln!("Page fault in user region.");
-    let vaddr = VirtualAddress::new(address);
-    let current_pagedir = CurrentPageDirectory::get();
-    match current_proc.get_range_containing_address(vaddr) {
-      Some(range) => {
-        // Three scenarios we need to support:
-        //  - Attempted to read/write an unmapped code page
-        //  - Attempted to write a Copy-on-Write page
-        //  - Expanding the stack / heap
-        if error & 1 == 0 {
-          // Page not present
-          match range.backing_type() {
-            MemoryRegionType::Direct(frame_range) => {
-              let offset = (address & 0xfffff000) - range.get_starting_address_as_usize();
-              let paddr = frame_range.get_starting_address().as_usize();
-              let frame = physical::frame::Frame::new(paddr + offset);
-              
-              let page_start = VirtualAddress::new(address & 0xfffff000);
-              let flags = PermissionFlags::new(PermissionFlags::USER_ACCESS | PermissionFlags::WRITE_ACCESS);
-              current_pagedir.map(frame, page_start, flags);
-              return;
-            },
-            _ => (),
-          }
-
-          // Page not present
-          let new_frame = match memory::physical::allocate_frame() {
-            Ok(frame) => frame,
-            Err(_) => {
-              // Out of memory
-              panic!("Unable to allocate userspace memory");
-            },
-          };
-          let page_start = VirtualAddress::new(address & 0xfffff000);
-          let flags = PermissionFlags::new(PermissionFlags::USER_ACCESS | PermissionFlags::WRITE_ACCESS);
-          current_pagedir.map(new_frame, page_start, flags);
-          if range.get_permissions() == Permissions::CopyOnWrite {
-            physical::reference_frame_at_address(new_frame.get_address());
-          }
-          // should zero out the new_frame here, now that it's mapped
-
-          if let MemoryRegionType::MemMapped(drive, handle, length) = range.backing_type() {
-            let offset = page_start.as_usize() - range.get_starting_address_as_usize();
-            let mut read_len = 0x1000;
-            if length < offset {
-              read_len = 0;
-            } else if offset + length < 0x1000 {
-              read_len = length;
-            }
-            let fs = filesystems::get_fs(drive).expect("Memmapped to invalid fileseystem");
-            let buffer = unsafe {
-              core::slice::from_raw_parts_mut(page_start.as_usize() as *mut u8, read_len)
-            };
-            fs.read(handle, buffer).expect("Error reading from memmapped file");
-          }
-
-          // If the range needs to be extended and has extension enabled, do so
-          // ...
-
-          return;
-        } else if error & 2 == 2 {
-          // Write attempted on a mapped page
-          kprintln!("Copy on Write not implemented yet");
-        }
-      },
-      None => (),
+    Err("page is present but was not accessible")
+  }
+}
+
+/// Ring 0 ignores the write flag on user pages, so a kernel write into user
+/// memory would land in a copy-on-write frame that another process still
+/// shares. Before the kernel writes to a range of user memory, give each
+/// present, read-only page in it a private copy, just as a write fault from
+/// the process itself would. Pages that are not present yet are mapped
+/// writable when the kernel first touches them.
+/// The range must already be known to lie in writable regions, so the only
+/// way copying can fail is by running out of memory.
+pub fn prepare_user_write(address: usize, length: usize) -> Result<(), SystemError> {
+  if length == 0 {
+    return Ok(());
+  }
+  let current_proc = process::current_process().ok_or(SystemError::BadPointer)?;
+  let current_pagedir = CurrentPageDirectory::get();
+  let end = address.checked_add(length).ok_or(SystemError::BadPointer)?;
+  let mut page_start = VirtualAddress::new(address & 0xfffff000);
+  while page_start.as_usize() < end {
+    if current_pagedir.get_physical_address(page_start).is_some()
+      && !current_pagedir.is_write_access_granted(page_start) {
+      let region = current_proc
+        .get_range_containing_address(page_start)
+        .ok_or(SystemError::BadPointer)?;
+      copy_page_on_write(region, page_start, true).map_err(|_| SystemError::OutOfMemory)?;
+    }
+    match page_start.as_usize().checked_add(0x1000) {
+      Some(next) => page_start = VirtualAddress::new(next),
+      None => break,
     }
   }
+  Ok(())
+}
 
-  /*  
-  if error & 1 == 0 {
-    kprintln!("  PAGE NOT PRESENT");
+/// Page table permissions for a page in a region
+fn page_flags(region: VirtualMemoryRegion, is_user_address: bool, writable: bool) -> PermissionFlags {
+  if !is_user_address {
+    // Kernel pages are only accessible from ring 0, which ignores the write
+    // flag
+    return PermissionFlags::empty();
   }
-  if error & 2 == 2 {
-    kprintln!("  WRITE ATTEMPTED");
+  if writable && region.get_permissions() != Permissions::ReadOnly {
+    PermissionFlags::new(PermissionFlags::USER_ACCESS | PermissionFlags::WRITE_ACCESS)
   } else {
-    kprintln!("  READ ATTEMPTED");
+    PermissionFlags::new(PermissionFlags::USER_ACCESS)
   }
-  if error & 4 == 4 {
-    kprintln!("  AT RING 3");
+}
+
+/// Fill in a page the region has not needed until now
+fn map_missing_page(region: VirtualMemoryRegion, page_start: VirtualAddress, is_user_address: bool) -> Result<(), &'static str> {
+  let current_pagedir = CurrentPageDirectory::get();
+  let offset = page_start.as_usize() - region.get_starting_address_as_usize();
+  match region.backing_type() {
+    MemoryRegionType::Direct(frame_range) | MemoryRegionType::DMA(frame_range) => {
+      // Already backed by specific physical memory
+      let paddr = frame_range.get_starting_address().as_usize();
+      let frame = physical::frame::Frame::new(paddr + offset);
+      current_pagedir.map(frame, page_start, page_flags(region, is_user_address, true));
+      return Ok(());
+    },
+    _ => (),
   }
-  if error & 16 == 16 {
-    kprintln!("  INSTRUCTION FETCH");
+
+  let new_frame = memory::physical::allocate_frame().map_err(|_| "out of memory")?;
+  // Count the first reference, so a fork that later shares the frame knows
+  // to copy it before either side writes
+  physical::reference_frame_at_address(new_frame.get_address());
+  current_pagedir.map(new_frame, page_start, page_flags(region, is_user_address, true));
+  unsafe {
+    // Ring 0 ignores the write flag, so even read-only pages can be filled
+    core::ptr::write_bytes(page_start.as_usize() as *mut u8, 0, 0x1000);
   }
-  */
-  kprintln!("Failed to map address: {:#101x}", address);
-  kprintln!("{:?}", stack_frame);
-  loop {}
+
+  let (drive, handle, file_start, length) = match region.backing_type() {
+    MemoryRegionType::MemMapped(drive, handle, length) => (drive, handle, 0, length),
+    MemoryRegionType::FileBacked(drive, handle, file_start, length) => (drive, handle, file_start, length),
+    _ => return Ok(()),
+  };
+  let read_len = if offset >= length {
+    0
+  } else if length - offset < 0x1000 {
+    length - offset
+  } else {
+    0x1000
+  };
+  if read_len > 0 {
+    let fs = filesystems::get_fs(drive).ok_or("mapped file's filesystem is gone")?;
+    let buffer = unsafe {
+      core::slice::from_raw_parts_mut(page_start.as_usize() as *mut u8, read_len)
+    };
+    fs.seek(handle, SeekMethod::Absolute(file_start + offset)).map_err(|_| "mapped file could not be read")?;
+    filesystems::VFS.read(drive, handle, buffer).map_err(|_| "mapped file could not be read")?;
+  }
+  Ok(())
+}
+
+/// Handle a write to a present page that was mapped read-only. Pages in
+/// copy-on-write regions are copied if anything else still shares their
+/// frame, and made writable otherwise.
+fn copy_page_on_write(region: VirtualMemoryRegion, page_start: VirtualAddress, is_user_address: bool) -> Result<(), &'static str> {
+  if region.get_permissions() == Permissions::ReadOnly {
+    return Err("memory is read-only");
+  }
+  let current_pagedir = CurrentPageDirectory::get();
+  let previous = current_pagedir.get_physical_address(page_start).ok_or("page is not present")?;
+  let frame = physical::get_frame_for_copy_on_write(previous).map_err(|_| "out of memory")?;
+  if frame.get_address() != previous {
+    page_directory::map_frame_to_temporary_page(frame);
+    unsafe {
+      core::ptr::copy_nonoverlapping(
+        page_start.as_usize() as *const u8,
+        page_directory::get_temporary_page_address().as_usize() as *mut u8,
+        0x1000,
+      );
+    }
+  }
+  current_pagedir.map(frame, page_start, page_flags(region, is_user_address, true));
+  Ok(())
 }
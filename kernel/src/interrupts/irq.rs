@@ -0,0 +1,144 @@
+//! Drivers attach to hardware IRQs through `register_handler`, rather than
+//! through IDT entries of their own. Every IRQ vector points to a stub that
+//! counts the interrupt, runs each handler registered for the line, and then
+//! acknowledges it at the interrupt controller. The time spent in each stub
+//! is recorded by `latency`.
+//!
+//! Several handlers can share a line, the way PCI devices often do. They are
+//! all run on every interrupt, so each one must check its own device before
+//! doing any work. A line is unmasked when its first handler is registered,
+//! and masked again when the last is removed.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::RwLock;
+use syscall::result::SystemError;
+use super::controller::{self, ISA_IRQ_COUNT};
+use super::latency;
+use super::stack::StackFrame;
+
+pub type IrqHandler = fn(&StackFrame);
+
+/// Handlers that can share a single line
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+type HandlerTable = [[Option<IrqHandler>; MAX_SHARED_HANDLERS]; ISA_IRQ_COUNT];
+
+/// Handlers are read on every interrupt, so they live in a fixed table that
+/// never needs the heap
+static HANDLERS: RwLock<HandlerTable> = RwLock::new([[None; MAX_SHARED_HANDLERS]; ISA_IRQ_COUNT]);
+
+/// Interrupts seen on each line since boot, including any with no handler
+static COUNTS: [AtomicU32; ISA_IRQ_COUNT] = [
+  AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0),
+  AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0),
+  AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0),
+  AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0),
+];
+
+/// Run `f` with the handlers locked for writing. Interrupts stay off, so no
+/// interrupt can arrive and wait on the lock forever.
+fn with_handlers<R, F: FnOnce(&mut HandlerTable) -> R>(f: F) -> R {
+  let int_reenable = super::is_interrupt_enabled();
+  super::cli();
+  let result = f(&mut HANDLERS.write());
+  if int_reenable {
+    super::sti();
+  }
+  result
+}
+
+/// Attach a handler to an IRQ line, unmasking the line if it is the first
+pub fn register_handler(irq: u8, handler: IrqHandler) -> Result<(), SystemError> {
+  if irq as usize >= ISA_IRQ_COUNT {
+    return Err(SystemError::InvalidArgument);
+  }
+  with_handlers(|handlers| {
+    let line = &mut handlers[irq as usize];
+    let was_empty = line.iter().all(|slot| slot.is_none());
+    let slot = line.iter_mut().find(|slot| slot.is_none()).ok_or(SystemError::Busy)?;
+    *slot = Some(handler);
+    if was_empty {
+      controller::unmask(irq);
+    }
+    Ok(())
+  })
+}
+
+/// Detach a handler, masking the line if no others remain
+pub fn unregister_handler(irq: u8, handler: IrqHandler) -> Result<(), SystemError> {
+  if irq as usize >= ISA_IRQ_COUNT {
+    return Err(SystemError::InvalidArgument);
+  }
+  with_handlers(|handlers| {
+    let line = &mut handlers[irq as usize];
+    let slot = line.iter_mut()
+      .find(|slot| slot.map(|existing| existing as usize) == Some(handler as usize))
+      .ok_or(SystemError::NoSuchEntity)?;
+    *slot = None;
+    if line.iter().all(|slot| slot.is_none()) {
+      controller::mask(irq);
+    }
+    Ok(())
+  })
+}
+
+/// How many interrupts each line has raised since boot
+pub fn get_counts() -> [u32; ISA_IRQ_COUNT] {
+  let mut counts = [0; ISA_IRQ_COUNT];
+  for (count, total) in counts.iter_mut().zip(COUNTS.iter()) {
+    *count = total.load(Ordering::Relaxed);
+  }
+  counts
+}
+
+/// How many handlers are attached to a line
+pub fn get_handler_count(irq: u8) -> usize {
+  match HANDLERS.read().get(irq as usize) {
+    Some(line) => line.iter().filter(|slot| slot.is_some()).count(),
+    None => 0,
+  }
+}
+
+fn dispatch(irq: u8, frame: &StackFrame) {
+  let start = latency::start();
+  COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+  // Copy the line out, so a handler that switches processes is not holding
+  // the lock
+  let line = HANDLERS.read()[irq as usize];
+  for handler in line.iter().flatten() {
+    handler(frame);
+  }
+  controller::acknowledge(irq);
+  latency::finish(irq, start);
+}
+
+macro_rules! irq_stub {
+  ($name:ident, $irq:expr) => {
+    extern "x86-interrupt" fn $name(frame: &StackFrame) {
+      dispatch($irq, frame);
+    }
+  };
+}
+
+irq_stub!(irq_0, 0);
+irq_stub!(irq_1, 1);
+irq_stub!(irq_2, 2);
+irq_stub!(irq_3, 3);
+irq_stub!(irq_4, 4);
+irq_stub!(irq_5, 5);
+irq_stub!(irq_6, 6);
+irq_stub!(irq_7, 7);
+irq_stub!(irq_8, 8);
+irq_stub!(irq_9, 9);
+irq_stub!(irq_10, 10);
+irq_stub!(irq_11, 11);
+irq_stub!(irq_12, 12);
+irq_stub!(irq_13, 13);
+irq_stub!(irq_14, 14);
+irq_stub!(irq_15, 15);
+
+/// The entry point for each IRQ, installed at IRQ_BASE_VECTOR onward
+pub const STUBS: [extern "x86-interrupt" fn(&StackFrame); ISA_IRQ_COUNT] = [
+  irq_0, irq_1, irq_2, irq_3, irq_4, irq_5, irq_6, irq_7,
+  irq_8, irq_9, irq_10, irq_11, irq_12, irq_13, irq_14, irq_15,
+];
@@ -0,0 +1,96 @@
+//! Measures how long each IRQ keeps the CPU in interrupt context, from the
+//! moment its stub is entered to the moment the controller is acknowledged.
+//! Everything else waits for that long, including other interrupts, so the
+//! worst cases show where handlers spin on a contended lock or do too much.
+//!
+//! Times come from the TSC, so nothing is recorded on processors without one,
+//! or before it has been calibrated. Each line keeps a count, a total for the
+//! average, its worst case, and a histogram with power-of-two buckets in
+//! microseconds: the first bucket holds anything under 1us, the next 1-2us,
+//! then 2-4us, and so on, with the last holding everything beyond.
+
+use crate::hardware::cpu;
+use spin::Mutex;
+use super::controller::ISA_IRQ_COUNT;
+
+pub const HISTOGRAM_BUCKETS: usize = 12;
+
+#[derive(Copy, Clone)]
+pub struct LatencyStats {
+  pub count: u32,
+  pub total_ns: u64,
+  pub worst_ns: u32,
+  pub histogram: [u32; HISTOGRAM_BUCKETS],
+}
+
+impl LatencyStats {
+  pub const fn new() -> LatencyStats {
+    LatencyStats {
+      count: 0,
+      total_ns: 0,
+      worst_ns: 0,
+      histogram: [0; HISTOGRAM_BUCKETS],
+    }
+  }
+
+  pub fn record(&mut self, ns: u32) {
+    self.count = self.count.wrapping_add(1);
+    self.total_ns += ns as u64;
+    if ns > self.worst_ns {
+      self.worst_ns = ns;
+    }
+    let bucket = &mut self.histogram[bucket_for(ns)];
+    *bucket = bucket.saturating_add(1);
+  }
+
+  pub fn get_average_ns(&self) -> u32 {
+    match self.count {
+      0 => 0,
+      count => (self.total_ns / count as u64) as u32,
+    }
+  }
+}
+
+/// Histogram bucket for a duration
+pub fn bucket_for(ns: u32) -> usize {
+  let us = ns / 1000;
+  let bucket = (32 - us.leading_zeros()) as usize;
+  bucket.min(HISTOGRAM_BUCKETS - 1)
+}
+
+/// Stats for each line. Interrupts are recorded with the CPU already in
+/// interrupt context, and everything else holds the lock with interrupts off,
+/// so recording never has to wait for it.
+static STATS: Mutex<[LatencyStats; ISA_IRQ_COUNT]> = Mutex::new([LatencyStats::new(); ISA_IRQ_COUNT]);
+
+/// Called as an IRQ stub is entered
+#[inline]
+pub fn start() -> Option<u64> {
+  cpu::read_tsc()
+}
+
+/// Called once the controller has been acknowledged, with the value returned
+/// by `start`
+pub fn finish(irq: u8, start: Option<u64>) {
+  let (start, end, khz) = match (start, cpu::read_tsc(), cpu::get_tsc_khz()) {
+    (Some(start), Some(end), Some(khz)) => (start, end, khz),
+    _ => return,
+  };
+  let ns = end.saturating_sub(start).saturating_mul(1_000_000) / khz as u64;
+  if let Some(mut stats) = STATS.try_lock() {
+    if let Some(line) = stats.get_mut(irq as usize) {
+      line.record(ns.min(u32::MAX as u64) as u32);
+    }
+  }
+}
+
+/// A copy of every line's stats
+pub fn get_stats() -> [LatencyStats; ISA_IRQ_COUNT] {
+  let int_reenable = super::is_interrupt_enabled();
+  super::cli();
+  let stats = *STATS.lock();
+  if int_reenable {
+    super::sti();
+  }
+  stats
+}
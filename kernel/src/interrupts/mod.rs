@@ -1,4 +1,7 @@
+pub mod controller;
 pub mod exceptions;
+pub mod irq;
+pub mod latency;
 pub mod pic;
 pub mod stack;
 pub mod syscall;
@@ -1,40 +1,60 @@
-use crate::{devices, input, process, time, x86};
+use crate::{deterministic, devices, input, process, time, x86};
 use super::stack;
+use super::syscall_legacy::VM8086Frame;
+
+pub fn pit(frame: &stack::StackFrame) {
+  // In deterministic mode the tick only wakes the CPU, and the idle loop
+  // advances the clock instead
+  if !deterministic::is_enabled() {
+    time::system::increment_offset(time::system::HUNDRED_NS_PER_TICK);
+    process::send_tick();
+  }
 
-pub extern "x86-interrupt" fn pit(_frame: &stack::StackFrame) {
-  time::system::increment_offset(time::system::HUNDRED_NS_PER_TICK);
-  process::send_tick();
-  
-  unsafe {
-    devices::PIC.acknowledge_interrupt(0);
+  if frame.eflags & 0x20000 != 0 {
+    // A DOS box was interrupted mid-program, so its memory is mapped and no
+    // kernel locks are held. It can take its virtual timer interrupt straight
+    // away, and have its screen redrawn.
+    let frame_ptr = frame as *const stack::StackFrame as usize;
+    if let Some(current) = process::current_process() {
+      unsafe {
+        let vm_frame = &mut *((frame_ptr + 12) as *mut VM8086Frame);
+        let stack_frame = &mut *(frame_ptr as *mut stack::StackFrame);
+        process::vm86::deliver_timer_interrupt(&current, stack_frame, vm_frame);
+        process::dos_video::refresh(&current);
+      }
+    }
   }
 }
 
 static KEYBOARD_PORT: x86::io::Port = x86::io::Port::new(0x60);
 
-pub extern "x86-interrupt" fn keyboard(_frame: &stack::StackFrame) {
+pub fn keyboard(_frame: &stack::StackFrame) {
   unsafe {
-    let mut data: [u8; 1] = [0; 1];
-    data[0] = KEYBOARD_PORT.read_u8();
-    input::INPUT_EVENTS.write(&data);
+    let data = KEYBOARD_PORT.read_u8();
+    input::push_scan_code(data);
     input::wake_thread();
-
-    devices::PIC.acknowledge_interrupt(1);
   }
 }
 
-pub extern "x86-interrupt" fn com1(_frame: &stack::StackFrame) {
+pub fn com1(_frame: &stack::StackFrame) {
   unsafe {
     devices::COM1.handle_interrupt();
-    devices::PIC.acknowledge_interrupt(4);
   }
 }
 
 
 
-pub extern "x86-interrupt" fn floppy(_frame: &stack::StackFrame) {
+pub fn floppy(_frame: &stack::StackFrame) {
   unsafe {
     devices::FLOPPY.handle_int6();
-    devices::PIC.acknowledge_interrupt(6);
+  }
+}
+
+pub fn mouse(_frame: &stack::StackFrame) {
+  unsafe {
+    let data = KEYBOARD_PORT.read_u8();
+    if let Some(packet) = devices::MOUSE.handle_byte(data) {
+      process::dos_mouse::push_packet(packet);
+    }
   }
 }
@@ -7,6 +7,23 @@ pub struct StackFrame {
   pub eflags: u32,
 }
 
+/// Interrupts that arrive from ring 3 switch to the kernel stack, so the CPU
+/// also saves the user stack pointer beyond the usual frame
+#[repr(C, packed)]
+pub struct UserStackFrame {
+  pub eip: u32,
+  pub cs: u32,
+  pub eflags: u32,
+  pub esp: u32,
+  pub ss: u32,
+}
+
+impl StackFrame {
+  pub fn is_from_usermode(&self) -> bool {
+    self.cs & 3 == 3
+  }
+}
+
 impl fmt::Debug for StackFrame {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     let eip = self.eip;
@@ -1,8 +1,16 @@
+use crate::hardware::qemu;
 use crate::kprintln;
-use crate::syscalls::{exec, file, fs};
+use crate::process;
+use crate::process::environment::MAX_STARTUP_DATA;
+use crate::syscalls::{exec, file, fs, messages, ports, power, testing, time};
+use crate::syscalls::user::Caller;
+use crate::xmodem;
 use super::stack;
+use syscall::memory::MapRequest;
+use syscall::process::SpawnRequest;
 use syscall::result::SystemError;
 
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct SavedRegisters {
   edi: u32,
@@ -14,10 +22,98 @@ pub struct SavedRegisters {
   eax: u32,
 }
 
+/// Saved on the user stack while a signal handler runs. The handler is entered
+/// as if it had been called from `restorer`, with the signal number as its
+/// only argument.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct SignalFrame {
+  restorer: u32,
+  signal: u32,
+  registers: SavedRegisters,
+  eip: u32,
+  eflags: u32,
+  /// Signal mask to restore once the handler returns
+  blocked: u32,
+}
+
+/// Flags that user code may change when returning from a handler: the
+/// arithmetic flags and the direction flag
+const USER_FLAGS: u32 = 0xcd5;
+const INTERRUPT_FLAG: u32 = 0x200;
+
+/// Before returning to userspace, redirect the process into the handler for a
+/// pending signal. The interrupted state is saved on the user stack, where
+/// `signal_return` finds it once the handler is done.
+unsafe fn deliver_signal(frame: &mut stack::StackFrame, registers: &mut SavedRegisters) {
+  if !frame.is_from_usermode() {
+    // Kernel-mode processes have no user stack to run a handler on
+    return;
+  }
+  let (next, terminated) = match process::current_process() {
+    Some(current) => (current.take_handled_signal(), current.is_terminated()),
+    None => return,
+  };
+  if terminated {
+    // A default action ended the process, so it never returns to userspace
+    process::yield_coop();
+    return;
+  }
+  let (sig, handler, restorer) = match next {
+    Some(next) => next,
+    None => return,
+  };
+  let user_frame = &mut *(frame as *mut stack::StackFrame as *mut stack::UserStackFrame);
+  let frame_address = user_frame.esp.wrapping_sub(core::mem::size_of::<SignalFrame>() as u32);
+  let blocked = match process::current_process() {
+    Some(current) => current.get_signal_state().write().enter_handler(sig, frame_address),
+    None => return,
+  };
+  let signal_frame = SignalFrame {
+    restorer,
+    signal: sig,
+    registers: *registers,
+    eip: user_frame.eip,
+    eflags: user_frame.eflags,
+    blocked,
+  };
+  // After a fork the stack page may still be shared with the parent, so it
+  // is copied before the frame is written
+  if Caller::new(true).write_value(frame_address, signal_frame).is_err() {
+    // There is nowhere to save the interrupted state, so the handler cannot
+    // run
+    if let Some(current) = process::current_process() {
+      current.terminate(syscall::signals::SEGFAULT, 0);
+    }
+    process::yield_coop();
+    return;
+  }
+  user_frame.esp = frame_address;
+  user_frame.eip = handler;
+}
+
+/// Resume the code interrupted by a signal, once its handler has returned
+unsafe fn signal_return(frame: &mut stack::StackFrame, registers: &mut SavedRegisters) -> Result<(), SystemError> {
+  if !frame.is_from_usermode() {
+    return Err(SystemError::InvalidArgument);
+  }
+  let current = process::current_process().ok_or(SystemError::Unknown)?;
+  let frame_address = current.get_signal_state().write().leave_handler().ok_or(SystemError::InvalidArgument)?;
+  let saved: SignalFrame = Caller::new(true).read_value(frame_address)?;
+  let user_frame = &mut *(frame as *mut stack::StackFrame as *mut stack::UserStackFrame);
+  *registers = saved.registers;
+  user_frame.eip = saved.eip;
+  user_frame.eflags = (saved.eflags & USER_FLAGS) | INTERRUPT_FLAG;
+  user_frame.esp = frame_address + core::mem::size_of::<SignalFrame>() as u32;
+  current.get_signal_state().write().set_blocked(saved.blocked);
+  Ok(())
+}
+
 #[no_mangle]
 #[inline(never)]
-pub unsafe extern "C" fn _syscall_inner(frame: &stack::StackFrame, registers: &mut SavedRegisters) {
+pub unsafe extern "C" fn _syscall_inner(frame: &mut stack::StackFrame, registers: &mut SavedRegisters) {
   let eax = registers.eax;
+  let caller = Caller::new(frame.is_from_usermode());
   match eax {
     // execution
     0x0 => { // exit
@@ -29,15 +125,12 @@ pub unsafe extern "C" fn _syscall_inner(frame: &stack::StackFrame, registers: &m
       registers.eax = pid;
     },
     0x2 => { // exec
-      let path_str_ptr = &*(registers.ebx as *const syscall::StringPtr);
-      let path_str = path_str_ptr.as_str();
-      let arg_str = if registers.ecx == 0 {
-        ""
-      } else {
-        let arg_str_ptr = &*(registers.ecx as *const syscall::StringPtr);
-        arg_str_ptr.as_str()
-      };
-      let result = match exec::exec_path(path_str, arg_str, registers.edx) {
+      let interp_mode = registers.edx;
+      let result = caller.read_path(registers.ebx).and_then(|path| {
+        let args = caller.read_text(registers.ecx)?;
+        exec::exec_path(&path, &args, interp_mode)
+      });
+      let result = match result {
         Ok(_) => 0,
         Err(e) => e.to_code(),
       };
@@ -57,8 +150,8 @@ pub unsafe extern "C" fn _syscall_inner(frame: &stack::StackFrame, registers: &m
       registers.eax = result;
     },
     0x5 => { // sleep
-      let time = registers.ebx;
-      exec::sleep(time);
+      let ms = registers.ebx;
+      exec::sleep(ms);
     },
     0x6 => { // yield
       exec::yield_coop();
@@ -71,24 +164,69 @@ pub unsafe extern "C" fn _syscall_inner(frame: &stack::StackFrame, registers: &m
     },
     0x09 => { // wait_pid
       let wait_id = registers.ebx;
-      let (pid, code) = exec::wait_pid(wait_id);
-      let status_ptr = registers.ecx as *mut u32;
-      *status_ptr = code;
-      registers.eax = pid;
+      let status_addr = registers.ecx;
+      // Checked before waiting, so a bad pointer cannot reap a child and lose
+      // its status
+      let result = caller.prepare_write(status_addr as usize, 4).and_then(|_| {
+        let (pid, code) = exec::wait_pid(wait_id);
+        caller.write_value(status_addr, code)?;
+        Ok(pid)
+      });
+      registers.eax = match result {
+        Ok(pid) => pid,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x0a => { // restart_userland
+      exec::restart_userland();
+    },
+    0x0b => { // uptime
+      registers.eax = match caller.write_value(registers.ebx, time::get_uptime()) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x0c => { // get_message
+      let id = registers.ebx as u16;
+      let buffer = caller.slice_mut(registers.ecx, registers.edx as usize);
+      let result = match buffer.and_then(|buffer| messages::get_message(id, buffer)) {
+        Ok(length) => length as u32,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x0d => { // set_locale
+      let result = match caller.read_name(registers.ebx).and_then(|locale| messages::set_locale(&locale)) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x0e => { // emulator_exit
+      qemu::debug_exit(registers.ebx);
+      // Only reached when the emulator has no exit device
+      registers.eax = SystemError::UnsupportedCommand.to_code();
+    },
+    0x0f => { // get_process_stats
+      let system = caller.value_mut::<syscall::process::SystemStats>(registers.ebx);
+      let records = caller.slice_mut::<syscall::process::ProcessStats>(registers.ecx, registers.edx as usize);
+      registers.eax = match (system, records) {
+        (Ok(system), Ok(records)) => exec::get_process_stats(&mut *system, records) as u32,
+        (Err(e), _) | (_, Err(e)) => e.to_code(),
+      };
     },
 
     // files
     0x10 => { // open
-      let path_str_ptr = &*(registers.ebx as *const syscall::StringPtr);
-      let path_str = path_str_ptr.as_str();
-      let result = match file::open_path(path_str) {
+      let flags = registers.ecx;
+      let result = match caller.read_path(registers.ebx).and_then(|path| file::open_path(&path, flags)) {
         Ok(handle) => handle,
         Err(e) => e.to_code(),
       };
       registers.eax = result;
     },
     0x11 => { // close
-      let handle = registers.eax;
+      let handle = registers.ebx;
       let result = match file::close(handle) {
         Ok(_) => 0,
         Err(e) => e.to_code(),
@@ -99,7 +237,9 @@ pub unsafe extern "C" fn _syscall_inner(frame: &stack::StackFrame, registers: &m
       let handle = registers.ebx;
       let dest_addr = registers.ecx as *mut u8;
       let length = registers.edx as usize;
-      let result = match file::read(handle, dest_addr, length) {
+      let result = caller.prepare_write(dest_addr as usize, length)
+        .and_then(|_| file::read(handle, dest_addr, length));
+      let result = match result {
         Ok(bytes_read) => bytes_read as u32,
         Err(e) => e.to_code(),
       };
@@ -109,34 +249,61 @@ pub unsafe extern "C" fn _syscall_inner(frame: &stack::StackFrame, registers: &m
       let handle = registers.ebx;
       let src_addr = registers.ecx as *const u8;
       let length = registers.edx as usize;
-      let result = match file::write(handle, src_addr, length) {
+      let result = caller.validate_read(src_addr as usize, length)
+        .and_then(|_| file::write(handle, src_addr, length));
+      let result = match result {
         Ok(bytes_written) => bytes_written as u32,
         Err(e) => e.to_code(),
       };
       registers.eax = result;
     },
     0x14 => { // unlink
-
+      let result = match caller.read_path(registers.ebx).and_then(|path| file::unlink(&path)) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
     },
     0x15 => { // seek
 
     },
     0x16 => { // stat
-
+      let result = caller.value_mut::<syscall::files::FileStatus>(registers.ecx).and_then(|status_ptr| {
+        let path = caller.read_path(registers.ebx)?;
+        file::stat(&path, status_ptr)
+      });
+      let result = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
     },
     0x17 => { // fstat
-
+      let handle = registers.ebx;
+      let result = caller.value_mut::<syscall::files::FileStatus>(registers.ecx)
+        .and_then(|status_ptr| file::fstat(handle, status_ptr));
+      let result = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
     },
     0x18 => { // mkdir
-
+      let result = match caller.read_path(registers.ebx).and_then(|path| file::mkdir(&path)) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
     },
     0x19 => { // rmdir
-
+      let result = match caller.read_path(registers.ebx).and_then(|path| file::rmdir(&path)) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
     },
     0x1a => { // opendir
-      let path_str_ptr = &*(registers.ebx as *const syscall::StringPtr);
-      let path_str = path_str_ptr.as_str();
-      let result = match file::open_dir(path_str) {
+      let result = match caller.read_path(registers.ebx).and_then(|path| file::open_dir(&path)) {
         Ok(handle) => handle,
         Err(e) => e.to_code(),
       };
@@ -145,15 +312,21 @@ pub unsafe extern "C" fn _syscall_inner(frame: &stack::StackFrame, registers: &m
     0x1b => { // readdir
       let handle = registers.ebx;
       let index = registers.ecx as usize;
-      let info_ptr = registers.edx as *mut syscall::files::DirEntryInfo;
-      let result = match file::read_dir(handle, index, info_ptr) {
+      let result = caller.value_mut::<syscall::files::DirEntryInfo>(registers.edx)
+        .and_then(|info_ptr| file::read_dir(handle, index, info_ptr));
+      let result = match result {
         Ok(_) => 0,
         Err(e) => e.to_code(),
       };
       registers.eax = result;
     },
     0x1c => { // closedir
-
+      let handle = registers.ebx;
+      let result = match file::close_dir(handle) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
     },
     0x1d => { // dup
       let to_duplicate = registers.ebx;
@@ -168,22 +341,34 @@ pub unsafe extern "C" fn _syscall_inner(frame: &stack::StackFrame, registers: &m
       let handle = registers.ebx;
       let command = registers.ecx;
       let arg = registers.edx;
-      let result = match file::ioctl(handle, command, arg) {
+      let result = if command == syscall::flags::F_GETPATH {
+        // Answered from the process's handle table, not by the filesystem
+        caller.read_value::<syscall::StringPtr>(arg).and_then(|buffer_ptr| {
+          let buffer = unsafe { caller.slice_mut::<u8>(buffer_ptr.addr as u32, buffer_ptr.length)? };
+          file::get_path(handle, buffer)
+        })
+      } else {
+        file::ioctl(handle, command, arg).map_err(|_| SystemError::UnsupportedCommand)
+      };
+      let result = match result {
         Ok(value) => value,
-        Err(_) => SystemError::UnsupportedCommand.to_code(),
+        Err(e) => e.to_code(),
       };
       registers.eax = result;
     },
     0x1f => { // pipe
-      let read_handle_ptr = registers.ebx as *mut usize;
-      let write_handle_ptr = registers.ecx as *mut usize;
-      let result = match file::pipe() {
-        Ok((read, write)) => {
-          *read_handle_ptr = read as usize;
-          *write_handle_ptr = write as usize;
-          0
+      let read_handle_ptr = caller.value_mut::<u32>(registers.ebx);
+      let write_handle_ptr = caller.value_mut::<u32>(registers.ecx);
+      let result = match (read_handle_ptr, write_handle_ptr) {
+        (Ok(read_handle_ptr), Ok(write_handle_ptr)) => match file::pipe() {
+          Ok((read, write)) => {
+            *read_handle_ptr = read;
+            *write_handle_ptr = write;
+            0
+          },
+          Err(_) => 0xffffffff,
         },
-        Err(_) => 0xffffffff,
+        (Err(e), _) | (_, Err(e)) => e.to_code(),
       };
       registers.eax = result;
     },
@@ -198,8 +383,162 @@ pub unsafe extern "C" fn _syscall_inner(frame: &stack::StackFrame, registers: &m
       registers.eax = result;
     },
     0x21 => { // chdir
+      let result = match caller.read_path(registers.ebx).and_then(|path| file::change_dir(&path)) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
     },
     0x22 => { // getcwd
+      registers.eax = match caller.slice_mut(registers.ebx, registers.ecx as usize) {
+        Ok(buffer) => file::get_cwd(buffer) as u32,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x23 => { // create
+      let result = match caller.read_path(registers.ebx).and_then(|path| file::create_path(&path)) {
+        Ok(handle) => handle,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x24 => { // open_by_id
+      let drive = registers.ebx;
+      let file_id = registers.ecx;
+      let flags = registers.edx;
+      let result = match file::open_by_id(drive, file_id, flags) {
+        Ok(handle) => handle,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x25 => { // get_xattr
+      let handle = registers.ebx;
+      let result = caller.read_value::<syscall::StringPtr>(registers.edx).and_then(|buffer_ptr| {
+        let (dest, length) = (buffer_ptr.addr, buffer_ptr.length);
+        caller.prepare_write(dest, length)?;
+        let name = caller.read_name(registers.ecx)?;
+        file::get_xattr(handle, &name, dest as *mut u8, length)
+      });
+      let result = match result {
+        Ok(length) => length as u32,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x26 => { // set_xattr
+      let handle = registers.ebx;
+      let result = caller.read_value::<syscall::StringPtr>(registers.edx).and_then(|value_ptr| {
+        let value = caller.copy_from_user(value_ptr.addr, value_ptr.length)?;
+        let name = caller.read_name(registers.ecx)?;
+        file::set_xattr(handle, &name, value.as_ptr(), value.len())
+      });
+      let result = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x27 => { // rename
+      let result = caller.read_path(registers.ebx).and_then(|old| {
+        let new = caller.read_path(registers.ecx)?;
+        file::rename(&old, &new)
+      });
+      let result = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x28 => { // link
+      let result = caller.read_path(registers.ebx).and_then(|existing| {
+        let new = caller.read_path(registers.ecx)?;
+        file::link(&existing, &new)
+      });
+      let result = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x29 => { // ftruncate
+      let handle = registers.ebx;
+      let length = registers.ecx as usize;
+      let result = match file::truncate(handle, length) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x2a => { // fallocate
+      let handle = registers.ebx;
+      let offset = registers.ecx as usize;
+      let length = registers.edx as usize;
+      let result = match file::preallocate(handle, offset, length) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x2b => { // memfd_create
+      let result = match file::memfd_create() {
+        Ok(handle) => handle,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x2c => { // receive_file
+      let result = caller.read_path(registers.ebx).and_then(|port| {
+        let path = caller.read_path(registers.ecx)?;
+        xmodem::receive_file(&port, &path)
+      });
+      let result = match result {
+        Ok(length) => length,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x2d => { // write_atomic
+      let src = registers.ecx as *const u8;
+      let length = registers.edx as usize;
+      let result = caller.validate_read(src as usize, length).and_then(|_| {
+        let path = caller.read_path(registers.ebx)?;
+        file::write_atomic(&path, src, length)
+      });
+      let result = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x2e => { // read_dir_entries
+      let handle = registers.ebx;
+      let batch_addr = registers.ecx;
+      let result = caller.read_value::<syscall::files::DirEntryBatch>(batch_addr).and_then(|mut batch| {
+        let entries = unsafe {
+          caller.slice_mut::<syscall::files::DirEntryInfo>(batch.entries, batch.capacity as usize)?
+        };
+        let (filled, cookie) = file::read_dir_entries(handle, batch.cookie, entries)?;
+        batch.cookie = cookie;
+        caller.write_value(batch_addr, batch)?;
+        Ok(filled)
+      });
+      let result = match result {
+        Ok(filled) => filled,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x2f => { // utime
+      let modified = registers.ecx;
+      let result = caller.read_path(registers.ebx).and_then(|path| {
+        file::set_modified_time(&path, modified)
+      });
+      let result = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
     },
 
     // filesystem
@@ -210,8 +549,206 @@ pub unsafe extern "C" fn _syscall_inner(frame: &stack::StackFrame, registers: &m
 
     },
     0x32 => { // mount
+      let result = caller.read_name(registers.ebx).and_then(|drive| {
+        let device = caller.read_path(registers.ecx)?;
+        let driver = caller.read_name(registers.edx)?;
+        fs::mount(&drive, &device, &driver)
+      });
+      let result = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
     },
     0x33 => { // unmount
+      let result = match caller.read_name(registers.ebx).and_then(|drive| fs::unmount(&drive)) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x34 => { // get_mount_table
+      let records = caller.slice_mut::<syscall::files::MountInfo>(registers.ebx, registers.ecx as usize);
+      registers.eax = match records {
+        Ok(records) => fs::get_mount_table(records),
+        Err(e) => e.to_code(),
+      };
+    },
+
+    // signals
+    0x40 => { // set_signal_action
+      let sig = registers.ebx;
+      let handler = registers.ecx;
+      let restorer = registers.edx;
+      let result = match exec::set_signal_action(sig, handler, restorer) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x41 => { // set_signal_mask
+      let method = registers.ebx;
+      let mask = registers.ecx;
+      let result = match exec::set_signal_mask(method, mask) {
+        Ok(previous) => previous,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x42 => { // signal_return
+      if let Err(e) = signal_return(frame, registers) {
+        registers.eax = e.to_code();
+      }
+    },
+
+    // job control
+    0x50 => { // set_process_group
+      let pid = registers.ebx;
+      let group = registers.ecx;
+      let result = match exec::set_process_group(pid, group) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x51 => { // get_process_group
+      let pid = registers.ebx;
+      let result = match exec::get_process_group(pid) {
+        Ok(group) => group,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x52 => { // create_session
+      let result = match exec::create_session() {
+        Ok(session) => session,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+
+    // threads and spawning
+    0x60 => { // create_thread
+      let entry = registers.ebx;
+      let stack = registers.ecx;
+      let arg = registers.edx;
+      let result = match exec::create_thread(entry, stack, arg) {
+        Ok(pid) => pid,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x61 => { // spawn
+      let result = caller.read_value::<SpawnRequest>(registers.ebx).and_then(|request| {
+        let path = caller.read_path(request.path)?;
+        let args = caller.read_text(request.args)?;
+        let environment = match request.environment {
+          0 => None,
+          addr => {
+            let length = request.environment_length as usize;
+            if length > MAX_STARTUP_DATA {
+              return Err(SystemError::InvalidArgument);
+            }
+            Some(caller.copy_from_user(addr as usize, length)?)
+          },
+        };
+        exec::spawn(&path, &args, &request, environment)
+      });
+      let result = match result {
+        Ok(pid) => pid,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+
+    // environment
+    0x70 => { // get_env
+      let result = caller.slice_mut(registers.ecx, registers.edx as usize).and_then(|buffer| {
+        let name = caller.read_name(registers.ebx)?;
+        exec::get_env(&name, buffer)
+      });
+      let result = match result {
+        Ok(length) => length,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x71 => { // set_env
+      let result = caller.read_name(registers.ebx).and_then(|name| {
+        let value = caller.read_text(registers.ecx)?;
+        exec::set_env(&name, &value)
+      });
+      let result = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x72 => { // unset_env
+      let result = match caller.read_name(registers.ebx).and_then(|name| exec::unset_env(&name)) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x73 => { // get_environment
+      let buffer = caller.slice_mut(registers.ebx, registers.ecx as usize);
+      let result = match buffer.and_then(|buffer| exec::get_environment(buffer)) {
+        Ok(length) => length,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+
+    // memory
+    0x80 => { // mmap
+      let result = match caller.read_value::<MapRequest>(registers.ebx).and_then(|request| exec::mmap(&request)) {
+        Ok(address) => address,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x81 => { // munmap
+      let result = match exec::munmap(registers.ebx, registers.ecx) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+
+    // headless testing
+    0x90 => { // test_report
+      let kind = registers.ebx;
+      let result = match caller.read_text(registers.ecx).and_then(|message| testing::report(kind, &message)) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x91 => { // test_finish
+      registers.eax = match testing::finish() {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+
+    // power
+    0xa0 => { // shutdown
+      registers.eax = match power::shutdown(registers.ebx) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+
+    // hardware
+    0xb0 => { // ioperm
+      let first = registers.ebx;
+      let count = registers.ecx;
+      let allowed = registers.edx != 0;
+      registers.eax = match ports::ioperm(first, count, allowed) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
     },
 
     // misc
@@ -224,4 +761,6 @@ pub unsafe extern "C" fn _syscall_inner(frame: &stack::StackFrame, registers: &m
       registers.eax = SystemError::Unknown.to_code();
     },
   }
+
+  deliver_signal(frame, registers);
 }
\ No newline at end of file
@@ -8,22 +8,31 @@
 #![no_std]
 
 // Test-safe modules
+pub mod acpi;
+pub mod assertions;
+pub mod audio;
 pub mod buffers;
+pub mod checksum;
 pub mod collections;
+pub mod deterministic;
+pub mod disks;
 pub mod files;
 pub mod filesystems;
+pub mod line_editor;
 pub mod memory;
+pub mod messages;
 pub mod pipes;
 pub mod promise;
+pub mod task;
+pub mod testreport;
 pub mod time;
+pub mod xmodem;
 
 #[cfg(not(test))]
 pub mod debug;
 #[cfg(not(test))]
 pub mod devices;
 #[cfg(not(test))]
-pub mod disks;
-#[cfg(not(test))]
 pub mod drivers;
 #[cfg(not(test))]
 pub mod gdt;
@@ -46,6 +55,8 @@ pub mod syscalls;
 #[cfg(not(test))]
 pub mod tty;
 #[cfg(not(test))]
+pub mod workers;
+#[cfg(not(test))]
 pub mod x86;
 
 use memory::address::PhysicalAddress;
@@ -77,6 +88,10 @@ extern {
 pub struct BootStruct {
   initfs_start: usize,
   initfs_size: usize,
+  boot_flags: usize,
+  /// Physical address of the E820 memory map, and how many entries it holds
+  memory_map_address: usize,
+  memory_map_count: usize,
 }
 
 /**
@@ -101,9 +116,9 @@ unsafe fn init_tables() {
 }
 
 #[cfg(not(test))]
-unsafe fn init_memory_new() {
+unsafe fn init_memory_new(memory_map: &[memory::map::MapEntry]) {
   let allocator_location = &label_rw_physical_end as *const u8 as usize;
-  memory::physical::init_allocator(allocator_location, 0x1000);
+  memory::physical::init_allocator(allocator_location, memory_map);
 
   let stack_start_address = PhysicalAddress::new(&label_stack_start as *const u8 as usize);
   let kernel_data_bounds = memory::virt::KernelDataBounds {
@@ -115,6 +130,7 @@ unsafe fn init_memory_new() {
   let initial_pagedir = memory::virt::create_initial_pagedir();
   memory::virt::map_kernel(initial_pagedir, &kernel_data_bounds);
   initial_pagedir.make_active();
+  gdt::set_double_fault_page_directory(initial_pagedir.get_address().as_u32());
   memory::virt::enable_paging();
 
   memory::physical::move_allocator_reference_to_highmem();
@@ -151,55 +167,91 @@ unsafe fn init_memory_new() {
 #[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn _start(boot_struct_ptr: *const BootStruct) -> ! {
-  let initfs_start = unsafe {
+  let (initfs_start, boot_flags, memory_map) = unsafe {
     let boot_struct = &*boot_struct_ptr;
-    boot_struct.initfs_start
-  } | 0xc0000000;
+    // Paging is not enabled yet, so the map is read at its physical address
+    let memory_map = memory::map::load_entries(boot_struct.memory_map_address, boot_struct.memory_map_count);
+    (boot_struct.initfs_start | 0xc0000000, boot_struct.boot_flags, memory_map)
+  };
 
   unsafe {
-    let boot_struct = &*boot_struct_ptr;
     zero_bss();
-    init_memory_new();
+    debug::detect_debugcon();
+    assertions::apply_boot_flags(boot_flags);
+    deterministic::apply_boot_flags(boot_flags);
+    testreport::apply_boot_flags(boot_flags);
+    init_memory_new(memory_map);
     init_tables();
   }
 
   unsafe {
     kprintln!("\nEntering the Kernel...");
 
+    hardware::cpu::init();
+    kprintln!("CPU: {}", hardware::cpu::get_info());
+    if let Some(khz) = hardware::cpu::get_tsc_khz() {
+      kprintln!("TSC: {} MHz", khz / 1000);
+    }
+
     kprintln!(
       "\nTotal Memory: {} KiB\nFree Memory: {} KiB",
-      memory::physical::get_frame_count() * 4,
+      memory::physical::get_usable_frame_count() * 4,
       memory::physical::get_free_frame_count() * 4,
     );
 
-    let heap_start = memory::address::VirtualAddress::new(0xc0400000);
+    let heap_start = memory::address::VirtualAddress::new(memory::heap::HEAP_START);
     {
       let heap_size_frames = memory::heap::INITIAL_HEAP_SIZE;
       memory::heap::map_allocator(heap_start, heap_size_frames);
       let heap_size = heap_size_frames * 0x1000;
-      kprintln!("Kernel heap at {:?}-{:?}", heap_start, memory::address::VirtualAddress::new(0xc0400000 + heap_size));
+      kprintln!("Kernel heap at {:?}-{:?}", heap_start, memory::address::VirtualAddress::new(memory::heap::HEAP_START + heap_size));
       memory::heap::init_allocator(heap_start, heap_size);
     }
     memory::physical::init_refcount();
 
     // Initialize hardware
+    acpi::init();
     devices::init();
     tty::init_ttys();
-    time::system::initialize_from_rtc();
 
     filesystems::init_fs();
 
     let init_fs = filesystems::init::InitFileSystem::new(memory::address::VirtualAddress::new(initfs_start));
     let boxed_fs = alloc::boxed::Box::new(init_fs);
-    filesystems::VFS.register_fs("INIT", boxed_fs).expect("Failed to register INIT FS");
+    filesystems::VFS.mount_drive(
+      "INIT",
+      boxed_fs,
+      filesystems::options::MountOptions::permanent(true),
+    ).expect("Failed to register INIT FS");
+
+    // The RTC keeps local time, so the clock can only be set once the time
+    // zone has been read from the INIT drive
+    if time::system::load_time_zone().is_err() {
+      klog!("No time zone configured, using UTC");
+    }
+    if deterministic::is_enabled() {
+      let boot_time = time::timestamp::TimestampHires::from_timestamp(deterministic::FIXED_BOOT_TIME);
+      time::system::reset_known_time(boot_time.0);
+      klog!("Deterministic mode enabled");
+    } else {
+      time::system::initialize_from_rtc();
+    }
+    {
+      let boot_time = time::system::to_local_datetime(time::system::get_boot_time().to_timestamp());
+      klog!("Boot time: {} {}", boot_time.date, boot_time.time);
+    }
+    // Without a catalog, the built-in English messages are used
+    let _ = messages::load_locale(messages::DEFAULT_LOCALE);
 
+    process::shared_data::init();
+    process::fpu::init();
     process::init();
     let init_process = process::all_processes_mut().spawn_first_process(heap_start);
     process::make_current(init_process);
   }
 
-  let current_time = time::system::get_system_time().to_timestamp().to_datetime();
-  tty::console_write(format_args!("System Time: {:} {:}\n", current_time.date, current_time.time));
+  let current_time = time::system::get_local_time();
+  messages::console(syscall::messages::SYSTEM_TIME, &[&current_time.date, &current_time.time]);
 
   // Spawn init process
   let init_proc_id = process::all_processes_mut().fork_current();
@@ -207,34 +259,57 @@ pub extern "C" fn _start(boot_struct_ptr: *const BootStruct) -> ! {
     let mut processes = process::all_processes_mut();
     let init_proc = processes.get_process(init_proc_id).unwrap();
     init_proc.set_initial_entry_point(user_init, 0xbffffffc);
+    // Userland gets a session of its own, apart from the kernel processes
+    init_proc.create_session();
   }
+  process::restart::set_init_process(init_proc_id);
 
   {
-    let input_proc = process::all_processes_mut().fork_current();
-    process::set_kernel_mode_function(input_proc, input::run_input);
+    process::spawn_kthread(input::run_input, "INPUT").expect("Failed to start input thread");
 
-    let disk_proc = process::all_processes_mut().fork_current();
-    process::set_kernel_mode_function(disk_proc, disks::floppy_driver);
+    workers::create_pool(workers::DISK_POOL, 2, 16);
+    workers::delayed::start();
+    disks::blockio::start();
+    disks::init();
+    disks::start_media_watch();
 
-    let ttys_proc = process::all_processes_mut().fork_current();
-    process::set_kernel_mode_function(ttys_proc, tty::ttys_process);
+    process::spawn_kthread(tty::ttys_process, "TTYS").expect("Failed to start TTY thread");
   }
 
   process::enter_usermode(init_proc_id);
 
   loop {
+    if process::restart::take_restart_request() {
+      klog!("Restarting userland");
+      process::restart::restart_userland(user_init, 0xbffffffc);
+    }
     unsafe {
       llvm_asm!("cli" : : : : "volatile");
+      if deterministic::is_enabled() {
+        // The PIT no longer moves the clock; each scheduling round is a tick
+        time::system::increment_offset(time::system::HUNDRED_NS_PER_TICK);
+        process::send_tick();
+      }
       process::yield_coop();
+      process::kthread::reap_exited();
       llvm_asm!("sti; hlt" : : : : "volatile");
     }
   }
 }
 
+/// Print a message from the catalog on its own line
+fn write_message(handle: u32, id: u16) {
+  let mut buffer: [u8; 128] = [0; 128];
+  if let Ok(length) = syscall::result::result_from_code(syscall::get_message(id, &mut buffer)) {
+    syscall::write(handle, buffer.as_ptr(), (length as usize).min(buffer.len()));
+  }
+  syscall::write_str(handle, "\n");
+}
+
 #[inline(never)]
 pub extern fn user_init() {
   let tty0 = syscall::open("DEV:\\TTY0");
-  syscall::write_str(tty0, "Initializing devices...\n");
+  write_message(tty0, syscall::messages::INITIALIZING_DEVICES);
   let pid = syscall::get_pid() as u8;
   let mut pidmsg: [u8; 7] = [b'P', b'I', b'D', b':', b' ', b' ', b'\n'];
   unsafe {
@@ -244,10 +319,10 @@ pub extern fn user_init() {
   syscall::raise(syscall::signals::STOP);
   syscall::yield_coop();
 
-  syscall::write_str(tty0, "System ready.\n");
+  write_message(tty0, syscall::messages::SYSTEM_READY);
 
   let mut entry = syscall::files::DirEntryInfo::empty();
-  syscall::write_str(tty0, "Root Directory Contents:\n");
+  write_message(tty0, syscall::messages::ROOT_DIRECTORY_CONTENTS);
   let dir_handle = syscall::open_dir("A:\\");
   let mut dir_index = 0;
   loop {
@@ -260,6 +335,10 @@ pub extern fn user_init() {
     syscall::write(tty0, entry.file_name.as_ptr(), entry.file_name.len());
     syscall::write_str(tty0, " ");
     syscall::write(tty0, entry.file_ext.as_ptr(), entry.file_ext.len());
+    if let Some(long_name) = entry.get_long_name() {
+      syscall::write_str(tty0, "  ");
+      syscall::write_str(tty0, long_name);
+    }
     syscall::write_str(tty0, "\n");
   }
   syscall::write_str(tty0, "DONE");
@@ -0,0 +1,400 @@
+//! Canonical-mode line editing, for any character device attached to a
+//! terminal. The editor takes the raw bytes typed at the terminal and keeps
+//! the line being edited. It produces two things: bytes to echo back, so the
+//! terminal shows the line as it changes, and finished lines once Enter is
+//! pressed. Echoed output uses ANSI cursor movement and erase sequences, which
+//! the console TTYs and serial terminals both understand. Lines are assumed to
+//! fit on one row of the terminal.
+//!
+//! Tab asks a Completer for the words that could finish the one before the
+//! cursor. A single match is filled in, followed by a space. When there are
+//! several, their common prefix is filled in, or if that adds nothing, they
+//! are listed below the line.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Longest line the editor will hold. Typing past it rings the bell.
+pub const MAX_LINE_LENGTH: usize = 255;
+
+const BELL: u8 = 0x07;
+const ESCAPE: u8 = 0x1b;
+
+/// Supplies the candidates for Tab completion
+pub trait Completer {
+  /// Every word that could replace `word`, the text between the last space
+  /// and the cursor
+  fn complete(&self, word: &str) -> Vec<String>;
+}
+
+/// A fixed set of words, like the command names published by a shell
+pub struct WordList {
+  words: Vec<String>,
+}
+
+impl WordList {
+  pub fn new() -> WordList {
+    WordList {
+      words: Vec::new(),
+    }
+  }
+
+  /// Build a list from text with one word on each line. Blank lines are
+  /// skipped.
+  pub fn from_text(text: &str) -> WordList {
+    let words = text.lines()
+      .map(|line| line.trim())
+      .filter(|line| !line.is_empty())
+      .map(String::from)
+      .collect();
+    WordList {
+      words,
+    }
+  }
+}
+
+impl Completer for WordList {
+  fn complete(&self, word: &str) -> Vec<String> {
+    self.words.iter()
+      .filter(|candidate| candidate.starts_with(word))
+      .cloned()
+      .collect()
+  }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum EscapeState {
+  None,
+  /// Saw ESC, and expects '[' next
+  Escape,
+  /// Inside a CSI sequence, with the numeric parameter so far
+  Csi(u8),
+}
+
+pub struct LineEditor {
+  line: Vec<u8>,
+  cursor: usize,
+  escape: EscapeState,
+  /// Lines finished with Enter, waiting to be read. An empty entry marks the
+  /// end of input, typed as Ctrl+D on an empty line.
+  finished: VecDeque<Vec<u8>>,
+}
+
+impl LineEditor {
+  pub fn new() -> LineEditor {
+    LineEditor {
+      line: Vec::with_capacity(MAX_LINE_LENGTH),
+      cursor: 0,
+      escape: EscapeState::None,
+      finished: VecDeque::new(),
+    }
+  }
+
+  /// Whether a finished line, or the end of input, is waiting to be read
+  pub fn has_line(&self) -> bool {
+    !self.finished.is_empty()
+  }
+
+  /// Copy out the oldest finished line, including its newline. A line longer
+  /// than the buffer is returned over several reads, and a read never returns
+  /// parts of two lines. Returns 0 at the end of input, or if no line is
+  /// finished yet.
+  pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+    let line = match self.finished.front_mut() {
+      Some(line) => line,
+      None => return 0,
+    };
+    let length = line.len().min(buffer.len());
+    buffer[..length].copy_from_slice(&line[..length]);
+    line.drain(..length);
+    if line.is_empty() {
+      self.finished.pop_front();
+    }
+    length
+  }
+
+  /// Process one byte typed at the terminal. Anything that should be shown on
+  /// the terminal in response is appended to `echo`.
+  pub fn input(&mut self, byte: u8, echo: &mut Vec<u8>, completer: &dyn Completer) {
+    match self.escape {
+      EscapeState::None => (),
+      EscapeState::Escape => {
+        self.escape = if byte == b'[' {
+          EscapeState::Csi(0)
+        } else {
+          EscapeState::None
+        };
+        return;
+      },
+      EscapeState::Csi(param) => {
+        self.escape = EscapeState::None;
+        match byte {
+          b'0'..=b'9' => {
+            let param = param.saturating_mul(10).saturating_add(byte - b'0');
+            self.escape = EscapeState::Csi(param);
+          },
+          b'C' => self.move_to(self.cursor + 1, echo),
+          b'D' => self.move_to(self.cursor.saturating_sub(1), echo),
+          b'H' => self.move_to(0, echo),
+          b'F' => self.move_to(self.line.len(), echo),
+          b'~' => match param {
+            1 | 7 => self.move_to(0, echo),
+            3 => self.remove(self.cursor, self.cursor + 1, echo),
+            4 | 8 => self.move_to(self.line.len(), echo),
+            _ => (),
+          },
+          _ => (),
+        }
+        return;
+      },
+    }
+
+    match byte {
+      ESCAPE => self.escape = EscapeState::Escape,
+      b'\r' | b'\n' => self.finish_line(echo),
+      0x08 | 0x7f => { // Backspace
+        if self.cursor > 0 {
+          self.remove(self.cursor - 1, self.cursor, echo);
+        }
+      },
+      0x01 => self.move_to(0, echo), // Ctrl+A
+      0x02 => self.move_to(self.cursor.saturating_sub(1), echo), // Ctrl+B
+      0x04 => { // Ctrl+D
+        if self.line.is_empty() {
+          self.finished.push_back(Vec::new());
+        } else {
+          self.remove(self.cursor, self.cursor + 1, echo);
+        }
+      },
+      0x05 => self.move_to(self.line.len(), echo), // Ctrl+E
+      0x06 => self.move_to(self.cursor + 1, echo), // Ctrl+F
+      0x0b => self.remove(self.cursor, self.line.len(), echo), // Ctrl+K
+      0x15 => self.remove(0, self.cursor, echo), // Ctrl+U
+      0x17 => self.remove(self.word_start(), self.cursor, echo), // Ctrl+W
+      b'\t' => self.complete(echo, completer),
+      0x20..=0x7e => self.insert(&[byte], echo),
+      _ => (),
+    }
+  }
+
+  /// Start of the word ending at the cursor, after any spaces before it
+  fn word_start(&self) -> usize {
+    let before = &self.line[..self.cursor];
+    let end = before.iter().rposition(|b| *b != b' ').map_or(0, |i| i + 1);
+    before[..end].iter().rposition(|b| *b == b' ').map_or(0, |i| i + 1)
+  }
+
+  fn move_to(&mut self, position: usize, echo: &mut Vec<u8>) {
+    let position = position.min(self.line.len());
+    if position < self.cursor {
+      push_csi(echo, self.cursor - position, b'D');
+    } else if position > self.cursor {
+      push_csi(echo, position - self.cursor, b'C');
+    }
+    self.cursor = position;
+  }
+
+  /// Redraw everything from the cursor to the end of the line, erasing
+  /// whatever used to be past it, and return the cursor to its place
+  fn redraw_tail(&self, echo: &mut Vec<u8>) {
+    let tail = &self.line[self.cursor..];
+    echo.extend_from_slice(tail);
+    push_csi(echo, 0, b'K');
+    if !tail.is_empty() {
+      push_csi(echo, tail.len(), b'D');
+    }
+  }
+
+  fn insert(&mut self, bytes: &[u8], echo: &mut Vec<u8>) {
+    if self.line.len() + bytes.len() > MAX_LINE_LENGTH {
+      echo.push(BELL);
+      return;
+    }
+    for (offset, byte) in bytes.iter().enumerate() {
+      self.line.insert(self.cursor + offset, *byte);
+    }
+    self.cursor += bytes.len();
+    echo.extend_from_slice(bytes);
+    if self.cursor < self.line.len() {
+      self.redraw_tail(echo);
+    }
+  }
+
+  /// Delete the bytes from `start` up to `end`, leaving the cursor at `start`
+  fn remove(&mut self, start: usize, end: usize, echo: &mut Vec<u8>) {
+    let end = end.min(self.line.len());
+    if start >= end {
+      return;
+    }
+    self.move_to(start, echo);
+    self.line.drain(start..end);
+    self.redraw_tail(echo);
+  }
+
+  fn finish_line(&mut self, echo: &mut Vec<u8>) {
+    self.move_to(self.line.len(), echo);
+    echo.extend_from_slice(b"\r\n");
+    let mut line = core::mem::replace(&mut self.line, Vec::with_capacity(MAX_LINE_LENGTH));
+    line.push(b'\n');
+    self.finished.push_back(line);
+    self.cursor = 0;
+  }
+
+  fn complete(&mut self, echo: &mut Vec<u8>, completer: &dyn Completer) {
+    let start = self.line[..self.cursor].iter().rposition(|b| *b == b' ').map_or(0, |i| i + 1);
+    // Only printable ASCII is ever inserted, so the word is always valid
+    let word = match core::str::from_utf8(&self.line[start..self.cursor]) {
+      Ok(word) => String::from(word),
+      Err(_) => return,
+    };
+    let candidates = completer.complete(&word);
+    let first = match candidates.first() {
+      Some(first) => first.as_bytes(),
+      None => {
+        echo.push(BELL);
+        return;
+      },
+    };
+    let common = candidates.iter().skip(1).fold(first.len(), |length, candidate| {
+      first.iter()
+        .zip(candidate.as_bytes())
+        .take(length)
+        .take_while(|(a, b)| a == b)
+        .count()
+    });
+    if candidates.len() == 1 {
+      let mut addition = Vec::from(&first[word.len().min(common)..]);
+      addition.push(b' ');
+      self.insert(&addition, echo);
+    } else if common > word.len() {
+      let addition = Vec::from(&first[word.len()..common]);
+      self.insert(&addition, echo);
+    } else {
+      // Nothing more is certain, so show the choices and then the line again
+      echo.extend_from_slice(b"\r\n");
+      for (index, candidate) in candidates.iter().enumerate() {
+        if index > 0 {
+          echo.extend_from_slice(b"  ");
+        }
+        echo.extend_from_slice(candidate.as_bytes());
+      }
+      echo.extend_from_slice(b"\r\n");
+      echo.extend_from_slice(&self.line);
+      if self.cursor < self.line.len() {
+        push_csi(echo, self.line.len() - self.cursor, b'D');
+      }
+    }
+  }
+}
+
+/// Append a CSI sequence with an optional count. A count of zero is left out.
+fn push_csi(echo: &mut Vec<u8>, count: usize, command: u8) {
+  echo.push(ESCAPE);
+  echo.push(b'[');
+  if count > 0 {
+    let mut digits = [0u8; 20];
+    let mut remaining = count;
+    let mut length = 0;
+    while remaining > 0 {
+      digits[length] = b'0' + (remaining % 10) as u8;
+      remaining /= 10;
+      length += 1;
+    }
+    echo.extend(digits[..length].iter().rev());
+  }
+  echo.push(command);
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::{LineEditor, WordList};
+
+  fn type_bytes(editor: &mut LineEditor, words: &WordList, bytes: &[u8]) -> Vec<u8> {
+    let mut echo = Vec::new();
+    for byte in bytes {
+      editor.input(*byte, &mut echo, words);
+    }
+    echo
+  }
+
+  fn read_line(editor: &mut LineEditor) -> Vec<u8> {
+    let mut buffer = [0; 64];
+    let length = editor.read(&mut buffer);
+    Vec::from(&buffer[..length])
+  }
+
+  #[test]
+  fn finishes_lines() {
+    let mut editor = LineEditor::new();
+    let words = WordList::new();
+    let echo = type_bytes(&mut editor, &words, b"dir");
+    assert_eq!(echo, b"dir");
+    assert!(!editor.has_line());
+    let echo = type_bytes(&mut editor, &words, b"\rver\r");
+    assert_eq!(echo, b"\r\nver\r\n");
+    assert_eq!(read_line(&mut editor), b"dir\n");
+    assert_eq!(read_line(&mut editor), b"ver\n");
+    assert!(!editor.has_line());
+  }
+
+  #[test]
+  fn edits_in_the_middle() {
+    let mut editor = LineEditor::new();
+    let words = WordList::new();
+    // Move back over "c", insert "b", then delete the "x" before it
+    let echo = type_bytes(&mut editor, &words, b"axc\x1b[Db\x02\x7f\r");
+    assert_eq!(read_line(&mut editor), b"abc\n");
+    assert!(echo.ends_with(b"\r\n"));
+  }
+
+  #[test]
+  fn kills_words_and_lines() {
+    let mut editor = LineEditor::new();
+    let words = WordList::new();
+    type_bytes(&mut editor, &words, b"copy a.txt  \x17b.txt\r");
+    assert_eq!(read_line(&mut editor), b"copy b.txt\n");
+    type_bytes(&mut editor, &words, b"mistake\x15ok\x01\x0b\r");
+    assert_eq!(read_line(&mut editor), b"\n");
+  }
+
+  #[test]
+  fn end_of_input() {
+    let mut editor = LineEditor::new();
+    let words = WordList::new();
+    type_bytes(&mut editor, &words, b"ab\x01\x04\x04");
+    assert!(!editor.has_line());
+    type_bytes(&mut editor, &words, b"\x04");
+    assert!(editor.has_line());
+    assert_eq!(read_line(&mut editor), b"");
+    assert!(!editor.has_line());
+  }
+
+  #[test]
+  fn completes_words() {
+    let mut editor = LineEditor::new();
+    let words = WordList::from_text("format\nfdisk\nmem\n");
+    type_bytes(&mut editor, &words, b"m\t");
+    type_bytes(&mut editor, &words, b"x\r");
+    assert_eq!(read_line(&mut editor), b"mem x\n");
+
+    let echo = type_bytes(&mut editor, &words, b"f\t");
+    assert_eq!(echo, b"f\r\nformat  fdisk\r\nf");
+    type_bytes(&mut editor, &words, b"o\t\r");
+    assert_eq!(read_line(&mut editor), b"format \n");
+  }
+
+  #[test]
+  fn long_lines_are_read_in_pieces() {
+    let mut editor = LineEditor::new();
+    let words = WordList::new();
+    type_bytes(&mut editor, &words, b"abcdef\rgh\r");
+    let mut buffer = [0; 4];
+    assert_eq!(editor.read(&mut buffer), 4);
+    assert_eq!(&buffer, b"abcd");
+    assert_eq!(editor.read(&mut buffer), 3);
+    assert_eq!(&buffer[..3], b"ef\n");
+    assert_eq!(read_line(&mut editor), b"gh\n");
+  }
+}
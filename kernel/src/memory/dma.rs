@@ -0,0 +1,106 @@
+//! The ISA DMA controllers used by the floppy drive and Sound Blaster only
+//! drive 24 address lines, so they can only reach the first 16MiB of physical
+//! memory. Each transfer is also confined to a single 64KiB page: the
+//! controller increments the low 16 bits of the address, and wraps around
+//! instead of carrying into the page register.
+//!
+//! Buffers are taken from the first 4MiB of physical memory, which the kernel
+//! keeps mapped at 0xc0000000 in every address space. That satisfies the 16MiB
+//! limit, and means a buffer can be used from any process without creating a
+//! new mapping for it.
+
+use super::address::{PhysicalAddress, VirtualAddress};
+use super::physical::{self, frame_bitmap::BitmapError, frame_range::FrameRange};
+
+/// Highest physical address an ISA DMA controller can reach
+pub const ISA_DMA_LIMIT: usize = 0x1000000;
+/// A single transfer cannot cross a multiple of this size
+pub const ISA_DMA_PAGE_SIZE: usize = 0x10000;
+
+/// Physical memory from 0 up to this address is also mapped in the kernel's
+/// half of memory
+const LOW_MEMORY_END: usize = 0x400000;
+const LOW_MEMORY_WINDOW: usize = 0xc0000000;
+
+/// Physically contiguous memory that an ISA DMA controller can transfer to or
+/// from in one go
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DmaBuffer {
+  range: FrameRange,
+}
+
+impl DmaBuffer {
+  pub fn get_physical_address(&self) -> PhysicalAddress {
+    self.range.get_starting_address()
+  }
+
+  /// Where the kernel can read and write the buffer
+  pub fn get_virtual_address(&self) -> VirtualAddress {
+    VirtualAddress::new(LOW_MEMORY_WINDOW + self.range.get_starting_address().as_usize())
+  }
+
+  pub fn size_in_bytes(&self) -> usize {
+    self.range.size_in_bytes()
+  }
+}
+
+/// Alignment, in frames, that keeps a block of frames inside one DMA page.
+/// Aligning a block to its size rounded up to a power of two means it can
+/// only end where the next block of that size would begin, and every such
+/// size up to the page size divides the page size evenly.
+fn page_safe_alignment(frame_count: usize) -> usize {
+  frame_count.next_power_of_two()
+}
+
+/// Whether a transfer covering the byte range would cross a DMA page
+pub fn crosses_dma_page(start: usize, length: usize) -> bool {
+  length > 0 && start / ISA_DMA_PAGE_SIZE != (start + length - 1) / ISA_DMA_PAGE_SIZE
+}
+
+/// Allocate a buffer of at least `length` bytes that an ISA DMA controller can
+/// use: below 16MiB, and within a single 64KiB page. Buffers larger than a
+/// page cannot be used for a single transfer, and are rejected.
+pub fn alloc_dma_buffer(length: usize) -> Result<DmaBuffer, BitmapError> {
+  if length == 0 || length > ISA_DMA_PAGE_SIZE {
+    return Err(BitmapError::InvalidRequest);
+  }
+  let frame_count = (length + 0xfff) >> 12;
+  let limit = LOW_MEMORY_END.min(ISA_DMA_LIMIT);
+  let range = physical::allocate_contiguous_below(frame_count, page_safe_alignment(frame_count), limit)?;
+  kinvariant!(
+    !crosses_dma_page(range.get_starting_address().as_usize(), range.size_in_bytes()),
+    "DMA buffer crosses a 64KiB boundary: {:?}", range
+  );
+  Ok(DmaBuffer { range })
+}
+
+/// Return a buffer's frames to the allocator. The buffer must no longer be in
+/// use by any device.
+pub fn free_dma_buffer(buffer: DmaBuffer) -> Result<(), BitmapError> {
+  physical::free_range(buffer.range)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{crosses_dma_page, page_safe_alignment, ISA_DMA_PAGE_SIZE};
+
+  #[test]
+  fn detects_page_crossings() {
+    assert!(!crosses_dma_page(0x20000, 0x10000));
+    assert!(crosses_dma_page(0x2f000, 0x2000));
+    assert!(!crosses_dma_page(0x2f000, 0x1000));
+    assert!(!crosses_dma_page(0x2f000, 0));
+  }
+
+  #[test]
+  fn aligned_blocks_stay_in_one_page() {
+    for frame_count in 1..=16 {
+      let alignment = page_safe_alignment(frame_count);
+      let mut start = 0;
+      while start < ISA_DMA_PAGE_SIZE * 4 {
+        assert!(!crosses_dma_page(start, frame_count << 12));
+        start += alignment << 12;
+      }
+    }
+  }
+}
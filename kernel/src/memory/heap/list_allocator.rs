@@ -148,10 +148,19 @@ impl ListAllocator {
     let new_free_space_ptr = new_free_space_addr as *mut AllocNode;
     let new_free_node = &mut *new_free_space_ptr;
     new_free_node.init(size - self.size);
-    self.get_last_free_node().set_next(new_free_space_addr);
+    if self.first_free == 0 {
+      // Every byte of the old heap was in use
+      self.first_free = new_free_space_addr;
+    } else {
+      self.get_last_free_node().set_next(new_free_space_addr);
+    }
     self.size = size;
     self.merge_free_areas();
-    crate::kprintln!("Extended heap, new size is {:x}, new space starts at {:x}", size, new_free_space_addr);
+    crate::klog!("Extended heap, new size is {:x}, new space starts at {:x}", size, new_free_space_addr);
+  }
+
+  pub fn get_size(&self) -> usize {
+    self.size
   }
 
   /// Return a reference to the last free node in the list
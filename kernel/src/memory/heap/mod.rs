@@ -33,11 +33,13 @@ unsafe impl GlobalAlloc for Allocator {
     let mut ptr = allocator.alloc(layout);
     if ptr.is_null() {
       // Attempt to extend the heap
-      let space_needed = layout.size();
+      let space_needed = layout.size() + layout.align();
       let new_size = expand_kernel_heap(space_needed);
-      allocator.expand_size(new_size);
-      // Try again with new free space
-      ptr = allocator.alloc(layout);
+      if new_size > allocator.get_size() {
+        allocator.expand_size(new_size);
+        // Try again with new free space
+        ptr = allocator.alloc(layout);
+      }
     }
     ptr
   }
@@ -50,6 +52,13 @@ unsafe impl GlobalAlloc for Allocator {
 
 pub const INITIAL_HEAP_SIZE: usize = 64;
 
+/// The heap starts here, and can grow until it is MAX_HEAP_SIZE bytes long.
+/// Its page tables are shared by every page directory, so pages mapped as it
+/// grows are visible to all processes at once.
+pub const HEAP_START: usize = 0xc0400000;
+pub const MAX_HEAP_SIZE: usize = 0x4000000;
+const MAX_HEAP_PAGES: usize = MAX_HEAP_SIZE / 0x1000;
+
 #[global_allocator]
 static ALLOCATOR: Allocator = Allocator::new();
 
@@ -58,12 +67,31 @@ pub fn init_allocator(location: VirtualAddress, size: usize) {
 }
 
 pub fn map_allocator(location: VirtualAddress, initial_frame_count: usize) {
-  for i in 0..initial_frame_count {
-    let heap_frame = physical::allocate_frame().unwrap();
-    let heap_vaddr = VirtualAddress::new(0xc0400000 + i * 0x1000);
-    let current_mapping = CurrentPageDirectory::get();
+  let mapped = map_heap_pages(location, initial_frame_count);
+  if mapped < initial_frame_count {
+    panic!("Not enough memory for the initial kernel heap");
+  }
+}
+
+/// Allocate frames for `count` heap pages starting at `start`, and map them
+/// into the shared kernel page tables. Returns how many pages were mapped, which
+/// falls short if memory runs out or the heap reaches its maximum size.
+pub fn map_heap_pages(start: VirtualAddress, count: usize) -> usize {
+  let first_page = (start.as_usize() - HEAP_START) / 0x1000;
+  let current_mapping = CurrentPageDirectory::get();
+  for i in 0..count {
+    let page = first_page + i;
+    if page >= MAX_HEAP_PAGES {
+      return i;
+    }
+    let heap_frame = match physical::allocate_frame() {
+      Ok(frame) => frame,
+      Err(_) => return i,
+    };
+    let heap_vaddr = VirtualAddress::new(HEAP_START + page * 0x1000);
     current_mapping.map(heap_frame, heap_vaddr, PermissionFlags::empty());
   }
+  count
 }
 
 #[cfg(not(test))]
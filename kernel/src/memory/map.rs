@@ -3,7 +3,20 @@ use core::fmt;
 pub const REGION_TYPE_FREE: u32 = 1;
 pub const REGION_TYPE_RESERVED: u32 = 2;
 pub const REGION_TYPE_ACPI_RECOVERABLE: u32 = 3;
+pub const REGION_TYPE_ACPI_NVS: u32 = 4;
+pub const REGION_TYPE_BAD: u32 = 5;
 
+/// Frames are only tracked below this address. The kernel cannot reach memory
+/// above 4GiB, and stopping a page short keeps every length in range of a
+/// 32-bit usize.
+const ADDRESS_LIMIT: u64 = 0xfffff000;
+
+/**
+ * Structure for handling the data generated by the BIOS memory mapping call
+ * (INT 0x15, EAX = 0xE820). The bootloader collects the entries into a table
+ * and passes its address and length to the kernel in the BootStruct. Entries
+ * are not sorted, may overlap, and leave holes for memory that does not exist.
+ */
 #[repr(C, packed)]
 pub struct MapEntry {
   pub base: u64,
@@ -12,10 +25,43 @@ pub struct MapEntry {
   pub acpi: u32,
 }
 
-pub unsafe fn load_entries_at_address(addr: usize) -> &'static [MapEntry] {
-  let length = addr as *const usize;
-  let first_entry = (addr as *mut u32).offset(1) as *mut MapEntry;
-  core::slice::from_raw_parts_mut(first_entry, *length)
+impl MapEntry {
+  pub fn is_usable(&self) -> bool {
+    self.region_type == REGION_TYPE_FREE && self.length > 0
+  }
+
+  /// The frames an entry covers below the address limit, as a page-aligned
+  /// start address and length. Usable memory only provides the frames it
+  /// covers completely, while any other type claims every frame it touches.
+  pub fn frame_span(&self) -> Option<(usize, usize)> {
+    let base = self.base;
+    let end = base.saturating_add(self.length).min(ADDRESS_LIMIT);
+    let (start, end) = if self.is_usable() {
+      ((base + 0xfff) & !0xfff, end & !0xfff)
+    } else {
+      (base & !0xfff, ((end + 0xfff) & !0xfff).min(ADDRESS_LIMIT))
+    };
+    if start >= end {
+      return None;
+    }
+    Some((start as usize, (end - start) as usize))
+  }
+}
+
+/// Read the table the bootloader left in memory
+pub unsafe fn load_entries(address: usize, count: usize) -> &'static [MapEntry] {
+  core::slice::from_raw_parts(address as *const MapEntry, count)
+}
+
+/// The end of the highest usable frame in the map. Memory is not assumed to be
+/// contiguous, so this is only an upper bound on how much of it exists.
+pub fn usable_limit(map: &[MapEntry]) -> usize {
+  map.iter()
+    .filter(|entry| entry.is_usable())
+    .filter_map(|entry| entry.frame_span())
+    .map(|(start, length)| start + length)
+    .max()
+    .unwrap_or(0)
 }
 
 impl fmt::Debug for MapEntry {
@@ -24,6 +70,8 @@ impl fmt::Debug for MapEntry {
       REGION_TYPE_FREE => "Free",
       REGION_TYPE_RESERVED => "Reserved",
       REGION_TYPE_ACPI_RECOVERABLE => "ACPI",
+      REGION_TYPE_ACPI_NVS => "ACPI NVS",
+      REGION_TYPE_BAD => "Bad",
       _ => "Unknown",
     };
     let start = self.base;
@@ -31,3 +79,37 @@ impl fmt::Debug for MapEntry {
     write!(f, "{:#010x}-{:#010x}: {}", start, end, type_string)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{MapEntry, REGION_TYPE_FREE, REGION_TYPE_RESERVED, usable_limit};
+
+  fn entry(base: u64, length: u64, region_type: u32) -> MapEntry {
+    MapEntry { base, length, region_type, acpi: 1 }
+  }
+
+  #[test]
+  fn usable_spans_shrink_to_whole_frames() {
+    assert_eq!(entry(0, 0x9fc00, REGION_TYPE_FREE).frame_span(), Some((0, 0x9f000)));
+    assert_eq!(entry(0x100800, 0x1800, REGION_TYPE_FREE).frame_span(), Some((0x101000, 0x1000)));
+    assert_eq!(entry(0x100800, 0x800, REGION_TYPE_FREE).frame_span(), None);
+  }
+
+  #[test]
+  fn reserved_spans_grow_to_whole_frames() {
+    assert_eq!(entry(0x9fc00, 0x400, REGION_TYPE_RESERVED).frame_span(), Some((0x9f000, 0x1000)));
+    assert_eq!(entry(0xfffc0000, 0x40000, REGION_TYPE_RESERVED).frame_span(), Some((0xfffc0000, 0x3f000)));
+  }
+
+  #[test]
+  fn ignores_memory_above_4gib() {
+    assert_eq!(entry(0x1_0000_0000, 0x1000_0000, REGION_TYPE_FREE).frame_span(), None);
+    let map = [
+      entry(0, 0x9fc00, REGION_TYPE_FREE),
+      entry(0x100000, 0x1ff00000, REGION_TYPE_FREE),
+      entry(0xfffc0000, 0x40000, REGION_TYPE_RESERVED),
+      entry(0x1_0000_0000, 0x1000_0000, REGION_TYPE_FREE),
+    ];
+    assert_eq!(usable_limit(&map), 0x20000000);
+  }
+}
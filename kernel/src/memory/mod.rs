@@ -1,6 +1,8 @@
 pub mod address;
+pub mod dma;
 pub mod map;
 pub mod physical;
+pub mod pressure;
 pub mod virt;
 
 // not test-safe
@@ -1,9 +1,16 @@
 use core::slice;
-use super::bios;
+use super::super::map::MapEntry;
 use super::frame_range::FrameRange;
 
 pub struct FrameBitmap {
   frame_count: usize,
+  /// Frames the memory map reported as usable. The bitmap spans every frame up
+  /// to the highest usable one, so holes and reserved areas are counted in
+  /// frame_count but not here.
+  usable_frames: usize,
+  /// Kept up to date as frames are allocated and freed, so that checking how
+  /// much memory is left does not mean scanning the whole bitmap
+  free_frames: usize,
   map: &'static mut [u8],
 }
 
@@ -14,10 +21,14 @@ impl FrameBitmap {
       byte_size += 1;
     }
     let data = start as *mut u8;
-    FrameBitmap {
+    let mut bitmap = FrameBitmap {
       frame_count,
+      usable_frames: frame_count,
+      free_frames: 0,
       map: unsafe { slice::from_raw_parts_mut(data, byte_size) },
-    }
+    };
+    bitmap.free_frames = bitmap.count_free_frames();
+    bitmap
   }
 
   pub fn move_to_highmem(&mut self) {
@@ -49,30 +60,46 @@ impl FrameBitmap {
       self.map[byte_index] = 0xff;
       frame += 8;
     }
+    self.free_frames = 0;
   }
 
   /**
    * Given a BIOS-generated memory map, iterate through that map and de-allocate
    * all known free ranges. If the process succeeds, the bitmap will accurately
    * reflect all memory areas available for allocation.
+   * Entries may overlap, so anything the map marks as reserved, ACPI, or bad
+   * is claimed again after the free ranges are released. The first frame,
+   * which holds the real-mode interrupt table, is never handed out.
    */
-  pub fn initialize_from_memory_map(&mut self, map: &[bios::MapEntry]) -> Result<(), BitmapError> {
+  pub fn initialize_from_memory_map(&mut self, map: &[MapEntry]) -> Result<(), BitmapError> {
     self.reset();
-    for entry in map.iter() {
-      if entry.region_type == bios::REGION_TYPE_FREE {
-        let range = FrameRange::new(
-          (entry.base & 0xffffffff) as usize,
-          (entry.length & 0xffffffff) as usize,
-        );
-        match self.free_range(range) {
-          Err(e) => return Err(e),
-          _ => (),
-        }
+    for entry in map.iter().filter(|entry| entry.is_usable()) {
+      if let Some(range) = self.clip_to_bitmap(entry.frame_span()) {
+        self.free_range(range)?;
+      }
+    }
+    for entry in map.iter().filter(|entry| !entry.is_usable()) {
+      if let Some(range) = self.clip_to_bitmap(entry.frame_span()) {
+        self.allocate_range(range)?;
       }
     }
+    if self.frame_count > 0 {
+      self.allocate_range(FrameRange::new(0, 0x1000))?;
+    }
+    self.usable_frames = self.get_free_frame_count();
     Ok(())
   }
 
+  /// Trim a span from the memory map to the frames this bitmap tracks
+  fn clip_to_bitmap(&self, span: Option<(usize, usize)>) -> Option<FrameRange> {
+    let (start, length) = span?;
+    let limit = self.frame_count << 12;
+    if start >= limit {
+      return None;
+    }
+    Some(FrameRange::new(start, length.min(limit - start)))
+  }
+
   /**
    * How big is this table, in 4096-byte frames? Useful for allocating itself.
    */
@@ -112,26 +139,66 @@ impl FrameBitmap {
     true
   }
 
+  /**
+   * Check that every frame in a range is marked as in use. Freeing a range
+   * that fails this check indicates a double free.
+   */
+  pub fn is_range_allocated(&self, range: FrameRange) -> bool {
+    if !self.contains_range(range) {
+      return false;
+    }
+    let first = range.get_first_frame_index();
+    let last = range.get_last_frame_index();
+    for frame in first..=last {
+      let byte_index = frame >> 3;
+      if self.map[byte_index] & (1 << (frame & 7)) == 0 {
+        return false;
+      }
+    }
+    true
+  }
+
   pub fn find_free_range(&self, frame_count: usize) -> Option<FrameRange> {
-    let mut frame = 0;
-    let mut remaining = frame_count;
+    self.find_free_aligned_range(frame_count, 1)
+  }
+
+  /**
+   * Find a run of free frames whose first frame index is a multiple of
+   * `alignment`, which must be a power of two. When a frame in use interrupts
+   * a run, the search resumes at the next aligned frame after it, and bytes of
+   * the bitmap that are entirely in use are skipped at once.
+   */
+  pub fn find_free_aligned_range(&self, frame_count: usize, alignment: usize) -> Option<FrameRange> {
+    self.find_free_range_below(frame_count, alignment, self.frame_count)
+  }
+
+  /**
+   * Search for an aligned run of free frames like find_free_aligned_range,
+   * but only among the frames below the index `limit`. Used for devices that
+   * cannot reach all of memory.
+   */
+  pub fn find_free_range_below(&self, frame_count: usize, alignment: usize, limit: usize) -> Option<FrameRange> {
+    if frame_count == 0 || !alignment.is_power_of_two() {
+      return None;
+    }
+    let limit = limit.min(self.frame_count);
+    let align_up = |frame: usize| (frame + alignment - 1) & !(alignment - 1);
     let mut search_start = 0;
-    while frame < self.frame_count {
+    let mut frame = 0;
+    while search_start + frame_count <= limit {
+      if frame == search_start + frame_count {
+        return Some(FrameRange::new(search_start << 12, frame_count << 12));
+      }
       let byte_index = frame >> 3;
-      let frame_mask = 1 << (frame & 7);
-      if self.map[byte_index] & frame_mask != 0 {
-        // occupied, start the search over
-        remaining = frame_count;
-        search_start = frame + 1;
+      if frame & 7 == 0 && self.map[byte_index] == 0xff {
+        search_start = align_up(frame + 8);
+        frame = search_start;
+      } else if self.map[byte_index] & (1 << (frame & 7)) != 0 {
+        search_start = align_up(frame + 1);
+        frame = search_start;
       } else {
-        remaining -= 1;
-        if remaining == 0 {
-          let starting_address = search_start << 12;
-          let length = (frame + 1 - search_start) << 12;
-          return Some(FrameRange::new(starting_address, length));
-        }
+        frame += 1;
       }
-      frame += 1;
     }
     None
   }
@@ -148,6 +215,9 @@ impl FrameBitmap {
     let last = range.get_last_frame_index();
     for frame in first..=last {
       let byte_index = frame >> 3;
+      if self.map[byte_index] & (1 << (frame & 7)) == 0 {
+        self.free_frames -= 1;
+      }
       self.map[byte_index] |= 1 << (frame & 7);
     }
     Ok(())
@@ -160,10 +230,32 @@ impl FrameBitmap {
    * one frame at a time.
    */
   pub fn allocate_frames(&mut self, frame_count: usize) -> Result<FrameRange, BitmapError> {
-    let range = match self.find_free_range(frame_count) {
+    self.allocate_contiguous(frame_count, 1)
+  }
+
+  /**
+   * Allocate a physically contiguous block of frames starting on a multiple of
+   * `alignment` frames. Devices that perform DMA often cannot cross certain
+   * boundaries, and aligning a buffer to its own size guarantees it doesn't.
+   * The block can be returned all at once with free_range, or piece by piece.
+   */
+  pub fn allocate_contiguous(&mut self, frame_count: usize, alignment: usize) -> Result<FrameRange, BitmapError> {
+    self.allocate_contiguous_below(frame_count, alignment, self.frame_count)
+  }
+
+  /**
+   * Allocate an aligned, contiguous block like allocate_contiguous, entirely
+   * below the frame index `limit`
+   */
+  pub fn allocate_contiguous_below(&mut self, frame_count: usize, alignment: usize, limit: usize) -> Result<FrameRange, BitmapError> {
+    if frame_count == 0 || !alignment.is_power_of_two() {
+      return Err(BitmapError::InvalidRequest);
+    }
+    let range = match self.find_free_range_below(frame_count, alignment, limit) {
       Some(r) => r,
       None => return Err(BitmapError::NoAvailableSpace),
     };
+    kinvariant!(self.is_range_free(range), "Search returned frames in use: {:?}", range);
     match self.allocate_range(range) {
       Ok(()) => Ok(range),
       Err(e) => Err(e)
@@ -182,6 +274,9 @@ impl FrameBitmap {
     let last = range.get_last_frame_index();
     for frame in first..=last {
       let byte_index = frame >> 3;
+      if self.map[byte_index] & (1 << (frame & 7)) != 0 {
+        self.free_frames += 1;
+      }
       self.map[byte_index] &= !(1 << (frame & 7));
     }
     Ok(())
@@ -191,11 +286,18 @@ impl FrameBitmap {
     self.frame_count
   }
 
-  /**
-   * Compute the number of unallocated frames. Basically, tells you how much
-   * memory is available.
-   */
+  pub fn get_usable_frame_count(&self) -> usize {
+    self.usable_frames
+  }
+
+  /// The number of unallocated frames. Basically, tells you how much memory is
+  /// available.
   pub fn get_free_frame_count(&self) -> usize {
+    self.free_frames
+  }
+
+  /// Count the unallocated frames by scanning the bitmap
+  fn count_free_frames(&self) -> usize {
     let mut frame = 0;
     let mut free = 0;
     while frame < self.frame_count {
@@ -223,6 +325,8 @@ impl FrameBitmap {
 pub enum BitmapError {
   NoAvailableSpace,
   OutOfBounds,
+  /// A block of zero frames, or an alignment that is not a power of two
+  InvalidRequest,
 }
 
 impl core::fmt::Debug for BitmapError {
@@ -230,6 +334,7 @@ impl core::fmt::Debug for BitmapError {
     match self {
       BitmapError::NoAvailableSpace => f.write_str("FrameBitmap: No available space"),
       BitmapError::OutOfBounds => f.write_str("FrameBitmap: Out of bounds"),
+      BitmapError::InvalidRequest => f.write_str("FrameBitmap: Invalid request"),
     }
   }
 }
@@ -237,6 +342,7 @@ impl core::fmt::Debug for BitmapError {
 #[cfg(test)]
 mod tests {
   use super::{BitmapError, FrameBitmap, FrameRange};
+  use crate::memory::map::{MapEntry, REGION_TYPE_ACPI_NVS, REGION_TYPE_FREE, REGION_TYPE_RESERVED};
 
   #[test]
   fn bitmap_creation() {
@@ -289,6 +395,48 @@ mod tests {
     assert_eq!(bitmap.find_free_range(4), Some(FrameRange::new(0x12000, 0x4000)));
   }
 
+  #[test]
+  fn find_aligned_range() {
+    let memory: [u8; 8] = [0; 8];
+    let mut bitmap = FrameBitmap::at_location(&memory[0] as *const u8 as usize, 60);
+    bitmap.allocate_range(FrameRange::new(0x1000, 0x1000)).unwrap();
+    assert_eq!(bitmap.find_free_aligned_range(4, 4), Some(FrameRange::new(0x4000, 0x4000)));
+    assert_eq!(bitmap.find_free_aligned_range(2, 16), Some(FrameRange::new(0x10000, 0x2000)));
+    // Full bytes of the bitmap are skipped without losing alignment
+    bitmap.allocate_range(FrameRange::new(0x8000, 0x10000)).unwrap();
+    assert_eq!(bitmap.find_free_aligned_range(4, 4), Some(FrameRange::new(0x4000, 0x4000)));
+    assert_eq!(bitmap.find_free_aligned_range(8, 8), Some(FrameRange::new(0x18000, 0x8000)));
+    assert_eq!(bitmap.find_free_aligned_range(8, 64), None);
+    assert_eq!(bitmap.find_free_aligned_range(4, 3), None);
+    assert_eq!(bitmap.find_free_aligned_range(0, 1), None);
+  }
+
+  #[test]
+  fn allocate_contiguous_blocks() {
+    let memory: [u8; 8] = [0; 8];
+    let mut bitmap = FrameBitmap::at_location(&memory[0] as *const u8 as usize, 64);
+    bitmap.allocate_frames(1).unwrap();
+    let block = bitmap.allocate_contiguous(16, 16).unwrap();
+    assert_eq!(block, FrameRange::new(0x10000, 0x10000));
+    let next = bitmap.allocate_contiguous(3, 4).unwrap();
+    assert_eq!(next, FrameRange::new(0x4000, 0x3000));
+    assert_eq!(bitmap.get_free_frame_count(), 64 - 20);
+    bitmap.free_range(block).unwrap();
+    assert_eq!(bitmap.get_free_frame_count(), 64 - 4);
+    assert_eq!(bitmap.allocate_contiguous(64, 1), Err(BitmapError::NoAvailableSpace));
+    assert_eq!(bitmap.allocate_contiguous(2, 6), Err(BitmapError::InvalidRequest));
+  }
+
+  #[test]
+  fn allocate_below_limit() {
+    let memory: [u8; 8] = [0; 8];
+    let mut bitmap = FrameBitmap::at_location(&memory[0] as *const u8 as usize, 64);
+    bitmap.allocate_range(FrameRange::new(0, 0x10000)).unwrap();
+    assert_eq!(bitmap.allocate_contiguous_below(16, 16, 32), Ok(FrameRange::new(0x10000, 0x10000)));
+    assert_eq!(bitmap.allocate_contiguous_below(1, 1, 32), Err(BitmapError::NoAvailableSpace));
+    assert_eq!(bitmap.find_free_range_below(1, 1, 1000), Some(FrameRange::new(0x20000, 0x1000)));
+  }
+
   #[test]
   fn free_frame_count() {
     let memory: [u8; 8] = [0; 8];
@@ -304,5 +452,41 @@ mod tests {
     assert_eq!(bitmap.get_free_frame_count(), 43);
     bitmap.free_range(range).unwrap();
     assert_eq!(bitmap.get_free_frame_count(), 53);
+    // Frames already in the requested state are not counted twice
+    bitmap.allocate_range(FrameRange::new(0, 0x4000)).unwrap();
+    assert_eq!(bitmap.get_free_frame_count(), 51);
+    bitmap.free_range(FrameRange::new(0x20000, 0x2000)).unwrap();
+    assert_eq!(bitmap.get_free_frame_count(), 51);
+    assert_eq!(bitmap.get_free_frame_count(), bitmap.count_free_frames());
+  }
+
+  #[test]
+  fn memory_map_with_holes() {
+    let memory: [u8; 8] = [0; 8];
+    let mut bitmap = FrameBitmap::at_location(&memory[0] as *const u8 as usize, 64);
+    let map = [
+      // Unaligned end loses its partial frame
+      MapEntry { base: 0, length: 0x9c00, region_type: REGION_TYPE_FREE, acpi: 1 },
+      MapEntry { base: 0x10000, length: 0x30000, region_type: REGION_TYPE_FREE, acpi: 1 },
+      // Overlaps the free range above, and wins
+      MapEntry { base: 0x20800, length: 0x1000, region_type: REGION_TYPE_RESERVED, acpi: 1 },
+      MapEntry { base: 0x3c000, length: 0x1000, region_type: REGION_TYPE_ACPI_NVS, acpi: 1 },
+      // Beyond the end of the bitmap
+      MapEntry { base: 0x80000, length: 0x10000, region_type: REGION_TYPE_RESERVED, acpi: 1 },
+    ];
+    bitmap.initialize_from_memory_map(&map).unwrap();
+    // Frame zero is always reserved
+    assert!(!bitmap.is_range_free(FrameRange::new(0, 0x1000)));
+    assert!(bitmap.is_range_free(FrameRange::new(0x1000, 0x8000)));
+    assert!(!bitmap.is_range_free(FrameRange::new(0x9000, 0x1000)));
+    // The hole between the two free entries
+    assert!(!bitmap.is_range_free(FrameRange::new(0xa000, 0x1000)));
+    assert!(!bitmap.is_range_free(FrameRange::new(0xf000, 0x1000)));
+    assert!(bitmap.is_range_free(FrameRange::new(0x10000, 0x10000)));
+    assert!(!bitmap.is_range_free(FrameRange::new(0x20000, 0x1000)));
+    assert!(!bitmap.is_range_free(FrameRange::new(0x21000, 0x1000)));
+    assert!(bitmap.is_range_free(FrameRange::new(0x22000, 0x1a000)));
+    assert!(!bitmap.is_range_free(FrameRange::new(0x3c000, 0x1000)));
+    assert_eq!(bitmap.get_usable_frame_count(), 8 + 16 + 26 + 3);
   }
 }
@@ -34,7 +34,7 @@ impl FrameRefcount {
    * returning the new total.
    */
   pub fn reference_frame_at_index(&mut self, index: usize) -> u8 {
-    // Do some amount of checking if the references exceed 255
+    kassert!(self.references[index] < 255, "Too many references to frame {}", index);
     let new_count = self.references[index] + 1;
     self.references[index] = new_count;
     new_count
@@ -1,4 +1,3 @@
-pub mod bios;
 pub mod frame_bitmap;
 pub mod frame_range;
 pub mod frame_refcount;
@@ -9,24 +8,21 @@ use frame_range::FrameRange;
 use frame_refcount::FrameRefcount;
 use spin::Mutex;
 use super::address::PhysicalAddress;
+use super::map::{self, MapEntry};
 
 static mut ALLOCATOR: Option<Mutex<FrameBitmap>> = None;
 static mut REF_COUNT: Option<Mutex<FrameRefcount>> = None;
 
-pub fn init_allocator(location: usize, memory_map_addr: usize) {
+/// Build the frame allocator from the BIOS memory map, placing its bitmap at
+/// `location`. Only frames the map reports as usable can be allocated; holes
+/// and reserved areas below the highest usable frame stay marked as in use.
+pub fn init_allocator(location: usize, memory_map: &[MapEntry]) {
   assert!(location & 0xfff == 0, "Allocator must start on a page boundary");
-  let mut limit = 0;
-  let memory_map = unsafe { bios::load_entries_at_address(memory_map_addr) };
-  // memory map is not guaranteed to be in order
-  for entry in memory_map.iter() {
-    let end = (entry.base + entry.length) as usize;
-    if end > limit {
-      limit = end;
-    }
-  }
+  let limit = map::usable_limit(memory_map);
+  assert!(limit > 0, "Memory map has no usable memory");
 
   let mut bitmap = FrameBitmap::at_location(location, limit >> 12);
-  bitmap.initialize_from_memory_map(&memory_map).unwrap();
+  bitmap.initialize_from_memory_map(memory_map).unwrap();
 
   let size_in_frames = bitmap.size_in_frames();
   let own_range = FrameRange::new(location, size_in_frames * 0x1000);
@@ -76,10 +72,41 @@ pub fn with_refcount<F, T>(f: F) -> T where
   }
 }
 
+/// Recalculate memory pressure once the bitmap has changed
+fn report_pressure() {
+  let (free, usable) = with_allocator(|alloc| {
+    (alloc.get_free_frame_count(), alloc.get_usable_frame_count())
+  });
+  super::pressure::update(free, usable);
+}
+
 pub fn allocate_frames(count: usize) -> Result<FrameRange, BitmapError> {
-  with_allocator(|alloc| {
+  let range = with_allocator(|alloc| {
     alloc.allocate_frames(count)
-  })
+  })?;
+  report_pressure();
+  Ok(range)
+}
+
+/// Allocate physically contiguous frames starting on a multiple of
+/// `alignment` frames, for buffers a device reads or writes directly. Release
+/// them with free_range when done.
+pub fn allocate_contiguous(count: usize, alignment: usize) -> Result<FrameRange, BitmapError> {
+  let range = with_allocator(|alloc| {
+    alloc.allocate_contiguous(count, alignment)
+  })?;
+  report_pressure();
+  Ok(range)
+}
+
+/// Allocate contiguous, aligned frames like allocate_contiguous, entirely
+/// below the physical address `limit`
+pub fn allocate_contiguous_below(count: usize, alignment: usize, limit: usize) -> Result<FrameRange, BitmapError> {
+  let range = with_allocator(|alloc| {
+    alloc.allocate_contiguous_below(count, alignment, limit >> 12)
+  })?;
+  report_pressure();
+  Ok(range)
 }
 
 pub fn allocate_frame() -> Result<frame::Frame, BitmapError> {
@@ -93,13 +120,18 @@ pub fn allocate_frame() -> Result<frame::Frame, BitmapError> {
 pub fn allocate_range(range: FrameRange) -> Result<(), BitmapError> {
   with_allocator(|alloc| {
     alloc.allocate_range(range)
-  })
+  })?;
+  report_pressure();
+  Ok(())
 }
 
 pub fn free_range(range: FrameRange) -> Result<(), BitmapError> {
   with_allocator(|alloc| {
+    kassert!(alloc.is_range_allocated(range), "Freeing frames that were not allocated: {:?}", range);
     alloc.free_range(range)
-  })
+  })?;
+  report_pressure();
+  Ok(())
 }
 
 pub fn get_frame_count() -> usize {
@@ -108,6 +140,13 @@ pub fn get_frame_count() -> usize {
   })
 }
 
+/// Frames of memory that actually exist and are not reserved by the firmware
+pub fn get_usable_frame_count() -> usize {
+  with_allocator(|alloc| {
+    alloc.get_usable_frame_count()
+  })
+}
+
 pub fn get_free_frame_count() -> usize {
   with_allocator(|alloc| {
     alloc.get_free_frame_count()
@@ -0,0 +1,192 @@
+//! Memory pressure tells userland when physical memory is running low, so
+//! programs holding caches they can rebuild have a chance to give memory back
+//! before allocations start failing. Programs subscribe by opening
+//! DEV:\MEMEV, and each read returns the level changes seen since the last
+//! read.
+//!
+//! The level is recalculated every time frames are allocated or freed, which
+//! can happen inside a page fault. Publishing from there must never wait on a
+//! lock or grow the heap, so events are only published when the queue is free,
+//! into space reserved when each listener subscribed. A change that could not
+//! be published right away is caught up on the next read.
+
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use crate::collections::{Event, EventQueue};
+use crate::files::handle::LocalHandle;
+use spin::Mutex;
+use syscall::memory::{MemoryEvent, MemoryPressure, MEMORY_EVENT_SIZE};
+
+/// Pressure is low once less than an eighth of usable memory is free
+const LOW_DIVISOR: usize = 8;
+/// Pressure is critical once less than a thirty-second is free
+const CRITICAL_DIVISOR: usize = 32;
+/// Once a level is reached, an extra sixty-fourth of memory must be freed to
+/// leave it, so allocating and freeing a single frame at the threshold does
+/// not send a stream of events
+const HYSTERESIS_DIVISOR: usize = 64;
+
+/// Each listener keeps at most this many unread events. If a listener falls
+/// behind, the oldest events are dropped.
+pub const MAX_PENDING_EVENTS: usize = 16;
+
+/// Decide the pressure level for an amount of free memory, given the level it
+/// was at before
+pub fn level_for(free_frames: usize, usable_frames: usize, previous: MemoryPressure) -> MemoryPressure {
+  let margin = usable_frames / HYSTERESIS_DIVISOR;
+  let is_below = |level: MemoryPressure, divisor: usize| {
+    let threshold = usable_frames / divisor;
+    if previous >= level {
+      free_frames < threshold + margin
+    } else {
+      free_frames < threshold
+    }
+  };
+  if is_below(MemoryPressure::Critical, CRITICAL_DIVISOR) {
+    MemoryPressure::Critical
+  } else if is_below(MemoryPressure::Low, LOW_DIVISOR) {
+    MemoryPressure::Low
+  } else {
+    MemoryPressure::Normal
+  }
+}
+
+impl Event for MemoryEvent {
+  const SIZE: usize = MEMORY_EVENT_SIZE;
+  const MAX_PENDING: usize = MAX_PENDING_EVENTS;
+
+  fn write_bytes(&self, buffer: &mut [u8]) {
+    buffer.copy_from_slice(&self.to_bytes());
+  }
+}
+
+/**
+ * Tracks every handle subscribed to memory pressure changes. Each listener
+ * receives its own copy of every change published after it subscribed.
+ */
+pub struct MemoryEventQueue {
+  /// The level most recently published
+  level: MemoryPressure,
+  events: EventQueue<MemoryEvent>,
+}
+
+impl MemoryEventQueue {
+  pub const fn new() -> MemoryEventQueue {
+    MemoryEventQueue {
+      level: MemoryPressure::Normal,
+      events: EventQueue::new(),
+    }
+  }
+
+  /// Subscribe a handle. Its first read reports the current level, so a new
+  /// listener does not have to wait for a change to learn where things stand.
+  pub fn listen(&mut self, handle: LocalHandle, current: MemoryEvent) {
+    self.catch_up(current);
+    self.events.listen(handle);
+    self.events.publish_to(handle, current);
+  }
+
+  pub fn stop_listening(&mut self, handle: LocalHandle) {
+    self.events.stop_listening(handle);
+  }
+
+  /// Publish the current state if its level has not been published yet
+  pub fn catch_up(&mut self, current: MemoryEvent) {
+    if current.level == self.level {
+      return;
+    }
+    self.level = current.level;
+    self.events.publish(current);
+  }
+
+  pub fn has_pending(&self, handle: LocalHandle) -> bool {
+    self.events.has_pending(handle)
+  }
+
+  /// Copy as many whole events as fit into the buffer, returning the number of
+  /// bytes written
+  pub fn read(&mut self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    self.events.read(handle, buffer)
+  }
+}
+
+pub static MEMORY_EVENTS: Mutex<MemoryEventQueue> = Mutex::new(MemoryEventQueue::new());
+
+static LEVEL: AtomicU8 = AtomicU8::new(MemoryPressure::Normal as u8);
+static FREE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn get_level() -> MemoryPressure {
+  MemoryPressure::from_u8(LEVEL.load(Ordering::SeqCst)).unwrap_or(MemoryPressure::Normal)
+}
+
+/// The level and free memory as of the last allocation or release
+pub fn get_current_event() -> MemoryEvent {
+  MemoryEvent::new(get_level(), FREE_FRAMES.load(Ordering::SeqCst) as u32)
+}
+
+/// Recalculate the level after frames were allocated or freed
+pub fn update(free_frames: usize, usable_frames: usize) {
+  FREE_FRAMES.store(free_frames, Ordering::SeqCst);
+  let previous = get_level();
+  let level = level_for(free_frames, usable_frames, previous);
+  if level == previous {
+    return;
+  }
+  LEVEL.store(level as u8, Ordering::SeqCst);
+  if let Some(mut events) = MEMORY_EVENTS.try_lock() {
+    events.catch_up(MemoryEvent::new(level, free_frames as u32));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::files::handle::{Handle, LocalHandle};
+  use syscall::memory::{MemoryEvent, MemoryPressure, MEMORY_EVENT_SIZE};
+  use super::{level_for, MemoryEventQueue, MAX_PENDING_EVENTS};
+
+  #[test]
+  fn levels_follow_thresholds() {
+    assert_eq!(level_for(1000, 1024, MemoryPressure::Normal), MemoryPressure::Normal);
+    assert_eq!(level_for(127, 1024, MemoryPressure::Normal), MemoryPressure::Low);
+    assert_eq!(level_for(31, 1024, MemoryPressure::Normal), MemoryPressure::Critical);
+    assert_eq!(level_for(31, 1024, MemoryPressure::Low), MemoryPressure::Critical);
+  }
+
+  #[test]
+  fn levels_are_left_past_a_margin() {
+    // Leaving low pressure takes 16 frames beyond the threshold of 128
+    assert_eq!(level_for(130, 1024, MemoryPressure::Low), MemoryPressure::Low);
+    assert_eq!(level_for(144, 1024, MemoryPressure::Low), MemoryPressure::Normal);
+    assert_eq!(level_for(40, 1024, MemoryPressure::Critical), MemoryPressure::Critical);
+    assert_eq!(level_for(60, 1024, MemoryPressure::Critical), MemoryPressure::Low);
+  }
+
+  #[test]
+  fn listeners_receive_changes() {
+    let mut queue = MemoryEventQueue::new();
+    let handle = LocalHandle::new(1);
+    queue.listen(handle, MemoryEvent::new(MemoryPressure::Normal, 500));
+    queue.catch_up(MemoryEvent::new(MemoryPressure::Normal, 400));
+    queue.catch_up(MemoryEvent::new(MemoryPressure::Low, 100));
+
+    let mut buffer = [0; MEMORY_EVENT_SIZE * 4];
+    assert_eq!(queue.read(handle, &mut buffer), Ok(MEMORY_EVENT_SIZE * 2));
+    assert_eq!(MemoryEvent::from_bytes(&buffer[0..]), Some(MemoryEvent::new(MemoryPressure::Normal, 500)));
+    assert_eq!(MemoryEvent::from_bytes(&buffer[MEMORY_EVENT_SIZE..]), Some(MemoryEvent::new(MemoryPressure::Low, 100)));
+    assert!(!queue.has_pending(handle));
+    assert_eq!(queue.read(LocalHandle::new(2), &mut buffer), Err(()));
+  }
+
+  #[test]
+  fn drops_oldest_when_full() {
+    let mut queue = MemoryEventQueue::new();
+    let handle = LocalHandle::new(1);
+    queue.listen(handle, MemoryEvent::new(MemoryPressure::Normal, 500));
+    for i in 0..MAX_PENDING_EVENTS {
+      let level = if i % 2 == 0 { MemoryPressure::Low } else { MemoryPressure::Normal };
+      queue.catch_up(MemoryEvent::new(level, i as u32));
+    }
+    let mut buffer = [0; MEMORY_EVENT_SIZE];
+    queue.read(handle, &mut buffer).unwrap();
+    assert_eq!(MemoryEvent::from_bytes(&buffer).unwrap().free_frames, 0);
+  }
+}
@@ -3,7 +3,7 @@ pub mod page_entry;
 pub mod page_table;
 pub mod region;
 
-use page_table::{PageTable, PageTableReference};
+use page_table::{PageTable, PageTableReference, SHARED_KERNEL_END_INDEX, SHARED_KERNEL_START_INDEX};
 use super::address::{PhysicalAddress, VirtualAddress};
 use super::physical;
 
@@ -61,6 +61,14 @@ pub fn map_kernel(directory_ref: PageTableReference, bounds: &KernelDataBounds)
   // Also, map it to highmem at 0xc0000000
   dir.get_mut(0x300).set_address(table_zero_frame.get_address());
   dir.get_mut(0x300).set_present();
+  // Every other shared kernel table is created empty now, so that page
+  // directories copied from this one all refer to the same tables
+  for index in (SHARED_KERNEL_START_INDEX + 1)..SHARED_KERNEL_END_INDEX {
+    let table_frame = physical::allocate_frame().unwrap();
+    unsafe { table_frame.zero_memory() };
+    dir.get_mut(index).set_address(table_frame.get_address());
+    dir.get_mut(index).set_present();
+  }
   // Finally, move the stack to the top of memory, just below the temp page
   let last_page_addr = dir.get(1022).get_address();
   let last_page = PageTable::at_address(VirtualAddress::new(last_page_addr.as_usize()));
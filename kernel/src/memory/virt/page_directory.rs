@@ -1,14 +1,26 @@
+use alloc::vec::Vec;
 use super::super::address::{PhysicalAddress, VirtualAddress};
 use super::super::physical::frame::Frame;
 use super::super::physical::allocate_frame;
 use super::super::physical::reference_frame_at_address;
-use super::page_table::PageTable;
+use super::page_entry::PageTableEntry;
+use super::page_table::{
+  PageTable,
+  SELF_REFERENCE_INDEX,
+  SHARED_KERNEL_END_INDEX,
+  SHARED_KERNEL_START_INDEX,
+  TABLE_ENTRY_COUNT,
+  TEMP_REFERENCE_INDEX,
+  is_shared_kernel_index,
+};
 use super::region::{MemoryRegionType, Permissions, VirtualMemoryRegion};
 
 pub struct PermissionFlags(u8);
 impl PermissionFlags {
   pub const USER_ACCESS: u8 = 1;
   pub const WRITE_ACCESS: u8 = 2;
+  /// For device registers, which must not be cached
+  pub const CACHE_DISABLE: u8 = 4;
 
   pub fn new(flags: u8) -> PermissionFlags {
     PermissionFlags(flags)
@@ -52,6 +64,41 @@ impl CurrentPageDirectory {
     invalidate_page(vaddr);
   }
 
+  /// Physical address that a virtual address is mapped to, if its page is
+  /// present
+  pub fn get_physical_address(&self, vaddr: VirtualAddress) -> Option<PhysicalAddress> {
+    let dir_index = vaddr.get_page_directory_index();
+    let table_index = vaddr.get_page_table_index();
+    let directory = PageTable::at_address(VirtualAddress::new(0xfffff000));
+    if !directory.get(dir_index).is_present() {
+      return None;
+    }
+    let table = PageTable::at_address(VirtualAddress::new(
+      0xffc00000 + 0x1000 * dir_index,
+    ));
+    let entry = table.get(table_index);
+    if !entry.is_present() {
+      return None;
+    }
+    Some(PhysicalAddress::new(entry.get_address().as_usize() | (vaddr.as_usize() & 0xfff)))
+  }
+
+  /// Whether a virtual address is mapped to a present page that the page
+  /// table marks writable
+  pub fn is_write_access_granted(&self, vaddr: VirtualAddress) -> bool {
+    let dir_index = vaddr.get_page_directory_index();
+    let table_index = vaddr.get_page_table_index();
+    let directory = PageTable::at_address(VirtualAddress::new(0xfffff000));
+    if !directory.get(dir_index).is_present() {
+      return false;
+    }
+    let table = PageTable::at_address(VirtualAddress::new(
+      0xffc00000 + 0x1000 * dir_index,
+    ));
+    let entry = table.get(table_index);
+    entry.is_present() && entry.is_write_access_granted()
+  }
+
   pub fn unmap_region(&self, region: VirtualMemoryRegion) {
     let mut page_start = VirtualAddress::new(region.get_starting_address_as_usize());
     while region.contains_address(page_start) {
@@ -66,11 +113,14 @@ impl PageDirectory for CurrentPageDirectory {
     let paddr = frame.get_address();
     let dir_index = vaddr.get_page_directory_index();
     let table_index = vaddr.get_page_table_index();
+    kassert!(paddr.as_usize() & 0xfff == 0, "Mapping unaligned frame {:?}", paddr);
+    kinvariant!(dir_index != 1023, "Mapping over the page directory itself at {:?}", vaddr);
     let top_page = PageTable::at_address(VirtualAddress::new(0xfffff000));
     // Address for the nested page table
     let table_address = VirtualAddress::new(0xffc00000 + (dir_index * 0x1000));
 
     let entry = top_page.get_mut(dir_index);
+    kinvariant!(entry.is_present() || !is_shared_kernel_index(dir_index), "Shared kernel table missing for {:?}", vaddr);
     if !entry.is_present() {
       // Create a page table
       let table_frame = allocate_frame().unwrap();
@@ -90,6 +140,9 @@ impl PageDirectory for CurrentPageDirectory {
       if flags.as_u8() & PermissionFlags::USER_ACCESS != 0 {
         table.get_mut(table_index).set_user_access();
       }
+      if flags.as_u8() & PermissionFlags::CACHE_DISABLE != 0 {
+        table.get_mut(table_index).set_cache_disabled();
+      }
     } else {
       let table = PageTable::at_address(table_address);
       let needs_invalidation = table.get(table_index).is_present();
@@ -101,6 +154,9 @@ impl PageDirectory for CurrentPageDirectory {
       if flags.as_u8() & PermissionFlags::USER_ACCESS != 0 {
         table.get_mut(table_index).set_user_access();
       }
+      if flags.as_u8() & PermissionFlags::CACHE_DISABLE != 0 {
+        table.get_mut(table_index).set_cache_disabled();
+      }
       if needs_invalidation {
         invalidate_page(vaddr);
       }
@@ -143,6 +199,11 @@ impl AlternatePageDirectory {
         }
         panic!("MemMapping not implemented");
       },
+      MemoryRegionType::FileBacked(_, _, _, _) => {
+        // Pages already read from the file are shared until either side
+        // writes, whatever the permissions, since the file is not changed
+        self.map_with_copy_on_write(region);
+      },
       MemoryRegionType::Anonymous(_) => {
         match region.get_permissions() {
           Permissions::ReadOnly => {
@@ -266,11 +327,15 @@ impl AlternatePageDirectory {
 
 impl PageDirectory for AlternatePageDirectory {
   fn map(&self, frame: Frame, vaddr: VirtualAddress, flags: PermissionFlags) {
+    kassert!(frame.get_address().as_usize() & 0xfff == 0, "Mapping unaligned frame {:?}", frame.get_address());
+    kassert!(self.directory_address.as_usize() & 0xfff == 0, "Unaligned page directory {:?}", self.directory_address);
     let pagedir_frame = Frame::new(self.directory_address.as_usize());
     map_frame_to_temporary_page(pagedir_frame);
     let dir_index = vaddr.get_page_directory_index();
     let table_index = vaddr.get_page_table_index();
+    kinvariant!(dir_index != 1023, "Mapping over the page directory itself at {:?}", vaddr);
     let directory = PageTable::at_address(get_temporary_page_address());
+    kinvariant!(directory.get(dir_index).is_present() || !is_shared_kernel_index(dir_index), "Shared kernel table missing for {:?}", vaddr);
     if !directory.get(dir_index).is_present() {
       // Allocate a page table
       let table_frame = allocate_frame().unwrap();
@@ -291,6 +356,9 @@ impl PageDirectory for AlternatePageDirectory {
       if flags.as_u8() & PermissionFlags::USER_ACCESS != 0 {
         table.get_mut(table_index).set_user_access();
       }
+      if flags.as_u8() & PermissionFlags::CACHE_DISABLE != 0 {
+        table.get_mut(table_index).set_cache_disabled();
+      }
     } else {
       let addr = directory.get(dir_index).get_address();
       map_frame_to_temporary_page(Frame::new(addr.as_usize()));
@@ -304,6 +372,9 @@ impl PageDirectory for AlternatePageDirectory {
       if flags.as_u8() & PermissionFlags::USER_ACCESS != 0 {
         table.get_mut(table_index).set_user_access();
       }
+      if flags.as_u8() & PermissionFlags::CACHE_DISABLE != 0 {
+        table.get_mut(table_index).set_cache_disabled();
+      }
       if needs_invalidation {
         invalidate_page(vaddr);
       }
@@ -329,6 +400,54 @@ pub fn invalidate_page(addr: VirtualAddress) {
   }
 }
 
+/// Point a new page directory, currently mapped to the temporary page, at the
+/// kernel's shared page tables
+pub fn share_kernel_tables(new_directory: &mut PageTable) {
+  let current = PageTable::at_address(get_current_page_address());
+  for index in SHARED_KERNEL_START_INDEX..SHARED_KERNEL_END_INDEX {
+    *new_directory.get_mut(index) = *current.get(index);
+  }
+}
+
+/// Visit every present page in a page directory, which need not be the
+/// current one, in address order. The callback receives the page's address,
+/// the directory entry covering it, and its own table entry. Tables are read
+/// through the temporary page and copied out first, so the callback is free
+/// to use the temporary page itself. The recursive mapping and the table
+/// holding the temporary page are skipped, since they describe the paging
+/// structures rather than memory.
+pub fn for_each_mapping<F>(directory_address: PhysicalAddress, mut f: F)
+  where F: FnMut(VirtualAddress, PageTableEntry, PageTableEntry) {
+  let mut directory: Vec<PageTableEntry> = Vec::with_capacity(TABLE_ENTRY_COUNT);
+  let mut table: Vec<PageTableEntry> = Vec::with_capacity(TABLE_ENTRY_COUNT);
+  copy_table(directory_address, &mut directory);
+  for dir_index in 0..TABLE_ENTRY_COUNT {
+    if dir_index == TEMP_REFERENCE_INDEX || dir_index == SELF_REFERENCE_INDEX {
+      continue;
+    }
+    let dir_entry = directory[dir_index];
+    if !dir_entry.is_present() {
+      continue;
+    }
+    copy_table(dir_entry.get_address(), &mut table);
+    for (table_index, entry) in table.iter().enumerate() {
+      if entry.is_present() {
+        let vaddr = VirtualAddress::new((dir_index << 22) | (table_index << 12));
+        f(vaddr, dir_entry, *entry);
+      }
+    }
+  }
+}
+
+fn copy_table(address: PhysicalAddress, dest: &mut Vec<PageTableEntry>) {
+  dest.clear();
+  map_frame_to_temporary_page(Frame::new(address.as_usize()));
+  let source = PageTable::at_address(get_temporary_page_address());
+  for index in 0..TABLE_ENTRY_COUNT {
+    dest.push(*source.get(index));
+  }
+}
+
 pub fn get_temporary_page_address() -> VirtualAddress {
   VirtualAddress::new(0xffbff000)
 }
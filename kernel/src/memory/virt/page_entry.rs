@@ -66,6 +66,10 @@ impl PageTableEntry {
     self.0 & ENTRY_WRITE_ACCESS == ENTRY_WRITE_ACCESS
   }
 
+  pub fn set_cache_disabled(&mut self) {
+    self.0 |= ENTRY_CACHE_DISABLED;
+  }
+
   pub fn set_present(&mut self) {
     self.0 |= ENTRY_PRESENT;
   }
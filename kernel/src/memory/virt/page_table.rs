@@ -7,6 +7,17 @@ pub const TABLE_ENTRY_COUNT: usize = 1024;
 pub const SELF_REFERENCE_INDEX: usize = 1023;
 pub const TEMP_REFERENCE_INDEX: usize = 1022;
 
+/// Kernel space from 0xc0000000 up to the thread stacks at 0xff000000 is
+/// mapped by page tables allocated once at boot. Every page directory points
+/// at the same tables, so a kernel mapping made in one process is immediately
+/// visible in all of them. The tables above are specific to each process.
+pub const SHARED_KERNEL_START_INDEX: usize = 0x300;
+pub const SHARED_KERNEL_END_INDEX: usize = 0x3fc;
+
+pub fn is_shared_kernel_index(index: usize) -> bool {
+  index >= SHARED_KERNEL_START_INDEX && index < SHARED_KERNEL_END_INDEX
+}
+
 #[derive(Copy, Clone)]
 #[repr(transparent)]
 pub struct PageTable([PageTableEntry; TABLE_ENTRY_COUNT]);
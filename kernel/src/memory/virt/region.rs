@@ -6,6 +6,11 @@ use super::super::physical::frame_range::FrameRange;
 pub enum MemoryRegionType {
   /// Memory backed by a memmapped file
   MemMapped(usize, LocalHandle, usize),
+  /// Memory backed by part of a file, opened just for the mapping: the drive,
+  /// the handle, the offset in the file where the region starts, and how many
+  /// bytes of the file are mapped. Pages past the end read as zeroes, and
+  /// writes are never sent back to the file.
+  FileBacked(usize, LocalHandle, usize, usize),
   /// Memory backed by an explicit physical memory range, like video RAM
   Direct(FrameRange),
   /// Backed by arbitrarily-allocated physical memory
@@ -130,6 +135,15 @@ impl VirtualMemoryRegion {
     self.copy_with_permissions(self.permissions)
   }
 
+  pub fn copy_with_backing(&self, backed_by: MemoryRegionType) -> VirtualMemoryRegion {
+    VirtualMemoryRegion {
+      start: self.start,
+      size: self.size,
+      backed_by,
+      permissions: self.permissions,
+    }
+  }
+
   pub fn copy_with_permissions(&self, permissions: Permissions) -> VirtualMemoryRegion {
     VirtualMemoryRegion {
       start: self.start,
@@ -0,0 +1,105 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::fmt;
+
+/// Message text for one locale, keyed by message number
+pub struct Catalog {
+  entries: BTreeMap<u16, String>,
+}
+
+impl Catalog {
+  pub fn new() -> Catalog {
+    Catalog {
+      entries: BTreeMap::new(),
+    }
+  }
+
+  /**
+   * Parse the contents of a catalog file. Each line holds a message number,
+   * an equals sign, and the text. Blank lines and lines starting with `#` are
+   * ignored, as are lines that cannot be parsed, so that a damaged catalog
+   * loses individual messages rather than all of them.
+   */
+  pub fn parse(text: &str) -> Catalog {
+    let mut catalog = Catalog::new();
+    for line in text.lines() {
+      let line = line.trim_end_matches('\r');
+      if line.trim().is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let separator = match line.find('=') {
+        Some(index) => index,
+        None => continue,
+      };
+      let id = match line[..separator].trim().parse::<u16>() {
+        Ok(id) => id,
+        Err(_) => continue,
+      };
+      catalog.entries.insert(id, String::from(&line[(separator + 1)..]));
+    }
+    catalog
+  }
+
+  pub fn get(&self, id: u16) -> Option<&str> {
+    self.entries.get(&id).map(|text| text.as_str())
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+}
+
+/// Write a message, replacing `%1` through `%9` with the matching argument.
+/// `%%` produces a single percent sign, and references to missing arguments
+/// are left as they are.
+pub fn expand(template: &str, args: &[&dyn fmt::Display], out: &mut dyn fmt::Write) -> fmt::Result {
+  let mut rest = template;
+  while let Some(index) = rest.find('%') {
+    out.write_str(&rest[..index])?;
+    let after = &rest[(index + 1)..];
+    match after.chars().next() {
+      Some('%') => {
+        out.write_char('%')?;
+        rest = &after[1..];
+      },
+      Some(digit @ '1'..='9') => {
+        let arg = (digit as usize) - ('1' as usize);
+        match args.get(arg) {
+          Some(value) => write!(out, "{}", value)?,
+          None => {
+            out.write_char('%')?;
+            out.write_char(digit)?;
+          },
+        }
+        rest = &after[1..];
+      },
+      _ => {
+        out.write_char('%')?;
+        rest = after;
+      },
+    }
+  }
+  out.write_str(rest)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_entries() {
+    let catalog = Catalog::parse("# comment\r\n\r\n1=Ready\r\n2 = Two words \nbad line\nx=no\n3=a=b");
+    assert_eq!(catalog.len(), 3);
+    assert_eq!(catalog.get(1), Some("Ready"));
+    assert_eq!(catalog.get(2), Some(" Two words "));
+    assert_eq!(catalog.get(3), Some("a=b"));
+    assert_eq!(catalog.get(4), None);
+  }
+
+  #[test]
+  fn expands_arguments() {
+    let mut out = String::new();
+    expand("%2 before %1, 100%% %3%", &[&"A:", &5], &mut out).unwrap();
+    assert_eq!(out, "5 before A:, 100% %3%");
+  }
+}
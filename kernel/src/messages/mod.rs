@@ -0,0 +1,123 @@
+//! User-visible messages are identified by number, and their text comes from
+//! a catalog for the current locale. Catalogs are read from `<LOCALE>.MSG` on
+//! the INIT: drive. Any message missing from the catalog, or every message if
+//! no catalog could be loaded, falls back to the built-in English text.
+
+#[cfg(not(test))]
+use alloc::{format, vec::Vec};
+use core::fmt;
+#[cfg(not(test))]
+use crate::filesystems;
+use spin::RwLock;
+#[cfg(not(test))]
+use syscall::files::OpenFlags;
+use syscall::messages::*;
+#[cfg(not(test))]
+use syscall::result::SystemError;
+
+pub mod catalog;
+
+use catalog::Catalog;
+
+pub const DEFAULT_LOCALE: &str = "EN";
+
+/// Locale names become file names, so they must fit in 8 characters
+const MAX_LOCALE_LENGTH: usize = 8;
+
+const BUILT_IN_MESSAGES: [(u16, &str); 11] = [
+  (KEYBOARD_READY, "Keyboard Ready"),
+  (FLOPPY_READY, "Floppy Controller Ready"),
+  (FLOPPY_INIT_FAILED, "Floppy Init Failed: %1"),
+  (MEDIA_CHANGED, "Media changed in %1"),
+  (REMOUNT_FAILED, "Failed to remount %1"),
+  (NO_READABLE_DISC, "No readable disc in %1"),
+  (SYSTEM_TIME, "System Time: %1 %2"),
+  (WORKER_POOL_STARVED, "Worker pool %1 starved: job waiting %2ms, %3 queued, %4/%5 workers busy"),
+  (INITIALIZING_DEVICES, "Initializing devices..."),
+  (SYSTEM_READY, "System ready."),
+  (ROOT_DIRECTORY_CONTENTS, "Root Directory Contents:"),
+];
+
+static CATALOG: RwLock<Option<Catalog>> = RwLock::new(None);
+
+pub fn get_built_in(id: u16) -> Option<&'static str> {
+  BUILT_IN_MESSAGES.iter().find(|(entry, _)| *entry == id).map(|(_, text)| *text)
+}
+
+/// Replace the active catalog
+pub fn install(catalog: Catalog) {
+  *CATALOG.write() = Some(catalog);
+}
+
+/// Run a function with the text of a message, or None if the message is
+/// unknown in both the catalog and the built-in set
+pub fn with_text<R, F: FnOnce(Option<&str>) -> R>(id: u16, f: F) -> R {
+  let catalog = CATALOG.read();
+  let text = catalog.as_ref().and_then(|c| c.get(id)).or_else(|| get_built_in(id));
+  f(text)
+}
+
+pub fn is_valid_locale(locale: &str) -> bool {
+  locale.len() > 0
+    && locale.len() <= MAX_LOCALE_LENGTH
+    && locale.bytes().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// A message and its arguments, which can be printed with any formatter
+pub struct Message<'a> {
+  id: u16,
+  args: &'a [&'a dyn fmt::Display],
+}
+
+impl<'a> Message<'a> {
+  pub fn new(id: u16, args: &'a [&'a dyn fmt::Display]) -> Message<'a> {
+    Message {
+      id,
+      args,
+    }
+  }
+}
+
+impl<'a> fmt::Display for Message<'a> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    with_text(self.id, |text| match text {
+      Some(text) => catalog::expand(text, self.args, f),
+      // Unknown messages still print something that can be looked up
+      None => write!(f, "Message {}", self.id),
+    })
+  }
+}
+
+/// Print a message on its own line on the console
+#[cfg(not(test))]
+pub fn console(id: u16, args: &[&dyn fmt::Display]) {
+  crate::tty::console_write(format_args!("{}\n", Message::new(id, args)));
+}
+
+/// Read the catalog for a locale from the INIT: drive and make it active. If
+/// it cannot be read, the current catalog stays in place.
+#[cfg(not(test))]
+pub fn load_locale(locale: &str) -> Result<(), SystemError> {
+  if !is_valid_locale(locale) {
+    return Err(SystemError::InvalidArgument);
+  }
+  let number = filesystems::get_fs_number("INIT").ok_or(SystemError::NoSuchDrive)?;
+  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
+  let path = format!("\\{}.MSG", locale);
+  let handle = filesystems::open_path(number, &path, OpenFlags::read_only())
+    .map_err(|_| SystemError::NoSuchEntity)?;
+  let mut contents = Vec::new();
+  let mut buffer = [0; 256];
+  let result = loop {
+    match fs.read(handle, &mut buffer) {
+      Ok(0) => break Ok(()),
+      Ok(read) => contents.extend_from_slice(&buffer[..read]),
+      Err(_) => break Err(SystemError::IOError),
+    }
+  };
+  let _ = fs.close(handle);
+  result?;
+  let text = core::str::from_utf8(&contents).map_err(|_| SystemError::InvalidArgument)?;
+  install(Catalog::parse(text));
+  Ok(())
+}
@@ -1,3 +1,4 @@
+use alloc::vec::Vec;
 use crate::collections::SlotList;
 use crate::files::handle::{Handle, LocalHandle};
 use spin::RwLock;
@@ -31,17 +32,26 @@ impl PipeCollection {
     Ok((read_handle, write_handle))
   }
 
+  fn get_pipe_handle(&self, handle: LocalHandle) -> Result<PipeHandle, PipeError> {
+    let handles = self.handles.read();
+    handles.get(handle.as_usize()).copied().ok_or(PipeError::InvalidHandle)
+  }
+
   /// Read available bytes into a mutable slice, using a Pipe Read Handle.
-  /// Returns the number of bytes copied to the buffer.
+  /// Returns the number of bytes copied to the buffer. Reading an empty pipe
+  /// returns a WouldBlock error while any write handles remain open, and zero
+  /// bytes once they have all been closed.
   pub fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, PipeError> {
-    let pipe_handle = {
-      let handles = self.handles.read();
-      *handles.get(handle.as_usize()).ok_or(PipeError::InvalidHandle)?
-    };
-    match pipe_handle {
+    match self.get_pipe_handle(handle)? {
       PipeHandle::ReadHandle(index) => {
         let pipes = self.pipes.read();
         let pipe = pipes.get(index).ok_or(PipeError::UnknownPipe)?;
+        if buffer.len() > 0 && !pipe.can_read() {
+          if pipe.has_writers() {
+            return Err(PipeError::WouldBlock);
+          }
+          return Ok(0);
+        }
         let read = pipe.data_buffer.read(buffer);
         Ok(read)
       },
@@ -50,29 +60,89 @@ impl PipeCollection {
   }
 
   /// Write bytes from a slice into the pipe, using a Pipe Write Handle.
-  /// Returns the number of bytes copied to the pipe.
+  /// Returns the number of bytes copied to the pipe, which may be fewer than
+  /// requested if the buffer fills up. If no bytes fit at all, a WouldBlock
+  /// error is returned.
   pub fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, PipeError> {
-    let pipe_handle = {
-      let handles = self.handles.read();
-      *handles.get(handle.as_usize()).ok_or(PipeError::InvalidHandle)?
-    };
-    match pipe_handle {
+    match self.get_pipe_handle(handle)? {
       PipeHandle::WriteHandle(index) => {
         let pipes = self.pipes.read();
         let pipe = pipes.get(index).ok_or(PipeError::UnknownPipe)?;
+        if !pipe.has_readers() {
+          return Err(PipeError::WriteToClosedPipe);
+        }
         let written = pipe.data_buffer.write(buffer);
+        if written == 0 && buffer.len() > 0 {
+          return Err(PipeError::WouldBlock);
+        }
         Ok(written)
       },
       PipeHandle::ReadHandle(_) => Err(PipeError::WrongHandleType),
     }
   }
 
-  pub fn get_available_bytes(&self, handle: LocalHandle) -> Result<usize, PipeError> {
-    let pipe_handle = {
-      let handles = self.handles.read();
-      *handles.get(handle.as_usize()).ok_or(PipeError::InvalidHandle)?
-    };
+  /// Register a process to be woken when the pipe behind a handle can make
+  /// progress: when data arrives for a read handle, or when space frees up
+  /// for a write handle
+  pub fn add_waiter(&self, handle: LocalHandle, id: u32) -> Result<(), PipeError> {
+    let pipe_handle = self.get_pipe_handle(handle)?;
+    let pipes = self.pipes.read();
+    let pipe = pipes.get(pipe_handle.to_index()).ok_or(PipeError::UnknownPipe)?;
+    match pipe_handle {
+      PipeHandle::ReadHandle(_) => pipe.add_waiting_reader(id),
+      PipeHandle::WriteHandle(_) => pipe.add_waiting_writer(id),
+    }
+    Ok(())
+  }
+
+  /// Determine whether reading or writing through a handle can make progress
+  /// without waiting. This is also true once the other end has been closed,
+  /// since the operation will complete with an end-of-file or an error.
+  pub fn is_ready(&self, handle: LocalHandle) -> Result<bool, PipeError> {
+    let pipe_handle = self.get_pipe_handle(handle)?;
+    let pipes = self.pipes.read();
+    let pipe = pipes.get(pipe_handle.to_index()).ok_or(PipeError::UnknownPipe)?;
+    match pipe_handle {
+      PipeHandle::ReadHandle(_) => Ok(pipe.can_read() || !pipe.has_writers()),
+      PipeHandle::WriteHandle(_) => Ok(!pipe.is_full() || !pipe.has_readers()),
+    }
+  }
+
+  /// After reading or writing through a handle, collect the processes waiting
+  /// at the other end of the pipe, which may now be able to continue
+  pub fn take_waiters(&self, handle: LocalHandle) -> Result<Vec<u32>, PipeError> {
+    let pipe_handle = self.get_pipe_handle(handle)?;
+    let pipes = self.pipes.read();
+    let pipe = pipes.get(pipe_handle.to_index()).ok_or(PipeError::UnknownPipe)?;
     match pipe_handle {
+      PipeHandle::ReadHandle(_) => Ok(pipe.take_waiting_writers()),
+      PipeHandle::WriteHandle(_) => Ok(pipe.take_waiting_readers()),
+    }
+  }
+
+  /// Close a handle, freeing the pipe once both ends are closed. Returns the
+  /// processes waiting at the other end, which need to be woken so they can
+  /// see that the pipe was closed.
+  pub fn close(&self, handle: LocalHandle) -> Result<Vec<u32>, PipeError> {
+    let pipe_handle = self.handles.write().remove(handle.as_usize()).ok_or(PipeError::InvalidHandle)?;
+    let index = pipe_handle.to_index();
+    let mut pipes = self.pipes.write();
+    let (waiters, unused) = {
+      let pipe = pipes.get(index).ok_or(PipeError::UnknownPipe)?;
+      let waiters = match pipe_handle {
+        PipeHandle::ReadHandle(_) => pipe.take_waiting_writers(),
+        PipeHandle::WriteHandle(_) => pipe.take_waiting_readers(),
+      };
+      (waiters, pipe.release_handle(pipe_handle.can_read()))
+    };
+    if unused {
+      pipes.remove(index);
+    }
+    Ok(waiters)
+  }
+
+  pub fn get_available_bytes(&self, handle: LocalHandle) -> Result<usize, PipeError> {
+    match self.get_pipe_handle(handle)? {
       PipeHandle::ReadHandle(index) => {
         let pipes = self.pipes.read();
         let pipe = pipes.get(index).ok_or(PipeError::UnknownPipe)?;
@@ -81,4 +151,40 @@ impl PipeCollection {
       PipeHandle::WriteHandle(_) => Err(PipeError::WrongHandleType),
     }
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn read_until_closed() {
+    let pipes = PipeCollection::new();
+    let (reader, writer) = pipes.create().unwrap();
+    let mut buffer = [0; 4];
+    assert!(matches!(pipes.read(reader, &mut buffer), Err(PipeError::WouldBlock)));
+    pipes.add_waiter(reader, 3).unwrap();
+    assert_eq!(pipes.write(writer, b"ab").unwrap(), 2);
+    assert_eq!(pipes.take_waiters(writer).unwrap(), [3]);
+    assert_eq!(pipes.read(reader, &mut buffer).unwrap(), 2);
+    pipes.add_waiter(reader, 3).unwrap();
+    assert_eq!(pipes.close(writer).unwrap(), [3]);
+    // With no writers left, an empty pipe reads as the end of the file
+    assert_eq!(pipes.read(reader, &mut buffer).unwrap(), 0);
+    pipes.close(reader).unwrap();
+    assert!(matches!(pipes.read(reader, &mut buffer), Err(PipeError::InvalidHandle)));
+  }
+
+  #[test]
+  fn full_and_broken_pipes() {
+    let pipes = PipeCollection::new();
+    let (reader, writer) = pipes.create().unwrap();
+    let data = [1; 300];
+    assert_eq!(pipes.write(writer, &data).unwrap(), 256);
+    assert!(matches!(pipes.write(writer, &data), Err(PipeError::WouldBlock)));
+    assert_eq!(pipes.is_ready(writer).unwrap(), false);
+    assert_eq!(pipes.is_ready(reader).unwrap(), true);
+    pipes.close(reader).unwrap();
+    assert!(matches!(pipes.write(writer, &data), Err(PipeError::WriteToClosedPipe)));
+  }
+}
@@ -8,4 +8,7 @@ pub enum PipeError {
   WrongHandleType,
   /// Writing to a pipe with no readers
   WriteToClosedPipe,
-}
\ No newline at end of file
+  /// Reading from an empty pipe, or writing to a full one. The caller should
+  /// wait for the other end to make progress and try again.
+  WouldBlock,
+}
@@ -1,10 +1,15 @@
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use crate::files::cursor::SeekMethod;
 use crate::files::handle::LocalHandle;
 use crate::files::ioctl::FIONREAD;
+use crate::filesystems::capabilities::Capabilities;
 use crate::filesystems::filesystem::FileSystem;
+use crate::process::{self, id::ProcessID};
 use super::collection::PipeCollection;
-use syscall::files::DirEntryInfo;
+use super::errors::PipeError;
+use syscall::files::{DirEntryInfo, OpenFlags};
+use syscall::result::SystemError;
 
 pub struct PipeFileSystem {
   collection: Arc<PipeCollection>,
@@ -16,24 +21,103 @@ impl PipeFileSystem {
       collection: Arc::clone(collection),
     }
   }
+
+  /// Put the current process to sleep until the other end of the pipe behind
+  /// `handle` reads or writes. The process is marked as blocked before it is
+  /// registered, so a wake-up arriving in between is not lost.
+  fn wait_for_progress(&self, handle: LocalHandle) -> Result<(), PipeError> {
+    let current = match process::current_process() {
+      Some(current) => current,
+      None => return Err(PipeError::WouldBlock),
+    };
+    current.block();
+    let ready = self.collection.add_waiter(handle, current.get_id().as_u32())
+      .and_then(|_| self.collection.is_ready(handle));
+    match ready {
+      Ok(false) => process::yield_coop(),
+      _ => current.resume(),
+    }
+    ready.map(|_| ())
+  }
+
+  /// Resume every blocked process in a list of waiters
+  fn wake(&self, waiters: Vec<u32>) {
+    let processes = process::all_processes();
+    for id in waiters {
+      if let Some(waiter) = processes.get_process(ProcessID::new(id)) {
+        waiter.resume();
+      }
+    }
+  }
+
+  /// Read at least one byte, sleeping until a writer provides data. Returns
+  /// zero once the pipe is empty and every write handle has been closed.
+  fn blocking_read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, PipeError> {
+    loop {
+      match self.collection.read(handle, buffer) {
+        Ok(read) => {
+          if read > 0 {
+            self.wake(self.collection.take_waiters(handle)?);
+          }
+          return Ok(read);
+        },
+        Err(PipeError::WouldBlock) => self.wait_for_progress(handle)?,
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  /// Write the entire buffer, sleeping whenever the pipe is full. If all read
+  /// handles are closed partway through, the bytes already written are
+  /// reported instead of an error.
+  fn blocking_write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, PipeError> {
+    let mut written = 0;
+    while written < buffer.len() {
+      match self.collection.write(handle, &buffer[written..]) {
+        Ok(chunk) => {
+          written += chunk;
+          self.wake(self.collection.take_waiters(handle)?);
+        },
+        Err(PipeError::WouldBlock) => self.wait_for_progress(handle)?,
+        Err(PipeError::WriteToClosedPipe) if written > 0 => break,
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(written)
+  }
 }
 
 impl FileSystem for PipeFileSystem {
+  /// Pipes are streams: they can be written, but not repositioned
+  fn get_capabilities(&self) -> Capabilities {
+    Capabilities {
+      writable: true,
+      ..Capabilities::none()
+    }
+  }
+
   /// Open only works for named pipes, which are not yet implemented
-  fn open(&self, _path: &str) -> Result<LocalHandle, ()> {
+  fn open(&self, _path: &str, _flags: OpenFlags) -> Result<LocalHandle, ()> {
     Err(())
   }
 
   fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
-    self.collection.read(handle, buffer).map_err(|_| ())
+    self.blocking_read(handle, buffer).map_err(|_| ())
   }
 
-  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
-    self.collection.write(handle, buffer).map_err(|_| ())
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, SystemError> {
+    self.blocking_write(handle, buffer).map_err(|e| match e {
+      PipeError::WriteToClosedPipe => SystemError::BrokenPipe,
+      _ => SystemError::BadFileDescriptor,
+    })
   }
 
-  fn close(&self, _handle: LocalHandle) -> Result<(), ()> {
-    Err(())
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    // Anyone waiting on the other end needs to observe the closure, either as
+    // the end of the file or as a broken pipe
+    let waiters = self.collection.close(handle).map_err(|_| ())?;
+    self.wake(waiters);
+    Ok(())
   }
 
   fn dup(&self, _handle: LocalHandle) -> Result<LocalHandle, ()> {
@@ -1,10 +1,13 @@
+#[cfg(not(test))]
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use crate::files::handle::LocalHandle;
+#[cfg(not(test))]
 use crate::filesystems::FileSystemType;
 
 pub mod collection;
 pub mod errors;
+#[cfg(not(test))]
 pub mod fs;
 pub mod handle;
 pub mod pipe;
@@ -17,6 +20,7 @@ use collection::PipeCollection;
 
 static mut PIPES: Option<Arc<PipeCollection>> = None;
 
+#[cfg(not(test))]
 pub fn create_fs() -> Box<FileSystemType> {
   unsafe {
     let pipes = Arc::new(PipeCollection::new());
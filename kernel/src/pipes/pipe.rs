@@ -1,5 +1,8 @@
 use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use crate::buffers::RingBuffer;
+use spin::RwLock;
 
 const BUFFER_SIZE: usize = 256;
 
@@ -10,6 +13,15 @@ pub struct Pipe {
   data_raw_ptr: usize,
   /// Ring buffer containing pipe data
   pub data_buffer: RingBuffer<'static>,
+  /// Number of open read handles. Once it reaches zero, writes fail.
+  reader_count: AtomicUsize,
+  /// Number of open write handles. Once it reaches zero, reading an empty pipe
+  /// reports the end of the file instead of waiting.
+  writer_count: AtomicUsize,
+  /// IDs of processes waiting for data to arrive
+  waiting_readers: RwLock<Vec<u32>>,
+  /// IDs of processes waiting for room in the buffer
+  waiting_writers: RwLock<Vec<u32>>,
 }
 
 impl Pipe {
@@ -23,6 +35,10 @@ impl Pipe {
     Pipe {
       data_raw_ptr: data_raw_ptr as usize,
       data_buffer: RingBuffer::new(data_slice),
+      reader_count: AtomicUsize::new(1),
+      writer_count: AtomicUsize::new(1),
+      waiting_readers: RwLock::new(Vec::new()),
+      waiting_writers: RwLock::new(Vec::new()),
     }
   }
 
@@ -35,6 +51,47 @@ impl Pipe {
   pub fn can_read(&self) -> bool {
     self.available_bytes() > 0
   }
+
+  /// Return true if there is no room left to write
+  pub fn is_full(&self) -> bool {
+    self.available_bytes() >= BUFFER_SIZE
+  }
+
+  pub fn has_readers(&self) -> bool {
+    self.reader_count.load(Ordering::SeqCst) > 0
+  }
+
+  pub fn has_writers(&self) -> bool {
+    self.writer_count.load(Ordering::SeqCst) > 0
+  }
+
+  /// Record that a read or write handle was closed. Returns true once no
+  /// handles of either kind remain.
+  pub fn release_handle(&self, reader: bool) -> bool {
+    let count = if reader {
+      &self.reader_count
+    } else {
+      &self.writer_count
+    };
+    count.fetch_sub(1, Ordering::SeqCst);
+    !self.has_readers() && !self.has_writers()
+  }
+
+  pub fn add_waiting_reader(&self, id: u32) {
+    self.waiting_readers.write().push(id);
+  }
+
+  pub fn add_waiting_writer(&self, id: u32) {
+    self.waiting_writers.write().push(id);
+  }
+
+  pub fn take_waiting_readers(&self) -> Vec<u32> {
+    core::mem::replace(&mut *self.waiting_readers.write(), Vec::new())
+  }
+
+  pub fn take_waiting_writers(&self) -> Vec<u32> {
+    core::mem::replace(&mut *self.waiting_writers.write(), Vec::new())
+  }
 }
 
 impl Drop for Pipe {
@@ -44,4 +101,4 @@ impl Drop for Pipe {
       Box::from_raw(ptr);
     }
   }
-}
\ No newline at end of file
+}
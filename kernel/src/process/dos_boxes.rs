@@ -0,0 +1,60 @@
+//! Each device emulated inside DOS boxes, like the timer, the video adapter,
+//! and the mouse, keeps a separate state for every box. Few processes run DOS
+//! programs at once, so a box's state is found by a linear search.
+//! The devices are driven from host interrupts as well as from the boxes
+//! themselves, so interrupts stay off while a table is locked.
+
+use alloc::vec::Vec;
+use crate::interrupts;
+use spin::Mutex;
+use super::id::ProcessID;
+
+/// The per-box states of one emulated device
+pub struct Boxes<T> {
+  states: Vec<(ProcessID, T)>,
+}
+
+impl<T> Boxes<T> {
+  pub fn get_mut(&mut self, pid: ProcessID) -> Option<&mut T> {
+    self.states.iter_mut().find(|(id, _)| *id == pid).map(|(_, state)| state)
+  }
+
+  /// Give a box a new state, replacing any it already had
+  pub fn set(&mut self, pid: ProcessID, state: T) {
+    match self.get_mut(pid) {
+      Some(existing) => *existing = state,
+      None => self.states.push((pid, state)),
+    }
+  }
+
+  pub fn remove(&mut self, pid: ProcessID) {
+    self.states.retain(|(id, _)| *id != pid);
+  }
+
+  pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+    self.states.iter_mut().map(|(_, state)| state)
+  }
+}
+
+pub struct BoxTable<T> {
+  boxes: Mutex<Boxes<T>>,
+}
+
+impl<T> BoxTable<T> {
+  pub const fn new() -> BoxTable<T> {
+    BoxTable {
+      boxes: Mutex::new(Boxes { states: Vec::new() }),
+    }
+  }
+
+  /// Run `f` with the box states locked
+  pub fn with<R, F: FnOnce(&mut Boxes<T>) -> R>(&self, f: F) -> R {
+    let int_reenable = interrupts::is_interrupt_enabled();
+    interrupts::cli();
+    let result = f(&mut self.boxes.lock());
+    if int_reenable {
+      interrupts::sti();
+    }
+    result
+  }
+}
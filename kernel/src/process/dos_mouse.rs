@@ -0,0 +1,625 @@
+//! The DOS mouse driver API, INT 33h, emulated for programs in VM86 boxes.
+//! Packets from the PS/2 mouse move a virtual mouse in every box that has
+//! called INT 33h, scaled by that box's mickey-to-pixel ratio and clamped to
+//! its ranges, so each program sees the mouse as if it had loaded its own
+//! driver.
+//!
+//! Packets arrive in interrupt context, where a box's memory is not mapped.
+//! Drawing the cursor and calling a program's event handler both need the
+//! box's address space, so they are deferred until the box next traps into
+//! the kernel, which programs polling the mouse or keyboard do constantly.
+//!
+//! Event handlers are called with a far return address pointing at a HLT
+//! placed in the BIOS inter-application area. HLT traps in VM86 mode, and the
+//! trap at that address restores the registers the program had before the
+//! handler was called.
+
+use crate::hardware::ps2mouse::MousePacket;
+use crate::interrupts::stack::StackFrame;
+use crate::interrupts::syscall_legacy::{DosApiRegisters, VM8086Frame};
+use crate::memory::address::VirtualAddress;
+use crate::memory::virt::page_directory::CurrentPageDirectory;
+use super::dos_boxes::BoxTable;
+use super::id::ProcessID;
+use super::process_state::ProcessState;
+use super::vm86::push_u16;
+
+/// Linear address of the HLT that event handlers return to
+pub const CALLBACK_RETURN: usize = 0x4f0;
+const HLT: u8 = 0xf4;
+
+const BUTTON_COUNT: usize = 3;
+
+// Bits in the event condition mask
+const EVENT_MOVED: u16 = 1;
+
+/// Default arrow cursor for graphics modes: 16 rows of screen mask, which is
+/// ANDed with the screen, then 16 rows of cursor mask, which is XORed
+const DEFAULT_GRAPHICS_CURSOR: [u16; 32] = [
+  0x3fff, 0x1fff, 0x0fff, 0x07ff, 0x03ff, 0x01ff, 0x00ff, 0x007f,
+  0x003f, 0x001f, 0x01ff, 0x10ff, 0x30ff, 0xf87f, 0xf87f, 0xfc3f,
+  0x0000, 0x4000, 0x6000, 0x7000, 0x7800, 0x7c00, 0x7e00, 0x7f00,
+  0x7f80, 0x7c00, 0x6c00, 0x4600, 0x0600, 0x0300, 0x0300, 0x0000,
+];
+
+#[derive(Copy, Clone)]
+struct ButtonLog {
+  count: u16,
+  x: i32,
+  y: i32,
+}
+
+impl ButtonLog {
+  const fn new() -> ButtonLog {
+    ButtonLog { count: 0, x: 0, y: 0 }
+  }
+
+  fn record(&mut self, x: i32, y: i32) {
+    self.count = self.count.wrapping_add(1);
+    self.x = x;
+    self.y = y;
+  }
+}
+
+#[derive(Copy, Clone)]
+struct Handler {
+  mask: u16,
+  segment: u16,
+  offset: u16,
+}
+
+/// Registers of the interrupted program while its event handler runs
+#[derive(Copy, Clone)]
+struct SavedRegisters {
+  regs: [u32; 7],
+  ds: u32,
+  es: u32,
+  sp: u32,
+  cs: u32,
+  ip: u32,
+  flags: u32,
+  vif: bool,
+}
+
+/// What was on screen under the cursor, so it can be put back
+enum DrawnCursor {
+  Text {
+    address: usize,
+    saved: u16,
+    drawn: u16,
+  },
+  Graphics {
+    left: i32,
+    top: i32,
+    saved: [u8; 256],
+  },
+}
+
+struct BoxMouse {
+  x: i32,
+  y: i32,
+  min_x: i32,
+  max_x: i32,
+  min_y: i32,
+  max_y: i32,
+  /// Mickeys per 8 pixels on each axis
+  ratio_x: i32,
+  ratio_y: i32,
+  /// Movement not yet large enough to move a whole pixel, in eighths of a
+  /// mickey
+  remainder_x: i32,
+  remainder_y: i32,
+  /// Mickeys moved since function 0Bh last read them
+  motion_x: i16,
+  motion_y: i16,
+  buttons: u8,
+  presses: [ButtonLog; BUTTON_COUNT],
+  releases: [ButtonLog; BUTTON_COUNT],
+  /// The cursor is shown when this is zero. Each hide call decrements it, and
+  /// each show call increments it up to zero.
+  visibility: i32,
+  text_screen_mask: u16,
+  text_cursor_mask: u16,
+  graphics_masks: [u16; 32],
+  hotspot_x: i32,
+  hotspot_y: i32,
+  drawn: Option<DrawnCursor>,
+  handler: Option<Handler>,
+  /// Events the handler has asked for that happened since it last ran
+  pending: u16,
+  /// Set while the event handler runs, so it is never re-entered
+  in_callback: Option<SavedRegisters>,
+  video_mode: u8,
+}
+
+impl BoxMouse {
+  fn new(video_mode: u8) -> BoxMouse {
+    let mut mouse = BoxMouse {
+      x: 0,
+      y: 0,
+      min_x: 0,
+      max_x: 0,
+      min_y: 0,
+      max_y: 0,
+      ratio_x: 8,
+      ratio_y: 16,
+      remainder_x: 0,
+      remainder_y: 0,
+      motion_x: 0,
+      motion_y: 0,
+      buttons: 0,
+      presses: [ButtonLog::new(); BUTTON_COUNT],
+      releases: [ButtonLog::new(); BUTTON_COUNT],
+      visibility: -1,
+      text_screen_mask: 0xffff,
+      text_cursor_mask: 0x7700,
+      graphics_masks: DEFAULT_GRAPHICS_CURSOR,
+      hotspot_x: 0,
+      hotspot_y: 0,
+      drawn: None,
+      handler: None,
+      pending: 0,
+      in_callback: None,
+      video_mode,
+    };
+    mouse.reset();
+    mouse
+  }
+
+  /// Restore the state a freshly loaded driver would have: hidden, centered,
+  /// with default ranges, ratios, cursors, and no handler
+  fn reset(&mut self) {
+    let (width, height) = screen_extent(self.video_mode);
+    self.min_x = 0;
+    self.max_x = width - 1;
+    self.min_y = 0;
+    self.max_y = height - 1;
+    self.x = width / 2;
+    self.y = height / 2;
+    self.ratio_x = 8;
+    self.ratio_y = 16;
+    self.remainder_x = 0;
+    self.remainder_y = 0;
+    self.motion_x = 0;
+    self.motion_y = 0;
+    self.presses = [ButtonLog::new(); BUTTON_COUNT];
+    self.releases = [ButtonLog::new(); BUTTON_COUNT];
+    self.visibility = -1;
+    self.text_screen_mask = 0xffff;
+    self.text_cursor_mask = 0x7700;
+    self.graphics_masks = DEFAULT_GRAPHICS_CURSOR;
+    self.hotspot_x = 0;
+    self.hotspot_y = 0;
+    self.handler = None;
+    self.pending = 0;
+  }
+
+  fn clamp_position(&mut self) {
+    self.x = self.x.max(self.min_x).min(self.max_x);
+    self.y = self.y.max(self.min_y).min(self.max_y);
+  }
+
+  fn apply_packet(&mut self, packet: MousePacket) {
+    let dx = packet.dx as i32;
+    // The mouse counts upward motion as positive, DOS counts downward
+    let dy = -(packet.dy as i32);
+    self.motion_x = self.motion_x.wrapping_add(dx as i16);
+    self.motion_y = self.motion_y.wrapping_add(dy as i16);
+
+    self.remainder_x += dx * 8;
+    self.remainder_y += dy * 8;
+    let pixels_x = self.remainder_x / self.ratio_x;
+    let pixels_y = self.remainder_y / self.ratio_y;
+    self.remainder_x -= pixels_x * self.ratio_x;
+    self.remainder_y -= pixels_y * self.ratio_y;
+
+    let (old_x, old_y) = (self.x, self.y);
+    self.x += pixels_x;
+    self.y += pixels_y;
+    self.clamp_position();
+
+    let mut events = 0;
+    if self.x != old_x || self.y != old_y {
+      events |= EVENT_MOVED;
+    }
+    for button in 0..BUTTON_COUNT {
+      let bit = 1 << button;
+      let was_down = self.buttons & bit != 0;
+      let is_down = packet.buttons & bit != 0;
+      if is_down && !was_down {
+        self.presses[button].record(self.x, self.y);
+        events |= 1 << (1 + button * 2);
+      } else if was_down && !is_down {
+        self.releases[button].record(self.x, self.y);
+        events |= 1 << (2 + button * 2);
+      }
+    }
+    self.buttons = packet.buttons;
+    if let Some(handler) = self.handler {
+      self.pending |= events & handler.mask;
+    }
+  }
+
+  /// Put back whatever the cursor covered
+  unsafe fn erase(&mut self) {
+    match self.drawn.take() {
+      Some(DrawnCursor::Text { address, saved, drawn }) => {
+        let cell = address as *mut u16;
+        // If the program has written over the cursor, its text stays
+        if cell.read_volatile() == drawn {
+          cell.write_volatile(saved);
+        }
+      },
+      Some(DrawnCursor::Graphics { left, top, saved }) => {
+        for_each_cursor_pixel(left, top, |index, pixel| {
+          pixel.write_volatile(saved[index]);
+        });
+      },
+      None => (),
+    }
+  }
+
+  /// Bring the drawn cursor in line with the current position and
+  /// visibility. Modes without a supported cursor are tracked but not drawn.
+  unsafe fn redraw(&mut self, process: &ProcessState) {
+    let target = if self.visibility < 0 {
+      None
+    } else {
+      self.cursor_origin()
+    };
+    let current = match &self.drawn {
+      Some(DrawnCursor::Text { address, .. }) => Some((*address as i32, 0)),
+      Some(DrawnCursor::Graphics { left, top, .. }) => Some((*left, *top)),
+      None => None,
+    };
+    if target == current {
+      return;
+    }
+    self.erase();
+    let (first, second) = match target {
+      Some(origin) => origin,
+      None => return,
+    };
+    if is_text_mode(self.video_mode) {
+      let address = first as usize;
+      if !video_mapped(process, address) {
+        return;
+      }
+      let cell = address as *mut u16;
+      let saved = cell.read_volatile();
+      let drawn = (saved & self.text_screen_mask) ^ self.text_cursor_mask;
+      cell.write_volatile(drawn);
+      self.drawn = Some(DrawnCursor::Text { address, saved, drawn });
+    } else {
+      if !video_mapped(process, 0xa0000) {
+        return;
+      }
+      let (left, top) = (first, second);
+      let mut saved = [0; 256];
+      let masks = self.graphics_masks;
+      for_each_cursor_pixel(left, top, |index, pixel| {
+        let row = index / 16;
+        let bit = 0x8000 >> (index % 16);
+        let value = pixel.read_volatile();
+        saved[index] = value;
+        let mut drawn = if masks[row] & bit != 0 { value } else { 0 };
+        if masks[16 + row] & bit != 0 {
+          drawn ^= 0x0f;
+        }
+        pixel.write_volatile(drawn);
+      });
+      self.drawn = Some(DrawnCursor::Graphics { left, top, saved });
+    }
+  }
+
+  /// Where the cursor belongs on screen: the cell address in text modes, or
+  /// the top-left pixel of the cursor image in mode 13h
+  fn cursor_origin(&self) -> Option<(i32, i32)> {
+    if is_text_mode(self.video_mode) {
+      let columns = if self.video_mode <= 1 { 40 } else { 80 };
+      let cell_width = 640 / columns;
+      let column = (self.x / cell_width).max(0).min(columns - 1);
+      let row = (self.y / 8).max(0).min(24);
+      let base = if self.video_mode == 7 { 0xb0000 } else { 0xb8000 };
+      Some((base + (row * columns + column) * 2, 0))
+    } else if self.video_mode == 0x13 {
+      // Horizontal coordinates are doubled in 320-pixel modes
+      Some((self.x / 2 - self.hotspot_x, self.y - self.hotspot_y))
+    } else {
+      None
+    }
+  }
+
+  fn enter_callback(
+    &mut self,
+    process: &ProcessState,
+    handler: Handler,
+    stack_frame: &mut StackFrame,
+    regs: &mut DosApiRegisters,
+    vm_frame: &mut VM8086Frame,
+  ) {
+    self.in_callback = Some(SavedRegisters {
+      regs: [regs.ax, regs.bx, regs.cx, regs.dx, regs.si, regs.di, regs.bp],
+      ds: vm_frame.ds,
+      es: vm_frame.es,
+      sp: vm_frame.sp,
+      cs: stack_frame.cs,
+      ip: stack_frame.eip,
+      flags: stack_frame.eflags,
+      vif: process.get_virtual_interrupt_flag(),
+    });
+    unsafe {
+      (CALLBACK_RETURN as *mut u8).write_volatile(HLT);
+      // Far return address
+      push_u16(vm_frame, 0);
+      push_u16(vm_frame, CALLBACK_RETURN as u16);
+    }
+    regs.ax = self.pending as u32;
+    regs.bx = self.buttons as u32;
+    regs.cx = self.x as u32 & 0xffff;
+    regs.dx = self.y as u32 & 0xffff;
+    regs.si = self.motion_x as u16 as u32;
+    regs.di = self.motion_y as u16 as u32;
+    self.pending = 0;
+    stack_frame.cs = handler.segment as u32;
+    stack_frame.eip = handler.offset as u32;
+    // Drivers call handlers with interrupts disabled
+    process.set_virtual_interrupt_flag(false);
+  }
+}
+
+/// Visit each on-screen pixel of a 16x16 mode 13h cursor whose top-left
+/// corner is at (left, top), with its index in the cursor image
+unsafe fn for_each_cursor_pixel<F: FnMut(usize, *mut u8)>(left: i32, top: i32, mut f: F) {
+  for row in 0..16 {
+    let y = top + row;
+    if y < 0 || y >= 200 {
+      continue;
+    }
+    for column in 0..16 {
+      let x = left + column;
+      if x < 0 || x >= 320 {
+        continue;
+      }
+      let pixel = (0xa0000 + (y * 320 + x) as usize) as *mut u8;
+      f((row * 16 + column) as usize, pixel);
+    }
+  }
+}
+
+fn is_text_mode(mode: u8) -> bool {
+  mode <= 3 || mode == 7
+}
+
+/// Virtual screen size in mouse coordinates for a video mode. Modes narrower
+/// than 640 pixels still report 640 columns, as real drivers do.
+fn screen_extent(mode: u8) -> (i32, i32) {
+  match mode {
+    0x0f | 0x10 => (640, 350),
+    0x11 | 0x12 => (640, 480),
+    _ => (640, 200),
+  }
+}
+
+/// Video memory is only touched if the box can see it; until boxes have a
+/// virtual screen of their own, a box without it mapped gets no cursor
+fn video_mapped(process: &ProcessState, address: usize) -> bool {
+  let vaddr = VirtualAddress::new(address);
+  CurrentPageDirectory::get().get_physical_address(vaddr).is_some()
+    || process.get_range_containing_address(vaddr).is_some()
+}
+
+/// Packets are applied from the mouse interrupt
+static BOXES: BoxTable<BoxMouse> = BoxTable::new();
+
+/// Move the virtual mouse in every box that uses it. Called from IRQ12.
+pub fn push_packet(packet: MousePacket) {
+  BOXES.with(|boxes| {
+    for mouse in boxes.iter_mut() {
+      mouse.apply_packet(packet);
+    }
+  });
+}
+
+/// Drop a box's mouse, when the process exits or replaces its program
+pub fn forget(pid: ProcessID) {
+  BOXES.with(|boxes| boxes.remove(pid));
+}
+
+/// Record a video mode change made through INT 10h, so the cursor is drawn
+/// the right way and the ranges match the new screen
+pub fn set_video_mode(process: &ProcessState, mode: u8) {
+  BOXES.with(|boxes| {
+    if let Some(mouse) = boxes.get_mut(process.get_id()) {
+      // Setting a mode clears the screen, so nothing needs to be erased
+      mouse.drawn = None;
+      mouse.video_mode = mode & 0x7f;
+      mouse.reset();
+    }
+  });
+}
+
+/// Catch up with events that arrived while the box was running: redraw the
+/// cursor, and enter the event handler if something it wants has happened.
+/// Called each time the box traps into the kernel.
+pub unsafe fn sync(
+  process: &ProcessState,
+  stack_frame: &mut StackFrame,
+  regs: &mut DosApiRegisters,
+  vm_frame: &mut VM8086Frame,
+) {
+  BOXES.with(|boxes| {
+    let mouse = match boxes.get_mut(process.get_id()) {
+      Some(mouse) => mouse,
+      None => return,
+    };
+    mouse.redraw(process);
+    if mouse.in_callback.is_some() || mouse.pending == 0 {
+      return;
+    }
+    if let Some(handler) = mouse.handler {
+      mouse.enter_callback(process, handler, stack_frame, regs, vm_frame);
+    }
+  });
+}
+
+/// Handle a HLT trap at the handler return address. Returns false if the box
+/// was not running an event handler, in which case the HLT is the program's.
+pub fn return_from_callback(
+  process: &ProcessState,
+  stack_frame: &mut StackFrame,
+  regs: &mut DosApiRegisters,
+  vm_frame: &mut VM8086Frame,
+) -> bool {
+  let saved = BOXES.with(|boxes| {
+    boxes.get_mut(process.get_id()).and_then(|mouse| mouse.in_callback.take())
+  });
+  let saved = match saved {
+    Some(saved) => saved,
+    None => return false,
+  };
+  regs.ax = saved.regs[0];
+  regs.bx = saved.regs[1];
+  regs.cx = saved.regs[2];
+  regs.dx = saved.regs[3];
+  regs.si = saved.regs[4];
+  regs.di = saved.regs[5];
+  regs.bp = saved.regs[6];
+  vm_frame.ds = saved.ds;
+  vm_frame.es = saved.es;
+  vm_frame.sp = saved.sp;
+  stack_frame.cs = saved.cs;
+  stack_frame.eip = saved.ip;
+  stack_frame.eflags = saved.flags;
+  process.set_virtual_interrupt_flag(saved.vif);
+  true
+}
+
+/// Service an INT 33h call from a box. The box's mouse is created on its first
+/// call, in the text mode that programs start in.
+pub unsafe fn handle_int33(
+  process: &ProcessState,
+  regs: &mut DosApiRegisters,
+  vm_frame: &mut VM8086Frame,
+) {
+  BOXES.with(|boxes| {
+    let pid = process.get_id();
+    if boxes.get_mut(pid).is_none() {
+      boxes.set(pid, BoxMouse::new(3));
+    }
+    if let Some(mouse) = boxes.get_mut(pid) {
+      mouse_function(mouse, process, regs, vm_frame);
+    }
+  });
+}
+
+unsafe fn mouse_function(
+  mouse: &mut BoxMouse,
+  process: &ProcessState,
+  regs: &mut DosApiRegisters,
+  vm_frame: &mut VM8086Frame,
+) {
+  let cx = (regs.cx & 0xffff) as i32;
+  let dx = (regs.dx & 0xffff) as i32;
+  let function = regs.ax & 0xffff;
+  match function {
+    0x00 | 0x21 => { // Reset, and report that a three-button mouse is present
+      mouse.erase();
+      mouse.reset();
+      regs.ax = 0xffff;
+      regs.bx = BUTTON_COUNT as u32;
+    },
+    0x01 => { // Show cursor
+      if mouse.visibility < 0 {
+        mouse.visibility += 1;
+      }
+    },
+    0x02 => { // Hide cursor
+      mouse.visibility -= 1;
+    },
+    0x03 => { // Position and buttons
+      regs.bx = mouse.buttons as u32;
+      regs.cx = mouse.x as u32;
+      regs.dx = mouse.y as u32;
+    },
+    0x04 => { // Set position
+      mouse.x = cx;
+      mouse.y = dx;
+      mouse.clamp_position();
+    },
+    0x05 | 0x06 => { // Button press or release information
+      let button = (regs.bx & 0xffff) as usize;
+      regs.ax = mouse.buttons as u32;
+      if button < BUTTON_COUNT {
+        let log = if function == 0x05 {
+          &mut mouse.presses[button]
+        } else {
+          &mut mouse.releases[button]
+        };
+        regs.bx = log.count as u32;
+        regs.cx = log.x as u32;
+        regs.dx = log.y as u32;
+        log.count = 0;
+      }
+    },
+    0x07 => { // Horizontal range
+      mouse.min_x = cx.min(dx);
+      mouse.max_x = cx.max(dx);
+      mouse.clamp_position();
+    },
+    0x08 => { // Vertical range
+      mouse.min_y = cx.min(dx);
+      mouse.max_y = cx.max(dx);
+      mouse.clamp_position();
+    },
+    0x09 => { // Graphics cursor, masks at ES:DX
+      mouse.hotspot_x = regs.bx as i16 as i32;
+      mouse.hotspot_y = regs.cx as i16 as i32;
+      let masks = (((vm_frame.es & 0xffff) << 4) + (regs.dx & 0xffff)) as usize;
+      for (index, mask) in mouse.graphics_masks.iter_mut().enumerate() {
+        *mask = core::ptr::read_unaligned((masks + index * 2) as *const u16);
+      }
+      mouse.erase();
+    },
+    0x0a => { // Text cursor. Only the software cursor is drawn.
+      if regs.bx & 0xffff == 0 {
+        mouse.text_screen_mask = cx as u16;
+        mouse.text_cursor_mask = dx as u16;
+        mouse.erase();
+      }
+    },
+    0x0b => { // Motion counters
+      regs.cx = mouse.motion_x as u16 as u32;
+      regs.dx = mouse.motion_y as u16 as u32;
+      mouse.motion_x = 0;
+      mouse.motion_y = 0;
+    },
+    0x0c | 0x14 => { // Set, or swap, the event handler at ES:DX
+      let previous = mouse.handler;
+      mouse.handler = Some(Handler {
+        mask: cx as u16,
+        segment: vm_frame.es as u16,
+        offset: dx as u16,
+      });
+      mouse.pending = 0;
+      if function == 0x14 {
+        let previous = previous.unwrap_or(Handler { mask: 0, segment: 0, offset: 0 });
+        regs.cx = previous.mask as u32;
+        vm_frame.es = previous.segment as u32;
+        regs.dx = previous.offset as u32;
+      }
+    },
+    0x0f => { // Mickey to pixel ratio
+      if cx > 0 && dx > 0 {
+        mouse.ratio_x = cx;
+        mouse.ratio_y = dx;
+      }
+    },
+    0x24 => { // Driver version 6.26, on a PS/2 mouse
+      regs.bx = 0x0626;
+      regs.cx = 0x0400;
+    },
+    _ => (),
+  }
+  mouse.redraw(process);
+}
@@ -0,0 +1,367 @@
+//! Each DOS box gets its own virtual timer (PIT) and interrupt controller
+//! (PIC), so programs that reprogram the timer for music or game timing see it
+//! tick at the rate they asked for, without touching the real timer that
+//! drives the scheduler.
+//!
+//! Virtual time only moves while the box is running: each host tick that lands
+//! on the box advances its PIT by the length of a tick. Channel 0 raises
+//! virtual IRQ0 each time its count runs out. The interrupt is reflected to
+//! the box's handler when the virtual PIC and the virtual interrupt flag allow
+//! it, either straight from the host timer interrupt or the next time the box
+//! traps into the kernel.
+//!
+//! Counts can only be read with the resolution of a host tick. A box that
+//! has not hooked the timer vector gets the BIOS behavior instead: the tick
+//! count in the BIOS data area is incremented and the interrupt is
+//! acknowledged.
+
+use crate::hardware::pit::BASE_FREQUENCY;
+use super::dos_boxes::BoxTable;
+use super::id::ProcessID;
+
+/// Hundreds of nanoseconds per second, the unit host ticks are measured in
+const HUNDRED_NS_PER_SECOND: u64 = 10_000_000;
+
+/// Interrupts that arrive while the box cannot take them are queued, up to a
+/// limit, so a box with interrupts off for a long time does not receive a
+/// burst of stale ticks afterwards
+const MAX_PENDING: u32 = 16;
+
+/// BIOS data area tick counter, and the flag set when it wraps at midnight
+const BDA_TICKS: usize = 0x46c;
+const BDA_MIDNIGHT: usize = 0x470;
+const TICKS_PER_DAY: u32 = 0x1800b0;
+
+/// Which bytes of a count are read or written through a channel's data port
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Access {
+  Low,
+  High,
+  LowHigh,
+}
+
+#[derive(Copy, Clone)]
+struct Channel {
+  /// Count loaded at the start of each period. Zero is written to mean 65536.
+  reload: u32,
+  access: Access,
+  /// Virtual clock when the current period started
+  start: u64,
+  /// Low byte of a count being written in two parts
+  partial: Option<u8>,
+  /// Count captured by a latch command, until it has been fully read
+  latch: Option<u16>,
+  /// Whether the next read of a two-part count returns the high byte
+  read_high: bool,
+}
+
+impl Channel {
+  const fn new() -> Channel {
+    Channel {
+      reload: 0x10000,
+      access: Access::LowHigh,
+      start: 0,
+      partial: None,
+      latch: None,
+      read_high: false,
+    }
+  }
+
+  fn count_at(&self, clock: u64) -> u16 {
+    let elapsed = (clock - self.start) % self.reload as u64;
+    (self.reload as u64 - elapsed) as u16
+  }
+
+  fn read(&mut self, clock: u64) -> u8 {
+    let count = self.latch.unwrap_or_else(|| self.count_at(clock));
+    let (value, done) = match self.access {
+      Access::Low => (count as u8, true),
+      Access::High => ((count >> 8) as u8, true),
+      Access::LowHigh => {
+        let high = self.read_high;
+        self.read_high = !high;
+        if high {
+          ((count >> 8) as u8, true)
+        } else {
+          (count as u8, false)
+        }
+      },
+    };
+    if done {
+      self.latch = None;
+    }
+    value
+  }
+
+  /// Write part of a count, returning true once a full count has been loaded
+  fn write(&mut self, value: u8, clock: u64) -> bool {
+    let count = match self.access {
+      Access::Low => value as u32,
+      Access::High => (value as u32) << 8,
+      Access::LowHigh => match self.partial.take() {
+        Some(low) => low as u32 | ((value as u32) << 8),
+        None => {
+          self.partial = Some(value);
+          return false;
+        },
+      },
+    };
+    self.reload = if count == 0 { 0x10000 } else { count };
+    self.start = clock;
+    true
+  }
+}
+
+/// The master 8259. The slave only keeps its mask, since no virtual device
+/// raises its interrupts.
+#[derive(Copy, Clone)]
+struct Pic {
+  vector_base: u8,
+  /// Interrupt mask register
+  mask: u8,
+  /// In-service register
+  in_service: u8,
+  /// Next initialization word expected after ICW1, or 0 outside of
+  /// initialization
+  init_step: u8,
+  expects_icw4: bool,
+  /// Set by OCW3 to read the in-service register instead of the request
+  /// register from the command port
+  read_in_service: bool,
+  slave_mask: u8,
+}
+
+impl Pic {
+  const fn new() -> Pic {
+    Pic {
+      vector_base: 0x08,
+      mask: 0,
+      in_service: 0,
+      init_step: 0,
+      expects_icw4: false,
+      read_in_service: false,
+      slave_mask: 0,
+    }
+  }
+
+  fn write_command(&mut self, value: u8) {
+    if value & 0x10 != 0 {
+      // ICW1 restarts initialization
+      self.init_step = 2;
+      self.expects_icw4 = value & 0x01 != 0;
+      self.mask = 0;
+      self.in_service = 0;
+    } else if value & 0x18 == 0 {
+      // OCW2: both specific and non-specific EOIs end IRQ0, the only virtual
+      // interrupt
+      if value & 0x20 != 0 {
+        self.in_service = 0;
+      }
+    } else if value & 0x18 == 0x08 {
+      // OCW3
+      if value & 0x02 != 0 {
+        self.read_in_service = value & 0x01 != 0;
+      }
+    }
+  }
+
+  fn write_data(&mut self, value: u8) {
+    match self.init_step {
+      2 => {
+        self.vector_base = value & 0xf8;
+        self.init_step = 3;
+      },
+      3 => {
+        self.init_step = if self.expects_icw4 { 4 } else { 0 };
+      },
+      4 => {
+        self.init_step = 0;
+      },
+      _ => self.mask = value,
+    }
+  }
+}
+
+struct BoxTimer {
+  /// Virtual PIT clocks since the box started
+  clock: u64,
+  /// Fraction of a PIT clock carried between ticks, in units of
+  /// 1/HUNDRED_NS_PER_SECOND
+  remainder: u64,
+  channels: [Channel; 3],
+  /// Virtual clock at which channel 0 next runs out
+  next_irq: u64,
+  /// IRQ0s raised but not yet taken by the box
+  pending: u32,
+  pic: Pic,
+}
+
+impl BoxTimer {
+  fn new() -> BoxTimer {
+    BoxTimer {
+      clock: 0,
+      remainder: 0,
+      channels: [Channel::new(); 3],
+      next_irq: 0x10000,
+      pending: 0,
+      pic: Pic::new(),
+    }
+  }
+
+  fn advance(&mut self, hundred_ns: u64) {
+    let total = hundred_ns * BASE_FREQUENCY as u64 + self.remainder;
+    self.clock += total / HUNDRED_NS_PER_SECOND;
+    self.remainder = total % HUNDRED_NS_PER_SECOND;
+    if self.next_irq <= self.clock {
+      let reload = self.channels[0].reload as u64;
+      let periods = (self.clock - self.next_irq) / reload + 1;
+      self.next_irq += periods * reload;
+      self.pending = (self.pending as u64 + periods).min(MAX_PENDING as u64) as u32;
+    }
+  }
+
+  fn request_register(&self) -> u8 {
+    if self.pending > 0 { 1 } else { 0 }
+  }
+
+  fn read_port(&mut self, port: u16) -> Option<u8> {
+    let value = match port {
+      0x20 => if self.pic.read_in_service {
+        self.pic.in_service
+      } else {
+        self.request_register()
+      },
+      0x21 => self.pic.mask,
+      0xa0 => 0,
+      0xa1 => self.pic.slave_mask,
+      0x40..=0x42 => {
+        let clock = self.clock;
+        self.channels[(port - 0x40) as usize].read(clock)
+      },
+      _ => return None,
+    };
+    Some(value)
+  }
+
+  fn write_port(&mut self, port: u16, value: u8) -> bool {
+    match port {
+      0x20 => self.pic.write_command(value),
+      0x21 => self.pic.write_data(value),
+      // The slave is only initialized and masked
+      0xa0 => (),
+      0xa1 => self.pic.slave_mask = value,
+      0x40..=0x42 => {
+        let index = (port - 0x40) as usize;
+        let clock = self.clock;
+        if self.channels[index].write(value, clock) && index == 0 {
+          self.next_irq = clock + self.channels[0].reload as u64;
+        }
+      },
+      0x43 => self.write_control(value),
+      _ => return false,
+    }
+    true
+  }
+
+  fn write_control(&mut self, value: u8) {
+    let index = (value >> 6) as usize;
+    if index == 3 {
+      // Read-back is only on the 8254, which DOS programs rarely assume
+      return;
+    }
+    let channel = &mut self.channels[index];
+    let clock = self.clock;
+    match (value >> 4) & 3 {
+      0 => {
+        if channel.latch.is_none() {
+          channel.latch = Some(channel.count_at(clock));
+          channel.read_high = false;
+        }
+      },
+      access => {
+        // The counting mode is not emulated; every channel counts down and
+        // reloads like modes 2 and 3
+        channel.access = match access {
+          1 => Access::Low,
+          2 => Access::High,
+          _ => Access::LowHigh,
+        };
+        channel.partial = None;
+        channel.latch = None;
+        channel.read_high = false;
+      },
+    }
+  }
+}
+
+/// The host timer interrupt advances the current box
+static BOXES: BoxTable<BoxTimer> = BoxTable::new();
+
+/// Give a box a freshly reset timer and PIC, in the state the BIOS leaves them
+pub fn reset(pid: ProcessID) {
+  BOXES.with(|boxes| boxes.set(pid, BoxTimer::new()));
+}
+
+/// Drop a box's timer, when the process exits or replaces its program
+pub fn forget(pid: ProcessID) {
+  BOXES.with(|boxes| boxes.remove(pid));
+}
+
+/// Move a box's virtual time forward. Called from the host timer interrupt
+/// for whichever process was running.
+pub fn advance(pid: ProcessID, hundred_ns: u64) {
+  BOXES.with(|boxes| {
+    if let Some(timer) = boxes.get_mut(pid) {
+      timer.advance(hundred_ns);
+    }
+  });
+}
+
+/// Read a virtual PIT or PIC port, or None if the port is not one of them
+pub fn read_port(pid: ProcessID, port: u16) -> Option<u8> {
+  BOXES.with(|boxes| boxes.get_mut(pid).and_then(|timer| timer.read_port(port)))
+}
+
+/// Write a virtual PIT or PIC port, returning false if the port is not one of
+/// them
+pub fn write_port(pid: ProcessID, port: u16, value: u8) -> bool {
+  BOXES.with(|boxes| {
+    match boxes.get_mut(pid) {
+      Some(timer) => timer.write_port(port, value),
+      None => false,
+    }
+  })
+}
+
+/// Take a pending IRQ0 if the box can receive it now, returning the vector
+/// to reflect it through. Boxes that have not hooked the vector get the BIOS
+/// tick instead, and None is returned. Must run with the box's memory mapped.
+pub unsafe fn take_interrupt(pid: ProcessID, interrupts_enabled: bool) -> Option<u8> {
+  BOXES.with(|boxes| {
+    let timer = boxes.get_mut(pid)?;
+    if timer.pending == 0 || !interrupts_enabled || timer.pic.mask & 1 != 0 || timer.pic.in_service & 1 != 0 {
+      return None;
+    }
+    timer.pending -= 1;
+    let vector = timer.pic.vector_base;
+    let handler = core::ptr::read_unaligned((vector as usize * 4) as *const u32);
+    if handler == 0 {
+      bios_tick();
+      return None;
+    }
+    timer.pic.in_service |= 1;
+    Some(vector)
+  })
+}
+
+/// What the BIOS INT 08h handler does when nothing else has hooked it
+unsafe fn bios_tick() {
+  let ticks = BDA_TICKS as *mut u32;
+  let next = ticks.read_unaligned().wrapping_add(1);
+  if next >= TICKS_PER_DAY {
+    ticks.write_unaligned(0);
+    (BDA_MIDNIGHT as *mut u8).write_volatile(1);
+  } else {
+    ticks.write_unaligned(next);
+  }
+}
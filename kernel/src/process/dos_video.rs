@@ -0,0 +1,270 @@
+//! DOS boxes see a CGA, with the EGA's registers alongside it, no matter what
+//! the real display is doing. Text memory at B800h is ordinary memory in each
+//! box, and the 6845 CRT controller and the CGA and EGA control ports are
+//! emulated per box, so programs that poke them directly find the values they
+//! wrote and a status port that shows retrace coming and going.
+//!
+//! Periodically, while the box is running, its visible text page is drawn
+//! onto the terminal whose foreground group it belongs to, starting at the
+//! address in the CRTC's start registers, with the cursor where the cursor
+//! registers put it. Only text modes are drawn; 40-column modes are shown with
+//! each cell doubled. Graphics modes keep their registers and memory but are
+//! not displayed.
+
+use crate::tty;
+use super::dos_boxes::BoxTable;
+use super::id::ProcessID;
+use super::process_state::ProcessState;
+
+/// Linear address and size of the box's CGA memory
+pub const TEXT_MEMORY: usize = 0xb8000;
+pub const TEXT_MEMORY_SIZE: usize = 0x4000;
+
+const COLUMNS: usize = 80;
+const ROWS: usize = 25;
+
+/// Host ticks between each redraw of a box's screen
+const REFRESH_TICKS: u32 = 4;
+
+/// CGA mode control register bits
+const MODE_80_COLUMNS: u8 = 0x01;
+const MODE_GRAPHICS: u8 = 0x02;
+const MODE_VIDEO_ENABLE: u8 = 0x08;
+
+/// 6845 registers for 80x25 text, as the BIOS programs them
+const CRTC_DEFAULTS: [u8; 18] = [
+  0x71, 0x50, 0x5a, 0x0a, 0x1f, 0x06, 0x19, 0x1c,
+  0x02, 0x07, 0x06, 0x07, 0x00, 0x00, 0x00, 0x00,
+  0x00, 0x00,
+];
+
+struct BoxVideo {
+  crtc_index: u8,
+  crtc: [u8; 18],
+  mode_control: u8,
+  color_select: u8,
+  /// Counts status reads, so retrace appears to come and go for programs that
+  /// wait for it
+  status_reads: u8,
+  misc_output: u8,
+  sequencer_index: u8,
+  sequencer: [u8; 5],
+  graphics_index: u8,
+  graphics: [u8; 9],
+  attribute_index: u8,
+  /// The attribute controller takes an index and then a value through the
+  /// same port. Reading the status port resets it to expect an index.
+  attribute_expects_data: bool,
+  attribute: [u8; 21],
+  ticks_until_refresh: u32,
+}
+
+impl BoxVideo {
+  fn new() -> BoxVideo {
+    BoxVideo {
+      crtc_index: 0,
+      crtc: CRTC_DEFAULTS,
+      mode_control: MODE_80_COLUMNS | MODE_VIDEO_ENABLE | 0x20,
+      color_select: 0,
+      status_reads: 0,
+      misc_output: 0x67,
+      sequencer_index: 0,
+      sequencer: [0x03, 0x00, 0x03, 0x00, 0x02],
+      graphics_index: 0,
+      graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x0e, 0x00, 0xff],
+      attribute_index: 0,
+      attribute_expects_data: false,
+      attribute: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07,
+        0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e, 0x3f,
+        0x0c, 0x00, 0x0f, 0x08, 0x00,
+      ],
+      ticks_until_refresh: 0,
+    }
+  }
+
+  fn read_status(&mut self) -> u8 {
+    self.attribute_expects_data = false;
+    self.status_reads = self.status_reads.wrapping_add(1);
+    // Bit 0 is set outside the visible area, bit 3 during vertical retrace
+    match self.status_reads & 3 {
+      0 => 0x09,
+      1 => 0x01,
+      _ => 0x00,
+    }
+  }
+
+  fn read_port(&mut self, port: u16) -> Option<u8> {
+    let value = match port {
+      0x3b4 | 0x3d4 => self.crtc_index,
+      0x3b5 | 0x3d5 => {
+        let index = self.crtc_index as usize;
+        // Only the start and cursor registers can be read back
+        if (12..=17).contains(&index) { self.crtc[index] } else { 0 }
+      },
+      0x3ba | 0x3da => self.read_status(),
+      0x3d8 => self.mode_control,
+      0x3d9 => self.color_select,
+      0x3c0 => self.attribute_index,
+      0x3c1 => self.attribute.get(self.attribute_index as usize & 0x1f).copied().unwrap_or(0),
+      0x3c4 => self.sequencer_index,
+      0x3c5 => self.sequencer.get(self.sequencer_index as usize).copied().unwrap_or(0),
+      0x3cc => self.misc_output,
+      0x3ce => self.graphics_index,
+      0x3cf => self.graphics.get(self.graphics_index as usize).copied().unwrap_or(0),
+      _ => return None,
+    };
+    Some(value)
+  }
+
+  fn write_port(&mut self, port: u16, value: u8) -> bool {
+    match port {
+      0x3b4 | 0x3d4 => self.crtc_index = value & 0x1f,
+      0x3b5 | 0x3d5 => {
+        if let Some(register) = self.crtc.get_mut(self.crtc_index as usize) {
+          *register = value;
+        }
+      },
+      0x3d8 => self.mode_control = value,
+      0x3d9 => self.color_select = value,
+      0x3c0 => {
+        if self.attribute_expects_data {
+          if let Some(register) = self.attribute.get_mut(self.attribute_index as usize & 0x1f) {
+            *register = value;
+          }
+        } else {
+          self.attribute_index = value;
+        }
+        self.attribute_expects_data = !self.attribute_expects_data;
+      },
+      0x3c2 => self.misc_output = value,
+      0x3c4 => self.sequencer_index = value,
+      0x3c5 => {
+        if let Some(register) = self.sequencer.get_mut(self.sequencer_index as usize) {
+          *register = value;
+        }
+      },
+      0x3ce => self.graphics_index = value,
+      0x3cf => {
+        if let Some(register) = self.graphics.get_mut(self.graphics_index as usize) {
+          *register = value;
+        }
+      },
+      _ => return false,
+    }
+    true
+  }
+
+  fn register_pair(&self, high: usize) -> usize {
+    ((self.crtc[high] as usize) << 8) | self.crtc[high + 1] as usize
+  }
+
+  /// Cursor position as a cell offset into text memory, unless the cursor
+  /// registers hide it
+  fn cursor_offset(&self) -> Option<usize> {
+    let start = self.crtc[10];
+    let end = self.crtc[11] & 0x1f;
+    if start & 0x20 != 0 || start & 0x1f > end {
+      return None;
+    }
+    Some(self.register_pair(14))
+  }
+
+  /// Copy the visible page into an 80x25 grid of cells for the terminal. Must
+  /// run with the box's memory mapped.
+  unsafe fn render(&self, cells: &mut [u16]) {
+    let columns = if self.mode_control & MODE_80_COLUMNS != 0 { COLUMNS } else { COLUMNS / 2 };
+    let start = self.register_pair(12);
+    let cursor = self.cursor_offset();
+    let memory = TEXT_MEMORY as *const u16;
+    for row in 0..ROWS {
+      for column in 0..columns {
+        let offset = start + row * columns + column;
+        let mut cell = memory.add(offset % (TEXT_MEMORY_SIZE / 2)).read_volatile();
+        if cursor == Some(offset) {
+          // Draw the cursor the way the terminal does, by swapping colors
+          let color = cell >> 8;
+          let swapped = ((color & 0x0f) << 4) | ((color & 0xf0) >> 4);
+          cell = (cell & 0xff) | (swapped << 8);
+        }
+        if columns == COLUMNS {
+          cells[row * COLUMNS + column] = cell;
+        } else {
+          cells[row * COLUMNS + column * 2] = cell;
+          cells[row * COLUMNS + column * 2 + 1] = (cell & 0xff00) | 0x20;
+        }
+      }
+    }
+  }
+}
+
+/// Screens are redrawn from the host timer interrupt
+static BOXES: BoxTable<BoxVideo> = BoxTable::new();
+
+/// Give a box a blank 80x25 text screen. Must be called from the box, once its
+/// text memory is mapped.
+pub fn reset(pid: ProcessID) {
+  unsafe {
+    let memory = TEXT_MEMORY as *mut u16;
+    for offset in 0..(TEXT_MEMORY_SIZE / 2) {
+      memory.add(offset).write_volatile(0x0720);
+    }
+  }
+  BOXES.with(|boxes| boxes.set(pid, BoxVideo::new()));
+}
+
+/// Drop a box's video state, when the process exits or replaces its program
+pub fn forget(pid: ProcessID) {
+  BOXES.with(|boxes| boxes.remove(pid));
+}
+
+/// Read an emulated video port, or None if the port is not one of them
+pub fn read_port(pid: ProcessID, port: u16) -> Option<u8> {
+  BOXES.with(|boxes| boxes.get_mut(pid).and_then(|video| video.read_port(port)))
+}
+
+/// Write an emulated video port, returning false if the port is not one of
+/// them
+pub fn write_port(pid: ProcessID, port: u16, value: u8) -> bool {
+  BOXES.with(|boxes| {
+    match boxes.get_mut(pid) {
+      Some(video) => video.write_port(port, value),
+      None => false,
+    }
+  })
+}
+
+/// Redraw the box's screen on its terminal, every few ticks. Called from the
+/// host timer interrupt while the box is running, so its memory is mapped and
+/// no kernel locks are held.
+pub unsafe fn refresh(process: &ProcessState) {
+  let mut cells = [0u16; COLUMNS * ROWS];
+  let rendered = BOXES.with(|boxes| {
+    let video = match boxes.get_mut(process.get_id()) {
+      Some(video) => video,
+      None => return false,
+    };
+    if video.ticks_until_refresh > 0 {
+      video.ticks_until_refresh -= 1;
+      return false;
+    }
+    video.ticks_until_refresh = REFRESH_TICKS;
+    if video.mode_control & MODE_GRAPHICS != 0 || video.mode_control & MODE_VIDEO_ENABLE == 0 {
+      return false;
+    }
+    video.render(&mut cells);
+    true
+  });
+  if !rendered {
+    return;
+  }
+  let router = match tty::get_router().try_read() {
+    Some(router) => router,
+    None => return,
+  };
+  if let Some(terminal) = router.find_foreground_tty(process.get_process_group()) {
+    if let Some(mut terminal) = terminal.try_write() {
+      terminal.draw_cells(&cells);
+    }
+  }
+}
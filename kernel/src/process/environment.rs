@@ -0,0 +1,92 @@
+//! Every process carries a set of environment variables, which are copied to
+//! children when they fork and kept when a process execs a new program. Native
+//! programs find them, along with their arguments, on their initial stack.
+//! DOS programs find them in an environment segment named by their PSP.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Most bytes of arguments and environment placed on a new program's stack.
+/// Variables that would not fit are left out.
+pub const MAX_STARTUP_DATA: usize = 0x1000;
+
+#[derive(Clone)]
+pub struct Environment {
+  /// Kept in the order variables were first set, which is the order programs
+  /// see them
+  variables: Vec<(String, String)>,
+}
+
+impl Environment {
+  pub fn new() -> Environment {
+    Environment {
+      variables: Vec::new(),
+    }
+  }
+
+  /// Names cannot be empty, or contain '=' or NUL, since either would make the
+  /// NAME=VALUE form ambiguous. Values cannot contain NUL.
+  pub fn is_valid(name: &str, value: &str) -> bool {
+    !name.is_empty() && !name.contains('=') && !name.contains('\0') && !value.contains('\0')
+  }
+
+  pub fn get(&self, name: &str) -> Option<&str> {
+    self.variables.iter()
+      .find(|(key, _)| key == name)
+      .map(|(_, value)| value.as_str())
+  }
+
+  /// Set a variable, replacing any earlier value. Returns false if the name or
+  /// value is not allowed.
+  pub fn set(&mut self, name: &str, value: &str) -> bool {
+    if !Environment::is_valid(name, value) {
+      return false;
+    }
+    match self.variables.iter_mut().find(|(key, _)| key == name) {
+      Some((_, existing)) => *existing = String::from(value),
+      None => self.variables.push((String::from(name), String::from(value))),
+    }
+    true
+  }
+
+  /// Remove a variable. Removing one that is not set is not an error.
+  pub fn unset(&mut self, name: &str) {
+    self.variables.retain(|(key, _)| key != name);
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+    self.variables.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+  }
+
+  /// Serialize the variables the way DOS lays out an environment segment:
+  /// each one as NAME=VALUE followed by a NUL, with one more NUL at the end
+  pub fn to_block(&self) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in self.iter() {
+      block.extend_from_slice(name.as_bytes());
+      block.push(b'=');
+      block.extend_from_slice(value.as_bytes());
+      block.push(0);
+    }
+    block.push(0);
+    block
+  }
+
+  /// Read variables back from a block in the form to_block produces. The
+  /// final empty string may be left off. Returns None if any entry is not a
+  /// valid NAME=VALUE pair.
+  pub fn from_block(block: &[u8]) -> Option<Environment> {
+    let mut environment = Environment::new();
+    for entry in block.split(|byte| *byte == 0) {
+      if entry.is_empty() {
+        break;
+      }
+      let entry = core::str::from_utf8(entry).ok()?;
+      let split = entry.find('=')?;
+      if !environment.set(&entry[..split], &entry[(split + 1)..]) {
+        return None;
+      }
+    }
+    Some(environment)
+  }
+}
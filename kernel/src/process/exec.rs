@@ -1,5 +1,7 @@
+use alloc::vec::Vec;
 use crate::files::handle::LocalHandle;
 use crate::memory::address::VirtualAddress;
+use super::environment::MAX_STARTUP_DATA;
 use super::process_state::ProcessState;
 use super::subsystem::{DosSubsystemMetadata, Subsystem};
 
@@ -14,6 +16,18 @@ pub enum InterpretationMode {
   DOS, // Assume it's ExecType::DOS
 }
 
+/// Native programs start with their stack pointer just below their arguments
+/// and environment, which are copied to the top of the stack region
+const STACK_TOP: usize = 0xc0000000;
+
+/// DOS programs find their environment in a segment of its own, between the
+/// BIOS data area and the PSP
+const DOS_ENVIRONMENT: usize = 0x600;
+const DOS_ENVIRONMENT_SIZE: usize = 0x900;
+
+/// Longest command tail that fits in a PSP, not counting the final CR
+const DOS_COMMAND_TAIL_MAX: usize = 126;
+
 impl InterpretationMode {
   pub fn from_u32(raw: u32) -> InterpretationMode {
     match raw {
@@ -62,18 +76,131 @@ impl ProcessState {
     }
   }
 
+  /// Create a DOS PSP struct in the 256 bytes before prog_start, along with
+  /// the environment segment it points to. The environment holds the
+  /// process's variables in the usual NAME=VALUE form, followed by the path of
+  /// the program. Variables that do not fit are left out.
   pub fn create_dos_psp(&self, prog_start: VirtualAddress) {
-    // Create a DOS PSP struct in the 256 bytes before prog_start
+    let psp = (prog_start.as_usize() - 0x100) as *mut u8;
+    let command_line = self.get_command_line();
+    let (program, tail) = match command_line.find(' ') {
+      Some(index) => command_line.split_at(index),
+      None => (command_line.as_str(), ""),
+    };
+    // The block ends with an empty entry, a count of the strings after it,
+    // and the program path
+    let trailer = program.len() + 4;
+    let mut block = Vec::new();
+    for (name, value) in self.get_environment().read().iter() {
+      if block.len() + name.len() + value.len() + 2 + trailer > DOS_ENVIRONMENT_SIZE {
+        break;
+      }
+      block.extend_from_slice(name.as_bytes());
+      block.push(b'=');
+      block.extend_from_slice(value.as_bytes());
+      block.push(0);
+    }
+    block.extend_from_slice(&[0, 1, 0]);
+    block.extend_from_slice(program.as_bytes());
+    block.push(0);
+    block.truncate(DOS_ENVIRONMENT_SIZE);
+
+    let tail = &tail.as_bytes()[..tail.len().min(DOS_COMMAND_TAIL_MAX)];
+    unsafe {
+      core::ptr::write_bytes(psp, 0, 0x100);
+      core::ptr::copy_nonoverlapping(block.as_ptr(), DOS_ENVIRONMENT as *mut u8, block.len());
+      // INT 20h, so returning to offset 0 exits
+      *psp = 0xcd;
+      *psp.add(1) = 0x20;
+      // Segment just past the end of conventional memory
+      (psp.add(0x02) as *mut u16).write_unaligned(0xa000);
+      (psp.add(0x2c) as *mut u16).write_unaligned((DOS_ENVIRONMENT >> 4) as u16);
+      // The command tail keeps the space before the first argument, and ends
+      // with a CR that its length does not count. The initial stack grows
+      // down from the end of this page, so programs should copy the tail
+      // before using much of it.
+      *psp.add(0x80) = tail.len() as u8;
+      core::ptr::copy_nonoverlapping(tail.as_ptr(), psp.add(0x81), tail.len());
+      *psp.add(0x81 + tail.len()) = 0x0d;
+    }
+  }
+
+  /// Copy a native program's arguments and environment to the top of its
+  /// stack, returning the stack pointer it should start with. The stack is
+  /// laid out as if `_start(argc, argv, envp)` had been called: a return
+  /// address of zero, the argument count, and pointers to NULL-terminated
+  /// arrays of argument and NAME=VALUE strings. Arguments are the words of
+  /// the command line, starting with the program path. Anything that would
+  /// take the total past MAX_STARTUP_DATA is left out.
+  pub fn write_startup_stack(&self) -> usize {
+    let command_line = self.get_command_line();
+    let environment = self.get_environment().read();
+    // Four words before the arrays, and a NULL ending each of them
+    let fits = |strings: usize, pointers: usize| {
+      ((strings + 3) & !3) + (pointers + 6) * 4 <= MAX_STARTUP_DATA
+    };
+    let mut strings_size = 0;
+    let mut args: Vec<&str> = Vec::new();
+    for arg in command_line.split(' ').filter(|arg| !arg.is_empty()) {
+      if !fits(strings_size + arg.len() + 1, args.len() + 1) {
+        break;
+      }
+      strings_size += arg.len() + 1;
+      args.push(arg);
+    }
+    let mut vars: Vec<(&str, &str)> = Vec::new();
+    for (name, value) in environment.iter() {
+      let size = name.len() + value.len() + 2;
+      if !fits(strings_size + size, args.len() + vars.len() + 1) {
+        break;
+      }
+      strings_size += size;
+      vars.push((name, value));
+    }
+
+    let strings_start = STACK_TOP - strings_size;
+    let envp_start = (strings_start & !3) - (vars.len() + 1) * 4;
+    let argv_start = envp_start - (args.len() + 1) * 4;
+    let esp = argv_start - 16;
+    unsafe {
+      let mut cursor = strings_start;
+      let argv = argv_start as *mut usize;
+      for (index, arg) in args.iter().enumerate() {
+        *argv.add(index) = cursor;
+        cursor = write_string(cursor, &[arg.as_bytes()]);
+      }
+      *argv.add(args.len()) = 0;
+      let envp = envp_start as *mut usize;
+      for (index, (name, value)) in vars.iter().enumerate() {
+        *envp.add(index) = cursor;
+        cursor = write_string(cursor, &[name.as_bytes(), b"=", value.as_bytes()]);
+      }
+      *envp.add(vars.len()) = 0;
+
+      let frame = esp as *mut usize;
+      *frame = 0;
+      *frame.add(1) = args.len();
+      *frame.add(2) = argv_start;
+      *frame.add(3) = envp_start;
+    }
+    esp
   }
 
   pub fn prepare_for_exec(&self, drive_number: usize, handle: LocalHandle, interp_mode: InterpretationMode) -> usize {
     let format = self.get_exec_format(interp_mode);
-    let new_subsystem = match format {
-      ExecFormat::DOS | ExecFormat::COM => Subsystem::DOS(DosSubsystemMetadata::new()),
-      _ => Subsystem::Native,
+    let is_dos = match format {
+      ExecFormat::DOS | ExecFormat::COM => true,
+      _ => false,
     };
 
     self.unmap_all();
+    self.get_signal_state().write().reset_handlers();
+    super::dos_mouse::forget(self.get_id());
+    super::dos_timer::forget(self.get_id());
+    super::dos_video::forget(self.get_id());
+    super::io_ports::forget(self.get_id());
+    super::fpu::forget(self.get_id());
+    *self.get_fpu_state().write() = None;
 
     let entry = match format {
       ExecFormat::BIN => {
@@ -107,8 +234,29 @@ impl ProcessState {
         panic!("Can't interpret MZ executables yet!");
       },
     };
-    *self.get_subsystem().write() = new_subsystem;
+    if is_dos {
+      // Every box has its own text memory, drawn onto its terminal
+      self.anonymous_map(VirtualAddress::new(super::dos_video::TEXT_MEMORY), super::dos_video::TEXT_MEMORY_SIZE);
+      self.enter_vm86(DosSubsystemMetadata::new());
+      super::vds::announce();
+      super::dos_timer::reset(self.get_id());
+      super::dos_video::reset(self.get_id());
+    } else {
+      *self.get_subsystem().write() = Subsystem::Native;
+    }
 
     entry
   }
 }
+
+/// Write the concatenation of `parts` as a NUL-terminated string, returning
+/// the address just past it
+unsafe fn write_string(address: usize, parts: &[&[u8]]) -> usize {
+  let mut cursor = address as *mut u8;
+  for part in parts {
+    core::ptr::copy_nonoverlapping(part.as_ptr(), cursor, part.len());
+    cursor = cursor.add(part.len());
+  }
+  *cursor = 0;
+  cursor as usize + 1
+}
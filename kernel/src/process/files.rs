@@ -1,18 +1,38 @@
-use crate::files::handle::{DriveHandlePair, FileHandle, FileHandleMap, LocalHandle};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::files::handle::{DriveHandlePair, FileHandle, FileHandleMap, Handle, LocalHandle};
+use crate::filesystems;
+use crate::kprintln;
+use super::all_processes;
 use super::process_state::ProcessState;
+use syscall::files::OpenFlags;
+use syscall::process::SPAWN_NO_HANDLE;
 use syscall::result::SystemError;
 
 impl ProcessState {
   // Files:
 
-  pub fn open_file(&self, drive: usize, local: LocalHandle) -> FileHandle {
+  pub fn open_file(&self, drive: usize, local: LocalHandle, flags: OpenFlags) -> FileHandle {
     let mut files = self.get_open_files().write();
-    match files.open_handle(drive, local) {
+    match files.open_handle(drive, local, flags) {
       Some(handle) => handle,
       None => panic!("Max open files exceeded"),
     }
   }
 
+  /// Record the full path a file was opened by, so it can be reported later
+  pub fn set_file_path(&self, handle: FileHandle, path: &str) {
+    let mut files = self.get_open_files().write();
+    files.set_path(handle, Arc::from(path));
+  }
+
+  /// The path a file was opened by, or None if it was not opened by name
+  pub fn get_open_file_path(&self, handle: FileHandle) -> Result<Option<Arc<str>>, SystemError> {
+    let files = self.get_open_files().read();
+    files.get_drive_and_handle(handle).ok_or(SystemError::BadFileDescriptor)?;
+    Ok(files.get_path(handle))
+  }
+
   pub fn close_file(&self, handle: FileHandle) -> Option<DriveHandlePair> {
     let mut files = self.get_open_files().write();
     files.close_handle(handle)
@@ -23,24 +43,61 @@ impl ProcessState {
     files.get_drive_and_handle(handle)
   }
 
+  /// Look up the filesystem handle behind a file handle, as long as the handle
+  /// was opened with the access an operation needs. Handles lacking it are
+  /// reported as bad descriptors, as POSIX does.
+  pub fn get_open_file_with_access(&self, handle: FileHandle, write: bool) -> Result<DriveHandlePair, SystemError> {
+    let files = self.get_open_files().read();
+    let pair = files.get_drive_and_handle(handle).ok_or(SystemError::BadFileDescriptor)?;
+    let flags = files.get_flags(handle).ok_or(SystemError::BadFileDescriptor)?;
+    let allowed = if write { flags.can_write() } else { flags.can_read() };
+    if !allowed {
+      return Err(SystemError::BadFileDescriptor);
+    }
+    Ok(pair)
+  }
+
+  pub fn get_open_file_flags(&self, handle: FileHandle) -> Option<OpenFlags> {
+    let files = self.get_open_files().read();
+    files.get_flags(handle)
+  }
+
   pub fn references_drive_and_handle(&self, drive: usize, local: LocalHandle) -> bool {
     let files = self.get_open_files().read();
     files.references_drive_and_handle(drive, local)
   }
 
   pub fn fork_file_map(&self) -> FileHandleMap {
-    let mut forked = FileHandleMap::new();
-    for (handle, pair) in self.get_open_files().read().iter() {
-      forked.set_handle_directly(handle, pair.0, pair.1);
+    self.get_open_files().read().clone()
+  }
+
+  /// Build the file map for a spawned child, which only receives the handles
+  /// it was explicitly given. The handle at each index of `handles` becomes
+  /// handle number `index` in the child; SPAWN_NO_HANDLE leaves it closed.
+  pub fn spawn_file_map(&self, handles: &[u32]) -> Result<FileHandleMap, SystemError> {
+    let files = self.get_open_files().read();
+    let mut child_files = FileHandleMap::new();
+    for (index, handle) in handles.iter().enumerate() {
+      if *handle == SPAWN_NO_HANDLE {
+        continue;
+      }
+      let parent_handle = FileHandle::new(*handle);
+      let pair = files.get_drive_and_handle(parent_handle).ok_or(SystemError::BadFileDescriptor)?;
+      let flags = files.get_flags(parent_handle).ok_or(SystemError::BadFileDescriptor)?;
+      let child_handle = FileHandle::new(index as u32);
+      child_files.set_handle_directly(child_handle, pair.0, pair.1, flags);
+      if let Some(path) = files.get_path(parent_handle) {
+        child_files.set_path(child_handle, path);
+      }
     }
-    forked
+    Ok(child_files)
   }
 
   // Directories:
 
   pub fn open_directory(&self, drive: usize, local: LocalHandle) -> Result<FileHandle, SystemError> {
     let mut dirs = self.get_open_directories().write();
-    match dirs.open_handle(drive, local) {
+    match dirs.open_handle(drive, local, OpenFlags::read_only()) {
       Some(handle) => Ok(handle),
       None => Err(SystemError::MaxFilesExceeded),
     }
@@ -57,10 +114,47 @@ impl ProcessState {
   }
 
   pub fn fork_directory_map(&self) -> FileHandleMap {
-    let mut forked = FileHandleMap::new();
-    for (handle, pair) in self.get_open_directories().read().iter() {
-      forked.set_handle_directly(handle, pair.0, pair.1);
+    self.get_open_directories().read().clone()
+  }
+
+  /// Close every file and directory handle held by the process, as it exits.
+  /// Filesystem handles still shared with other processes stay open.
+  pub fn close_all_handles(&self) {
+    let mut released: Vec<DriveHandlePair> = Vec::new();
+    for pair in self.take_all_handles() {
+      // Duplicated handles within the process only need to be released once
+      if !released.contains(&pair) {
+        released.push(pair);
+        // Closing writes back anything the filesystem was still holding.
+        // There is no caller left to return a failure to, so it is logged.
+        if release_handle(pair).is_err() {
+          kprintln!("Process {:?} exited, but a file on drive {} could not be written back", self.get_id(), pair.0);
+        }
+      }
     }
-    forked
   }
-}
\ No newline at end of file
+
+  /// Remove every file and directory handle from the process, returning the
+  /// drive and local handle each one referenced. The caller is responsible
+  /// for closing them.
+  pub fn take_all_handles(&self) -> Vec<DriveHandlePair> {
+    let mut pairs = Vec::new();
+    for map in [self.get_open_files(), self.get_open_directories()].iter() {
+      let mut handles = map.write();
+      pairs.extend(handles.iter().map(|(_, pair)| pair));
+      *handles = FileHandleMap::new();
+    }
+    pairs
+  }
+}
+
+/// Called after a process stops referencing a filesystem handle. The handle is
+/// only closed on its filesystem once no process refers to it; until then,
+/// releasing it succeeds without doing anything.
+pub fn release_handle(pair: DriveHandlePair) -> Result<(), SystemError> {
+  if all_processes().references_drive_and_handle(pair) {
+    return Ok(());
+  }
+  let fs = filesystems::get_fs(pair.0).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.close(pair.1).map_err(|_| SystemError::IOError)
+}
@@ -0,0 +1,171 @@
+//! Floating point registers are switched lazily. Most processes never touch
+//! the FPU, so rather than save and restore its state on every switch, the
+//! kernel sets CR0.TS whenever it runs a process that does not own the
+//! registers. The first floating point or SSE instruction that process runs
+//! raises the device-not-available exception, and only then is the previous
+//! owner's state saved and the new owner's state loaded.
+//!
+//! State is saved with FXSAVE when the processor supports it, since that also
+//! covers the SSE registers, and with FNSAVE otherwise. A process gets its
+//! save area the first time it loses the registers, so one that never uses
+//! floating point never allocates one. Machines without an FPU are left
+//! alone, and floating point instructions fault as they always have.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use crate::hardware::cpu::{self, Feature};
+use crate::x86::registers;
+use super::id::ProcessID;
+use super::process_state::ProcessState;
+
+/// CR0 bits controlling the FPU
+const CR0_MONITOR_COPROCESSOR: u32 = 1 << 1;
+const CR0_EMULATION: u32 = 1 << 2;
+const CR0_TASK_SWITCHED: u32 = 1 << 3;
+/// CR4 bit allowing FXSAVE to cover the SSE registers, and SSE instructions
+/// to run at all
+const CR4_OSFXSR: u32 = 1 << 9;
+
+/// MXCSR after reset: every SIMD exception masked, round to nearest
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// Raw ID used when no process owns the registers
+const NO_OWNER: u32 = 0xffffffff;
+
+/// Large enough for FXSAVE, which also requires 16-byte alignment. FNSAVE
+/// only uses the first 108 bytes.
+#[repr(C, align(16))]
+#[derive(Copy, Clone)]
+pub struct SaveArea([u8; 512]);
+
+impl SaveArea {
+  pub const fn new() -> SaveArea {
+    SaveArea([0; 512])
+  }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static USE_FXSR: AtomicBool = AtomicBool::new(false);
+
+/// Process whose state is currently in the registers
+static OWNER: AtomicU32 = AtomicU32::new(NO_OWNER);
+
+/// Set up the FPU for lazy switching. Must run after the CPU has been
+/// identified, and before the first process is entered.
+pub fn init() {
+  if !cpu::has_feature(Feature::Fpu) {
+    return;
+  }
+  let fxsr = cpu::has_feature(Feature::Fxsr);
+  if fxsr && cpu::has_feature(Feature::Sse) {
+    registers::set_cr4(registers::get_cr4() | CR4_OSFXSR);
+  }
+  USE_FXSR.store(fxsr, Ordering::SeqCst);
+  // With MP set, WAIT also honors TS, so every instruction that touches the
+  // FPU raises the exception
+  let cr0 = registers::get_cr0() & !CR0_EMULATION;
+  registers::set_cr0(cr0 | CR0_MONITOR_COPROCESSOR | CR0_TASK_SWITCHED);
+  ENABLED.store(true, Ordering::SeqCst);
+}
+
+fn set_task_switched() {
+  registers::set_cr0(registers::get_cr0() | CR0_TASK_SWITCHED);
+}
+
+fn clear_task_switched() {
+  unsafe {
+    llvm_asm!("clts" : : : : "volatile");
+  }
+}
+
+/// Called on every switch, before the next process runs. Only the owner can
+/// use the registers without trapping.
+pub fn prepare_switch(next: ProcessID) {
+  if !ENABLED.load(Ordering::SeqCst) {
+    return;
+  }
+  if OWNER.load(Ordering::SeqCst) == next.as_u32() {
+    clear_task_switched();
+  } else {
+    set_task_switched();
+  }
+}
+
+/// Copy the registers into a save area, leaving them intact
+unsafe fn save(area: &mut SaveArea) {
+  let ptr = area.0.as_mut_ptr();
+  if USE_FXSR.load(Ordering::SeqCst) {
+    llvm_asm!("fxsave [$0]" : : "r"(ptr) : "memory" : "intel", "volatile");
+  } else {
+    // FNSAVE reinitializes the FPU once it is done, so the state is loaded
+    // straight back
+    llvm_asm!("fnsave [$0]
+          frstor [$0]" : : "r"(ptr) : "memory" : "intel", "volatile");
+  }
+}
+
+unsafe fn restore(area: &SaveArea) {
+  let ptr = area.0.as_ptr();
+  if USE_FXSR.load(Ordering::SeqCst) {
+    llvm_asm!("fxrstor [$0]" : : "r"(ptr) : "memory" : "intel", "volatile");
+  } else {
+    llvm_asm!("frstor [$0]" : : "r"(ptr) : "memory" : "intel", "volatile");
+  }
+}
+
+/// Give the registers the state a new program starts with
+unsafe fn reset() {
+  llvm_asm!("fninit" : : : : "volatile");
+  if USE_FXSR.load(Ordering::SeqCst) && cpu::has_feature(Feature::Sse) {
+    let mxcsr = DEFAULT_MXCSR;
+    llvm_asm!("ldmxcsr dword ptr [$0]" : : "r"(&mxcsr) : : "intel", "volatile");
+  }
+}
+
+fn save_into(process: &ProcessState) {
+  let mut state = process.get_fpu_state().write();
+  let area = state.get_or_insert_with(|| Box::new(SaveArea::new()));
+  unsafe { save(area) };
+}
+
+/// Handle the device-not-available exception: hand the registers from their
+/// previous owner to the current process
+pub fn handle_unavailable() {
+  clear_task_switched();
+  let current = match super::current_process() {
+    Some(current) => current,
+    None => return,
+  };
+  let owner = OWNER.load(Ordering::SeqCst);
+  if owner == current.get_id().as_u32() {
+    return;
+  }
+  if owner != NO_OWNER {
+    if let Some(previous) = super::all_processes().get_process(ProcessID::new(owner)) {
+      save_into(&previous);
+    }
+  }
+  match *current.get_fpu_state().read() {
+    Some(ref area) => unsafe { restore(area) },
+    None => unsafe { reset() },
+  }
+  OWNER.store(current.get_id().as_u32(), Ordering::SeqCst);
+}
+
+/// State for a forked child, which continues with whatever its parent had
+pub fn fork_state(parent: &ProcessState) -> Option<Box<SaveArea>> {
+  if ENABLED.load(Ordering::SeqCst) && OWNER.load(Ordering::SeqCst) == parent.get_id().as_u32() {
+    // The parent is running, so the registers are usable without trapping
+    save_into(parent);
+  }
+  parent.get_fpu_state().read().clone()
+}
+
+/// Drop a process's claim on the registers, when it exits or replaces its
+/// program. The next process to use them starts from a fresh state rather
+/// than inherit what was left behind.
+pub fn forget(id: ProcessID) {
+  if OWNER.compare_exchange(id.as_u32(), NO_OWNER, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+    set_task_switched();
+  }
+}
@@ -0,0 +1,181 @@
+//! Programs can ask for direct access to specific I/O ports, so a driver that
+//! lives in userspace, like a utility that programs VGA modes, can talk to its
+//! hardware without a round trip through the kernel for every byte.
+//!
+//! Access is granted through the I/O permission bitmap at the end of the TSS.
+//! Each process that has been granted ports keeps a bitmap of its own, only as
+//! long as its highest granted port needs, and it is copied into the TSS when
+//! the process is switched in. Every other process runs with every port
+//! denied, so IN and OUT trap the way they always have. Switching between
+//! processes that hold no ports does not touch the TSS at all.
+//!
+//! Permissions belong to a single thread. They are not passed on by fork or
+//! spawn, and are dropped when the process runs a new program.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use crate::gdt;
+use crate::interrupts;
+use spin::Mutex;
+use syscall::result::SystemError;
+use super::id::ProcessID;
+
+/// Ports the kernel drives itself, as inclusive ranges. A program writing to
+/// these could confuse the kernel's view of the hardware, or take over the
+/// machine outright, so they are never handed out.
+const KERNEL_PORTS: [(u16, u16); 14] = [
+  (0x00, 0x1f), // DMA controller 1
+  (0x20, 0x21), // Master PIC
+  (0x40, 0x43), // PIT
+  (0x60, 0x64), // Keyboard controller
+  (0x70, 0x71), // CMOS and RTC
+  (0x80, 0x8f), // DMA page registers
+  (0xa0, 0xa1), // Slave PIC
+  (0xc0, 0xdf), // DMA controller 2
+  (0xe9, 0xe9), // QEMU debug console
+  (0xf4, 0xf4), // QEMU exit device
+  (0x1f0, 0x1f7), // Primary ATA channel
+  (0x2f8, 0x2ff), // COM2
+  (0x3f0, 0x3f7), // Floppy controller, and the ATA control register
+  (0x3f8, 0x3ff), // COM1
+];
+
+/// Raw ID used when the TSS holds no process's permissions
+const NO_OWNER: u32 = 0xffffffff;
+
+/// Process whose permissions are in the TSS
+static LOADED_OWNER: AtomicU32 = AtomicU32::new(NO_OWNER);
+/// Bytes at the start of the TSS bitmap that may have been cleared
+static LOADED_LENGTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Set bits deny a port, the same as in the TSS
+struct IoPermissions {
+  bitmap: Vec<u8>,
+}
+
+impl IoPermissions {
+  fn new() -> IoPermissions {
+    IoPermissions {
+      bitmap: Vec::new(),
+    }
+  }
+
+  fn set(&mut self, first: u16, last: u16, allowed: bool) {
+    let needed = last as usize / 8 + 1;
+    if allowed && self.bitmap.len() < needed {
+      self.bitmap.resize(needed, 0xff);
+    }
+    for port in first..=last {
+      let byte = port as usize / 8;
+      if byte >= self.bitmap.len() {
+        break;
+      }
+      let bit = 1 << (port % 8);
+      if allowed {
+        self.bitmap[byte] &= !bit;
+      } else {
+        self.bitmap[byte] |= bit;
+      }
+    }
+    // Trailing bytes that deny everything do not need to be copied on switch
+    while self.bitmap.last() == Some(&0xff) {
+      self.bitmap.pop();
+    }
+  }
+}
+
+/// Processes holding ports. Few ever do, so they are found by a linear search.
+static PERMISSIONS: Mutex<Vec<(ProcessID, IoPermissions)>> = Mutex::new(Vec::new());
+
+/// Run `f` with the permissions locked. The TSS is reloaded on every switch,
+/// which can happen from the timer interrupt, so interrupts stay off while the
+/// lock is held.
+fn with_permissions<R, F: FnOnce(&mut Vec<(ProcessID, IoPermissions)>) -> R>(f: F) -> R {
+  let int_reenable = interrupts::is_interrupt_enabled();
+  interrupts::cli();
+  let result = f(&mut PERMISSIONS.lock());
+  if int_reenable {
+    interrupts::sti();
+  }
+  result
+}
+
+fn is_kernel_port(first: u16, last: u16) -> bool {
+  KERNEL_PORTS.iter().any(|&(start, end)| first <= end && last >= start)
+}
+
+/// Allow or deny the ports from `first` through `last` for a process.
+/// Granting any port the kernel uses itself is refused. Denying is always
+/// allowed.
+pub fn set_access(pid: ProcessID, first: u16, last: u16, allowed: bool) -> Result<(), SystemError> {
+  if last < first {
+    return Err(SystemError::InvalidArgument);
+  }
+  if allowed && is_kernel_port(first, last) {
+    return Err(SystemError::PermissionDenied);
+  }
+  let is_current = super::get_current_pid() == pid;
+  with_permissions(|permissions| {
+    let index = match permissions.iter().position(|(id, _)| *id == pid) {
+      Some(index) => index,
+      None if allowed => {
+        permissions.push((pid, IoPermissions::new()));
+        permissions.len() - 1
+      },
+      None => return,
+    };
+    permissions[index].1.set(first, last, allowed);
+    if permissions[index].1.bitmap.is_empty() {
+      permissions.swap_remove(index);
+    }
+    // A process changing its own ports sees the change right away
+    if is_current || LOADED_OWNER.load(Ordering::SeqCst) == pid.as_u32() {
+      load(permissions, pid);
+    }
+  });
+  Ok(())
+}
+
+/// Put a process's permissions in the TSS, replacing whatever was there
+fn load(permissions: &Vec<(ProcessID, IoPermissions)>, pid: ProcessID) {
+  let bitmap = permissions.iter().find(|(id, _)| *id == pid).map(|(_, ports)| &ports.bitmap);
+  unsafe {
+    gdt::deny_io_ports(LOADED_LENGTH.load(Ordering::SeqCst));
+    match bitmap {
+      Some(bitmap) => {
+        gdt::set_io_bitmap(bitmap);
+        LOADED_LENGTH.store(bitmap.len(), Ordering::SeqCst);
+        LOADED_OWNER.store(pid.as_u32(), Ordering::SeqCst);
+      },
+      None => {
+        LOADED_LENGTH.store(0, Ordering::SeqCst);
+        LOADED_OWNER.store(NO_OWNER, Ordering::SeqCst);
+      },
+    }
+  }
+}
+
+/// Called just before switching to `next`, to give it its own ports
+pub fn prepare_switch(next: ProcessID) {
+  let owner = LOADED_OWNER.load(Ordering::SeqCst);
+  if owner == next.as_u32() {
+    return;
+  }
+  with_permissions(|permissions| {
+    if owner == NO_OWNER && !permissions.iter().any(|(id, _)| *id == next) {
+      // Nothing is loaded, and nothing needs to be
+      return;
+    }
+    load(permissions, next);
+  });
+}
+
+/// Drop a process's ports, when it exits or replaces its program
+pub fn forget(pid: ProcessID) {
+  with_permissions(|permissions| {
+    permissions.retain(|(id, _)| *id != pid);
+    if LOADED_OWNER.load(Ordering::SeqCst) == pid.as_u32() {
+      load(permissions, pid);
+    }
+  });
+}
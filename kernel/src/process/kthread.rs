@@ -0,0 +1,163 @@
+//! Kernel threads run long-lived kernel code, like driver loops and worker
+//! pools. Unlike a forked kernel process, a thread is not a copy of anything:
+//! it runs in the kernel process's page directory, tracks no user memory,
+//! holds no handles, and starts on a small stack of its own. Threads are still
+//! entries in the process map, so they are scheduled, blocked, and signalled
+//! like any other process.
+//!
+//! Stacks are carved out of a reserved range of kernel address space, which
+//! only the kernel's page directory maps. Each stack sits above an unmapped
+//! guard page, so that an overflow faults rather than running into the next
+//! stack. Once mapped, a stack's frames are kept and reused by the next
+//! thread to take its slot.
+
+use alloc::vec::Vec;
+use crate::memory::address::{PhysicalAddress, VirtualAddress};
+use crate::memory::physical;
+use crate::memory::physical::frame::Frame;
+use crate::memory::virt::page_directory::{self, AlternatePageDirectory, PageDirectory, PermissionFlags};
+use crate::memory::virt::region::{ExpansionDirection, MemoryRegionType, Permissions, VirtualMemoryRegion};
+use spin::Mutex;
+use super::id::ProcessID;
+use super::process_state::ProcessState;
+
+/// Kernel thread stacks fill the page table below the one holding the kernel
+/// stack and temporary page
+pub const STACKS_START: usize = 0xff400000;
+const STACKS_END: usize = 0xff800000;
+pub const STACK_PAGES: usize = 8;
+/// Each slot is a guard page followed by the stack
+const SLOT_SIZE: usize = (STACK_PAGES + 1) * 0x1000;
+pub const MAX_THREADS: usize = (STACKS_END - STACKS_START) / SLOT_SIZE;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KernelThreadError {
+  /// Every stack slot is taken
+  TooManyThreads,
+  /// There were no free frames for the stack
+  OutOfMemory,
+}
+
+struct StackSlot {
+  /// Thread currently running on the stack
+  owner: Option<ProcessID>,
+  /// Frame backing the top page of the stack, where the entry is written
+  top_frame: PhysicalAddress,
+}
+
+static SLOTS: Mutex<Vec<StackSlot>> = Mutex::new(Vec::new());
+
+fn stack_region(slot: usize) -> VirtualMemoryRegion {
+  let start = STACKS_START + slot * SLOT_SIZE + 0x1000;
+  VirtualMemoryRegion::new(
+    VirtualAddress::new(start),
+    STACK_PAGES * 0x1000,
+    MemoryRegionType::Anonymous(ExpansionDirection::None),
+    Permissions::ReadWrite,
+  )
+}
+
+/// Whether an address is in the unmapped page at the bottom of a slot
+pub fn is_guard_page(addr: usize) -> bool {
+  if addr < STACKS_START || addr >= STACKS_START + MAX_THREADS * SLOT_SIZE {
+    return false;
+  }
+  (addr - STACKS_START) % SLOT_SIZE < 0x1000
+}
+
+/// The kernel process is always the first one, and its directory holds every
+/// thread's stack
+fn get_kernel_process() -> alloc::sync::Arc<ProcessState> {
+  match super::all_processes().get_process(ProcessID::new(0)) {
+    Some(kernel) => kernel.clone(),
+    None => panic!("Kernel process does not exist"),
+  }
+}
+
+/// Map a new stack into the kernel's page directory, returning the frame at
+/// its top. Frames already mapped when memory runs out stay in place, and
+/// are overwritten if the slot is tried again.
+fn map_stack(directory: PhysicalAddress, region: VirtualMemoryRegion) -> Result<PhysicalAddress, KernelThreadError> {
+  let pagedir = AlternatePageDirectory::new(directory);
+  let mut top_frame = PhysicalAddress::new(0);
+  let mut page = region.get_starting_address();
+  for _ in 0..STACK_PAGES {
+    let frame = physical::allocate_frame().map_err(|_| KernelThreadError::OutOfMemory)?;
+    top_frame = frame.get_address();
+    pagedir.map(frame, page, PermissionFlags::new(PermissionFlags::WRITE_ACCESS));
+    page = page.offset(0x1000);
+  }
+  Ok(top_frame)
+}
+
+/// Claim a free slot for `pid`, mapping its stack if it has never been used
+fn claim_slot(pid: ProcessID, directory: PhysicalAddress) -> Result<(usize, PhysicalAddress), KernelThreadError> {
+  let mut slots = SLOTS.lock();
+  if let Some(index) = slots.iter().position(|slot| slot.owner.is_none()) {
+    slots[index].owner = Some(pid);
+    return Ok((index, slots[index].top_frame));
+  }
+  let index = slots.len();
+  if index >= MAX_THREADS {
+    return Err(KernelThreadError::TooManyThreads);
+  }
+  let top_frame = map_stack(directory, stack_region(index))?;
+  slots.push(StackSlot {
+    owner: Some(pid),
+    top_frame,
+  });
+  Ok((index, top_frame))
+}
+
+/// Start a kernel thread that runs `entry`. The thread is named for PROC and
+/// debugging, and exits when `entry` returns. It is runnable immediately, and
+/// first runs the next time the current process yields.
+pub fn spawn_kthread(entry: extern fn(), name: &str) -> Result<ProcessID, KernelThreadError> {
+  let kernel = get_kernel_process();
+  let directory = kernel.get_page_directory().get_address();
+  let pid = super::all_processes().get_next_pid();
+  let (slot, top_frame) = claim_slot(pid, directory)?;
+  let stack = stack_region(slot);
+
+  // The thread's first switch returns into the trampoline at the top of its
+  // stack, the same way a forked kernel process starts
+  page_directory::map_frame_to_temporary_page(Frame::new(top_frame.as_usize()));
+  let temp_page = page_directory::get_temporary_page_address().as_usize();
+  unsafe {
+    let entry_slot = (temp_page + 0xff8) as *mut usize;
+    *entry_slot = run_kthread as usize;
+  }
+
+  let thread = ProcessState::kernel_thread(pid, &kernel, stack, entry, name);
+  super::all_processes_mut().add_process(pid, thread);
+  Ok(pid)
+}
+
+#[inline(never)]
+extern "C" fn run_kthread() {
+  let entry = super::current_process().and_then(|current| current.get_kernel_thread_entry());
+  if let Some(entry) = entry {
+    entry();
+  }
+  super::exit(0);
+}
+
+/// Remove threads that have exited, and free their stacks for reuse. Must not
+/// be called from a kernel thread, since it may be the one being removed.
+/// The slots are always locked before the process map.
+pub fn reap_exited() {
+  let mut slots = SLOTS.lock();
+  let mut processes = super::all_processes_mut();
+  for slot in slots.iter_mut() {
+    if let Some(pid) = slot.owner {
+      let exited = match processes.get_process(pid) {
+        Some(thread) => thread.is_terminated(),
+        None => true,
+      };
+      if exited {
+        processes.reap(pid);
+        slot.owner = None;
+      }
+    }
+  }
+}
@@ -1,9 +1,22 @@
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU32, Ordering};
+use crate::files::handle::DriveHandlePair;
 use crate::memory::address::VirtualAddress;
 use super::id::ProcessID;
 use super::process_state::ProcessState;
+use super::queue;
+
+/// Outcome of registering to wait on a child process
+pub enum WaitResult {
+  /// A child had already exited, and has been reaped
+  Exited(ProcessID, u32),
+  /// The current process is now blocked until a child exits
+  Blocked,
+  /// There is no matching child to wait on
+  NoChild,
+}
 
 /**
  * Mapping of PIDs to process structures.
@@ -33,33 +46,18 @@ impl ProcessMap {
     self.processes.iter()
   }
 
+  /// Choose the next process to run, in round-robin order. If nothing else
+  /// is runnable, the current process keeps running.
   pub fn get_next_running_process(&self) -> ProcessID {
-    let mut first = None;
-    for (pid, process) in self.processes.iter() {
-      if process.is_running() {
-        if first.is_none() {
-          first = Some(*pid);
-        }
-        if *pid > self.current {
-          return *pid;
-        }
-      }
-    }
-    // If we hit the end of the list, loop back to the first running process
-    // we found. If there is none, we stay on the current process.
-    match first {
-      Some(pid) => pid,
-      None => self.current,
-    }
+    let current = self.current;
+    queue::with_queues(|queues| queues.next_ready(current)).unwrap_or(current)
   }
-  
 
   pub fn spawn_first_process(&mut self, heap_location: VirtualAddress) -> ProcessID {
     let pid = self.get_next_pid();
-    self.processes.insert(
-      pid,
-      Arc::new(ProcessState::first(pid, heap_location))
-    );
+    let first = ProcessState::first(pid, heap_location);
+    queue::add(pid, first.get_run_state());
+    self.processes.insert(pid, Arc::new(first));
     pid
   }
 
@@ -67,13 +65,18 @@ impl ProcessMap {
     let pid = self.get_next_pid();
     let cur = self.get_current_process().expect("No current process to fork");
     let next = cur.fork(pid);
-    self.processes.insert(
-      pid,
-      Arc::new(next),
-    );
+    queue::add(pid, next.get_run_state());
+    self.processes.insert(pid, Arc::new(next));
     pid
   }
 
+  /// Add a process that was created from scratch rather than forked, such as
+  /// a kernel thread
+  pub fn add_process(&mut self, pid: ProcessID, process: ProcessState) {
+    queue::add(pid, process.get_run_state());
+    self.processes.insert(pid, Arc::new(process));
+  }
+
   pub fn get_process(&self, pid: ProcessID) -> Option<&Arc<ProcessState>> {
     self.processes.get(&pid)
   }
@@ -82,11 +85,150 @@ impl ProcessMap {
     self.processes.get(&self.current)
   }
 
+  /// Determine whether a process is `ancestor` itself, or was forked from it
+  /// at any depth
+  pub fn is_descendant(&self, pid: ProcessID, ancestor: ProcessID) -> bool {
+    let mut current = pid;
+    loop {
+      if current == ancestor {
+        return true;
+      }
+      let parent = match self.processes.get(&current) {
+        Some(process) => process.get_parent(),
+        None => return false,
+      };
+      // The first process is its own parent
+      if parent == current {
+        return false;
+      }
+      current = parent;
+    }
+  }
+
+  /// Iterate over every process directly forked from `parent`
+  pub fn children_of(&self, parent: ProcessID) -> impl Iterator<Item = (&ProcessID, &Arc<ProcessState>)> {
+    self.processes.iter().filter(move |(pid, process)| {
+      **pid != parent && process.get_parent() == parent
+    })
+  }
+
+  /// Hand every child of `parent` to `new_parent`, returning the IDs of those
+  /// that have already terminated, since the new parent has not yet been told
+  /// about them
+  pub fn reparent_children(&self, parent: ProcessID, new_parent: ProcessID) -> Vec<ProcessID> {
+    let mut exited = Vec::new();
+    for (pid, process) in self.children_of(parent) {
+      process.set_parent(new_parent);
+      if process.is_terminated() {
+        exited.push(*pid);
+      }
+    }
+    exited
+  }
+
+  /// Collect every live process in a group
+  pub fn group_members(&self, group: ProcessID) -> Vec<Arc<ProcessState>> {
+    self.processes.values()
+      .filter(|process| process.get_process_group() == group && !process.is_terminated())
+      .cloned()
+      .collect()
+  }
+
+  /// Collect every live thread in a thread group, including its leader
+  pub fn thread_group_members(&self, group: ProcessID) -> Vec<Arc<ProcessState>> {
+    self.processes.values()
+      .filter(|process| process.get_thread_group() == group && !process.is_terminated())
+      .cloned()
+      .collect()
+  }
+
+  /// Determine whether any live process in `session` belongs to `group`
+  pub fn group_exists_in_session(&self, group: ProcessID, session: ProcessID) -> bool {
+    self.processes.values().any(|process| {
+      process.get_process_group() == group
+        && process.get_session() == session
+        && !process.is_terminated()
+    })
+  }
+
+  /// Find a child of `parent` that has terminated but not yet been reaped. If
+  /// `child` is set, only that specific process is considered.
+  pub fn find_terminated_child(&self, parent: ProcessID, child: Option<ProcessID>) -> Option<ProcessID> {
+    self.children_of(parent)
+      .filter(|(pid, _)| child.map_or(true, |id| **pid == id))
+      .find(|(_, process)| process.is_terminated())
+      .map(|(pid, _)| *pid)
+  }
+
+  /// Remove a terminated process from the map, returning its exit status.
+  /// Processes that are still alive are left in place.
+  pub fn reap(&mut self, pid: ProcessID) -> Option<u32> {
+    if !self.processes.get(&pid)?.is_terminated() {
+      return None;
+    }
+    self.processes.remove(&pid).map(|process| process.get_exit_code())
+  }
+
+  /// Register the current process as waiting on one of its children, or on
+  /// any child if `child` is None. If a matching child has already exited, it
+  /// is reaped immediately and its ID and exit status are returned without
+  /// blocking. Otherwise the current process is blocked until a child exits,
+  /// and the caller should yield and then try again.
+  pub fn wait_on_child(&mut self, child: Option<ProcessID>) -> WaitResult {
+    let parent = self.current;
+    if let Some(pid) = self.find_terminated_child(parent, child) {
+      return match self.reap(pid) {
+        Some(code) => WaitResult::Exited(pid, code),
+        None => WaitResult::NoChild,
+      };
+    }
+    let has_child = self.children_of(parent)
+      .any(|(pid, _)| child.map_or(true, |id| *pid == id));
+    if !has_child {
+      return WaitResult::NoChild;
+    }
+    match self.get_current_process() {
+      Some(current) => {
+        current.block_on_child(child);
+        WaitResult::Blocked
+      },
+      None => WaitResult::NoChild,
+    }
+  }
+
+  /// Determine whether any process still holds a file or directory handle
+  /// pointing at a filesystem handle. Forked and duplicated handles share the
+  /// same filesystem handle, which can only be closed once none remain.
+  pub fn references_drive_and_handle(&self, pair: DriveHandlePair) -> bool {
+    self.processes.values().any(|process| {
+      process.get_open_files().read().references_drive_and_handle(pair.0, pair.1)
+        || process.get_open_directories().read().references_drive_and_handle(pair.0, pair.1)
+    })
+  }
+
+  /// Check whether any process holds a file or directory open on a drive
+  pub fn references_drive(&self, drive: usize) -> bool {
+    self.processes.values().any(|process| {
+      process.get_open_files().read().references_drive(drive)
+        || process.get_open_directories().read().references_drive(drive)
+    })
+  }
+
+  /// Count the files and directories open on a drive across every process.
+  /// Threads share their leader's handles, so only leaders are counted.
+  pub fn count_drive_handles(&self, drive: usize) -> usize {
+    self.processes.values().filter(|process| !process.is_thread()).map(|process| {
+      process.get_open_files().read().count_drive_handles(drive)
+        + process.get_open_directories().read().count_drive_handles(drive)
+    }).sum()
+  }
+
   pub fn get_current_pid(&self) -> ProcessID {
     self.current
   }
 
   pub fn make_current(&mut self, pid: ProcessID) {
     self.current = pid;
+    super::shared_data::set_current_pid(pid.as_u32());
   }
 }
\ No newline at end of file
@@ -1,11 +1,13 @@
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use crate::files::handle::LocalHandle;
+use crate::filesystems;
 use crate::memory::{
   address::{PhysicalAddress, VirtualAddress},
-  heap::INITIAL_HEAP_SIZE,
+  heap::{self, INITIAL_HEAP_SIZE},
   physical::{self, frame_range::FrameRange},
   virt::{
-    page_directory::{AlternatePageDirectory, CurrentPageDirectory, self},
+    page_directory::{AlternatePageDirectory, CurrentPageDirectory, PageDirectory, PermissionFlags, self},
     page_table::{PageTable, PageTableReference},
     region::{
       ExpansionDirection,
@@ -16,16 +18,29 @@ use crate::memory::{
   },
 };
 use spin::RwLock;
+use super::id::ProcessID;
 use super::process_state::ProcessState;
 
-/// The kernel stack extends from 0xffbf0000 to 0xffbfefff
-pub const STACK_START: VirtualAddress = VirtualAddress::new(0xffbf0000);
+/// The page below the kernel stack is never mapped, so overflowing the stack
+/// faults instead of running into the thread stacks beneath it
+pub const STACK_GUARD: VirtualAddress = VirtualAddress::new(0xffbf0000);
+
+/// The kernel stack extends from 0xffbf1000 to 0xffbfefff
+pub const STACK_START: VirtualAddress = VirtualAddress::new(0xffbf1000);
 pub const STACK_SIZE: usize = 0xffbff000 - STACK_START.as_usize();
 
+/// The user stack grows down as it is used, up to this many bytes
+pub const MAX_USER_STACK_SIZE: usize = 0x100000;
+
+/// Where the kernel places mappings that do not ask for an address. They
+/// stay below 0x80000000, so an address is never mistaken for an error code.
+const MAP_AREA_START: usize = 0x400000;
+const MAP_AREA_END: usize = 0x80000000;
+
 static KERNEL_HEAP: RwLock<VirtualMemoryRegion> =
   RwLock::new(
     VirtualMemoryRegion::new(
-      VirtualAddress::new(0),
+      VirtualAddress::new(heap::HEAP_START),
       INITIAL_HEAP_SIZE * 0x1000,
       MemoryRegionType::Anonymous(ExpansionDirection::After),
       Permissions::ReadOnly,
@@ -35,14 +50,22 @@ static KERNEL_HEAP: RwLock<VirtualMemoryRegion> =
 /// Store custom memmap regions shared between all processes in kernel space
 static KERNEL_MEMMAP: RwLock<Vec<VirtualMemoryRegion>> = RwLock::new(Vec::new());
 
-/// Increase the kernel heap range, returning the new range size
+/// Increase the kernel heap range, returning the new range size. The new
+/// pages are backed right away, since the allocator writes to them as soon as
+/// it takes them over. If memory runs out, the size may not change at all.
 pub fn expand_kernel_heap(min_space_needed: usize) -> usize {
-  let mut frames_needed = min_space_needed / 0x1000;
+  let mut frames_needed = (min_space_needed + 0xfff) / 0x1000;
   if frames_needed < INITIAL_HEAP_SIZE {
     frames_needed = INITIAL_HEAP_SIZE;
   }
   let mut heap = KERNEL_HEAP.write();
-  heap.expand(frames_needed)
+  let heap_end = heap.get_starting_address_as_usize() + heap.get_size();
+  let mapped = heap::map_heap_pages(VirtualAddress::new(heap_end), frames_needed);
+  heap.expand(mapped)
+}
+
+pub fn get_kernel_heap_size() -> usize {
+  KERNEL_HEAP.read().get_size()
 }
 
 pub struct MemoryRegions {
@@ -51,6 +74,9 @@ pub struct MemoryRegions {
   pub heap_region: VirtualMemoryRegion,
   pub stack_region: VirtualMemoryRegion,
   pub execution_regions: Vec<VirtualMemoryRegion>,
+  /// Kernel stacks mapped for extra threads, by slot, and the thread using
+  /// each one
+  pub thread_stacks: BTreeMap<usize, Option<ProcessID>>,
 }
 
 impl MemoryRegions {
@@ -92,6 +118,43 @@ impl MemoryRegions {
       ),
 
       execution_regions,
+      thread_stacks: BTreeMap::new(),
+    }
+  }
+
+  /// A spawned process has a kernel stack of its own, and no user memory
+  /// until its program is loaded
+  pub fn for_spawn(&self) -> MemoryRegions {
+    MemoryRegions {
+      kernel_stack_region: VirtualMemoryRegion::new(
+        STACK_START,
+        STACK_SIZE,
+        MemoryRegionType::Anonymous(ExpansionDirection::None),
+        Permissions::ReadWrite,
+      ),
+      kernel_exec_region: self.kernel_exec_region.copy_for_new_process(),
+      heap_region: VirtualMemoryRegion::empty(),
+      stack_region: VirtualMemoryRegion::new(
+        VirtualAddress::new(0xc0000000 - 0x2000),
+        0x2000,
+        MemoryRegionType::Anonymous(ExpansionDirection::Before),
+        Permissions::ReadWrite,
+      ),
+      execution_regions: Vec::new(),
+      thread_stacks: BTreeMap::new(),
+    }
+  }
+
+  /// Kernel threads run entirely in kernel memory, so only their stack is
+  /// tracked
+  pub fn kernel_thread(stack: VirtualMemoryRegion) -> MemoryRegions {
+    MemoryRegions {
+      kernel_stack_region: stack,
+      kernel_exec_region: VirtualMemoryRegion::empty(),
+      heap_region: VirtualMemoryRegion::empty(),
+      stack_region: VirtualMemoryRegion::empty(),
+      execution_regions: Vec::new(),
+      thread_stacks: BTreeMap::new(),
     }
   }
 
@@ -106,15 +169,17 @@ impl MemoryRegions {
     let stack_region = self.stack_region.copy_for_new_process();
     let execution_regions = self.execution_regions
       .iter()
-      .map(|&range| range.copy_for_new_process())
+      .map(|&range| duplicate_mapping(range.copy_for_new_process()))
       .collect();
 
+    // Other threads' stacks are not copied, and the new process has none
     MemoryRegions {
       kernel_stack_region,
       kernel_exec_region,
       heap_region,
       stack_region,
       execution_regions,
+      thread_stacks: BTreeMap::new(),
     }
   }
 
@@ -131,6 +196,13 @@ impl MemoryRegions {
       return Some(kernel_stack.clone());
     }
 
+    for slot in self.thread_stacks.keys() {
+      let thread_stack = super::thread::stack_region(*slot);
+      if thread_stack.contains_address(addr) {
+        return Some(thread_stack);
+      }
+    }
+
     let kernel_exec = self.kernel_exec_region;
     if kernel_exec.contains_address(addr) {
       return Some(kernel_exec.clone());
@@ -145,6 +217,12 @@ impl MemoryRegions {
       }
     }
 
+    if let Some(shared_data) = super::shared_data::get_region() {
+      if shared_data.contains_address(addr) {
+        return Some(shared_data);
+      }
+    }
+
     let heap = self.heap_region;
     if heap.contains_address(addr) {
       return Some(heap.clone());
@@ -163,40 +241,138 @@ impl MemoryRegions {
 
     None
   }
+
+  /// Whether no user region besides the stack overlaps the range from `start`
+  /// up to `end`
+  fn is_user_range_free(&self, start: usize, end: usize) -> bool {
+    let overlaps = |region: &VirtualMemoryRegion| {
+      let region_start = region.get_starting_address_as_usize();
+      region.get_size() > 0 && region_start < end && start < region_start + region.get_size()
+    };
+    !overlaps(&self.heap_region) && !self.execution_regions.iter().any(overlaps)
+  }
+
+  /// Find the highest free range of `length` bytes in the area for mappings
+  fn find_free_map_range(&self, length: usize) -> Option<usize> {
+    let mut end = MAP_AREA_END;
+    while end >= MAP_AREA_START + length {
+      let start = end - length;
+      if self.is_user_range_free(start, end) {
+        return Some(start);
+      }
+      // Try again just below whatever is in the way
+      let blocking = core::iter::once(&self.heap_region)
+        .chain(self.execution_regions.iter())
+        .filter(|region| {
+          let region_start = region.get_starting_address_as_usize();
+          region.get_size() > 0 && region_start < end && start < region_start + region.get_size()
+        })
+        .map(|region| region.get_starting_address_as_usize())
+        .min()?;
+      end = blocking;
+    }
+    None
+  }
+}
+
+/// A forked process gets its own handle to each mapped file, so either
+/// process can unmap it without affecting the other. If the file cannot be
+/// reopened, pages not yet read are filled with zeroes instead.
+fn duplicate_mapping(region: VirtualMemoryRegion) -> VirtualMemoryRegion {
+  if let MemoryRegionType::FileBacked(drive, handle, offset, length) = region.backing_type() {
+    let duplicate = filesystems::get_fs(drive).and_then(|fs| fs.dup(handle).ok());
+    let backed_by = match duplicate {
+      Some(new_handle) => MemoryRegionType::FileBacked(drive, new_handle, offset, length),
+      None => MemoryRegionType::Anonymous(ExpansionDirection::None),
+    };
+    return region.copy_with_backing(backed_by);
+  }
+  region
+}
+
+/// Close the file behind a mapping, once the mapping is gone
+fn release_mapping(region: &VirtualMemoryRegion) {
+  if let MemoryRegionType::FileBacked(drive, handle, _, _) = region.backing_type() {
+    if let Some(fs) = filesystems::get_fs(drive) {
+      let _ = fs.close(handle);
+    }
+  }
+}
+
+/// Whether an address is in one of the guard pages below the kernel stacks of
+/// processes, threads, and kernel threads
+pub fn is_stack_guard(addr: usize) -> bool {
+  let page = addr & 0xfffff000;
+  page == STACK_GUARD.as_usize()
+    || super::thread::is_guard_page(addr)
+    || super::kthread::is_guard_page(addr)
+}
+
+/// Allocate a page directory that maps nothing but itself, the tables shared
+/// by every directory, and an empty top table for the temporary page and
+/// kernel stack. Returns the directory's physical address.
+fn create_page_directory() -> PhysicalAddress {
+  let temp_page_address = page_directory::get_temporary_page_address();
+
+  // Create the top page, which will contain the temp page and kernel stack
+  let top_page = physical::allocate_frame().unwrap();
+  page_directory::map_frame_to_temporary_page(top_page);
+  PageTable::at_address(temp_page_address).zero();
+
+  // Create the new page directory
+  let directory_frame = physical::allocate_frame().unwrap();
+  page_directory::map_frame_to_temporary_page(directory_frame);
+  let directory_table = PageTable::at_address(temp_page_address);
+  directory_table.zero();
+
+  // Map the directory table to itself
+  directory_table.get_mut(1023).set_address(directory_frame.get_address());
+  directory_table.get_mut(1023).set_present();
+  // Map the top page
+  directory_table.get_mut(1022).set_address(top_page.get_address());
+  directory_table.get_mut(1022).set_present();
+  // The kernel image and heap live in tables shared by every directory
+  page_directory::share_kernel_tables(directory_table);
+
+  directory_frame.get_address()
+}
+
+/// Build the page directory for a spawned process, which starts with no user
+/// memory at all. Its kernel stack is mapped with fresh frames, and `entry` is
+/// written at the top for the first switch to return into.
+pub fn spawn_page_directory(entry: usize) -> PageTableReference {
+  let directory_address = create_page_directory();
+  let new_page_dir = AlternatePageDirectory::new(directory_address);
+  let temp_page_address = page_directory::get_temporary_page_address();
+  let mut page = STACK_START;
+  let mut top_frame = None;
+  while page.as_usize() < STACK_START.as_usize() + STACK_SIZE {
+    let frame = physical::allocate_frame().unwrap();
+    new_page_dir.map(frame, page, PermissionFlags::new(PermissionFlags::WRITE_ACCESS));
+    top_frame = Some(frame);
+    page = page.offset(0x1000);
+  }
+  if let Some(frame) = top_frame {
+    page_directory::map_frame_to_temporary_page(frame);
+    unsafe {
+      let entry_slot = (temp_page_address.as_usize() + 0xff8) as *mut usize;
+      *entry_slot = entry;
+    }
+  }
+  PageTableReference::new(directory_address)
 }
 
 impl ProcessState {
   pub fn fork_page_directory(&self) -> PageTableReference {
-    let temp_page_address = page_directory::get_temporary_page_address();
-
-    // Create the top page, which will contain the temp page and kernel stack
-    let top_page = physical::allocate_frame().unwrap();
-    page_directory::map_frame_to_temporary_page(top_page);
-    PageTable::at_address(temp_page_address).zero();
-
-    // Create the new page directory
-    let directory_frame = physical::allocate_frame().unwrap();
-    page_directory::map_frame_to_temporary_page(directory_frame);
-    let directory_table = PageTable::at_address(temp_page_address);
-    directory_table.zero();
-
-    // Map the directory table to itself
-    directory_table.get_mut(1023).set_address(directory_frame.get_address());
-    directory_table.get_mut(1023).set_present();
-    // Map the top page
-    directory_table.get_mut(1022).set_address(top_page.get_address());
-    directory_table.get_mut(1022).set_present();
+    let directory_address = create_page_directory();
 
     // Map each of the ranges
-    let new_page_dir = AlternatePageDirectory::new(directory_frame.get_address());
-    {
-      let kernel_heap = *KERNEL_HEAP.read();
-      new_page_dir.map_region(kernel_heap);
-    }
+    let new_page_dir = AlternatePageDirectory::new(directory_address);
     {
       let regions = self.get_memory_regions().read();
-      new_page_dir.map_region(regions.kernel_stack_region);
-      new_page_dir.map_region(regions.kernel_exec_region);
+      // A thread forking runs on its own stack, which becomes the new
+      // process's only kernel stack
+      new_page_dir.map_region(self.get_kernel_stack());
       new_page_dir.map_region(regions.stack_region);
       new_page_dir.map_region(regions.heap_region);
 
@@ -205,7 +381,7 @@ impl ProcessState {
       }
     }
 
-    PageTableReference::new(directory_frame.get_address())
+    PageTableReference::new(directory_address)
   }
 
   pub fn unmap_all(&self) {
@@ -214,10 +390,85 @@ impl ProcessState {
     while regions.execution_regions.len() > 0 {
       if let Some(region) = regions.execution_regions.pop() {
         current_pagedir.unmap_region(region);
+        release_mapping(&region);
       }
     }
   }
 
+  /// Close the files behind every mapping, for a process that is exiting.
+  /// Its pages are left alone, since its page directory may not be current.
+  pub fn release_mapped_files(&self) {
+    let regions = self.get_memory_regions().read();
+    for region in regions.execution_regions.iter() {
+      release_mapping(region);
+    }
+  }
+
+  /// Add a region for the mmap syscall, at `address` or wherever there is
+  /// room if it is None. Returns the start of the region, or None if the
+  /// requested range is taken or there is no room.
+  pub fn map_region(&self, address: Option<VirtualAddress>, length: usize, backed_by: MemoryRegionType, permissions: Permissions) -> Option<VirtualAddress> {
+    let region_length = (length + 0xfff) & 0xfffff000;
+    if region_length == 0 {
+      return None;
+    }
+    let mut regions = self.get_memory_regions().write();
+    let start = match address {
+      Some(address) => {
+        let start = address.as_usize();
+        let end = start.checked_add(region_length)?;
+        if start & 0xfff != 0 || end > MAP_AREA_END || !regions.is_user_range_free(start, end) {
+          return None;
+        }
+        start
+      },
+      None => regions.find_free_map_range(region_length)?,
+    };
+    let region = VirtualMemoryRegion::new(VirtualAddress::new(start), region_length, backed_by, permissions);
+    regions.execution_regions.push(region);
+    Some(VirtualAddress::new(start))
+  }
+
+  /// Remove a region made by `map_region`, which must be the current
+  /// process. The range has to match the whole region.
+  pub fn unmap_user_region(&self, address: VirtualAddress, length: usize) -> Result<(), ()> {
+    let region_length = (length + 0xfff) & 0xfffff000;
+    let region = {
+      let mut regions = self.get_memory_regions().write();
+      let index = regions.execution_regions
+        .iter()
+        .position(|region| region.get_starting_address() == address && region.get_size() == region_length)
+        .ok_or(())?;
+      regions.execution_regions.remove(index)
+    };
+    CurrentPageDirectory::get().unmap_region(region);
+    release_mapping(&region);
+    Ok(())
+  }
+
+  /// Extend the user stack down to the page containing `addr`, if the stack
+  /// would stay within its maximum size and not run into another region.
+  /// Returns whether the stack now covers the address.
+  pub fn grow_stack(&self, addr: VirtualAddress) -> bool {
+    let mut regions = self.get_memory_regions().write();
+    let stack = regions.stack_region;
+    if stack.get_size() == 0 {
+      // Kernel threads have no user stack
+      return false;
+    }
+    let start = stack.get_starting_address_as_usize();
+    let end = start + stack.get_size();
+    let new_start = addr.as_usize() & 0xfffff000;
+    if new_start >= start || end - new_start > MAX_USER_STACK_SIZE {
+      return false;
+    }
+    if !regions.is_user_range_free(new_start, start) {
+      return false;
+    }
+    regions.stack_region.expand((start - new_start) / 0x1000);
+    true
+  }
+
   /// Move the heap to a specific page boundary. This should only be called when
   /// a program is first mapped.
   pub fn start_heap(&self, addr: VirtualAddress) {
@@ -335,8 +586,8 @@ impl ProcessState {
 
   pub fn kernel_mmap_dma(&self, length: usize) -> (PhysicalAddress, VirtualAddress) {
     let mut kernel_memmap = KERNEL_MEMMAP.write();
-    // Find a free space below the stack
-    let mut last_occupied = STACK_START.as_usize();
+    // Find a free space below the stack and its guard page
+    let mut last_occupied = STACK_GUARD.as_usize();
     for region in kernel_memmap.iter() {
       let region_start = region.get_starting_address_as_usize();
       if region_start < last_occupied {
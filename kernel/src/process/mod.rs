@@ -1,21 +1,41 @@
 use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, Ordering};
 use crate::files::handle::LocalHandle;
 use crate::gdt;
 use crate::kprintln;
 use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+pub mod dos_boxes;
+pub mod dos_mouse;
+pub mod dos_timer;
+pub mod dos_video;
+pub mod environment;
 pub mod exec;
 pub mod files;
+pub mod fpu;
 pub mod id;
+pub mod io_ports;
+pub mod kthread;
 pub mod map;
 pub mod memory;
 pub mod process_state;
+pub mod queue;
+pub mod restart;
+pub mod shared_data;
 pub mod signals;
+pub mod spawn;
 pub mod subsystem;
+pub mod thread;
+pub mod vds;
+pub mod vm86;
+
+pub use kthread::spawn_kthread;
+pub use thread::spawn_thread;
 
 static mut PROCESS_MAP: Option<RwLock<map::ProcessMap>> = None;
 
 pub fn init() {
+  queue::init();
   unsafe {
     PROCESS_MAP = Some(RwLock::new(map::ProcessMap::new()));
   }
@@ -51,6 +71,15 @@ pub fn current_process() -> Option<Arc<process_state::ProcessState>> {
   }
 }
 
+/// Like current_process, but gives up rather than wait on the process map.
+/// Meant for reporting from fault handlers that may have interrupted a holder
+/// of the lock.
+pub fn try_current_process_id() -> Option<id::ProcessID> {
+  let lock = unsafe { PROCESS_MAP.as_ref()? };
+  let map = lock.try_read()?;
+  map.get_current_process().map(|p| p.get_id())
+}
+
 pub fn make_current(pid: id::ProcessID) {
   let mut map = all_processes_mut();
   map.make_current(pid);
@@ -63,13 +92,20 @@ pub fn switch_to(pid: id::ProcessID) {
     let old_proc_esp = current.get_kernel_stack_container() as *const RwLock<usize>;
     //kprintln!("Switch from {:?} to {:?}", current.get_id(), pid);
     //kprintln!(" Cur esp was {:x}", current.get_kernel_stack_pointer());
+    kinvariant!(current.get_id() != pid, "Switching from {:?} to itself", pid);
     map.make_current(pid);
     let next = map.get_process(pid).unwrap();
+    kinvariant!(next.is_running(), "Switching to {:?}, which is not running", pid);
     //kprintln!(" Next esp is {:x}", next.get_kernel_stack_pointer());
     unsafe {
-      gdt::set_tss_stack_pointer(memory::STACK_START.as_u32() + memory::STACK_SIZE as u32 - 4);
+      gdt::set_tss_stack_pointer(next.get_kernel_stack_top() as u32);
     }
+    fpu::prepare_switch(pid);
+    io_ports::prepare_switch(pid);
     let pagedir = next.get_page_directory().get_address().as_usize();
+    unsafe {
+      gdt::set_double_fault_page_directory(pagedir as u32);
+    }
     let new_proc_esp = next.get_kernel_stack_container() as *const RwLock<usize>;
     (pagedir, old_proc_esp, new_proc_esp)
   };
@@ -88,9 +124,14 @@ pub fn enter_usermode(pid: id::ProcessID) {
     map.make_current(pid);
     let next = map.get_process(pid).unwrap();
     unsafe {
-      gdt::set_tss_stack_pointer(memory::STACK_START.as_u32() + memory::STACK_SIZE as u32 - 4);
+      gdt::set_tss_stack_pointer(next.get_kernel_stack_top() as u32);
     }
+    fpu::prepare_switch(pid);
+    io_ports::prepare_switch(pid);
     let pagedir = next.get_page_directory().get_address().as_usize();
+    unsafe {
+      gdt::set_double_fault_page_directory(pagedir as u32);
+    }
     let new_proc_esp = next.get_kernel_stack_container() as *const RwLock<usize>;
     (pagedir, old_proc_esp, new_proc_esp)
   };
@@ -136,9 +177,47 @@ pub fn sleep(ms: usize) {
   yield_coop();
 }
 
+/// The first process runs the idle loop once the kernel has started, so the
+/// time it spends on the CPU is time the system was idle
+pub const IDLE_PROCESS: id::ProcessID = id::ProcessID::new(0);
+
+/// Timer ticks since boot, used to age CPU usage figures
+static TICK_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Sleepers are counted down this many at a time on each tick
+const SLEEPER_BATCH: usize = 16;
+
+pub fn get_tick_count() -> u32 {
+  TICK_COUNT.load(Ordering::SeqCst)
+}
+
+/// Charge the tick to the current process, and count down every sleeper.
+/// Processes that are neither running nor sleeping are not touched.
 pub fn send_tick() {
-  for (_id, p) in all_processes().iter() {
-    p.update_tick();
+  let now = TICK_COUNT.fetch_add(1, Ordering::SeqCst).wrapping_add(1);
+  let processes = all_processes();
+  if let Some(current) = processes.get_current_process() {
+    current.record_cpu_tick(now);
+    // Boxes only see time pass while they run
+    dos_timer::advance(current.get_id(), crate::time::system::HUNDRED_NS_PER_TICK);
+  }
+  // This runs in the timer interrupt, which may have arrived while the heap
+  // was locked, so the sleepers are copied out a batch at a time into a
+  // buffer on the stack. Waking a sleeper changes the queues, so it happens
+  // once they are unlocked.
+  let mut sleeping = [id::ProcessID::new(0); SLEEPER_BATCH];
+  let mut after = None;
+  loop {
+    let count = queue::with_queues(|queues| queues.get_sleeping(after, &mut sleeping));
+    for pid in sleeping[..count].iter() {
+      if let Some(p) = processes.get_process(*pid) {
+        p.update_tick();
+      }
+    }
+    if count < SLEEPER_BATCH {
+      break;
+    }
+    after = Some(sleeping[count - 1]);
   }
 }
 
@@ -195,14 +274,14 @@ pub fn exit(code: u32) {
 }
 
 pub fn exec(drive_number: usize, handle: LocalHandle, interp_mode: exec::InterpretationMode) {
-  let (entry, flags, segments) = {
+  let (entry, esp, flags, segments) = {
     let cur = current_process().unwrap();
     let entry = cur.prepare_for_exec(drive_number, handle, interp_mode);
-    let (flags, segments) = match cur.get_vm8086_metadata() {
-      Some(meta) => (0x20200, Some(meta)),
-      None => (0x200, None),
+    let (esp, flags, segments) = match cur.get_vm8086_metadata() {
+      Some(meta) => (0xffc, 0x20200, Some(meta)),
+      None => (cur.write_startup_stack(), 0x200, None),
     };
-    (entry, flags, segments)
+    (entry, esp, flags, segments)
   };
 
   match segments {
@@ -216,12 +295,12 @@ pub fn exec(drive_number: usize, handle: LocalHandle, interp_mode: exec::Interpr
           push $2
           push $3
           push $4
-          push 0xffc
           push $5
           push $6
+          push $7
           push 0
           iretd" : :
-          "*m"(&meta.gs), "*m"(&meta.fs), "*m"(&meta.ds), "*m"(&meta.es), "*m"(&meta.ss), "r"(flags), "r"(cs) : :
+          "*m"(&meta.gs), "*m"(&meta.fs), "*m"(&meta.ds), "*m"(&meta.es), "*m"(&meta.ss), "r"(esp), "r"(flags), "r"(cs) : :
           "intel", "volatile"
         );
       }
@@ -230,12 +309,12 @@ pub fn exec(drive_number: usize, handle: LocalHandle, interp_mode: exec::Interpr
       unsafe {
         llvm_asm!("
           push 0x23
-          push 0xbffffffc
           push $0
-          push 0x1b
           push $1
+          push 0x1b
+          push $2
           iretd" : :
-          "r"(flags), "r"(entry) : :
+          "r"(esp), "r"(flags), "r"(entry) : :
           "intel", "volatile"
         );
       }
@@ -243,14 +322,6 @@ pub fn exec(drive_number: usize, handle: LocalHandle, interp_mode: exec::Interpr
   }
 }
 
-pub fn set_kernel_mode_function(pid: id::ProcessID, f: extern fn()) {
-  let processes = all_processes();
-  let process = processes.get_process(pid);
-  if let Some(p) = process {
-    p.set_kernel_mode_entry_point(f);
-  }
-}
-
 pub fn get_current_pid() -> id::ProcessID {
   all_processes().get_current_pid()
 }
@@ -263,9 +334,29 @@ pub fn send_signal(pid: id::ProcessID, sig: u32) {
   }
 }
 
-pub fn wait(pid: id::ProcessID) -> u32 {
-  all_processes().get_current_process().unwrap().block_on_child(pid);
-  yield_coop();
-  // process has resumed
-  all_processes().get_current_process().unwrap().get_resume_code()
+/// Send a signal to every process in a group. The map is released before any
+/// signal is delivered, since a signal may end its recipient.
+pub fn send_signal_to_group(group: id::ProcessID, sig: u32) {
+  let members = all_processes().group_members(group);
+  for member in members {
+    member.send_signal(sig);
+  }
+}
+
+/// Wait for a child process to exit, or any child if `pid` is None, and reap
+/// it. Returns the ID and exit status of the reaped child, or None if the
+/// current process has no matching children.
+pub fn wait(pid: Option<id::ProcessID>) -> Option<(id::ProcessID, u32)> {
+  loop {
+    let result = all_processes_mut().wait_on_child(pid);
+    match result {
+      map::WaitResult::Exited(child, code) => return Some((child, code)),
+      map::WaitResult::NoChild => return None,
+      map::WaitResult::Blocked => {
+        yield_coop();
+        // The process has resumed; clear the resume code and reap the child
+        current_process().unwrap().get_resume_code();
+      },
+    }
+  }
 }
@@ -4,12 +4,20 @@ use crate::memory::address::VirtualAddress;
 use crate::memory::physical::frame::Frame;
 use crate::memory::virt::page_directory;
 use crate::memory::virt::page_table::{PageTable, PageTableReference};
-use crate::memory::virt::region::VirtualMemoryRegion;
+use crate::memory::virt::region::{Permissions, VirtualMemoryRegion};
 use crate::promise::Promise;
 use crate::time;
+use crate::time::usage::CpuUsage;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
 use spin::RwLock;
+use super::environment::Environment;
+use super::fpu::{self, SaveArea};
 use super::id::ProcessID;
-use super::memory::{MemoryRegions, STACK_SIZE, STACK_START};
+use super::memory::MemoryRegions;
+use super::queue;
+use super::signals::SignalState;
 use super::subsystem::Subsystem;
 
 /// Current state of the process
@@ -37,25 +45,63 @@ pub enum BlockReason {
   None,
   /// Waiting for a child to exit
   Child(ProcessID),
+  /// Waiting for whichever child exits first
+  AnyChild,
+  /// Waiting for a promise to be resolved, such as a read handed off to a
+  /// disk worker
+  Promise,
+  /// Waiting in a WaitQueue for a driver or another process to notify it
+  WaitQueue,
 }
 
+/// Working directory of the init process
+pub const INITIAL_CWD: &str = "A:\\";
+
+/// Name of the first process, inherited by every kernel process forked from it
+pub const KERNEL_COMMAND_LINE: &str = "KERNEL";
+
 pub struct ProcessState {
   pid: ProcessID,
-  parent: ProcessID,
-
-  memory_regions: RwLock<MemoryRegions>,
-  heap_break: RwLock<VirtualAddress>,
+  /// Changes if the parent exits first, and the process is adopted
+  parent: RwLock<ProcessID>,
+  /// Job control: signals typed at a terminal go to every process in its
+  /// foreground group. Groups are named after the process that created them.
+  process_group: RwLock<ProcessID>,
+  /// Groups can only be joined by processes in the same session
+  session: RwLock<ProcessID>,
+  /// Threads in a group share their memory and handles. The group is named
+  /// after its leader, and a process that never created threads leads a
+  /// group of its own.
+  thread_group: ProcessID,
+
+  memory_regions: Arc<RwLock<MemoryRegions>>,
+  heap_break: Arc<RwLock<VirtualAddress>>,
 
   page_directory: PageTableReference,
 
+  /// Each thread has its own kernel stack, even when it shares a directory
+  kernel_stack: VirtualMemoryRegion,
   kernel_esp: RwLock<usize>,
 
-  open_files: RwLock<FileHandleMap>,
-  open_directories: RwLock<FileHandleMap>,
+  open_files: Arc<RwLock<FileHandleMap>>,
+  open_directories: Arc<RwLock<FileHandleMap>>,
+  /// Absolute path, including drive, that relative paths are resolved against
+  cwd: RwLock<String>,
+  /// Path of the executable and its arguments, set by exec
+  command_line: RwLock<String>,
+  /// Environment variables. Forks get a copy, threads share their group's.
+  environment: Arc<RwLock<Environment>>,
 
   run_state: RwLock<RunState>,
+  signals: RwLock<SignalState>,
+  cpu_usage: RwLock<CpuUsage>,
   subsystem: RwLock<Subsystem>,
   exit_code: RwLock<u32>,
+  /// Floating point registers, saved when another process took them over.
+  /// Empty until the process first loses them.
+  fpu_state: RwLock<Option<Box<SaveArea>>>,
+  /// Set for kernel threads, which run this function instead of any program
+  kernel_thread_entry: Option<extern fn()>,
 }
 
 impl ProcessState {
@@ -63,23 +109,36 @@ impl ProcessState {
    * Used to generate the init process, which has no parent
    */
   pub fn first(pid: ProcessID, heap_start: VirtualAddress) -> ProcessState {
+    let regions = MemoryRegions::initial(heap_start);
+    let kernel_stack = regions.kernel_stack_region;
     ProcessState {
       pid,
-      parent: pid,
+      parent: RwLock::new(pid),
+      process_group: RwLock::new(pid),
+      session: RwLock::new(pid),
+      thread_group: pid,
 
-      memory_regions: RwLock::new(MemoryRegions::initial(heap_start)),
-      heap_break: RwLock::new(VirtualAddress::new(0)),
+      memory_regions: Arc::new(RwLock::new(regions)),
+      heap_break: Arc::new(RwLock::new(VirtualAddress::new(0))),
 
       page_directory: PageTableReference::current(),
 
+      kernel_stack,
       kernel_esp: RwLock::new(0),
 
-      open_files: RwLock::new(FileHandleMap::new()),
-      open_directories: RwLock::new(FileHandleMap::new()),
+      open_files: Arc::new(RwLock::new(FileHandleMap::new())),
+      open_directories: Arc::new(RwLock::new(FileHandleMap::new())),
+      cwd: RwLock::new(String::from(INITIAL_CWD)),
+      command_line: RwLock::new(String::from(KERNEL_COMMAND_LINE)),
+      environment: Arc::new(RwLock::new(Environment::new())),
 
       run_state: RwLock::new(RunState::Running),
+      signals: RwLock::new(SignalState::new()),
+      cpu_usage: RwLock::new(CpuUsage::new()),
       subsystem: RwLock::new(Subsystem::Native),
       exit_code: RwLock::new(0),
+      fpu_state: RwLock::new(None),
+      kernel_thread_entry: None,
     }
   }
 
@@ -88,30 +147,174 @@ impl ProcessState {
    * forked from an existing one.
    */
   pub fn fork(&self, pid: ProcessID) -> ProcessState {
-    let new_regions = RwLock::new(self.memory_regions.read().fork());
+    let mut new_regions = self.memory_regions.read().fork();
+    new_regions.kernel_stack_region = self.kernel_stack.copy_with_permissions(Permissions::ReadWrite);
+    if let Some(slot) = super::thread::stack_slot(self.kernel_stack) {
+      new_regions.thread_stacks.insert(slot, Some(pid));
+    }
+    let kernel_stack = new_regions.kernel_stack_region;
     let new_pagedir = self.fork_page_directory();
     let new_filemap = self.fork_file_map();
     let new_dirmap = self.fork_directory_map();
     let heap_break = *self.heap_break.read();
+    let cwd = self.cwd.read().clone();
+    let command_line = self.command_line.read().clone();
+    let environment = self.environment.read().clone();
+    let signals = self.signals.read().fork();
     ProcessState {
       pid,
-      parent: self.pid,
+      parent: RwLock::new(self.pid),
+      process_group: RwLock::new(self.get_process_group()),
+      session: RwLock::new(self.get_session()),
+      thread_group: pid,
 
-      memory_regions: new_regions,
-      heap_break: RwLock::new(heap_break),
+      memory_regions: Arc::new(RwLock::new(new_regions)),
+      heap_break: Arc::new(RwLock::new(heap_break)),
 
       page_directory: new_pagedir,
 
+      kernel_stack,
       kernel_esp: RwLock::new(
-        STACK_START.as_usize() + STACK_SIZE - 4
+        kernel_stack.get_starting_address_as_usize() + kernel_stack.get_size() - 4
       ),
 
-      open_files: RwLock::new(new_filemap),
-      open_directories: RwLock::new(new_dirmap),
+      open_files: Arc::new(RwLock::new(new_filemap)),
+      open_directories: Arc::new(RwLock::new(new_dirmap)),
+      cwd: RwLock::new(cwd),
+      command_line: RwLock::new(command_line),
+      environment: Arc::new(RwLock::new(environment)),
 
       run_state: RwLock::new(RunState::Running),
+      signals: RwLock::new(signals),
+      cpu_usage: RwLock::new(CpuUsage::new()),
       subsystem: RwLock::new(Subsystem::Native),
       exit_code: RwLock::new(0),
+      fpu_state: RwLock::new(fpu::fork_state(self)),
+      kernel_thread_entry: None,
+    }
+  }
+
+  /**
+   * Used to create a child that will load a new program instead of running a
+   * copy of this one. None of this process's memory is copied: the child
+   * starts with an empty address space and only the handles it was given.
+   */
+  pub fn spawn_child(&self, pid: ProcessID, page_directory: PageTableReference, files: FileHandleMap, command_line: String, environment: Environment) -> ProcessState {
+    let regions = self.memory_regions.read().for_spawn();
+    let kernel_stack = regions.kernel_stack_region;
+    let stack_top = kernel_stack.get_starting_address_as_usize() + kernel_stack.get_size();
+    let cwd = self.cwd.read().clone();
+    let signals = self.signals.read().fork();
+    ProcessState {
+      pid,
+      parent: RwLock::new(self.pid),
+      process_group: RwLock::new(self.get_process_group()),
+      session: RwLock::new(self.get_session()),
+      thread_group: pid,
+
+      memory_regions: Arc::new(RwLock::new(regions)),
+      heap_break: Arc::new(RwLock::new(VirtualAddress::new(0))),
+
+      page_directory,
+
+      kernel_stack,
+      // The first switch pops the entry written at the top of the stack
+      kernel_esp: RwLock::new(stack_top - 8),
+
+      open_files: Arc::new(RwLock::new(files)),
+      open_directories: Arc::new(RwLock::new(FileHandleMap::new())),
+      cwd: RwLock::new(cwd),
+      command_line: RwLock::new(command_line),
+      environment: Arc::new(RwLock::new(environment)),
+
+      run_state: RwLock::new(RunState::Running),
+      signals: RwLock::new(signals),
+      cpu_usage: RwLock::new(CpuUsage::new()),
+      subsystem: RwLock::new(Subsystem::Native),
+      exit_code: RwLock::new(0),
+      fpu_state: RwLock::new(None),
+      kernel_thread_entry: None,
+    }
+  }
+
+  /**
+   * Used to create a kernel thread, which runs `entry` on its own stack in the
+   * kernel process's page directory. It belongs to the kernel process, and
+   * owns no user memory or handles.
+   */
+  pub fn kernel_thread(pid: ProcessID, kernel: &ProcessState, stack: VirtualMemoryRegion, entry: extern fn(), name: &str) -> ProcessState {
+    let stack_top = stack.get_starting_address_as_usize() + stack.get_size();
+    ProcessState {
+      pid,
+      parent: RwLock::new(kernel.pid),
+      process_group: RwLock::new(kernel.get_process_group()),
+      session: RwLock::new(kernel.get_session()),
+      thread_group: pid,
+
+      memory_regions: Arc::new(RwLock::new(MemoryRegions::kernel_thread(stack))),
+      heap_break: Arc::new(RwLock::new(VirtualAddress::new(0))),
+
+      page_directory: PageTableReference::new(kernel.page_directory.get_address()),
+
+      kernel_stack: stack,
+      // The first switch pops the entry written at the top of the stack
+      kernel_esp: RwLock::new(stack_top - 8),
+
+      open_files: Arc::new(RwLock::new(FileHandleMap::new())),
+      open_directories: Arc::new(RwLock::new(FileHandleMap::new())),
+      cwd: RwLock::new(String::from(INITIAL_CWD)),
+      command_line: RwLock::new(String::from(name)),
+      environment: Arc::new(RwLock::new(Environment::new())),
+
+      run_state: RwLock::new(RunState::Running),
+      signals: RwLock::new(SignalState::new()),
+      cpu_usage: RwLock::new(CpuUsage::new()),
+      subsystem: RwLock::new(Subsystem::Native),
+      exit_code: RwLock::new(0),
+      fpu_state: RwLock::new(None),
+      kernel_thread_entry: Some(entry),
+    }
+  }
+
+  /**
+   * Used to create another thread in this process's group. The thread shares
+   * the page directory, memory regions, heap, and open handles, but has its
+   * own kernel stack, registers, and signal state.
+   */
+  pub fn clone_thread(&self, pid: ProcessID, kernel_stack: VirtualMemoryRegion) -> ProcessState {
+    let cwd = self.cwd.read().clone();
+    let command_line = self.command_line.read().clone();
+    let signals = self.signals.read().fork();
+    ProcessState {
+      pid,
+      parent: RwLock::new(self.pid),
+      process_group: RwLock::new(self.get_process_group()),
+      session: RwLock::new(self.get_session()),
+      thread_group: self.thread_group,
+
+      memory_regions: self.memory_regions.clone(),
+      heap_break: self.heap_break.clone(),
+
+      page_directory: PageTableReference::new(self.page_directory.get_address()),
+
+      kernel_stack,
+      kernel_esp: RwLock::new(
+        kernel_stack.get_starting_address_as_usize() + kernel_stack.get_size() - 4
+      ),
+
+      open_files: self.open_files.clone(),
+      open_directories: self.open_directories.clone(),
+      cwd: RwLock::new(cwd),
+      command_line: RwLock::new(command_line),
+      environment: self.environment.clone(),
+
+      run_state: RwLock::new(RunState::Running),
+      signals: RwLock::new(signals),
+      cpu_usage: RwLock::new(CpuUsage::new()),
+      subsystem: RwLock::new(Subsystem::Native),
+      exit_code: RwLock::new(0),
+      fpu_state: RwLock::new(None),
+      kernel_thread_entry: None,
     }
   }
 
@@ -155,15 +358,12 @@ impl ProcessState {
     *self.kernel_esp.write() = kernel_esp - 4 * 5;
   }
 
-  pub fn set_kernel_mode_entry_point(&self, func: extern fn()) {
-    let stack_addr = 0xffbfeff8;
-    *self.kernel_esp.write() = stack_addr;
-    self.make_current_stack_frame_editable();
-    let temp_page_address = page_directory::get_temporary_page_address().as_usize();
-    unsafe {
-      let stack_ptr = 0xffbffff8 as *mut usize;
-      *stack_ptr = func as usize; 
-    }
+  pub fn is_kernel_thread(&self) -> bool {
+    self.kernel_thread_entry.is_some()
+  }
+
+  pub fn get_kernel_thread_entry(&self) -> Option<extern fn()> {
+    self.kernel_thread_entry
   }
 
   pub fn get_range_containing_address(&self, addr: VirtualAddress) -> Option<VirtualMemoryRegion> {
@@ -175,7 +375,43 @@ impl ProcessState {
   }
 
   pub fn get_parent(&self) -> ProcessID {
-    self.parent
+    *self.parent.read()
+  }
+
+  pub fn set_parent(&self, parent: ProcessID) {
+    *self.parent.write() = parent;
+  }
+
+  pub fn get_process_group(&self) -> ProcessID {
+    *self.process_group.read()
+  }
+
+  pub fn set_process_group(&self, group: ProcessID) {
+    *self.process_group.write() = group;
+  }
+
+  pub fn get_session(&self) -> ProcessID {
+    *self.session.read()
+  }
+
+  /// Start a new session, led by this process, in a new group of its own
+  pub fn create_session(&self) {
+    *self.session.write() = self.pid;
+    *self.process_group.write() = self.pid;
+  }
+
+  pub fn is_session_leader(&self) -> bool {
+    self.get_session() == self.pid
+  }
+
+  pub fn get_thread_group(&self) -> ProcessID {
+    self.thread_group
+  }
+
+  /// Threads share memory and handles with their group leader, and release
+  /// none of them when they exit
+  pub fn is_thread(&self) -> bool {
+    self.thread_group != self.pid
   }
 
   pub fn get_page_directory(&self) -> &PageTableReference {
@@ -186,6 +422,16 @@ impl ProcessState {
     &self.memory_regions
   }
 
+  pub fn get_kernel_stack(&self) -> VirtualMemoryRegion {
+    self.kernel_stack
+  }
+
+  /// Address the CPU switches to when this process is interrupted in
+  /// userspace
+  pub fn get_kernel_stack_top(&self) -> usize {
+    self.kernel_stack.get_starting_address_as_usize() + self.kernel_stack.get_size() - 4
+  }
+
   pub fn get_kernel_stack_pointer(&self) -> usize {
     self.kernel_esp.read().clone()
   }
@@ -202,6 +448,34 @@ impl ProcessState {
     &self.open_directories
   }
 
+  pub fn get_cwd(&self) -> String {
+    self.cwd.read().clone()
+  }
+
+  pub fn set_cwd(&self, cwd: String) {
+    *self.cwd.write() = cwd;
+  }
+
+  pub fn get_command_line(&self) -> String {
+    self.command_line.read().clone()
+  }
+
+  pub fn set_command_line(&self, command_line: String) {
+    *self.command_line.write() = command_line;
+  }
+
+  pub fn get_environment(&self) -> &RwLock<Environment> {
+    &self.environment
+  }
+
+  pub fn get_fpu_state(&self) -> &RwLock<Option<Box<SaveArea>>> {
+    &self.fpu_state
+  }
+
+  pub fn get_signal_state(&self) -> &RwLock<SignalState> {
+    &self.signals
+  }
+
   pub fn get_subsystem(&self) -> &RwLock<Subsystem> {
     &self.subsystem
   }
@@ -215,27 +489,32 @@ impl ProcessState {
   }
 
   pub fn sleep(&self, ms: usize) {
-    let mut run_state = self.run_state.write();
-    *run_state = RunState::Sleeping(ms);
+    self.set_run_state(RunState::Sleeping(ms));
   }
 
+  /// Count down a sleep by one timer tick
   pub fn update_tick(&self) {
-    let run_state = self.run_state.read().clone();
-    match run_state {
-      RunState::Sleeping(duration) => {
-        if duration > time::system::MS_PER_TICK {
-          let remaining = duration - time::system::MS_PER_TICK;
-          *self.run_state.write() = RunState::Sleeping(remaining);
-          return;
-        }
-        *self.run_state.write() = RunState::Running;
+    self.update_run_state(|state| match state {
+      RunState::Sleeping(duration) if duration > time::system::MS_PER_TICK => {
+        Some(RunState::Sleeping(duration - time::system::MS_PER_TICK))
       },
-      _ => (),
-    }
+      RunState::Sleeping(_) => Some(RunState::Running),
+      _ => None,
+    });
+  }
+
+  /// Charge timer tick number `now` to the process, which was running when it
+  /// arrived
+  pub fn record_cpu_tick(&self, now: u32) {
+    self.cpu_usage.write().charge(now);
+  }
+
+  pub fn get_cpu_usage(&self) -> CpuUsage {
+    self.cpu_usage.read().as_of(super::get_tick_count())
   }
 
   pub fn is_running(&self) -> bool {
-    let run_state = self.run_state.read().clone();
+    let run_state = self.get_run_state();
     match run_state {
       RunState::Running => true,
       RunState::Resumed(_) => true,
@@ -243,8 +522,31 @@ impl ProcessState {
     }
   }
 
-  pub fn get_run_state(&self) -> &RwLock<RunState> {
-    &self.run_state
+  pub fn is_terminated(&self) -> bool {
+    *self.run_state.read() == RunState::Terminated
+  }
+
+  pub fn get_run_state(&self) -> RunState {
+    *self.run_state.read()
+  }
+
+  /// Change the run state with `f`, which receives the current state and
+  /// returns the new one, or None to leave it as it is. The scheduler's queues
+  /// are updated to match. Returns whether the state was changed.
+  pub fn update_run_state<F: FnOnce(RunState) -> Option<RunState>>(&self, f: F) -> bool {
+    let mut run_state = self.run_state.write();
+    let previous = *run_state;
+    let next = match f(previous) {
+      Some(next) => next,
+      None => return false,
+    };
+    *run_state = next;
+    queue::with_queues(|queues| queues.transition(self.pid, previous, next));
+    true
+  }
+
+  pub fn set_run_state(&self, state: RunState) {
+    self.update_run_state(|_| Some(state));
   }
 
   pub fn get_heap_break(&self) -> &RwLock<VirtualAddress> {
@@ -0,0 +1,345 @@
+//! The scheduler keeps each process in a queue matching its run state, so that
+//! picking the next process to run, or finding the sleepers whose timeouts
+//! need to count down, never scans the whole process map. Every change to a
+//! run state goes through `ProcessState::update_run_state`, which moves the
+//! process between queues.
+//!
+//! Runnable processes form a ring linked through their IDs, so a process can
+//! be taken out of the middle of it, or moved to the front, by following its
+//! links instead of searching for it. Every operation is a lookup or two in
+//! an ordered map, whatever the length of the queue.
+//!
+//! The queues have their own lock, separate from the process map, since run
+//! states change while the map is locked for reading or for writing. Nothing
+//! else is locked while the queues are held.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::ops::Bound;
+use crate::interrupts;
+use spin::Mutex;
+use super::id::ProcessID;
+use super::process_state::RunState;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Queue {
+  Ready,
+  Sleeping,
+  Blocked,
+}
+
+/// Terminated processes are in no queue, and are never scheduled again
+fn queue_for(state: RunState) -> Option<Queue> {
+  match state {
+    RunState::Running | RunState::Resumed(_) => Some(Queue::Ready),
+    RunState::Sleeping(_) => Some(Queue::Sleeping),
+    RunState::Blocked(_) | RunState::Paused => Some(Queue::Blocked),
+    RunState::Terminated => None,
+  }
+}
+
+#[derive(Copy, Clone)]
+struct Links {
+  prev: Option<ProcessID>,
+  next: Option<ProcessID>,
+}
+
+/// A doubly linked list of processes. The links are kept by ID, rather than
+/// in the process itself, so they are covered by the queue lock.
+struct ReadyList {
+  head: Option<ProcessID>,
+  tail: Option<ProcessID>,
+  links: BTreeMap<ProcessID, Links>,
+}
+
+impl ReadyList {
+  fn new() -> ReadyList {
+    ReadyList {
+      head: None,
+      tail: None,
+      links: BTreeMap::new(),
+    }
+  }
+
+  fn len(&self) -> usize {
+    self.links.len()
+  }
+
+  fn set_next(&mut self, pid: Option<ProcessID>, next: Option<ProcessID>) {
+    match pid.and_then(|pid| self.links.get_mut(&pid)) {
+      Some(links) => links.next = next,
+      None => self.head = next,
+    }
+  }
+
+  fn set_prev(&mut self, pid: Option<ProcessID>, prev: Option<ProcessID>) {
+    match pid.and_then(|pid| self.links.get_mut(&pid)) {
+      Some(links) => links.prev = prev,
+      None => self.tail = prev,
+    }
+  }
+
+  fn push_back(&mut self, pid: ProcessID) {
+    if self.links.contains_key(&pid) {
+      return;
+    }
+    let prev = self.tail;
+    self.links.insert(pid, Links { prev, next: None });
+    self.set_next(prev, Some(pid));
+    self.tail = Some(pid);
+  }
+
+  fn push_front(&mut self, pid: ProcessID) {
+    if self.links.contains_key(&pid) {
+      return;
+    }
+    let next = self.head;
+    self.links.insert(pid, Links { prev: None, next });
+    self.set_prev(next, Some(pid));
+    self.head = Some(pid);
+  }
+
+  /// Unlink a process, returning false if it was not in the list
+  fn remove(&mut self, pid: ProcessID) -> bool {
+    let links = match self.links.remove(&pid) {
+      Some(links) => links,
+      None => return false,
+    };
+    self.set_next(links.prev, links.next);
+    self.set_prev(links.next, links.prev);
+    true
+  }
+
+  fn pop_front(&mut self) -> Option<ProcessID> {
+    let pid = self.head?;
+    self.remove(pid);
+    Some(pid)
+  }
+}
+
+pub struct RunQueues {
+  /// Runnable processes in round-robin order, including the current one
+  ready: ReadyList,
+  sleeping: BTreeSet<ProcessID>,
+  /// Blocked and paused processes, which only leave when something else
+  /// changes their state
+  blocked: BTreeSet<ProcessID>,
+}
+
+impl RunQueues {
+  pub fn new() -> RunQueues {
+    RunQueues {
+      ready: ReadyList::new(),
+      sleeping: BTreeSet::new(),
+      blocked: BTreeSet::new(),
+    }
+  }
+
+  fn insert(&mut self, pid: ProcessID, queue: Queue) {
+    match queue {
+      Queue::Ready => self.ready.push_back(pid),
+      Queue::Sleeping => {
+        self.sleeping.insert(pid);
+      },
+      Queue::Blocked => {
+        self.blocked.insert(pid);
+      },
+    }
+  }
+
+  fn remove(&mut self, pid: ProcessID, queue: Queue) {
+    match queue {
+      Queue::Ready => {
+        self.ready.remove(pid);
+      },
+      Queue::Sleeping => {
+        self.sleeping.remove(&pid);
+      },
+      Queue::Blocked => {
+        self.blocked.remove(&pid);
+      },
+    }
+  }
+
+  /// Move a process to the queue for its new state. Changes within a state,
+  /// like a sleep counting down, leave it where it is.
+  pub fn transition(&mut self, pid: ProcessID, from: RunState, to: RunState) {
+    let previous = queue_for(from);
+    let next = queue_for(to);
+    if previous == next {
+      return;
+    }
+    if let Some(queue) = previous {
+      self.remove(pid, queue);
+    }
+    if let Some(queue) = next {
+      self.insert(pid, queue);
+    }
+  }
+
+  /// Pick the runnable process after `current`, and rotate it to the back of
+  /// the queue. Returns None if nothing else can run.
+  pub fn next_ready(&mut self, current: ProcessID) -> Option<ProcessID> {
+    // The current process is skipped at most once, so this never loops more
+    // than twice
+    for _ in 0..self.ready.len().min(2) {
+      let pid = self.ready.pop_front()?;
+      self.ready.push_back(pid);
+      if pid != current {
+        return Some(pid);
+      }
+    }
+    None
+  }
+
+  /// Move a runnable process to the front of the queue, so that it is picked
+  /// at the next switch. Returns false if the process is not runnable.
+  pub fn promote(&mut self, pid: ProcessID) -> bool {
+    if !self.ready.remove(pid) {
+      return false;
+    }
+    self.ready.push_front(pid);
+    true
+  }
+
+  /// Fill `buffer` with sleeping processes in ID order, starting after
+  /// `after`, and return how many were written. The timer walks the sleepers
+  /// in batches this way, since it cannot allocate and cannot hold the queues
+  /// while it wakes them.
+  pub fn get_sleeping(&self, after: Option<ProcessID>, buffer: &mut [ProcessID]) -> usize {
+    let start = match after {
+      Some(pid) => Bound::Excluded(pid),
+      None => Bound::Unbounded,
+    };
+    let mut count = 0;
+    for (slot, pid) in buffer.iter_mut().zip(self.sleeping.range((start, Bound::Unbounded))) {
+      *slot = *pid;
+      count += 1;
+    }
+    count
+  }
+}
+
+static mut RUN_QUEUES: Option<Mutex<RunQueues>> = None;
+
+pub fn init() {
+  unsafe {
+    RUN_QUEUES = Some(Mutex::new(RunQueues::new()));
+  }
+}
+
+/// Run `f` with the queues locked. The timer interrupt counts down sleepers,
+/// so interrupts stay off while the lock is held; otherwise a tick arriving
+/// mid-update would wait forever on the lock.
+pub fn with_queues<R, F: FnOnce(&mut RunQueues) -> R>(f: F) -> R {
+  let queues = match unsafe { &RUN_QUEUES } {
+    Some(queues) => queues,
+    None => panic!("Run queues not initialized"),
+  };
+  let int_reenable = interrupts::is_interrupt_enabled();
+  interrupts::cli();
+  let result = f(&mut queues.lock());
+  if int_reenable {
+    interrupts::sti();
+  }
+  result
+}
+
+/// Add a new process, in the queue for its initial state
+pub fn add(pid: ProcessID, state: RunState) {
+  if let Some(queue) = queue_for(state) {
+    with_queues(|queues| queues.insert(pid, queue));
+  }
+}
+
+/// Let a runnable process run next, ahead of everything else waiting its turn
+pub fn promote(pid: ProcessID) -> bool {
+  with_queues(|queues| queues.promote(pid))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::process_state::BlockReason;
+
+  fn ready_order(list: &ReadyList) -> alloc::vec::Vec<u32> {
+    let mut order = alloc::vec::Vec::new();
+    let mut next = list.head;
+    while let Some(pid) = next {
+      order.push(pid.as_u32());
+      next = list.links.get(&pid).unwrap().next;
+    }
+    order
+  }
+
+  fn list_of(ids: &[u32]) -> ReadyList {
+    let mut list = ReadyList::new();
+    for id in ids {
+      list.push_back(ProcessID::new(*id));
+    }
+    list
+  }
+
+  #[test]
+  fn push_and_pop() {
+    let mut list = list_of(&[1, 2]);
+    list.push_front(ProcessID::new(3));
+    list.push_back(ProcessID::new(2));
+    assert_eq!(ready_order(&list), [3, 1, 2]);
+    assert_eq!(list.len(), 3);
+    assert_eq!(list.pop_front().map(|pid| pid.as_u32()), Some(3));
+    assert_eq!(list.pop_front().map(|pid| pid.as_u32()), Some(1));
+    assert_eq!(list.pop_front().map(|pid| pid.as_u32()), Some(2));
+    assert!(list.pop_front().is_none());
+    assert!(list.tail.is_none());
+  }
+
+  #[test]
+  fn remove_anywhere() {
+    let mut list = list_of(&[1, 2, 3, 4, 5]);
+    assert!(list.remove(ProcessID::new(1)));
+    assert_eq!(ready_order(&list), [2, 3, 4, 5]);
+    assert!(list.remove(ProcessID::new(5)));
+    assert_eq!(ready_order(&list), [2, 3, 4]);
+    assert!(list.remove(ProcessID::new(3)));
+    assert_eq!(ready_order(&list), [2, 4]);
+    assert!(!list.remove(ProcessID::new(3)));
+    // The ends are still linked correctly after removals
+    list.push_back(ProcessID::new(6));
+    list.push_front(ProcessID::new(7));
+    assert_eq!(ready_order(&list), [7, 2, 4, 6]);
+    assert_eq!(list.tail.map(|pid| pid.as_u32()), Some(6));
+  }
+
+  #[test]
+  fn rotate_and_promote() {
+    let mut queues = RunQueues::new();
+    for id in 1..=4 {
+      queues.transition(ProcessID::new(id), RunState::Terminated, RunState::Running);
+    }
+    assert_eq!(queues.next_ready(ProcessID::new(1)).map(|pid| pid.as_u32()), Some(2));
+    assert_eq!(ready_order(&queues.ready), [3, 4, 1, 2]);
+    assert!(queues.promote(ProcessID::new(1)));
+    assert_eq!(ready_order(&queues.ready), [1, 3, 4, 2]);
+    assert!(queues.promote(ProcessID::new(2)));
+    assert_eq!(ready_order(&queues.ready), [2, 1, 3, 4]);
+    queues.transition(ProcessID::new(3), RunState::Running, RunState::Sleeping(10));
+    assert!(!queues.promote(ProcessID::new(3)));
+    assert_eq!(queues.next_ready(ProcessID::new(2)).map(|pid| pid.as_u32()), Some(1));
+    queues.transition(ProcessID::new(1), RunState::Running, RunState::Terminated);
+    queues.transition(ProcessID::new(4), RunState::Running, RunState::Blocked(BlockReason::None));
+    assert_eq!(queues.next_ready(ProcessID::new(2)), None);
+  }
+
+  #[test]
+  fn sleepers_in_batches() {
+    let mut queues = RunQueues::new();
+    for id in [5, 2, 9, 7].iter() {
+      queues.transition(ProcessID::new(*id), RunState::Terminated, RunState::Sleeping(10));
+    }
+    let mut buffer = [ProcessID::new(0); 3];
+    assert_eq!(queues.get_sleeping(None, &mut buffer), 3);
+    assert_eq!(buffer.iter().map(|pid| pid.as_u32()).collect::<alloc::vec::Vec<u32>>(), [2, 5, 7]);
+    assert_eq!(queues.get_sleeping(Some(buffer[2]), &mut buffer), 1);
+    assert_eq!(buffer[0].as_u32(), 9);
+  }
+}
@@ -0,0 +1,75 @@
+//! A soft restart replaces every user process with a fresh copy of init,
+//! without reinitializing the kernel or its drivers. It is meant as a quick way
+//! to recover from a wedged userland during development.
+//!
+//! The restart itself runs on the kernel's idle process, outside of the process
+//! that requested it: the requesting process is just another user process that
+//! needs to be terminated.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use crate::filesystems;
+use super::id::ProcessID;
+use super::process_state::RunState;
+use super::{all_processes, all_processes_mut};
+
+static RESTART_REQUESTED: AtomicBool = AtomicBool::new(false);
+static INIT_PID: AtomicU32 = AtomicU32::new(0);
+
+/// Record which process is init. Every process descended from it is
+/// considered part of userland.
+pub fn set_init_process(pid: ProcessID) {
+  INIT_PID.store(pid.as_u32(), Ordering::SeqCst);
+}
+
+pub fn get_init_process() -> ProcessID {
+  ProcessID::new(INIT_PID.load(Ordering::SeqCst))
+}
+
+/// Ask the idle process to restart userland the next time it runs
+pub fn request_restart() {
+  RESTART_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn take_restart_request() -> bool {
+  RESTART_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Terminate init and all of its descendants, close every file they held
+/// open, remove them from the process map, remount all drives, and launch a
+/// new init process running `init_entry`.
+/// Must be called from a kernel process, since the new init is forked from the
+/// current process.
+pub fn restart_userland(init_entry: extern fn(), init_stack: usize) {
+  let init_pid = get_init_process();
+  let user_processes: Vec<_> = {
+    let processes = all_processes();
+    processes.iter()
+      .filter(|(pid, _)| processes.is_descendant(**pid, init_pid))
+      .map(|(_, process)| process.clone())
+      .collect()
+  };
+
+  for process in user_processes.iter() {
+    process.set_run_state(RunState::Terminated);
+    process.close_all_handles();
+  }
+
+  {
+    let mut processes = all_processes_mut();
+    for process in user_processes.iter() {
+      processes.reap(process.get_id());
+    }
+  }
+
+  filesystems::VFS.remount_all();
+
+  let new_init = all_processes_mut().fork_current();
+  {
+    let processes = all_processes();
+    let init_process = processes.get_process(new_init).unwrap();
+    init_process.set_initial_entry_point(init_entry, init_stack);
+    init_process.create_session();
+  }
+  set_init_process(new_init);
+}
@@ -0,0 +1,76 @@
+//! The shared data page lets userland read its PID and the current time
+//! without a syscall. One physical page is mapped read-only into every
+//! process at SHARED_DATA_ADDRESS, as a region that no process owns, and
+//! the kernel keeps it up to date through its own mapping: the time fields on
+//! every timer tick, and the PID on every process switch.
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::memory::address::VirtualAddress;
+use crate::memory::physical::{self, frame_range::FrameRange};
+use crate::memory::virt::page_directory::{CurrentPageDirectory, PageDirectory, PermissionFlags};
+use crate::memory::virt::region::{MemoryRegionType, Permissions, VirtualMemoryRegion};
+use syscall::shared::{SharedData, SHARED_DATA_ADDRESS};
+
+/// Where the kernel writes the page. It sits in the shared kernel page tables,
+/// just above the window used to read ACPI tables.
+const KERNEL_ADDRESS: usize = 0xfe100000;
+
+/// Physical address of the page, or zero until `init` has run
+static FRAME_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocate the page and map it for the kernel. Must run once the physical
+/// allocator is ready, before the first process is made current.
+pub fn init() {
+  let frame = physical::allocate_frame().expect("No memory for the shared data page");
+  CurrentPageDirectory::get().map(frame, VirtualAddress::new(KERNEL_ADDRESS), PermissionFlags::empty());
+  unsafe {
+    core::ptr::write_bytes(KERNEL_ADDRESS as *mut u8, 0, 0x1000);
+  }
+  FRAME_ADDRESS.store(frame.get_address().as_usize(), Ordering::SeqCst);
+}
+
+fn get_data() -> Option<*mut SharedData> {
+  match FRAME_ADDRESS.load(Ordering::SeqCst) {
+    0 => None,
+    _ => Some(KERNEL_ADDRESS as *mut SharedData),
+  }
+}
+
+/// The region that maps the page into a process. Its page is filled in on
+/// the first access, like any other region.
+pub fn get_region() -> Option<VirtualMemoryRegion> {
+  match FRAME_ADDRESS.load(Ordering::SeqCst) {
+    0 => None,
+    frame_address => Some(VirtualMemoryRegion::new(
+      VirtualAddress::new(SHARED_DATA_ADDRESS),
+      0x1000,
+      MemoryRegionType::Direct(FrameRange::new(frame_address, 0x1000)),
+      Permissions::ReadOnly,
+    )),
+  }
+}
+
+pub fn set_current_pid(pid: u32) {
+  if let Some(data) = get_data() {
+    unsafe { write_volatile(&mut (*data).pid, pid) };
+  }
+}
+
+/// Publish the clocks after a timer tick. Called with interrupts disabled, so
+/// a reader in userland only ever has to retry for a tick that interrupted
+/// its own read.
+pub fn publish_time(uptime: u64, system_time: u64) {
+  let data = match get_data() {
+    Some(data) => data,
+    None => return,
+  };
+  unsafe {
+    let sequence = read_volatile(&(*data).sequence);
+    write_volatile(&mut (*data).sequence, sequence.wrapping_add(1));
+    write_volatile(&mut (*data).ticks, read_volatile(&(*data).ticks).wrapping_add(1));
+    write_volatile(&mut (*data).uptime, uptime);
+    write_volatile(&mut (*data).system_time, system_time);
+    write_volatile(&mut (*data).sequence, sequence.wrapping_add(2));
+  }
+}
@@ -1,76 +1,349 @@
+use alloc::vec::Vec;
 use super::all_processes;
 use super::id::ProcessID;
 use super::process_state::{BlockReason, ProcessState, RunState};
+use syscall::result::SystemError;
+use syscall::signals::{self, SIGNAL_COUNT};
 
 fn exit_code(signal: u32, code: u32) -> u32 {
   ((code & 0xff) << 8) | (signal & 0x7f)
 }
 
+/// What a process does when it receives a signal
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum SignalAction {
+  /// Apply the signal's default action
+  Default,
+  /// Discard the signal
+  Ignore,
+  /// Run a function in userspace, which returns into `restorer`. The restorer
+  /// asks the kernel to resume the code the handler interrupted.
+  Handler { handler: u32, restorer: u32 },
+}
+
+/// Built-in behavior of a signal with no handler
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum DefaultAction {
+  Terminate,
+  Ignore,
+  Stop,
+  Continue,
+}
+
+pub fn default_action(sig: u32) -> DefaultAction {
+  match sig {
+    signals::CHILD => DefaultAction::Ignore,
+    signals::CONTINUE => DefaultAction::Continue,
+    signals::STOP | signals::TSTOP => DefaultAction::Stop,
+    _ => DefaultAction::Terminate,
+  }
+}
+
+/// KILL and STOP can never be handled, ignored, or blocked, so that there is
+/// always a way to end or pause a process
+fn is_catchable(sig: u32) -> bool {
+  sig != signals::KILL && sig != signals::STOP
+}
+
+fn signal_bit(sig: u32) -> u32 {
+  1 << sig
+}
+
+const UNCATCHABLE_MASK: u32 = (1 << signals::KILL) | (1 << signals::STOP);
+
+/// Per-process signal bookkeeping. Bit N of each mask refers to signal N.
+pub struct SignalState {
+  /// Signals that have been sent but not yet delivered
+  pending: u32,
+  /// Signals held as pending until they are unblocked
+  blocked: u32,
+  actions: [SignalAction; SIGNAL_COUNT as usize],
+  /// User stack addresses of the frames saved for handlers that have not yet
+  /// returned. A handler can itself be interrupted by another signal.
+  frames: Vec<u32>,
+}
+
+impl SignalState {
+  pub fn new() -> SignalState {
+    SignalState {
+      pending: 0,
+      blocked: 0,
+      actions: [SignalAction::Default; SIGNAL_COUNT as usize],
+      frames: Vec::new(),
+    }
+  }
+
+  /// A forked child keeps its parent's actions and mask, but none of the
+  /// signals waiting to be delivered to the parent
+  pub fn fork(&self) -> SignalState {
+    SignalState {
+      pending: 0,
+      blocked: self.blocked,
+      actions: self.actions,
+      frames: Vec::new(),
+    }
+  }
+
+  /// Handlers belong to the program being replaced by exec, so they are
+  /// returned to the default action. Ignored signals stay ignored.
+  pub fn reset_handlers(&mut self) {
+    for action in self.actions.iter_mut() {
+      if let SignalAction::Handler { .. } = action {
+        *action = SignalAction::Default;
+      }
+    }
+    self.frames.clear();
+  }
+
+  pub fn get_action(&self, sig: u32) -> SignalAction {
+    self.actions[sig as usize]
+  }
+
+  /// Change the action for a signal, returning the previous one
+  pub fn set_action(&mut self, sig: u32, action: SignalAction) -> Result<SignalAction, SystemError> {
+    if sig == 0 || sig >= SIGNAL_COUNT || !is_catchable(sig) {
+      return Err(SystemError::InvalidArgument);
+    }
+    let previous = self.actions[sig as usize];
+    self.actions[sig as usize] = action;
+    if action == SignalAction::Ignore {
+      self.pending &= !signal_bit(sig);
+    }
+    Ok(previous)
+  }
+
+  pub fn get_blocked(&self) -> u32 {
+    self.blocked
+  }
+
+  pub fn set_blocked(&mut self, mask: u32) {
+    self.blocked = mask & !UNCATCHABLE_MASK & !1;
+  }
+
+  pub fn get_pending(&self) -> u32 {
+    self.pending
+  }
+
+  pub fn is_blocked(&self, sig: u32) -> bool {
+    self.blocked & signal_bit(sig) != 0
+  }
+
+  pub fn mark_pending(&mut self, sig: u32) {
+    self.pending |= signal_bit(sig);
+  }
+
+  /// Remove the lowest numbered signal that is pending and not blocked
+  pub fn take_deliverable(&mut self) -> Option<u32> {
+    let ready = self.pending & !self.blocked;
+    if ready == 0 {
+      return None;
+    }
+    let sig = ready.trailing_zeros();
+    self.pending &= !signal_bit(sig);
+    Some(sig)
+  }
+
+  /// Record the frame saved for a handler that is about to run. The signal is
+  /// blocked until the handler returns, and the previous mask is returned so
+  /// it can be saved in the frame.
+  pub fn enter_handler(&mut self, sig: u32, frame_address: u32) -> u32 {
+    let previous = self.blocked;
+    self.set_blocked(previous | signal_bit(sig));
+    self.frames.push(frame_address);
+    previous
+  }
+
+  /// Remove the frame of the most recent handler, once it has returned
+  pub fn leave_handler(&mut self) -> Option<u32> {
+    self.frames.pop()
+  }
+}
+
 impl ProcessState {
-  /// Handle a signal number
+  /// Handle a signal number. KILL, STOP, and CONTINUE take effect right away.
+  /// Other signals with a handler are marked as pending, and the handler runs
+  /// the next time the process returns to userspace. Signals that are blocked
+  /// wait as pending until they are unblocked.
   pub fn send_signal(&self, sig: u32) {
-    match sig {
-      syscall::signals::STOP | syscall::signals::TSTOP => {
-        let mut run_state = self.get_run_state().write();
-        *run_state = RunState::Paused;
+    if sig == 0 || sig >= SIGNAL_COUNT {
+      return;
+    }
+    if sig == signals::CONTINUE {
+      // A stopped process always resumes, even if CONTINUE is handled
+      self.update_run_state(|state| match state {
+        RunState::Paused => Some(RunState::Running),
+        _ => None,
+      });
+    }
+    let (action, blocked) = {
+      let state = self.get_signal_state().read();
+      (state.get_action(sig), state.is_blocked(sig))
+    };
+    match action {
+      SignalAction::Ignore => (),
+      SignalAction::Handler { .. } => {
+        self.get_signal_state().write().mark_pending(sig);
+        self.interrupt_sleep();
       },
-      syscall::signals::CONTINUE => {
-        let mut run_state = self.get_run_state().write();
-        if *run_state == RunState::Paused {
-          *run_state = RunState::Running;
+      SignalAction::Default => {
+        if blocked {
+          self.get_signal_state().write().mark_pending(sig);
+        } else {
+          self.apply_default_action(sig);
         }
       },
+    }
+  }
 
-      syscall::signals::INT |
-      syscall::signals::TERM => {
-        // TODO: Check if there is a signal handler
-
-        self.terminate(sig, 0);
-      },
-      syscall::signals::KILL => {
-        self.terminate(sig, 0);
+  fn apply_default_action(&self, sig: u32) {
+    match default_action(sig) {
+      DefaultAction::Terminate => self.terminate(sig, 0),
+      DefaultAction::Stop => {
+        self.update_run_state(|state| match state {
+          RunState::Terminated => None,
+          _ => Some(RunState::Paused),
+        });
       },
+      DefaultAction::Ignore | DefaultAction::Continue => (),
+    }
+  }
 
-      _ => (),
+  /// Wake a sleeping process early, so that a handler can run without waiting
+  /// for the sleep to finish
+  fn interrupt_sleep(&self) {
+    self.update_run_state(|state| match state {
+      RunState::Sleeping(_) => Some(RunState::Running),
+      _ => None,
+    });
+  }
+
+  /// Find the next pending signal that has a handler to run. Pending signals
+  /// without a handler, which were blocked when they arrived, have their
+  /// default action applied along the way; if that terminates the process,
+  /// there is nothing left to run.
+  pub fn take_handled_signal(&self) -> Option<(u32, u32, u32)> {
+    loop {
+      let next = {
+        let mut state = self.get_signal_state().write();
+        state.take_deliverable().map(|sig| (sig, state.get_action(sig)))
+      };
+      let (sig, action) = next?;
+      match action {
+        SignalAction::Handler { handler, restorer } => return Some((sig, handler, restorer)),
+        SignalAction::Ignore => (),
+        SignalAction::Default => self.apply_default_action(sig),
+      }
+      if self.is_terminated() {
+        return None;
+      }
     }
   }
 
   /// Put the process in uninterruptible sleep, waiting for a resource. It can
   /// only be awoken with the resume() call.
   pub fn block(&self) {
-    let mut run_state = self.get_run_state().write();
-    *run_state = RunState::Blocked(BlockReason::None);
+    self.set_run_state(RunState::Blocked(BlockReason::None));
   }
 
-  pub fn block_on_child(&self, id: ProcessID) {
-    let mut run_state = self.get_run_state().write();
-    *run_state = RunState::Blocked(BlockReason::Child(id));
+  /// Block until a child exits. If `id` is None, any child will do.
+  pub fn block_on_child(&self, id: Option<ProcessID>) {
+    let reason = match id {
+      Some(id) => BlockReason::Child(id),
+      None => BlockReason::AnyChild,
+    };
+    self.set_run_state(RunState::Blocked(reason));
+  }
+
+  /// Block until a promise is resolved. Resolving the promise resumes the
+  /// process.
+  pub fn block_on_promise(&self) {
+    self.set_run_state(RunState::Blocked(BlockReason::Promise));
+  }
+
+  /// Block until notified through a WaitQueue
+  pub fn block_on_wait_queue(&self) {
+    self.set_run_state(RunState::Blocked(BlockReason::WaitQueue));
   }
 
   pub fn resume(&self) {
-    let mut run_state = self.get_run_state().write();
-    match *run_state {
-      RunState::Blocked(_) => {
-        *run_state = RunState::Running;
-      },
-      _ => (),
-    }
+    self.update_run_state(|state| match state {
+      RunState::Blocked(_) => Some(RunState::Running),
+      _ => None,
+    });
   }
 
   /// Kill the process, either because the process called exit() or a
-  /// terminating signal was sent
+  /// terminating signal was sent. The process stays in the process map as a
+  /// zombie, holding its exit status, until its parent reaps it with wait_pid.
   pub fn terminate(&self, signal: u32, code: u32) {
+    let terminated = self.update_run_state(|state| match state {
+      RunState::Terminated => None,
+      _ => Some(RunState::Terminated),
+    });
+    if !terminated {
+      return;
+    }
     self.set_exit_code(exit_code(signal, code));
-    let mut run_state = self.get_run_state().write();
-    *run_state = RunState::Terminated;
 
-    // Tell the parent process that the child has terminated
+    if self.is_thread() {
+      // The group keeps the thread's memory and handles, and the stack is
+      // free for the next thread once this one yields
+      super::thread::release_stack(&mut self.get_memory_regions().write(), self.get_id());
+    } else {
+      // Threads cannot outlive their leader's memory and handles
+      self.end_threads(signal, code);
+
+      // Closing files can wake other processes, such as readers at the other
+      // end of a pipe
+      self.close_all_handles();
+      self.release_mapped_files();
+      super::dos_mouse::forget(self.get_id());
+      super::dos_timer::forget(self.get_id());
+      super::dos_video::forget(self.get_id());
+      super::spawn::forget(self.get_id());
+    }
+
+    super::fpu::forget(self.get_id());
+    super::io_ports::forget(self.get_id());
+
     let current_id = self.get_id();
     let parent_id = self.get_parent();
     let processes = all_processes();
-    match processes.get_process(parent_id) {
-      Some(parent) => parent.child_exited(current_id, code),
-      None => (),
+
+    // Children outlive their parent, and are adopted by init, which becomes
+    // responsible for reaping them. If init itself exits, they pass to its
+    // parent instead.
+    let init_id = super::restart::get_init_process();
+    let adopter_id = if init_id == current_id {
+      parent_id
+    } else {
+      init_id
+    };
+    if adopter_id != current_id {
+      let exited = processes.reparent_children(current_id, adopter_id);
+      if let Some(adopter) = processes.get_process(adopter_id) {
+        for child in exited {
+          adopter.child_exited(child, 0);
+        }
+      }
+    }
+
+    // Tell the parent process that the child has terminated. The first process
+    // is its own parent, and has nobody to tell.
+    if parent_id != current_id {
+      if let Some(parent) = processes.get_process(parent_id) {
+        parent.child_exited(current_id, code);
+      }
+    }
+  }
+
+  /// Terminate every other live thread in this process's group
+  pub fn end_threads(&self, signal: u32, code: u32) {
+    let threads = all_processes().thread_group_members(self.get_thread_group());
+    for thread in threads {
+      if thread.get_id() != self.get_id() {
+        thread.terminate(signal, code);
+      }
     }
   }
 
@@ -78,27 +351,26 @@ impl ProcessState {
     self.terminate(0, code);
   }
 
+  /// Notify a parent that one of its children has terminated: SIGCHLD is
+  /// sent, and the parent is woken if it was waiting on that child
   pub fn child_exited(&self, child: ProcessID, code: u32) {
     self.send_signal(syscall::signals::CHILD);
-    let mut run_state = self.get_run_state().write();
-    match *run_state {
-      RunState::Blocked(BlockReason::Child(id)) => {
-        if id == child {
-          *run_state = RunState::Resumed(code);
-        }
-      },
-      _ => (),
-    }
+    self.update_run_state(|state| match state {
+      RunState::Blocked(BlockReason::Child(id)) if id == child => Some(RunState::Resumed(code)),
+      RunState::Blocked(BlockReason::AnyChild) => Some(RunState::Resumed(code)),
+      _ => None,
+    });
   }
 
   pub fn get_resume_code(&self) -> u32 {
-    let mut run_state = self.get_run_state().write();
-    match *run_state {
+    let mut resume_code = 0;
+    self.update_run_state(|state| match state {
       RunState::Resumed(code) => {
-        *run_state = RunState::Running;
-        code
+        resume_code = code;
+        Some(RunState::Running)
       },
-      _ => 0,
-    }
+      _ => None,
+    });
+    resume_code
   }
 }
\ No newline at end of file
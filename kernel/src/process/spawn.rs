@@ -0,0 +1,72 @@
+//! Spawning starts a program in a new child process without first copying the
+//! parent, the way a shell runs an external command. A fork followed by an
+//! exec duplicates the parent's memory and handles only to throw them away a
+//! moment later; a spawned child instead starts with an empty address space,
+//! a fresh kernel stack, and only the handles the parent passed to it.
+//!
+//! The parent opens the executable before the child exists, so a missing file
+//! is reported to the caller rather than as an exit code. The child's first
+//! switch lands in a trampoline that loads the program from that handle, the
+//! same way a kernel thread starts.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use crate::files::handle::{FileHandleMap, LocalHandle};
+use crate::filesystems;
+use spin::Mutex;
+use super::environment::Environment;
+use super::exec::InterpretationMode;
+use super::id::ProcessID;
+use super::memory::spawn_page_directory;
+
+/// Exit code of a spawned child that was removed before its program loaded
+const NOT_LOADED: u32 = 0xff;
+
+/// The program a spawned child runs once it is first scheduled
+struct SpawnTarget {
+  drive: usize,
+  handle: LocalHandle,
+  interp_mode: InterpretationMode,
+}
+
+static PENDING: Mutex<BTreeMap<ProcessID, SpawnTarget>> = Mutex::new(BTreeMap::new());
+
+/// Create a child of the current process that runs the executable open at
+/// `handle`. The child owns the handle from here on. It is runnable
+/// immediately, and first runs the next time the current process yields.
+pub fn spawn(drive: usize, handle: LocalHandle, interp_mode: InterpretationMode, command_line: String, environment: Environment, files: FileHandleMap) -> ProcessID {
+  let current = match super::current_process() {
+    Some(current) => current,
+    None => panic!("Cannot spawn without a current process"),
+  };
+  let pid = super::all_processes().get_next_pid();
+  let page_directory = spawn_page_directory(run_spawned as usize);
+  let child = current.spawn_child(pid, page_directory, files, command_line, environment);
+  PENDING.lock().insert(pid, SpawnTarget {
+    drive,
+    handle,
+    interp_mode,
+  });
+  super::all_processes_mut().add_process(pid, child);
+  pid
+}
+
+#[inline(never)]
+extern "C" fn run_spawned() {
+  let pid = super::get_current_pid();
+  let target = PENDING.lock().remove(&pid);
+  match target {
+    Some(target) => super::exec(target.drive, target.handle, target.interp_mode),
+    None => super::exit(NOT_LOADED),
+  }
+}
+
+/// Close the executable of a child that exited before loading it
+pub fn forget(pid: ProcessID) {
+  let target = PENDING.lock().remove(&pid);
+  if let Some(target) = target {
+    if let Some(fs) = filesystems::get_fs(target.drive) {
+      let _ = fs.close(target.handle);
+    }
+  }
+}
@@ -16,6 +16,8 @@ pub struct DosSubsystemMetadata {
   pub ss: usize,
 
   pub interrupts_enabled: bool,
+  /// Nesting count of VDS requests to disable DMA translation, per channel
+  pub dma_translation_disabled: [u8; 8],
 }
 
 impl DosSubsystemMetadata {
@@ -27,6 +29,7 @@ impl DosSubsystemMetadata {
       gs: 0,
       ss: 0,
       interrupts_enabled: false,
+      dma_translation_disabled: [0; 8],
     }
   }
 }
@@ -0,0 +1,157 @@
+//! A process can run more than one thread. Each thread is an entry in the
+//! process map with its own ID, registers, and run state, but the threads in a
+//! group share one page directory, one set of memory regions, and one table
+//! of open handles. The group is named after the thread that created the first
+//! of them, its leader.
+//!
+//! Every thread needs its own kernel stack, and since the directory is shared
+//! they cannot all live at the usual kernel stack address. Extra threads take
+//! a slot in a reserved range below the kernel thread stacks instead. The
+//! range is private to each page directory, so different groups reuse the
+//! same addresses. A slot is released when its thread terminates, and its
+//! frames stay mapped for the next thread in the group.
+
+use alloc::sync::Arc;
+use crate::memory::address::VirtualAddress;
+use crate::memory::physical;
+use crate::memory::virt::page_directory::{CurrentPageDirectory, PageDirectory, PermissionFlags};
+use crate::memory::virt::region::{ExpansionDirection, MemoryRegionType, Permissions, VirtualMemoryRegion};
+use super::id::ProcessID;
+use super::memory::MemoryRegions;
+use super::process_state::ProcessState;
+
+pub const STACKS_START: usize = 0xff000000;
+const STACKS_END: usize = super::kthread::STACKS_START;
+pub const STACK_PAGES: usize = 8;
+/// Each slot is a guard page followed by the stack
+const SLOT_SIZE: usize = (STACK_PAGES + 1) * 0x1000;
+pub const MAX_THREADS: usize = (STACKS_END - STACKS_START) / SLOT_SIZE;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ThreadError {
+  /// Every kernel stack slot in the group is taken
+  TooManyThreads,
+  /// There were no free frames for the kernel stack
+  OutOfMemory,
+  /// The entry point or stack is not in user memory
+  InvalidAddress,
+}
+
+pub fn stack_region(slot: usize) -> VirtualMemoryRegion {
+  let start = STACKS_START + slot * SLOT_SIZE + 0x1000;
+  VirtualMemoryRegion::new(
+    VirtualAddress::new(start),
+    STACK_PAGES * 0x1000,
+    MemoryRegionType::Anonymous(ExpansionDirection::None),
+    Permissions::ReadWrite,
+  )
+}
+
+/// Whether an address is in the unmapped page at the bottom of a slot
+pub fn is_guard_page(addr: usize) -> bool {
+  if addr < STACKS_START || addr >= STACKS_START + MAX_THREADS * SLOT_SIZE {
+    return false;
+  }
+  (addr - STACKS_START) % SLOT_SIZE < 0x1000
+}
+
+/// Find the slot holding a kernel stack, if it is a thread stack at all. A
+/// process forked from a thread keeps running on that thread's stack.
+pub fn stack_slot(stack: VirtualMemoryRegion) -> Option<usize> {
+  let start = stack.get_starting_address_as_usize();
+  if start < STACKS_START + 0x1000 || start >= STACKS_END {
+    return None;
+  }
+  Some((start - STACKS_START) / SLOT_SIZE)
+}
+
+/// Claim a kernel stack slot for `pid` in the current page directory, mapping
+/// its frames if no earlier thread has used it
+fn claim_stack(regions: &mut MemoryRegions, pid: ProcessID) -> Result<VirtualMemoryRegion, ThreadError> {
+  let free = regions.thread_stacks.iter()
+    .find(|(_, owner)| owner.is_none())
+    .map(|(slot, _)| *slot);
+  if let Some(slot) = free {
+    regions.thread_stacks.insert(slot, Some(pid));
+    return Ok(stack_region(slot));
+  }
+  let slot = (0..MAX_THREADS)
+    .find(|slot| !regions.thread_stacks.contains_key(slot))
+    .ok_or(ThreadError::TooManyThreads)?;
+  let region = stack_region(slot);
+  let pagedir = CurrentPageDirectory::get();
+  let mut page = region.get_starting_address();
+  for _ in 0..STACK_PAGES {
+    let frame = physical::allocate_frame().map_err(|_| ThreadError::OutOfMemory)?;
+    pagedir.map(frame, page, PermissionFlags::new(PermissionFlags::WRITE_ACCESS));
+    page = page.offset(0x1000);
+  }
+  regions.thread_stacks.insert(slot, Some(pid));
+  Ok(region)
+}
+
+/// Give up a thread's kernel stack slot, once the thread has terminated. The
+/// thread may still be running on it, but never runs again once it yields,
+/// and the next claim can only come from another thread.
+pub fn release_stack(regions: &mut MemoryRegions, pid: ProcessID) {
+  for owner in regions.thread_stacks.values_mut() {
+    if *owner == Some(pid) {
+      *owner = None;
+    }
+  }
+}
+
+/// Start another thread in the current process's group. It enters userspace
+/// at `entry` with its stack pointer just below `stack`, as if `entry` had
+/// been called with `arg` as its only argument. The entry function must exit
+/// rather than return, since it has nowhere to return to.
+pub fn spawn_thread(entry: VirtualAddress, stack: VirtualAddress, arg: u32) -> Result<ProcessID, ThreadError> {
+  if entry.as_usize() >= 0xc0000000 || stack.as_usize() >= 0xc0000000 || stack.as_usize() < 8 {
+    return Err(ThreadError::InvalidAddress);
+  }
+  let current: Arc<ProcessState> = match super::current_process() {
+    Some(current) => current,
+    None => return Err(ThreadError::InvalidAddress),
+  };
+  let pid = super::all_processes().get_next_pid();
+  let kernel_stack = claim_stack(&mut current.get_memory_regions().write(), pid)?;
+
+  // The user stack is shared memory, so the argument can be written directly
+  let user_esp = stack.as_usize() - 8;
+  unsafe {
+    let user_stack = user_esp as *mut u32;
+    // Return address, followed by the argument
+    *user_stack = 0;
+    *user_stack.offset(1) = arg;
+  }
+
+  // The first switch to the thread returns into the trampoline, which pops
+  // the interrupt frame below it to enter userspace
+  let stack_top = kernel_stack.get_starting_address_as_usize() + kernel_stack.get_size() - 4;
+  let frame = unsafe {
+    let frame = (stack_top - 4 * 6) as *mut usize;
+    *frame = enter_thread as usize;
+    // Instruction pointer
+    *frame.offset(1) = entry.as_usize();
+    // Code segment
+    *frame.offset(2) = 0x1b;
+    // eflags, with interrupts enabled
+    *frame.offset(3) = 0x200;
+    // Stack pointer
+    *frame.offset(4) = user_esp;
+    // Stack segment
+    *frame.offset(5) = 0x23;
+    frame as usize
+  };
+
+  let thread = current.clone_thread(pid, kernel_stack);
+  *thread.get_kernel_stack_container().write() = frame;
+  super::all_processes_mut().add_process(pid, thread);
+  Ok(pid)
+}
+
+#[naked]
+#[inline(never)]
+unsafe extern "C" fn enter_thread() {
+  llvm_asm!("iretd" : : : : "intel", "volatile");
+}
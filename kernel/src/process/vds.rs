@@ -0,0 +1,370 @@
+//! Virtual DMA Services, the INT 4Bh interface that lets DOS programs set up
+//! DMA transfers while running under paging. A DOS box's linear addresses are
+//! not physical ones, and its memory may be scattered, copy-on-write, or above
+//! the 16MiB an ISA controller can reach. Programs that speak VDS ask for the
+//! physical address of a buffer before programming the DMA controller, and
+//! the kernel either translates the region in place, or substitutes a buffer
+//! of its own that is suitable for DMA and copies data through it.
+//!
+//! Only the contiguous-region functions are supported. Scatter/gather requests
+//! fail with "function not supported", which VDS clients are expected to
+//! handle by falling back to a locked region.
+
+use crate::interrupts::stack::StackFrame;
+use crate::interrupts::syscall_legacy::{DosApiRegisters, VM8086Frame};
+use crate::memory::address::{PhysicalAddress, VirtualAddress};
+use crate::memory::virt::page_directory::CurrentPageDirectory;
+use spin::Mutex;
+use super::id::ProcessID;
+use super::process_state::ProcessState;
+use super::subsystem::Subsystem;
+
+const FLAG_CARRY: u32 = 1;
+
+/// Byte in the BIOS data area whose bit 5 announces that VDS is available
+const BDA_VDS_FLAGS: usize = 0x47b;
+const BDA_VDS_PRESENT: u8 = 1 << 5;
+
+/// Size of the kernel's substitute buffer, and the largest region that can be
+/// copied through it
+pub const BUFFER_SIZE: usize = 0x10000;
+/// ID handed to DOS programs for the substitute buffer. Zero means no buffer.
+const BUFFER_ID: u16 = 1;
+
+/// ISA DMA addresses are 24 bits wide
+const DMA_ADDRESS_LIMIT: usize = 0x1000000;
+
+// Flags passed in DX
+const COPY_DATA: u16 = 1 << 1;
+const NO_AUTO_BUFFER: u16 = 1 << 2;
+const NO_CROSS_64K: u16 = 1 << 4;
+const NO_CROSS_128K: u16 = 1 << 5;
+
+// Error codes returned in AL
+const ERROR_NOT_CONTIGUOUS: u8 = 0x01;
+const ERROR_CROSSED_BOUNDARY: u8 = 0x02;
+const ERROR_NO_BUFFER: u8 = 0x04;
+const ERROR_REGION_TOO_LARGE: u8 = 0x05;
+const ERROR_BUFFER_IN_USE: u8 = 0x06;
+const ERROR_INVALID_REGION: u8 = 0x07;
+const ERROR_INVALID_BUFFER: u8 = 0x0a;
+const ERROR_COPY_OUT_OF_RANGE: u8 = 0x0b;
+const ERROR_INVALID_CHANNEL: u8 = 0x0c;
+const ERROR_DISABLE_OVERFLOW: u8 = 0x0d;
+const ERROR_DISABLE_UNDERFLOW: u8 = 0x0e;
+const ERROR_NOT_SUPPORTED: u8 = 0x0f;
+
+/// The DMA Descriptor Structure that programs pass in ES:DI
+struct Descriptor {
+  size: u32,
+  offset: u32,
+  segment: u16,
+  buffer_id: u16,
+  physical: u32,
+}
+
+impl Descriptor {
+  unsafe fn read(address: usize) -> Descriptor {
+    Descriptor {
+      size: core::ptr::read_unaligned(address as *const u32),
+      offset: core::ptr::read_unaligned((address + 4) as *const u32),
+      segment: core::ptr::read_unaligned((address + 8) as *const u16),
+      buffer_id: core::ptr::read_unaligned((address + 0x0a) as *const u16),
+      physical: core::ptr::read_unaligned((address + 0x0c) as *const u32),
+    }
+  }
+
+  unsafe fn write(&self, address: usize) {
+    core::ptr::write_unaligned(address as *mut u32, self.size);
+    core::ptr::write_unaligned((address + 0x0a) as *mut u16, self.buffer_id);
+    core::ptr::write_unaligned((address + 0x0c) as *mut u32, self.physical);
+  }
+
+  /// Linear address of the region within the DOS box
+  fn linear_address(&self) -> usize {
+    ((self.segment as usize) << 4) + self.offset as usize
+  }
+}
+
+/// The substitute buffer is shared by every DOS box, and lent to one at a time
+struct SubstituteBuffer {
+  addresses: Option<(PhysicalAddress, VirtualAddress)>,
+  owner: Option<ProcessID>,
+}
+
+static BUFFER: Mutex<SubstituteBuffer> = Mutex::new(SubstituteBuffer {
+  addresses: None,
+  owner: None,
+});
+
+/// Set the BIOS data area flag that VDS clients check before calling INT 4Bh.
+/// Must be called from the DOS box, once its low memory is mapped.
+pub fn announce() {
+  unsafe {
+    let flags = BDA_VDS_FLAGS as *mut u8;
+    flags.write_volatile(flags.read_volatile() | BDA_VDS_PRESENT);
+  }
+}
+
+/// Service an INT 4Bh call. Returns false if AH does not select VDS, so the
+/// interrupt should be passed on to the program's own handler.
+pub unsafe fn handle_vds(
+  process: &ProcessState,
+  stack_frame: &mut StackFrame,
+  regs: &mut DosApiRegisters,
+  vm_frame: &mut VM8086Frame,
+) -> bool {
+  if (regs.ax >> 8) & 0xff != 0x81 {
+    return false;
+  }
+  let descriptor_address = (((vm_frame.es & 0xffff) << 4) + (regs.di & 0xffff)) as usize;
+  let flags = regs.dx as u16;
+  let result = match regs.ax & 0xff {
+    0x02 => get_version(regs),
+    0x03 => lock_region(process, descriptor_address, flags),
+    0x04 => unlock_region(process, descriptor_address, flags),
+    0x07 => request_buffer(process, descriptor_address, flags),
+    0x08 => release_buffer(process, descriptor_address, flags),
+    0x09 | 0x0a => {
+      let offset = (((regs.bx & 0xffff) << 16) | (regs.cx & 0xffff)) as usize;
+      copy_buffer(process, descriptor_address, offset, regs.ax & 0xff == 0x09)
+    },
+    0x0b => disable_translation(process, regs.bx as usize & 0xffff),
+    0x0c => enable_translation(process, regs.bx as usize & 0xffff),
+    _ => Err(ERROR_NOT_SUPPORTED),
+  };
+  match result {
+    Ok(()) => stack_frame.eflags &= !FLAG_CARRY,
+    Err(code) => {
+      regs.ax = (regs.ax & 0xffffff00) | code as u32;
+      stack_frame.eflags |= FLAG_CARRY;
+    },
+  }
+  true
+}
+
+fn get_version(regs: &mut DosApiRegisters) -> Result<(), u8> {
+  // Version 1.0, with no special product number or revision
+  regs.ax = (regs.ax & 0xffff0000) | 0x0100;
+  regs.bx = 0;
+  regs.cx = 0;
+  // Maximum buffer size in SI:DI
+  regs.si = (BUFFER_SIZE >> 16) as u32;
+  regs.di = (BUFFER_SIZE & 0xffff) as u32;
+  // No special bus or memory properties
+  regs.dx = 0;
+  Ok(())
+}
+
+/// Whether a physical range can be handed to the DMA controller as-is
+fn dma_reachable(start: usize, size: usize, flags: u16) -> Result<(), u8> {
+  if size == 0 {
+    return Ok(());
+  }
+  let end = start + size - 1;
+  if end >= DMA_ADDRESS_LIMIT {
+    return Err(ERROR_NOT_CONTIGUOUS);
+  }
+  if flags & NO_CROSS_64K != 0 && start >> 16 != end >> 16 {
+    return Err(ERROR_CROSSED_BOUNDARY);
+  }
+  if flags & NO_CROSS_128K != 0 && start >> 17 != end >> 17 {
+    return Err(ERROR_CROSSED_BOUNDARY);
+  }
+  Ok(())
+}
+
+/// Make sure each page of a region is present and private to the DOS box, then
+/// find how many bytes from its start are physically contiguous. Writing each
+/// page back to itself resolves demand-paged and copy-on-write pages, so the
+/// frames found cannot change underneath a transfer.
+unsafe fn translate_region(start: usize, size: usize) -> Result<(PhysicalAddress, usize), u8> {
+  if start + size > 0x110000 {
+    return Err(ERROR_INVALID_REGION);
+  }
+  let pagedir = CurrentPageDirectory::get();
+  let mut first = None;
+  let mut contiguous = 0;
+  let mut address = start;
+  let end = start + size.max(1);
+  while address < end {
+    let byte = address as *mut u8;
+    byte.write_volatile(byte.read_volatile());
+    let physical = pagedir.get_physical_address(VirtualAddress::new(address)).ok_or(ERROR_INVALID_REGION)?;
+    let page_end = ((address & !0xfff) + 0x1000).min(end);
+    match first {
+      None => first = Some(physical),
+      Some(first) if first.as_usize() + contiguous == physical.as_usize() => (),
+      Some(_) => break,
+    }
+    contiguous += page_end - address;
+    address = page_end;
+  }
+  let first = first.ok_or(ERROR_INVALID_REGION)?;
+  Ok((first, contiguous.min(size)))
+}
+
+fn get_buffer_addresses() -> Option<(PhysicalAddress, VirtualAddress)> {
+  let mut buffer = BUFFER.lock();
+  if buffer.addresses.is_none() {
+    let current = super::current_process()?;
+    buffer.addresses = Some(current.kernel_mmap_dma(BUFFER_SIZE));
+  }
+  buffer.addresses
+}
+
+/// Lend the substitute buffer to a process. A buffer held by a process that
+/// has since exited is taken back first.
+fn claim_buffer(process: &ProcessState, size: usize, flags: u16) -> Result<PhysicalAddress, u8> {
+  if size > BUFFER_SIZE {
+    return Err(ERROR_REGION_TOO_LARGE);
+  }
+  let (physical, _) = get_buffer_addresses().ok_or(ERROR_NO_BUFFER)?;
+  dma_reachable(physical.as_usize(), size, flags)?;
+  let mut buffer = BUFFER.lock();
+  if let Some(owner) = buffer.owner {
+    let owner_alive = super::all_processes()
+      .get_process(owner)
+      .map(|owner| !owner.is_terminated())
+      .unwrap_or(false);
+    if owner_alive {
+      return Err(ERROR_BUFFER_IN_USE);
+    }
+  }
+  buffer.owner = Some(process.get_id());
+  Ok(physical)
+}
+
+fn check_buffer(process: &ProcessState, buffer_id: u16) -> Result<VirtualAddress, u8> {
+  let buffer = BUFFER.lock();
+  if buffer_id != BUFFER_ID || buffer.owner != Some(process.get_id()) {
+    return Err(ERROR_INVALID_BUFFER);
+  }
+  buffer.addresses.map(|(_, virt)| virt).ok_or(ERROR_INVALID_BUFFER)
+}
+
+fn free_buffer(process: &ProcessState) {
+  let mut buffer = BUFFER.lock();
+  if buffer.owner == Some(process.get_id()) {
+    buffer.owner = None;
+  }
+}
+
+/// Copy between a DOS box region and the substitute buffer, starting
+/// `offset` bytes into the buffer
+unsafe fn copy_through_buffer(buffer: VirtualAddress, offset: usize, region: usize, size: usize, into_buffer: bool) -> Result<(), u8> {
+  if offset + size > BUFFER_SIZE {
+    return Err(ERROR_COPY_OUT_OF_RANGE);
+  }
+  let buffer_ptr = (buffer.as_usize() + offset) as *mut u8;
+  let region_ptr = region as *mut u8;
+  if into_buffer {
+    core::ptr::copy_nonoverlapping(region_ptr, buffer_ptr, size);
+  } else {
+    core::ptr::copy_nonoverlapping(buffer_ptr, region_ptr, size);
+  }
+  Ok(())
+}
+
+unsafe fn lock_region(process: &ProcessState, descriptor_address: usize, flags: u16) -> Result<(), u8> {
+  let mut descriptor = Descriptor::read(descriptor_address);
+  let start = descriptor.linear_address();
+  let size = descriptor.size as usize;
+  let (physical, contiguous) = translate_region(start, size)?;
+  let in_place = if contiguous < size {
+    Err(ERROR_NOT_CONTIGUOUS)
+  } else {
+    dma_reachable(physical.as_usize(), size, flags)
+  };
+  match in_place {
+    Ok(()) => {
+      descriptor.buffer_id = 0;
+      descriptor.physical = physical.as_u32();
+    },
+    Err(code) if flags & NO_AUTO_BUFFER != 0 => {
+      // Report how much of the region could have been used as it is
+      descriptor.size = contiguous as u32;
+      descriptor.write(descriptor_address);
+      return Err(code);
+    },
+    Err(_) => {
+      let buffer_physical = claim_buffer(process, size, flags)?;
+      if flags & COPY_DATA != 0 {
+        let buffer = check_buffer(process, BUFFER_ID)?;
+        copy_through_buffer(buffer, 0, start, size, true)?;
+      }
+      descriptor.buffer_id = BUFFER_ID;
+      descriptor.physical = buffer_physical.as_u32();
+    },
+  }
+  descriptor.write(descriptor_address);
+  Ok(())
+}
+
+unsafe fn unlock_region(process: &ProcessState, descriptor_address: usize, flags: u16) -> Result<(), u8> {
+  let descriptor = Descriptor::read(descriptor_address);
+  if descriptor.buffer_id == 0 {
+    // Regions used in place hold nothing that needs to be released
+    return Ok(());
+  }
+  release(process, &descriptor, flags)
+}
+
+unsafe fn release(process: &ProcessState, descriptor: &Descriptor, flags: u16) -> Result<(), u8> {
+  let buffer = check_buffer(process, descriptor.buffer_id)?;
+  if flags & COPY_DATA != 0 {
+    copy_through_buffer(buffer, 0, descriptor.linear_address(), descriptor.size as usize, false)?;
+  }
+  free_buffer(process);
+  Ok(())
+}
+
+unsafe fn request_buffer(process: &ProcessState, descriptor_address: usize, flags: u16) -> Result<(), u8> {
+  let mut descriptor = Descriptor::read(descriptor_address);
+  let size = descriptor.size as usize;
+  let physical = claim_buffer(process, size, flags)?;
+  if flags & COPY_DATA != 0 {
+    let buffer = check_buffer(process, BUFFER_ID)?;
+    copy_through_buffer(buffer, 0, descriptor.linear_address(), size, true)?;
+  }
+  descriptor.buffer_id = BUFFER_ID;
+  descriptor.physical = physical.as_u32();
+  descriptor.write(descriptor_address);
+  Ok(())
+}
+
+unsafe fn release_buffer(process: &ProcessState, descriptor_address: usize, flags: u16) -> Result<(), u8> {
+  let descriptor = Descriptor::read(descriptor_address);
+  release(process, &descriptor, flags)
+}
+
+unsafe fn copy_buffer(process: &ProcessState, descriptor_address: usize, offset: usize, into_buffer: bool) -> Result<(), u8> {
+  let descriptor = Descriptor::read(descriptor_address);
+  let buffer = check_buffer(process, descriptor.buffer_id)?;
+  copy_through_buffer(buffer, offset, descriptor.linear_address(), descriptor.size as usize, into_buffer)
+}
+
+/// Programs that set up their own translation can ask for it to be turned
+/// off on a channel. The kernel never translates behind a program's back, so
+/// only the nesting count is kept, to report mismatched calls.
+fn disable_translation(process: &ProcessState, channel: usize) -> Result<(), u8> {
+  update_translation_count(process, channel, |count| count.checked_add(1).ok_or(ERROR_DISABLE_OVERFLOW))
+}
+
+fn enable_translation(process: &ProcessState, channel: usize) -> Result<(), u8> {
+  update_translation_count(process, channel, |count| count.checked_sub(1).ok_or(ERROR_DISABLE_UNDERFLOW))
+}
+
+fn update_translation_count<F: FnOnce(u8) -> Result<u8, u8>>(process: &ProcessState, channel: usize, f: F) -> Result<(), u8> {
+  if channel > 7 {
+    return Err(ERROR_INVALID_CHANNEL);
+  }
+  match *process.get_subsystem().write() {
+    Subsystem::DOS(ref mut meta) => {
+      let count = &mut meta.dma_translation_disabled[channel];
+      *count = f(*count)?;
+      Ok(())
+    },
+    Subsystem::Native => Err(ERROR_NOT_SUPPORTED),
+  }
+}
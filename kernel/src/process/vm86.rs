@@ -0,0 +1,323 @@
+//! Support for running real-mode DOS programs in Virtual 8086 mode.
+//!
+//! A VM86 process runs with IOPL 0, so any instruction that touches the
+//! interrupt flag, performs port IO, or issues a software interrupt will raise
+//! a General Protection Fault. The GPF handler hands those faults to this
+//! module, which decodes the faulting instruction and emulates it against the
+//! process's virtual machine state.
+//! The real interrupt flag is never cleared on behalf of a DOS program; it only
+//! modifies a virtual copy stored in the process subsystem metadata.
+
+use crate::interrupts::stack::StackFrame;
+use crate::interrupts::syscall_legacy::{dos_api, DosApiRegisters, VM8086Frame};
+use super::process_state::ProcessState;
+use super::subsystem::{DosSubsystemMetadata, Subsystem};
+
+const FLAG_INTERRUPT: u32 = 0x200;
+const FLAG_IOPL: u32 = 0x3000;
+const FLAG_VM: u32 = 0x20000;
+
+/// Result of attempting to emulate a trapped instruction
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TrapResult {
+  /// The instruction was emulated, and the program can continue
+  Handled,
+  /// The instruction could not be emulated
+  Unhandled(u8),
+}
+
+impl ProcessState {
+  /// Switch the process to the DOS subsystem. The next time it enters
+  /// userspace, it will be running in Virtual 8086 mode with the provided
+  /// segment registers.
+  pub fn enter_vm86(&self, metadata: DosSubsystemMetadata) {
+    *self.get_subsystem().write() = Subsystem::DOS(metadata);
+  }
+
+  pub fn get_virtual_interrupt_flag(&self) -> bool {
+    match *self.get_subsystem().read() {
+      Subsystem::DOS(meta) => meta.interrupts_enabled,
+      Subsystem::Native => true,
+    }
+  }
+
+  pub fn set_virtual_interrupt_flag(&self, enabled: bool) {
+    if let Subsystem::DOS(ref mut meta) = *self.get_subsystem().write() {
+      meta.interrupts_enabled = enabled;
+    }
+  }
+}
+
+/// Convert a real-mode segment:offset pair to a linear address
+fn linear_address(segment: u32, offset: u32) -> usize {
+  (((segment & 0xffff) << 4) + (offset & 0xffff)) as usize
+}
+
+unsafe fn read_u8(segment: u32, offset: u32) -> u8 {
+  *(linear_address(segment, offset) as *const u8)
+}
+
+unsafe fn read_u16(segment: u32, offset: u32) -> u16 {
+  core::ptr::read_unaligned(linear_address(segment, offset) as *const u16)
+}
+
+pub unsafe fn push_u16(vm_frame: &mut VM8086Frame, value: u16) {
+  let sp = vm_frame.sp.wrapping_sub(2) & 0xffff;
+  vm_frame.sp = sp;
+  core::ptr::write_unaligned(linear_address(vm_frame.ss, sp) as *mut u16, value);
+}
+
+unsafe fn pop_u16(vm_frame: &mut VM8086Frame) -> u16 {
+  let sp = vm_frame.sp;
+  let value = read_u16(vm_frame.ss, sp);
+  vm_frame.sp = sp.wrapping_add(2) & 0xffff;
+  value
+}
+
+/// Build the FLAGS value that the DOS program expects to see, substituting
+/// the virtual interrupt flag for the real one
+fn virtual_flags(eflags: u32, vif: bool) -> u32 {
+  let base = eflags & !(FLAG_INTERRUPT | FLAG_IOPL | FLAG_VM);
+  if vif {
+    base | FLAG_INTERRUPT
+  } else {
+    base
+  }
+}
+
+/// Apply a FLAGS value written by the DOS program. Privileged bits are kept
+/// from the real flags, and the interrupt bit is redirected to the virtual
+/// flag.
+fn apply_flags(current: u32, requested: u32, mask: u32) -> (u32, bool) {
+  let protected = FLAG_INTERRUPT | FLAG_IOPL | FLAG_VM;
+  let writable = mask & !protected;
+  let eflags = (current & !writable) | (requested & writable);
+  (eflags, requested & FLAG_INTERRUPT != 0)
+}
+
+/// Port reads from a VM86 program are virtualized, one byte at a time. The
+/// box's timer, interrupt controller, and video adapter are emulated, and
+/// other ports float high the way an empty ISA bus would.
+fn emulate_port_read(process: &ProcessState, port: u16, width: usize) -> u32 {
+  let pid = process.get_id();
+  let mut value = 0;
+  for index in 0..width {
+    let byte_port = port.wrapping_add(index as u16);
+    let byte = super::dos_timer::read_port(pid, byte_port)
+      .or_else(|| super::dos_video::read_port(pid, byte_port))
+      .unwrap_or(0xff);
+    value |= (byte as u32) << (index * 8);
+  }
+  value
+}
+
+/// Port writes reach the box's virtual devices, and are discarded otherwise
+fn emulate_port_write(process: &ProcessState, port: u16, width: usize, value: u32) {
+  let pid = process.get_id();
+  for index in 0..width {
+    let byte_port = port.wrapping_add(index as u16);
+    let byte = (value >> (index * 8)) as u8;
+    if !super::dos_timer::write_port(pid, byte_port, byte) {
+      super::dos_video::write_port(pid, byte_port, byte);
+    }
+  }
+}
+
+fn set_accumulator(regs: &mut DosApiRegisters, width: usize, value: u32) {
+  regs.ax = match width {
+    1 => (regs.ax & 0xffffff00) | (value & 0xff),
+    2 => (regs.ax & 0xffff0000) | (value & 0xffff),
+    _ => value,
+  };
+}
+
+fn get_accumulator(regs: &DosApiRegisters, width: usize) -> u32 {
+  match width {
+    1 => regs.ax & 0xff,
+    2 => regs.ax & 0xffff,
+    _ => regs.ax,
+  }
+}
+
+/// Simulate a real-mode interrupt by pushing FLAGS, CS, and IP onto the
+/// program's stack and jumping through the interrupt vector table
+unsafe fn reflect_interrupt(
+  process: &ProcessState,
+  vector: u8,
+  return_ip: u32,
+  stack_frame: &mut StackFrame,
+  vm_frame: &mut VM8086Frame,
+) {
+  let vif = process.get_virtual_interrupt_flag();
+  push_u16(vm_frame, virtual_flags(stack_frame.eflags, vif) as u16);
+  push_u16(vm_frame, stack_frame.cs as u16);
+  push_u16(vm_frame, return_ip as u16);
+  process.set_virtual_interrupt_flag(false);
+
+  let vector_offset = vector as u32 * 4;
+  stack_frame.eip = read_u16(0, vector_offset) as u32;
+  stack_frame.cs = read_u16(0, vector_offset + 2) as u32;
+}
+
+/// Longest instruction the CPU will decode, prefixes included
+const MAX_INSTRUCTION_LENGTH: u32 = 15;
+
+/// Move IP forward within the code segment. Real-mode offsets are 16 bits, so
+/// an instruction at the end of a segment continues at its start.
+fn advance_ip(ip: u32, count: u32) -> u32 {
+  (ip + count) & 0xffff
+}
+
+/**
+ * Decode and emulate the instruction that caused a GPF inside a VM86 process.
+ * On success, the stack frame and registers are updated so that returning from
+ * the exception resumes the program after the emulated instruction.
+ */
+pub unsafe fn handle_gpf(
+  process: &ProcessState,
+  stack_frame: &mut StackFrame,
+  regs: &mut DosApiRegisters,
+  vm_frame: &mut VM8086Frame,
+) -> TrapResult {
+  let cs = stack_frame.cs;
+  let start_ip = stack_frame.eip;
+  let mut ip = start_ip;
+  let mut operand_32 = false;
+
+  let mut op = read_u8(cs, ip);
+  // Consume any prefixes. The CPU refuses to run an instruction longer than
+  // 15 bytes, so a longer run of prefixes is left to fault.
+  let mut prefix_count = 0;
+  loop {
+    match op {
+      0x66 => operand_32 = true,
+      // Segment overrides and REP are irrelevant to the instructions emulated
+      // here
+      0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0xf2 | 0xf3 => (),
+      _ => break,
+    }
+    prefix_count += 1;
+    if prefix_count >= MAX_INSTRUCTION_LENGTH {
+      return TrapResult::Unhandled(op);
+    }
+    ip = advance_ip(ip, 1);
+    op = read_u8(cs, ip);
+  }
+  let word_width = if operand_32 { 4 } else { 2 };
+
+  match op {
+    0xfa => { // CLI
+      process.set_virtual_interrupt_flag(false);
+      stack_frame.eip = advance_ip(ip, 1);
+    },
+    0xfb => { // STI
+      process.set_virtual_interrupt_flag(true);
+      stack_frame.eip = advance_ip(ip, 1);
+    },
+    0x9c => { // PUSHF
+      let flags = virtual_flags(stack_frame.eflags, process.get_virtual_interrupt_flag());
+      if operand_32 {
+        push_u16(vm_frame, (flags >> 16) as u16);
+      }
+      push_u16(vm_frame, flags as u16);
+      stack_frame.eip = advance_ip(ip, 1);
+    },
+    0x9d => { // POPF
+      let mut requested = pop_u16(vm_frame) as u32;
+      let mut mask = 0xffff;
+      if operand_32 {
+        requested |= (pop_u16(vm_frame) as u32) << 16;
+        mask = 0xffffffff;
+      }
+      let (eflags, vif) = apply_flags(stack_frame.eflags, requested, mask);
+      stack_frame.eflags = eflags;
+      process.set_virtual_interrupt_flag(vif);
+      stack_frame.eip = advance_ip(ip, 1);
+    },
+    0xcf => { // IRET
+      let new_ip = pop_u16(vm_frame) as u32;
+      let new_cs = pop_u16(vm_frame) as u32;
+      let requested = pop_u16(vm_frame) as u32;
+      let (eflags, vif) = apply_flags(stack_frame.eflags, requested, 0xffff);
+      stack_frame.eflags = eflags;
+      stack_frame.cs = new_cs;
+      stack_frame.eip = new_ip;
+      process.set_virtual_interrupt_flag(vif);
+    },
+    0xcd => { // INT imm8
+      let vector = read_u8(cs, advance_ip(ip, 1));
+      match vector {
+        0x21 => {
+          // DOS API calls are serviced directly by the kernel
+          dos_api(regs, vm_frame);
+          stack_frame.eip = advance_ip(ip, 2);
+        },
+        0x4b if super::vds::handle_vds(process, stack_frame, regs, vm_frame) => {
+          stack_frame.eip = advance_ip(ip, 2);
+        },
+        0x33 => {
+          super::dos_mouse::handle_int33(process, regs, vm_frame);
+          stack_frame.eip = advance_ip(ip, 2);
+        },
+        0x10 => {
+          // Mode changes are watched so the mouse cursor can follow them
+          if regs.ax & 0xff00 == 0 {
+            super::dos_mouse::set_video_mode(process, regs.ax as u8);
+          }
+          reflect_interrupt(process, vector, advance_ip(ip, 2), stack_frame, vm_frame);
+        },
+        _ => reflect_interrupt(process, vector, advance_ip(ip, 2), stack_frame, vm_frame),
+      }
+    },
+    0xcc => { // INT3
+      reflect_interrupt(process, 3, advance_ip(ip, 1), stack_frame, vm_frame);
+    },
+    0xe4 | 0xe5 => { // IN AL/AX, imm8
+      let port = read_u8(cs, advance_ip(ip, 1)) as u16;
+      let width = if op == 0xe4 { 1 } else { word_width };
+      set_accumulator(regs, width, emulate_port_read(process, port, width));
+      stack_frame.eip = advance_ip(ip, 2);
+    },
+    0xe6 | 0xe7 => { // OUT imm8, AL/AX
+      let port = read_u8(cs, advance_ip(ip, 1)) as u16;
+      let width = if op == 0xe6 { 1 } else { word_width };
+      emulate_port_write(process, port, width, get_accumulator(regs, width));
+      stack_frame.eip = advance_ip(ip, 2);
+    },
+    0xec | 0xed => { // IN AL/AX, DX
+      let port = regs.dx as u16;
+      let width = if op == 0xec { 1 } else { word_width };
+      set_accumulator(regs, width, emulate_port_read(process, port, width));
+      stack_frame.eip = advance_ip(ip, 1);
+    },
+    0xee | 0xef => { // OUT DX, AL/AX
+      let port = regs.dx as u16;
+      let width = if op == 0xee { 1 } else { word_width };
+      emulate_port_write(process, port, width, get_accumulator(regs, width));
+      stack_frame.eip = advance_ip(ip, 1);
+    },
+    0xf4 if linear_address(cs, ip) == super::dos_mouse::CALLBACK_RETURN => { // HLT
+      if !super::dos_mouse::return_from_callback(process, stack_frame, regs, vm_frame) {
+        return TrapResult::Unhandled(op);
+      }
+    },
+    _ => return TrapResult::Unhandled(op),
+  }
+  deliver_timer_interrupt(process, stack_frame, vm_frame);
+  super::dos_mouse::sync(process, stack_frame, regs, vm_frame);
+  TrapResult::Handled
+}
+
+/// Reflect a pending virtual IRQ0 into the box, if it can take one now. Runs
+/// whenever the box traps into the kernel, and from the host timer interrupt
+/// when it arrives while the box is running.
+pub unsafe fn deliver_timer_interrupt(
+  process: &ProcessState,
+  stack_frame: &mut StackFrame,
+  vm_frame: &mut VM8086Frame,
+) {
+  let vif = process.get_virtual_interrupt_flag();
+  if let Some(vector) = super::dos_timer::take_interrupt(process.get_id(), vif) {
+    reflect_interrupt(process, vector, stack_frame.eip, stack_frame, vm_frame);
+  }
+}
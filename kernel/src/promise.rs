@@ -1,36 +1,57 @@
 use alloc::sync::Arc;
 use spin::RwLock;
 
+/// State shared between a promise and every copy of its value
+struct PromiseState<T: Copy> {
+  value: RwLock<Option<T>>,
+  /// Process blocked in `PromiseValue::wait`, stored as a raw process ID. It
+  /// is resumed as soon as the promise is resolved.
+  waiter: RwLock<Option<u32>>,
+}
+
 pub struct Promise<T: Copy> {
-  value: Arc<RwLock<Option<T>>>,
+  state: Arc<PromiseState<T>>,
 }
 
 impl<T: Copy> Promise<T> {
   pub fn new() -> Promise<T> {
     Promise {
-      value: Arc::new(RwLock::new(None)),
+      state: Arc::new(PromiseState {
+        value: RwLock::new(None),
+        waiter: RwLock::new(None),
+      }),
     }
   }
 
+  /// Create a promise that already holds its value, for work that completed
+  /// without needing to wait
+  pub fn resolved(value: T) -> Promise<T> {
+    let promise = Promise::new();
+    promise.resolve(value);
+    promise
+  }
+
   pub fn resolve(&self, value: T) {
-    let mut lock = self.value.write();
-    *lock = Some(value);
+    {
+      let mut lock = self.state.value.write();
+      *lock = Some(value);
+    }
+    let waiter = self.state.waiter.write().take();
+    if let Some(pid) = waiter {
+      wake_waiter(pid);
+    }
   }
 
   pub fn get_value(&self) -> PromiseValue<T> {
-    PromiseValue::new(&self.value)
+    PromiseValue(Arc::clone(&self.state))
   }
 }
 
-pub struct PromiseValue<T: Copy>(Arc<RwLock<Option<T>>>);
+pub struct PromiseValue<T: Copy>(Arc<PromiseState<T>>);
 
 impl<T: Copy> PromiseValue<T> {
-  pub fn new(value: &Arc<RwLock<Option<T>>>) -> PromiseValue<T> {
-    PromiseValue(Arc::clone(value))
-  }
-
   pub fn is_resolved(&self) -> bool {
-    match self.0.try_read() {
+    match self.0.value.try_read() {
       Some(value) => match *value {
         Some(_) => true,
         None => false,
@@ -40,9 +61,63 @@ impl<T: Copy> PromiseValue<T> {
   }
 
   pub fn get_result(&self) -> Option<T> {
-    match self.0.try_read() {
+    match self.0.value.try_read() {
       Some(value) => *value,
       None => None,
     }
   }
+
+  /// Block the current process until the promise is resolved, and return the
+  /// value it was resolved with. Processes only switch when they yield, so the
+  /// promise cannot be resolved between the check and the process blocking.
+  #[cfg(not(test))]
+  pub fn wait(&self) -> T {
+    use crate::process;
+    loop {
+      if let Some(value) = self.get_result() {
+        return value;
+      }
+      let current = match process::current_process() {
+        Some(current) => current,
+        None => continue,
+      };
+      *self.0.waiter.write() = Some(current.get_id().as_u32());
+      current.block_on_promise();
+      process::yield_coop();
+    }
+  }
+}
+
+#[cfg(not(test))]
+fn wake_waiter(pid: u32) {
+  let processes = crate::process::all_processes();
+  if let Some(waiter) = processes.get_process(crate::process::id::ProcessID::new(pid)) {
+    waiter.resume();
+  }
+}
+
+#[cfg(test)]
+fn wake_waiter(_pid: u32) {
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Promise;
+
+  #[test]
+  fn resolves_every_copy() {
+    let promise: Promise<u32> = Promise::new();
+    let first = promise.get_value();
+    let second = promise.get_value();
+    assert!(!first.is_resolved());
+    promise.resolve(5);
+    assert_eq!(first.get_result(), Some(5));
+    assert_eq!(second.get_result(), Some(5));
+  }
+
+  #[test]
+  fn already_resolved() {
+    let promise = Promise::resolved(3u8);
+    assert!(promise.get_value().is_resolved());
+  }
 }
@@ -1,8 +1,21 @@
+use alloc::vec::Vec;
 use crate::files::filename;
+use crate::files::handle::{FileHandle, Handle};
 use crate::filesystems;
 use crate::memory::address::VirtualAddress;
+use crate::memory::virt::region::{ExpansionDirection, MemoryRegionType, Permissions};
 use crate::process;
+use crate::process::environment::Environment;
+use crate::process::id::ProcessID;
+use crate::process::process_state::RunState;
+use crate::process::signals::SignalAction;
+use crate::process::thread::ThreadError;
+use syscall::files::{FileStatus, OpenFlags};
+use syscall::memory::MapRequest;
+use syscall::process::{self as process_stats, ProcessStats, SpawnRequest, SystemStats, SPAWN_MAX_HANDLES};
 use syscall::result::SystemError;
+use syscall::signals;
+use super::file::resolve_path;
 
 pub fn yield_coop() {
   process::yield_coop();
@@ -16,16 +29,102 @@ pub fn fork() -> u32 {
   process::fork()
 }
 
-pub fn exec_path(path_str: &'static str, arg_str: &'static str, raw_interp_mode: u32) -> Result<(), SystemError> {
-  let (drive, path) = filename::string_to_drive_and_path(path_str);
+pub fn exec_path(path_str: &str, arg_str: &str, raw_interp_mode: u32) -> Result<(), SystemError> {
+  let full_path = resolve_path(path_str);
+  let (drive, path) = filename::string_to_drive_and_path(&full_path);
   let number = filesystems::get_fs_number(drive).ok_or(SystemError::NoSuchDrive)?;
-  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
-  let local_handle = fs.open(path).map_err(|_| SystemError::NoSuchEntity)?;
+  let local_handle = filesystems::open_path(number, path, OpenFlags::read_only()).map_err(|_| SystemError::NoSuchEntity)?;
+  let mut command_line = full_path.clone();
+  if arg_str.len() > 0 {
+    command_line.push(' ');
+    command_line.push_str(arg_str);
+  }
+  let current = process::current_process().ok_or(SystemError::Unknown)?;
+  // Only the group leader can replace the program, and the new program starts
+  // with no other threads
+  if current.is_thread() {
+    return Err(SystemError::PermissionDenied);
+  }
+  current.end_threads(signals::KILL, 0);
+  current.set_command_line(command_line);
   let interp_mode = process::exec::InterpretationMode::from_u32(raw_interp_mode);
   process::exec(number, local_handle, interp_mode);
   Ok(())
 }
 
+/// Start a program in a new child process, returning the child's ID. Nothing
+/// fails in the child: the path, handles, and environment are all checked
+/// before it is created.
+pub fn spawn(path_str: &str, arg_str: &str, request: &SpawnRequest, environment_block: Option<Vec<u8>>) -> Result<u32, SystemError> {
+  let current = process::current_process().ok_or(SystemError::Unknown)?;
+  let environment = match environment_block {
+    Some(block) => Environment::from_block(&block).ok_or(SystemError::InvalidArgument)?,
+    None => current.get_environment().read().clone(),
+  };
+  let handle_count = (request.handle_count as usize).min(SPAWN_MAX_HANDLES);
+  let files = current.spawn_file_map(&request.handles[..handle_count])?;
+
+  let full_path = resolve_path(path_str);
+  let (drive, path) = filename::string_to_drive_and_path(&full_path);
+  let number = filesystems::get_fs_number(drive).ok_or(SystemError::NoSuchDrive)?;
+  let local_handle = filesystems::open_path(number, path, OpenFlags::read_only()).map_err(|_| SystemError::NoSuchEntity)?;
+  let mut command_line = full_path.clone();
+  if arg_str.len() > 0 {
+    command_line.push(' ');
+    command_line.push_str(arg_str);
+  }
+  let interp_mode = process::exec::InterpretationMode::from_u32(request.format);
+  let pid = process::spawn::spawn(number, local_handle, interp_mode, command_line, environment, files);
+  Ok(pid.as_u32())
+}
+
+/// Start a new thread in the current process, returning its ID
+pub fn create_thread(entry: u32, stack: u32, arg: u32) -> Result<u32, SystemError> {
+  let entry = VirtualAddress::new(entry as usize);
+  let stack = VirtualAddress::new(stack as usize);
+  match process::spawn_thread(entry, stack, arg) {
+    Ok(pid) => Ok(pid.as_u32()),
+    Err(ThreadError::TooManyThreads) => Err(SystemError::TooManyThreads),
+    Err(ThreadError::OutOfMemory) => Err(SystemError::OutOfMemory),
+    Err(ThreadError::InvalidAddress) => Err(SystemError::InvalidArgument),
+  }
+}
+
+/// Copy the value of an environment variable into `buffer`, returning its full
+/// length
+pub fn get_env(name: &str, buffer: &mut [u8]) -> Result<u32, SystemError> {
+  let current = process::current_process().ok_or(SystemError::Unknown)?;
+  let environment = current.get_environment().read();
+  let value = environment.get(name).ok_or(SystemError::NoSuchEntity)?;
+  let length = value.len().min(buffer.len());
+  buffer[..length].copy_from_slice(&value.as_bytes()[..length]);
+  Ok(value.len() as u32)
+}
+
+pub fn set_env(name: &str, value: &str) -> Result<(), SystemError> {
+  let current = process::current_process().ok_or(SystemError::Unknown)?;
+  if current.get_environment().write().set(name, value) {
+    Ok(())
+  } else {
+    Err(SystemError::InvalidArgument)
+  }
+}
+
+pub fn unset_env(name: &str) -> Result<(), SystemError> {
+  let current = process::current_process().ok_or(SystemError::Unknown)?;
+  current.get_environment().write().unset(name);
+  Ok(())
+}
+
+/// Copy the whole environment block into `buffer`, returning its full length
+pub fn get_environment(buffer: &mut [u8]) -> Result<u32, SystemError> {
+  let current = process::current_process().ok_or(SystemError::Unknown)?;
+  let block = current.get_environment().read().to_block();
+  let length = block.len().min(buffer.len());
+  buffer[..length].copy_from_slice(&block[..length]);
+  Ok(block.len() as u32)
+}
+
 pub fn exit(code: u32) {
   process::exit(code);
 }
@@ -43,16 +142,156 @@ pub fn send_signal(id: u32, sig: u32) {
   process::send_signal(process::id::ProcessID::new(id), sig);
 }
 
+/// Change how the current process responds to a signal. A handler of
+/// ACTION_DEFAULT or ACTION_IGNORE selects those actions; any other value is
+/// the address of a function to run, which returns into `restorer`.
+pub fn set_signal_action(sig: u32, handler: u32, restorer: u32) -> Result<(), SystemError> {
+  let action = match handler {
+    signals::ACTION_DEFAULT => SignalAction::Default,
+    signals::ACTION_IGNORE => SignalAction::Ignore,
+    _ => {
+      if restorer == 0 {
+        return Err(SystemError::InvalidArgument);
+      }
+      SignalAction::Handler { handler, restorer }
+    },
+  };
+  process::current_process()
+    .ok_or(SystemError::Unknown)?
+    .get_signal_state()
+    .write()
+    .set_action(sig, action)
+    .map(|_| ())
+}
+
+/// Block or unblock signals for the current process, returning the previous
+/// mask
+pub fn set_signal_mask(method: u32, mask: u32) -> Result<u32, SystemError> {
+  let current = process::current_process().ok_or(SystemError::Unknown)?;
+  let mut state = current.get_signal_state().write();
+  let previous = state.get_blocked();
+  let blocked = match method {
+    signals::MASK_BLOCK => previous | mask,
+    signals::MASK_UNBLOCK => previous & !mask,
+    signals::MASK_SET => mask,
+    _ => return Err(SystemError::InvalidArgument),
+  };
+  state.set_blocked(blocked);
+  Ok(previous)
+}
+
+fn process_or_current(pid: u32) -> ProcessID {
+  match pid {
+    0 => process::get_current_pid(),
+    _ => ProcessID::new(pid),
+  }
+}
+
+/// Move a process into a group. A pid of zero refers to the current process,
+/// and a group of zero makes the process the leader of a new group. Only the
+/// current process and its children can be moved, and only into a group that
+/// already exists in their session. Session leaders cannot leave their group.
+pub fn set_process_group(pid: u32, group: u32) -> Result<(), SystemError> {
+  let current = process::current_process().ok_or(SystemError::Unknown)?;
+  let processes = process::all_processes();
+  let target_id = process_or_current(pid);
+  let target = processes.get_process(target_id).ok_or(SystemError::NoSuchEntity)?;
+  if target_id != current.get_id() && target.get_parent() != current.get_id() {
+    return Err(SystemError::PermissionDenied);
+  }
+  if target.get_session() != current.get_session() || target.is_session_leader() {
+    return Err(SystemError::PermissionDenied);
+  }
+  let group_id = match group {
+    0 => target_id,
+    _ => ProcessID::new(group),
+  };
+  if group_id != target_id && !processes.group_exists_in_session(group_id, target.get_session()) {
+    return Err(SystemError::PermissionDenied);
+  }
+  target.set_process_group(group_id);
+  Ok(())
+}
+
+/// Look up the group of a process, or of the current process if pid is zero
+pub fn get_process_group(pid: u32) -> Result<u32, SystemError> {
+  let processes = process::all_processes();
+  let target = processes.get_process(process_or_current(pid)).ok_or(SystemError::NoSuchEntity)?;
+  Ok(target.get_process_group().as_u32())
+}
+
+/// Start a new session and group led by the current process, returning its
+/// ID. A process that already leads a group cannot start a session, since the
+/// rest of its group would be left behind in the old one.
+pub fn create_session() -> Result<u32, SystemError> {
+  let current = process::current_process().ok_or(SystemError::Unknown)?;
+  if current.get_process_group() == current.get_id() {
+    return Err(SystemError::PermissionDenied);
+  }
+  current.create_session();
+  Ok(current.get_id().as_u32())
+}
+
+/// Fill in CPU figures for the whole system, and a record for as many
+/// processes as fit in `records`. Returns the number of records written.
+pub fn get_process_stats(system: &mut SystemStats, records: &mut [ProcessStats]) -> usize {
+  let processes = process::all_processes();
+  let mut written = 0;
+  for (id, process) in processes.iter() {
+    if written >= records.len() {
+      break;
+    }
+    let state = match process.get_run_state() {
+      RunState::Running | RunState::Resumed(_) => process_stats::STATE_RUNNING,
+      RunState::Sleeping(_) => process_stats::STATE_SLEEPING,
+      RunState::Paused => process_stats::STATE_PAUSED,
+      RunState::Blocked(_) => process_stats::STATE_BLOCKED,
+      RunState::Terminated => process_stats::STATE_TERMINATED,
+    };
+    let usage = process.get_cpu_usage();
+    let mut record = ProcessStats {
+      pid: id.as_u32(),
+      parent: process.get_parent().as_u32(),
+      state,
+      cpu_permille: usage.get_permille(),
+      cpu_ticks: usage.get_total_ticks(),
+      name: [0; process_stats::PROCESS_NAME_LENGTH],
+    };
+    record.set_name(filename::program_name(&process.get_command_line()));
+    records[written] = record;
+    written += 1;
+  }
+  let idle = processes.get_process(process::IDLE_PROCESS).map(|p| p.get_cpu_usage().get_permille());
+  *system = SystemStats {
+    idle_permille: idle.unwrap_or(0),
+    process_count: processes.iter().count() as u32,
+    ms_per_tick: crate::time::system::MS_PER_TICK as u32,
+  };
+  written
+}
+
+/// Wait for a child to exit. An ID of zero waits on any child.
 pub fn wait_pid(id: u32) -> (u32, u32) {
-  if id == 0 {
-    // TODO: wait on any process
-    (0, 0)
-  } else {
-    let code = process::wait(process::id::ProcessID::new(id));
-    (id, code)
+  let child = match id {
+    0 => None,
+    _ => Some(process::id::ProcessID::new(id)),
+  };
+  match process::wait(child) {
+    Some((pid, code)) => (pid.as_u32(), code),
+    None => (0, 0),
   }
 }
 
+/// Terminate every user process and launch a new init. The calling process
+/// is one of those terminated, so this only returns if the restart fails.
+pub fn restart_userland() {
+  process::restart::request_restart();
+  if let Some(cur) = process::current_process() {
+    cur.block();
+  }
+  process::yield_coop();
+}
+
 pub fn brk(method: u32, offset: u32) -> Result<u32, ()> {
   let cur = process::current_process().ok_or(())?;
   match method {
@@ -69,3 +308,62 @@ pub fn brk(method: u32, offset: u32) -> Result<u32, ()> {
     },
   }
 }
+
+/// Map anonymous memory or part of an open file, returning the address of the
+/// new region. A file mapping holds its own handle to the file, so closing
+/// the original handle does not affect it.
+pub fn mmap(request: &MapRequest) -> Result<u32, SystemError> {
+  let cur = process::current_process().ok_or(SystemError::Unknown)?;
+  let length = request.length as usize;
+  if length == 0 {
+    return Err(SystemError::InvalidArgument);
+  }
+  let permissions = if request.is_writable() {
+    Permissions::ReadWrite
+  } else {
+    Permissions::ReadOnly
+  };
+  let backed_by = if request.is_anonymous() {
+    MemoryRegionType::Anonymous(ExpansionDirection::None)
+  } else {
+    let offset = request.offset as usize;
+    if offset & 0xfff != 0 {
+      return Err(SystemError::InvalidArgument);
+    }
+    let pair = cur.get_open_file_with_access(FileHandle::new(request.handle), false)?;
+    let fs = filesystems::get_fs(pair.0).ok_or(SystemError::NoSuchFileSystem)?;
+    // Filesystems that cannot report a size are read until they come up short
+    let mut status = FileStatus::empty();
+    let file_length = match fs.stat(pair.1, &mut status) {
+      Ok(_) => (status.byte_size as usize).saturating_sub(offset).min(length),
+      Err(_) => length,
+    };
+    let handle = fs.dup(pair.1).map_err(|_| SystemError::IOError)?;
+    MemoryRegionType::FileBacked(pair.0, handle, offset, file_length)
+  };
+  let address = match request.address {
+    0 => None,
+    address => Some(VirtualAddress::new(address as usize)),
+  };
+  match cur.map_region(address, length, backed_by, permissions) {
+    Some(start) => Ok(start.as_u32()),
+    None => {
+      if let MemoryRegionType::FileBacked(drive, handle, _, _) = backed_by {
+        if let Some(fs) = filesystems::get_fs(drive) {
+          let _ = fs.close(handle);
+        }
+      }
+      match address {
+        Some(_) => Err(SystemError::InvalidArgument),
+        None => Err(SystemError::OutOfMemory),
+      }
+    },
+  }
+}
+
+/// Remove a region created by mmap
+pub fn munmap(address: u32, length: u32) -> Result<(), SystemError> {
+  let cur = process::current_process().ok_or(SystemError::Unknown)?;
+  cur.unmap_user_region(VirtualAddress::new(address as usize), length as usize)
+    .map_err(|_| SystemError::InvalidArgument)
+}
@@ -1,60 +1,386 @@
+use alloc::format;
+use alloc::string::String;
+use core::sync::atomic::{AtomicU32, Ordering};
 use crate::files::cursor::SeekMethod;
 use crate::files::filename;
-use crate::files::handle::{FileHandle, Handle};
+use crate::files::handle::{FileHandle, Handle, LocalHandle};
 use crate::filesystems;
+use crate::filesystems::capabilities::Capabilities;
 use crate::pipes;
+use crate::process;
+use crate::time::timestamp::Timestamp;
 use super::current_process;
-use syscall::files::{DirEntryInfo, DirEntryType};
+use syscall::files::{DirEntryInfo, DirEntryType, FileStatus, OpenFlags, OPEN_WRITE};
 use syscall::result::SystemError;
 
-pub fn open_path(path_str: &'static str) -> Result<u32, SystemError> {
-  let (drive, path) = filename::string_to_drive_and_path(path_str);
-  let number = filesystems::get_fs_number(drive).ok_or(SystemError::NoSuchDrive)?;
-  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
-  let local_handle = fs.open(path).map_err(|_| SystemError::NoSuchEntity)?;
-  Ok(current_process().open_file(number, local_handle).as_u32())
+/// Resolve a path from userspace against the current process's working
+/// directory, producing an absolute path that always includes a drive. Paths
+/// naming a DOS device like NUL or CON lead to that device on DEV:.
+pub fn resolve_path(path_str: &str) -> String {
+  let full_path = filename::resolve_path(&current_process().get_cwd(), path_str);
+  filename::dos_device_path(&full_path).unwrap_or(full_path)
 }
 
-pub fn close(handle: u32) -> Result<(), SystemError> {
-  let pair_to_close = {
-    let cur = current_process();
-    let prev = cur.close_file(FileHandle::new(handle));
-    match prev {
-      Some(pair) => if !current_process().references_drive_and_handle(pair.0, pair.1) {
-        Some(pair)
-      } else {
-        // Another handle in this process references the same file descriptor
-        None
-      },
-      None => None,
-    }
+/// Open a file with the access modes and options in `flags`. Missing files are
+/// created if OPEN_CREATE is set, and OPEN_TRUNCATE empties the file once it
+/// has been opened for writing.
+pub fn open_path(path_str: &str, flags: u32) -> Result<u32, SystemError> {
+  let flags = OpenFlags::from_u32(flags);
+  let full_path = resolve_path(path_str);
+  let (drive, path) = filename::string_to_drive_and_path(&full_path);
+  let number = filesystems::get_fs_number(drive).ok_or(SystemError::NoSuchDrive)?;
+  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_media()?;
+  if flags.can_write() || flags.should_create() {
+    filesystems::check_writable(number)?;
+  }
+  if flags.should_truncate() && !flags.can_write() {
+    return Err(SystemError::InvalidArgument);
+  }
+  fs.check_open(path, flags)?;
+  let local_handle = match filesystems::open_path(number, path, flags) {
+    Ok(handle) => handle,
+    Err(_) if flags.should_create() => {
+      filesystems::invalidate_path(number, path);
+      // Newly created files are opened for reading and writing, so reopen the
+      // file with the access that was actually requested
+      let created = fs.create(path)?;
+      let _ = fs.close(created);
+      filesystems::open_path(number, path, flags).map_err(|_| SystemError::NoSuchEntity)?
+    },
+    Err(_) => return Err(SystemError::NoSuchEntity),
   };
+  if flags.should_truncate() {
+    if let Err(e) = fs.truncate(local_handle, 0) {
+      let _ = fs.close(local_handle);
+      return Err(e);
+    }
+  }
+  let handle = current_process().open_file(number, local_handle, flags);
+  current_process().set_file_path(handle, &full_path);
+  Ok(handle.as_u32())
+}
 
-  let pair = pair_to_close.ok_or(SystemError::BadFileDescriptor)?;
-  match filesystems::get_fs(pair.0) {
-    Some(fs) => fs.close(pair.1).map_err(|_| SystemError::IOError),
-    None => Err(SystemError::NoSuchFileSystem),
+pub fn open_by_id(drive: u32, file_id: u32, flags: u32) -> Result<u32, SystemError> {
+  let flags = OpenFlags::from_u32(flags);
+  let number = drive as usize;
+  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchDrive)?;
+  if flags.can_write() {
+    filesystems::check_writable(number)?;
   }
+  let local_handle = fs.open_by_id(file_id, flags).map_err(|_| SystemError::NoSuchEntity)?;
+  Ok(current_process().open_file(number, local_handle, flags).as_u32())
 }
 
-pub unsafe fn read(handle: u32, dest: *mut u8, length: usize) -> Result<usize, SystemError> {
+pub unsafe fn stat(path_str: &str, status: *mut FileStatus) -> Result<(), SystemError> {
+  let full_path = resolve_path(path_str);
+  let (drive, path) = filename::string_to_drive_and_path(&full_path);
+  let number = filesystems::get_fs_number(drive).ok_or(SystemError::NoSuchDrive)?;
+  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
+  let local_handle = filesystems::open_path(number, path, OpenFlags::read_only()).map_err(|_| SystemError::NoSuchEntity)?;
+  (*status).link_count = 1;
+  let result = fs.stat(local_handle, &mut *status).map_err(|_| SystemError::UnsupportedCommand);
+  let _ = fs.close(local_handle);
+  result?;
+  (*status).drive = number as u32;
+  Ok(())
+}
+
+pub unsafe fn fstat(handle: u32, status: *mut FileStatus) -> Result<(), SystemError> {
   let drive_and_handle = current_process()
     .get_open_file_info(FileHandle::new(handle))
     .ok_or(SystemError::BadFileDescriptor)?;
 
+  let fs = filesystems::get_fs(drive_and_handle.0).ok_or(SystemError::NoSuchFileSystem)?;
+  (*status).link_count = 1;
+  fs.stat(drive_and_handle.1, &mut *status).map_err(|_| SystemError::UnsupportedCommand)?;
+  (*status).drive = drive_and_handle.0 as u32;
+  Ok(())
+}
+
+pub unsafe fn get_xattr(handle: u32, name: &str, dest: *mut u8, length: usize) -> Result<usize, SystemError> {
+  let drive_and_handle = current_process()
+    .get_open_file_info(FileHandle::new(handle))
+    .ok_or(SystemError::BadFileDescriptor)?;
+
+  get_capabilities(drive_and_handle.0)?.require_xattrs()?;
   let fs = filesystems::get_fs(drive_and_handle.0).ok_or(SystemError::NoSuchFileSystem)?;
   let buffer = core::slice::from_raw_parts_mut(dest, length);
-  fs.read(drive_and_handle.1, buffer).map_err(|_| SystemError::IOError)
+  fs.get_xattr(drive_and_handle.1, name, buffer)
 }
 
-pub unsafe fn write(handle: u32, src: *const u8, length: usize) -> Result<usize, SystemError> {
+pub unsafe fn set_xattr(handle: u32, name: &str, src: *const u8, length: usize) -> Result<(), SystemError> {
   let drive_and_handle = current_process()
     .get_open_file_info(FileHandle::new(handle))
     .ok_or(SystemError::BadFileDescriptor)?;
 
+  get_capabilities(drive_and_handle.0)?.require_xattrs()?;
+  filesystems::check_writable(drive_and_handle.0)?;
+  let fs = filesystems::get_fs(drive_and_handle.0).ok_or(SystemError::NoSuchFileSystem)?;
+  let value = core::slice::from_raw_parts(src, length);
+  fs.set_xattr(drive_and_handle.1, name, value)
+}
+
+pub fn create_path(path_str: &str) -> Result<u32, SystemError> {
+  let full_path = resolve_path(path_str);
+  let (drive, path) = filename::string_to_drive_and_path(&full_path);
+  let number = filesystems::get_fs_number(drive).ok_or(SystemError::NoSuchDrive)?;
+  filesystems::check_writable(number)?;
+  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
+  filesystems::invalidate_path(number, path);
+  let local_handle = fs.create(path)?;
+  let handle = current_process().open_file(number, local_handle, OpenFlags::read_write());
+  current_process().set_file_path(handle, &full_path);
+  Ok(handle.as_u32())
+}
+
+pub fn unlink(path_str: &str) -> Result<(), SystemError> {
+  let full_path = resolve_path(path_str);
+  let (drive, path) = filename::string_to_drive_and_path(&full_path);
+  let number = filesystems::get_fs_number(drive).ok_or(SystemError::NoSuchDrive)?;
+  filesystems::check_writable(number)?;
+  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
+  filesystems::invalidate_path(number, path);
+  fs.delete(path)
+}
+
+/// Set when a file was last modified, in seconds since 1980. Changing the
+/// time counts as writing to the file, so the drive must be writable and the
+/// file must not be held exclusively by another handle.
+pub fn set_modified_time(path_str: &str, modified: u32) -> Result<(), SystemError> {
+  let full_path = resolve_path(path_str);
+  let (drive, path) = filename::string_to_drive_and_path(&full_path);
+  let number = get_writable_drive(drive)?;
+  get_capabilities(number)?.require_timestamps()?;
+  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_media()?;
+  fs.check_open(path, OpenFlags::read_write())?;
+  fs.set_modified_time(path, Timestamp(modified))
+}
+
+pub fn mkdir(path_str: &str) -> Result<(), SystemError> {
+  let full_path = resolve_path(path_str);
+  let (drive, path) = filename::string_to_drive_and_path(&full_path);
+  let number = get_writable_drive(drive)?;
+  get_capabilities(number)?.require_directories()?;
+  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_media()?;
+  filesystems::invalidate_path(number, path);
+  fs.mkdir(path)
+}
+
+pub fn rmdir(path_str: &str) -> Result<(), SystemError> {
+  let full_path = resolve_path(path_str);
+  let (drive, path) = filename::string_to_drive_and_path(&full_path);
+  let number = get_writable_drive(drive)?;
+  get_capabilities(number)?.require_directories()?;
+  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_media()?;
+  // Lookups cached beneath the directory are no longer valid either
+  filesystems::VFS.invalidate_drive(number);
+  fs.rmdir(path)
+}
+
+/// Look up a drive that is about to be modified, failing if it was mounted
+/// read-only or its filesystem cannot be written
+fn get_writable_drive(drive: &str) -> Result<usize, SystemError> {
+  let number = filesystems::get_fs_number(drive).ok_or(SystemError::NoSuchDrive)?;
+  filesystems::check_writable(number)?;
+  Ok(number)
+}
+
+fn get_capabilities(number: usize) -> Result<Capabilities, SystemError> {
+  filesystems::get_capabilities(number).ok_or(SystemError::NoSuchFileSystem)
+}
+
+pub fn link(existing_path_str: &str, new_path_str: &str) -> Result<(), SystemError> {
+  let full_existing_path = resolve_path(existing_path_str);
+  let (existing_drive, existing_path) = filename::string_to_drive_and_path(&full_existing_path);
+  let full_new_path = resolve_path(new_path_str);
+  let (new_drive, new_path) = filename::string_to_drive_and_path(&full_new_path);
+  let number = get_writable_drive(existing_drive)?;
+  if filesystems::get_fs_number(new_drive) != Some(number) {
+    // A link is just another directory entry, so it must be on the same drive
+    return Err(SystemError::InvalidArgument);
+  }
+  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_media()?;
+  filesystems::invalidate_path(number, new_path);
+  fs.link(existing_path, new_path)
+}
+
+/// Move a file to a new path. On the same drive the filesystem renames it in
+/// place. Between drives, the contents are copied and the original is only
+/// deleted once the copy has succeeded.
+pub fn rename(old_path_str: &str, new_path_str: &str) -> Result<(), SystemError> {
+  let full_old_path = resolve_path(old_path_str);
+  let (old_drive, old_path) = filename::string_to_drive_and_path(&full_old_path);
+  let full_new_path = resolve_path(new_path_str);
+  let (new_drive, new_path) = filename::string_to_drive_and_path(&full_new_path);
+  let old_number = get_writable_drive(old_drive)?;
+  let new_number = get_writable_drive(new_drive)?;
+  let old_fs = filesystems::get_fs(old_number).ok_or(SystemError::NoSuchFileSystem)?;
+  old_fs.check_media()?;
+  if old_number == new_number {
+    // Renaming a directory moves every path beneath it, so no cached lookup
+    // on the drive can be trusted afterwards
+    filesystems::VFS.invalidate_drive(old_number);
+    return old_fs.rename(old_path, new_path);
+  }
+
+  let new_fs = filesystems::get_fs(new_number).ok_or(SystemError::NoSuchFileSystem)?;
+  new_fs.check_media()?;
+  if let Ok(existing) = filesystems::open_path(new_number, new_path, OpenFlags::read_only()) {
+    let _ = new_fs.close(existing);
+    return Err(SystemError::AlreadyExists);
+  }
+  let source = filesystems::open_path(old_number, old_path, OpenFlags::read_only()).map_err(|_| SystemError::NoSuchEntity)?;
+  let mut status = FileStatus::empty();
+  let is_file = match old_fs.stat(source, &mut status) {
+    Ok(_) => match status.entry_type {
+      DirEntryType::Directory => false,
+      _ => true,
+    },
+    Err(_) => true,
+  };
+  if !is_file {
+    // Moving a whole directory tree between drives is left to userspace
+    let _ = old_fs.close(source);
+    return Err(SystemError::UnsupportedCommand);
+  }
+  filesystems::invalidate_path(new_number, new_path);
+  let copied = new_fs.create(new_path).and_then(|dest| {
+    let result = copy_contents(&old_fs, source, &new_fs, dest);
+    let closed = new_fs.close(dest).map_err(|_| SystemError::IOError);
+    result.and(closed)
+  });
+  let _ = old_fs.close(source);
+  if let Err(e) = copied {
+    let _ = new_fs.delete(new_path);
+    return Err(e);
+  }
+  filesystems::invalidate_path(old_number, old_path);
+  old_fs.delete(old_path)
+}
+
+/// Replace the entire contents of a file, so that a crash at any point leaves
+/// it with either its old or its new contents. The data is written to a
+/// temporary file beside the target, which is closed to flush it to the disk
+/// and then moved over the target. Missing files are created.
+pub unsafe fn write_atomic(path_str: &str, src: *const u8, length: usize) -> Result<(), SystemError> {
+  let full_path = resolve_path(path_str);
+  let (drive, path) = filename::string_to_drive_and_path(&full_path);
+  let number = get_writable_drive(drive)?;
+  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_media()?;
+  let temp_path = filename::temporary_path(path);
+  filesystems::invalidate_path(number, &temp_path);
+  // Creating an existing file empties it, so a temporary file left behind by
+  // an earlier crash is simply overwritten
+  let temp = fs.create(&temp_path)?;
+  let data = core::slice::from_raw_parts(src, length);
+  let written = write_all(number, temp, data);
+  let closed = fs.close(temp).map_err(|_| SystemError::IOError);
+  if let Err(e) = written.and(closed) {
+    let _ = fs.delete(&temp_path);
+    return Err(e);
+  }
+  filesystems::invalidate_path(number, path);
+  let replaced = fs.replace(&temp_path, path);
+  if replaced.is_err() {
+    let _ = fs.delete(&temp_path);
+  }
+  replaced
+}
+
+fn write_all(drive: usize, handle: LocalHandle, data: &[u8]) -> Result<(), SystemError> {
+  let mut written = 0;
+  while written < data.len() {
+    let count = filesystems::VFS.write(drive, handle, &data[written..])?;
+    if count == 0 {
+      return Err(SystemError::NoSpace);
+    }
+    written += count;
+  }
+  Ok(())
+}
+
+fn copy_contents(source_fs: &filesystems::FileSystemType, source: LocalHandle, dest_fs: &filesystems::FileSystemType, dest: LocalHandle) -> Result<(), SystemError> {
+  let mut buffer = [0; 512];
+  loop {
+    let read = source_fs.read(source, &mut buffer).map_err(|_| SystemError::IOError)?;
+    if read == 0 {
+      return Ok(());
+    }
+    let mut written = 0;
+    while written < read {
+      let count = dest_fs.write(dest, &buffer[written..read])?;
+      if count == 0 {
+        return Err(SystemError::NoSpace);
+      }
+      written += count;
+    }
+  }
+}
+
+pub fn close(handle: u32) -> Result<(), SystemError> {
+  let pair = current_process()
+    .close_file(FileHandle::new(handle))
+    .ok_or(SystemError::BadFileDescriptor)?;
+  process::files::release_handle(pair)
+}
+
+pub fn close_dir(handle: u32) -> Result<(), SystemError> {
+  let pair = current_process().close_directory(FileHandle::new(handle))?;
+  process::files::release_handle(pair)
+}
+
+pub unsafe fn read(handle: u32, dest: *mut u8, length: usize) -> Result<usize, SystemError> {
+  let drive_and_handle = current_process().get_open_file_with_access(FileHandle::new(handle), false)?;
+
+  let fs = filesystems::get_fs(drive_and_handle.0).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_handle(drive_and_handle.1)?;
+  let buffer = core::slice::from_raw_parts_mut(dest, length);
+  filesystems::VFS.read(drive_and_handle.0, drive_and_handle.1, buffer)
+}
+
+pub unsafe fn write(handle: u32, src: *const u8, length: usize) -> Result<usize, SystemError> {
+  let file_handle = FileHandle::new(handle);
+  let drive_and_handle = current_process().get_open_file_with_access(file_handle, true)?;
+
+  filesystems::check_writable(drive_and_handle.0)?;
   let fs = filesystems::get_fs(drive_and_handle.0).ok_or(SystemError::NoSuchFileSystem)?;
+  let append = current_process().get_open_file_flags(file_handle).map_or(false, |f| f.is_append());
+  if append {
+    // Every write in append mode lands at the current end of the file, even
+    // if another handle has extended it since the last write
+    let mut status = FileStatus::empty();
+    fs.stat(drive_and_handle.1, &mut status).map_err(|_| SystemError::IOError)?;
+    fs.seek(drive_and_handle.1, SeekMethod::Absolute(status.byte_size as usize)).map_err(|_| SystemError::IOError)?;
+  }
   let buffer = core::slice::from_raw_parts(src, length);
-  fs.write(drive_and_handle.1, buffer).map_err(|_| SystemError::IOError)
+  filesystems::VFS.write(drive_and_handle.0, drive_and_handle.1, buffer)
+}
+
+pub fn truncate(handle: u32, length: usize) -> Result<(), SystemError> {
+  let drive_and_handle = current_process().get_open_file_with_access(FileHandle::new(handle), true)?;
+
+  filesystems::check_writable(drive_and_handle.0)?;
+  let fs = filesystems::get_fs(drive_and_handle.0).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_handle(drive_and_handle.1)?;
+  fs.truncate(drive_and_handle.1, length)
+}
+
+/// Reserve space for the byte range starting at `offset`, extending the file
+/// if the range reaches past its end
+pub fn preallocate(handle: u32, offset: usize, length: usize) -> Result<(), SystemError> {
+  let drive_and_handle = current_process().get_open_file_with_access(FileHandle::new(handle), true)?;
+
+  let end = offset.checked_add(length).ok_or(SystemError::InvalidArgument)?;
+  filesystems::check_writable(drive_and_handle.0)?;
+  let fs = filesystems::get_fs(drive_and_handle.0).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_handle(drive_and_handle.1)?;
+  fs.preallocate(drive_and_handle.1, end)
 }
 
 pub fn ioctl(handle: u32, command: u32, arg: u32) -> Result<u32, SystemError> {
@@ -66,10 +392,24 @@ pub fn ioctl(handle: u32, command: u32, arg: u32) -> Result<u32, SystemError> {
   fs.ioctl(drive_and_handle.1, command, arg).map_err(|_| SystemError::IOError)
 }
 
+/// Copy the path a file was opened by into the buffer, returning the length of
+/// the whole path
+pub fn get_path(handle: u32, buffer: &mut [u8]) -> Result<u32, SystemError> {
+  let path = current_process()
+    .get_open_file_path(FileHandle::new(handle))?
+    .ok_or(SystemError::UnsupportedCommand)?;
+  let length = path.len().min(buffer.len());
+  buffer[..length].copy_from_slice(&path.as_bytes()[..length]);
+  Ok(path.len() as u32)
+}
+
 pub fn dup(to_duplicate: u32, to_replace: u32) -> Result<u32, SystemError> {
   let drive_and_handle = current_process()
     .get_open_file_info(FileHandle::new(to_duplicate))
     .ok_or(SystemError::BadFileDescriptor)?;
+  let flags = current_process()
+    .get_open_file_flags(FileHandle::new(to_duplicate))
+    .ok_or(SystemError::BadFileDescriptor)?;
 
   let (handle, pair_to_close) = {
     let cur = current_process();
@@ -80,26 +420,19 @@ pub fn dup(to_duplicate: u32, to_replace: u32) -> Result<u32, SystemError> {
       FileHandle::new(to_replace)
     };
 
-    let prev = files.set_handle_directly(handle, drive_and_handle.0, drive_and_handle.1);
-    match prev {
-      Some(pair) => if !current_process().references_drive_and_handle(pair.0, pair.1) {
-        (handle, Some(pair))
-      } else {
-        // Another handle in this process references the same file descriptor
-        (handle, None)
-      },
-      None => (handle, None),
+    let path = files.get_path(FileHandle::new(to_duplicate));
+    let prev = files.set_handle_directly(handle, drive_and_handle.0, drive_and_handle.1, flags);
+    if let Some(path) = path {
+      files.set_path(handle, path);
     }
+    (handle, prev)
   };
 
-  let pair = pair_to_close.ok_or(SystemError::BadFileDescriptor)?;
-  match filesystems::get_fs(pair.0) {
-    Some(fs) => {
-      fs.close(pair.1).map_err(|_| SystemError::IOError)?;
-      Ok(handle.as_u32())
-    },
-    None => Err(SystemError::NoSuchFileSystem),
+  // Replacing an open handle closes whatever it pointed to before
+  if let Some(pair) = pair_to_close {
+    process::files::release_handle(pair)?;
   }
+  Ok(handle.as_u32())
 }
 
 pub fn pipe() -> Result<(u32, u32), SystemError> {
@@ -107,13 +440,33 @@ pub fn pipe() -> Result<(u32, u32), SystemError> {
   let (read, write) = {
     let current = current_process();
     let fs_number = unsafe { filesystems::PIPE_FS };
-    let read = current.open_file(fs_number, read_local).as_u32();
-    let write = current.open_file(fs_number, write_local).as_u32();
+    let read = current.open_file(fs_number, read_local, OpenFlags::read_only()).as_u32();
+    let write = current.open_file(fs_number, write_local, OpenFlags::from_u32(OPEN_WRITE)).as_u32();
     (read, write)
   };
   Ok((read, write))
 }
 
+/// Used to generate a temporary, unique name for each anonymous file
+static NEXT_ANONYMOUS_FILE: AtomicU32 = AtomicU32::new(0);
+
+/// Create an anonymous memory-backed file on the ANON: drive, and return a
+/// handle to it. The file is unlinked immediately, so it can only be reached
+/// through the handle and its duplicates, and its memory is released when the
+/// last of them is closed. It starts empty; use ftruncate to set its size.
+pub fn memfd_create() -> Result<u32, SystemError> {
+  let fs_number = unsafe { filesystems::ANON_FS };
+  let fs = filesystems::get_fs(fs_number).ok_or(SystemError::NoSuchFileSystem)?;
+  let id = NEXT_ANONYMOUS_FILE.fetch_add(1, Ordering::SeqCst);
+  let path = format!("\\{:08X}", id);
+  let local_handle = fs.create(&path)?;
+  if let Err(e) = fs.delete(&path) {
+    let _ = fs.close(local_handle);
+    return Err(e);
+  }
+  Ok(current_process().open_file(fs_number, local_handle, OpenFlags::read_write()).as_u32())
+}
+
 pub fn seek(handle: u32, method: u32, cursor: u32) -> Result<u32, SystemError> {
   let seek_method = match method {
     1 => SeekMethod::Relative(cursor as i32 as isize),
@@ -123,25 +476,68 @@ pub fn seek(handle: u32, method: u32, cursor: u32) -> Result<u32, SystemError> {
     .get_open_file_info(FileHandle::new(handle))
     .ok_or(SystemError::BadFileDescriptor)?;
 
+  get_capabilities(drive_and_handle.0)?.require_seek()?;
   let fs = filesystems::get_fs(drive_and_handle.0).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_handle(drive_and_handle.1)?;
   fs.seek(drive_and_handle.1, seek_method)
     .map(|new_cursor| new_cursor as u32)
     .map_err(|_| SystemError::IOError)
 }
 
-pub fn open_dir(path_str: &'static str) -> Result<u32, SystemError> {
-  let (drive, path) = filename::string_to_drive_and_path(path_str);
+pub fn open_dir(path_str: &str) -> Result<u32, SystemError> {
+  let full_path = resolve_path(path_str);
+  let (drive, path) = filename::string_to_drive_and_path(&full_path);
   let number = filesystems::get_fs_number(drive).ok_or(SystemError::NoSuchDrive)?;
+  get_capabilities(number)?.require_directories()?;
   let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_media()?;
   let local_handle = fs.open_dir(path).map_err(|_| SystemError::NoSuchEntity)?;
   current_process().open_directory(number, local_handle).map(|handle| handle.as_u32())
 }
 
+/// Change the working directory of the current process. The new directory must
+/// exist; it is opened and closed again to confirm that.
+pub fn change_dir(path_str: &str) -> Result<(), SystemError> {
+  let full_path = resolve_path(path_str);
+  let (drive, path) = filename::string_to_drive_and_path(&full_path);
+  let number = filesystems::get_fs_number(drive).ok_or(SystemError::NoSuchDrive)?;
+  get_capabilities(number)?.require_directories()?;
+  let fs = filesystems::get_fs(number).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_media()?;
+  let local_handle = fs.open_dir(path).map_err(|_| SystemError::NoSuchEntity)?;
+  let _ = fs.close(local_handle);
+  current_process().set_cwd(full_path);
+  Ok(())
+}
+
+/// Copy the working directory into a buffer, truncating it if the buffer is
+/// too small, and return the full length of the path
+pub fn get_cwd(buffer: &mut [u8]) -> usize {
+  let cwd = current_process().get_cwd();
+  let length = cwd.len().min(buffer.len());
+  buffer[..length].copy_from_slice(&cwd.as_bytes()[..length]);
+  cwd.len()
+}
+
 pub fn read_dir(handle: u32, index: usize, info: *mut DirEntryInfo) -> Result<(), SystemError> {
   let drive_and_handle = current_process()
     .get_open_dir_info(FileHandle::new(handle))
     .ok_or(SystemError::BadFileDescriptor)?;
   let fs = filesystems::get_fs(drive_and_handle.0).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_handle(drive_and_handle.1)?;
   let entry = unsafe { &mut *info };
   fs.read_dir(drive_and_handle.1, index, entry).map_err(|_| SystemError::NoSuchEntity)
 }
+
+/// Fill a batch of entries from an open directory, starting at the index in
+/// the cookie, and return how many were read along with the next cookie
+pub fn read_dir_entries(handle: u32, cookie: u32, entries: &mut [DirEntryInfo]) -> Result<(u32, u32), SystemError> {
+  let drive_and_handle = current_process()
+    .get_open_dir_info(FileHandle::new(handle))
+    .ok_or(SystemError::BadFileDescriptor)?;
+  let fs = filesystems::get_fs(drive_and_handle.0).ok_or(SystemError::NoSuchFileSystem)?;
+  fs.check_handle(drive_and_handle.1)?;
+  let filled = fs.read_dir_entries(drive_and_handle.1, cookie as usize, entries)
+    .map_err(|_| SystemError::NoSuchEntity)?;
+  Ok((filled as u32, cookie + filled as u32))
+}
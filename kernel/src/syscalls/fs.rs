@@ -1,4 +1,80 @@
+use crate::files::filename;
+use crate::filesystems::{self, MountSource};
+use crate::filesystems::options::MountOptions;
+use crate::process;
+use syscall::files::MountInfo;
+use syscall::result::SystemError;
+
 /// Register the current process as a new filesystem driver
 pub fn register() {
 
 }
+
+/// Drive names are up to eight upper case letters or digits
+fn is_valid_drive_name(name: &str) -> bool {
+  name.len() > 0 && name.len() <= 8 && name.bytes().all(|ch| ch.is_ascii_uppercase() || ch.is_ascii_digit())
+}
+
+/// Mount the filesystem on a device as a new drive. The device must be given
+/// as a path on the DEV: drive, and may only back one writable drive at a
+/// time.
+pub fn mount(drive: &str, device_path: &str, driver: &str) -> Result<(), SystemError> {
+  if !is_valid_drive_name(drive) {
+    return Err(SystemError::InvalidArgument);
+  }
+  if filesystems::get_fs_number(drive).is_some() {
+    return Err(SystemError::AlreadyExists);
+  }
+  let (device_drive, device) = filename::string_to_drive_and_path(device_path);
+  if device_drive != "DEV" {
+    return Err(SystemError::InvalidArgument);
+  }
+  let device = device.trim_start_matches('\\');
+  if device.len() == 0 || device.len() > 8 {
+    return Err(SystemError::InvalidArgument);
+  }
+  let options = MountOptions::new();
+  // Checked again once the filesystem is built, but reading a disk only to
+  // turn it away is worth avoiding
+  if !filesystems::VFS.can_mount_device(device, options.read_only) {
+    return Err(SystemError::Busy);
+  }
+  let fs = filesystems::create_for_device(driver, device)?;
+  let source = MountSource::new(driver, Some(device));
+  filesystems::VFS.mount(drive, fs, options, source).map(|_| ())
+}
+
+/// Remove a drive. Drives the kernel depends on cannot be unmounted, and a
+/// drive stays mounted as long as any process has a file open on it.
+pub fn unmount(drive: &str) -> Result<(), SystemError> {
+  let number = filesystems::get_fs_number(drive).ok_or(SystemError::NoSuchDrive)?;
+  if process::all_processes().references_drive(number) {
+    return Err(SystemError::Busy);
+  }
+  filesystems::VFS.unmount_drive(number)
+}
+
+/// Fill in a record for as many mounted drives as fit in `records`. Returns
+/// the number of drives mounted.
+pub fn get_mount_table(records: &mut [MountInfo]) -> u32 {
+  let mut mounted = 0;
+  for index in 0..filesystems::VFS.get_slot_count() {
+    let name = match filesystems::VFS.get_drive_name(index) {
+      Some(name) => name,
+      None => continue,
+    };
+    if let Some(record) = records.get_mut(mounted) {
+      let mut info = MountInfo::empty();
+      MountInfo::set_name(&mut info.drive_name, &name);
+      if let Some(source) = filesystems::VFS.get_mount_source(index) {
+        MountInfo::set_name(&mut info.driver, &source.driver);
+        MountInfo::set_name(&mut info.device, source.device.as_deref().unwrap_or(""));
+      }
+      info.flags = filesystems::get_mount_options(index).map(|o| o.get_flags()).unwrap_or(0);
+      info.open_handles = process::all_processes().count_drive_handles(index) as u32;
+      *record = info;
+    }
+    mounted += 1;
+  }
+  mounted as u32
+}
@@ -0,0 +1,17 @@
+use crate::messages;
+use syscall::result::SystemError;
+
+/// Copy the text of a message into a buffer, truncating it if the buffer is
+/// too small, and return the full length of the text
+pub fn get_message(id: u16, buffer: &mut [u8]) -> Result<usize, SystemError> {
+  messages::with_text(id, |text| {
+    let text = text.ok_or(SystemError::NoSuchEntity)?;
+    let length = text.len().min(buffer.len());
+    buffer[..length].copy_from_slice(&text.as_bytes()[..length]);
+    Ok(text.len())
+  })
+}
+
+pub fn set_locale(locale: &str) -> Result<(), SystemError> {
+  messages::load_locale(locale)
+}
@@ -4,6 +4,12 @@ use crate::process;
 pub mod exec;
 pub mod file;
 pub mod fs;
+pub mod messages;
+pub mod ports;
+pub mod power;
+pub mod testing;
+pub mod time;
+pub mod user;
 
 fn current_process() -> Arc<process::process_state::ProcessState> {
   process::current_process().expect("Running a syscall for an unknown process")
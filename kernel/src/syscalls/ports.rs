@@ -0,0 +1,15 @@
+use crate::process;
+use syscall::result::SystemError;
+
+/// Let the current thread use `count` I/O ports starting at `first` directly,
+/// or take that access away again
+pub fn ioperm(first: u32, count: u32, allowed: bool) -> Result<(), SystemError> {
+  if count == 0 {
+    return Ok(());
+  }
+  let last = first.checked_add(count - 1).ok_or(SystemError::InvalidArgument)?;
+  if last > 0xffff {
+    return Err(SystemError::InvalidArgument);
+  }
+  process::io_ports::set_access(process::get_current_pid(), first as u16, last as u16, allowed)
+}
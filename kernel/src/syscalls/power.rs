@@ -0,0 +1,20 @@
+use crate::acpi;
+use crate::filesystems;
+use syscall::flags::{SHUTDOWN_POWEROFF, SHUTDOWN_REBOOT};
+use syscall::result::SystemError;
+
+/// Only returns if the machine could not be powered off
+pub fn shutdown(action: u32) -> Result<(), SystemError> {
+  match action {
+    SHUTDOWN_POWEROFF => {
+      filesystems::VFS.sync_all();
+      acpi::poweroff();
+      Err(SystemError::UnsupportedCommand)
+    },
+    SHUTDOWN_REBOOT => {
+      filesystems::VFS.sync_all();
+      acpi::reboot()
+    },
+    _ => Err(SystemError::InvalidArgument),
+  }
+}
@@ -0,0 +1,18 @@
+use crate::testreport;
+use syscall::result::SystemError;
+
+pub fn report(kind: u32, message: &str) -> Result<(), SystemError> {
+  if !testreport::is_enabled() {
+    return Err(SystemError::UnsupportedCommand);
+  }
+  testreport::report(kind, message).map_err(|_| SystemError::InvalidArgument)
+}
+
+/// Only returns if reporting is off, or the emulator has no exit device
+pub fn finish() -> Result<(), SystemError> {
+  if !testreport::is_enabled() {
+    return Err(SystemError::UnsupportedCommand);
+  }
+  testreport::finish();
+  Err(SystemError::UnsupportedCommand)
+}
@@ -0,0 +1,6 @@
+use crate::time;
+
+/// Time since boot in 100ns increments, unaffected by changes to the clock
+pub fn get_uptime() -> u64 {
+  time::system::get_uptime().0
+}
@@ -0,0 +1,197 @@
+//! Syscall arguments that point into the calling process's memory are never
+//! trusted. Before the kernel reads or writes through one, the whole range is
+//! checked against the regions the process has mapped, so a bad pointer fails
+//! the syscall with BadPointer instead of faulting inside the kernel. Strings
+//! are copied into kernel memory before they are used, so a program cannot
+//! change them halfway through a syscall.
+//!
+//! Processes that have not yet left kernel mode, like the kernel's own startup
+//! code, call syscalls with pointers into kernel memory. Those pointers are
+//! trusted as they are.
+//!
+//! The kernel runs with CR0.WP clear, so its writes ignore read-only page
+//! table entries. Before writing to user memory, any copy-on-write pages in
+//! the range are copied, the same way a write fault from the process would.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::interrupts::exceptions::prepare_user_write;
+use crate::memory::address::VirtualAddress;
+use crate::memory::virt::region::Permissions;
+use crate::process;
+use syscall::result::SystemError;
+use syscall::{MAX_NAME_LENGTH, MAX_PATH_LENGTH, MAX_STRING_LENGTH, StringPtr};
+
+/// Everything from this address up belongs to the kernel
+const USER_MEMORY_END: usize = 0xc0000000;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Access {
+  Read,
+  Write,
+}
+
+/// The process making a syscall, and whether its pointers need checking
+#[derive(Copy, Clone)]
+pub struct Caller {
+  from_usermode: bool,
+}
+
+impl Caller {
+  pub fn new(from_usermode: bool) -> Caller {
+    Caller {
+      from_usermode,
+    }
+  }
+
+  /// Check that a range of bytes lies entirely in regions the process has
+  /// mapped, and that the regions can be written if `access` asks for it.
+  /// Pages in those regions may not be present yet; touching them faults
+  /// them in as usual.
+  /// Writes must go through `prepare_write` instead, so that they never land
+  /// in a page still shared with another process.
+  fn validate(&self, addr: usize, length: usize, access: Access) -> Result<(), SystemError> {
+    if !self.from_usermode {
+      return Ok(());
+    }
+    let end = match addr.checked_add(length) {
+      Some(end) if end <= USER_MEMORY_END => end,
+      _ => return Err(SystemError::BadPointer),
+    };
+    let current = process::current_process().ok_or(SystemError::BadPointer)?;
+    let mut cursor = addr;
+    while cursor < end {
+      let region = current
+        .get_range_containing_address(VirtualAddress::new(cursor))
+        .ok_or(SystemError::BadPointer)?;
+      if access == Access::Write && region.get_permissions() == Permissions::ReadOnly {
+        return Err(SystemError::BadPointer);
+      }
+      let region_end = region.get_starting_address_as_usize() + region.get_size();
+      if region_end <= cursor {
+        return Err(SystemError::BadPointer);
+      }
+      cursor = region_end;
+    }
+    Ok(())
+  }
+
+  /// Check the range holding `count` values of type T, including its
+  /// alignment
+  fn validate_values<T>(&self, addr: u32, count: usize, access: Access) -> Result<(), SystemError> {
+    let addr = addr as usize;
+    if addr % core::mem::align_of::<T>() != 0 {
+      return Err(SystemError::BadPointer);
+    }
+    let length = core::mem::size_of::<T>().checked_mul(count).ok_or(SystemError::BadPointer)?;
+    self.validate(addr, length, access)
+  }
+
+  /// Check a range the kernel is only going to read from
+  pub fn validate_read(&self, addr: usize, length: usize) -> Result<(), SystemError> {
+    self.validate(addr, length, Access::Read)
+  }
+
+  /// Check a range the kernel is about to write to, and give the process its
+  /// own copy of any copy-on-write pages in it
+  pub fn prepare_write(&self, addr: usize, length: usize) -> Result<(), SystemError> {
+    self.validate(addr, length, Access::Write)?;
+    if !self.from_usermode {
+      return Ok(());
+    }
+    prepare_user_write(addr, length)
+  }
+
+  /// Copy `length` bytes from user memory into a kernel buffer
+  pub fn copy_from_user(&self, addr: usize, length: usize) -> Result<Vec<u8>, SystemError> {
+    self.validate(addr, length, Access::Read)?;
+    let mut buffer = Vec::with_capacity(length);
+    unsafe {
+      core::ptr::copy_nonoverlapping(addr as *const u8, buffer.as_mut_ptr(), length);
+      buffer.set_len(length);
+    }
+    Ok(buffer)
+  }
+
+  /// Copy bytes from the kernel out to user memory
+  pub fn copy_to_user(&self, addr: usize, bytes: &[u8]) -> Result<(), SystemError> {
+    self.prepare_write(addr, bytes.len())?;
+    unsafe {
+      core::ptr::copy_nonoverlapping(bytes.as_ptr(), addr as *mut u8, bytes.len());
+    }
+    Ok(())
+  }
+
+  /// Read a single value from user memory. It need not be aligned.
+  pub fn read_value<T: Copy>(&self, addr: u32) -> Result<T, SystemError> {
+    self.validate(addr as usize, core::mem::size_of::<T>(), Access::Read)?;
+    Ok(unsafe { core::ptr::read_unaligned(addr as usize as *const T) })
+  }
+
+  /// Write a single value to user memory. It need not be aligned.
+  pub fn write_value<T: Copy>(&self, addr: u32, value: T) -> Result<(), SystemError> {
+    self.prepare_write(addr as usize, core::mem::size_of::<T>())?;
+    unsafe { core::ptr::write_unaligned(addr as usize as *mut T, value) };
+    Ok(())
+  }
+
+  /// Check a pointer to a single value that the kernel will fill in
+  pub fn value_mut<T>(&self, addr: u32) -> Result<*mut T, SystemError> {
+    self.validate_values::<T>(addr, 1, Access::Write)?;
+    self.prepare_write(addr as usize, core::mem::size_of::<T>())?;
+    Ok(addr as usize as *mut T)
+  }
+
+  /// Borrow a user buffer in place, for data too large to copy. The caller
+  /// must not hold it past the end of the syscall, since the process may
+  /// unmap it afterwards.
+  pub unsafe fn slice_mut<'a, T>(&self, addr: u32, count: usize) -> Result<&'a mut [T], SystemError> {
+    if count == 0 {
+      return Ok(&mut []);
+    }
+    self.validate_values::<T>(addr, count, Access::Write)?;
+    self.prepare_write(addr as usize, core::mem::size_of::<T>() * count)?;
+    Ok(core::slice::from_raw_parts_mut(addr as usize as *mut T, count))
+  }
+
+  /// Copy the string described by the StringPtr at `ptr_addr`. A single NUL
+  /// at the end is dropped, for callers that pass C strings; a NUL anywhere
+  /// else is rejected, since the kernel would see a different string than the
+  /// caller.
+  pub fn read_string(&self, ptr_addr: u32, max_length: usize) -> Result<String, SystemError> {
+    let ptr: StringPtr = self.read_value(ptr_addr)?;
+    let (addr, length) = (ptr.addr, ptr.length);
+    // Leave room for the NUL, and refuse to copy anything longer
+    if length > max_length + 1 {
+      return Err(SystemError::NameTooLong);
+    }
+    let mut bytes = self.copy_from_user(addr, length)?;
+    if bytes.last() == Some(&0) {
+      bytes.pop();
+    }
+    if bytes.len() > max_length {
+      return Err(SystemError::NameTooLong);
+    }
+    if bytes.contains(&0) {
+      return Err(SystemError::InvalidArgument);
+    }
+    String::from_utf8(bytes).map_err(|_| SystemError::InvalidArgument)
+  }
+
+  pub fn read_path(&self, ptr_addr: u32) -> Result<String, SystemError> {
+    self.read_string(ptr_addr, MAX_PATH_LENGTH)
+  }
+
+  pub fn read_name(&self, ptr_addr: u32) -> Result<String, SystemError> {
+    self.read_string(ptr_addr, MAX_NAME_LENGTH)
+  }
+
+  /// Read a string that is not a path or name, like program arguments. A null
+  /// pointer reads as an empty string.
+  pub fn read_text(&self, ptr_addr: u32) -> Result<String, SystemError> {
+    if ptr_addr == 0 {
+      return Ok(String::new());
+    }
+    self.read_string(ptr_addr, MAX_STRING_LENGTH)
+  }
+}
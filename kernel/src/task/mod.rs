@@ -0,0 +1,10 @@
+//! Primitives that let kernel code coordinate with interrupt handlers and
+//! other processes without spinning or polling
+
+pub mod mutex;
+#[cfg(not(test))]
+pub mod wait_queue;
+
+pub use mutex::SleepMutex;
+#[cfg(not(test))]
+pub use wait_queue::WaitQueue;
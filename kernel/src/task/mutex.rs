@@ -0,0 +1,181 @@
+//! A SleepMutex protects data that is held for a long time, such as across
+//! disk I/O. A process that finds the lock taken blocks until it is released,
+//! instead of spinning through the rest of its time slice the way it would on
+//! a spin lock.
+//!
+//! The lock records which process holds it. When a process has to wait, it
+//! hands its turn to the owner: the owner is moved to the front of the run
+//! queue, so it finishes its critical section without waiting behind every
+//! other runnable process. The scheduler has no priority levels, so this is
+//! the form priority inheritance takes here; a process that other processes
+//! are waiting on is treated as the most urgent one to run. Only the owner
+//! that a waiter sees directly is promoted, not a chain of owners.
+//!
+//! Before the scheduler is running, or while the process map cannot be read,
+//! there is nothing to block on, and the lock spins instead. It must never be
+//! taken from an interrupt handler.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use spin::Mutex;
+#[cfg(not(test))]
+use super::WaitQueue;
+
+struct LockState {
+  locked: bool,
+  /// Raw ID of the process holding the lock, when it was taken by a process
+  owner: Option<u32>,
+}
+
+pub struct SleepMutex<T: ?Sized> {
+  /// Only held long enough to take or release the lock, never while blocked
+  state: Mutex<LockState>,
+  /// Processes waiting for the lock to be released
+  #[cfg(not(test))]
+  waiters: WaitQueue,
+  data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SleepMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SleepMutex<T> {}
+
+impl<T> SleepMutex<T> {
+  pub const fn new(data: T) -> SleepMutex<T> {
+    SleepMutex {
+      state: Mutex::new(LockState {
+        locked: false,
+        owner: None,
+      }),
+      #[cfg(not(test))]
+      waiters: WaitQueue::new(),
+      data: UnsafeCell::new(data),
+    }
+  }
+}
+
+impl<T: ?Sized> SleepMutex<T> {
+  /// Take the lock, blocking the current process until it is available
+  pub fn lock(&self) -> SleepMutexGuard<T> {
+    loop {
+      if let Some(guard) = self.try_lock() {
+        return guard;
+      }
+      self.wait_for_release();
+    }
+  }
+
+  /// Take the lock only if nobody holds it
+  pub fn try_lock(&self) -> Option<SleepMutexGuard<T>> {
+    let mut state = self.state.lock();
+    if state.locked {
+      return None;
+    }
+    state.locked = true;
+    state.owner = current_pid();
+    Some(SleepMutexGuard {
+      lock: self,
+    })
+  }
+
+  pub fn is_locked(&self) -> bool {
+    self.state.lock().locked
+  }
+
+  /// Raw ID of the process holding the lock, if it is held by a process
+  pub fn get_owner(&self) -> Option<u32> {
+    self.state.lock().owner
+  }
+
+  fn unlock(&self) {
+    {
+      let mut state = self.state.lock();
+      state.locked = false;
+      state.owner = None;
+    }
+    #[cfg(not(test))]
+    self.waiters.notify_one();
+  }
+
+  #[cfg(not(test))]
+  fn wait_for_release(&self) {
+    use crate::process::{id::ProcessID, queue};
+
+    let current = match current_pid() {
+      Some(pid) => pid,
+      // Nothing can block yet, so try again right away
+      None => return,
+    };
+    if let Some(owner) = self.get_owner() {
+      kassert!(owner != current, "Process {} locked a SleepMutex it already holds", current);
+      queue::promote(ProcessID::new(owner));
+    }
+    // The condition runs with interrupts off, so it must not spin on the state
+    // lock. If the lock is busy, it reports ready and the loop in `lock`
+    // checks again with interrupts on.
+    self.waiters.wait_until(|| {
+      self.state.try_lock().map_or(true, |state| !state.locked)
+    });
+  }
+
+  #[cfg(test)]
+  fn wait_for_release(&self) {}
+}
+
+#[cfg(not(test))]
+fn current_pid() -> Option<u32> {
+  crate::process::try_current_process_id().map(|pid| pid.as_u32())
+}
+
+#[cfg(test)]
+fn current_pid() -> Option<u32> {
+  None
+}
+
+pub struct SleepMutexGuard<'a, T: ?Sized> {
+  lock: &'a SleepMutex<T>,
+}
+
+impl<'a, T: ?Sized> Deref for SleepMutexGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.lock.data.get() }
+  }
+}
+
+impl<'a, T: ?Sized> DerefMut for SleepMutexGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.lock.data.get() }
+  }
+}
+
+impl<'a, T: ?Sized> Drop for SleepMutexGuard<'a, T> {
+  fn drop(&mut self) {
+    self.lock.unlock();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::SleepMutex;
+
+  #[test]
+  fn guards_data() {
+    let lock = SleepMutex::new(alloc::vec![1, 2]);
+    {
+      let mut data = lock.lock();
+      data.push(3);
+      assert!(lock.is_locked());
+      assert!(lock.try_lock().is_none());
+    }
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.try_lock().unwrap(), [1, 2, 3]);
+  }
+
+  #[test]
+  fn no_owner_outside_a_process() {
+    let lock = SleepMutex::new(0);
+    let _guard = lock.lock();
+    assert_eq!(lock.get_owner(), None);
+  }
+}
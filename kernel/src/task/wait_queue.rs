@@ -0,0 +1,116 @@
+//! A WaitQueue holds processes blocked until some event happens, like an IRQ
+//! arriving or data showing up in a buffer. Whoever causes the event calls
+//! `notify_one` or `notify_all`, which is safe from interrupt handlers as well
+//! as from other processes and kernel threads.
+//!
+//! Notifications are not remembered: waking a queue with nobody on it does
+//! nothing. Code waiting on an event should use `wait_until` with a condition
+//! that reflects the event, which is checked with interrupts disabled right
+//! before blocking, so an interrupt cannot deliver its notification in the
+//! gap between the check and the block.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::interrupts;
+use crate::process::{self, process_state::{BlockReason, ProcessState, RunState}};
+use spin::Mutex;
+
+pub struct WaitQueue {
+  /// Blocked processes, in the order they started waiting. Processes are held
+  /// directly rather than by ID, so that waking them never needs the process
+  /// map, which an interrupted process may have locked.
+  waiters: Mutex<Vec<Arc<ProcessState>>>,
+}
+
+impl WaitQueue {
+  pub const fn new() -> WaitQueue {
+    WaitQueue {
+      waiters: Mutex::new(Vec::new()),
+    }
+  }
+
+  /// Run `f` with the waiters locked. Interrupt handlers notify queues, so
+  /// interrupts stay off while the lock is held.
+  fn with_waiters<R, F: FnOnce(&mut Vec<Arc<ProcessState>>) -> R>(&self, f: F) -> R {
+    let int_reenable = interrupts::is_interrupt_enabled();
+    interrupts::cli();
+    let result = f(&mut self.waiters.lock());
+    if int_reenable {
+      interrupts::sti();
+    }
+    result
+  }
+
+  /// Block the current process until the queue is notified. Without a
+  /// condition to check, a notification that arrives before this call is
+  /// missed; most callers want `wait_until`.
+  pub fn wait(&self) {
+    if self.block_unless(&|| false) {
+      process::yield_coop();
+    }
+  }
+
+  /// Block the current process until `condition` returns true. It is checked
+  /// before blocking, and again each time the queue is notified.
+  pub fn wait_until<F: Fn() -> bool>(&self, condition: F) {
+    while self.block_unless(&condition) {
+      process::yield_coop();
+    }
+  }
+
+  /// Put the current process on the queue and block it, unless `ready`
+  /// returns true. Returns whether the process was blocked. Interrupts stay
+  /// off from the check until the process is blocked.
+  fn block_unless<F: Fn() -> bool>(&self, ready: &F) -> bool {
+    let current = match process::current_process() {
+      Some(current) => current,
+      None => return false,
+    };
+    let int_reenable = interrupts::is_interrupt_enabled();
+    interrupts::cli();
+    let blocked = if ready() {
+      false
+    } else {
+      self.with_waiters(|waiters| waiters.push(Arc::clone(&current)));
+      current.block_on_wait_queue();
+      true
+    };
+    if int_reenable {
+      interrupts::sti();
+    }
+    blocked
+  }
+
+  /// Wake the process that has waited longest. Waiters that were already
+  /// woken some other way, such as by being terminated, are dropped and
+  /// skipped. Returns whether a process was woken.
+  pub fn notify_one(&self) -> bool {
+    self.with_waiters(|waiters| {
+      while !waiters.is_empty() {
+        let waiter = waiters.remove(0);
+        if wake(&waiter) {
+          return true;
+        }
+      }
+      false
+    })
+  }
+
+  /// Wake every waiting process, returning how many were woken
+  pub fn notify_all(&self) -> usize {
+    let waiters = self.with_waiters(|waiters| core::mem::replace(waiters, Vec::new()));
+    waiters.iter().filter(|waiter| wake(waiter)).count()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.with_waiters(|waiters| waiters.is_empty())
+  }
+}
+
+/// Resume a waiter if it is still blocked on a queue
+fn wake(waiter: &ProcessState) -> bool {
+  waiter.update_run_state(|state| match state {
+    RunState::Blocked(BlockReason::WaitQueue) => Some(RunState::Running),
+    _ => None,
+  })
+}
@@ -0,0 +1,151 @@
+//! Headless test reporting lets userland regression suites run unattended
+//! under QEMU. Test programs report each result with the `test_report`
+//! syscall, and the kernel writes it to the emulator's debug console as a TAP
+//! line while keeping a tally. Once the suite calls `test_finish`, the kernel
+//! prints a summary and stops the emulator through isa-debug-exit, with a
+//! status that tells the harness whether every test passed.
+//!
+//! Reporting is off unless the `test_report` feature is set, or Scroll Lock is
+//! on while the bootloader runs, so that a stray test program can never stop
+//! an emulator someone is using interactively.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use syscall::testing::{
+  TEST_DIAGNOSTIC, TEST_EXIT_FAILED, TEST_EXIT_PASSED, TEST_FAIL, TEST_PASS,
+  TEST_SKIP,
+};
+
+/// Keyboard flag from the BIOS, passed through by the bootloader
+const BOOT_FLAG_SCROLL_LOCK: usize = 0x10;
+
+static ENABLED: AtomicBool = AtomicBool::new(cfg!(feature = "test_report"));
+
+pub fn is_enabled() -> bool {
+  ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+  ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Apply the keyboard state captured by the bootloader
+pub fn apply_boot_flags(flags: usize) {
+  if flags & BOOT_FLAG_SCROLL_LOCK != 0 {
+    set_enabled(true);
+  }
+}
+
+/// Counts of each result reported so far
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Tally {
+  pub passed: usize,
+  pub failed: usize,
+  pub skipped: usize,
+}
+
+impl Tally {
+  pub const fn new() -> Tally {
+    Tally {
+      passed: 0,
+      failed: 0,
+      skipped: 0,
+    }
+  }
+
+  pub fn total(&self) -> usize {
+    self.passed + self.failed + self.skipped
+  }
+
+  /// Count one result, returning its number in the run. Diagnostics are not
+  /// results, and return None.
+  pub fn record(&mut self, kind: u32) -> Result<Option<usize>, ()> {
+    match kind {
+      TEST_PASS => self.passed += 1,
+      TEST_FAIL => self.failed += 1,
+      TEST_SKIP => self.skipped += 1,
+      TEST_DIAGNOSTIC => return Ok(None),
+      _ => return Err(()),
+    }
+    Ok(Some(self.total()))
+  }
+
+  /// A run that reported nothing most likely crashed before its first test,
+  /// so it fails rather than passing vacuously
+  pub fn exit_code(&self) -> u32 {
+    if self.failed > 0 || self.passed + self.skipped == 0 {
+      TEST_EXIT_FAILED
+    } else {
+      TEST_EXIT_PASSED
+    }
+  }
+}
+
+static TALLY: Mutex<Tally> = Mutex::new(Tally::new());
+
+/// Record a result and write it to the debug console. Each result is one TAP
+/// line, and diagnostics become TAP comments.
+#[cfg(not(test))]
+pub fn report(kind: u32, message: &str) -> Result<(), ()> {
+  use core::fmt::Write;
+  use crate::hardware::qemu::DebugConsole;
+
+  let number = TALLY.lock().record(kind)?;
+  let mut console = DebugConsole::new();
+  let _ = match (kind, number) {
+    (TEST_FAIL, Some(number)) => writeln!(console, "not ok {} - {}", number, message),
+    (TEST_SKIP, Some(number)) => writeln!(console, "ok {} - {} # SKIP", number, message),
+    (_, Some(number)) => writeln!(console, "ok {} - {}", number, message),
+    (_, None) => writeln!(console, "# {}", message),
+  };
+  Ok(())
+}
+
+/// Print the plan and totals, then stop the emulator. Only returns if there is
+/// no exit device to stop it.
+#[cfg(not(test))]
+pub fn finish() {
+  use core::fmt::Write;
+  use crate::hardware::qemu::{self, DebugConsole};
+
+  let tally = *TALLY.lock();
+  let mut console = DebugConsole::new();
+  let _ = writeln!(console, "1..{}", tally.total());
+  let _ = writeln!(
+    console,
+    "# passed {}, failed {}, skipped {}",
+    tally.passed,
+    tally.failed,
+    tally.skipped,
+  );
+  qemu::debug_exit(tally.exit_code());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Tally;
+  use syscall::testing::{
+    TEST_DIAGNOSTIC, TEST_EXIT_FAILED, TEST_EXIT_PASSED, TEST_FAIL, TEST_PASS,
+    TEST_SKIP,
+  };
+
+  #[test]
+  fn numbers_results() {
+    let mut tally = Tally::new();
+    assert_eq!(tally.record(TEST_PASS), Ok(Some(1)));
+    assert_eq!(tally.record(TEST_DIAGNOSTIC), Ok(None));
+    assert_eq!(tally.record(TEST_SKIP), Ok(Some(2)));
+    assert_eq!(tally.record(7), Err(()));
+    assert_eq!(tally.total(), 2);
+  }
+
+  #[test]
+  fn exit_code_reflects_failures() {
+    let mut tally = Tally::new();
+    assert_eq!(tally.exit_code(), TEST_EXIT_FAILED);
+    tally.record(TEST_PASS).unwrap();
+    assert_eq!(tally.exit_code(), TEST_EXIT_PASSED);
+    tally.record(TEST_FAIL).unwrap();
+    assert_eq!(tally.exit_code(), TEST_EXIT_FAILED);
+  }
+}
@@ -45,13 +45,102 @@ pub struct DateTime {
 
 const SECONDS_IN_DAY: u32 = 60 * 60 * 24;
 
-const MONTH_START_OFFSET: [u32; 12] = [
-  0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334,
+const MONTH_START_OFFSET: [u32; 13] = [
+  0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334, 365,
 ];
 
+/// Years are stored as an offset from 1980
+pub const BASE_YEAR: u32 = 1980;
+
+/// 1 January 1980 was a Tuesday
+const BASE_WEEKDAY: u32 = 2;
+
+/// Whether a year, counted from 1980, has a 29 February. 2000 was a leap year,
+/// but 2100 will not be.
+pub fn is_leap_year(year_offset: u32) -> bool {
+  let year = BASE_YEAR + year_offset;
+  year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+/// Leap years from 1 AD up to, but not including, a full year number
+fn leap_years_before(year: u32) -> u32 {
+  let previous = year - 1;
+  previous / 4 - previous / 100 + previous / 400
+}
+
+/// Days from 1 January 1980 to the start of a year, counted from 1980
+pub fn days_before_year(year_offset: u32) -> u32 {
+  let leap_days = leap_years_before(BASE_YEAR + year_offset) - leap_years_before(BASE_YEAR);
+  year_offset * 365 + leap_days
+}
+
+/// Days from the start of a year to the first of a month. Month 13 gives the
+/// length of the year.
+pub fn days_before_month(year_offset: u32, month: u32) -> u32 {
+  let mut days = MONTH_START_OFFSET[month as usize - 1];
+  if month > 2 && is_leap_year(year_offset) {
+    days += 1;
+  }
+  days
+}
+
+pub fn days_in_month(year_offset: u32, month: u32) -> u32 {
+  days_before_month(year_offset, month + 1) - days_before_month(year_offset, month)
+}
+
+/// Day of the week, from 0 for Sunday to 6 for Saturday, of a day counted
+/// from 1 January 1980
+pub fn weekday_from_days(days: u32) -> u8 {
+  ((days + BASE_WEEKDAY) % 7) as u8
+}
+
 pub fn year_offset_from_days(days: u32) -> u32 {
-  let hundredths = days * 100;
-  hundredths / 36525
+  // Every year is at least 365 days long, so this never guesses too early
+  let mut year = days / 365;
+  while year > 0 && days_before_year(year) > days {
+    year -= 1;
+  }
+  year
+}
+
+impl Date {
+  /// Days from 1 January 1980 to this date
+  pub fn to_days(&self) -> u32 {
+    let year = self.year as u32;
+    days_before_year(year) + days_before_month(year, self.month as u32) + self.day as u32 - 1
+  }
+
+  pub fn from_days(days: u32) -> Date {
+    let year = year_offset_from_days(days);
+    let year_days = days - days_before_year(year);
+    let mut month = 1;
+    while month < 12 && days_before_month(year, month + 1) <= year_days {
+      month += 1;
+    }
+    let day = year_days - days_before_month(year, month) + 1;
+    Date {
+      day: day as u8,
+      month: month as u8,
+      year: year as u8,
+    }
+  }
+
+  /// Day of the week, from 0 for Sunday to 6 for Saturday
+  pub fn day_of_week(&self) -> u8 {
+    weekday_from_days(self.to_days())
+  }
+
+  pub fn get_full_year(&self) -> u32 {
+    BASE_YEAR + self.year as u32
+  }
+
+  /// Whether the fields describe a day that exists
+  pub fn is_valid(&self) -> bool {
+    self.month >= 1
+      && self.month <= 12
+      && self.day >= 1
+      && (self.day as u32) <= days_in_month(self.year as u32, self.month as u32)
+  }
 }
 
 impl Timestamp {
@@ -63,22 +152,6 @@ impl Timestamp {
 
   pub fn to_datetime(&self) -> DateTime {
     let (days, raw_time) = self.to_days_with_remainder();
-    let year_offset = year_offset_from_days(days);
-    let quadrennial_days = days % (365 + 365 + 365 + 366);
-    let year_days = if quadrennial_days > 365 { (quadrennial_days - 366) % 365 } else { quadrennial_days };
-    let mut month = 0;
-    let mut leap = 0;
-    while month < 12 && MONTH_START_OFFSET[month] + leap <= year_days {
-      month += 1;
-      if month == 2 && year_offset % 4 == 0 {
-        // 2000 is a leap year, don't need to check against 2100
-        leap = 1;
-      }
-    }
-    let mut day = year_days + 1 - MONTH_START_OFFSET[month - 1];
-    if month > 2 {
-      day -= leap;
-    }
 
     let total_minutes = raw_time / 60;
     let seconds = raw_time % 60;
@@ -86,11 +159,7 @@ impl Timestamp {
     let minutes = total_minutes % 60;
 
     DateTime {
-      date: Date {
-        day: day as u8,
-        month: month as u8,
-        year: year_offset as u8,
-      },
+      date: Date::from_days(days),
       time: Time {
         hours: hours as u8,
         minutes: minutes as u8,
@@ -99,28 +168,22 @@ impl Timestamp {
     }
   }
 
+  /// Dates after early 2116 do not fit in a Timestamp, and are clamped to the
+  /// last second it can hold
   pub fn from_datetime(dt: DateTime) -> Timestamp {
-    let quadrennials = dt.date.year as u32 / 4;
-    let year_remainder = dt.date.year as u32 % 4;
-    let mut days = quadrennials * (366 + 365 + 365 + 365) + year_remainder * 365;
-    if year_remainder > 0 {
-      days += 1;
-    }
-    days += MONTH_START_OFFSET[dt.date.month as usize - 1];
-    days += dt.date.day as u32;
+    let days = dt.date.to_days() as u64;
+    let timestamp = days * SECONDS_IN_DAY as u64
+      + dt.time.hours as u64 * 60 * 60
+      + dt.time.minutes as u64 * 60
+      + dt.time.seconds as u64;
 
-    let timestamp = days * SECONDS_IN_DAY
-      + dt.time.hours as u32 * 60 * 60
-      + dt.time.minutes as u32 * 60
-      + dt.time.seconds as u32;
-
-    Timestamp(timestamp)
+    Timestamp(timestamp.min(u32::MAX as u64) as u32)
   }
 }
 
 #[cfg(test)]
 mod tests {
-  use super::{Date, Time, Timestamp, year_offset_from_days};
+  use super::{Date, DateTime, Time, Timestamp, is_leap_year, year_offset_from_days};
 
   #[test]
   fn year_offset() {
@@ -172,4 +235,55 @@ mod tests {
     let dt = Timestamp(1278713001).to_datetime();
     assert_eq!(Timestamp::from_datetime(dt), Timestamp(1278713001));
   }
+
+  #[test]
+  fn leap_years() {
+    assert!(is_leap_year(0));
+    assert!(!is_leap_year(1));
+    assert!(is_leap_year(20)); // 2000
+    assert!(!is_leap_year(120)); // 2100
+    let date = Timestamp(126230400 + 60 * 86400).to_datetime().date;
+    assert_eq!(date, Date{ day: 1, month: 3, year: 4 });
+    // 2100 has no 29 February
+    let end_of_feb = Date{ day: 28, month: 2, year: 120 };
+    let next = Date::from_days(end_of_feb.to_days() + 1);
+    assert_eq!(next, Date{ day: 1, month: 3, year: 120 });
+    assert!(!Date{ day: 29, month: 2, year: 120 }.is_valid());
+    assert!(Date{ day: 29, month: 2, year: 20 }.is_valid());
+  }
+
+  #[test]
+  fn round_trip_dates() {
+    let mut days = 0;
+    while days < 49000 {
+      let date = Date::from_days(days);
+      assert!(date.is_valid());
+      assert_eq!(date.to_days(), days);
+      days += 1;
+    }
+  }
+
+  #[test]
+  fn day_of_week() {
+    assert_eq!(Date{ day: 1, month: 1, year: 0 }.day_of_week(), 2);
+    assert_eq!(Date{ day: 29, month: 2, year: 20 }.day_of_week(), 2);
+    assert_eq!(Date{ day: 8, month: 7, year: 40 }.day_of_week(), 3);
+    assert_eq!(Date{ day: 1, month: 3, year: 120 }.day_of_week(), 1);
+  }
+
+  #[test]
+  fn dates_past_2100() {
+    let dt = DateTime {
+      date: Date{ day: 1, month: 1, year: 121 },
+      time: Time{ hours: 12, minutes: 0, seconds: 0 },
+    };
+    let converted = Timestamp::from_datetime(dt).to_datetime();
+    assert_eq!(converted.date, Date{ day: 1, month: 1, year: 121 });
+    assert_eq!(converted.time, Time{ hours: 12, minutes: 0, seconds: 0 });
+    let too_late = DateTime {
+      date: Date{ day: 1, month: 1, year: 200 },
+      time: Time{ hours: 0, minutes: 0, seconds: 0 },
+    };
+    assert_eq!(Timestamp::from_datetime(too_late), Timestamp(u32::MAX));
+  }
 }
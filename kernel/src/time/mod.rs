@@ -2,3 +2,5 @@ pub mod date;
 #[cfg(not(test))]
 pub mod system;
 pub mod timestamp;
+pub mod usage;
+pub mod zone;
@@ -1,10 +1,15 @@
 /// Utilities for managing system time
 
-use spin::Mutex;
+use alloc::vec::Vec;
+use spin::{Mutex, RwLock};
+use syscall::files::OpenFlags;
 
 use crate::devices;
+use crate::filesystems;
 use crate::interrupts;
+use super::date::DateTime;
 use super::timestamp::{Timestamp, TimestampHires};
+use super::zone::TimeZone;
 
 pub const HUNDRED_NS_PER_TICK: u64 = 100002;
 pub const MS_PER_TICK: usize = (HUNDRED_NS_PER_TICK / 10000) as usize;
@@ -17,6 +22,18 @@ static KNOWN_TIME: Mutex<TimestampHires> = Mutex::new(TimestampHires(0));
 /// Store an offset, regularly updated by the PIT
 static TIME_OFFSET: Mutex<TimestampHires> = Mutex::new(TimestampHires(0));
 
+/// Time elapsed since the timer started ticking. Unlike the offset, this is
+/// never reset when the wall clock is corrected, so it is safe for measuring
+/// intervals.
+static UPTIME: Mutex<TimestampHires> = Mutex::new(TimestampHires(0));
+
+/// The local time zone. System time is kept in UTC, and converted through
+/// this whenever it is shown to the user or written to disk.
+static TIME_ZONE: RwLock<TimeZone> = RwLock::new(TimeZone::utc());
+
+/// File on the INIT drive holding a POSIX TZ string
+const TIME_ZONE_PATH: &str = "\\TZ";
+
 /// Reset the known true reference point
 pub fn reset_known_time(time: u64) {
   let int_reenable = interrupts::is_interrupt_enabled();
@@ -64,25 +81,99 @@ pub fn get_offset_seconds() -> u64 {
   seconds
 }
 
+/// Called on each timer tick to advance both the system time and the uptime
 pub fn increment_offset(delta: u64) {
   let int_reenable = interrupts::is_interrupt_enabled();
   interrupts::cli();
 
   {
-    TIME_OFFSET.lock().increment(delta);
+    let mut offset = TIME_OFFSET.lock();
+    let mut uptime = UPTIME.lock();
+    offset.increment(delta);
+    uptime.increment(delta);
+    let system_time = *KNOWN_TIME.lock() + *offset;
+    crate::process::shared_data::publish_time(uptime.0, system_time.0);
+  }
+
+  if int_reenable {
+    interrupts::sti();
   }
+}
+
+/// Time since the kernel started its timer, in 100ns increments. The value
+/// advances once per tick, so it has the resolution of the PIT.
+pub fn get_uptime() -> TimestampHires {
+  let int_reenable = interrupts::is_interrupt_enabled();
+  interrupts::cli();
+
+  let uptime = {
+    *UPTIME.lock()
+  };
 
   if int_reenable {
     interrupts::sti();
   }
+  uptime
 }
 
-/// Process 
+/// The wall-clock time at which the system booted, derived from the current
+/// time and the uptime so that it follows any correction to the clock
+pub fn get_boot_time() -> TimestampHires {
+  let now = get_system_time();
+  let uptime = get_uptime();
+  TimestampHires(now.0.saturating_sub(uptime.0))
+}
+
+/// Set the system time from the CMOS clock. Following DOS, the RTC holds
+/// local time, so the time zone should be loaded first.
 pub fn initialize_from_rtc() {
   let cmos_time = unsafe {
     devices::RTC.read_time()
   };
-  let timestamp = Timestamp::from_datetime(cmos_time.to_datetime());
+  let local = Timestamp::from_datetime(cmos_time.to_datetime());
+  let timestamp = get_time_zone().to_utc(local);
   let system_time = TimestampHires::from_timestamp(timestamp);
   reset_known_time(system_time.0);
 }
+
+pub fn get_time_zone() -> TimeZone {
+  *TIME_ZONE.read()
+}
+
+pub fn set_time_zone(zone: TimeZone) {
+  *TIME_ZONE.write() = zone;
+}
+
+/// Convert a UTC timestamp to the local date and time
+pub fn to_local_datetime(time: Timestamp) -> DateTime {
+  get_time_zone().to_local_datetime(time)
+}
+
+/// The current date and time in the local time zone
+pub fn get_local_time() -> DateTime {
+  to_local_datetime(get_system_time().to_timestamp())
+}
+
+/// Read the time zone from the TZ file on the INIT drive. If the file is
+/// missing or cannot be parsed, the clock stays on UTC.
+pub fn load_time_zone() -> Result<(), ()> {
+  let number = filesystems::get_fs_number("INIT").ok_or(())?;
+  let fs = filesystems::get_fs(number).ok_or(())?;
+  let handle = filesystems::open_path(number, TIME_ZONE_PATH, OpenFlags::read_only())
+    .map_err(|_| ())?;
+  let mut contents = Vec::new();
+  let mut buffer = [0; 64];
+  let result = loop {
+    match fs.read(handle, &mut buffer) {
+      Ok(0) => break Ok(()),
+      Ok(read) => contents.extend_from_slice(&buffer[..read]),
+      Err(_) => break Err(()),
+    }
+  };
+  let _ = fs.close(handle);
+  result?;
+  let text = core::str::from_utf8(&contents).map_err(|_| ())?;
+  let zone = TimeZone::parse(text).ok_or(())?;
+  set_time_zone(zone);
+  Ok(())
+}
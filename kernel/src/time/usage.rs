@@ -0,0 +1,161 @@
+//! CPU usage is tracked as an exponentially decaying average of the ticks a
+//! process spent running. Each tick the average moves 1/32 of the way toward
+//! either 100% or 0%, depending on whether the process was on the CPU, so
+//! bursts from about the last third of a second dominate the figure. Readers
+//! get a smoothed value without having to sample counters over an interval.
+//!
+//! Processes that are not running do not need to be visited on every tick:
+//! the ticks they missed are applied all at once when the process next runs,
+//! or when its usage is read.
+
+/// Fixed-point representation of 100%
+const FULL: u32 = 100 << 16;
+/// Each tick keeps 31/32 of the previous average
+const DECAY_SHIFT: u32 = 5;
+/// Once an average falls below 1 << DECAY_SHIFT it no longer decays, and even
+/// a full average gets there within this many ticks
+const MAX_DECAY_TICKS: u32 = 512;
+
+#[derive(Copy, Clone)]
+pub struct CpuUsage {
+  average: u32,
+  total_ticks: u32,
+  /// Tick count when the average was last brought up to date
+  last_tick: u32,
+}
+
+impl CpuUsage {
+  pub fn new() -> CpuUsage {
+    CpuUsage {
+      average: 0,
+      total_ticks: 0,
+      last_tick: 0,
+    }
+  }
+
+  /// Account for one timer tick, during which the process either ran or not
+  pub fn tick(&mut self, ran: bool) {
+    self.average -= self.average >> DECAY_SHIFT;
+    if ran {
+      self.average += FULL >> DECAY_SHIFT;
+      self.total_ticks = self.total_ticks.wrapping_add(1);
+    }
+  }
+
+  /// Account for a run of ticks during which the process did not run
+  pub fn skip(&mut self, ticks: u32) {
+    for _ in 0..ticks.min(MAX_DECAY_TICKS) {
+      self.average -= self.average >> DECAY_SHIFT;
+    }
+  }
+
+  /// Account for tick number `now`, during which the process ran. Ticks since
+  /// the last one charged are counted as idle.
+  pub fn charge(&mut self, now: u32) {
+    self.skip(now.wrapping_sub(self.last_tick).saturating_sub(1));
+    self.tick(true);
+    self.last_tick = now;
+  }
+
+  /// The usage as it stands at tick number `now`, counting any ticks since
+  /// the last charge as idle
+  pub fn as_of(&self, now: u32) -> CpuUsage {
+    let mut usage = *self;
+    usage.skip(now.wrapping_sub(self.last_tick));
+    usage.last_tick = now;
+    usage
+  }
+
+  /// Recent share of the CPU, in tenths of a percent
+  pub fn get_permille(&self) -> u32 {
+    let permille = ((self.average as u64 * 10 + (1 << 15)) >> 16) as u32;
+    permille.min(1000)
+  }
+
+  /// Ticks spent running since tracking began
+  pub fn get_total_ticks(&self) -> u32 {
+    self.total_ticks
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::CpuUsage;
+
+  #[test]
+  fn converges_on_full_usage() {
+    let mut usage = CpuUsage::new();
+    assert_eq!(usage.get_permille(), 0);
+    for _ in 0..500 {
+      usage.tick(true);
+    }
+    assert_eq!(usage.get_permille(), 1000);
+    assert_eq!(usage.get_total_ticks(), 500);
+  }
+
+  #[test]
+  fn decays_when_idle() {
+    let mut usage = CpuUsage::new();
+    for _ in 0..500 {
+      usage.tick(true);
+    }
+    // After one time constant, roughly 1/e of the average remains
+    for _ in 0..32 {
+      usage.tick(false);
+    }
+    let remaining = usage.get_permille();
+    assert!(remaining > 350 && remaining < 380);
+    for _ in 0..1000 {
+      usage.tick(false);
+    }
+    assert_eq!(usage.get_permille(), 0);
+    assert_eq!(usage.get_total_ticks(), 500);
+  }
+
+  #[test]
+  fn tracks_partial_usage() {
+    let mut usage = CpuUsage::new();
+    for i in 0..2000 {
+      usage.tick(i % 4 == 0);
+    }
+    let permille = usage.get_permille();
+    assert!(permille > 200 && permille < 300);
+  }
+
+  #[test]
+  fn skipping_matches_idle_ticks() {
+    let mut ticked = CpuUsage::new();
+    for _ in 0..100 {
+      ticked.tick(true);
+    }
+    let mut skipped = ticked;
+    for _ in 0..40 {
+      ticked.tick(false);
+    }
+    skipped.skip(40);
+    assert_eq!(skipped.get_permille(), ticked.get_permille());
+    for _ in 0..2000 {
+      ticked.tick(false);
+    }
+    skipped.skip(2000);
+    assert_eq!(skipped.get_permille(), ticked.get_permille());
+  }
+
+  #[test]
+  fn charges_catch_up_on_missed_ticks() {
+    let mut charged = CpuUsage::new();
+    let mut ticked = CpuUsage::new();
+    for now in 1..=100 {
+      charged.charge(now);
+      ticked.tick(true);
+    }
+    for _ in 0..20 {
+      ticked.tick(false);
+    }
+    charged.charge(121);
+    ticked.tick(true);
+    assert_eq!(charged.get_permille(), ticked.get_permille());
+    assert_eq!(charged.get_total_ticks(), 101);
+    assert!(charged.as_of(200).get_permille() < charged.get_permille());
+  }
+}
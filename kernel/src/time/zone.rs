@@ -0,0 +1,344 @@
+//! The system clock always counts UTC. Anything shown to the user, or stored
+//! in a format that has no notion of time zones like FAT directory entries,
+//! is converted to local time through a TimeZone first.
+//!
+//! Zones are described with POSIX TZ strings, such as `EST5EDT,M3.2.0,M11.1.0`
+//! or `CET-1CEST,M3.5.0,M10.5.0/3`. Offsets in these strings count hours
+//! *west* of Greenwich, so `EST5` is five hours behind UTC. Daylight saving
+//! rules pick the nth weekday of a month; only the `Mm.w.d` form is supported.
+
+use super::date::{DateTime, days_before_month, days_before_year, weekday_from_days};
+use super::timestamp::Timestamp;
+
+const SECONDS_IN_HOUR: i32 = 60 * 60;
+const SECONDS_IN_DAY: i64 = 60 * 60 * 24;
+
+/// The point at which daylight saving starts or ends: a weekday (0 is Sunday)
+/// in the given week of a month, where week 5 means the last one. The time is
+/// measured in local time, as it was before the change.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TransitionRule {
+  pub month: u8,
+  pub week: u8,
+  pub weekday: u8,
+  pub seconds: i32,
+}
+
+impl TransitionRule {
+  /// Local time of the transition in a year, counted from 1980
+  fn local_time_in_year(&self, year: u32) -> i64 {
+    let month_start = days_to_month(year, self.month as u32);
+    let month_end = days_to_month(year, self.month as u32 + 1);
+    let first_weekday = weekday_from_days(month_start) as u32;
+    let mut day = month_start + (self.weekday as u32 + 7 - first_weekday) % 7;
+    day += (self.week as u32 - 1) * 7;
+    while day >= month_end {
+      day -= 7;
+    }
+    day as i64 * SECONDS_IN_DAY + self.seconds as i64
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DaylightRule {
+  /// Seconds east of UTC while daylight saving is in effect
+  pub offset: i32,
+  pub start: TransitionRule,
+  pub end: TransitionRule,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TimeZone {
+  /// Seconds east of UTC for standard time
+  pub offset: i32,
+  pub daylight: Option<DaylightRule>,
+}
+
+/// US rules, used when a zone has a daylight name but no rule of its own
+const DEFAULT_START: TransitionRule = TransitionRule { month: 3, week: 2, weekday: 0, seconds: 2 * SECONDS_IN_HOUR };
+const DEFAULT_END: TransitionRule = TransitionRule { month: 11, week: 1, weekday: 0, seconds: 2 * SECONDS_IN_HOUR };
+
+impl TimeZone {
+  pub const fn utc() -> TimeZone {
+    TimeZone {
+      offset: 0,
+      daylight: None,
+    }
+  }
+
+  /// Parse a POSIX TZ string
+  pub fn parse(spec: &str) -> Option<TimeZone> {
+    let mut parser = Parser { rest: spec.trim().as_bytes() };
+    parser.name()?;
+    let offset = -parser.offset()?;
+    if parser.is_done() {
+      return Some(TimeZone { offset, daylight: None });
+    }
+    parser.name()?;
+    let daylight_offset = match parser.peek() {
+      Some(b',') | None => offset + SECONDS_IN_HOUR,
+      _ => -parser.offset()?,
+    };
+    let (start, end) = if parser.is_done() {
+      (DEFAULT_START, DEFAULT_END)
+    } else {
+      parser.expect(b',')?;
+      let start = parser.rule()?;
+      parser.expect(b',')?;
+      let end = parser.rule()?;
+      (start, end)
+    };
+    if !parser.is_done() {
+      return None;
+    }
+    Some(TimeZone {
+      offset,
+      daylight: Some(DaylightRule {
+        offset: daylight_offset,
+        start,
+        end,
+      }),
+    })
+  }
+
+  /// Seconds east of UTC in effect at a moment in time
+  pub fn offset_at(&self, utc: Timestamp) -> i32 {
+    let rule = match self.daylight {
+      Some(rule) => rule,
+      None => return self.offset,
+    };
+    let standard = utc.0 as i64 + self.offset as i64;
+    let year = Timestamp(standard.max(0) as u32).to_datetime().date.year as u32;
+    // Daylight saving starts by the clock of standard time, and ends by the
+    // clock of daylight time
+    let start = rule.start.local_time_in_year(year) - self.offset as i64;
+    let end = rule.end.local_time_in_year(year) - rule.offset as i64;
+    let time = utc.0 as i64;
+    let in_daylight = if start < end {
+      time >= start && time < end
+    } else {
+      // Southern hemisphere zones are on daylight time over the new year
+      time >= start || time < end
+    };
+    if in_daylight {
+      rule.offset
+    } else {
+      self.offset
+    }
+  }
+
+  pub fn to_local(&self, utc: Timestamp) -> Timestamp {
+    shift(utc, self.offset_at(utc))
+  }
+
+  /// Convert a local time back to UTC. Local times that are skipped when the
+  /// clocks go forward are treated as standard time, and ones that repeat when
+  /// they go back resolve to the earlier of the two.
+  pub fn to_utc(&self, local: Timestamp) -> Timestamp {
+    if let Some(rule) = self.daylight {
+      let as_daylight = shift(local, -rule.offset);
+      if self.offset_at(as_daylight) == rule.offset {
+        return as_daylight;
+      }
+    }
+    shift(local, -self.offset)
+  }
+
+  pub fn to_local_datetime(&self, utc: Timestamp) -> DateTime {
+    self.to_local(utc).to_datetime()
+  }
+}
+
+/// Move a timestamp by a number of seconds, clamping at the ends of the range
+/// a Timestamp can hold
+fn shift(time: Timestamp, seconds: i32) -> Timestamp {
+  let shifted = time.0 as i64 + seconds as i64;
+  Timestamp(shifted.max(0).min(u32::MAX as i64) as u32)
+}
+
+/// Days from 1 January 1980 to the first of a month, where month 13 is the
+/// start of the following year
+fn days_to_month(year: u32, month: u32) -> u32 {
+  days_before_year(year) + days_before_month(year, month)
+}
+
+struct Parser<'a> {
+  rest: &'a [u8],
+}
+
+impl<'a> Parser<'a> {
+  fn peek(&self) -> Option<u8> {
+    self.rest.first().copied()
+  }
+
+  fn is_done(&self) -> bool {
+    self.rest.is_empty()
+  }
+
+  fn advance(&mut self) -> Option<u8> {
+    let next = self.peek()?;
+    self.rest = &self.rest[1..];
+    Some(next)
+  }
+
+  fn expect(&mut self, byte: u8) -> Option<()> {
+    if self.advance()? == byte {
+      Some(())
+    } else {
+      None
+    }
+  }
+
+  /// A zone abbreviation: at least three letters, or anything in angle
+  /// brackets like `<+0530>`
+  fn name(&mut self) -> Option<()> {
+    if self.peek() == Some(b'<') {
+      self.advance();
+      while self.advance()? != b'>' {}
+      return Some(());
+    }
+    let mut length = 0;
+    while let Some(b'A'..=b'Z') | Some(b'a'..=b'z') = self.peek() {
+      self.advance();
+      length += 1;
+    }
+    if length >= 3 {
+      Some(())
+    } else {
+      None
+    }
+  }
+
+  fn number(&mut self) -> Option<i32> {
+    let mut value: i32 = 0;
+    let mut digits = 0;
+    while let Some(digit @ b'0'..=b'9') = self.peek() {
+      self.advance();
+      value = value.checked_mul(10)?.checked_add((digit - b'0') as i32)?;
+      digits += 1;
+    }
+    if digits > 0 {
+      Some(value)
+    } else {
+      None
+    }
+  }
+
+  /// `[+|-]hh[:mm[:ss]]`, in seconds
+  fn offset(&mut self) -> Option<i32> {
+    let sign = match self.peek() {
+      Some(b'-') => {
+        self.advance();
+        -1
+      },
+      Some(b'+') => {
+        self.advance();
+        1
+      },
+      _ => 1,
+    };
+    let hours = self.number()?;
+    let mut seconds = hours * SECONDS_IN_HOUR;
+    if self.peek() == Some(b':') {
+      self.advance();
+      seconds += self.number()? * 60;
+      if self.peek() == Some(b':') {
+        self.advance();
+        seconds += self.number()?;
+      }
+    }
+    if hours > 24 {
+      return None;
+    }
+    Some(sign * seconds)
+  }
+
+  /// `Mm.w.d[/time]`
+  fn rule(&mut self) -> Option<TransitionRule> {
+    self.expect(b'M')?;
+    let month = self.number()?;
+    self.expect(b'.')?;
+    let week = self.number()?;
+    self.expect(b'.')?;
+    let weekday = self.number()?;
+    if month < 1 || month > 12 || week < 1 || week > 5 || weekday > 6 {
+      return None;
+    }
+    let seconds = if self.peek() == Some(b'/') {
+      self.advance();
+      self.offset()?
+    } else {
+      2 * SECONDS_IN_HOUR
+    };
+    Some(TransitionRule {
+      month: month as u8,
+      week: week as u8,
+      weekday: weekday as u8,
+      seconds,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{SECONDS_IN_DAY, TimeZone, Timestamp, TransitionRule, days_to_month};
+  use crate::time::date::{Date, Time};
+
+  fn at(year: u32, month: u32, day: u32, hours: u32, minutes: u32) -> Timestamp {
+    let days = days_to_month(year - 1980, month) + day - 1;
+    Timestamp(days * SECONDS_IN_DAY as u32 + hours * 3600 + minutes * 60)
+  }
+
+  #[test]
+  fn parses_zones() {
+    assert_eq!(TimeZone::parse("UTC0"), Some(TimeZone::utc()));
+    let zone = TimeZone::parse("EST5EDT,M3.2.0,M11.1.0").unwrap();
+    assert_eq!(zone.offset, -5 * 3600);
+    let daylight = zone.daylight.unwrap();
+    assert_eq!(daylight.offset, -4 * 3600);
+    assert_eq!(daylight.start, TransitionRule { month: 3, week: 2, weekday: 0, seconds: 7200 });
+    let zone = TimeZone::parse("<+0530>-5:30").unwrap();
+    assert_eq!(zone.offset, 5 * 3600 + 30 * 60);
+    assert_eq!(zone.daylight, None);
+    assert_eq!(TimeZone::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap().daylight.unwrap().end.seconds, 3 * 3600);
+    assert_eq!(TimeZone::parse("X5"), None);
+    assert_eq!(TimeZone::parse("EST5EDT,M13.1.0,M11.1.0"), None);
+  }
+
+  #[test]
+  fn converts_standard_time() {
+    let zone = TimeZone::parse("EST5EDT,M3.2.0,M11.1.0").unwrap();
+    let utc = at(2021, 1, 15, 12, 0);
+    let local = zone.to_local_datetime(utc);
+    assert_eq!(local.date, Date { day: 15, month: 1, year: 41 });
+    assert_eq!(local.time, Time { hours: 7, minutes: 0, seconds: 0 });
+    assert_eq!(zone.to_utc(zone.to_local(utc)), utc);
+  }
+
+  #[test]
+  fn follows_daylight_saving() {
+    let zone = TimeZone::parse("EST5EDT,M3.2.0,M11.1.0").unwrap();
+    // In 2021, DST ran from 14 March to 7 November
+    assert_eq!(zone.offset_at(at(2021, 3, 14, 6, 59)), -5 * 3600);
+    assert_eq!(zone.offset_at(at(2021, 3, 14, 7, 0)), -4 * 3600);
+    assert_eq!(zone.offset_at(at(2021, 11, 7, 5, 59)), -4 * 3600);
+    assert_eq!(zone.offset_at(at(2021, 11, 7, 6, 0)), -5 * 3600);
+    let summer = at(2021, 7, 4, 16, 0);
+    assert_eq!(zone.to_local_datetime(summer).time, Time { hours: 12, minutes: 0, seconds: 0 });
+    assert_eq!(zone.to_utc(zone.to_local(summer)), summer);
+  }
+
+  #[test]
+  fn southern_hemisphere() {
+    let zone = TimeZone::parse("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+    assert_eq!(zone.offset_at(at(2021, 1, 1, 0, 0)), 11 * 3600);
+    assert_eq!(zone.offset_at(at(2021, 6, 1, 0, 0)), 10 * 3600);
+    assert_eq!(zone.offset_at(at(2021, 12, 1, 0, 0)), 11 * 3600);
+  }
+
+  #[test]
+  fn clamps_at_epoch() {
+    let zone = TimeZone::parse("EST5").unwrap();
+    assert_eq!(zone.to_local(Timestamp(0)), Timestamp(0));
+  }
+}
@@ -1,5 +1,10 @@
 use crate::drivers::driver::DeviceDriver;
 use crate::files::handle::LocalHandle;
+use syscall::files::OpenFlags;
+use crate::process::{self, id::ProcessID};
+use crate::drivers::keyboard;
+use syscall::flags::{TCGETS, TCSETS, TIOCGPGRP, TIOCSPGRP, TTY_GET_LOCK_KEYS, TTY_SET_COMPLETIONS, TTY_SET_LOCK_KEYS};
+use super::keyboard::LockKeys;
 
 /// Device driver representing a TTY, so a shell program can open up DEV:/TTY1
 /// and listen to console input / publish to the terminal.
@@ -16,7 +21,7 @@ impl TTYDevice {
 }
 
 impl DeviceDriver for TTYDevice {
-  fn open(&self, _handle: LocalHandle) -> Result<(), ()> {
+  fn open(&self, _handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
 
     Ok(())
   }
@@ -39,14 +44,63 @@ impl DeviceDriver for TTYDevice {
   }
 
   fn write(&self, _handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
-    let router = super::get_router().read();
-    let buffers = router.get_tty_buffers(self.tty_id);
-    match buffers {
-      Some(b) => {
-        let bytes_written = b.write(buffer);
-        Ok(bytes_written)
+    let (buffers, throttled) = {
+      let router = super::get_router().read();
+      let buffers = router.get_tty_buffers(self.tty_id).ok_or(())?;
+      let throttled = match router.get_tty(self.tty_id) {
+        Some(tty) => tty.try_read().map(|t| t.is_throttled()).unwrap_or(false),
+        None => false,
+      };
+      (buffers, throttled)
+    };
+    let bytes_written = buffers.write(buffer);
+    // A writer that has filled a throttled TTY gives up the rest of its time,
+    // rather than immediately retrying
+    if throttled && bytes_written < buffer.len() {
+      process::yield_coop();
+    }
+    Ok(bytes_written)
+  }
+
+  fn ioctl(&self, _handle: LocalHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    let tty = super::get_router().read().get_tty(self.tty_id).ok_or(())?;
+    match command {
+      TCGETS => Ok(tty.read().get_flags()),
+      TCSETS => {
+        tty.write().set_flags(arg);
+        Ok(0)
       },
-      None => Err(()),
+      TIOCGPGRP => Ok(tty.read().get_foreground_group().map_or(0, |group| group.as_u32())),
+      TIOCSPGRP => {
+        let group = match arg {
+          0 => None,
+          _ => {
+            let group = ProcessID::new(arg);
+            if process::all_processes().group_members(group).is_empty() {
+              return Err(());
+            }
+            Some(group)
+          },
+        };
+        tty.write().set_foreground_group(group);
+        Ok(0)
+      },
+      TTY_GET_LOCK_KEYS => Ok(tty.read().get_lock_keys().get_flags()),
+      TTY_SET_LOCK_KEYS => {
+        let locks = LockKeys::from_flags(arg);
+        let mut tty = tty.write();
+        tty.set_lock_keys(locks);
+        if tty.is_active() {
+          keyboard::update_leds(locks.get_led_byte());
+        }
+        Ok(0)
+      },
+      TTY_SET_COMPLETIONS => {
+        let completions = crate::drivers::line::read_completions(arg)?;
+        tty.write().set_completions(completions);
+        Ok(0)
+      },
+      _ => Err(()),
     }
   }
 }
\ No newline at end of file
@@ -1,9 +1,55 @@
 use crate::drivers::keyboard::{KeyAction, codes::{KeyCode, US_LAYOUT}};
+use syscall::flags::{LOCK_CAPS, LOCK_NUM, LOCK_SCROLL};
+use syscall::signals;
+
+/// Caps, Num, and Scroll Lock. Each console keeps its own state, and the
+/// keyboard LEDs follow whichever console is active.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct LockKeys(u32);
+
+impl LockKeys {
+  pub const fn new() -> LockKeys {
+    LockKeys(0)
+  }
+
+  pub fn from_flags(flags: u32) -> LockKeys {
+    LockKeys(flags & (LOCK_CAPS | LOCK_NUM | LOCK_SCROLL))
+  }
+
+  pub fn get_flags(&self) -> u32 {
+    self.0
+  }
+
+  pub fn is_caps(&self) -> bool {
+    self.0 & LOCK_CAPS != 0
+  }
+
+  pub fn toggle(&mut self, flag: u32) {
+    self.0 ^= flag;
+  }
+
+  /// The LOCK_* flags are in the order the keyboard expects its LED bits
+  pub fn get_led_byte(&self) -> u8 {
+    self.0 as u8
+  }
+}
+
+fn lock_flag(code: KeyCode) -> Option<u32> {
+  match code {
+    KeyCode::Caps => Some(LOCK_CAPS),
+    KeyCode::NumLock => Some(LOCK_NUM),
+    KeyCode::ScrollLock => Some(LOCK_SCROLL),
+    _ => None,
+  }
+}
 
 pub struct KeyState {
   pub alt: bool,
   pub ctrl: bool,
   pub shift: bool,
+  /// Lock keys currently held down, so that typematic repeats do not toggle
+  /// them again
+  held_locks: u32,
 }
 
 impl KeyState {
@@ -12,10 +58,31 @@ impl KeyState {
       alt: false,
       ctrl: false,
       shift: false,
+      held_locks: 0,
     }
   }
 
-  pub fn process_key_action(&mut self, action: KeyAction, buffer: &mut [u8]) -> Option<usize> {
+  /// Returns None if the action is not for a lock key. Otherwise, returns the
+  /// lock flag to toggle, if the key has just been pressed.
+  pub fn process_lock_key(&mut self, action: KeyAction) -> Option<Option<u32>> {
+    match action {
+      KeyAction::Press(code) => {
+        let flag = lock_flag(code)?;
+        if self.held_locks & flag != 0 {
+          return Some(None);
+        }
+        self.held_locks |= flag;
+        Some(Some(flag))
+      },
+      KeyAction::Release(code) => {
+        let flag = lock_flag(code)?;
+        self.held_locks &= !flag;
+        Some(None)
+      },
+    }
+  }
+
+  pub fn process_key_action(&mut self, action: KeyAction, locks: LockKeys, buffer: &mut [u8]) -> Option<usize> {
     match action {
       KeyAction::Press(code) => {
         match code {
@@ -31,7 +98,7 @@ impl KeyState {
             self.shift = true;
             None
           },
-          _ => Some(self.key_code_to_ascii(code, buffer)),
+          _ => Some(self.key_code_to_ascii(code, locks, buffer)),
         }
       },
       KeyAction::Release(code) => {
@@ -46,7 +113,19 @@ impl KeyState {
     }
   }
 
-  pub fn key_code_to_ascii(&self, input: KeyCode, buffer: &mut [u8]) -> usize {
+  /// Signal requested by a key combination, like Ctrl+C
+  pub fn job_control_signal(&self, action: KeyAction) -> Option<u32> {
+    if !self.ctrl {
+      return None;
+    }
+    match action {
+      KeyAction::Press(KeyCode::C) => Some(signals::INT),
+      KeyAction::Press(KeyCode::Z) => Some(signals::TSTOP),
+      _ => None,
+    }
+  }
+
+  pub fn key_code_to_ascii(&self, input: KeyCode, locks: LockKeys, buffer: &mut [u8]) -> usize {
     match input {
       KeyCode::ArrowLeft => {
         buffer[0] = 0x1b;
@@ -62,7 +141,14 @@ impl KeyState {
         } else {
           (0, 0)
         };
-        buffer[0] = if self.shift {
+        // Caps Lock only affects letters, and Shift reverses it
+        let is_letter = normal.is_ascii_lowercase();
+        let upper = if is_letter {
+          self.shift != locks.is_caps()
+        } else {
+          self.shift
+        };
+        buffer[0] = if upper {
           shifted
         } else {
           normal
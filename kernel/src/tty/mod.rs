@@ -2,6 +2,7 @@ pub mod buffers;
 pub mod device;
 pub mod keyboard;
 pub mod router;
+pub mod screen;
 pub mod tty;
 
 use core::fmt::Write;
@@ -33,7 +34,10 @@ pub extern "C" fn ttys_process() {
     // Check each TTY buffer for new data that we need to process
     let router = get_router();
     match router.try_read() {
-      Some(r) => r.process_buffers(),
+      Some(r) => {
+        r.update_bells();
+        r.process_buffers();
+      },
       None => (),
     }
     yield_coop();
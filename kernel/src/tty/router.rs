@@ -1,12 +1,18 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use crate::drivers::keyboard::{KeyAction, codes::KeyCode};
+use crate::drivers::keyboard::{self, KeyAction, codes::KeyCode};
+use crate::process::id::ProcessID;
 use spin::RwLock;
 
 use super::buffers::TTYReadWriteBuffers;
-use super::keyboard::KeyState;
+use super::keyboard::{KeyState, LockKeys};
 use super::tty::TTY;
 
+/// Output is drawn in chunks of this size, redrawing the cursor once per chunk
+const OUTPUT_CHUNK_SIZE: usize = 64;
+/// Most bytes a throttled TTY will draw each time the TTY process runs
+const THROTTLED_BYTES_PER_PASS: usize = 128;
+
 /// Associates a TTY driver, containing internal screen state and the ability to
 /// write to the VGA buffer, with a device file that can be written and read by
 /// other processes.
@@ -77,6 +83,31 @@ impl TTYRouter {
     }
   }
 
+  pub fn get_tty(&self, index: usize) -> Option<Arc<RwLock<TTY>>> {
+    let set = self.tty_set.read();
+    set.get(index).map(|data| data.get_tty())
+  }
+
+  /// Stop any bells that have finished ringing
+  pub fn update_bells(&self) {
+    let now = crate::time::system::get_system_time().in_ms();
+    let set = self.tty_set.read();
+    for data in set.iter() {
+      if let Some(mut tty) = data.tty.try_write() {
+        tty.update_bell(now);
+      }
+    }
+  }
+
+  /// Find the TTY whose foreground group is `group`, if any
+  pub fn find_foreground_tty(&self, group: ProcessID) -> Option<Arc<RwLock<TTY>>> {
+    let set = self.tty_set.read();
+    let found = set.iter().find(|data| {
+      data.tty.read().get_foreground_group() == Some(group)
+    });
+    found.map(|data| data.get_tty())
+  }
+
   pub fn get_active_tty(&self) -> Option<Arc<RwLock<TTY>>> {
     let set = self.tty_set.read();
     let active = set.get(self.active_tty);
@@ -100,25 +131,51 @@ impl TTYRouter {
       let mut next = tty.write();
       next.set_active(true);
       unsafe { next.swap_in(); }
+      keyboard::update_leds(next.get_lock_keys().get_led_byte());
+    }
+  }
+
+  /// Flip one of the active TTY's lock keys, and light the LEDs to match
+  fn toggle_lock_key(&self, flag: u32) {
+    if let Some(tty) = self.get_active_tty() {
+      let mut tty = tty.write();
+      let mut locks = tty.get_lock_keys();
+      locks.toggle(flag);
+      tty.set_lock_keys(locks);
+      keyboard::update_leds(locks.get_led_byte());
     }
   }
 
-  pub fn send_key_action(&mut self, action: KeyAction) {
+  /// Route a key to the active TTY. Ctrl+C and Ctrl+Z are not passed on if
+  /// the TTY has a foreground group; instead, the group and the signal it
+  /// should receive are returned. The caller sends the signal once the router
+  /// is unlocked, since ending a process may close its TTY handles.
+  pub fn send_key_action(&mut self, action: KeyAction) -> Option<(ProcessID, u32)> {
     let mut buffer: [u8; 4] = [0; 4];
 
-    let output = self.key_state.process_key_action(action, &mut buffer);
+    if let Some(toggled) = self.key_state.process_lock_key(action) {
+      if let Some(flag) = toggled {
+        self.toggle_lock_key(flag);
+      }
+      return None;
+    }
+    let locks = match self.get_active_tty() {
+      Some(tty) => tty.read().get_lock_keys(),
+      None => LockKeys::new(),
+    };
+    let output = self.key_state.process_key_action(action, locks, &mut buffer);
     if let Some(len) = output {
       match action {
         KeyAction::Press(KeyCode::Num0) => {
           if self.key_state.alt {
             self.set_active_tty(0);
-            return;
+            return None;
           }
         },
         KeyAction::Press(KeyCode::Num1) => {
           if self.key_state.alt {
             self.set_active_tty(1);
-            return;
+            return None;
           }
         },
         _ => (),
@@ -127,35 +184,45 @@ impl TTYRouter {
       let tty_set = self.tty_set.read();
       if let Some(active) = tty_set.get(self.active_tty) {
         let mut tty = active.tty.write();
-        let data: &[u8] = &buffer[0..len];
-        for i in 0..len {
-          tty.handle_input(data[i]);
+        if let Some(sig) = self.key_state.job_control_signal(action) {
+          if let Some(group) = tty.get_foreground_group() {
+            return Some((group, sig));
+          }
         }
-        active.buffers.output_buffer.write(&data);
+        let mut ready = Vec::with_capacity(len);
+        tty.handle_input(&buffer[0..len], &mut ready);
+        active.buffers.output_buffer.write(&ready);
       }
     }
+    None
   }
 
   /// Iterate through all ring buffers, and send all available data to the
-  /// matching TTY device.
+  /// matching TTY device. Throttled TTYs only draw a limited amount of output
+  /// on each pass; the rest waits in the ring buffer for the next one.
   pub fn process_buffers(&self) {
     let set = self.tty_set.read();
     for data in set.iter() {
       let buffers = data.get_buffers();
       match data.tty.try_write() {
         Some(mut tty) => {
-          let mut data: [u8; 4] = [0; 4];
+          if tty.is_flashing() {
+            continue;
+          }
+          let mut data: [u8; OUTPUT_CHUNK_SIZE] = [0; OUTPUT_CHUNK_SIZE];
           let mut to_read = buffers.input_buffer.available_bytes();
+          if tty.is_throttled() {
+            to_read = to_read.min(THROTTLED_BYTES_PER_PASS);
+          }
           while to_read > 0 {
-            let bytes_read = buffers.input_buffer.read(&mut data);
-            to_read = if bytes_read == data.len() {
+            let chunk = to_read.min(data.len());
+            let bytes_read = buffers.input_buffer.read(&mut data[..chunk]);
+            to_read = if bytes_read == chunk {
               to_read - bytes_read
             } else {
               0
             };
-            for i in 0..bytes_read {
-              tty.send_data(data[i]);
-            }
+            tty.send_bulk(&data[..bytes_read]);
           }
         },
         // If the tty is locked, we'll just get to it on the next call
@@ -0,0 +1,61 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use crate::drivers::driver::DeviceDriver;
+use crate::files::{cursor::SeekMethod, handle::LocalHandle};
+use spin::RwLock;
+use syscall::files::OpenFlags;
+
+/// DEV:\SCREEN captures the terminal currently on screen, so its exact
+/// contents can be saved with a bug report. Opening the device takes a
+/// snapshot, and reading returns it as the 80x25 character and color byte
+/// pairs of VGA text memory. A DOS box in the foreground is captured as it is
+/// drawn on its terminal; graphics modes are never displayed, so they do not
+/// appear. Each handle keeps its own snapshot, so reopen it to capture again.
+pub struct ScreenDevice {
+  snapshots: RwLock<BTreeMap<LocalHandle, Snapshot>>,
+}
+
+struct Snapshot {
+  cells: Vec<u8>,
+  cursor: usize,
+}
+
+impl ScreenDevice {
+  pub fn new() -> ScreenDevice {
+    ScreenDevice {
+      snapshots: RwLock::new(BTreeMap::new()),
+    }
+  }
+}
+
+impl DeviceDriver for ScreenDevice {
+  fn open(&self, handle: LocalHandle, _flags: OpenFlags) -> Result<(), ()> {
+    let tty = super::get_router().read().get_active_tty().ok_or(())?;
+    let cells = tty.read().snapshot();
+    self.snapshots.write().insert(handle, Snapshot { cells, cursor: 0 });
+    Ok(())
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    self.snapshots.write().remove(&handle);
+    Ok(())
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let mut snapshots = self.snapshots.write();
+    let snapshot = snapshots.get_mut(&handle).ok_or(())?;
+    let start = snapshot.cursor.min(snapshot.cells.len());
+    let remaining = &snapshot.cells[start..];
+    let length = remaining.len().min(buffer.len());
+    buffer[..length].copy_from_slice(&remaining[..length]);
+    snapshot.cursor = start + length;
+    Ok(length)
+  }
+
+  fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
+    let mut snapshots = self.snapshots.write();
+    let snapshot = snapshots.get_mut(&handle).ok_or(())?;
+    snapshot.cursor = offset.from_current_position(snapshot.cursor);
+    Ok(snapshot.cursor)
+  }
+}
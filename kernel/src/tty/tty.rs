@@ -1,10 +1,18 @@
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use crate::hardware::vga::text_mode::{TextMode};
+use crate::line_editor::{LineEditor, WordList};
 use crate::memory::address::VirtualAddress;
+use crate::process::id::ProcessID;
+use super::keyboard::LockKeys;
+use syscall::flags::{TTY_BELL_MUTE, TTY_BELL_VISUAL, TTY_CANONICAL, TTY_ECHO, TTY_THROTTLE};
 
 const BACK_BUFFER_SIZE: usize = 80 * 25 * 2;
 
+/// How long the speaker sounds, or the screen stays inverted, for each BEL
+const BELL_DURATION_MS: u64 = 100;
+const BELL_FREQUENCY: u32 = 750;
+
 #[derive(Copy, Clone)]
 pub enum ParseState {
   Ready,
@@ -16,10 +24,21 @@ pub enum ParseState {
 pub enum LineDiscipline {
   /// Send individual bytes directly to the TTY device, no output
   Raw,
-  /// Process one line at a time. Arrow keys and 
+  /// Process one line at a time, editing it in place until Enter is pressed
   Canonical,
 }
 
+/// What happens when a BEL character is written to the TTY
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum BellMode {
+  /// Sound the PC speaker
+  Audible,
+  /// Briefly invert the colors of the screen
+  Visual,
+  /// Ignore the bell
+  Muted,
+}
+
 /// Interface for a TTY. It parses ANSI-style terminal bytes and 
 pub struct TTY {
   /// Whether this TTY is currently active, determines whether it outputs new
@@ -27,10 +46,21 @@ pub struct TTY {
   is_active: bool,
   /// Line discipline determines how data is collected before passed to readers
   line_discipline: LineDiscipline,
+  /// Holds the line being typed in canonical mode
+  editor: LineEditor,
+  completions: WordList,
   /// Whether echoing is enabled, controled by ioctl commands
   echo: bool,
   /// Whether the cursor is currently visible
   show_cursor: bool,
+  bell_mode: BellMode,
+  /// Time, in ms, when the current bell should stop
+  bell_end: Option<u64>,
+  /// Whether output is rate limited, controlled by ioctl commands
+  throttle: bool,
+  /// Process group that receives signals typed at the keyboard
+  foreground_group: Option<ProcessID>,
+  lock_keys: LockKeys,
   /// Track the current parsing state
   parse_state: ParseState,
   arg_digits_written: usize,
@@ -50,8 +80,15 @@ impl TTY {
     TTY {
       is_active: false,
       line_discipline: LineDiscipline::Raw,
+      editor: LineEditor::new(),
+      completions: WordList::new(),
       echo: true,
       show_cursor: true,
+      bell_mode: BellMode::Audible,
+      bell_end: None,
+      throttle: false,
+      foreground_group: None,
+      lock_keys: LockKeys::new(),
       parse_state: ParseState::Ready,
       arg_digits_written: 0,
       csi_args: Vec::with_capacity(8),
@@ -64,6 +101,10 @@ impl TTY {
     self.is_active = active;
   }
 
+  pub fn is_active(&self) -> bool {
+    self.is_active
+  }
+
   pub fn send_data(&mut self, byte: u8) {
     let output = unsafe { self.process_character(byte) };
 
@@ -75,9 +116,161 @@ impl TTY {
     }
   }
 
-  pub fn handle_input(&mut self, byte: u8) {
+  /// Draw a run of output at once. The cursor is only redrawn at the end,
+  /// rather than after every character.
+  pub fn send_bulk(&mut self, bytes: &[u8]) {
+    let show_cursor = self.show_cursor;
+    if show_cursor {
+      self.text_buffer.disable_cursor();
+      self.show_cursor = false;
+    }
+    for byte in bytes.iter() {
+      let output = unsafe { self.process_character(*byte) };
+      if let Some(ch) = output {
+        self.text_buffer.write_byte(ch);
+      }
+    }
+    self.show_cursor = show_cursor;
+    if show_cursor {
+      self.text_buffer.invert_cursor();
+    }
+  }
+
+  /// Report the TTY_* flags describing this terminal's current behavior
+  pub fn get_flags(&self) -> u32 {
+    let mut flags = 0;
     if self.echo {
-      self.send_data(byte);
+      flags |= TTY_ECHO;
+    }
+    match self.bell_mode {
+      BellMode::Audible => (),
+      BellMode::Visual => flags |= TTY_BELL_VISUAL,
+      BellMode::Muted => flags |= TTY_BELL_MUTE,
+    }
+    if self.throttle {
+      flags |= TTY_THROTTLE;
+    }
+    if let LineDiscipline::Canonical = self.line_discipline {
+      flags |= TTY_CANONICAL;
+    }
+    flags
+  }
+
+  pub fn set_flags(&mut self, flags: u32) {
+    // Finish any bell in progress using the old mode, so it is undone properly
+    self.update_bell(u64::max_value());
+    self.echo = flags & TTY_ECHO != 0;
+    self.bell_mode = if flags & TTY_BELL_MUTE != 0 {
+      BellMode::Muted
+    } else if flags & TTY_BELL_VISUAL != 0 {
+      BellMode::Visual
+    } else {
+      BellMode::Audible
+    };
+    self.throttle = flags & TTY_THROTTLE != 0;
+    self.line_discipline = if flags & TTY_CANONICAL != 0 {
+      LineDiscipline::Canonical
+    } else {
+      LineDiscipline::Raw
+    };
+  }
+
+  pub fn set_completions(&mut self, completions: WordList) {
+    self.completions = completions;
+  }
+
+  pub fn get_foreground_group(&self) -> Option<ProcessID> {
+    self.foreground_group
+  }
+
+  pub fn set_foreground_group(&mut self, group: Option<ProcessID>) {
+    self.foreground_group = group;
+  }
+
+  pub fn get_lock_keys(&self) -> LockKeys {
+    self.lock_keys
+  }
+
+  pub fn set_lock_keys(&mut self, locks: LockKeys) {
+    self.lock_keys = locks;
+  }
+
+  pub fn is_throttled(&self) -> bool {
+    self.throttle
+  }
+
+  /// While the screen is inverted for a visual bell, output is held back so
+  /// that new characters are not drawn in the wrong colors
+  pub fn is_flashing(&self) -> bool {
+    self.bell_end.is_some() && self.bell_mode == BellMode::Visual
+  }
+
+  /// Only the active TTY can be heard or seen, so bells on background
+  /// terminals are dropped. A bell that rings while another is still going
+  /// just extends it.
+  fn ring_bell(&mut self) {
+    if !self.is_active || self.bell_mode == BellMode::Muted {
+      return;
+    }
+    let now = crate::time::system::get_system_time().in_ms();
+    if self.bell_end.is_none() {
+      match self.bell_mode {
+        BellMode::Audible => unsafe {
+          crate::devices::PIT.start_tone(BELL_FREQUENCY);
+        },
+        BellMode::Visual => self.text_buffer.invert_screen(),
+        BellMode::Muted => (),
+      }
+    }
+    self.bell_end = Some(now + BELL_DURATION_MS);
+  }
+
+  /// Stop a bell once its time is up. Called regularly by the TTY process.
+  pub fn update_bell(&mut self, now: u64) {
+    let end = match self.bell_end {
+      Some(end) => end,
+      None => return,
+    };
+    if now < end && self.is_active {
+      return;
+    }
+    match self.bell_mode {
+      BellMode::Audible => unsafe {
+        crate::devices::PIT.stop_tone();
+      },
+      BellMode::Visual => self.text_buffer.invert_screen(),
+      BellMode::Muted => (),
+    }
+    self.bell_end = None;
+  }
+
+  /// Take bytes typed at the keyboard, and append whatever readers should
+  /// receive now to `ready`: everything in raw mode, or each line once it is
+  /// finished in canonical mode
+  pub fn handle_input(&mut self, data: &[u8], ready: &mut Vec<u8>) {
+    match self.line_discipline {
+      LineDiscipline::Raw => {
+        if self.echo {
+          self.send_bulk(data);
+        }
+        ready.extend_from_slice(data);
+      },
+      LineDiscipline::Canonical => {
+        let mut echo = Vec::new();
+        for byte in data.iter() {
+          self.editor.input(*byte, &mut echo, &self.completions);
+        }
+        if self.echo {
+          self.send_bulk(&echo);
+        }
+        // The end of input cannot be passed through the ring buffer, so it
+        // reads as nothing
+        let mut chunk: [u8; 64] = [0; 64];
+        while self.editor.has_line() {
+          let length = self.editor.read(&mut chunk);
+          ready.extend_from_slice(&chunk[..length]);
+        }
+      },
     }
   }
 
@@ -99,6 +292,10 @@ impl TTY {
             self.parse_state = ParseState::EscapeStart;
             return None;
           },
+          0x07 => {
+            self.ring_bell();
+            return None;
+          },
           _ => return Some(byte),
         }
       },
@@ -212,6 +409,20 @@ impl TTY {
     }
   }
 
+  /// Show a screen drawn elsewhere, like a DOS box's text memory, in place of
+  /// the terminal's own output
+  pub fn draw_cells(&mut self, cells: &[u16]) {
+    self.text_buffer.write_cells(cells);
+  }
+
+  /// Capture what the terminal shows, whether it is on screen or in the back
+  /// buffer, as character and color byte pairs
+  pub fn snapshot(&self) -> Vec<u8> {
+    let mut cells = alloc::vec![0; BACK_BUFFER_SIZE];
+    self.text_buffer.read_cells(&mut cells);
+    cells
+  }
+
   pub fn force_background(&mut self) {
     let back_ptr = self.back_buffer.as_ptr();
     self.text_buffer.set_buffer_pointer(back_ptr as usize);
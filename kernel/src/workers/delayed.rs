@@ -0,0 +1,66 @@
+//! Delayed work runs a job once a number of milliseconds have passed, without
+//! a driver needing its own kernel process that sleeps in a loop. Jobs are held
+//! in a timer wheel, and a single timer process advances the wheel each tick
+//! and runs whatever has expired.
+//!
+//! Jobs run on the timer process itself, so they must be short and must not
+//! block: anything that waits on hardware should be handed to a worker pool
+//! from inside the job. The same process checks the worker pools for
+//! starvation each tick.
+
+use alloc::boxed::Box;
+use crate::collections::{TimerId, TimerWheel};
+use crate::process;
+use crate::time::system::{get_uptime, HUNDRED_NS_PER_TICK, MS_PER_TICK};
+use spin::Mutex;
+use super::{Job, WorkerError};
+
+/// Most timers that can be pending at once
+pub const MAX_PENDING_TIMERS: usize = 64;
+
+static TIMERS: Mutex<TimerWheel<Job>> = Mutex::new(TimerWheel::new(MAX_PENDING_TIMERS));
+
+fn ms_to_ticks(ms: usize) -> u64 {
+  ((ms + MS_PER_TICK - 1) / MS_PER_TICK) as u64
+}
+
+/// Run `f` on the timer process after `ms` milliseconds. The returned ID can
+/// be used to cancel the job before it runs.
+pub fn schedule_delayed_work<F: FnOnce() + Send + 'static>(f: F, ms: usize) -> Result<TimerId, WorkerError> {
+  TIMERS.lock()
+    .schedule(ms_to_ticks(ms), Box::new(f))
+    .map_err(|_| WorkerError::QueueFull)
+}
+
+/// Cancel a delayed job. Returns false if it has already run.
+pub fn cancel_delayed_work(id: TimerId) -> bool {
+  TIMERS.lock().cancel(id).is_some()
+}
+
+/// Start the kernel thread that runs delayed work
+pub fn start() {
+  process::spawn_kthread(run_timers, "TIMERS").expect("Failed to start timer thread");
+}
+
+#[inline(never)]
+extern "C" fn run_timers() {
+  loop {
+    let now = get_uptime().0 / HUNDRED_NS_PER_TICK;
+    loop {
+      // The lock is released before any job runs, so jobs are free to
+      // schedule more work
+      let expired = {
+        let mut timers = TIMERS.lock();
+        if timers.get_current_tick() >= now {
+          break;
+        }
+        timers.advance()
+      };
+      for job in expired {
+        job();
+      }
+    }
+    super::check_starvation();
+    process::sleep(MS_PER_TICK);
+  }
+}
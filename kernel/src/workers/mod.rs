@@ -0,0 +1,130 @@
+//! Kernel worker pools allow blocking filesystem and device work to be handed
+//! off to a small group of kernel threads. Each pool has its own queue, so a
+//! slow or stalled device only ties up the workers that serve it.
+
+pub mod delayed;
+pub mod pool;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::process::{self, id::ProcessID};
+use crate::time;
+use spin::RwLock;
+use syscall::messages::WORKER_POOL_STARVED;
+
+pub use delayed::{cancel_delayed_work, schedule_delayed_work};
+pub use pool::{Job, PoolStats, WorkerError, WorkerPool};
+
+/// Name of the pool used to service disk drives
+pub const DISK_POOL: &str = "DISK";
+
+static POOLS: RwLock<Vec<Arc<WorkerPool>>> = RwLock::new(Vec::new());
+
+fn current_time_ms() -> u64 {
+  time::system::get_system_time().in_ms()
+}
+
+/// Create a new pool and start `worker_count` kernel threads to service it.
+/// Workers that cannot be started are skipped, leaving the pool smaller.
+pub fn create_pool(name: &'static str, worker_count: usize, max_depth: usize) -> Arc<WorkerPool> {
+  let pool = Arc::new(WorkerPool::new(name, max_depth));
+  POOLS.write().push(Arc::clone(&pool));
+  for _ in 0..worker_count {
+    // Workers look up their pool when they first run, which is after this
+    // returns, so membership can be recorded once the thread exists
+    if let Ok(pid) = process::spawn_kthread(run_worker, name) {
+      pool.add_member(pid);
+    }
+  }
+  pool
+}
+
+pub fn get_pool(name: &str) -> Option<Arc<WorkerPool>> {
+  let pools = POOLS.read();
+  for pool in pools.iter() {
+    if pool.get_name() == name {
+      return Some(Arc::clone(pool));
+    }
+  }
+  None
+}
+
+fn get_pool_for_worker(pid: ProcessID) -> Option<Arc<WorkerPool>> {
+  let pools = POOLS.read();
+  for pool in pools.iter() {
+    if pool.has_member(pid) {
+      return Some(Arc::clone(pool));
+    }
+  }
+  None
+}
+
+/// Check whether a process is a worker in the named pool. Work that a worker
+/// submits to its own pool and then waits on may never be picked up, so
+/// callers use this to run such work directly instead.
+pub fn is_member(name: &str, pid: ProcessID) -> bool {
+  match get_pool(name) {
+    Some(pool) => pool.has_member(pid),
+    None => false,
+  }
+}
+
+/// Queue a job on the named pool
+pub fn submit(name: &str, job: Job) -> Result<(), WorkerError> {
+  let pool = get_pool(name).ok_or(WorkerError::NoSuchPool)?;
+  pool.submit(job, current_time_ms())
+}
+
+/// Convenience wrapper that boxes a closure before submitting it
+pub fn submit_fn<F: FnOnce() + Send + 'static>(name: &str, f: F) -> Result<(), WorkerError> {
+  submit(name, Box::new(f))
+}
+
+/// Check every pool for jobs that have waited too long, and log a warning for
+/// each starved pool. Called from the timer process every tick, so a pool is
+/// reported even when all of its workers are stuck inside jobs.
+pub fn check_starvation() {
+  let now = current_time_ms();
+  let pools = POOLS.read();
+  for pool in pools.iter() {
+    if let Some(waited) = pool.check_starvation(now) {
+      let stats = pool.get_stats(now);
+      crate::messages::console(WORKER_POOL_STARVED, &[
+        &pool.get_name(),
+        &waited,
+        &stats.queued,
+        &(stats.workers - stats.idle),
+        &stats.workers,
+      ]);
+    }
+  }
+}
+
+/// Entry point for every worker process. The worker looks up the pool it
+/// belongs to, and then runs jobs until the queue is empty, at which point it
+/// stops itself until more work is submitted.
+#[inline(never)]
+pub extern "C" fn run_worker() {
+  let current = match process::current_process() {
+    Some(current) => current,
+    None => return,
+  };
+  let pool = match get_pool_for_worker(current.get_id()) {
+    Some(pool) => pool,
+    None => {
+      process::exit(0);
+      return;
+    },
+  };
+
+  loop {
+    match pool.take_job(&current) {
+      Some(job) => {
+        job();
+        pool.mark_completed();
+      },
+      None => process::yield_coop(),
+    }
+  }
+}
@@ -0,0 +1,179 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use crate::interrupts;
+use crate::process::{id::ProcessID, process_state::ProcessState, send_signal};
+use spin::{Mutex, RwLock};
+
+/// A unit of work that can be run to completion on any worker in a pool.
+pub type Job = Box<dyn FnOnce() + Send>;
+
+/// If a queued job has waited longer than this without being picked up, the
+/// pool is considered starved and a warning is emitted.
+pub const STARVATION_THRESHOLD_MS: u64 = 2000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WorkerError {
+  /// The pool already has `max_depth` jobs waiting to be run
+  QueueFull,
+  /// The requested pool does not exist
+  NoSuchPool,
+}
+
+struct QueuedJob {
+  job: Job,
+  /// System time, in ms, when the job was submitted
+  queued_at: u64,
+}
+
+/// Snapshot of a pool's state, useful for diagnostics
+#[derive(Copy, Clone, Debug)]
+pub struct PoolStats {
+  pub workers: usize,
+  pub idle: usize,
+  pub queued: usize,
+  pub completed: usize,
+  pub oldest_wait_ms: u64,
+}
+
+/**
+ * A WorkerPool is a named group of kernel threads that pull jobs off of a
+ * shared queue. Blocking operations (like waiting on a disk controller) can
+ * be pushed to a pool so that one slow drive does not serialize work for every
+ * other device.
+ * The queue is bounded; once `max_depth` jobs are waiting, submission fails
+ * rather than letting callers pile up unbounded work.
+ */
+pub struct WorkerPool {
+  name: &'static str,
+  max_depth: usize,
+  queue: Mutex<VecDeque<QueuedJob>>,
+  /// Every process that belongs to this pool
+  members: RwLock<Vec<ProcessID>>,
+  /// Members that are currently stopped, waiting for work
+  idle: Mutex<Vec<ProcessID>>,
+  completed: RwLock<usize>,
+  /// Set once a starvation warning has been emitted, so that the console is
+  /// not flooded. It is cleared when the queue drains.
+  starvation_reported: RwLock<bool>,
+}
+
+impl WorkerPool {
+  pub fn new(name: &'static str, max_depth: usize) -> WorkerPool {
+    WorkerPool {
+      name,
+      max_depth,
+      queue: Mutex::new(VecDeque::with_capacity(max_depth)),
+      members: RwLock::new(Vec::new()),
+      idle: Mutex::new(Vec::new()),
+      completed: RwLock::new(0),
+      starvation_reported: RwLock::new(false),
+    }
+  }
+
+  pub fn get_name(&self) -> &'static str {
+    self.name
+  }
+
+  pub fn add_member(&self, pid: ProcessID) {
+    self.members.write().push(pid);
+  }
+
+  pub fn has_member(&self, pid: ProcessID) -> bool {
+    self.members.read().contains(&pid)
+  }
+
+  pub fn get_members(&self) -> Vec<ProcessID> {
+    self.members.read().clone()
+  }
+
+  /// Run `f` with the queue locked. Interrupts stay off while it is held, so
+  /// a worker that has stopped itself can never be switched out before it
+  /// releases the lock.
+  fn with_queue<R, F: FnOnce(&mut VecDeque<QueuedJob>) -> R>(&self, f: F) -> R {
+    let int_reenable = interrupts::is_interrupt_enabled();
+    interrupts::cli();
+    let result = f(&mut self.queue.lock());
+    if int_reenable {
+      interrupts::sti();
+    }
+    result
+  }
+
+  /// Place a job at the end of the queue, and wake an idle worker if one is
+  /// available. If all workers are busy, the job will be picked up as soon as
+  /// one of them finishes its current task.
+  pub fn submit(&self, job: Job, now: u64) -> Result<(), WorkerError> {
+    let waiting = self.with_queue(|queue| {
+      if queue.len() >= self.max_depth {
+        return Err(WorkerError::QueueFull);
+      }
+      queue.push_back(QueuedJob {
+        job,
+        queued_at: now,
+      });
+      Ok(self.idle.lock().pop())
+    })?;
+    // A worker only goes on the idle list once it has already stopped, so
+    // this always finds it stopped and wakes it
+    if let Some(pid) = waiting {
+      send_signal(pid, syscall::signals::CONTINUE);
+    }
+    Ok(())
+  }
+
+  /// Remove the next job from the queue. If there is nothing to do, the worker
+  /// joins the idle list and is stopped in a single step under the queue
+  /// lock, so a concurrent submit either leaves a job for it to find or sees
+  /// it on the idle list after it has stopped. The caller yields after a None
+  /// result, and runs again once a submit continues it.
+  pub fn take_job(&self, worker: &ProcessState) -> Option<Job> {
+    self.with_queue(|queue| match queue.pop_front() {
+      Some(queued) => Some(queued.job),
+      None => {
+        *self.starvation_reported.write() = false;
+        self.idle.lock().push(worker.get_id());
+        worker.send_signal(syscall::signals::STOP);
+        None
+      },
+    })
+  }
+
+  pub fn mark_completed(&self) {
+    *self.completed.write() += 1;
+  }
+
+  /// Determine how long the oldest queued job has been waiting. If it exceeds
+  /// the starvation threshold and this has not been reported yet, returns
+  /// the wait time so that the caller can emit a warning.
+  pub fn check_starvation(&self, now: u64) -> Option<u64> {
+    let oldest = self.with_queue(|queue| queue.front().map(|queued| queued.queued_at))?;
+    let waited = now.saturating_sub(oldest);
+    if waited < STARVATION_THRESHOLD_MS {
+      return None;
+    }
+    let mut reported = self.starvation_reported.write();
+    if *reported {
+      return None;
+    }
+    *reported = true;
+    Some(waited)
+  }
+
+  pub fn get_stats(&self, now: u64) -> PoolStats {
+    let (queued, oldest_wait_ms, idle) = self.with_queue(|queue| {
+      let oldest_wait_ms = match queue.front() {
+        Some(queued) => now.saturating_sub(queued.queued_at),
+        None => 0,
+      };
+      (queue.len(), oldest_wait_ms, self.idle.lock().len())
+    });
+    PoolStats {
+      workers: self.members.read().len(),
+      idle,
+      queued,
+      completed: *self.completed.read(),
+      oldest_wait_ms,
+    }
+  }
+}
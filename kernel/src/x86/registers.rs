@@ -21,4 +21,50 @@ pub fn enable_paging() {
           "intel", "volatile"
     );
   }
-}
\ No newline at end of file
+}
+
+pub fn get_cr0() -> u32 {
+  let cr0: u32;
+  unsafe {
+    llvm_asm!("mov $0, cr0" : "=r"(cr0) : : : "intel", "volatile");
+  }
+  cr0
+}
+
+pub fn set_cr0(value: u32) {
+  unsafe {
+    llvm_asm!("mov cr0, $0" : : "r"(value) : : "intel", "volatile");
+  }
+}
+
+/// CR4 only exists on the Pentium and later, so callers must check for a
+/// feature that implies it first
+pub fn get_cr4() -> u32 {
+  let cr4: u32;
+  unsafe {
+    llvm_asm!("mov $0, cr4" : "=r"(cr4) : : : "intel", "volatile");
+  }
+  cr4
+}
+
+pub fn set_cr4(value: u32) {
+  unsafe {
+    llvm_asm!("mov cr4, $0" : : "r"(value) : : "intel", "volatile");
+  }
+}
+
+/// Model-specific registers only exist when CPUID reports them, which every
+/// processor with a local APIC does
+pub fn read_msr(msr: u32) -> u64 {
+  let (low, high): (u32, u32);
+  unsafe {
+    llvm_asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) : : "intel", "volatile");
+  }
+  ((high as u64) << 32) | low as u64
+}
+
+pub fn write_msr(msr: u32, value: u64) {
+  unsafe {
+    llvm_asm!("wrmsr" : : "{ecx}"(msr), "{eax}"(value as u32), "{edx}"((value >> 32) as u32) : : "intel", "volatile");
+  }
+}
@@ -0,0 +1,17 @@
+//! XMODEM lets files be pushed to a machine over a serial cable, so that new
+//! programs can be tried on real hardware without writing a floppy each time.
+//! The sender (a terminal program on another computer) waits until the
+//! receiver asks it to start, then sends the file in numbered blocks, each of
+//! which the receiver acknowledges before the next is sent.
+//!
+//! The original protocol pads the last block with 0x1a, and the padding is
+//! written to the file along with everything else. Executables are unaffected
+//! by the extra bytes at their end.
+
+pub mod receiver;
+
+#[cfg(not(test))]
+pub mod service;
+
+#[cfg(not(test))]
+pub use service::receive_file;
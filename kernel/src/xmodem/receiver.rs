@@ -0,0 +1,269 @@
+use alloc::vec::Vec;
+use crate::checksum::{crc16, sum8};
+
+pub const SOH: u8 = 0x01;
+pub const STX: u8 = 0x02;
+pub const EOT: u8 = 0x04;
+pub const ACK: u8 = 0x06;
+pub const NAK: u8 = 0x15;
+pub const CAN: u8 = 0x18;
+/// Sent instead of NAK to ask for the CRC variant of the protocol
+pub const CRC_REQUEST: u8 = b'C';
+
+/// How each packet's data is verified
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CheckMode {
+  /// Original XMODEM: one byte, the sum of the data
+  Checksum,
+  /// Two byte CRC-16, high byte first
+  Crc,
+}
+
+impl CheckMode {
+  fn trailer_length(&self) -> usize {
+    match self {
+      CheckMode::Checksum => 1,
+      CheckMode::Crc => 2,
+    }
+  }
+}
+
+/// Result of feeding a byte to the receiver
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Step {
+  /// The current packet is not complete yet
+  Incomplete,
+  /// A new block arrived, and can be read with `get_block`
+  Block,
+  /// The sender repeated the last block, because it missed the ACK
+  Duplicate,
+  /// The packet failed its check, and should be sent again
+  Corrupt,
+  /// The sender has no more data
+  Finished,
+  /// The sender gave up, or sent a block out of sequence
+  Cancelled,
+}
+
+impl Step {
+  /// The byte to send back to the sender, if any
+  pub fn response(&self) -> Option<u8> {
+    match self {
+      Step::Block | Step::Duplicate | Step::Finished => Some(ACK),
+      Step::Corrupt => Some(NAK),
+      Step::Incomplete | Step::Cancelled => None,
+    }
+  }
+}
+
+/**
+ * State machine for the receiving end of an XMODEM transfer. Bytes from the
+ * serial port are fed in one at a time; timeouts and retries are left to the
+ * caller, which only needs to know what to send back after each step.
+ * Both 128-byte (SOH) and 1K (STX) blocks are accepted.
+ */
+pub struct Receiver {
+  mode: CheckMode,
+  expected_block: u8,
+  packet: Vec<u8>,
+  /// Length of the packet being collected, known once the header arrives
+  packet_length: usize,
+  /// End of the data in the last accepted block
+  block_end: usize,
+  /// Set after a single CAN, since a cancel is only honored when doubled
+  cancel_pending: bool,
+}
+
+impl Receiver {
+  pub fn new(mode: CheckMode) -> Receiver {
+    Receiver {
+      mode,
+      expected_block: 1,
+      packet: Vec::with_capacity(1024 + 5),
+      packet_length: 0,
+      block_end: 3,
+      cancel_pending: false,
+    }
+  }
+
+  pub fn get_mode(&self) -> CheckMode {
+    self.mode
+  }
+
+  /// Senders pick the check from the first byte the receiver sends. Receivers
+  /// ask for CRC first, and fall back to checksums if the sender never
+  /// answers.
+  pub fn set_mode(&mut self, mode: CheckMode) {
+    self.mode = mode;
+  }
+
+  /// The byte that asks the sender to start
+  pub fn start_byte(&self) -> u8 {
+    match self.mode {
+      CheckMode::Checksum => NAK,
+      CheckMode::Crc => CRC_REQUEST,
+    }
+  }
+
+  /// Discard a partially received packet, after the line went quiet
+  pub fn timeout(&mut self) {
+    self.packet.clear();
+    self.packet_length = 0;
+  }
+
+  /// Data of the block most recently returned as Step::Block. It is only
+  /// valid until the next byte is pushed.
+  pub fn get_block(&self) -> &[u8] {
+    &self.packet[3..self.block_end]
+  }
+
+  pub fn push(&mut self, byte: u8) -> Step {
+    if self.packet_length == 0 {
+      return self.push_header(byte);
+    }
+    self.packet.push(byte);
+    if self.packet.len() < self.packet_length {
+      return Step::Incomplete;
+    }
+    self.finish_packet()
+  }
+
+  fn push_header(&mut self, byte: u8) -> Step {
+    if byte == CAN {
+      if self.cancel_pending {
+        return Step::Cancelled;
+      }
+      self.cancel_pending = true;
+      return Step::Incomplete;
+    }
+    self.cancel_pending = false;
+    let data_length = match byte {
+      SOH => 128,
+      STX => 1024,
+      EOT => return Step::Finished,
+      // Line noise between packets is ignored
+      _ => return Step::Incomplete,
+    };
+    self.packet.clear();
+    self.block_end = 3;
+    self.packet.push(byte);
+    self.packet_length = 3 + data_length + self.mode.trailer_length();
+    Step::Incomplete
+  }
+
+  fn finish_packet(&mut self) -> Step {
+    // The next byte starts a new packet, whatever happens to this one
+    let length = self.packet_length;
+    self.packet_length = 0;
+    let block = self.packet[1];
+    if block != !self.packet[2] {
+      return Step::Corrupt;
+    }
+    let data = &self.packet[3..(length - self.mode.trailer_length())];
+    let valid = match self.mode {
+      CheckMode::Checksum => sum8(data) == self.packet[length - 1],
+      CheckMode::Crc => {
+        let expected = ((self.packet[length - 2] as u16) << 8) | (self.packet[length - 1] as u16);
+        crc16(data) == expected
+      },
+    };
+    if !valid {
+      return Step::Corrupt;
+    }
+    if block == self.expected_block.wrapping_sub(1) {
+      return Step::Duplicate;
+    }
+    if block != self.expected_block {
+      return Step::Cancelled;
+    }
+    self.expected_block = self.expected_block.wrapping_add(1);
+    self.block_end = length - self.mode.trailer_length();
+    Step::Block
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::*;
+
+  fn packet(block: u8, data: &[u8], mode: CheckMode) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.push(if data.len() == 1024 { STX } else { SOH });
+    bytes.push(block);
+    bytes.push(!block);
+    bytes.extend_from_slice(data);
+    match mode {
+      CheckMode::Checksum => bytes.push(sum8(data)),
+      CheckMode::Crc => {
+        let crc = crc16(data);
+        bytes.push((crc >> 8) as u8);
+        bytes.push(crc as u8);
+      },
+    }
+    bytes
+  }
+
+  fn feed(receiver: &mut Receiver, bytes: &[u8]) -> Step {
+    let mut step = Step::Incomplete;
+    for byte in bytes {
+      step = receiver.push(*byte);
+    }
+    step
+  }
+
+  #[test]
+  fn crc_matches_reference() {
+    assert_eq!(crc16(b"123456789"), 0x31c3);
+  }
+
+  #[test]
+  fn receives_blocks_in_order() {
+    let mut receiver = Receiver::new(CheckMode::Crc);
+    assert_eq!(receiver.start_byte(), CRC_REQUEST);
+    let first = [0x41; 128];
+    assert_eq!(feed(&mut receiver, &packet(1, &first, CheckMode::Crc)), Step::Block);
+    assert_eq!(receiver.get_block(), &first[..]);
+
+    let second = [0x42; 1024];
+    assert_eq!(feed(&mut receiver, &packet(2, &second, CheckMode::Crc)), Step::Block);
+    assert_eq!(receiver.get_block().len(), 1024);
+
+    assert_eq!(receiver.push(EOT), Step::Finished);
+  }
+
+  #[test]
+  fn checksum_mode() {
+    let mut receiver = Receiver::new(CheckMode::Checksum);
+    assert_eq!(receiver.start_byte(), NAK);
+    let data = [7; 128];
+    assert_eq!(feed(&mut receiver, &packet(1, &data, CheckMode::Checksum)), Step::Block);
+  }
+
+  #[test]
+  fn rejects_corrupt_packets() {
+    let mut receiver = Receiver::new(CheckMode::Crc);
+    let mut bytes = packet(1, &[1; 128], CheckMode::Crc);
+    bytes[10] ^= 0xff;
+    let step = feed(&mut receiver, &bytes);
+    assert_eq!(step, Step::Corrupt);
+    assert_eq!(step.response(), Some(NAK));
+    // The resent packet is still accepted as block 1
+    assert_eq!(feed(&mut receiver, &packet(1, &[1; 128], CheckMode::Crc)), Step::Block);
+  }
+
+  #[test]
+  fn duplicates_and_sequence_errors() {
+    let mut receiver = Receiver::new(CheckMode::Crc);
+    assert_eq!(feed(&mut receiver, &packet(1, &[1; 128], CheckMode::Crc)), Step::Block);
+    assert_eq!(feed(&mut receiver, &packet(1, &[1; 128], CheckMode::Crc)), Step::Duplicate);
+    assert_eq!(feed(&mut receiver, &packet(3, &[3; 128], CheckMode::Crc)), Step::Cancelled);
+  }
+
+  #[test]
+  fn cancel_requires_two_bytes() {
+    let mut receiver = Receiver::new(CheckMode::Crc);
+    assert_eq!(receiver.push(CAN), Step::Incomplete);
+    assert_eq!(receiver.push(CAN), Step::Cancelled);
+  }
+}
@@ -0,0 +1,138 @@
+use crate::process;
+use crate::syscalls::file;
+use crate::time;
+use super::receiver::{CheckMode, Receiver, Step, CAN, NAK};
+use syscall::files::{OPEN_CREATE, OPEN_NONBLOCK, OPEN_READ_WRITE, OPEN_TRUNCATE, OPEN_WRITE};
+use syscall::result::SystemError;
+
+/// How long to wait for the sender to answer each request to start
+const START_INTERVAL_MS: u64 = 3000;
+/// Requests for CRC sent before falling back to plain checksums
+const CRC_ATTEMPTS: usize = 3;
+/// Requests sent in total before giving up on the sender
+const START_ATTEMPTS: usize = 10;
+/// Longest pause allowed once the transfer has begun
+const PACKET_TIMEOUT_MS: u64 = 1000;
+/// Consecutive bad packets or timeouts tolerated before cancelling
+const MAX_ERRORS: usize = 10;
+/// How long to sleep when the port has no data
+const POLL_INTERVAL_MS: usize = 10;
+
+fn now_ms() -> u64 {
+  time::system::get_uptime().in_ms()
+}
+
+fn send_byte(port: u32, byte: u8) {
+  let _ = unsafe { file::write(port, &byte as *const u8, 1) };
+}
+
+fn read_byte(port: u32) -> Option<u8> {
+  let mut byte: u8 = 0;
+  match unsafe { file::read(port, &mut byte as *mut u8, 1) } {
+    Ok(1) => Some(byte),
+    _ => None,
+  }
+}
+
+fn cancel(port: u32) {
+  send_byte(port, CAN);
+  send_byte(port, CAN);
+}
+
+/// Receive a file over a serial port, writing it to `dest_path`. The port is
+/// a device path like DEV:\COM1. Any existing file at the destination is
+/// replaced. Returns the number of bytes written.
+pub fn receive_file(port_path: &str, dest_path: &str) -> Result<u32, SystemError> {
+  let port = file::open_path(port_path, OPEN_READ_WRITE | OPEN_NONBLOCK)?;
+  let dest = match file::open_path(dest_path, OPEN_WRITE | OPEN_CREATE | OPEN_TRUNCATE) {
+    Ok(handle) => handle,
+    Err(e) => {
+      let _ = file::close(port);
+      return Err(e);
+    },
+  };
+  let result = run_transfer(port, dest);
+  let _ = file::close(dest);
+  let _ = file::close(port);
+  result
+}
+
+fn run_transfer(port: u32, dest: u32) -> Result<u32, SystemError> {
+  let mut receiver = Receiver::new(CheckMode::Crc);
+  let mut started = false;
+  let mut start_attempts = 1;
+  let mut errors = 0;
+  let mut written: u32 = 0;
+
+  send_byte(port, receiver.start_byte());
+  let mut last_activity = now_ms();
+  loop {
+    let byte = match read_byte(port) {
+      Some(byte) => byte,
+      None => {
+        let idle = now_ms() - last_activity;
+        if !started && idle >= START_INTERVAL_MS {
+          if start_attempts >= START_ATTEMPTS {
+            return Err(SystemError::IOError);
+          }
+          if start_attempts == CRC_ATTEMPTS {
+            receiver.set_mode(CheckMode::Checksum);
+          }
+          start_attempts += 1;
+          receiver.timeout();
+          send_byte(port, receiver.start_byte());
+          last_activity = now_ms();
+        } else if started && idle >= PACKET_TIMEOUT_MS {
+          errors += 1;
+          if errors >= MAX_ERRORS {
+            cancel(port);
+            return Err(SystemError::IOError);
+          }
+          receiver.timeout();
+          send_byte(port, NAK);
+          last_activity = now_ms();
+        }
+        process::sleep(POLL_INTERVAL_MS);
+        continue;
+      },
+    };
+    last_activity = now_ms();
+
+    let step = receiver.push(byte);
+    match step {
+      Step::Incomplete => continue,
+      Step::Block => {
+        // The sender waits for the ACK, so nothing arrives while the block is
+        // being written out
+        let block = receiver.get_block();
+        let result = unsafe { file::write(dest, block.as_ptr(), block.len()) };
+        if let Err(e) = result {
+          cancel(port);
+          return Err(e);
+        }
+        written += block.len() as u32;
+        errors = 0;
+      },
+      Step::Duplicate => (),
+      Step::Corrupt => {
+        errors += 1;
+        if errors >= MAX_ERRORS {
+          cancel(port);
+          return Err(SystemError::IOError);
+        }
+      },
+      Step::Finished => (),
+      Step::Cancelled => {
+        cancel(port);
+        return Err(SystemError::IOError);
+      },
+    }
+    started = true;
+    if let Some(response) = step.response() {
+      send_byte(port, response);
+    }
+    if step == Step::Finished {
+      return Ok(written);
+    }
+  }
+}
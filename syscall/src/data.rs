@@ -1,3 +1,17 @@
+/// Longest path, including its drive name, that syscalls accept
+pub const MAX_PATH_LENGTH: usize = 256;
+/// Longest name of a drive, driver, locale, environment variable, or extended
+/// attribute that syscalls accept
+pub const MAX_NAME_LENGTH: usize = 64;
+/// Longest of any other string passed to a syscall, like program arguments or
+/// environment values
+pub const MAX_STRING_LENGTH: usize = 4096;
+
+/// Strings are passed to syscalls as a pointer and a length. The kernel copies
+/// them before use, and rejects any that run past the end of user memory, are
+/// longer than the limit for their kind, are not UTF-8, or contain a NUL
+/// anywhere but the last byte.
+#[derive(Copy, Clone)]
 #[repr(C, packed)]
 pub struct StringPtr {
   pub addr: usize,
@@ -15,9 +29,4 @@ impl StringPtr {
   pub fn get_starting_ptr(&self) -> *const u8 {
     self.addr as *const u8
   }
-
-  pub unsafe fn as_str(&self) -> &'static str {
-    let bytes = core::slice::from_raw_parts(self.get_starting_ptr(), self.length);
-    core::str::from_utf8_unchecked(bytes)
-  }
 }
\ No newline at end of file
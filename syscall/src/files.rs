@@ -3,13 +3,23 @@ pub enum DirEntryType {
   Empty = 0,
   Directory = 1,
   File = 2,
+  /// A device read and written as a stream of bytes, found on DEV:
+  CharacterDevice = 3,
+  /// A device addressed in fixed-size blocks, like a disk, found on DEV:
+  BlockDevice = 4,
 }
 
+/// Space reserved for a long file name, encoded as UTF-8
+pub const LONG_NAME_BYTES: usize = 256;
+
 pub struct DirEntryInfo {
   pub file_name: [u8; 8],
   pub file_ext: [u8; 3],
   pub entry_type: DirEntryType,
   pub byte_size: usize,
+  /// Long file name, if the filesystem stores one for this entry
+  pub long_name: [u8; LONG_NAME_BYTES],
+  pub long_name_length: usize,
 }
 
 impl DirEntryInfo {
@@ -19,7 +29,27 @@ impl DirEntryInfo {
       file_ext: [0x20, 0x20, 0x20],
       entry_type: DirEntryType::Empty,
       byte_size: 0,
+      long_name: [0; LONG_NAME_BYTES],
+      long_name_length: 0,
+    }
+  }
+
+  /// Store a long file name, truncating it at a character boundary if it does
+  /// not fit
+  pub fn set_long_name(&mut self, name: &str) {
+    let mut length = name.len().min(LONG_NAME_BYTES);
+    while !name.is_char_boundary(length) {
+      length -= 1;
     }
+    self.long_name[..length].copy_from_slice(&name.as_bytes()[..length]);
+    self.long_name_length = length;
+  }
+
+  pub fn get_long_name(&self) -> Option<&str> {
+    if self.long_name_length == 0 {
+      return None;
+    }
+    core::str::from_utf8(&self.long_name[..self.long_name_length]).ok()
   }
 
   pub fn is_empty(&self) -> bool {
@@ -28,4 +58,261 @@ impl DirEntryInfo {
       _ => false,
     }
   }
-}
\ No newline at end of file
+}
+
+/// Describes a batch of directory entries for read_dir_entries
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct DirEntryBatch {
+  /// Address of an array of DirEntryInfo to fill
+  pub entries: u32,
+  /// Number of entries the array can hold
+  pub capacity: u32,
+  /// Where to continue reading. Start at 0; after each call the kernel moves
+  /// it past the entries it returned.
+  pub cookie: u32,
+}
+
+/// Allow reading through the handle
+pub const OPEN_READ: u32 = 1;
+/// Allow writing through the handle
+pub const OPEN_WRITE: u32 = 2;
+pub const OPEN_READ_WRITE: u32 = OPEN_READ | OPEN_WRITE;
+/// Move to the end of the file before every write
+pub const OPEN_APPEND: u32 = 4;
+/// Create the file if it does not exist
+pub const OPEN_CREATE: u32 = 8;
+/// Discard the existing contents of the file when it is opened
+pub const OPEN_TRUNCATE: u32 = 0x10;
+/// Return immediately with whatever data is available, possibly none, instead
+/// of waiting for more to arrive
+pub const OPEN_NONBLOCK: u32 = 0x20;
+/// Write every change straight to the disk, instead of letting the filesystem
+/// hold small writes in memory until the handle is flushed or closed
+pub const OPEN_SYNC: u32 = 0x40;
+/// Fail with Busy if the device is already open, and keep any other handle
+/// from opening it until this one is closed. Only devices honor this flag.
+pub const OPEN_EXCLUSIVE: u32 = 0x80;
+
+/// Flags describing how an open handle may be used
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+  /// Decode flags passed to open. Neither access bit being set is treated as
+  /// read-write, so that programs written before access modes existed keep
+  /// working.
+  pub fn from_u32(flags: u32) -> OpenFlags {
+    if flags & OPEN_READ_WRITE == 0 {
+      OpenFlags(flags | OPEN_READ_WRITE)
+    } else {
+      OpenFlags(flags)
+    }
+  }
+
+  pub fn read_only() -> OpenFlags {
+    OpenFlags(OPEN_READ)
+  }
+
+  pub fn read_write() -> OpenFlags {
+    OpenFlags(OPEN_READ_WRITE)
+  }
+
+  pub fn as_u32(&self) -> u32 {
+    self.0
+  }
+
+  pub fn can_read(&self) -> bool {
+    self.0 & OPEN_READ != 0
+  }
+
+  pub fn can_write(&self) -> bool {
+    self.0 & OPEN_WRITE != 0
+  }
+
+  pub fn is_append(&self) -> bool {
+    self.0 & OPEN_APPEND != 0
+  }
+
+  pub fn should_create(&self) -> bool {
+    self.0 & OPEN_CREATE != 0
+  }
+
+  pub fn should_truncate(&self) -> bool {
+    self.0 & OPEN_TRUNCATE != 0
+  }
+
+  pub fn is_nonblocking(&self) -> bool {
+    self.0 & OPEN_NONBLOCK != 0
+  }
+
+  pub fn is_sync(&self) -> bool {
+    self.0 & OPEN_SYNC != 0
+  }
+
+  pub fn is_exclusive(&self) -> bool {
+    self.0 & OPEN_EXCLUSIVE != 0
+  }
+}
+
+/// Information about an open file, returned by stat and fstat
+pub struct FileStatus {
+  /// Index of the drive containing the file
+  pub drive: u32,
+  /// Identifier that stays the same for as long as the file exists, even if it
+  /// is renamed. Combined with the drive, it can be used to reopen the file.
+  /// A value of zero means the filesystem cannot provide a stable identifier.
+  pub file_id: u32,
+  pub entry_type: DirEntryType,
+  pub byte_size: usize,
+  /// Number of names referring to the file. Filesystems without hard links
+  /// always report one.
+  pub link_count: u32,
+}
+
+impl FileStatus {
+  pub fn empty() -> FileStatus {
+    FileStatus {
+      drive: 0,
+      file_id: 0,
+      entry_type: DirEntryType::Empty,
+      byte_size: 0,
+      link_count: 0,
+    }
+  }
+}
+
+/// Size of each record read from DEV:\MOUNTEV
+pub const MOUNT_EVENT_SIZE: usize = 10;
+
+/// Buffer for the FAT_GET_CLUSTERS ioctl
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ClusterMap {
+  /// Address of an array of u32 cluster numbers
+  pub addr: usize,
+  /// Number of entries the array can hold
+  pub count: usize,
+}
+
+/// Argument for the FAT_MOVE_CLUSTER ioctl
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ClusterMove {
+  /// Position of the cluster in the file's chain, starting from 0
+  pub index: u32,
+  /// Free cluster that should receive its contents
+  pub target: u32,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MountEventKind {
+  Mounted = 1,
+  Unmounted = 2,
+  /// The media in a removable drive was replaced. The drive must be remounted
+  /// before it can be used again.
+  MediaChanged = 3,
+}
+
+/// Notification that the set of available drives has changed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MountEvent {
+  pub kind: MountEventKind,
+  /// Drive name, padded with spaces
+  pub drive_name: [u8; 8],
+}
+
+impl MountEvent {
+  pub fn new(kind: MountEventKind, name: &str) -> MountEvent {
+    let mut drive_name = [0x20; 8];
+    for (dest, src) in drive_name.iter_mut().zip(name.as_bytes().iter()) {
+      *dest = *src;
+    }
+    MountEvent {
+      kind,
+      drive_name,
+    }
+  }
+
+  pub fn to_bytes(&self) -> [u8; MOUNT_EVENT_SIZE] {
+    let mut bytes = [0; MOUNT_EVENT_SIZE];
+    bytes[0] = self.kind as u8;
+    bytes[2..].copy_from_slice(&self.drive_name);
+    bytes
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Option<MountEvent> {
+    if bytes.len() < MOUNT_EVENT_SIZE {
+      return None;
+    }
+    let kind = match bytes[0] {
+      1 => MountEventKind::Mounted,
+      2 => MountEventKind::Unmounted,
+      3 => MountEventKind::MediaChanged,
+      _ => return None,
+    };
+    let mut drive_name = [0; 8];
+    drive_name.copy_from_slice(&bytes[2..MOUNT_EVENT_SIZE]);
+    Some(MountEvent {
+      kind,
+      drive_name,
+    })
+  }
+}
+
+/// Length of each name in a MountInfo record
+pub const MOUNT_NAME_LENGTH: usize = 8;
+
+/// The drive rejects writes
+pub const MOUNT_FLAG_READ_ONLY: u32 = 1;
+/// The drive cannot be unmounted
+pub const MOUNT_FLAG_PERMANENT: u32 = 2;
+/// Writes go straight to the device instead of being cached
+pub const MOUNT_FLAG_SYNC: u32 = 4;
+/// Reading a file does not update its access date
+pub const MOUNT_FLAG_NO_ATIME: u32 = 8;
+
+/// One entry of the mount table, filled in by get_mount_table. Names are
+/// padded with zeroes.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct MountInfo {
+  pub drive_name: [u8; MOUNT_NAME_LENGTH],
+  /// Filesystem driver reading the drive, like FAT or ISO9660
+  pub driver: [u8; MOUNT_NAME_LENGTH],
+  /// Device on the DEV: drive holding the data, empty for drives in memory
+  pub device: [u8; MOUNT_NAME_LENGTH],
+  pub flags: u32,
+  /// Files and directories currently open on the drive, across all processes
+  pub open_handles: u32,
+}
+
+impl MountInfo {
+  pub fn empty() -> MountInfo {
+    MountInfo {
+      drive_name: [0; MOUNT_NAME_LENGTH],
+      driver: [0; MOUNT_NAME_LENGTH],
+      device: [0; MOUNT_NAME_LENGTH],
+      flags: 0,
+      open_handles: 0,
+    }
+  }
+
+  /// Copy a name into one of the fields, cutting it off if it is too long
+  pub fn set_name(field: &mut [u8; MOUNT_NAME_LENGTH], name: &str) {
+    let length = name.len().min(MOUNT_NAME_LENGTH);
+    *field = [0; MOUNT_NAME_LENGTH];
+    field[..length].copy_from_slice(&name.as_bytes()[..length]);
+  }
+
+  /// Read one of the name fields back as a string
+  pub fn get_name(field: &[u8; MOUNT_NAME_LENGTH]) -> &str {
+    let length = field.iter().position(|byte| *byte == 0).unwrap_or(MOUNT_NAME_LENGTH);
+    core::str::from_utf8(&field[..length]).unwrap_or("")
+  }
+
+  pub fn is_read_only(&self) -> bool {
+    self.flags & MOUNT_FLAG_READ_ONLY != 0
+  }
+}
@@ -1 +1,131 @@
-pub const FIONREAD: u32 = 0x400419ff;
\ No newline at end of file
+pub const FIONREAD: u32 = 0x400419ff;
+
+/// Read the flags of a TTY, returned as the result of the ioctl
+pub const TCGETS: u32 = 0x5401;
+/// Replace the flags of a TTY with the ioctl argument
+pub const TCSETS: u32 = 0x5402;
+/// Get the foreground process group of a TTY, or 0 if it has none
+pub const TIOCGPGRP: u32 = 0x540f;
+/// Make the group in the ioctl argument the TTY's foreground group. Ctrl+C and
+/// Ctrl+Z send INT and TSTOP to every process in it. An argument of 0 clears
+/// the foreground group.
+pub const TIOCSPGRP: u32 = 0x5410;
+/// Read the Caps, Num, and Scroll Lock state of a TTY, as LOCK_* flags
+pub const TTY_GET_LOCK_KEYS: u32 = 0x5411;
+/// Replace the lock key state of a TTY with the LOCK_* flags in the argument.
+/// If the TTY is active, the keyboard LEDs are updated to match.
+pub const TTY_SET_LOCK_KEYS: u32 = 0x5412;
+/// Replace the words offered for Tab completion in canonical mode. The
+/// argument points to a StringPtr holding the words, one per line.
+pub const TTY_SET_COMPLETIONS: u32 = 0x5413;
+
+/// Echo keyboard input to the screen
+pub const TTY_ECHO: u32 = 1;
+/// Flash the screen instead of sounding the PC speaker on BEL
+pub const TTY_BELL_VISUAL: u32 = 2;
+/// Ignore BEL entirely. Takes priority over TTY_BELL_VISUAL.
+pub const TTY_BELL_MUTE: u32 = 4;
+/// Limit how much output a TTY draws at once, and slow down writers that keep
+/// its buffer full, so that other TTYs and keyboard input stay responsive
+pub const TTY_THROTTLE: u32 = 8;
+/// Collect input a line at a time, with editing keys and Tab completion,
+/// instead of passing each byte to readers as it is typed
+pub const TTY_CANONICAL: u32 = 16;
+
+/// Lock key flags, in the same order as the keyboard's LEDs
+pub const LOCK_SCROLL: u32 = 1;
+pub const LOCK_NUM: u32 = 2;
+pub const LOCK_CAPS: u32 = 4;
+
+/// Read the keyboard's repeat rate, in characters per second
+pub const KBD_GET_REPEAT_RATE: u32 = 0x4b01;
+/// Set the keyboard's repeat rate, in characters per second. The rate is
+/// rounded to the nearest one supported by the controller, between 2 and 30.
+pub const KBD_SET_REPEAT_RATE: u32 = 0x4b02;
+/// Read the delay before a held key starts repeating, in milliseconds
+pub const KBD_GET_REPEAT_DELAY: u32 = 0x4b03;
+/// Set the delay before a held key starts repeating, in milliseconds. The
+/// controller supports 250, 500, 750, and 1000; other values are rounded.
+pub const KBD_SET_REPEAT_DELAY: u32 = 0x4b04;
+/// Read the key produced by a scan code. The argument is the scan code, with
+/// 0x80 set for codes that follow an 0xE0 prefix. Returns 0 for no key.
+pub const KBD_GET_KEYMAP: u32 = 0x4b05;
+/// Remap a scan code. The low byte of the argument is the scan code, as for
+/// KBD_GET_KEYMAP, and the next byte is the key code it should produce. A key
+/// code of 0 restores the default.
+pub const KBD_SET_KEYMAP: u32 = 0x4b06;
+/// Restore every scan code to its default key
+pub const KBD_RESET_KEYMAP: u32 = 0x4b07;
+/// Returns 1 if sticky keys are enabled
+pub const KBD_GET_STICKY: u32 = 0x4b08;
+/// Enable sticky keys if the argument is nonzero. While enabled, tapping
+/// Shift, Ctrl, or Alt holds it down until the next key has been typed.
+pub const KBD_SET_STICKY: u32 = 0x4b09;
+
+/// Copy the full path an open file was opened by, including its drive, into
+/// the buffer described by the StringPtr the argument points to. Returns the
+/// length of the whole path, which may be longer than the buffer. Handles that
+/// were not opened by name, like pipes, return UnsupportedCommand.
+pub const F_GETPATH: u32 = 0x6601;
+
+/// Copy the numbers of the clusters holding an open FAT file or directory, in
+/// order, into the buffer described by the ClusterMap the argument points to.
+/// Returns the total number of clusters, which may be more than the buffer
+/// holds.
+pub const FAT_GET_CLUSTERS: u32 = 0x4601;
+/// Returns 1 if the cluster number in the argument is free, and 0 otherwise
+pub const FAT_IS_CLUSTER_FREE: u32 = 0x4602;
+/// Move one cluster of an open file to a free cluster, as described by the
+/// ClusterMove the argument points to. Moving the first cluster changes the
+/// file's identifier. The handle must be open for writing, and no other handle
+/// may have the file open or be able to write to the drive.
+pub const FAT_MOVE_CLUSTER: u32 = 0x4603;
+/// Write the size, first cluster, and timestamps of an open file back to its
+/// directory entry immediately, instead of waiting for the handle to close.
+/// Has the same requirements as FAT_MOVE_CLUSTER.
+pub const FAT_UPDATE_ENTRY: u32 = 0x4604;
+
+/// Samples written to DEV:\AUDIO are unsigned 8-bit values, centered on 0x80
+pub const AUDIO_FORMAT_U8: u32 = 1;
+/// Samples written to DEV:\AUDIO are signed 16-bit little-endian values
+pub const AUDIO_FORMAT_S16: u32 = 2;
+/// Combined with a sample format, frames hold a left and a right sample
+pub const AUDIO_FORMAT_STEREO: u32 = 0x100;
+/// Loudest volume a stream can be set to, where it plays unchanged
+pub const AUDIO_VOLUME_MAX: u32 = 100;
+
+/// Set the sample format of an audio handle to the AUDIO_FORMAT_* flags in the
+/// argument. Anything already written but not yet played is discarded.
+pub const AUDIO_SET_FORMAT: u32 = 0x4101;
+/// Set the sample rate of an audio handle, in Hz. Streams at any rate from
+/// 4000 to 48000 are resampled to the mixer's output rate.
+pub const AUDIO_SET_RATE: u32 = 0x4102;
+/// Returns the sample rate of an audio handle, in Hz
+pub const AUDIO_GET_RATE: u32 = 0x4103;
+/// Set the volume of an audio handle, from 0 to AUDIO_VOLUME_MAX. Larger values
+/// are clamped.
+pub const AUDIO_SET_VOLUME: u32 = 0x4104;
+/// Returns the volume of an audio handle
+pub const AUDIO_GET_VOLUME: u32 = 0x4105;
+/// Returns how many samples written to an audio handle have not been played
+pub const AUDIO_GET_QUEUED: u32 = 0x4106;
+
+/// Freeze the current contents of a snapshot-capable disk, like DEV:\RAM0.
+/// Later writes are kept aside in memory, and the frozen image is left
+/// untouched until the snapshot is committed. Fails if a snapshot is already
+/// active.
+pub const DISK_SNAPSHOT_CREATE: u32 = 0x4401;
+/// Throw away every write made since the snapshot was created, returning the
+/// disk to its frozen contents
+pub const DISK_SNAPSHOT_DISCARD: u32 = 0x4402;
+/// Write every change made since the snapshot was created to the disk itself,
+/// and end the snapshot
+pub const DISK_SNAPSHOT_COMMIT: u32 = 0x4403;
+/// Returns the number of blocks written since the snapshot was created. Fails
+/// if no snapshot is active.
+pub const DISK_SNAPSHOT_GET_CHANGED: u32 = 0x4404;
+
+/// Turn the machine off with `shutdown`
+pub const SHUTDOWN_POWEROFF: u32 = 1;
+/// Reset the machine with `shutdown`
+pub const SHUTDOWN_REBOOT: u32 = 2;
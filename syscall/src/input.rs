@@ -0,0 +1,38 @@
+//! Events produced by reading the KBD device. Each event is KEY_EVENT_SIZE
+//! bytes long: the action, the key code, and the time of the event in
+//! milliseconds since boot, as a little-endian u32.
+
+/// A key was pressed
+pub const KEY_PRESS: u8 = 1;
+/// A key was released
+pub const KEY_RELEASE: u8 = 2;
+/// A key that was already held down was repeated by the keyboard
+pub const KEY_REPEAT: u8 = 3;
+
+pub const KEY_EVENT_SIZE: usize = 6;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct KeyEvent {
+  pub action: u8,
+  pub key_code: u8,
+  /// Milliseconds since boot when the key was received
+  pub time: u32,
+}
+
+impl KeyEvent {
+  pub fn to_bytes(&self) -> [u8; KEY_EVENT_SIZE] {
+    let time = self.time.to_le_bytes();
+    [self.action, self.key_code, time[0], time[1], time[2], time[3]]
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Option<KeyEvent> {
+    if bytes.len() < KEY_EVENT_SIZE {
+      return None;
+    }
+    Some(KeyEvent {
+      action: bytes[0],
+      key_code: bytes[1],
+      time: u32::from_le_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]),
+    })
+  }
+}
@@ -6,8 +6,14 @@
 pub mod data;
 pub mod files;
 pub mod flags;
+pub mod input;
+pub mod memory;
+pub mod messages;
+pub mod process;
 pub mod result;
+pub mod shared;
 pub mod signals;
+pub mod testing;
 
 pub use data::*;
 
@@ -28,11 +34,106 @@ pub fn debug() -> u32 {
   syscall_inner(0xffff, 0, 0, 0)
 }
 
-pub fn open(path: &'static str) -> u32 {
+pub fn open(path: &str) -> u32 {
   let path_ptr = StringPtr::from_str(path);
   syscall_inner(0x10, &path_ptr as *const StringPtr as u32, 0, 0)
 }
 
+/**
+ * Open a path with a combination of the OPEN_* flags from `files`, which
+ * control whether the handle can read or write, and how the file is prepared
+ */
+pub fn open_with_flags(path: &str, flags: u32) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x10, &path_ptr as *const StringPtr as u32, flags, 0)
+}
+
+/**
+ * Close a file handle. The underlying file is only closed once no handle in
+ * any process refers to it.
+ */
+pub fn close(handle: u32) -> u32 {
+  syscall_inner(0x11, handle, 0, 0)
+}
+
+/**
+ * Create a new file at the path, or truncate it if it already exists, and
+ * return a handle to it
+ */
+pub fn create(path: &str) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x23, &path_ptr as *const StringPtr as u32, 0, 0)
+}
+
+pub fn unlink(path: &str) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x14, &path_ptr as *const StringPtr as u32, 0, 0)
+}
+
+/**
+ * Move a file or directory to a new path. Files can be moved between drives,
+ * in which case their contents are copied and the original is deleted.
+ */
+pub fn rename(old_path: &str, new_path: &str) -> u32 {
+  let old_ptr = StringPtr::from_str(old_path);
+  let new_ptr = StringPtr::from_str(new_path);
+  syscall_inner(0x27, &old_ptr as *const StringPtr as u32, &new_ptr as *const StringPtr as u32, 0)
+}
+
+/**
+ * Create an additional name for an existing file on the same drive
+ */
+pub fn link(existing_path: &str, new_path: &str) -> u32 {
+  let existing_ptr = StringPtr::from_str(existing_path);
+  let new_ptr = StringPtr::from_str(new_path);
+  syscall_inner(0x28, &existing_ptr as *const StringPtr as u32, &new_ptr as *const StringPtr as u32, 0)
+}
+
+/**
+ * Fill `status` with information about the file at a path
+ */
+pub fn stat(path: &str, status: &mut files::FileStatus) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x16, &path_ptr as *const StringPtr as u32, status as *mut files::FileStatus as u32, 0)
+}
+
+pub fn fstat(handle: u32, status: &mut files::FileStatus) -> u32 {
+  syscall_inner(0x17, handle, status as *mut files::FileStatus as u32, 0)
+}
+
+/**
+ * Reopen a file using the drive and file id reported by stat
+ */
+pub fn open_by_id(drive: u32, file_id: u32) -> u32 {
+  syscall_inner(0x24, drive, file_id, 0)
+}
+
+/**
+ * Read a named extended attribute of an open file into the buffer. Returns
+ * the full length of the attribute value, which may be larger than the buffer.
+ */
+pub fn get_xattr(handle: u32, name: &str, buffer: &mut [u8]) -> u32 {
+  let name_ptr = StringPtr::from_str(name);
+  let buffer_ptr = StringPtr {
+    addr: buffer.as_mut_ptr() as usize,
+    length: buffer.len(),
+  };
+  syscall_inner(0x25, handle, &name_ptr as *const StringPtr as u32, &buffer_ptr as *const StringPtr as u32)
+}
+
+/**
+ * Set a named extended attribute on an open file. Setting an empty value
+ * removes the attribute.
+ */
+pub fn set_xattr(handle: u32, name: &str, value: &[u8]) -> u32 {
+  let name_ptr = StringPtr::from_str(name);
+  let value_ptr = StringPtr {
+    addr: value.as_ptr() as usize,
+    length: value.len(),
+  };
+  syscall_inner(0x26, handle, &name_ptr as *const StringPtr as u32, &value_ptr as *const StringPtr as u32)
+}
+
 pub fn read(handle: u32, buffer: *mut u8, length: usize) -> usize {
   syscall_inner(0x12, handle, buffer as u32, length as u32) as usize
 }
@@ -45,13 +146,159 @@ pub fn write_str(handle: u32, str: &str) -> usize {
   write(handle, str.as_ptr(), str.len())
 }
 
-pub fn open_dir(path: &'static str) -> u32 {
+/**
+ * Create an empty directory. Its parent must already exist.
+ */
+pub fn mkdir(path: &str) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x18, &path_ptr as *const StringPtr as u32, 0, 0)
+}
+
+/**
+ * Remove a directory, which must be empty
+ */
+pub fn rmdir(path: &str) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x19, &path_ptr as *const StringPtr as u32, 0, 0)
+}
+
+pub fn open_dir(path: &str) -> u32 {
   let path_ptr = StringPtr::from_str(path);
   syscall_inner(0x1a, &path_ptr as *const StringPtr as u32, 0, 0)
 }
 
-pub fn read_dir(handle: u32, index: u32, info: *mut files::DirEntryInfo) {
-  syscall_inner(0x1b, handle, index, info as u32);
+/**
+ * Read the entry at an index of an open directory. Past the last entry, the
+ * info is left empty.
+ */
+pub fn read_dir(handle: u32, index: u32, info: *mut files::DirEntryInfo) -> u32 {
+  syscall_inner(0x1b, handle, index, info as u32)
+}
+
+/**
+ * Read as many entries of an open directory as fit in `entries`, continuing
+ * from `cookie`, which should start at 0 and is updated for the next call.
+ * Returns the number of entries filled; 0 means the directory has been read
+ * to the end.
+ */
+pub fn read_dir_entries(handle: u32, entries: &mut [files::DirEntryInfo], cookie: &mut u32) -> u32 {
+  let mut batch = files::DirEntryBatch {
+    entries: entries.as_mut_ptr() as u32,
+    capacity: entries.len() as u32,
+    cookie: *cookie,
+  };
+  let result = syscall_inner(0x2e, handle, &mut batch as *mut files::DirEntryBatch as u32, 0);
+  *cookie = batch.cookie;
+  result
+}
+
+pub fn close_dir(handle: u32) -> u32 {
+  syscall_inner(0x1c, handle, 0, 0)
+}
+
+/**
+ * Mount the filesystem on a device as a new drive, reading it with the named
+ * filesystem driver, e.g. `mount("C", "DEV:\\HDA", "FAT")`
+ */
+pub fn mount(drive: &str, device: &str, driver: &str) -> u32 {
+  let drive_ptr = StringPtr::from_str(drive);
+  let device_ptr = StringPtr::from_str(device);
+  let driver_ptr = StringPtr::from_str(driver);
+  syscall_inner(
+    0x32,
+    &drive_ptr as *const StringPtr as u32,
+    &device_ptr as *const StringPtr as u32,
+    &driver_ptr as *const StringPtr as u32,
+  )
+}
+
+/**
+ * Remove a mounted drive. Fails while any process has a file open on it.
+ */
+pub fn unmount(drive: &str) -> u32 {
+  let drive_ptr = StringPtr::from_str(drive);
+  syscall_inner(0x33, &drive_ptr as *const StringPtr as u32, 0, 0)
+}
+
+/**
+ * Fill `records` with one entry per mounted drive. Returns the number of
+ * drives mounted, which may be more than the number of records written if the
+ * array was too small.
+ */
+pub fn get_mount_table(records: &mut [files::MountInfo]) -> u32 {
+  syscall_inner(0x34, records.as_mut_ptr() as u32, records.len() as u32, 0)
+}
+
+/**
+ * Change the working directory that relative paths are resolved against
+ */
+pub fn chdir(path: &str) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x21, &path_ptr as *const StringPtr as u32, 0, 0)
+}
+
+/**
+ * Copy the current working directory into a buffer, returning the full length
+ * of the path. If the buffer is too small, the path is truncated.
+ */
+pub fn getcwd(buffer: &mut [u8]) -> u32 {
+  syscall_inner(0x22, buffer.as_mut_ptr() as u32, buffer.len() as u32, 0)
+}
+
+/**
+ * Shrink or extend an open file to exactly `length` bytes. Space past the new
+ * end is released, and any new bytes read as zeroes.
+ */
+pub fn ftruncate(handle: u32, length: u32) -> u32 {
+  syscall_inner(0x29, handle, length, 0)
+}
+
+/**
+ * Reserve disk space for a range of an open file, extending it with zeroes if
+ * the range reaches past the current end. Later writes within the range will
+ * not fail for lack of space.
+ */
+pub fn fallocate(handle: u32, offset: u32, length: u32) -> u32 {
+  syscall_inner(0x2a, handle, offset, length)
+}
+
+/**
+ * Create an anonymous file backed only by memory. It has no name, and exists
+ * until every handle to it is closed. Handles can be duplicated or inherited
+ * to share the contents with other processes. The file starts empty; use
+ * ftruncate to give it a size.
+ */
+pub fn memfd_create() -> u32 {
+  syscall_inner(0x2b, 0, 0, 0)
+}
+
+/**
+ * Receive a file over a serial port with XMODEM, replacing any file at
+ * `path`. Returns the number of bytes received.
+ */
+pub fn receive_file(port: &str, path: &str) -> u32 {
+  let port_ptr = StringPtr::from_str(port);
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x2c, &port_ptr as *const StringPtr as u32, &path_ptr as *const StringPtr as u32, 0)
+}
+
+/**
+ * Replace the contents of a file so that a crash leaves it with either the
+ * old or the new data, never a mix of the two
+ */
+pub fn write_file_atomic(path: &str, data: &[u8]) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x2d, &path_ptr as *const StringPtr as u32, data.as_ptr() as u32, data.len() as u32)
+}
+
+/**
+ * Set when a file was last modified, as a number of seconds since midnight on
+ * 1 January 1980, UTC. Used by copy utilities and archivers to preserve the
+ * original time.
+ */
+pub fn set_modified_time(path: &str, modified: u32) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x2f, &path_ptr as *const StringPtr as u32, modified, 0)
 }
 
 pub fn dup(handle: u32) -> u32 {
@@ -66,6 +313,57 @@ pub fn ioctl(handle: u32, command: u32, arg: u32) -> u32 {
   syscall_inner(0x1e, handle, command, arg)
 }
 
+/**
+ * Copy the path an open file was opened by into `buffer`, returning the
+ * length of the full path
+ */
+pub fn get_handle_path(handle: u32, buffer: &mut [u8]) -> u32 {
+  let buffer_ptr = StringPtr {
+    addr: buffer.as_mut_ptr() as usize,
+    length: buffer.len(),
+  };
+  ioctl(handle, flags::F_GETPATH, &buffer_ptr as *const StringPtr as u32)
+}
+
+/**
+ * Fill `clusters` with the cluster numbers of an open file on a FAT drive,
+ * returning the total number of clusters the file occupies
+ */
+pub fn get_cluster_map(handle: u32, clusters: &mut [u32]) -> u32 {
+  let map = files::ClusterMap {
+    addr: clusters.as_mut_ptr() as usize,
+    count: clusters.len(),
+  };
+  ioctl(handle, flags::FAT_GET_CLUSTERS, &map as *const files::ClusterMap as u32)
+}
+
+/**
+ * Check whether a cluster on the same drive as an open file is free,
+ * returning 1 if it is
+ */
+pub fn is_cluster_free(handle: u32, cluster: u32) -> u32 {
+  ioctl(handle, flags::FAT_IS_CLUSTER_FREE, cluster)
+}
+
+/**
+ * Move the cluster at `index` in an open file's chain to the free cluster
+ * `target`. Used by DEFRAG, which needs exclusive write access to the drive.
+ */
+pub fn move_cluster(handle: u32, index: u32, target: u32) -> u32 {
+  let request = files::ClusterMove {
+    index,
+    target,
+  };
+  ioctl(handle, flags::FAT_MOVE_CLUSTER, &request as *const files::ClusterMove as u32)
+}
+
+/**
+ * Write an open file's directory entry to disk without closing it
+ */
+pub fn update_entry(handle: u32) -> u32 {
+  ioctl(handle, flags::FAT_UPDATE_ENTRY, 0)
+}
+
 pub fn pipe(handles: &[u32; 2]) -> u32 {
   syscall_inner(0x1f, &handles[0] as *const u32 as u32, &handles[1] as *const u32 as u32, 0)
 }
@@ -82,22 +380,36 @@ pub fn fork() -> u32 {
   syscall_inner(0x01, 0, 0, 0)
 }
 
-pub fn exec(path: &'static str) {
+pub fn exec(path: &str) {
   let path_ptr = StringPtr::from_str(path);
   syscall_inner(0x02, &path_ptr as *const StringPtr as u32, 0, 0);
 }
 
-pub fn execv(path: &'static str, args: &'static str) {
+pub fn execv(path: &str, args: &str) {
   let path_ptr = StringPtr::from_str(path);
   let arg_ptr = StringPtr::from_str(args);
   syscall_inner(0x02, &path_ptr as *const StringPtr as u32, &arg_ptr as *const StringPtr as u32, 0);
 }
 
-pub fn exec_format(path: &'static str, format: u32) {
+pub fn exec_format(path: &str, format: u32) {
   let path_ptr = StringPtr::from_str(path);
   syscall_inner(0x02, &path_ptr as *const StringPtr as u32, 0, format);
 }
 
+/**
+ * Start a program in a new child process, without copying the current one.
+ * `handles` lists the handles that become handles 0, 1, 2... of the child,
+ * with SPAWN_NO_HANDLE leaving a number unused. `environment` is a block like
+ * the one get_environment fills in, or None to pass on the current
+ * environment. Returns the child's ID, or an error code.
+ */
+pub fn spawn(path: &str, args: &str, handles: &[u32], environment: Option<&[u8]>) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  let arg_ptr = StringPtr::from_str(args);
+  let request = process::SpawnRequest::new(&path_ptr, &arg_ptr, handles, environment);
+  syscall_inner(0x61, &request as *const process::SpawnRequest as u32, 0, 0)
+}
+
 pub fn brk(addr: u32) -> u32 {
   syscall_inner(0x04, 0, addr, 0)
 }
@@ -106,6 +418,24 @@ pub fn sbrk(delta: i32) -> u32 {
   syscall_inner(0x04, 1, delta as u32, 0)
 }
 
+/**
+ * Map anonymous memory or part of an open file into the process. Nothing is
+ * read or allocated until the program touches each page. Returns the address
+ * of the mapping, which is always below 0x80000000 so it cannot be mistaken
+ * for an error code.
+ */
+pub fn mmap(request: &memory::MapRequest) -> u32 {
+  syscall_inner(0x80, request as *const memory::MapRequest as u32, 0, 0)
+}
+
+/**
+ * Remove a mapping made by mmap. The address and length must cover the whole
+ * mapping.
+ */
+pub fn munmap(address: u32, length: u32) -> u32 {
+  syscall_inner(0x81, address, length, 0)
+}
+
 pub fn yield_coop() {
   syscall_inner(0x06, 0, 0, 0);
 }
@@ -119,8 +449,12 @@ pub fn exit(code: u32) -> ! {
   unsafe { core::intrinsics::unreachable() }
 }
 
+/**
+ * The ID of the current process, read from the shared data page without
+ * entering the kernel
+ */
 pub fn get_pid() -> u32 {
-  syscall_inner(0x03, 0, 0, 0)
+  shared::read_pid()
 }
 
 pub fn wait_pid(id: u32) -> (u32, u32) {
@@ -136,6 +470,112 @@ pub fn send_signal(pid: u32, signal: u32) {
   syscall_inner(0x8, pid, signal, 0);
 }
 
+/**
+ * Terminate every user process, remount all drives, and start a new init
+ * process. Only returns if the restart could not be performed.
+ */
+pub fn restart_userland() {
+  syscall_inner(0x0a, 0, 0, 0);
+}
+
+/**
+ * Fetch the time since boot, in 100ns increments. Unlike the system time, it
+ * only ever moves forward, so it is suitable for measuring intervals. It is
+ * read from the shared data page, so it only changes once per timer tick.
+ */
+pub fn uptime() -> u64 {
+  shared::read_times().1
+}
+
+/**
+ * Fetch the system time in UTC, in 100ns increments, from the shared data
+ * page. It follows any correction made to the clock, so it can jump.
+ */
+pub fn system_time() -> u64 {
+  shared::read_times().2
+}
+
+/**
+ * Stop the emulator, if it provides the isa-debug-exit device. QEMU exits
+ * with the status `(code << 1) | 1`. Returns an error when there is no device
+ * to stop, as on real hardware.
+ */
+pub fn emulator_exit(code: u32) -> u32 {
+  syscall_inner(0x0e, code, 0, 0)
+}
+
+/**
+ * Report the result of a userland test, one of the TEST_* values in `testing`,
+ * when the kernel was booted to run tests headless. The result is printed on
+ * the emulator's debug console, and counted toward the exit status. Fails if
+ * test reporting is not enabled.
+ */
+pub fn test_report(kind: u32, message: &str) -> u32 {
+  let message_ptr = StringPtr::from_str(message);
+  syscall_inner(0x90, kind, &message_ptr as *const StringPtr as u32, 0)
+}
+
+/**
+ * Print a summary of every reported test and stop the emulator, with a status
+ * that shows whether they all passed. Only returns on failure.
+ */
+pub fn test_finish() -> u32 {
+  syscall_inner(0x91, 0, 0, 0)
+}
+
+/**
+ * Write every drive's cached data to disk, then power off or reset the
+ * machine, depending on whether `action` is SHUTDOWN_POWEROFF or
+ * SHUTDOWN_REBOOT. Only returns on failure, such as when the machine cannot be
+ * powered off because it has no ACPI support.
+ */
+pub fn shutdown(action: u32) -> u32 {
+  syscall_inner(0xa0, action, 0, 0)
+}
+
+/**
+ * Let the current thread use `count` I/O ports starting at `first` with IN and
+ * OUT, or take that access away when `allowed` is false. Ports the kernel
+ * drives itself, like the PIC and the disk controllers, are never granted.
+ * Access is not inherited by children, and ends when the thread runs a new
+ * program.
+ */
+pub fn ioperm(first: u16, count: u32, allowed: bool) -> u32 {
+  syscall_inner(0xb0, first as u32, count, allowed as u32)
+}
+
+/**
+ * Fill `records` with one entry per process, and `system` with overall CPU
+ * figures, in a single call. Returns the number of records written; if
+ * `system.process_count` is larger, the array was too small to hold them all.
+ */
+pub fn get_process_stats(system: &mut process::SystemStats, records: &mut [process::ProcessStats]) -> u32 {
+  syscall_inner(
+    0x0f,
+    system as *mut process::SystemStats as u32,
+    records.as_mut_ptr() as u32,
+    records.len() as u32,
+  )
+}
+
+/**
+ * Copy the text of a message from the current locale's catalog into the
+ * buffer, and return the full length of the text. Arguments are left as `%1`
+ * through `%9` for the caller to fill in.
+ */
+pub fn get_message(id: u16, buffer: &mut [u8]) -> u32 {
+  syscall_inner(0x0c, id as u32, buffer.as_mut_ptr() as u32, buffer.len() as u32)
+}
+
+/**
+ * Switch the message catalog to a different locale, loaded from
+ * INIT:\<locale>.MSG
+ */
+pub fn set_locale(locale: &str) -> u32 {
+  let locale_ptr = StringPtr::from_str(locale);
+  syscall_inner(0x0d, &locale_ptr as *const StringPtr as u32, 0, 0)
+}
+
 /**
  * Send a signal to the current thread
  */
@@ -143,3 +583,113 @@ pub fn raise(signal: u32) {
   syscall_inner(0x7, signal, 0, 0);
 }
 
+/**
+ * Run `handler` whenever the current process receives `signal`. The handler
+ * runs on the process's own stack, and the interrupted code resumes once it
+ * returns.
+ */
+pub fn set_signal_handler(signal: u32, handler: extern "C" fn(u32)) -> u32 {
+  syscall_inner(0x40, signal, handler as u32, signal_return as u32)
+}
+
+/**
+ * Restore the default action for a signal, or ignore it, using
+ * signals::ACTION_DEFAULT or signals::ACTION_IGNORE
+ */
+pub fn set_signal_action(signal: u32, action: u32) -> u32 {
+  syscall_inner(0x40, signal, action, 0)
+}
+
+/**
+ * Block or unblock a set of signals, where bit N refers to signal N. Blocked
+ * signals are held until they are unblocked. Returns the previous mask.
+ */
+pub fn set_signal_mask(method: u32, mask: u32) -> u32 {
+  syscall_inner(0x41, method, mask, 0)
+}
+
+/**
+ * Move a process into a process group. A pid of zero refers to the calling
+ * process, and a group of zero starts a new group led by that process. Only
+ * the caller and its children can be moved, within their own session.
+ */
+pub fn set_process_group(pid: u32, group: u32) -> u32 {
+  syscall_inner(0x50, pid, group, 0)
+}
+
+/**
+ * Get the process group of a process, or of the caller if pid is zero
+ */
+pub fn get_process_group(pid: u32) -> u32 {
+  syscall_inner(0x51, pid, 0, 0)
+}
+
+/**
+ * Start a new session, with the caller leading it and a new process group.
+ * Fails if the caller already leads a group. Returns the session ID.
+ */
+pub fn create_session() -> u32 {
+  syscall_inner(0x52, 0, 0, 0)
+}
+
+/**
+ * Start a new thread sharing the caller's memory and open files. The thread
+ * calls `entry` with `arg`, using the stack that ends at `stack_top`, and must
+ * call exit rather than return. Exiting ends only that thread, unless it is
+ * the original one, which ends every thread. Returns the thread's ID, which
+ * can be waited on like a child process.
+ */
+pub fn create_thread(entry: extern "C" fn(u32), stack_top: u32, arg: u32) -> u32 {
+  syscall_inner(0x60, entry as u32, stack_top, arg)
+}
+
+/**
+ * Copy the value of an environment variable into a buffer, returning its full
+ * length. If the buffer is too small, the value is truncated. Fails if the
+ * variable is not set.
+ *
+ * The environment is copied into children when they fork, shared between
+ * threads, and kept across exec. Native programs also find it on their
+ * initial stack, which holds a return address of zero followed by argc, argv,
+ * and envp, as if `_start(argc, argv, envp)` had been called.
+ */
+pub fn get_env(name: &str, buffer: &mut [u8]) -> u32 {
+  let name_ptr = StringPtr::from_str(name);
+  syscall_inner(0x70, &name_ptr as *const StringPtr as u32, buffer.as_mut_ptr() as u32, buffer.len() as u32)
+}
+
+/**
+ * Set an environment variable, replacing any earlier value. Fails if the name
+ * is empty or contains '=', or if either string contains a NUL.
+ */
+pub fn set_env(name: &str, value: &str) -> u32 {
+  let name_ptr = StringPtr::from_str(name);
+  let value_ptr = StringPtr::from_str(value);
+  syscall_inner(0x71, &name_ptr as *const StringPtr as u32, &value_ptr as *const StringPtr as u32, 0)
+}
+
+/**
+ * Remove an environment variable. Removing one that is not set succeeds.
+ */
+pub fn unset_env(name: &str) -> u32 {
+  let name_ptr = StringPtr::from_str(name);
+  syscall_inner(0x72, &name_ptr as *const StringPtr as u32, 0, 0)
+}
+
+/**
+ * Copy the whole environment into a buffer, returning its full length. Each
+ * variable is written as NAME=VALUE followed by a NUL, and the block ends with
+ * one more NUL. If the buffer is too small, the block is truncated.
+ */
+pub fn get_environment(buffer: &mut [u8]) -> u32 {
+  syscall_inner(0x73, buffer.as_mut_ptr() as u32, buffer.len() as u32, 0)
+}
+
+/**
+ * Signal handlers return here, which asks the kernel to restore the state
+ * saved when the signal arrived
+ */
+extern "C" fn signal_return() {
+  syscall_inner(0x42, 0, 0, 0);
+}
+
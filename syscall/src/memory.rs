@@ -0,0 +1,117 @@
+/// Values of `MapRequest::flags`
+/// The mapping can be written. Writes to a file mapping are private to the
+/// process that made them, and never reach the file.
+pub const MAP_WRITE: u32 = 1;
+/// The mapping starts out filled with zeroes, instead of coming from a file.
+/// The handle and offset are ignored.
+pub const MAP_ANONYMOUS: u32 = 2;
+
+/// Describes a new mapping for the mmap syscall. Its pages are only read from
+/// the file, or allocated, once the program first touches them.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct MapRequest {
+  /// Page-aligned address to place the mapping at, or 0 to let the kernel
+  /// choose one
+  pub address: u32,
+  /// Length in bytes, rounded up to whole pages
+  pub length: u32,
+  pub flags: u32,
+  /// Open file to map from
+  pub handle: u32,
+  /// Page-aligned offset in the file where the mapping starts. Bytes past
+  /// the end of the file read as zeroes.
+  pub offset: u32,
+}
+
+impl MapRequest {
+  pub fn anonymous(length: u32, flags: u32) -> MapRequest {
+    MapRequest {
+      address: 0,
+      length,
+      flags: flags | MAP_ANONYMOUS,
+      handle: 0,
+      offset: 0,
+    }
+  }
+
+  pub fn file(handle: u32, offset: u32, length: u32, flags: u32) -> MapRequest {
+    MapRequest {
+      address: 0,
+      length,
+      flags: flags & !MAP_ANONYMOUS,
+      handle,
+      offset,
+    }
+  }
+
+  pub fn is_writable(&self) -> bool {
+    self.flags & MAP_WRITE != 0
+  }
+
+  pub fn is_anonymous(&self) -> bool {
+    self.flags & MAP_ANONYMOUS != 0
+  }
+}
+
+/// Size of each record read from DEV:\MEMEV
+pub const MEMORY_EVENT_SIZE: usize = 8;
+
+/// How close the system is to running out of physical memory
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(u8)]
+pub enum MemoryPressure {
+  Normal = 0,
+  /// Free memory is getting scarce. Programs holding caches they can rebuild
+  /// should start releasing them.
+  Low = 1,
+  /// Almost nothing is left. Anything that can be freed should be.
+  Critical = 2,
+}
+
+impl MemoryPressure {
+  pub fn from_u8(value: u8) -> Option<MemoryPressure> {
+    match value {
+      0 => Some(MemoryPressure::Normal),
+      1 => Some(MemoryPressure::Low),
+      2 => Some(MemoryPressure::Critical),
+      _ => None,
+    }
+  }
+}
+
+/// Notification that the memory pressure level has changed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MemoryEvent {
+  pub level: MemoryPressure,
+  /// Free 4KiB frames when the level changed
+  pub free_frames: u32,
+}
+
+impl MemoryEvent {
+  pub fn new(level: MemoryPressure, free_frames: u32) -> MemoryEvent {
+    MemoryEvent {
+      level,
+      free_frames,
+    }
+  }
+
+  pub fn to_bytes(&self) -> [u8; MEMORY_EVENT_SIZE] {
+    let mut bytes = [0; MEMORY_EVENT_SIZE];
+    bytes[0] = self.level as u8;
+    bytes[4..].copy_from_slice(&self.free_frames.to_le_bytes());
+    bytes
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Option<MemoryEvent> {
+    if bytes.len() < MEMORY_EVENT_SIZE {
+      return None;
+    }
+    let level = MemoryPressure::from_u8(bytes[0])?;
+    let free_frames = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    Some(MemoryEvent {
+      level,
+      free_frames,
+    })
+  }
+}
@@ -0,0 +1,26 @@
+//! Numbers identifying user-visible messages. The text of each message is
+//! looked up in the catalog for the current locale, so that it can be
+//! translated without changing any code. `%1` through `%9` in the text are
+//! replaced with the arguments supplied by whoever prints the message.
+
+// Kernel messages, printed to the console
+pub const KEYBOARD_READY: u16 = 1;
+pub const FLOPPY_READY: u16 = 2;
+/// %1: the controller error
+pub const FLOPPY_INIT_FAILED: u16 = 3;
+/// %1: the drive
+pub const MEDIA_CHANGED: u16 = 4;
+/// %1: the drive
+pub const REMOUNT_FAILED: u16 = 5;
+/// %1: the CD-ROM device
+pub const NO_READABLE_DISC: u16 = 6;
+/// %1: the date, %2: the time
+pub const SYSTEM_TIME: u16 = 7;
+/// %1: the pool, %2: how long the oldest job has waited in ms, %3: queued
+/// jobs, %4: busy workers, %5: total workers
+pub const WORKER_POOL_STARVED: u16 = 8;
+
+// Messages printed by init
+pub const INITIALIZING_DEVICES: u16 = 100;
+pub const SYSTEM_READY: u16 = 101;
+pub const ROOT_DIRECTORY_CONTENTS: u16 = 102;
@@ -0,0 +1,128 @@
+/// Values of `ProcessStats::state`
+pub const STATE_RUNNING: u32 = 0;
+pub const STATE_SLEEPING: u32 = 1;
+pub const STATE_PAUSED: u32 = 2;
+pub const STATE_BLOCKED: u32 = 3;
+pub const STATE_TERMINATED: u32 = 4;
+
+/// Longest program name held in a ProcessStats record
+pub const PROCESS_NAME_LENGTH: usize = 16;
+
+/// One record per process, filled in by get_process_stats
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ProcessStats {
+  pub pid: u32,
+  pub parent: u32,
+  pub state: u32,
+  /// Recent share of the CPU, in tenths of a percent. The figure is a decaying
+  /// average, so it is smoothed over roughly the last third of a second.
+  pub cpu_permille: u32,
+  /// Timer ticks spent running since the process was created
+  pub cpu_ticks: u32,
+  /// File name of the running program, padded with zeroes. Longer names are
+  /// cut off.
+  pub name: [u8; PROCESS_NAME_LENGTH],
+}
+
+impl ProcessStats {
+  pub fn empty() -> ProcessStats {
+    ProcessStats {
+      pid: 0,
+      parent: 0,
+      state: STATE_RUNNING,
+      cpu_permille: 0,
+      cpu_ticks: 0,
+      name: [0; PROCESS_NAME_LENGTH],
+    }
+  }
+
+  pub fn get_name(&self) -> &str {
+    let length = self.name.iter().position(|byte| *byte == 0).unwrap_or(PROCESS_NAME_LENGTH);
+    core::str::from_utf8(&self.name[..length]).unwrap_or("")
+  }
+
+  pub fn set_name(&mut self, name: &str) {
+    let mut length = name.len().min(PROCESS_NAME_LENGTH);
+    // Never split a character
+    while !name.is_char_boundary(length) {
+      length -= 1;
+    }
+    self.name = [0; PROCESS_NAME_LENGTH];
+    self.name[..length].copy_from_slice(&name.as_bytes()[..length]);
+  }
+}
+
+/// System-wide figures returned along with the process records
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SystemStats {
+  /// Share of recent time the CPU spent idle, in tenths of a percent
+  pub idle_permille: u32,
+  /// Total number of processes, which may exceed the records provided
+  pub process_count: u32,
+  /// Length of a timer tick, in milliseconds
+  pub ms_per_tick: u32,
+}
+
+impl SystemStats {
+  pub fn empty() -> SystemStats {
+    SystemStats {
+      idle_permille: 0,
+      process_count: 0,
+      ms_per_tick: 0,
+    }
+  }
+}
+
+/// Most handles a spawn request can pass to the new process
+pub const SPAWN_MAX_HANDLES: usize = 8;
+/// Placed in `SpawnRequest::handles` to leave a handle number unused in the
+/// new process
+pub const SPAWN_NO_HANDLE: u32 = 0xffffffff;
+
+/// Describes a new process for the spawn syscall. Rather than copy the caller
+/// and replace the copy's program, the kernel builds the process directly
+/// around the program, so nothing else is inherited.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SpawnRequest {
+  /// Address of a StringPtr holding the program's path
+  pub path: u32,
+  /// Address of a StringPtr holding the arguments, or 0 for none
+  pub args: u32,
+  /// Address of an environment block laid out like the one get_environment
+  /// returns, or 0 to pass on the caller's environment
+  pub environment: u32,
+  pub environment_length: u32,
+  /// Caller handles that become handles 0, 1, 2... of the new process. Only
+  /// the first `handle_count` entries are used, and handles not listed are
+  /// not inherited.
+  pub handles: [u32; SPAWN_MAX_HANDLES],
+  pub handle_count: u32,
+  /// How to interpret the program, like the format passed to exec
+  pub format: u32,
+}
+
+impl SpawnRequest {
+  /// Build a request from StringPtrs, which must outlive the syscall. Handles
+  /// past SPAWN_MAX_HANDLES are ignored.
+  pub fn new(path: &crate::data::StringPtr, args: &crate::data::StringPtr, handles: &[u32], environment: Option<&[u8]>) -> SpawnRequest {
+    let handle_count = handles.len().min(SPAWN_MAX_HANDLES);
+    let mut request_handles = [SPAWN_NO_HANDLE; SPAWN_MAX_HANDLES];
+    request_handles[..handle_count].copy_from_slice(&handles[..handle_count]);
+    let (environment, environment_length) = match environment {
+      Some(block) => (block.as_ptr() as u32, block.len() as u32),
+      None => (0, 0),
+    };
+    SpawnRequest {
+      path: path as *const crate::data::StringPtr as u32,
+      args: args as *const crate::data::StringPtr as u32,
+      environment,
+      environment_length,
+      handles: request_handles,
+      handle_count: handle_count as u32,
+      format: 0,
+    }
+  }
+}
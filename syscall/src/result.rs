@@ -28,6 +28,30 @@ pub enum SystemError {
   IOError = 10,
   /// The process cannot open any more file handles
   MaxFilesExceeded = 11,
+  /// Attempted to modify a drive that was mounted read-only
+  ReadOnlyFileSystem = 12,
+  /// The drive has no room left for new data or directory entries
+  NoSpace = 13,
+  /// An argument was malformed or out of range
+  InvalidArgument = 14,
+  /// The disk was changed since the file was opened, or the drive must be
+  /// remounted before it can be used again
+  MediaChanged = 15,
+  /// A file or directory already exists at the destination path
+  AlreadyExists = 16,
+  /// The resource is still in use, like a drive with open files
+  Busy = 17,
+  /// The operation is not allowed on this object
+  PermissionDenied = 18,
+  /// There was not enough free memory to complete the operation
+  OutOfMemory = 19,
+  /// The process cannot create any more threads
+  TooManyThreads = 20,
+  /// A path or name was longer than the kernel accepts
+  NameTooLong = 21,
+  /// A pointer passed to a syscall did not point to memory the process can
+  /// access
+  BadPointer = 22,
 }
 
 impl SystemError {
@@ -45,6 +69,17 @@ impl SystemError {
       9 => SystemError::UnsupportedCommand,
       10 => SystemError::IOError,
       11 => SystemError::MaxFilesExceeded,
+      12 => SystemError::ReadOnlyFileSystem,
+      13 => SystemError::NoSpace,
+      14 => SystemError::InvalidArgument,
+      15 => SystemError::MediaChanged,
+      16 => SystemError::AlreadyExists,
+      17 => SystemError::Busy,
+      18 => SystemError::PermissionDenied,
+      19 => SystemError::OutOfMemory,
+      20 => SystemError::TooManyThreads,
+      21 => SystemError::NameTooLong,
+      22 => SystemError::BadPointer,
 
       _ => SystemError::Unknown,
     }
@@ -0,0 +1,54 @@
+//! The kernel maps a read-only page into every process, holding values that
+//! programs ask for often enough that a syscall for each request would
+//! dominate a tight loop. Reading them is an ordinary memory access.
+
+use core::ptr::read_volatile;
+
+/// Where the shared page appears in every process
+pub const SHARED_DATA_ADDRESS: usize = 0xbfe00000;
+
+/// Layout of the shared page
+#[repr(C)]
+pub struct SharedData {
+  /// Odd while the kernel is updating the time fields. A reader that sees an
+  /// odd value, or a different value after reading, must try again.
+  pub sequence: u32,
+  /// ID of the running process. Only one process runs at a time, so the
+  /// kernel rewrites this whenever it switches, and it always matches the
+  /// process reading it.
+  pub pid: u32,
+  /// Timer ticks since boot
+  pub ticks: u32,
+  pub _reserved: u32,
+  /// Time since boot, in 100ns increments
+  pub uptime: u64,
+  /// System time in UTC, in the same units and epoch as the kernel clock
+  pub system_time: u64,
+}
+
+fn get_shared_data() -> *const SharedData {
+  SHARED_DATA_ADDRESS as *const SharedData
+}
+
+pub fn read_pid() -> u32 {
+  unsafe { read_volatile(&(*get_shared_data()).pid) }
+}
+
+/// Read the tick count, uptime, and system time as one consistent snapshot
+pub fn read_times() -> (u32, u64, u64) {
+  let data = get_shared_data();
+  loop {
+    unsafe {
+      let before = read_volatile(&(*data).sequence);
+      if before & 1 != 0 {
+        continue;
+      }
+      let ticks = read_volatile(&(*data).ticks);
+      let uptime = read_volatile(&(*data).uptime);
+      let system_time = read_volatile(&(*data).system_time);
+      if read_volatile(&(*data).sequence) == before {
+        return (ticks, uptime, system_time);
+      }
+    }
+  }
+}
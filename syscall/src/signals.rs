@@ -14,4 +14,16 @@ pub const TERM: u32 = 15;
 pub const CHILD: u32 = 17;
 pub const CONTINUE: u32 = 18;
 pub const STOP: u32 = 19;
-pub const TSTOP: u32 = 20;
\ No newline at end of file
+pub const TSTOP: u32 = 20;
+
+/// Signal numbers run from 1 up to, but not including, this value
+pub const SIGNAL_COUNT: u32 = 32;
+
+/// Handler values for `set_signal_action`
+pub const ACTION_DEFAULT: u32 = 0;
+pub const ACTION_IGNORE: u32 = 1;
+
+/// How `set_signal_mask` combines its mask with the current one
+pub const MASK_BLOCK: u32 = 0;
+pub const MASK_UNBLOCK: u32 = 1;
+pub const MASK_SET: u32 = 2;
@@ -0,0 +1,18 @@
+//! Results reported by userland test programs with `test_report`, when the
+//! kernel has been booted to run tests headless
+
+/// The named test passed
+pub const TEST_PASS: u32 = 0;
+/// The named test failed, which fails the whole run
+pub const TEST_FAIL: u32 = 1;
+/// The named test was not run, and counts neither way
+pub const TEST_SKIP: u32 = 2;
+/// Extra output explaining a result, which is printed but not counted
+pub const TEST_DIAGNOSTIC: u32 = 3;
+
+/// Written to isa-debug-exit by `test_finish` when every test passed, so QEMU
+/// exits with status 1
+pub const TEST_EXIT_PASSED: u32 = 0;
+/// Written when any test failed, or none were reported at all, so QEMU exits
+/// with status 5. A kernel panic in a testing build writes 3, for status 7.
+pub const TEST_EXIT_FAILED: u32 = 2;